@@ -0,0 +1,93 @@
+//! Packs the initial ramdisk embedded into the kernel, see `src/fs/initrd.rs`.
+//!
+//! The files under `initrd/` are written to a ustar archive in `OUT_DIR`, unless the environment
+//! variable `INITRD` names an archive to embed as is.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+fn main() -> io::Result<()> {
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set")).join("initrd");
+    println!("cargo:rerun-if-env-changed=INITRD");
+
+    if let Some(archive) = env::var_os("INITRD") {
+        println!("cargo:rerun-if-changed={}", Path::new(&archive).display());
+        fs::copy(&archive, &out)?;
+        return Ok(());
+    }
+
+    let root = Path::new("initrd");
+    println!("cargo:rerun-if-changed={}", root.display());
+    let mut archive = Vec::new();
+    if root.is_dir() {
+        pack(root, root, &mut archive)?;
+    }
+    // the end of the archive
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    fs::File::create(&out)?.write_all(&archive)
+}
+
+/// Append the entries under `dir` to `archive`, sorted by name so that the archive is reproducible.
+fn pack(root: &Path, dir: &Path, archive: &mut Vec<u8>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = path
+            .strip_prefix(root)
+            .expect("entry outside of the initrd")
+            .to_str()
+            .expect("initrd file name not in UTF-8")
+            .replace('\\', "/");
+        if entry.file_type()?.is_dir() {
+            archive.extend_from_slice(&header(&format!("{}/", name), b'5', 0));
+            pack(root, &path, archive)?;
+        } else {
+            let data = fs::read(&path)?;
+            archive.extend_from_slice(&header(&name, b'0', data.len()));
+            archive.extend_from_slice(&data);
+            let padded = (archive.len() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+            archive.resize(padded, 0);
+        }
+    }
+    Ok(())
+}
+
+/// The ustar header of an entry of type `kind` at `name` with `size` bytes of data.
+fn header(name: &str, kind: u8, size: usize) -> [u8; BLOCK_SIZE] {
+    assert!(
+        name.len() <= 100,
+        "initrd path {} longer than 100 bytes",
+        name
+    );
+    let mut header = [0; BLOCK_SIZE];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    let mode: &[u8] = if kind == b'5' {
+        b"0000755\0"
+    } else {
+        b"0000644\0"
+    };
+    field(100, mode);
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, b"00000000000\0");
+    field(156, &[kind]);
+    field(257, b"ustar\0");
+    field(263, b"00");
+
+    // the checksum is computed with its own field made of spaces
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    header
+}
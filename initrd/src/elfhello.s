@@ -0,0 +1,34 @@
+# The source of /bin/elfhello, a static ELF64 executable linked at the start of the programs, loaded
+# by its program headers instead of as raw machine code. Exits with 1, the number of writes counted
+# in `.data` plus the zeroed word of `.bss`:
+#
+#     llvm-mc -triple=x86_64 -filetype=obj elfhello.s -o elfhello.o
+#     ld -static -s -z max-page-size=4096 -z noseparate-code -Ttext-segment=0x200000400000 \
+#         -o ../bin/elfhello elfhello.o
+
+.intel_syntax noprefix
+.globl _start
+.text
+_start:
+    # write(1, msg, len), then count the calls in .data
+    mov edi, 1
+    lea rsi, [rip + msg]
+    mov edx, len
+    mov eax, 1
+    syscall
+    inc qword ptr [rip + calls]
+    # exit(calls + bss), 1 as .bss is zeroed
+    mov rdi, [rip + calls]
+    add rdi, [rip + zeroed]
+    mov eax, 2
+    syscall
+.section .rodata
+msg:
+    .ascii "Hello from an ELF executable!\n"
+.set len, . - msg
+.data
+calls:
+    .quad 0
+.bss
+zeroed:
+    .quad 0
@@ -0,0 +1,18 @@
+# The source of /bin/hello, raw machine code loaded at its first byte:
+#
+#     llvm-mc -triple=x86_64 -filetype=obj hello.s -o hello.o
+#     objcopy -O binary -j .text hello.o ../bin/hello
+
+.intel_syntax noprefix
+    # write(1, msg, 19)
+    mov edi, 1
+    lea rsi, [rip + msg]
+    mov edx, 19
+    mov eax, 1
+    syscall
+    # exit(0)
+    xor edi, edi
+    mov eax, 2
+    syscall
+msg:
+    .ascii "Hello from ring 3!\n"
@@ -0,0 +1,42 @@
+# The source of /bin/upcase, which copies its standard input to its standard output in upper case
+# until the end of the input. Exits with 0, or with the error of the read which failed.
+#
+#     llvm-mc -triple=x86_64 -filetype=obj upcase.s -o upcase.o
+#     objcopy -O binary -j .text upcase.o ../bin/upcase
+
+.intel_syntax noprefix
+    sub rsp, 256
+next_chunk:
+    # read(0, buf, 256)
+    xor edi, edi
+    mov rsi, rsp
+    mov edx, 256
+    xor eax, eax
+    syscall
+    test rax, rax
+    jle done
+    mov rdx, rax
+    xor ecx, ecx
+next_byte:
+    mov al, [rsi + rcx]
+    cmp al, 'a'
+    jb skip
+    cmp al, 'z'
+    ja skip
+    sub al, 32
+    mov [rsi + rcx], al
+skip:
+    inc rcx
+    cmp rcx, rdx
+    jb next_byte
+    # write(1, buf, len)
+    mov edi, 1
+    mov eax, 1
+    syscall
+    jmp next_chunk
+done:
+    # exit(-result)
+    mov rdi, rax
+    neg rdi
+    mov eax, 2
+    syscall
@@ -0,0 +1,283 @@
+//! Lookup of ACPI tables.
+//!
+//! The firmware leaves the Root System Description Pointer in the first KiB of the Extended BIOS
+//! Data Area or in the BIOS area below 1 MiB, it points to the RSDT (or the XSDT since ACPI 2.0)
+//! listing the physical addresses of all the other tables. The bootloader doesn't pass the RSDP
+//! on, it's searched for once and remembered. The checksums of the RSDP and of each table are
+//! validated before use.
+//!
+//! The tables describing the machine to several subsystems are parsed here: the [Madt] with the
+//! processors and the interrupt controllers and the [Fadt] with the power management registers.
+//! Other tables are looked up by [find_table] and parsed by the drivers using them. Tables are
+//! read through the mapping of the complete physical memory, see [memory::phys_to_virt], parsing
+//! never allocates.
+
+use core::{mem, ptr, slice};
+
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+pub use self::{
+    fadt::{Fadt, GenericAddress},
+    madt::{Madt, MadtEntry},
+};
+
+pub mod fadt;
+pub mod madt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Physical address of the real mode segment of the EBDA.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+/// The physical address and the entry size of the root table, see [root_table].
+static ROOT_TABLE: OnceCell<Option<(u64, u64)>> = OnceCell::uninit();
+
+/// The header common to all system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    /// The signature of the table, e.g. `FACP` or `HPET`.
+    pub signature: [u8; 4],
+    /// The length of the table including the header.
+    pub length: u32,
+    /// The revision of the table structure.
+    pub revision: u8,
+    checksum: u8,
+    /// The OEM supplying the table.
+    pub oem_id: [u8; 6],
+    /// The OEM identifier of the table.
+    pub oem_table_id: [u8; 8],
+    /// The OEM revision of the table.
+    pub oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// Read a `T` at the physical address `addr`.
+///
+/// # Safety
+/// `addr` must be mapped in the mapping of the complete physical memory and hold a valid `T`.
+unsafe fn read_phys<T: Copy>(addr: u64) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(PhysAddr::new(addr)).as_ptr())
+}
+
+/// Whether the bytes in `[addr, addr + len)` sum to 0 modulo 256.
+///
+/// # Safety
+/// The range must be mapped in the mapping of the complete physical memory.
+unsafe fn checksum_valid(addr: u64, len: u64) -> bool {
+    (addr..addr + len).fold(0u8, |sum, a| sum.wrapping_add(read_phys::<u8>(a))) == 0
+}
+
+/// Search the 16-byte aligned addresses in `[start, end)` for a valid RSDP.
+fn search_rsdp(start: u64, end: u64) -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        // # Safety
+        // The first MiB of physical memory is always mapped by the bootloader.
+        unsafe { &read_phys::<[u8; 8]>(addr) == RSDP_SIGNATURE && checksum_valid(addr, 20) }
+    })
+}
+
+/// The physical address of the RSDP, `None` if the firmware doesn't support ACPI.
+pub fn rsdp() -> Option<PhysAddr> {
+    // # Safety
+    // The BIOS data area in the first page of physical memory is always present.
+    let ebda = u64::from(unsafe { read_phys::<u16>(EBDA_SEGMENT_POINTER) }) << 4;
+    search_rsdp(ebda, ebda + 1024)
+        .filter(|_| ebda != 0)
+        .or_else(|| search_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+        .map(PhysAddr::new)
+}
+
+/// The physical address and the entry size of the root table, either the XSDT with 8-byte entries
+/// or the RSDT with 4-byte entries.
+fn root_table() -> Option<(u64, u64)> {
+    *ROOT_TABLE.get_or_init(find_root_table)
+}
+
+fn find_root_table() -> Option<(u64, u64)> {
+    let rsdp = rsdp()?.as_u64();
+
+    // # Safety
+    // `rsdp` points to an RSDP with a valid checksum, the extended fields exist since revision 2.
+    unsafe {
+        let revision = read_phys::<u8>(rsdp + 15);
+        if revision >= 2 {
+            let length = u64::from(read_phys::<u32>(rsdp + 20));
+            let xsdt = read_phys::<u64>(rsdp + 24);
+            if xsdt != 0 && checksum_valid(rsdp, length) {
+                return Some((xsdt, 8));
+            }
+        }
+        Some((u64::from(read_phys::<u32>(rsdp + 16)), 4))
+    }
+}
+
+/// The physical address and the header of the table with `signature`, `None` if the firmware
+/// provides no such table or the table is corrupted.
+pub fn find_table(signature: &[u8; 4]) -> Option<(PhysAddr, SdtHeader)> {
+    let (root, entry_size) = root_table()?;
+
+    // # Safety
+    // The root table and the tables it points to are provided by the firmware in memory reserved
+    // for ACPI, each table is checked before its content is used.
+    unsafe {
+        let header = read_phys::<SdtHeader>(root);
+        if !checksum_valid(root, u64::from(header.length)) {
+            return None;
+        }
+
+        let header_size = mem::size_of::<SdtHeader>() as u64;
+        let entries = (u64::from(header.length) - header_size) / entry_size;
+        (0..entries)
+            .map(|i| {
+                let entry = root + header_size + i * entry_size;
+                if entry_size == 8 {
+                    read_phys::<u64>(entry)
+                } else {
+                    u64::from(read_phys::<u32>(entry))
+                }
+            })
+            .map(|addr| (addr, read_phys::<SdtHeader>(addr)))
+            .find(|(addr, header)| {
+                &header.signature == signature && checksum_valid(*addr, u64::from(header.length))
+            })
+            .map(|(addr, header)| (PhysAddr::new(addr), header))
+    }
+}
+
+/// Read the field of type `T` at `offset` of the table at `table`.
+///
+/// # Safety
+/// The table must have been found by [find_table] and be longer than `offset + size_of::<T>()`.
+unsafe fn read_field<T: Copy>(table: PhysAddr, offset: u64) -> T {
+    read_phys(table.as_u64() + offset)
+}
+
+/// The parsed FADT, `None` if the firmware provides none.
+pub fn fadt() -> Option<Fadt> {
+    let (table, header) = find_table(b"FACP")?;
+    Fadt::parse(table, header)
+}
+
+/// The parsed MADT, `None` if the firmware provides none.
+pub fn madt() -> Option<Madt> {
+    let (table, header) = find_table(b"APIC")?;
+    Madt::parse(table, header)
+}
+
+/// AML opcodes in the definition of the sleep packages.
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+/// The `SLP_TYPa` and `SLP_TYPb` values of the sleep state `S<state>`, written to the PM1 control
+/// blocks to enter it. `None` if the DSDT doesn't define the state.
+///
+/// The values are only defined in AML, by a package named `_S<state>_` in the DSDT. The DSDT isn't
+/// interpreted, its bytes are searched for the name followed by the package, which is how
+/// firmwares encode it in practice.
+pub fn sleep_types(state: u8) -> Option<(u8, u8)> {
+    let dsdt = fadt()?.dsdt.as_u64();
+
+    // # Safety
+    // The FADT points to the DSDT in memory reserved for ACPI, its checksum is validated before
+    // its content is used.
+    let aml = unsafe {
+        let header = read_phys::<SdtHeader>(dsdt);
+        let length = u64::from(header.length);
+        if &header.signature != b"DSDT" || !checksum_valid(dsdt, length) {
+            return None;
+        }
+        let body = memory::phys_to_virt(PhysAddr::new(dsdt + mem::size_of::<SdtHeader>() as u64));
+        slice::from_raw_parts(
+            body.as_ptr::<u8>(),
+            (length as usize).saturating_sub(mem::size_of::<SdtHeader>()),
+        )
+    };
+
+    let name = [b'_', b'S', b'0' + state, b'_'];
+    aml.windows(name.len())
+        .enumerate()
+        .filter(|&(i, window)| window == name && is_name_op(aml, i))
+        .find_map(|(i, _)| parse_sleep_package(&aml[i + name.len()..]))
+}
+
+/// Whether the name at `at` in `aml` is defined by a NameOp, optionally with a root prefix.
+fn is_name_op(aml: &[u8], at: usize) -> bool {
+    match at {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => {
+            aml[at - 1] == AML_NAME_OP
+                || (aml[at - 1] == AML_ROOT_CHAR && aml[at - 2] == AML_NAME_OP)
+        }
+    }
+}
+
+/// Parse the first two integers of the package at the start of `aml`.
+fn parse_sleep_package(aml: &[u8]) -> Option<(u8, u8)> {
+    let (&op, rest) = aml.split_first()?;
+    if op != AML_PACKAGE_OP {
+        return None;
+    }
+    // the top 2 bits of the first byte of PkgLength count the bytes following it
+    let pkg_length_bytes = 1 + usize::from(rest.first()? >> 6);
+    // skip PkgLength and NumElements
+    let mut elements = rest.get(pkg_length_bytes + 1..)?;
+
+    let mut next = || {
+        let (&op, rest) = elements.split_first()?;
+        let (value, rest) = match op {
+            AML_ZERO_OP => (0, rest),
+            AML_ONE_OP => (1, rest),
+            AML_BYTE_PREFIX => (*rest.first()?, &rest[1..]),
+            _ => return None,
+        };
+        elements = rest;
+        Some(value)
+    };
+    Some((next()?, next()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn find_fadt() {
+        // the FADT is mandatory on all ACPI systems
+        let (_, header) = find_table(b"FACP").expect("no FADT");
+        assert!(header.length as usize > mem::size_of::<SdtHeader>());
+        assert!(find_table(b"NONE").is_none());
+    }
+
+    #[test_case]
+    fn parse_sleep_packages() {
+        // Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero }) as compiled by iasl
+        assert_eq!(
+            parse_sleep_package(&[0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00]),
+            Some((0, 0))
+        );
+        // byte prefixed values and a 2-byte PkgLength
+        assert_eq!(
+            parse_sleep_package(&[0x12, 0x40, 0x00, 0x02, 0x0a, 0x05, 0x01]),
+            Some((5, 1))
+        );
+        assert_eq!(parse_sleep_package(&[0x12, 0x04, 0x02, 0x0a]), None);
+        assert_eq!(parse_sleep_package(&[0x0a, 0x05]), None);
+    }
+
+    #[test_case]
+    fn soft_off_defined() {
+        // QEMU puts the machine into S5 with SLP_TYP 0 on both PM1 control blocks
+        assert_eq!(sleep_types(5), Some((0, 0)));
+    }
+}
@@ -0,0 +1,145 @@
+//! The Fixed ACPI Description Table, signature `FACP`.
+//!
+//! The FADT locates the fixed hardware registers of ACPI power management: the PM1 event and
+//! control blocks used to enter sleep states, the PM timer and since ACPI 2.0 the reset register.
+//! Only the 32-bit fields of ACPI 1.0 are read for the blocks, firmwares still fill them in for I/O
+//! port blocks.
+
+use x86_64::PhysAddr;
+
+use super::{read_field, SdtHeader};
+
+const OFFSET_DSDT: u64 = 40;
+const OFFSET_SCI_INTERRUPT: u64 = 46;
+const OFFSET_SMI_COMMAND: u64 = 48;
+const OFFSET_ACPI_ENABLE: u64 = 52;
+const OFFSET_ACPI_DISABLE: u64 = 53;
+const OFFSET_PM1A_EVENT_BLOCK: u64 = 56;
+const OFFSET_PM1B_EVENT_BLOCK: u64 = 60;
+const OFFSET_PM1A_CONTROL_BLOCK: u64 = 64;
+const OFFSET_PM1B_CONTROL_BLOCK: u64 = 68;
+const OFFSET_PM_TIMER_BLOCK: u64 = 76;
+const OFFSET_CENTURY: u64 = 108;
+const OFFSET_BOOT_ARCHITECTURE: u64 = 109;
+const OFFSET_FLAGS: u64 = 112;
+const OFFSET_RESET_REGISTER: u64 = 116;
+const OFFSET_RESET_VALUE: u64 = 128;
+
+/// Length of the FADT of ACPI 1.0, up to the flags.
+const LENGTH_V1: u32 = 116;
+
+/// Set in [Fadt::flags] if the reset register is supported.
+pub const FLAG_RESET_REGISTER: u32 = 1 << 10;
+/// Set in [Fadt::boot_architecture] if the machine has an 8042 keyboard controller.
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// The address space of registers in system memory.
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+/// The address space of registers in the I/O ports.
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+/// The location of a register, a Generic Address Structure in the ACPI specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// The address space, e.g. [ADDRESS_SPACE_IO].
+    pub address_space: u8,
+    /// The size of the register in bits.
+    pub bit_width: u8,
+    /// The offset of the register in bits.
+    pub bit_offset: u8,
+    /// The access size, 1 for byte access up to 4 for qword access, 0 if undefined.
+    pub access_size: u8,
+    /// The address in the address space.
+    pub address: u64,
+}
+
+/// The fields of the FADT used by the kernel. Blocks are I/O ports, 0 if not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// The physical address of the DSDT.
+    pub dsdt: PhysAddr,
+    /// The legacy interrupt line of the System Control Interrupt.
+    pub sci_interrupt: u16,
+    /// The port taking [Fadt::acpi_enable] and [Fadt::acpi_disable], 0 if ACPI is always enabled.
+    pub smi_command_port: u32,
+    /// Written to the SMI command port to hand the power management registers to the OS.
+    pub acpi_enable: u8,
+    /// Written to the SMI command port to hand the power management registers back.
+    pub acpi_disable: u8,
+    /// The PM1a event block.
+    pub pm1a_event_block: u32,
+    /// The PM1b event block.
+    pub pm1b_event_block: u32,
+    /// The PM1a control block, which puts the machine to sleep.
+    pub pm1a_control_block: u32,
+    /// The PM1b control block.
+    pub pm1b_control_block: u32,
+    /// The PM timer block.
+    pub pm_timer_block: u32,
+    /// The RTC register holding the century, 0 if there is none.
+    pub century: u8,
+    /// Legacy devices on the machine, e.g. [BOOT_ARCH_8042].
+    pub boot_architecture: u16,
+    /// Fixed feature flags, e.g. [FLAG_RESET_REGISTER].
+    pub flags: u32,
+    /// The reset register and the value to write to it, `None` if not supported.
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// Parse the FADT at `table`, `None` if it's too short even for ACPI 1.0.
+    pub fn parse(table: PhysAddr, header: SdtHeader) -> Option<Self> {
+        let length = header.length;
+        if length < LENGTH_V1 {
+            return None;
+        }
+
+        // # Safety
+        // The table has been validated by [super::find_table] and is long enough for the fields
+        // of ACPI 1.0, the reset register is only read from longer tables.
+        unsafe {
+            let flags = read_field::<u32>(table, OFFSET_FLAGS);
+            let reset =
+                if u64::from(length) > OFFSET_RESET_VALUE && flags & FLAG_RESET_REGISTER != 0 {
+                    Some((
+                        read_field::<GenericAddress>(table, OFFSET_RESET_REGISTER),
+                        read_field::<u8>(table, OFFSET_RESET_VALUE),
+                    ))
+                } else {
+                    None
+                };
+
+            Some(Fadt {
+                dsdt: PhysAddr::new(u64::from(read_field::<u32>(table, OFFSET_DSDT))),
+                sci_interrupt: read_field(table, OFFSET_SCI_INTERRUPT),
+                smi_command_port: read_field(table, OFFSET_SMI_COMMAND),
+                acpi_enable: read_field(table, OFFSET_ACPI_ENABLE),
+                acpi_disable: read_field(table, OFFSET_ACPI_DISABLE),
+                pm1a_event_block: read_field(table, OFFSET_PM1A_EVENT_BLOCK),
+                pm1b_event_block: read_field(table, OFFSET_PM1B_EVENT_BLOCK),
+                pm1a_control_block: read_field(table, OFFSET_PM1A_CONTROL_BLOCK),
+                pm1b_control_block: read_field(table, OFFSET_PM1B_CONTROL_BLOCK),
+                pm_timer_block: read_field(table, OFFSET_PM_TIMER_BLOCK),
+                century: read_field(table, OFFSET_CENTURY),
+                boot_architecture: read_field(table, OFFSET_BOOT_ARCHITECTURE),
+                flags,
+                reset,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn power_management_blocks() {
+        let fadt = super::super::fadt().expect("no FADT");
+        assert_ne!(fadt.pm1a_control_block, 0);
+        assert_ne!(fadt.pm_timer_block, 0);
+        // QEMU routes the SCI to IRQ 9 on both the i440FX and the Q35 machines
+        assert_eq!(fadt.sci_interrupt, 9);
+    }
+}
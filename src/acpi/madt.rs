@@ -0,0 +1,240 @@
+//! The Multiple APIC Description Table, signature `APIC`.
+//!
+//! The MADT lists the interrupt controllers of the machine: a local APIC per processor, the I/O
+//! APICs with the ranges of global system interrupts they serve and the overrides of the identity
+//! mapping from the ISA IRQs to global system interrupts. Entries are variable-length records
+//! following the fixed fields, they're read lazily by [Madt::entries].
+
+use x86_64::PhysAddr;
+
+use super::{read_field, SdtHeader};
+
+const OFFSET_LOCAL_APIC_ADDRESS: u64 = 36;
+const OFFSET_FLAGS: u64 = 40;
+const OFFSET_ENTRIES: u64 = 44;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Set in [Madt::flags] if the machine also has the legacy dual 8259 PICs.
+pub const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+/// The polarity bits of the `MPS INTI` flags of an interrupt override when the interrupt is active
+/// low.
+pub const INTI_ACTIVE_LOW: u16 = 0b11;
+/// The trigger mode bits of the `MPS INTI` flags of an interrupt override when the interrupt is
+/// level triggered.
+pub const INTI_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+/// Set in the flags of a local APIC entry if the processor is usable.
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// An entry of the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    /// The local APIC of a processor.
+    LocalApic {
+        /// The ID of the processor in the ACPI namespace.
+        processor_id: u8,
+        /// The ID of the local APIC.
+        apic_id: u8,
+        /// Whether the processor is usable.
+        enabled: bool,
+    },
+    /// An I/O APIC.
+    IoApic {
+        /// The ID of the I/O APIC.
+        id: u8,
+        /// The physical address of its registers.
+        address: PhysAddr,
+        /// The first global system interrupt it serves.
+        gsi_base: u32,
+    },
+    /// An ISA IRQ not identity mapped to the global system interrupts.
+    InterruptOverride {
+        /// The ISA IRQ.
+        irq: u8,
+        /// The global system interrupt it's delivered on.
+        gsi: u32,
+        /// The polarity and trigger mode, `MPS INTI` flags in the ACPI specification.
+        flags: u16,
+    },
+    /// A local APIC input connected to the NMI.
+    LocalApicNmi {
+        /// The ID of the processor in the ACPI namespace, 0xff for all processors.
+        processor_id: u8,
+        /// The polarity and trigger mode.
+        flags: u16,
+        /// The LINT input, 0 or 1.
+        lint: u8,
+    },
+    /// The 64-bit physical address of the local APICs, replacing [Madt::local_apic_address].
+    LocalApicAddressOverride(PhysAddr),
+    /// An entry of another type, carries the type.
+    Other(u8),
+}
+
+/// The parsed fixed fields of the MADT, see [Madt::entries] for the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Madt {
+    table: PhysAddr,
+    length: u64,
+    /// The physical address of the local APICs, unless overridden by an entry.
+    pub local_apic_address: PhysAddr,
+    /// Flags of the machine, e.g. [FLAG_PCAT_COMPAT].
+    pub flags: u32,
+}
+
+impl Madt {
+    /// Parse the MADT at `table`, `None` if it's too short for the fixed fields.
+    pub fn parse(table: PhysAddr, header: SdtHeader) -> Option<Self> {
+        let length = u64::from(header.length);
+        if length < OFFSET_ENTRIES {
+            return None;
+        }
+
+        // # Safety
+        // The table has been validated by [super::find_table] and holds the fixed fields.
+        unsafe {
+            Some(Madt {
+                table,
+                length,
+                local_apic_address: PhysAddr::new(u64::from(read_field::<u32>(
+                    table,
+                    OFFSET_LOCAL_APIC_ADDRESS,
+                ))),
+                flags: read_field(table, OFFSET_FLAGS),
+            })
+        }
+    }
+
+    /// The entries of the table in order.
+    pub fn entries(&self) -> Entries {
+        Entries {
+            table: self.table,
+            offset: OFFSET_ENTRIES,
+            end: self.length,
+        }
+    }
+
+    /// The local APIC IDs of the usable processors.
+    pub fn processors(&self) -> impl Iterator<Item = u8> {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic {
+                apic_id,
+                enabled: true,
+                ..
+            } => Some(apic_id),
+            _ => None,
+        })
+    }
+
+    /// The global system interrupt the ISA IRQ `irq` is delivered on.
+    pub fn irq_to_gsi(&self, irq: u8) -> u32 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::InterruptOverride {
+                    irq: source, gsi, ..
+                } if source == irq => Some(gsi),
+                _ => None,
+            })
+            .unwrap_or_else(|| u32::from(irq))
+    }
+
+    /// The `MPS INTI` flags of the ISA IRQ `irq`, 0 (the defaults of the ISA bus: active high, edge
+    /// triggered) unless overridden.
+    pub fn irq_flags(&self, irq: u8) -> u16 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::InterruptOverride {
+                    irq: source, flags, ..
+                } if source == irq => Some(flags),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Iterator over the entries of the MADT, created by [Madt::entries].
+#[derive(Debug, Clone)]
+pub struct Entries {
+    table: PhysAddr,
+    offset: u64,
+    end: u64,
+}
+
+impl Iterator for Entries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.offset + 2 > self.end {
+            return None;
+        }
+
+        let offset = self.offset;
+        // # Safety
+        // The entry header is inside the table, the fields of an entry are only read if its length
+        // covers them and it fits in the table.
+        unsafe {
+            let kind = read_field::<u8>(self.table, offset);
+            let length = u64::from(read_field::<u8>(self.table, offset + 1));
+            // a corrupted length would loop forever or read past the table
+            if length < 2 || offset + length > self.end {
+                self.offset = self.end;
+                return None;
+            }
+            self.offset += length;
+
+            let entry = match (kind, length) {
+                (ENTRY_LOCAL_APIC, 8..=u64::MAX) => MadtEntry::LocalApic {
+                    processor_id: read_field(self.table, offset + 2),
+                    apic_id: read_field(self.table, offset + 3),
+                    enabled: read_field::<u32>(self.table, offset + 4) & LOCAL_APIC_ENABLED != 0,
+                },
+                (ENTRY_IO_APIC, 12..=u64::MAX) => MadtEntry::IoApic {
+                    id: read_field(self.table, offset + 2),
+                    address: PhysAddr::new(u64::from(read_field::<u32>(self.table, offset + 4))),
+                    gsi_base: read_field(self.table, offset + 8),
+                },
+                (ENTRY_INTERRUPT_OVERRIDE, 10..=u64::MAX) => MadtEntry::InterruptOverride {
+                    irq: read_field(self.table, offset + 3),
+                    gsi: read_field(self.table, offset + 4),
+                    flags: read_field(self.table, offset + 8),
+                },
+                (ENTRY_LOCAL_APIC_NMI, 6..=u64::MAX) => MadtEntry::LocalApicNmi {
+                    processor_id: read_field(self.table, offset + 2),
+                    flags: read_field(self.table, offset + 3),
+                    lint: read_field(self.table, offset + 5),
+                },
+                (ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE, 12..=u64::MAX) => {
+                    MadtEntry::LocalApicAddressOverride(PhysAddr::new(read_field(
+                        self.table,
+                        offset + 4,
+                    )))
+                }
+                _ => MadtEntry::Other(kind),
+            };
+            Some(entry)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn interrupt_controllers() {
+        let madt = super::super::madt().expect("no MADT");
+        assert!(madt.processors().count() >= 1);
+        assert!(madt.entries().any(|entry| matches!(
+            entry,
+            MadtEntry::IoApic { address, .. } if address == PhysAddr::new(0xfec0_0000)
+        )));
+        // QEMU delivers the PIT on GSI 2 through an override of IRQ 0
+        assert_eq!(madt.irq_to_gsi(0), 2);
+        assert_eq!(madt.irq_to_gsi(1), 1);
+        assert_eq!(madt.irq_flags(1), 0);
+    }
+}
@@ -16,14 +16,18 @@ use x86_64::{
     VirtAddr,
 };
 
-use crate::locked::Locked;
+use crate::{
+    info,
+    locked::{LockStats, Locked},
+};
 
 use self::fixed_size_block::FixedSizeBlockAllocator;
 
 /// Start of the kernel heap region in the virtual address space.
 pub const HEAP_START: usize = 0x4444_4444_0000;
 
-/// Size of the kernel heap region in the virtual address space.
+/// Default size of the kernel heap region in the virtual address space, can be overridden by the
+/// `heap_size=` option on the kernel command line.
 pub const HEAP_SIZE: usize = 1024 * 1024;
 
 #[global_allocator]
@@ -42,15 +46,17 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
-/// Initialize the heap region in the virtual address space, map them to physical frames.
+/// Initialize the `heap_size`-byte heap region in the virtual address space, map them to physical
+/// frames.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    heap_size: usize,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         // the same as array indices, the last virtual address in the heap is off by one
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + heap_size - 1u64;
 
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
@@ -76,12 +82,44 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, heap_size);
     }
 
+    info!(
+        "kernel heap mapped at {:#x}, {} KiB",
+        HEAP_START,
+        heap_size / 1024
+    );
+
     Ok(())
 }
 
+/// Collect statistics of the kernel heap allocator.
+pub fn stats() -> fixed_size_block::Stats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Collect statistics of the kernel heap allocator without waiting for its lock, `None` if the
+/// allocator is locked.
+pub fn try_stats() -> Option<fixed_size_block::Stats> {
+    ALLOCATOR.try_lock().map(|allocator| allocator.stats())
+}
+
+/// Whether the kernel heap allocator is locked, by the running code or another processor.
+pub fn is_locked() -> bool {
+    ALLOCATOR.owner().is_some()
+}
+
+/// Check the consistency of the kernel heap allocator, see [FixedSizeBlockAllocator::check].
+pub fn check() -> Result<(), &'static str> {
+    ALLOCATOR.lock().check()
+}
+
+/// Statistics of the lock of the kernel heap allocator.
+pub fn lock_stats() -> LockStats {
+    ALLOCATOR.stats()
+}
+
 /// Align the address `addr` up to the alignment `align`. The returned aligned address is always
 /// greater or equal to `addr`. Return `None` if the supplied alignment is not a power of 2, or the
 /// resulting pointer overflowed.
@@ -109,4 +147,13 @@ mod tests {
         assert_eq!(align_up(0x1010, 0x11), None);
         assert_eq!(align_up(usize::MAX, 0x10), None);
     }
+
+    #[test_case]
+    fn consistent_after_free() {
+        use alloc::{boxed::Box, vec::Vec};
+
+        let boxes: Vec<Box<[u8; 24]>> = (0..16).map(|_| Box::new([0; 24])).collect();
+        drop(boxes);
+        assert_eq!(check(), Ok(()));
+    }
 }
@@ -4,7 +4,11 @@ use core::{
     ptr::{null_mut, NonNull},
 };
 
-use crate::locked::Locked;
+use crate::{
+    fault::{self, Fault},
+    locked::Locked,
+    metrics::{Counter, Histogram},
+};
 
 /// The block sizes to use. To simplify the implementation each block has alignment equal to its
 /// size, as a consequence the block sizes defined here must be a power of 2.
@@ -15,10 +19,36 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+static ALLOCATION_SIZE: Histogram = Histogram::new("heap.allocation_size");
+static FAILED_ALLOCATIONS: Counter = Counter::new("heap.failed_allocations");
+
 /// A fixed-size block allocator, maintains multiple node lists of same sized memory chunks.
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// number of fulfilled allocations
+    allocations: usize,
+    /// number of deallocations
+    deallocations: usize,
+}
+
+/// A snapshot of the state of a [FixedSizeBlockAllocator].
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Start of the heap region.
+    pub heap_start: usize,
+    /// Size of the heap region in bytes.
+    pub heap_size: usize,
+    /// Bytes allocated from the fallback allocator, including free blocks in the node lists.
+    pub fallback_used: usize,
+    /// Bytes free in the fallback allocator.
+    pub fallback_free: usize,
+    /// Pairs of a block size and the number of free blocks of that size in its node list.
+    pub free_blocks: [(usize, usize); BLOCK_SIZES.len()],
+    /// Number of fulfilled allocations since the initialization of the allocator.
+    pub allocations: usize,
+    /// Number of deallocations since the initialization of the allocator.
+    pub deallocations: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -31,6 +61,8 @@ impl FixedSizeBlockAllocator {
             // how is the uniqueness of the possible mutable reference guaranteed in this case?
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocations: 0,
+            deallocations: 0,
         }
     }
 
@@ -43,6 +75,63 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// Collect statistics of the allocator. Walks all the node lists.
+    pub fn stats(&self) -> Stats {
+        let mut free_blocks = [(0, 0); BLOCK_SIZES.len()];
+        for (i, head) in self.list_heads.iter().enumerate() {
+            let mut count = 0;
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                count += 1;
+                node = current.next.as_deref();
+            }
+            free_blocks[i] = (BLOCK_SIZES[i], count);
+        }
+
+        Stats {
+            heap_start: self.fallback_allocator.bottom(),
+            heap_size: self.fallback_allocator.size(),
+            fallback_used: self.fallback_allocator.used(),
+            fallback_free: self.fallback_allocator.free(),
+            free_blocks,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+        }
+    }
+
+    /// Check the consistency of the allocator: every free block lies inside the heap region and is
+    /// aligned to its block size, and the fallback allocator accounts for the whole heap. Returns
+    /// a description of the first inconsistency found.
+    pub fn check(&self) -> Result<(), &'static str> {
+        let bottom = self.fallback_allocator.bottom();
+        let size = self.fallback_allocator.size();
+        if self.fallback_allocator.used() + self.fallback_allocator.free() != size {
+            return Err("fallback allocator lost track of heap memory");
+        }
+
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES.iter()) {
+            // more blocks than fit in the heap means the list has a cycle
+            let mut remaining = size / block_size;
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                let addr = current as *const ListNode as usize;
+                if addr < bottom || addr + block_size > bottom + size {
+                    return Err("free block outside of the heap");
+                }
+                if addr % block_size != 0 {
+                    return Err("free block not aligned to its size");
+                }
+                if remaining == 0 {
+                    return Err("cycle in a free block list");
+                }
+                remaining -= 1;
+                node = current.next.as_deref();
+            }
+        }
+
+        Ok(())
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
@@ -65,9 +154,14 @@ fn list_index(layout: &Layout) -> Option<usize> {
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if fault::should_fail(Fault::Allocation) {
+            FAILED_ALLOCATIONS.inc();
+            return null_mut();
+        }
+
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => match allocator.list_heads[index].take() {
                 Some(node) => {
                     allocator.list_heads[index] = node.next.take();
@@ -87,11 +181,20 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 // the required layout doesn't fit in any predefined block size
                 allocator.fallback_alloc(layout)
             }
+        };
+
+        if ptr.is_null() {
+            FAILED_ALLOCATIONS.inc();
+        } else {
+            allocator.allocations += 1;
+            ALLOCATION_SIZE.record(layout.size() as u64);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.deallocations += 1;
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
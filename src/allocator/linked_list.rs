@@ -26,8 +26,9 @@ impl ListNode {
     }
 }
 
-/// A linked list allocator that embeds its data structures into free chunks. This allocator will
-/// not coalesce free memory chunks.
+/// A linked list allocator that embeds its data structures into free chunks. The free list is kept
+/// in address order, so that a freed chunk is merged with the free chunks right before and after
+/// it.
 pub struct LinkedListAllocator {
     head: ListNode,
 }
@@ -52,7 +53,8 @@ impl LinkedListAllocator {
         self.add_free_region(heap_start, heap_size)
     }
 
-    /// Add the `size`-byte free memory region starting at `addr` to the start of the free list.
+    /// Add the `size`-byte free memory region starting at `addr` to the free list, merged with the
+    /// free regions it's adjacent to.
     ///
     /// # Safety
     /// This function is unsafe because the caller must guarantee that the given memory region is
@@ -62,8 +64,41 @@ impl LinkedListAllocator {
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), Some(addr));
         assert!(size >= mem::size_of::<ListNode>());
 
+        // the last region before `addr`, or the dummy head which is never merged with
+        let mut current = &mut self.head;
+        let mut at_head = true;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+            at_head = false;
+        }
+        let overlaps = (!at_head && current.end_addr() > addr)
+            || current
+                .next
+                .as_ref()
+                .map_or(false, |next| next.start_addr() < addr + size);
+        assert!(!overlaps, "freed region {:#x} overlaps a free region", addr);
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
+        if node
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() == addr + size)
+        {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        if !at_head && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next;
+            return;
+        }
 
         let node_ptr = addr as *mut ListNode;
         // # Safety
@@ -77,7 +112,20 @@ impl LinkedListAllocator {
         //
         // The instance is only invalidated after its references removed from the free list, in
         // all cases references to [ListNode] existing in the free list point to valid instances.
-        self.head.next = node_ptr.as_mut();
+        current.next = node_ptr.as_mut();
+    }
+
+    /// The number of regions in the free list and their total size.
+    pub fn free_regions(&self) -> (usize, usize) {
+        let mut count = 0;
+        let mut size = 0;
+        let mut current = &self.head;
+        while let Some(region) = &current.next {
+            count += 1;
+            size += region.size;
+            current = region;
+        }
+        (count, size)
     }
 
     /// Looks for a free region with the given size and alignment and removes it from the list.
@@ -103,7 +151,18 @@ impl LinkedListAllocator {
 }
 
 fn alloc_from_region(region: &ListNode, layout: Layout) -> Option<usize> {
-    let alloc_start = align_up(region.start_addr(), layout.align())?;
+    let mut alloc_start = align_up(region.start_addr(), layout.align())?;
+    if alloc_start > region.start_addr()
+        && alloc_start - region.start_addr() < mem::size_of::<ListNode>()
+    {
+        // the padding before the allocation is returned to the free list, it must hold a ListNode
+        alloc_start = align_up(
+            region
+                .start_addr()
+                .checked_add(mem::size_of::<ListNode>())?,
+            layout.align(),
+        )?;
+    }
     let alloc_end = alloc_start.checked_add(layout.size())?;
 
     if alloc_end > region.end_addr() {
@@ -144,9 +203,12 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
                 let alloc_end = alloc_start
                     .checked_add(layout.size())
                     .expect("allocation overflow");
-                let excess_size = region.end_addr() - alloc_end;
-                if excess_size > 0 {
-                    allocator.add_free_region(alloc_end, excess_size);
+                let (region_start, region_end) = (region.start_addr(), region.end_addr());
+                if alloc_start > region_start {
+                    allocator.add_free_region(region_start, alloc_start - region_start);
+                }
+                if region_end > alloc_end {
+                    allocator.add_free_region(alloc_end, region_end - alloc_end);
                 }
                 alloc_start as *mut u8
             }
@@ -159,3 +221,84 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         self.lock().add_free_region(ptr as usize, aligned.size());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    const HEAP_SIZE: usize = 16 * 1024;
+
+    /// Run `f` on an allocator managing a fresh page-aligned `HEAP_SIZE`-byte heap.
+    fn with_allocator(f: impl FnOnce(&Locked<LinkedListAllocator>)) {
+        let mut memory = vec![0u8; HEAP_SIZE + 4096];
+        let start = align_up(memory.as_mut_ptr() as usize, 4096).unwrap();
+        let allocator = Locked::new(LinkedListAllocator::new());
+        // # Safety
+        // The memory is owned by this function and outlives the allocator.
+        unsafe { allocator.lock().init(start, HEAP_SIZE) };
+        f(&allocator);
+    }
+
+    #[test_case]
+    fn free_regions_merged() {
+        with_allocator(|allocator| {
+            let sizes = [24, 200, 8, 1000, 64, 16, 512];
+            let layouts: Vec<Layout> = sizes
+                .iter()
+                .cycle()
+                .take(40)
+                .map(|&size| Layout::from_size_align(size, 8).unwrap())
+                .collect();
+            // # Safety
+            // Each pointer is freed once with the layout it was allocated with.
+            unsafe {
+                let ptrs: Vec<*mut u8> = layouts
+                    .iter()
+                    .map(|&layout| allocator.alloc(layout))
+                    .collect();
+                assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+
+                // every other chunk first, none of them adjacent
+                for (&ptr, &layout) in ptrs.iter().zip(&layouts).step_by(2) {
+                    allocator.dealloc(ptr, layout);
+                }
+                assert_eq!(allocator.lock().free_regions().0, 21);
+                for (&ptr, &layout) in ptrs.iter().zip(&layouts).skip(1).step_by(2) {
+                    allocator.dealloc(ptr, layout);
+                }
+            }
+            assert_eq!(allocator.lock().free_regions(), (1, HEAP_SIZE));
+
+            let whole = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+            // # Safety
+            // As above.
+            unsafe {
+                let ptr = allocator.alloc(whole);
+                assert!(!ptr.is_null());
+                allocator.dealloc(ptr, whole);
+            }
+        });
+    }
+
+    #[test_case]
+    fn alignment_padding_freed() {
+        with_allocator(|allocator| {
+            let small = Layout::from_size_align(8, 8).unwrap();
+            let aligned = Layout::from_size_align(64, 256).unwrap();
+            // # Safety
+            // Each pointer is freed once with the layout it was allocated with.
+            unsafe {
+                let first = allocator.alloc(small);
+                let second = allocator.alloc(aligned);
+                assert_eq!(second as usize, first as usize + 256);
+                // the padding before the aligned chunk and the rest of the heap
+                assert_eq!(allocator.lock().free_regions().0, 2);
+
+                allocator.dealloc(second, aligned);
+                allocator.dealloc(first, small);
+            }
+            assert_eq!(allocator.lock().free_regions(), (1, HEAP_SIZE));
+        });
+    }
+}
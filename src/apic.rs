@@ -0,0 +1,260 @@
+//! The local APIC of the bootstrap processor, used for its timer.
+//!
+//! The external interrupts are delivered through the local APIC either by the I/O APIC, see
+//! [crate::ioapic], or without one by the legacy PICs in virtual wire mode. [eoi] acknowledges the
+//! interrupts generated by the local APIC itself and those of the I/O APIC. The timer of the local
+//! APIC counts down from an initial count at a rate calibrated against the TSC, or on processors
+//! supporting it fires when the TSC reaches a deadline, see [set_tsc_deadline].
+//!
+//! The local APIC also sends the inter-processor interrupts starting the application processors
+//! and those coordinating the processors once started, see [crate::smp].
+
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::Msr,
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{memory, time::tsc};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+const APIC_BASE_MASK: u64 = 0xf_ffff_f000;
+
+const REG_ID: u64 = 0x020;
+const REG_EOI: u64 = 0x0b0;
+const REG_SPURIOUS: u64 = 0x0f0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_TIMER_INITIAL_COUNT: u64 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u64 = 0x390;
+const REG_TIMER_DIVIDE: u64 = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DESTINATION_SHIFT: u32 = 24;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// The timer counts down once every 16 bus cycles.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// The vector of spurious interrupts, its low 4 bits must be set on older processors.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The time the timer is calibrated over.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+static BASE: OnceCell<VirtAddr> = OnceCell::uninit();
+/// Frequency of the timer count with [TIMER_DIVIDE_BY_16], 0 before calibration.
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: u64) -> u32 {
+    let base = *BASE.try_get().expect("the local APIC is not initialized");
+    // # Safety
+    // `reg` is an offset into the mapped register page, registers are accessed as 32-bit words.
+    unsafe { ptr::read_volatile((base + reg).as_ptr()) }
+}
+
+fn write(reg: u64, value: u32) {
+    let base = *BASE.try_get().expect("the local APIC is not initialized");
+    // # Safety
+    // Same as [read].
+    unsafe { ptr::write_volatile((base + reg).as_mut_ptr(), value) }
+}
+
+/// Whether the processor has a local APIC, reported by CPUID.
+pub fn is_present() -> bool {
+    // # Safety
+    // CPUID is available on all x86_64 processors, leaf 1 always exists.
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// Whether the timer supports the TSC-deadline mode, reported by CPUID.
+pub fn supports_tsc_deadline() -> bool {
+    // # Safety
+    // Same as [is_present].
+    unsafe { __cpuid(1).ecx & (1 << 24) != 0 }
+}
+
+/// Map the registers of the local APIC, software-enable it and calibrate its timer. Must be called
+/// after the TSC is calibrated. Returns `false` if the processor has no local APIC or its
+/// registers can't be mapped.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> bool {
+    if !is_present() {
+        return false;
+    }
+
+    // # Safety
+    // IA32_APIC_BASE exists on all processors with a local APIC.
+    let phys = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_MASK;
+    // # Safety
+    // The register page of the local APIC is not memory handed out by the frame allocator.
+    let base = match unsafe { memory::map_mmio(mapper, frame_allocator, PhysAddr::new(phys), 4096) }
+    {
+        Ok(base) => base,
+        Err(_) => return false,
+    };
+    if BASE.try_init_once(|| base).is_err() {
+        return false;
+    }
+
+    write(
+        REG_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+    stop_timer();
+    calibrate_timer();
+    true
+}
+
+/// Software-enable the local APIC of an application processor, its registers are mapped by [init]
+/// at the same address as those of the bootstrap processor.
+pub fn init_ap() {
+    write(
+        REG_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// Whether [init] succeeded.
+pub fn is_initialized() -> bool {
+    BASE.is_initialized()
+}
+
+/// The ID of the local APIC, the destination of the interrupts delivered to this processor.
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+/// Measure the rate of the timer count over [CALIBRATION_TIME] of the TSC.
+fn calibrate_timer() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+
+    let deadline = tsc::rdtsc() + tsc::nanos_to_cycles(CALIBRATION_TIME.as_nanos() as u64);
+    write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+    while tsc::rdtsc() < deadline {
+        core::hint::spin_loop();
+    }
+    let counted = u64::from(u32::MAX - read(REG_TIMER_CURRENT_COUNT));
+    write(REG_TIMER_INITIAL_COUNT, 0);
+
+    let hz = counted * 1_000_000_000 / CALIBRATION_TIME.as_nanos() as u64;
+    TIMER_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// The calibrated frequency of the timer count, 0 before [init].
+pub fn timer_frequency_hz() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Fire the interrupt `vector` every `period`, returns the actual period which is rounded to whole
+/// timer counts.
+pub fn set_timer_periodic(vector: u8, period: Duration) -> Duration {
+    let hz = u128::from(timer_frequency_hz());
+    let count = (period.as_nanos() * hz / 1_000_000_000).clamp(1, u128::from(u32::MAX));
+
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(vector));
+    write(REG_TIMER_INITIAL_COUNT, count as u32);
+    Duration::from_nanos((count * 1_000_000_000 / hz) as u64)
+}
+
+/// The initial and the current count of the timer, the current count counts down to 0 from the
+/// initial count and reloads in the periodic mode.
+pub fn timer_counts() -> (u32, u32) {
+    (read(REG_TIMER_INITIAL_COUNT), read(REG_TIMER_CURRENT_COUNT))
+}
+
+/// Switch the timer to the TSC-deadline mode firing the interrupt `vector`, the timer is disarmed
+/// until [set_tsc_deadline]. The caller must check [supports_tsc_deadline].
+pub fn set_timer_tsc_deadline(vector: u8) {
+    write(REG_LVT_TIMER, LVT_TIMER_TSC_DEADLINE | u32::from(vector));
+}
+
+/// Fire the timer interrupt once the TSC reaches `deadline`, 0 disarms the timer. A deadline in
+/// the past fires immediately. Only effective in the TSC-deadline mode.
+pub fn set_tsc_deadline(deadline: u64) {
+    // # Safety
+    // IA32_TSC_DEADLINE exists on processors supporting the TSC-deadline mode, writing it only
+    // arms the timer.
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) }
+}
+
+/// Stop and mask the timer.
+pub fn stop_timer() {
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_TIMER_INITIAL_COUNT, 0);
+}
+
+/// Send the interrupt command `command` to the local APIC `apic_id`, returns once it's delivered.
+fn send_ipi(apic_id: u8, command: u32) {
+    // the two halves of the command register must not be interleaved by a handler sending another
+    interrupts::without_interrupts(|| {
+        write(REG_ICR_HIGH, u32::from(apic_id) << ICR_DESTINATION_SHIFT);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Send an INIT IPI to the processor of the local APIC `apic_id`, which resets it to wait for a
+/// startup IPI.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a startup IPI to the processor of the local APIC `apic_id`, which starts in real mode at
+/// the physical address `page << 12`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(
+        apic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page),
+    );
+}
+
+/// Send the interrupt `vector` to the processor of the local APIC `apic_id`, its handler must
+/// signal the end of the interrupt with [eoi].
+pub fn send_fixed(apic_id: u8, vector: u8) {
+    send_ipi(
+        apic_id,
+        ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | u32::from(vector),
+    );
+}
+
+/// Signal the end of an interrupt generated by the local APIC, must be called by its interrupt
+/// handlers except for the spurious interrupt.
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn timer_calibrated() {
+        // QEMU always emulates a local APIC
+        assert!(is_initialized());
+        assert!(timer_frequency_hz() > 0);
+        assert_eq!(read(REG_LVT_TIMER) & LVT_MASKED, LVT_MASKED);
+    }
+}
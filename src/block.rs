@@ -0,0 +1,212 @@
+//! Block devices, storage read and written in whole sectors.
+//!
+//! Drivers implement [BlockDevice], reads and writes are futures completed by the interrupts of
+//! the device so that a task waiting for the disk doesn't hold up the others.
+//!
+//! Drivers [register] their devices on initialization under a name made of the prefix of the
+//! driver and a letter in the order of registration, as on Linux: `hda` for the first ATA disk,
+//! `sdb` for the second AHCI disk, then `sdz`, `sdaa` and so on. Consumers such as filesystems
+//! look devices up by name with [get] instead of depending on a driver.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{fmt, future::Future, pin::Pin};
+
+use spin::Mutex;
+
+pub mod ahci;
+pub mod ata;
+
+/// The size of a sector in bytes, the unit of addressing of block devices.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The prefix of the names of the devices, which may be given to [get].
+pub const NAME_PREFIX: &str = "/dev/";
+
+/// The registered devices, in the order of registration.
+static DEVICES: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// A device in the registry.
+struct Registered {
+    /// The prefix of the driver.
+    prefix: &'static str,
+    name: String,
+    device: &'static dyn BlockDevice,
+}
+
+/// The future of a read or a write of a [BlockDevice].
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+/// Errors of the requests to a [BlockDevice].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last sector of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    PartialSector(usize),
+    /// The device reported an error, with its status and error registers.
+    Device {
+        /// The status register of the device.
+        status: u8,
+        /// The error register of the device.
+        error: u8,
+    },
+    /// The device didn't answer in time.
+    Timeout,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "request past the end of the device"),
+            BlockError::PartialSector(len) => {
+                write!(
+                    f,
+                    "buffer of {} bytes is not a whole number of sectors",
+                    len
+                )
+            }
+            BlockError::Device { status, error } => write!(
+                f,
+                "device error, status {:#04x}, error {:#04x}",
+                status, error
+            ),
+            BlockError::Timeout => write!(f, "device timed out"),
+        }
+    }
+}
+
+/// A device storing data in sectors of [SECTOR_SIZE] bytes addressed by their logical block
+/// address (LBA), from 0 to [BlockDevice::sector_count] excluded.
+pub trait BlockDevice: Send + Sync {
+    /// The number of sectors of the device.
+    fn sector_count(&self) -> u64;
+
+    /// Read the sectors from `lba` on into `buf`, a whole number of sectors.
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a>;
+
+    /// Write `buf`, a whole number of sectors, to the sectors from `lba` on.
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a>;
+}
+
+/// The registered devices are shared as references, e.g. in an `Arc<dyn BlockDevice>` alongside
+/// devices owned by their users.
+impl<D: BlockDevice + ?Sized> BlockDevice for &'static D {
+    fn sector_count(&self) -> u64 {
+        (**self).sector_count()
+    }
+
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        (**self).read(lba, buf)
+    }
+
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        (**self).write(lba, buf)
+    }
+}
+
+/// Check a request of `len` bytes from `lba` on against a device of `sector_count` sectors,
+/// returns the number of sectors requested.
+pub fn check_request(sector_count: u64, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if len % SECTOR_SIZE != 0 {
+        return Err(BlockError::PartialSector(len));
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= sector_count => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// The name of the device `index` (from 0) of the driver with the prefix `prefix`, e.g. `sda`.
+fn device_name(prefix: &str, index: usize) -> String {
+    // bijective base 26: a to z, then aa to zz, and so on
+    let mut letters = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        letters.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    let mut name = String::from(prefix);
+    name.extend(letters.iter().rev().map(|&letter| char::from(letter)));
+    name
+}
+
+/// Register `device` under the next free name with the prefix `prefix`, e.g. `vd` for virtio
+/// disks, returns the name.
+pub fn register(prefix: &'static str, device: &'static dyn BlockDevice) -> String {
+    let mut devices = DEVICES.lock();
+    let index = devices
+        .iter()
+        .filter(|registered| registered.prefix == prefix)
+        .count();
+    let name = device_name(prefix, index);
+    devices.push(Registered {
+        prefix,
+        name: name.clone(),
+        device,
+    });
+    name
+}
+
+/// The device registered as `name`, with or without [NAME_PREFIX].
+pub fn get(name: &str) -> Option<&'static dyn BlockDevice> {
+    let name = name.strip_prefix(NAME_PREFIX).unwrap_or(name);
+    DEVICES
+        .lock()
+        .iter()
+        .find(|registered| registered.name == name)
+        .map(|registered| registered.device)
+}
+
+/// The names of the registered devices, in the order of registration.
+pub fn names() -> Vec<String> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|registered| registered.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn requests_checked() {
+        assert_eq!(check_request(8, 6, 2 * SECTOR_SIZE), Ok(2));
+        assert_eq!(check_request(8, 0, 0), Ok(0));
+        assert_eq!(
+            check_request(8, 7, 2 * SECTOR_SIZE),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            check_request(8, u64::MAX, SECTOR_SIZE),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            check_request(8, 0, 100),
+            Err(BlockError::PartialSector(100))
+        );
+    }
+
+    #[test_case]
+    fn names_as_on_linux() {
+        assert_eq!(device_name("sd", 0), "sda");
+        assert_eq!(device_name("sd", 25), "sdz");
+        assert_eq!(device_name("sd", 26), "sdaa");
+        assert_eq!(device_name("vd", 27), "vdab");
+        assert_eq!(device_name("sd", 26 + 26 * 26), "sdaaa");
+    }
+
+    #[test_case]
+    fn drivers_registered() {
+        // the boot disk on the primary ATA channel and the test disk on the AHCI controller
+        let hda = get("/dev/hda").expect("the boot disk is not registered");
+        assert_eq!(hda.sector_count(), ata::disks()[0].sector_count());
+        let sda = get("sda").expect("the test disk is not registered");
+        assert_eq!(sda.sector_count(), ahci::disks()[0].sector_count());
+        assert!(get("sdz").is_none());
+        assert!(names().iter().any(|name| name == "hda"));
+    }
+}
@@ -0,0 +1,621 @@
+//! Driver of the disks on an AHCI (SATA) controller, `-device ahci` on QEMU.
+//!
+//! The registers of the controller (the HBA) are mapped from its BAR 5 (ABAR), with a block of
+//! registers for each of its 32 ports. A port fetches commands from a command list in memory and
+//! posts the FISes received from its drive to a FIS receive area, both set up by the driver in DMA
+//! memory along with a command table holding the FIS of the command and the list of its data
+//! buffers (PRDT). Only the first slot of the command list is used, without native command
+//! queuing: a port runs one command at a time, moving data through a bounce buffer of its own.
+//! Commands complete with an interrupt of the controller, which acknowledges the status of every
+//! port and wakes the command waiting on it. Drives are identified on boot with polling.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{check_request, BlockDevice, BlockError, BlockFuture, SECTOR_SIZE};
+use crate::{
+    dma::{self, DmaBuffer},
+    info, interrupts, memory,
+    pci::{self, Bar},
+    task::mutex::AsyncMutex,
+    time, warn,
+};
+
+/// The class codes of AHCI controllers.
+const CLASS_MASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+/// The BAR of the registers of the controller.
+const ABAR: usize = 5;
+
+/// Registers of the HBA.
+const REG_GHC: u64 = 0x04;
+const REG_IS: u64 = 0x08;
+const REG_PI: u64 = 0x0c;
+const REG_VS: u64 = 0x10;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+/// The registers of port `n` are at `PORTS_OFFSET + n * PORT_SIZE`.
+const PORTS_OFFSET: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const MAX_PORTS: usize = 32;
+
+/// Registers of a port.
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0c;
+const PORT_IS: u64 = 0x10;
+const PORT_IE: u64 = 0x14;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+/// Device to host register FIS interrupt, raised when a command completes.
+const IS_DHRS: u32 = 1 << 0;
+/// Task file error interrupt, raised when a command fails.
+const IS_TFES: u32 = 1 << 30;
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+/// Device detected with communication established, in the DET field of SSTS.
+const SSTS_DET_PRESENT: u32 = 3;
+/// Interface in the active state, in the IPM field of SSTS.
+const SSTS_IPM_ACTIVE: u32 = 1;
+/// The signature of SATA drives, packet devices have their own.
+const SIG_ATA: u32 = 0x0000_0101;
+
+/// Layout of the page of DMA memory of a port: the command list of 32 headers of 32 bytes, the
+/// FIS receive area and the command table of the only slot used.
+const COMMAND_LIST_OFFSET: u64 = 0;
+const FIS_OFFSET: u64 = 1024;
+const TABLE_OFFSET: u64 = 2048;
+/// Offset of the PRDT in the command table, after the command FIS and the ATAPI command.
+const PRDT_OFFSET: u64 = 0x80;
+/// The size of a command header.
+const HEADER_SIZE: u64 = 32;
+/// The slot of the command list used for every command.
+const SLOT: u32 = 0;
+
+/// Set in the first word of a command header for commands writing to the device.
+const HEADER_WRITE: u32 = 1 << 6;
+/// Host to device register FIS, 5 double words.
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_H2D_DWORDS: u32 = 5;
+/// Set in a host to device register FIS carrying a command.
+const FIS_COMMAND: u8 = 1 << 7;
+/// Set in the device register for LBA addressing.
+const DEVICE_LBA: u8 = 1 << 6;
+
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// Set in word 83 of the identify data of drives supporting 48-bit LBA.
+const IDENTIFY_LBA48: u16 = 1 << 10;
+
+/// The size of the bounce buffer of a port, the most a command transfers.
+const BOUNCE_SIZE: usize = 32 * 1024;
+/// The prefix of the names of the drives in the [super] registry.
+pub const NAME_PREFIX: &str = "sd";
+
+/// Number of polls of a register before a port is given up, 10 microseconds apart.
+const POLLS: usize = 100_000;
+/// The longest a command waits for its completion.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CONTROLLER: OnceCell<Controller> = OnceCell::uninit();
+
+/// The first AHCI controller and the drives on its ports.
+struct Controller {
+    abar: VirtAddr,
+    disks: Vec<AhciDisk>,
+}
+
+/// A drive identified on a port of the controller.
+pub struct AhciDisk {
+    port: u8,
+    /// The registers of the port.
+    base: VirtAddr,
+    /// The command list, the FIS receive area and the command table.
+    memory: DmaBuffer,
+    bounce: DmaBuffer,
+    sectors: u64,
+    model: String,
+    /// Held for the whole of a command.
+    lock: AsyncMutex<()>,
+    /// The interrupt status of the port acknowledged by the interrupt handler, cleared by the
+    /// command waiting for it.
+    irq_status: AtomicU32,
+    waker: AtomicWaker,
+}
+
+impl fmt::Debug for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AhciDisk")
+            .field("port", &self.port)
+            .field("sectors", &self.sectors)
+            .field("model", &self.model)
+            .finish()
+    }
+}
+
+fn read(base: VirtAddr, reg: u64) -> u32 {
+    // # Safety
+    // `base` is a block of registers of the controller mapped by [init].
+    unsafe { ptr::read_volatile((base + reg).as_ptr::<u32>()) }
+}
+
+fn write(base: VirtAddr, reg: u64, value: u32) {
+    // # Safety
+    // As above, the controller only accesses the memory set up for its ports.
+    unsafe { ptr::write_volatile((base + reg).as_mut_ptr::<u32>(), value) }
+}
+
+/// Poll the register `reg` until `done` holds for it, returns whether it did in time.
+fn poll(base: VirtAddr, reg: u64, done: impl Fn(u32) -> bool) -> bool {
+    for _ in 0..POLLS {
+        if done(read(base, reg)) {
+            return true;
+        }
+        time::delay_us(10);
+    }
+    false
+}
+
+impl AhciDisk {
+    /// Set up the port `port` at `base` and identify its drive, `None` if there is no SATA drive
+    /// on the port or the DMA pool is exhausted.
+    fn new(port: u8, base: VirtAddr) -> Option<Self> {
+        let ssts = read(base, PORT_SSTS);
+        if ssts & 0xf != SSTS_DET_PRESENT
+            || (ssts >> 8) & 0xf != SSTS_IPM_ACTIVE
+            || read(base, PORT_SIG) != SIG_ATA
+        {
+            return None;
+        }
+
+        let mut disk = AhciDisk {
+            port,
+            base,
+            memory: dma::alloc(4096)?,
+            bounce: dma::alloc(BOUNCE_SIZE as u64)?,
+            sectors: 0,
+            model: String::new(),
+            lock: AsyncMutex::new(()),
+            irq_status: AtomicU32::new(0),
+            waker: AtomicWaker::new(),
+        };
+        if !disk.start() {
+            warn!("ahci port {} failed to start", port);
+            return None;
+        }
+        if let Err(err) = disk.identify() {
+            warn!("ahci port {} failed to identify its drive: {}", port, err);
+            return None;
+        }
+        Some(disk)
+    }
+
+    /// Stop the port, returns whether it stopped in time.
+    fn stop(&self) -> bool {
+        let cmd = read(self.base, PORT_CMD);
+        write(self.base, PORT_CMD, cmd & !CMD_ST);
+        if !poll(self.base, PORT_CMD, |cmd| cmd & CMD_CR == 0) {
+            return false;
+        }
+        write(self.base, PORT_CMD, read(self.base, PORT_CMD) & !CMD_FRE);
+        poll(self.base, PORT_CMD, |cmd| cmd & CMD_FR == 0)
+    }
+
+    /// Point the port to its memory, clear its errors and start it, returns whether it started.
+    fn start(&self) -> bool {
+        if !self.stop() {
+            return false;
+        }
+        let split = |addr: PhysAddr| (addr.as_u64() as u32, (addr.as_u64() >> 32) as u32);
+        let (low, high) = split(self.memory.phys_addr() + COMMAND_LIST_OFFSET);
+        write(self.base, PORT_CLB, low);
+        write(self.base, PORT_CLBU, high);
+        let (low, high) = split(self.memory.phys_addr() + FIS_OFFSET);
+        write(self.base, PORT_FB, low);
+        write(self.base, PORT_FBU, high);
+
+        // both are cleared by writing their set bits back
+        write(self.base, PORT_SERR, u32::MAX);
+        write(self.base, PORT_IS, u32::MAX);
+        write(self.base, PORT_CMD, read(self.base, PORT_CMD) | CMD_FRE);
+        if !poll(self.base, PORT_TFD, |tfd| tfd & (TFD_BSY | TFD_DRQ) == 0) {
+            return false;
+        }
+        write(self.base, PORT_CMD, read(self.base, PORT_CMD) | CMD_ST);
+        true
+    }
+
+    fn identify(&mut self) -> Result<(), BlockError> {
+        self.prepare(COMMAND_IDENTIFY, 0, 0, SECTOR_SIZE, false);
+        write(self.base, PORT_CI, 1 << SLOT);
+        if !poll(self.base, PORT_CI, |ci| ci & (1 << SLOT) == 0) {
+            return Err(BlockError::Timeout);
+        }
+        self.result()?;
+
+        let mut data = [0; SECTOR_SIZE];
+        self.bounce.read_bytes(0, &mut data);
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]);
+        self.sectors = if word(83) & IDENTIFY_LBA48 != 0 {
+            (100..104)
+                .rev()
+                .fold(0, |sectors, i| sectors << 16 | u64::from(word(i)))
+        } else {
+            u64::from(word(60)) | u64::from(word(61)) << 16
+        };
+        // the model is a string of 40 characters padded with spaces, in big-endian words
+        for i in 27..47 {
+            let [first, second] = word(i).to_be_bytes();
+            self.model.push(char::from(first));
+            self.model.push(char::from(second));
+        }
+        self.model.truncate(self.model.trim_end().len());
+        Ok(())
+    }
+
+    /// The model of the drive as it reports it.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// The port of the controller the drive is attached to.
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    /// Write the command header, the command FIS and the PRDT of `command` on `count` sectors
+    /// from `lba` on, moving `len` bytes through the bounce buffer.
+    fn prepare(&self, command: u8, lba: u64, count: u16, len: usize, to_device: bool) {
+        debug_assert!(len <= BOUNCE_SIZE);
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_H2D;
+        fis[1] = FIS_COMMAND;
+        fis[2] = command;
+        fis[7] = DEVICE_LBA;
+        let lba = lba.to_le_bytes();
+        fis[4..7].copy_from_slice(&lba[..3]);
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+
+        let prdt_entries = if len > 0 { 1 } else { 0 };
+        let flags = if to_device { HEADER_WRITE } else { 0 };
+        let table = self.memory.phys_at(TABLE_OFFSET);
+        let header = [
+            prdt_entries << 16 | flags | FIS_H2D_DWORDS,
+            // the number of bytes transferred, updated by the controller
+            0,
+            table.as_u64() as u32,
+            (table.as_u64() >> 32) as u32,
+        ];
+        let bounce = self.bounce.phys_addr().as_u64();
+        let prd = [
+            bounce as u32,
+            (bounce >> 32) as u32,
+            0,
+            // the byte count is stored minus 1, it must be even
+            (len.max(2) - 1) as u32,
+        ];
+
+        // the controller doesn't read the slot until it's issued
+        self.memory.write_bytes(TABLE_OFFSET, &fis);
+        self.memory
+            .write(COMMAND_LIST_OFFSET + u64::from(SLOT) * HEADER_SIZE, header);
+        self.memory.write(TABLE_OFFSET + PRDT_OFFSET, prd);
+        self.memory.sync_for_device();
+    }
+
+    /// The outcome of the last command according to the task file of the port.
+    fn result(&self) -> Result<(), BlockError> {
+        let tfd = read(self.base, PORT_TFD);
+        if tfd & (TFD_ERR | TFD_BSY | TFD_DRQ) != 0 {
+            Err(BlockError::Device {
+                status: tfd as u8,
+                error: (tfd >> 8) as u8,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Issue the command prepared in the slot and wait for its completion. A failed command
+    /// leaves the port stopped, it's restarted before returning the error.
+    async fn run(&self) -> Result<(), BlockError> {
+        self.irq_status.store(0, Ordering::SeqCst);
+        write(self.base, PORT_CI, 1 << SLOT);
+        let outcome = match (Completion {
+            disk: self,
+            deadline: time::ticks() + time::duration_to_ticks(COMMAND_TIMEOUT),
+            timer_set: false,
+        })
+        .await
+        {
+            Ok(()) => self.result(),
+            Err(err) => Err(err),
+        };
+        if outcome.is_err() && !self.start() {
+            warn!("ahci port {} failed to restart", self.port);
+        }
+        outcome
+    }
+
+    async fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        let _guard = self.lock.lock().await;
+
+        let mut lba = lba;
+        for chunk in buf.chunks_mut(BOUNCE_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.prepare(COMMAND_READ_DMA_EXT, lba, count as u16, chunk.len(), false);
+            self.run().await?;
+            self.bounce.sync_for_cpu();
+            self.bounce.read_bytes(0, chunk);
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    async fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.lock.lock().await;
+
+        let mut lba = lba;
+        for chunk in buf.chunks(BOUNCE_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.bounce.write_bytes(0, chunk);
+            self.prepare(COMMAND_WRITE_DMA_EXT, lba, count as u16, chunk.len(), true);
+            self.run().await?;
+            lba += count as u64;
+        }
+
+        // the drive may hold the data in its write cache until flushed
+        self.prepare(COMMAND_FLUSH_CACHE_EXT, 0, 0, 0, false);
+        self.run().await
+    }
+}
+
+/// The future of the completion of the command issued on a port.
+struct Completion<'a> {
+    disk: &'a AhciDisk,
+    deadline: u64,
+    timer_set: bool,
+}
+
+impl Completion<'_> {
+    fn check(&self) -> Option<Result<(), BlockError>> {
+        let disk = self.disk;
+        if disk.irq_status.load(Ordering::SeqCst) & IS_TFES != 0 {
+            Some(Err(disk.result().err().unwrap_or(BlockError::Device {
+                status: 0,
+                error: 0,
+            })))
+        } else if read(disk.base, PORT_CI) & (1 << SLOT) == 0 {
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Future for Completion<'_> {
+    type Output = Result<(), BlockError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(outcome) = self.check() {
+            return Poll::Ready(outcome);
+        }
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(Err(BlockError::Timeout));
+        }
+
+        self.disk.waker.register(cx.waker());
+        if !self.timer_set {
+            time::wheel::register(self.deadline, cx.waker().clone());
+            self.timer_set = true;
+        }
+
+        // the command may have completed after the first check
+        match self.check() {
+            Some(outcome) => {
+                self.disk.waker.take();
+                Poll::Ready(outcome)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(self.read_sectors(lba, buf))
+    }
+
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(self.write_sectors(lba, buf))
+    }
+}
+
+/// Acknowledge the interrupts of the ports of the controller and wake the commands waiting on
+/// them.
+fn handle_interrupt() {
+    let controller = match CONTROLLER.try_get() {
+        Ok(controller) => controller,
+        Err(_) => return,
+    };
+    // the line may be shared, the status tells whether the controller raised the interrupt
+    let pending = read(controller.abar, REG_IS);
+    if pending == 0 {
+        return;
+    }
+    for disk in &controller.disks {
+        if pending & (1 << disk.port) != 0 {
+            let status = read(disk.base, PORT_IS);
+            write(disk.base, PORT_IS, status);
+            disk.irq_status.fetch_or(status, Ordering::SeqCst);
+            disk.waker.wake();
+        }
+    }
+    // the status of the controller is cleared after those of the ports
+    write(controller.abar, REG_IS, pending);
+}
+
+/// Set up the first AHCI controller if there is one and identify the drives on its ports, must
+/// be called once after [pci::init] and [dma::init].
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let device = match pci::find_class(CLASS_MASS_STORAGE, SUBCLASS_SATA)
+        .find(|device| device.prog_if == PROG_IF_AHCI)
+    {
+        Some(device) => device,
+        None => return,
+    };
+    let (phys, size) = match device.bars[ABAR] {
+        Bar::Memory { address, size, .. } => (PhysAddr::new(address), size),
+        _ => {
+            warn!("ahci at {} has no register BAR", device.address);
+            return;
+        }
+    };
+    // # Safety
+    // The BAR is a range of device registers assigned by the firmware.
+    let abar = match unsafe { memory::map_mmio(mapper, frame_allocator, phys, size) } {
+        Ok(abar) => abar,
+        Err(_) => {
+            warn!("failed to map the registers of ahci at {}", device.address);
+            return;
+        }
+    };
+    device.enable_bus_mastering();
+
+    write(abar, REG_GHC, read(abar, REG_GHC) | GHC_AE);
+    let version = read(abar, REG_VS);
+    info!(
+        "ahci {}.{} at {}",
+        version >> 16,
+        version & 0xffff,
+        device.address
+    );
+
+    let implemented = read(abar, REG_PI);
+    let disks: Vec<_> = (0..MAX_PORTS as u8)
+        .filter(|port| implemented & (1 << port) != 0)
+        .filter_map(|port| {
+            let base = abar + PORTS_OFFSET + u64::from(port) * PORT_SIZE;
+            AhciDisk::new(port, base)
+        })
+        .collect();
+    for disk in &disks {
+        info!(
+            "ahci port {}: {}, {} sectors ({} MiB)",
+            disk.port,
+            disk.model,
+            disk.sectors,
+            disk.sectors * SECTOR_SIZE as u64 / (1024 * 1024)
+        );
+        // the status left by the identification is dropped
+        write(disk.base, PORT_IS, u32::MAX);
+        write(disk.base, PORT_IE, IS_DHRS | IS_TFES);
+    }
+
+    CONTROLLER.init_once(|| Controller { abar, disks });
+    for disk in self::disks() {
+        super::register(NAME_PREFIX, disk);
+    }
+    match device.interrupt_line {
+        Some(irq) => {
+            interrupts::register_irq_handler(irq, handle_interrupt);
+            write(abar, REG_GHC, read(abar, REG_GHC) | GHC_IE);
+        }
+        None => warn!("ahci at {} has no interrupt line", device.address),
+    }
+}
+
+/// The drives identified by [init], ordered by their ports.
+pub fn disks() -> &'static [AhciDisk] {
+    CONTROLLER
+        .try_get()
+        .map(|controller| controller.disks.as_slice())
+        .unwrap_or(&[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+
+    /// The test disk, see package.metadata.bootimage.test-args in Cargo.toml.
+    const TEST_DISK_SECTORS: u64 = 64 * 1024 * 1024 / SECTOR_SIZE as u64;
+
+    fn test_disk() -> &'static AhciDisk {
+        disks().first().expect("the test disk is not identified")
+    }
+
+    #[test_case]
+    fn test_disk_identified() {
+        let disk = test_disk();
+        assert_eq!(disk.sector_count(), TEST_DISK_SECTORS);
+        assert!(disk.model().starts_with("QEMU"));
+    }
+
+    #[test_case]
+    fn write_read_back() {
+        let disk = test_disk();
+        // spans two commands
+        let len = BOUNCE_SIZE + 2 * SECTOR_SIZE;
+        let lba = disk.sector_count() - (len / SECTOR_SIZE) as u64;
+        let pattern: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        block_on(disk.write(lba, &pattern)).unwrap();
+        let mut read = alloc::vec![0; len];
+        block_on(disk.read(lba, &mut read)).unwrap();
+        assert_eq!(read, pattern);
+    }
+
+    #[test_case]
+    fn out_of_range_refused() {
+        let disk = test_disk();
+        let mut sector = [0; SECTOR_SIZE];
+        assert_eq!(
+            block_on(disk.read(disk.sector_count(), &mut sector)),
+            Err(BlockError::OutOfRange)
+        );
+    }
+}
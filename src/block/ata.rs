@@ -0,0 +1,533 @@
+//! Driver of the disks on the legacy ATA (IDE) channels in PIO mode, `-drive if=ide` on QEMU.
+//!
+//! Each of the two channels has a master and a slave drive behind the same I/O ports, a channel
+//! runs one command at a time. Commands address up to 2^28 sectors (28-bit LBA) and move every
+//! sector through the data port. The drive raises the interrupt of its channel whenever a sector
+//! is ready to be read, once a sector has been written and when a command completes, the request
+//! is suspended in between. Drives are identified on boot with their interrupts disabled and
+//! polled.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+
+use super::{check_request, BlockDevice, BlockError, BlockFuture, SECTOR_SIZE};
+use crate::{
+    info, interrupts,
+    io::{IoError, PortRange},
+    task::mutex::AsyncMutex,
+    time, warn,
+};
+
+/// Number of ports of the command block registers.
+const COMMAND_BLOCK_PORTS: u16 = 8;
+/// Offsets of the command block registers from the I/O base of a channel.
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+/// The status register on reads, the command register on writes.
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+/// The status read from a channel without drives, the bus floats high.
+const STATUS_FLOATING: u8 = 0xff;
+
+/// Set in the device control register to disable the interrupts of the channel.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+/// Set in the drive register for LBA addressing, with the bits always set on old drives.
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_FLUSH_CACHE: u8 = 0xe7;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// The largest number of sectors of a command, written as 0 to the sector count register.
+const MAX_SECTORS: usize = 256;
+/// The number of sectors addressable with 28-bit LBA.
+const LBA28_SECTORS: u64 = 1 << 28;
+/// Set in word 49 of the identify data of drives supporting LBA.
+const IDENTIFY_LBA: u16 = 1 << 9;
+
+/// The prefix of the names of the drives in the [super] registry.
+pub const NAME_PREFIX: &str = "hd";
+
+/// Number of polls of the status register before a drive is given up, 10 microseconds apart.
+const POLLS: usize = 100_000;
+/// The longest a command waits for an interrupt.
+const IRQ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The primary and the secondary channels at their legacy I/O ports.
+static CHANNELS: [Channel; 2] = [
+    Channel::new("primary", 0x1f0, 0x3f6, interrupts::ATA_IRQS[0]),
+    Channel::new("secondary", 0x170, 0x376, interrupts::ATA_IRQS[1]),
+];
+
+static DISKS: OnceCell<Vec<AtaDisk>> = OnceCell::uninit();
+
+/// An ATA channel, its two drives share the registers and the interrupt line.
+struct Channel {
+    name: &'static str,
+    io_base: u16,
+    /// The port of the alternate status register on reads and of the device control register on
+    /// writes.
+    control: u16,
+    irq: u8,
+    ports: OnceCell<ChannelPorts>,
+    /// Held for the whole of a command.
+    lock: AsyncMutex<()>,
+    /// Set by the interrupt handler, cleared by the command waiting for it.
+    irq_fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// The ports of an ATA channel, claimed by [init].
+struct ChannelPorts {
+    command: PortRange,
+    control: PortRange,
+}
+
+impl Channel {
+    const fn new(name: &'static str, io_base: u16, control: u16, irq: u8) -> Self {
+        Channel {
+            name,
+            io_base,
+            control,
+            irq,
+            ports: OnceCell::uninit(),
+            lock: AsyncMutex::new(()),
+            irq_fired: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Claim the ports of the channel.
+    fn claim(&self) -> Result<(), IoError> {
+        // # Safety
+        // The ports are the legacy ports of the channel, its drives only access memory through the
+        // data port in PIO mode.
+        let command = unsafe { PortRange::claim(self.io_base, COMMAND_BLOCK_PORTS, "ata")? };
+        let control = unsafe { PortRange::claim(self.control, 1, "ata")? };
+        self.ports.init_once(|| ChannelPorts { command, control });
+        Ok(())
+    }
+
+    fn ports(&self) -> &ChannelPorts {
+        self.ports
+            .try_get()
+            .expect("the ports of the channel are not claimed")
+    }
+
+    /// Read the register `reg`, reading the status register acknowledges the interrupt of the
+    /// drives, which only the interrupt handler and [init] do.
+    fn read_reg(&self, reg: u16) -> u8 {
+        self.ports().command.read(reg)
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        self.ports().command.write(reg, value)
+    }
+
+    /// The status of the selected drive without acknowledging its interrupt.
+    fn alt_status(&self) -> u8 {
+        self.ports().control.read(0)
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        let control = if enabled { 0 } else { CONTROL_NIEN };
+        self.ports().control.write(0, control)
+    }
+
+    /// Wait the 400 ns a drive needs to put its status on the bus after a selection.
+    fn settle(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    /// Poll until the selected drive is no longer busy, then until all the bits of `ready` are set
+    /// in its status.
+    fn poll(&self, ready: u8) -> Result<(), BlockError> {
+        for _ in 0..POLLS {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(self.error(status));
+                }
+                if status & ready == ready {
+                    return Ok(());
+                }
+            }
+            time::delay_us(10);
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Check the status of the selected drive after an interrupt, all the bits of `ready` must be
+    /// set.
+    fn check(&self, ready: u8) -> Result<(), BlockError> {
+        let status = self.alt_status();
+        if status & (STATUS_BSY | STATUS_ERR | STATUS_DF) != 0 || status & ready != ready {
+            Err(self.error(status))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn error(&self, status: u8) -> BlockError {
+        BlockError::Device {
+            status,
+            error: self.read_reg(REG_ERROR),
+        }
+    }
+
+    /// Select the drive and write the registers of `command` on `count` sectors from `lba` on.
+    /// The interrupt flag is cleared before the command is started.
+    fn issue(&self, slave: bool, command: u8, lba: u64, count: usize) -> Result<(), BlockError> {
+        debug_assert!(lba + count as u64 <= LBA28_SECTORS && count <= MAX_SECTORS);
+        self.poll(0)?;
+        let drive = if slave { DRIVE_SLAVE } else { 0 };
+        self.write_reg(REG_DRIVE, DRIVE_LBA | drive | (lba >> 24) as u8 & 0x0f);
+        self.settle();
+        self.poll(0)?;
+
+        self.irq_fired.store(false, Ordering::SeqCst);
+        // 256 sectors wrap to 0
+        self.write_reg(REG_SECTOR_COUNT, count as u8);
+        self.write_reg(REG_LBA_LOW, lba as u8);
+        self.write_reg(REG_LBA_MID, (lba >> 8) as u8);
+        self.write_reg(REG_LBA_HIGH, (lba >> 16) as u8);
+        self.write_reg(REG_COMMAND, command);
+        Ok(())
+    }
+
+    fn read_data(&self, sector: &mut [u8]) {
+        // the drive has a sector ready, it's read a word at a time
+        let command = &self.ports().command;
+        for bytes in sector.chunks_exact_mut(2) {
+            bytes.copy_from_slice(&command.read::<u16>(REG_DATA).to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, sector: &[u8]) {
+        // the drive expects a sector, it's written a word at a time
+        let command = &self.ports().command;
+        for bytes in sector.chunks_exact(2) {
+            command.write(REG_DATA, u16::from_le_bytes([bytes[0], bytes[1]]));
+        }
+    }
+
+    /// Wait for the next interrupt of the channel, at most [IRQ_TIMEOUT].
+    fn interrupt(&self) -> WaitInterrupt<'_> {
+        WaitInterrupt {
+            channel: self,
+            deadline: time::ticks() + time::duration_to_ticks(IRQ_TIMEOUT),
+            timer_set: false,
+        }
+    }
+
+    /// Acknowledge the interrupt of the channel and wake the command waiting for it.
+    fn handle_interrupt(&self) {
+        self.read_reg(REG_STATUS);
+        self.irq_fired.store(true, Ordering::SeqCst);
+        self.waker.wake();
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// The future returned by [Channel::interrupt].
+struct WaitInterrupt<'a> {
+    channel: &'a Channel,
+    deadline: u64,
+    timer_set: bool,
+}
+
+impl Future for WaitInterrupt<'_> {
+    type Output = Result<(), BlockError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let channel = self.channel;
+        if channel.irq_fired.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(Ok(()));
+        }
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(Err(BlockError::Timeout));
+        }
+
+        channel.waker.register(cx.waker());
+        if !self.timer_set {
+            time::wheel::register(self.deadline, cx.waker().clone());
+            self.timer_set = true;
+        }
+
+        // the interrupt may have fired after the first check
+        if channel.irq_fired.swap(false, Ordering::SeqCst) {
+            channel.waker.take();
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+fn primary_interrupt() {
+    CHANNELS[0].handle_interrupt();
+}
+
+fn secondary_interrupt() {
+    CHANNELS[1].handle_interrupt();
+}
+
+/// A drive identified on an ATA channel.
+#[derive(Debug)]
+pub struct AtaDisk {
+    channel: &'static Channel,
+    slave: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl AtaDisk {
+    /// Identify the master or the slave drive of `channel`, `None` if there is no drive or it's
+    /// not an ATA drive addressable by LBA, e.g. an ATAPI CD-ROM drive.
+    fn identify(channel: &'static Channel, slave: bool) -> Option<Self> {
+        channel.issue(slave, COMMAND_IDENTIFY, 0, 0).ok()?;
+        if channel.alt_status() == 0 {
+            return None;
+        }
+        for _ in 0..POLLS {
+            if channel.alt_status() & STATUS_BSY == 0 {
+                break;
+            }
+            time::delay_us(10);
+        }
+        // packet devices abort the command with their signature in the LBA registers
+        if channel.read_reg(REG_LBA_MID) != 0 || channel.read_reg(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        channel.poll(STATUS_DRQ).ok()?;
+
+        let mut data = [0; SECTOR_SIZE];
+        channel.read_data(&mut data);
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]);
+        if word(49) & IDENTIFY_LBA == 0 {
+            return None;
+        }
+        let sectors = u64::from(word(60)) | u64::from(word(61)) << 16;
+        // the model is a string of 40 characters padded with spaces, in big-endian words
+        let mut model = String::new();
+        for i in 27..47 {
+            let [first, second] = word(i).to_be_bytes();
+            model.push(char::from(first));
+            model.push(char::from(second));
+        }
+        model.truncate(model.trim_end().len());
+
+        Some(AtaDisk {
+            channel,
+            slave,
+            sectors,
+            model,
+        })
+    }
+
+    /// The model of the drive as it reports it.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// The position of the drive, e.g. "primary master".
+    pub fn position(&self) -> (&'static str, &'static str) {
+        (
+            self.channel.name,
+            if self.slave { "slave" } else { "master" },
+        )
+    }
+
+    async fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        let channel = self.channel;
+        let _guard = channel.lock.lock().await;
+
+        let mut lba = lba;
+        for chunk in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            channel.issue(self.slave, COMMAND_READ_SECTORS, lba, count)?;
+            // an interrupt for every sector ready in the data port
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                channel.interrupt().await?;
+                channel.check(STATUS_DRQ)?;
+                channel.read_data(sector);
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    async fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, lba, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let channel = self.channel;
+        let _guard = channel.lock.lock().await;
+
+        let mut lba = lba;
+        for chunk in buf.chunks(MAX_SECTORS * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            channel.issue(self.slave, COMMAND_WRITE_SECTORS, lba, count)?;
+            // the first sector is expected right away, then an interrupt follows every sector
+            // written, the last one ending the command
+            channel.poll(STATUS_DRQ)?;
+            for (i, sector) in chunk.chunks(SECTOR_SIZE).enumerate() {
+                if i > 0 {
+                    channel.check(STATUS_DRQ)?;
+                }
+                channel.write_data(sector);
+                channel.interrupt().await?;
+            }
+            channel.check(0)?;
+            lba += count as u64;
+        }
+
+        // the drive may hold the data in its write cache until flushed
+        channel.issue(self.slave, COMMAND_FLUSH_CACHE, 0, 0)?;
+        channel.interrupt().await?;
+        channel.check(0)
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(self.read_sectors(lba, buf))
+    }
+
+    fn write<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(self.write_sectors(lba, buf))
+    }
+}
+
+/// Identify the drives on both ATA channels and enable the interrupts of the channels with
+/// drives, must be called once after [interrupts::init_pics].
+pub fn init() {
+    let mut disks = Vec::new();
+    for (channel, handler) in CHANNELS
+        .iter()
+        .zip([primary_interrupt as fn(), secondary_interrupt].iter())
+    {
+        if let Err(err) = channel.claim() {
+            warn!("ata {} channel: {}", channel.name, err);
+            continue;
+        }
+        if channel.read_reg(REG_STATUS) == STATUS_FLOATING {
+            continue;
+        }
+        channel.set_interrupts(false);
+
+        let found = disks.len();
+        for &slave in &[false, true] {
+            if let Some(disk) = AtaDisk::identify(channel, slave) {
+                let (channel, drive) = disk.position();
+                info!(
+                    "ata {} {}: {}, {} sectors ({} MiB)",
+                    channel,
+                    drive,
+                    disk.model,
+                    disk.sectors,
+                    disk.sectors * SECTOR_SIZE as u64 / (1024 * 1024)
+                );
+                disks.push(disk);
+            }
+        }
+        if disks.len() > found {
+            interrupts::register_irq_handler(channel.irq, *handler);
+            channel.set_interrupts(true);
+        }
+    }
+    DISKS.init_once(|| disks);
+    for disk in self::disks() {
+        super::register(NAME_PREFIX, disk);
+    }
+}
+
+/// The drives identified by [init], in the order primary master, primary slave, secondary master
+/// and secondary slave.
+pub fn disks() -> &'static [AtaDisk] {
+    DISKS.try_get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+
+    /// The boot disk, attached by bootimage as the primary master.
+    fn boot_disk() -> &'static AtaDisk {
+        disks()
+            .first()
+            .filter(|disk| disk.position() == ("primary", "master"))
+            .expect("the boot disk is not identified")
+    }
+
+    #[test_case]
+    fn boot_sector_read() {
+        let disk = boot_disk();
+        assert!(disk.model().starts_with("QEMU"));
+        let mut sector = [0; SECTOR_SIZE];
+        block_on(disk.read(0, &mut sector)).unwrap();
+        assert_eq!(sector[510..], [0x55, 0xaa]);
+    }
+
+    #[test_case]
+    fn write_read_back() {
+        let disk = boot_disk();
+        let lba = disk.sector_count() - 2;
+        let mut original = [0; 2 * SECTOR_SIZE];
+        block_on(disk.read(lba, &mut original)).unwrap();
+
+        let pattern: Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+        block_on(disk.write(lba, &pattern)).unwrap();
+        let mut read = [0; 2 * SECTOR_SIZE];
+        block_on(disk.read(lba, &mut read)).unwrap();
+        block_on(disk.write(lba, &original)).unwrap();
+        assert_eq!(read[..], pattern[..]);
+    }
+
+    #[test_case]
+    fn out_of_range_refused() {
+        let disk = boot_disk();
+        let mut sector = [0; SECTOR_SIZE];
+        assert_eq!(
+            block_on(disk.read(disk.sector_count(), &mut sector)),
+            Err(BlockError::OutOfRange)
+        );
+    }
+}
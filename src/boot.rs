@@ -0,0 +1,90 @@
+//! Timing of the boot milestones.
+//!
+//! [init](crate::init) records a milestone after each of its steps by [milestone], the time stamp
+//! counter at each milestone gives a per-step breakdown of the boot time so that regressions in the
+//! initialization of a subsystem are visible. Milestones are kept in a fixed-size array as most of
+//! them are recorded before the heap is available.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::time::tsc;
+
+/// Maximum number of milestones, later milestones are dropped.
+const MAX_MILESTONES: usize = 32;
+
+struct Milestones {
+    entries: [(&'static str, u64); MAX_MILESTONES],
+    len: usize,
+}
+
+static MILESTONES: Mutex<Milestones> = Mutex::new(Milestones {
+    entries: [("", 0); MAX_MILESTONES],
+    len: 0,
+});
+
+/// Record the milestone `name` reached at the current time stamp counter.
+pub fn milestone(name: &'static str) {
+    let tsc = tsc::rdtsc();
+    let mut milestones = MILESTONES.lock();
+    let len = milestones.len;
+    if len < MAX_MILESTONES {
+        milestones.entries[len] = (name, tsc);
+        milestones.len += 1;
+    }
+}
+
+/// The breakdown of the boot time, one line per milestone with the cycles and microseconds spent
+/// since the previous milestone and the share of the total. Microseconds are only known once the
+/// TSC is calibrated.
+pub struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let milestones = MILESTONES.lock();
+        let entries = &milestones.entries[..milestones.len];
+        let (first, last) = match (entries.first(), entries.last()) {
+            (Some(first), Some(last)) => (first.1, last.1),
+            _ => return writeln!(f, "no boot milestones recorded"),
+        };
+        let total = (last - first).max(1);
+
+        for window in entries.windows(2) {
+            let (name, tsc) = window[1];
+            let cycles = tsc - window[0].1;
+            // floating point is emulated in software, the share is computed in permille instead
+            let permille = cycles * 1000 / total;
+            writeln!(
+                f,
+                "{:<20} {:>14} cycles {:>9} us {:>3}.{}%",
+                name,
+                cycles,
+                tsc::cycles_to_nanos(cycles) / 1000,
+                permille / 10,
+                permille % 10
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<20} {:>14} cycles {:>9} us",
+            "total",
+            last - first,
+            tsc::cycles_to_nanos(last - first) / 1000
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn report_milestones() {
+        // recorded by init before the tests run
+        let report = Report.to_string();
+        assert!(report.contains("heap"));
+        assert!(report.contains("total"));
+    }
+}
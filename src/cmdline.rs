@@ -0,0 +1,274 @@
+//! The kernel command line, parsed into a typed [Config].
+//!
+//! The bootloader passes no command line to the kernel, instead the command line is baked into
+//! the kernel at build time from the environment variable `KERNEL_CMDLINE`, or [DEFAULT_CMDLINE]
+//! if the variable is not set, e.g.
+//!
+//! ```text
+//! KERNEL_CMDLINE="console=serial loglevel=info,rust_kernel::memory=debug" cargo run
+//! ```
+//!
+//! The command line consists of whitespace separated `key=value` options, the last occurrence of
+//! an option wins. The recognized options are:
+//!
+//! - `console=vga|serial|both`: the consoles to run shell sessions on
+//! - `framebuffer=on|off`: print to a console on the framebuffer of the GPU instead of the VGA
+//!   text buffer, see [crate::framebuffer]
+//! - `keymap=us|uk|dvorak|azerty`: the layout of the keyboard, see
+//!   [crate::task::keyboard::Layout]
+//! - `ctrlkeys=on|off`: decode Ctrl+letter to control characters, see
+//!   [crate::task::keyboard::set_control_handling]
+//! - `loglevel=<directives>`: log filters, see [crate::klog::apply_directives]
+//! - `heap_size=<bytes>`: size of the kernel heap, in decimal or hexadecimal with a `0x` prefix
+//! - `test=<substring>`: only run the tests with names containing the substring
+//! - `seed=<number>`: the seed of randomized tests, see [crate::testing::seed]
+//! - `test_output=quiet|normal|verbose`: how much the test runner prints, see
+//!   [crate::testing::Verbosity]
+//! - `tick=pit|rtc|hpet|apic|tsc-deadline`: the timer driving the timer tick, see
+//!   [crate::time::TickSource]
+//! - `clocksource=<name>`: the counter read by [crate::time::Instant], see
+//!   [crate::time::clocksource]
+//!
+//! The command line is parsed without allocation as the heap size must be known before the heap
+//! is initialized. Invalid options are ignored and reported by [Config::errors].
+
+use core::convert::TryFrom;
+
+use conquer_once::spin::OnceCell;
+
+use crate::{allocator::HEAP_SIZE, task::keyboard::Layout, testing::Verbosity, time::TickSource};
+
+/// The command line used when `KERNEL_CMDLINE` is not set at build time.
+pub const DEFAULT_CMDLINE: &str = "console=both";
+
+/// The consoles to run shell sessions on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    /// The VGA text buffer with input from the keyboard.
+    Vga,
+    /// A terminal on the first serial port.
+    Serial,
+    /// Both the VGA text buffer and the serial terminal.
+    Both,
+}
+
+impl Console {
+    /// Whether a shell session should run on the VGA text buffer.
+    pub fn vga(self) -> bool {
+        self != Console::Serial
+    }
+
+    /// Whether a shell session should run on the serial terminal.
+    pub fn serial(self) -> bool {
+        self != Console::Vga
+    }
+}
+
+/// Maximum number of invalid options remembered by [Config].
+const MAX_ERRORS: usize = 8;
+
+/// Options parsed from the kernel command line.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// The consoles to run shell sessions on.
+    pub console: Console,
+    /// Whether to print to the framebuffer of the GPU instead of the VGA text buffer.
+    pub framebuffer: bool,
+    /// The layout of the keyboard.
+    pub keymap: Layout,
+    /// Whether Ctrl+letter is decoded to control characters.
+    pub ctrlkeys: bool,
+    /// Log filter directives.
+    pub loglevel: Option<&'static str>,
+    /// Size of the kernel heap in bytes.
+    pub heap_size: usize,
+    /// Substring of the names of the tests to run.
+    pub test_filter: Option<&'static str>,
+    /// The seed of randomized tests.
+    pub test_seed: Option<u64>,
+    /// How much the test runner prints.
+    pub test_output: Verbosity,
+    /// The timer driving the timer tick.
+    pub tick_source: TickSource,
+    /// The name of the preferred clock source, validated once the clock sources are probed.
+    pub clocksource: Option<&'static str>,
+    /// Whether to route the external interrupts through the I/O APIC when there's one.
+    pub ioapic: bool,
+    /// Whether to start the application processors.
+    pub smp: bool,
+    errors: [&'static str; MAX_ERRORS],
+    error_count: usize,
+}
+
+impl Config {
+    /// Parse `cmdline`, options not mentioned on the command line take their default values.
+    pub fn parse(cmdline: &'static str) -> Self {
+        let mut config = Config {
+            console: Console::Both,
+            framebuffer: false,
+            keymap: Layout::Us,
+            ctrlkeys: false,
+            loglevel: None,
+            heap_size: HEAP_SIZE,
+            test_filter: None,
+            test_seed: None,
+            test_output: Verbosity::Normal,
+            tick_source: TickSource::Pit,
+            clocksource: None,
+            ioapic: true,
+            smp: true,
+            errors: [""; MAX_ERRORS],
+            error_count: 0,
+        };
+
+        for option in cmdline.split_whitespace() {
+            if !config.apply(option) && config.error_count < MAX_ERRORS {
+                config.errors[config.error_count] = option;
+                config.error_count += 1;
+            }
+        }
+
+        config
+    }
+
+    /// Apply a single option, returns `false` if the option is unknown or invalid.
+    fn apply(&mut self, option: &'static str) -> bool {
+        let (key, value) = match option.split_once('=') {
+            Some(pair) => pair,
+            None => return false,
+        };
+
+        match key {
+            "console" => {
+                self.console = match value {
+                    "vga" => Console::Vga,
+                    "serial" => Console::Serial,
+                    "both" => Console::Both,
+                    _ => return false,
+                }
+            }
+            "framebuffer" => match parse_switch(value) {
+                Some(on) => self.framebuffer = on,
+                None => return false,
+            },
+            "keymap" => match Layout::from_name(value) {
+                Some(layout) => self.keymap = layout,
+                None => return false,
+            },
+            "ctrlkeys" => match parse_switch(value) {
+                Some(on) => self.ctrlkeys = on,
+                None => return false,
+            },
+            "loglevel" => self.loglevel = Some(value),
+            "heap_size" => match parse_usize(value) {
+                // the heap is mapped in whole pages
+                Some(size) if size > 0 && size % 4096 == 0 => self.heap_size = size,
+                _ => return false,
+            },
+            "test" => self.test_filter = Some(value),
+            "test_output" => match Verbosity::from_name(value) {
+                Some(verbosity) => self.test_output = verbosity,
+                None => return false,
+            },
+            "seed" => match parse_number(value) {
+                Some(seed) => self.test_seed = Some(seed),
+                None => return false,
+            },
+            "clocksource" => self.clocksource = Some(value),
+            "tick" => match TickSource::from_name(value) {
+                Some(source) => self.tick_source = source,
+                None => return false,
+            },
+            "ioapic" => match parse_switch(value) {
+                Some(on) => self.ioapic = on,
+                None => return false,
+            },
+            "smp" => match parse_switch(value) {
+                Some(on) => self.smp = on,
+                None => return false,
+            },
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// The unknown or invalid options on the command line, only the first few are remembered.
+    pub fn errors(&self) -> &[&'static str] {
+        &self.errors[..self.error_count]
+    }
+}
+
+/// Parse a number in decimal or hexadecimal with a `0x` prefix, the syntax of numbers on the
+/// command line and in the shell.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// [parse_number] for sizes and addresses.
+pub fn parse_usize(s: &str) -> Option<usize> {
+    parse_number(s).and_then(|n| usize::try_from(n).ok())
+}
+
+/// Parse `on` or `off`.
+fn parse_switch(s: &str) -> Option<bool> {
+    match s {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+static CONFIG: OnceCell<Config> = OnceCell::uninit();
+
+/// The whole kernel command line.
+pub fn get() -> &'static str {
+    option_env!("KERNEL_CMDLINE").unwrap_or(DEFAULT_CMDLINE)
+}
+
+/// The options parsed from the kernel command line, parsed on the first call.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| Config::parse(get()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_options() {
+        let config = Config::parse(
+            "console=vga loglevel=warn loglevel=debug,rust_kernel=off heap_size=0x2000 \
+             heap_size=100 quiet test=alloc seed=0x2a seed=x test_output=quiet tick=rtc tick=tsc \
+             clocksource=hpet ioapic=off ioapic=no smp=off framebuffer=on keymap=dvorak \
+             keymap=qwertz ctrlkeys=on",
+        );
+        assert_eq!(config.console, Console::Vga);
+        assert!(config.framebuffer);
+        assert_eq!(config.keymap, Layout::Dvorak);
+        assert!(config.ctrlkeys);
+        assert_eq!(config.loglevel, Some("debug,rust_kernel=off"));
+        assert_eq!(config.heap_size, 0x2000);
+        assert_eq!(config.test_filter, Some("alloc"));
+        assert_eq!(config.test_seed, Some(42));
+        assert_eq!(config.test_output, Verbosity::Quiet);
+        assert_eq!(config.tick_source, TickSource::Rtc);
+        assert_eq!(config.clocksource, Some("hpet"));
+        assert!(!config.ioapic);
+        assert!(!config.smp);
+        assert_eq!(
+            config.errors(),
+            &[
+                "heap_size=100",
+                "quiet",
+                "seed=x",
+                "tick=tsc",
+                "ioapic=no",
+                "keymap=qwertz"
+            ]
+        );
+    }
+}
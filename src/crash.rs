@@ -0,0 +1,154 @@
+//! Crash reports emitted over the first serial port on panic.
+//!
+//! A report is framed by the lines [BEGIN] and [END] so that a host tool can cut it out of the
+//! rest of the serial output. Each line in between is a record of space separated fields, the
+//! first field names the kind of the record:
+//!
+//! ```text
+//! panic <message>
+//! reg <name> <hex value>
+//! halted <cpu> <hex instruction pointer> <hex stack pointer>
+//! frame <index> <hex return address>
+//! heap <key> <value>
+//! irq <vector> <count> <name>
+//! log <seq> <ticks> <level> <target> <message>
+//! ```
+//!
+//! Messages are always the last field and take the rest of the line, line breaks and backslashes
+//! in messages are escaped as `\n` and `\\`. Records that can't be collected safely in the panic
+//! handler, e.g. heap statistics when the panic happened inside the allocator, are skipped.
+//!
+//! The report is written without allocation and without waiting for any lock other than the
+//! serial port, which the panic handler releases beforehand. The other processors are halted by
+//! then, each `halted` record is where one of them was stopped, see [smp::halt_others]. The panic is also recorded in the
+//! CMOS for the next boot, see [crate::persist::last_crash].
+
+use core::{fmt, panic::PanicInfo};
+
+use x86_64::registers::{
+    control::{Cr0, Cr2, Cr3, Cr4},
+    rflags,
+};
+
+use crate::{allocator, interrupts, klog, persist, serial_print, serial_println, smp, unwind};
+
+/// The first line of a crash report.
+pub const BEGIN: &str = "-----BEGIN CRASH REPORT-----";
+/// The last line of a crash report.
+pub const END: &str = "-----END CRASH REPORT-----";
+/// Number of the most recent log records included in a report.
+pub const LOG_RECORDS: usize = 32;
+
+/// Writes to the serial port, escaping line breaks and backslashes.
+struct Escaped;
+
+impl fmt::Write for Escaped {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                serial_print!("\\n");
+            }
+            for (j, piece) in part.split('\\').enumerate() {
+                if j > 0 {
+                    serial_print!("\\\\");
+                }
+                serial_print!("{}", piece);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `args` escaped, followed by a line break.
+fn escaped_line(args: fmt::Arguments) {
+    use fmt::Write;
+
+    let _ = Escaped.write_fmt(args);
+    serial_println!();
+}
+
+/// Read the stack pointer and the frame pointer of the caller.
+#[inline(always)]
+fn stack_registers() -> (u64, u64) {
+    let (rsp, rbp): (u64, u64);
+    // # Safety
+    // Reading registers has no side effect.
+    unsafe {
+        asm!(
+            "mov {}, rsp",
+            "mov {}, rbp",
+            out(reg) rsp,
+            out(reg) rbp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    (rsp, rbp)
+}
+
+/// Write a crash report of the panic `info` to the first serial port. Must only be called from a
+/// panic handler after [crate::force_unlock_outputs].
+pub fn report(info: &PanicInfo) {
+    let (rsp, rbp) = stack_registers();
+
+    persist::record_crash(info.location().map_or(0, |location| location.line()));
+    serial_println!("{}", BEGIN);
+
+    serial_print!("panic ");
+    escaped_line(format_args!("{}", info));
+
+    let registers = [
+        ("rsp", rsp),
+        ("rbp", rbp),
+        ("rflags", rflags::read_raw()),
+        ("cr0", Cr0::read_raw()),
+        ("cr2", Cr2::read().as_u64()),
+        ("cr3", Cr3::read().0.start_address().as_u64()),
+        ("cr4", Cr4::read_raw()),
+    ];
+    for (name, value) in registers.iter() {
+        serial_println!("reg {} {:#x}", name, value);
+    }
+
+    for halted in smp::halted() {
+        serial_println!(
+            "halted {} {:#x} {:#x}",
+            halted.cpu,
+            halted.instruction_pointer.as_u64(),
+            halted.stack_pointer.as_u64()
+        );
+    }
+
+    for (i, address) in unwind::backtrace().enumerate() {
+        serial_println!("frame {} {:#x}", i, address.as_u64());
+    }
+
+    if let Some(stats) = allocator::try_stats() {
+        serial_println!("heap start {:#x}", stats.heap_start);
+        serial_println!("heap size {}", stats.heap_size);
+        serial_println!("heap fallback_used {}", stats.fallback_used);
+        serial_println!("heap fallback_free {}", stats.fallback_free);
+        serial_println!("heap allocations {}", stats.allocations);
+        serial_println!("heap deallocations {}", stats.deallocations);
+    }
+
+    for vector in 0..=u8::MAX {
+        let count = interrupts::interrupt_count(vector);
+        if count > 0 {
+            let name = interrupts::vector_name(vector).unwrap_or("unknown");
+            serial_println!("irq {} {} {}", vector, count, name);
+        }
+    }
+
+    klog::try_for_each_latest(LOG_RECORDS, |record| {
+        serial_print!(
+            "log {} {} {} {} ",
+            record.seq,
+            record.timestamp,
+            record.level,
+            record.target
+        );
+        escaped_line(format_args!("{}", record.message()));
+    });
+
+    serial_println!("{}", END);
+}
@@ -0,0 +1,261 @@
+//! Physically contiguous memory shared with devices.
+//!
+//! Devices address memory by its physical address, rings and buffers spanning more than a page
+//! must be contiguous in physical memory, which the heap doesn't guarantee. A pool of contiguous
+//! frames is reserved once at boot and handed out in whole pages, the kernel accesses it through
+//! the mapping of the complete physical memory. Drivers allocate their rings and buffers once on
+//! initialization, allocations are never freed.
+//!
+//! A [DmaBuffer] owns its range: drivers access it through bounds-checked volatile reads and writes
+//! of plain data ([Pod]) instead of raw pointers. DMA is cache-coherent on x86_64, only the order
+//! of the accesses matters: [DmaBuffer::sync_for_device] makes the writes of the kernel visible
+//! before the device is told about them, [DmaBuffer::sync_for_cpu] keeps the reads of the kernel
+//! after the check of what the device has written.
+
+use core::{
+    mem, ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameAllocator, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory;
+
+/// Size of the pool reserved by [init].
+pub const POOL_SIZE: u64 = 512 * 1024;
+const PAGE_SIZE: u64 = 4096;
+
+/// The physical addresses of the next free page and the end of the pool, both 0 before [init].
+static POOL: Mutex<(u64, u64)> = Mutex::new((0, 0));
+
+/// Plain data, valid for any bit pattern a device may write.
+///
+/// # Safety
+/// The type must have no padding and every bit pattern must be a valid value.
+pub unsafe trait Pod: Copy {}
+
+// # Safety
+// Integers and arrays of them have no padding and no invalid values.
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A page-aligned range of physically contiguous memory, zeroed on allocation.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    size: u64,
+}
+
+impl DmaBuffer {
+    /// The physical address of the buffer, as given to devices.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The physical address of the byte at `offset` of the buffer.
+    ///
+    /// # Panics
+    /// Panics if `offset` is past the end of the buffer.
+    pub fn phys_at(&self, offset: u64) -> PhysAddr {
+        assert!(offset < self.size, "offset {:#x} out of the buffer", offset);
+        self.phys + offset
+    }
+
+    /// The virtual address of the buffer, as accessed by the kernel.
+    pub fn virt_addr(&self) -> VirtAddr {
+        memory::phys_to_virt(self.phys)
+    }
+
+    /// The size of the buffer in bytes, a multiple of the page size.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The pointer to `len` bytes at `offset` aligned for `T`.
+    fn ptr<T>(&self, offset: u64, len: usize) -> *mut T {
+        assert!(
+            offset
+                .checked_add(len as u64)
+                .map_or(false, |end| end <= self.size),
+            "{} bytes at offset {:#x} out of the buffer",
+            len,
+            offset
+        );
+        assert_eq!(
+            offset % mem::align_of::<T>() as u64,
+            0,
+            "misaligned offset {:#x}",
+            offset
+        );
+        (self.virt_addr() + offset).as_mut_ptr()
+    }
+
+    /// Read the value at `offset`, which the device may be writing.
+    ///
+    /// # Panics
+    /// Panics if the value is out of the buffer or `offset` isn't aligned for `T`.
+    pub fn read<T: Pod>(&self, offset: u64) -> T {
+        let ptr = self.ptr::<T>(offset, mem::size_of::<T>());
+        // # Safety
+        // The value is in the buffer and aligned, any bit pattern is a valid `T`.
+        unsafe { ptr::read_volatile(ptr) }
+    }
+
+    /// Write `value` at `offset`, which the device may be reading.
+    ///
+    /// # Panics
+    /// Panics if the value is out of the buffer or `offset` isn't aligned for `T`.
+    pub fn write<T: Pod>(&self, offset: u64, value: T) {
+        let ptr = self.ptr::<T>(offset, mem::size_of::<T>());
+        // # Safety
+        // The value is in the buffer and aligned, the buffer is owned by `self`.
+        unsafe { ptr::write_volatile(ptr, value) }
+    }
+
+    /// Copy the bytes at `offset` to `buf`, the device must be done with them.
+    ///
+    /// # Panics
+    /// Panics if the bytes are out of the buffer.
+    pub fn read_bytes(&self, offset: u64, buf: &mut [u8]) {
+        let ptr = self.ptr::<u8>(offset, buf.len());
+        // # Safety
+        // The bytes are in the buffer, which doesn't overlap with kernel objects.
+        unsafe { ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) }
+    }
+
+    /// Copy `data` to the bytes at `offset`, the device must not be using them.
+    ///
+    /// # Panics
+    /// Panics if the bytes are out of the buffer.
+    pub fn write_bytes(&self, offset: u64, data: &[u8]) {
+        let ptr = self.ptr::<u8>(offset, data.len());
+        // # Safety
+        // As above.
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) }
+    }
+
+    /// Order the writes to the buffer before the following accesses, e.g. the write of a
+    /// register notifying the device of them.
+    pub fn sync_for_device(&self) {
+        fence(Ordering::SeqCst);
+    }
+
+    /// Order the following reads of the buffer after the preceding ones, e.g. the read of the
+    /// index telling which entries the device has written.
+    pub fn sync_for_cpu(&self) {
+        fence(Ordering::SeqCst);
+    }
+}
+
+/// Reserve [POOL_SIZE] bytes of physically contiguous frames from `frame_allocator`, must be called
+/// once after [memory::init].
+pub fn init(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = memory::alloc_contiguous(frame_allocator, POOL_SIZE / PAGE_SIZE)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    *POOL.lock() = (start.as_u64(), start.as_u64() + POOL_SIZE);
+    Ok(())
+}
+
+/// Allocate `size` bytes rounded up to whole pages, `None` if the pool is exhausted.
+pub fn alloc(size: u64) -> Option<DmaBuffer> {
+    alloc_aligned(size, PAGE_SIZE)
+}
+
+/// Allocate `size` bytes rounded up to whole pages at a physical address aligned to `align`,
+/// `None` if the pool is exhausted. The pages skipped for the alignment are lost.
+///
+/// # Panics
+/// Panics if `align` isn't a power of 2.
+pub fn alloc_aligned(size: u64, align: u64) -> Option<DmaBuffer> {
+    assert!(
+        align.is_power_of_two(),
+        "alignment {} not a power of 2",
+        align
+    );
+    let align = align.max(PAGE_SIZE);
+    let size = (size.max(1) + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let phys = {
+        let mut pool = POOL.lock();
+        let (next, end) = *pool;
+        let start = (next + align - 1) / align * align;
+        if start > end || end - start < size {
+            return None;
+        }
+        pool.0 = start + size;
+        PhysAddr::new(start)
+    };
+
+    let buffer = DmaBuffer { phys, size };
+    // # Safety
+    // The pages have just been taken from the pool reserved from the frame allocator, nothing
+    // else refers to them.
+    unsafe {
+        buffer
+            .virt_addr()
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, size as usize);
+    }
+    Some(buffer)
+}
+
+/// Number of bytes left in the pool.
+pub fn available() -> u64 {
+    let (next, end) = *POOL.lock();
+    end - next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{should_panic, ShouldPanic};
+
+    #[test_case]
+    fn whole_pages_allocated() {
+        let before = available();
+        let buffer = alloc(100).expect("DMA pool exhausted");
+        assert_eq!(buffer.size(), PAGE_SIZE);
+        assert!(buffer.phys_addr().is_aligned(PAGE_SIZE));
+        assert_eq!(available(), before - PAGE_SIZE);
+        assert_eq!(
+            memory::translate(buffer.virt_addr() + 100u64).map(|(phys, _)| phys),
+            Some(buffer.phys_addr() + 100u64)
+        );
+    }
+
+    #[test_case]
+    fn aligned_allocation() {
+        let buffer = alloc_aligned(1, 4 * PAGE_SIZE).expect("DMA pool exhausted");
+        assert!(buffer.phys_addr().is_aligned(4 * PAGE_SIZE));
+        assert_eq!(buffer.size(), PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn checked_accesses() {
+        let buffer = alloc(1).expect("DMA pool exhausted");
+        buffer.write::<u32>(8, 0x1234_5678);
+        assert_eq!(buffer.read::<u16>(8), 0x5678);
+        assert_eq!(buffer.read::<[u8; 2]>(10), [0x34, 0x12]);
+        buffer.write_bytes(PAGE_SIZE - 3, b"end");
+        let mut end = [0; 3];
+        buffer.read_bytes(PAGE_SIZE - 3, &mut end);
+        assert_eq!(&end, b"end");
+        assert_eq!(buffer.phys_at(8), buffer.phys_addr() + 8u64);
+    }
+
+    fn out_of_bounds_access() {
+        let buffer = alloc(1).expect("DMA pool exhausted");
+        buffer.read::<u32>(PAGE_SIZE - 2);
+    }
+
+    #[test_case]
+    const OUT_OF_BOUNDS_ACCESS: ShouldPanic = should_panic!(out_of_bounds_access);
+}
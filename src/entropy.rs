@@ -0,0 +1,313 @@
+//! The kernel entropy pool.
+//!
+//! The pool is a 256-bit ChaCha20 key. Entropy is mixed in by XORing it into the key and replacing
+//! the key with the first half of a ChaCha20 block under it, so that the key depends on everything
+//! mixed in so far without revealing it. Output is the ChaCha20 keystream under the key, after
+//! which the key is replaced by fresh keystream ("fast key erasure"): a key read from memory later
+//! doesn't reveal the output already handed out.
+//!
+//! The pool is seeded on boot from all the available sources:
+//! - the virtio entropy device, random bytes from the host
+//! - RDSEED and RDRAND, if the processor supports them
+//! - timing jitter, the low bits of the time stamp counter around memory accesses
+//!
+//! and reseeded from them every [RESEED_INTERVAL] bytes of output. Drivers may mix in their own
+//! unpredictable data with [add]. Random bytes are read through [crate::random].
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step};
+
+use spin::Mutex;
+
+use crate::{info, time::tsc, virtio, warn};
+
+/// Bytes of output after which the pool is reseeded.
+pub const RESEED_INTERVAL: u64 = 1024 * 1024;
+/// Bytes gathered from each source on a (re)seed.
+const SEED_SIZE: usize = 32;
+/// Number of time stamp counter samples taken for a seed from jitter.
+const JITTER_SAMPLES: usize = 256;
+
+/// The nonces separating the uses of the ChaCha20 block function.
+const NONCE_MIX: [u32; 3] = [0x6d69_7800, 0, 0];
+const NONCE_OUTPUT: [u32; 3] = [0x6f75_7400, 0, 0];
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    key: [0; 8],
+    output: 0,
+    seeded: false,
+});
+
+struct Pool {
+    key: [u32; 8],
+    /// Bytes of output since the last seed.
+    output: u64,
+    seeded: bool,
+}
+
+impl Pool {
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, &byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= u32::from(byte) << (8 * (i % 4));
+            }
+            let block = chacha20_block(&self.key, 0, NONCE_MIX);
+            self.key.copy_from_slice(&block[..8]);
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        // block 0 is the next key, the output starts at block 1
+        for (counter, chunk) in (1..).zip(buf.chunks_mut(64)) {
+            let block = chacha20_block(&self.key, counter, NONCE_OUTPUT);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        let block = chacha20_block(&self.key, 0, NONCE_OUTPUT);
+        self.key.copy_from_slice(&block[..8]);
+        self.output += buf.len() as u64;
+    }
+}
+
+/// The ChaCha20 block function of RFC 8439: the 16 words of the block `counter` of the keystream
+/// under `key` and `nonce`.
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: [u32; 3]) -> [u32; 16] {
+    let mut initial = [0; 16];
+    // "expand 32-byte k"
+    initial[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(&nonce);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(initial.iter()) {
+        *word = word.wrapping_add(*initial);
+    }
+    state
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Whether the processor has RDRAND, reported by CPUID.
+pub fn has_rdrand() -> bool {
+    // # Safety
+    // CPUID leaf 1 is available on all x86_64 processors.
+    unsafe { __cpuid(1).ecx & (1 << 30) != 0 }
+}
+
+/// Whether the processor has RDSEED, reported by CPUID.
+pub fn has_rdseed() -> bool {
+    // # Safety
+    // CPUID is available on all x86_64 processors, the leaf is checked against the highest
+    // supported leaf before use.
+    unsafe { __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 18) != 0 }
+}
+
+/// Fill `buf` with 64-bit words of `step`, retrying each a few times as recommended by Intel.
+/// Returns whether `buf` has been filled.
+fn fill_with(buf: &mut [u8], step: impl Fn(&mut u64) -> bool) -> bool {
+    buf.chunks_mut(8).all(|chunk| {
+        let mut word = 0;
+        if (0..10).any(|_| step(&mut word)) {
+            chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+            true
+        } else {
+            false
+        }
+    })
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand(word: &mut u64) -> bool {
+    _rdrand64_step(word) == 1
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed(word: &mut u64) -> bool {
+    _rdseed64_step(word) == 1
+}
+
+/// Timing jitter: the deltas of the time stamp counter around accesses to memory spread over
+/// pages, which vary with the state of the caches, the TLB and the host.
+fn jitter() -> [u8; JITTER_SAMPLES] {
+    let mut samples = [0; JITTER_SAMPLES];
+    let mut scratch = [0u8; 4096 * 2];
+    let mut last = tsc::rdtsc();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let index = (last as usize).wrapping_mul(i + 1) % scratch.len();
+        // # Safety
+        // `index` is in bounds, the volatile access keeps the load from being optimized away.
+        unsafe {
+            let byte = core::ptr::read_volatile(&scratch[index]);
+            core::ptr::write_volatile(&mut scratch[index], byte.wrapping_add(1));
+        }
+        let now = tsc::rdtsc();
+        // only the lowest bits vary
+        *sample = now.wrapping_sub(last) as u8;
+        last = now;
+    }
+    samples
+}
+
+/// Mix seeds from all the available sources into the pool, returns the names of the sources used.
+fn seed(pool: &mut Pool) -> [Option<&'static str>; 4] {
+    let mut sources = [None; 4];
+    let mut buf = [0; SEED_SIZE];
+
+    if virtio::rng::read(&mut buf) == buf.len() {
+        pool.mix(&buf);
+        sources[0] = Some("virtio-rng");
+    }
+    // # Safety
+    // The instructions are only executed if CPUID reports them.
+    if has_rdseed() && fill_with(&mut buf, |word| unsafe { rdseed(word) }) {
+        pool.mix(&buf);
+        sources[1] = Some("rdseed");
+    }
+    // # Safety
+    // As above.
+    if has_rdrand() && fill_with(&mut buf, |word| unsafe { rdrand(word) }) {
+        pool.mix(&buf);
+        sources[2] = Some("rdrand");
+    }
+    pool.mix(&jitter());
+    sources[3] = Some("jitter");
+
+    pool.output = 0;
+    pool.seeded = true;
+    sources
+}
+
+/// Seed the pool from all the sources, must be called once after the entropy devices are
+/// initialized.
+pub fn init() {
+    let sources = seed(&mut POOL.lock());
+    info!(
+        "entropy pool seeded from {}",
+        sources
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<alloc::vec::Vec<_>>()
+            .join(", ")
+    );
+    if sources[..3].iter().all(Option::is_none) {
+        warn!("no hardware entropy source, the entropy pool relies on timing jitter only");
+    }
+}
+
+/// Mix `data` into the pool. The data doesn't have to be random, it can't make the pool more
+/// predictable.
+pub fn add(data: &[u8]) {
+    POOL.lock().mix(data);
+}
+
+/// Fill `buf` with random bytes from the pool, must not be called from interrupt handlers. The pool
+/// is seeded from the sources available so far if it's used before [init], e.g. by early boot
+/// code.
+pub fn fill(buf: &mut [u8]) {
+    let mut pool = POOL.lock();
+    if !pool.seeded || pool.output >= RESEED_INTERVAL {
+        seed(&mut pool);
+    }
+    pool.fill(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn chacha20_test_vector() {
+        // RFC 8439 section 2.3.2
+        let key = [
+            0x0302_0100,
+            0x0706_0504,
+            0x0b0a_0908,
+            0x0f0e_0d0c,
+            0x1312_1110,
+            0x1716_1514,
+            0x1b1a_1918,
+            0x1f1e_1d1c,
+        ];
+        let block = chacha20_block(&key, 1, [0x0900_0000, 0x4a00_0000, 0]);
+        assert_eq!(
+            block,
+            [
+                0xe4e7_f110,
+                0x1559_3bd1,
+                0x1fdd_0f50,
+                0xc471_20a3,
+                0xc7f4_d1c7,
+                0x0368_c033,
+                0x9aaa_2204,
+                0x4e6c_d4c3,
+                0x4664_82d2,
+                0x09aa_9f07,
+                0x05d7_c214,
+                0xa202_8bd9,
+                0xd19c_12b5,
+                0xb94e_16de,
+                0xe883_d0cb,
+                0x4e3c_50a2,
+            ]
+        );
+    }
+
+    #[test_case]
+    fn seeded_on_first_use() {
+        let mut pool = Pool {
+            key: [0; 8],
+            output: 0,
+            seeded: false,
+        };
+        let before = pool.key;
+        seed(&mut pool);
+        assert!(pool.seeded);
+        assert_ne!(pool.key, before);
+    }
+
+    #[test_case]
+    fn mixing_changes_output() {
+        let mut a = Pool {
+            key: [0; 8],
+            output: 0,
+            seeded: true,
+        };
+        let mut b = Pool {
+            key: [0; 8],
+            output: 0,
+            seeded: true,
+        };
+        a.mix(b"a");
+        b.mix(b"b");
+        let (mut out_a, mut out_b) = ([0; 16], [0; 16]);
+        a.fill(&mut out_a);
+        b.fill(&mut out_b);
+        assert_ne!(out_a, out_b);
+
+        // the key is erased after each output
+        let mut again = [0; 16];
+        a.fill(&mut again);
+        assert_ne!(out_a, again);
+    }
+}
@@ -0,0 +1,178 @@
+//! Fault injection for tests.
+//!
+//! Error paths depending on hardware misbehaving or resources running out are hard to reach in
+//! QEMU. A test arms a fault with [inject], the next hits of the matching injection point then
+//! fail as if the fault really happened:
+//!
+//! ```ignore
+//! // the second allocation from now on fails, all the others succeed
+//! fault::inject(Fault::Allocation, 1, 1);
+//! ```
+//!
+//! Disarmed injection points cost a single atomic load. The test framework disarms all faults
+//! before each test, see [crate::testing::fixture].
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::metrics::Counter;
+
+/// An injectable fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The kernel heap allocator returns null.
+    Allocation,
+    /// A keyboard or serial interrupt is handled without delivering its input, as if the interrupt
+    /// was lost.
+    Interrupt,
+    /// The ring of ready tasks of the executor is full when a task is spawned or woken, the task
+    /// spills into the growable queue.
+    TaskQueue,
+}
+
+const FAULTS: usize = 3;
+
+/// The state of an injection point.
+struct Point {
+    /// number of hits passing before the faults
+    skip: AtomicU64,
+    /// number of hits still to fail
+    remaining: AtomicU64,
+}
+
+static POINTS: [Point; FAULTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const DISARMED: Point = Point {
+        skip: AtomicU64::new(0),
+        remaining: AtomicU64::new(0),
+    };
+    [DISARMED; FAULTS]
+};
+
+/// The injected page faults, pages overlapping `[start, end)`.
+static PAGE_FAULT_START: AtomicU64 = AtomicU64::new(0);
+static PAGE_FAULT_END: AtomicU64 = AtomicU64::new(0);
+
+static INJECTED: Counter = Counter::new("fault.injected");
+
+/// Let `skip` hits of the injection point of `fault` pass, then fail the following `times` hits.
+/// Replaces the previous injection of the same fault.
+pub fn inject(fault: Fault, skip: u64, times: u64) {
+    let point = &POINTS[fault as usize];
+    // disarm first, a hit in between must not see the new skip count with the old remaining count
+    point.remaining.store(0, Ordering::SeqCst);
+    point.skip.store(skip, Ordering::SeqCst);
+    point.remaining.store(times, Ordering::SeqCst);
+}
+
+/// Disarm `fault`.
+pub fn clear(fault: Fault) {
+    POINTS[fault as usize].remaining.store(0, Ordering::SeqCst);
+}
+
+/// Simulate page faults on the pages overlapping the `len`-byte range starting at `start`:
+/// [crate::memory::translate] reports them as not mapped, code checking addresses before accessing
+/// them takes its error path. Replaces the previously injected range.
+pub fn inject_page_fault(start: VirtAddr, len: u64) {
+    let end = start
+        .as_u64()
+        .saturating_add(len.max(1))
+        .saturating_add(4095)
+        & !4095;
+    PAGE_FAULT_END.store(0, Ordering::SeqCst);
+    PAGE_FAULT_START.store(start.align_down(4096u64).as_u64(), Ordering::SeqCst);
+    PAGE_FAULT_END.store(end, Ordering::SeqCst);
+}
+
+/// Disarm all the faults including the injected page faults.
+pub fn clear_all() {
+    for point in POINTS.iter() {
+        point.remaining.store(0, Ordering::SeqCst);
+    }
+    PAGE_FAULT_END.store(0, Ordering::SeqCst);
+}
+
+/// Whether this hit of the injection point of `fault` fails, called by the code subject to the
+/// fault.
+pub(crate) fn should_fail(fault: Fault) -> bool {
+    let point = &POINTS[fault as usize];
+    if point.remaining.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+
+    let skipped = point
+        .skip
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |skip| {
+            skip.checked_sub(1)
+        })
+        .is_ok();
+    if skipped {
+        return false;
+    }
+
+    let failed = point
+        .remaining
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+            remaining.checked_sub(1)
+        })
+        .is_ok();
+    if failed {
+        INJECTED.inc();
+    }
+    failed
+}
+
+/// Whether a page fault is injected at `addr`.
+pub(crate) fn page_fault_at(addr: VirtAddr) -> bool {
+    let end = PAGE_FAULT_END.load(Ordering::Relaxed);
+    if end == 0 {
+        return false;
+    }
+    let start = PAGE_FAULT_START.load(Ordering::Relaxed);
+    (start..end).contains(&addr.as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory;
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    #[test_case]
+    fn fail_after_skipping() {
+        inject(Fault::TaskQueue, 2, 2);
+        let mut hits = [false; 5];
+        for hit in hits.iter_mut() {
+            *hit = should_fail(Fault::TaskQueue);
+        }
+        assert_eq!(hits, [false, false, true, true, false]);
+    }
+
+    #[test_case]
+    fn fail_allocation() {
+        let layout = Layout::new::<u64>();
+        inject(Fault::Allocation, 1, 1);
+        // # Safety
+        // The layout has a non-zero size, the allocations are freed with the same layout.
+        unsafe {
+            let first = alloc(layout);
+            assert!(!first.is_null());
+            assert!(alloc(layout).is_null());
+            let third = alloc(layout);
+            assert!(!third.is_null());
+            dealloc(first, layout);
+            dealloc(third, layout);
+        }
+    }
+
+    #[test_case]
+    fn simulated_page_fault() {
+        let value = 0u64;
+        let addr = VirtAddr::from_ptr(&value);
+        inject_page_fault(addr, 8);
+        assert!(!memory::is_mapped(addr, 8, false));
+        clear_all();
+        assert!(memory::is_mapped(addr, 8, false));
+    }
+}
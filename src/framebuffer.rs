@@ -0,0 +1,358 @@
+//! A text console drawn with a bitmap font on the framebuffer of the virtio GPU, for displays
+//! without the VGA text mode, e.g. under UEFI or in the high resolution modes of `-vga std`.
+//!
+//! The console is enabled by the `framebuffer=on` option on the kernel command line when a GPU is
+//! present, see [init]. From then on `print!` and the log records are routed to it instead of the
+//! [VGA text buffer](crate::vga_buffer). The glyphs come from [FONT], a PC screen font of 8x16
+//! pixels rendered from DejaVu Sans Mono, see [psf].
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use self::psf::Font;
+use crate::virtio::gpu::{self, GpuError, Rect};
+
+pub mod psf;
+
+/// The font of the console.
+pub static FONT: &[u8] = include_bytes!("framebuffer/font.psf");
+
+/// The color of the text, the yellow of the VGA text buffer.
+const FOREGROUND: u32 = 0xffff55;
+const BACKGROUND: u32 = 0x000000;
+/// The distance between two tab stops.
+const TAB_WIDTH: u32 = 8;
+const BACKSPACE: u8 = 0x08;
+/// The glyph of the characters without one, a small square in code page 437.
+const UNPRINTABLE: u8 = 0xfe;
+
+/// A grid of character cells on a framebuffer of `width` x `height` pixels, `0xRRGGBB` each row
+/// after row. The console only keeps its position, the text is in the pixels.
+pub struct TextConsole {
+    font: Font<'static>,
+    width: u32,
+    height: u32,
+    columns: u32,
+    rows: u32,
+    row: u32,
+    column: u32,
+}
+
+impl TextConsole {
+    /// A console in `font` on a framebuffer of `width` x `height` pixels, with as many whole cells
+    /// as fit, at least one.
+    pub fn new(font: Font<'static>, width: u32, height: u32) -> Self {
+        TextConsole {
+            font,
+            width,
+            height,
+            columns: (width / font.width()).max(1),
+            rows: (height / font.height()).max(1),
+            row: 0,
+            column: 0,
+        }
+    }
+
+    /// The number of columns and rows of cells.
+    pub fn size(&self) -> (u32, u32) {
+        (self.columns, self.rows)
+    }
+
+    /// The row and the column of the next character.
+    pub fn position(&self) -> (u32, u32) {
+        (self.row, self.column)
+    }
+
+    /// Write `args` to `pixels` as the VGA text buffer would, returns the rectangle of pixels
+    /// changed. Panics if `pixels` are fewer than those of the framebuffer.
+    pub fn print(&mut self, pixels: &mut [u32], args: fmt::Arguments) -> Rect {
+        assert!(pixels.len() >= (self.width * self.height) as usize);
+
+        let mut printer = Printer {
+            console: self,
+            pixels,
+            dirty: None,
+        };
+        // writing to the pixels can't fail, a formatting trait returning error is ignored
+        let _ = fmt::Write::write_fmt(&mut printer, args);
+        let dirty = printer.dirty;
+        match dirty {
+            Some((top, bottom)) => {
+                let height = self.font.height();
+                Rect::new(0, top * height, self.width, (bottom - top + 1) * height)
+            }
+            None => Rect::default(),
+        }
+    }
+
+    /// Move the position of the next character `n` cells backwards, stops at the top left.
+    pub fn move_left(&mut self, n: u32) {
+        let position = self.linear_position().saturating_sub(n);
+        self.set_linear_position(position);
+    }
+
+    /// Move the position of the next character `n` cells forwards, stops at the bottom right.
+    pub fn move_right(&mut self, n: u32) {
+        let position = self.linear_position().saturating_add(n);
+        self.set_linear_position(position.min(self.columns * self.rows - 1));
+    }
+
+    /// Blank the whole framebuffer, the next character is written to the top left.
+    pub fn clear(&mut self, pixels: &mut [u32]) {
+        pixels[..(self.width * self.height) as usize].fill(BACKGROUND);
+        self.set_linear_position(0);
+    }
+
+    /// A full row (`column == columns`) is the same as the start of the next row.
+    fn linear_position(&self) -> u32 {
+        self.row * self.columns + self.column
+    }
+
+    fn set_linear_position(&mut self, position: u32) {
+        self.row = position / self.columns;
+        self.column = position % self.columns;
+    }
+
+    fn draw_glyph(&self, pixels: &mut [u32], row: u32, column: u32, code: u8) {
+        let (glyph_width, glyph_height) = (self.font.width(), self.font.height());
+        for y in 0..glyph_height {
+            let start = ((row * glyph_height + y) * self.width + column * glyph_width) as usize;
+            let line = &mut pixels[start..start + glyph_width as usize];
+            for (x, pixel) in (0..).zip(line.iter_mut()) {
+                *pixel = if self.font.is_set(usize::from(code), x, y) {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+            }
+        }
+    }
+
+    /// Move to the start of the next row, scrolls the text up a row from the last row. Returns
+    /// whether it scrolled.
+    fn new_line(&mut self, pixels: &mut [u32]) -> bool {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return false;
+        }
+
+        let row_size = (self.font.height() * self.width) as usize;
+        let text_size = row_size * self.rows as usize;
+        pixels.copy_within(row_size..text_size, 0);
+        pixels[text_size - row_size..text_size].fill(BACKGROUND);
+        true
+    }
+}
+
+/// Writes to a [TextConsole] and tracks the rows changed.
+struct Printer<'a> {
+    console: &'a mut TextConsole,
+    pixels: &'a mut [u32],
+    /// The first and the last rows changed.
+    dirty: Option<(u32, u32)>,
+}
+
+impl Printer<'_> {
+    fn touch(&mut self, row: u32) {
+        self.dirty = Some(match self.dirty {
+            Some((top, bottom)) => (top.min(row), bottom.max(row)),
+            None => (row, row),
+        });
+    }
+
+    fn new_line(&mut self) {
+        if self.console.new_line(self.pixels) {
+            self.dirty = Some((0, self.console.rows - 1));
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            BACKSPACE => {
+                if let Some(position) = self.console.linear_position().checked_sub(1) {
+                    self.console.set_linear_position(position);
+                    let (row, column) = self.console.position();
+                    self.console.draw_glyph(self.pixels, row, column, b' ');
+                    self.touch(row);
+                }
+            }
+            b'\t' => {
+                if self.console.column >= self.console.columns {
+                    self.new_line();
+                }
+                let stop = (self.console.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.console.column < stop.min(self.console.columns) {
+                    self.write_byte(b' ');
+                }
+            }
+            _ => {
+                if self.console.column >= self.console.columns {
+                    self.new_line();
+                }
+                let (row, column) = self.console.position();
+                self.console.draw_glyph(self.pixels, row, column, byte);
+                self.touch(row);
+                self.console.column += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Write for Printer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            let code = match byte {
+                0x20..=0x7e | b'\n' | b'\t' | BACKSPACE => byte,
+                _ => UNPRINTABLE,
+            };
+            self.write_byte(code);
+        }
+        Ok(())
+    }
+}
+
+/// The console on the GPU, `None` until [init].
+static CONSOLE: Mutex<Option<TextConsole>> = Mutex::new(None);
+/// Whether the output is routed to [CONSOLE].
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn font() -> Font<'static> {
+    Font::parse(FONT).expect("the embedded font is invalid")
+}
+
+/// Route `print!` and the log records to a console on the framebuffer of the GPU, which must have
+/// been initialized, see [gpu::init]. The screen is cleared.
+pub fn init() -> Result<(), GpuError> {
+    let (width, height) = gpu::resolution()?;
+    let mut console = TextConsole::new(font(), width, height);
+    gpu::draw(|framebuffer| console.clear(framebuffer.pixels()))?;
+    gpu::flush(Rect::new(0, 0, width, height))?;
+
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+    ACTIVE.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Whether the output is printed to the framebuffer instead of the VGA text buffer.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Apply `f` to the console, then update the display with the rectangle it returns. The console
+/// starts over on a blank screen if the mode of the display changed.
+fn with_console(f: impl FnOnce(&mut TextConsole, &mut [u32]) -> Rect) {
+    // An interrupt handler may print while the console is locked, as for the VGA text buffer.
+    interrupts::without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        let console = match console.as_mut() {
+            Some(console) => console,
+            None => return,
+        };
+
+        let dirty = gpu::draw(|framebuffer| {
+            let (width, height) = (framebuffer.width(), framebuffer.height());
+            if (width, height) != (console.width, console.height) {
+                *console = TextConsole::new(font(), width, height);
+                console.clear(framebuffer.pixels());
+                return Rect::new(0, 0, width, height);
+            }
+            f(console, framebuffer.pixels())
+        });
+        // there's nowhere to report the failure of printing
+        if let Ok(rect) = dirty {
+            let _ = gpu::flush(rect);
+        }
+    });
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    with_console(|console, pixels| console.print(pixels, args));
+}
+
+/// Move the position of the next character printed to the framebuffer `n` cells backwards.
+pub fn cursor_left(n: usize) {
+    with_console(|console, _| {
+        console.move_left(n as u32);
+        Rect::default()
+    });
+}
+
+/// Move the position of the next character printed to the framebuffer `n` cells forwards.
+pub fn cursor_right(n: usize) {
+    with_console(|console, _| {
+        console.move_right(n as u32);
+        Rect::default()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Whether the cell in `row` and `column` of `pixels` shows the glyph of `code`.
+    fn shows(console: &TextConsole, pixels: &[u32], row: u32, column: u32, code: u8) -> bool {
+        let font = console.font;
+        (0..font.height()).all(|y| {
+            (0..font.width()).all(|x| {
+                let offset = (row * font.height() + y) * console.width + column * font.width() + x;
+                let expected = if font.is_set(usize::from(code), x, y) {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+                pixels[offset as usize] == expected
+            })
+        })
+    }
+
+    #[test_case]
+    fn text_drawn() {
+        // 4 columns and 2 rows, with a few pixels to spare
+        let (width, height) = (36, 40);
+        let mut pixels = vec![0x12_3456; (width * height) as usize];
+        let mut console = TextConsole::new(font(), width, height);
+        assert_eq!(console.size(), (4, 2));
+        console.clear(&mut pixels);
+
+        let dirty = console.print(&mut pixels, format_args!("a\tb{}", '\u{e9}'));
+        assert_eq!(dirty, Rect::new(0, 0, width, 32));
+        assert!(shows(&console, &pixels, 0, 0, b'a'));
+        assert!(shows(&console, &pixels, 0, 1, b' '));
+        // the tab stop is past the last column, the next character goes on the next row
+        assert!(shows(&console, &pixels, 1, 0, b'b'));
+        assert!(shows(&console, &pixels, 1, 1, UNPRINTABLE));
+        assert!(shows(&console, &pixels, 1, 2, UNPRINTABLE));
+        assert_eq!(console.position(), (1, 3));
+
+        console.move_left(3);
+        let dirty = console.print(&mut pixels, format_args!("\x08c"));
+        assert_eq!(dirty, Rect::new(0, 0, width, 16));
+        assert!(shows(&console, &pixels, 0, 3, b'c'));
+    }
+
+    #[test_case]
+    fn text_scrolled() {
+        let (width, height) = (16, 32);
+        let mut pixels = vec![0; (width * height) as usize];
+        let mut console = TextConsole::new(font(), width, height);
+        console.clear(&mut pixels);
+
+        console.print(&mut pixels, format_args!("ab\ncd"));
+        let dirty = console.print(&mut pixels, format_args!("\ne"));
+        assert_eq!(dirty, Rect::new(0, 0, width, height));
+        assert!(shows(&console, &pixels, 0, 0, b'c'));
+        assert!(shows(&console, &pixels, 0, 1, b'd'));
+        assert!(shows(&console, &pixels, 1, 0, b'e'));
+        assert!(shows(&console, &pixels, 1, 1, b' '));
+        assert_eq!(console.position(), (1, 1));
+    }
+}
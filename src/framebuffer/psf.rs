@@ -0,0 +1,185 @@
+//! PC Screen Fonts, the bitmap fonts of the Linux console, in version 1 or 2.
+//!
+//! Glyphs are bitmaps of `height` rows of `width` bits, the leftmost pixel in the most significant
+//! bit, each row padded to whole bytes. Only the glyphs are read, characters are mapped to the
+//! glyph of their index in code page 437 as in the VGA text buffer, unicode tables are ignored.
+
+use core::{convert::TryInto, fmt};
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+/// Set in the mode of version 1 fonts of 512 glyphs instead of 256.
+const PSF1_MODE_512: u8 = 0x01;
+/// The width of the glyphs of version 1 fonts.
+const PSF1_WIDTH: u32 = 8;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+/// The smallest header of version 2 fonts, the glyphs follow at the offset in its field
+/// [PSF2_HEADER_SIZE].
+const PSF2_MIN_HEADER_SIZE: usize = 32;
+/// Offsets of the fields of the header of version 2 fonts.
+const PSF2_HEADER_SIZE: usize = 8;
+const PSF2_LENGTH: usize = 16;
+const PSF2_CHAR_SIZE: usize = 20;
+const PSF2_HEIGHT: usize = 24;
+const PSF2_WIDTH: usize = 28;
+
+/// Errors of [Font::parse].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// The data doesn't start with the magic number of either version.
+    BadMagic,
+    /// The data is shorter than its header says.
+    Truncated,
+    /// The glyphs are empty or their size doesn't match their dimensions.
+    BadGeometry,
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontError::BadMagic => write!(f, "not a PC screen font"),
+            FontError::Truncated => write!(f, "truncated font"),
+            FontError::BadGeometry => write!(f, "invalid glyph dimensions"),
+        }
+    }
+}
+
+/// A parsed font, borrowing its glyphs.
+#[derive(Debug, Clone, Copy)]
+pub struct Font<'a> {
+    glyphs: &'a [u8],
+    count: usize,
+    glyph_size: usize,
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Font<'a> {
+    /// Parse the font in `data`, either version.
+    pub fn parse(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_v2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_v1(data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_v1(data: &'a [u8]) -> Result<Self, FontError> {
+        let (mode, height) = match data.get(2..PSF1_HEADER_SIZE) {
+            Some(&[mode, height]) => (mode, height),
+            _ => return Err(FontError::Truncated),
+        };
+        let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        Self::new(
+            &data[PSF1_HEADER_SIZE..],
+            count,
+            usize::from(height),
+            PSF1_WIDTH,
+            u32::from(height),
+        )
+    }
+
+    fn parse_v2(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.len() < PSF2_MIN_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+        let field = |offset: usize| {
+            u32::from_le_bytes(data[offset..offset + 4].try_into().expect("4 bytes"))
+        };
+        let glyphs = data
+            .get(field(PSF2_HEADER_SIZE) as usize..)
+            .ok_or(FontError::Truncated)?;
+        Self::new(
+            glyphs,
+            field(PSF2_LENGTH) as usize,
+            field(PSF2_CHAR_SIZE) as usize,
+            field(PSF2_WIDTH),
+            field(PSF2_HEIGHT),
+        )
+    }
+
+    fn new(
+        glyphs: &'a [u8],
+        count: usize,
+        glyph_size: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, FontError> {
+        let row_size = (width as usize + 7) / 8;
+        if count == 0 || width == 0 || height == 0 || glyph_size != row_size * height as usize {
+            return Err(FontError::BadGeometry);
+        }
+        let glyphs = count
+            .checked_mul(glyph_size)
+            .and_then(|size| glyphs.get(..size))
+            .ok_or(FontError::Truncated)?;
+
+        Ok(Font {
+            glyphs,
+            count,
+            glyph_size,
+            width,
+            height,
+        })
+    }
+
+    /// The width of the glyphs in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The height of the glyphs in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Whether the pixel in the column `x` of the row `y` of the glyph of `code` is set. Codes
+    /// past the last glyph are drawn as blanks.
+    pub fn is_set(&self, code: usize, x: u32, y: u32) -> bool {
+        if code >= self.count || x >= self.width || y >= self.height {
+            return false;
+        }
+        let row_size = (self.width as usize + 7) / 8;
+        let byte = self.glyphs[code * self.glyph_size + y as usize * row_size + x as usize / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn embedded_font_parsed() {
+        let font = Font::parse(super::super::FONT).unwrap();
+        assert_eq!((font.width(), font.height()), (8, 16));
+        // the bar of the capital H
+        assert!((1..7).any(|x| font.is_set(usize::from(b'H'), x, 8)));
+        assert!((0..8).all(|x| (0..16).all(|y| !font.is_set(usize::from(b' '), x, y))));
+        assert!(!font.is_set(256, 0, 0));
+    }
+
+    #[test_case]
+    fn version_1_parsed() {
+        // 256 glyphs of 8x2 pixels, the glyph 1 is a diagonal
+        let mut data = Vec::from(&[0x36, 0x04, 0, 2][..]);
+        data.resize(PSF1_HEADER_SIZE + 256 * 2, 0);
+        data[PSF1_HEADER_SIZE + 2..PSF1_HEADER_SIZE + 4].copy_from_slice(&[0x80, 0x40]);
+        let font = Font::parse(&data).unwrap();
+        assert_eq!((font.width(), font.height()), (8, 2));
+        assert!(font.is_set(1, 0, 0) && font.is_set(1, 1, 1));
+        assert!(!font.is_set(1, 1, 0) && !font.is_set(0, 0, 0));
+
+        assert_eq!(
+            Font::parse(&data[..PSF1_HEADER_SIZE + 100]).unwrap_err(),
+            FontError::Truncated
+        );
+        assert_eq!(Font::parse(b"font").unwrap_err(), FontError::BadMagic);
+        data[3] = 0;
+        assert_eq!(Font::parse(&data).unwrap_err(), FontError::BadGeometry);
+    }
+}
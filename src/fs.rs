@@ -0,0 +1,478 @@
+//! A virtual filesystem, the filesystems mounted in a single tree of absolute paths.
+//!
+//! A filesystem exposes its files and directories as [Node]s reached from its [FileSystem::root],
+//! and is [mount]ed at a path, e.g. the initial ramdisk at [initrd::MOUNT_POINT]. A path is
+//! resolved in the filesystem mounted at its longest prefix, the directories leading to the mount
+//! points, e.g. `/` and `/boot`, exist as long as something is mounted below them.
+//!
+//! Paths are absolute and resolved lexically: empty components and `.` are skipped, `..` goes up
+//! one directory, never above `/`.
+
+use alloc::{borrow::ToOwned, boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::{fmt, future::Future, pin::Pin};
+
+use spin::Mutex;
+
+use crate::{
+    block::{self, BlockError},
+    task,
+};
+
+pub mod console;
+pub mod devfs;
+pub mod fat;
+pub mod fd;
+pub mod initrd;
+pub mod mmap;
+pub mod pipe;
+pub mod ramfs;
+pub mod tarfs;
+
+/// The mounted filesystems, in the order they were mounted.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+struct Mount {
+    /// The components of the mount point.
+    components: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// Errors of the filesystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No such file or directory.
+    NotFound,
+    /// A component of the path is a file.
+    NotADirectory,
+    /// The node is a directory, which can't be read as a file.
+    IsADirectory,
+    /// The filesystem can't be written.
+    ReadOnly,
+    /// The path isn't absolute.
+    InvalidPath,
+    /// A filesystem is already mounted at the path.
+    Busy,
+    /// No filesystem is mounted at the path.
+    NotMounted,
+    /// The data of the filesystem is inconsistent, with what is wrong.
+    Corrupted(&'static str),
+    /// The file wasn't opened for the access.
+    PermissionDenied,
+    /// The descriptor isn't open.
+    BadDescriptor,
+    /// The descriptor table is full.
+    TooManyOpenFiles,
+    /// The offset would be negative or overflow.
+    InvalidSeek,
+    /// The node is a stream without offsets.
+    NotSeekable,
+    /// Nothing can be read or written without waiting.
+    WouldBlock,
+    /// The pipe has no reader left.
+    BrokenPipe,
+    /// The block device of the filesystem failed.
+    Device(BlockError),
+    /// The entry to create already exists.
+    AlreadyExists,
+    /// The file would be larger than the filesystem allows.
+    FileTooLarge,
+    /// An argument is out of range, e.g. an offset not aligned to a page.
+    InvalidArgument,
+    /// The node can't be mapped into memory, e.g. a device.
+    NotMappable,
+    /// The kernel ran out of memory or of room to track the request.
+    OutOfMemory,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::ReadOnly => write!(f, "read-only filesystem"),
+            FsError::InvalidPath => write!(f, "path is not absolute"),
+            FsError::Busy => write!(f, "a filesystem is already mounted there"),
+            FsError::NotMounted => write!(f, "no filesystem mounted there"),
+            FsError::Corrupted(what) => write!(f, "corrupted filesystem: {}", what),
+            FsError::PermissionDenied => write!(f, "permission denied"),
+            FsError::BadDescriptor => write!(f, "bad file descriptor"),
+            FsError::TooManyOpenFiles => write!(f, "too many open files"),
+            FsError::InvalidSeek => write!(f, "invalid seek"),
+            FsError::NotSeekable => write!(f, "illegal seek"),
+            FsError::WouldBlock => write!(f, "resource temporarily unavailable"),
+            FsError::BrokenPipe => write!(f, "broken pipe"),
+            FsError::Device(err) => write!(f, "{}", err),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::FileTooLarge => write!(f, "file too large"),
+            FsError::InvalidArgument => write!(f, "invalid argument"),
+            FsError::NotMappable => write!(f, "no such device"),
+            FsError::OutOfMemory => write!(f, "cannot allocate memory"),
+        }
+    }
+}
+
+/// The kinds of nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A character device, read and written as a stream, the offsets are ignored.
+    CharDevice,
+}
+
+/// What is known of a node without reading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// The kind of the node.
+    pub kind: NodeKind,
+    /// The size of a file in bytes, 0 for a directory or a device.
+    pub size: u64,
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry in the directory.
+    pub name: String,
+    /// The kind of the node of the entry.
+    pub kind: NodeKind,
+}
+
+/// A file or a directory of a filesystem. The operations not supported by the kind of the node
+/// fail by default.
+pub trait Node: Send + Sync {
+    /// What is known of the node.
+    fn metadata(&self) -> Metadata;
+
+    /// The node private to a file opened on this node, e.g. a reader of a stream with its own
+    /// position, `None` if the files share this node.
+    fn open(&self) -> Result<Option<Arc<dyn Node>>, FsError> {
+        Ok(None)
+    }
+
+    /// The entry `name` of a directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Node>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// The entries of a directory, sorted by name.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Read the bytes of a file from `offset` on into `buf`, returns the number of bytes read, 0
+    /// at the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Write `buf` to a file from `offset` on, returns the number of bytes written.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Set the size of a file to `len`, cut or extended with zeros.
+    fn set_len(&self, _len: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// A future ready once the node can be read, or written if `write` is set, without failing
+    /// with [FsError::WouldBlock]. `None` for the nodes which never become ready by themselves,
+    /// e.g. the console.
+    fn ready(&self, _write: bool) -> Option<Ready> {
+        None
+    }
+
+    /// Create the entry `name` of `kind` in a directory, returns its node.
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Node>, FsError> {
+        match self.metadata().kind {
+            NodeKind::Directory => Err(FsError::ReadOnly),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+/// The future of [Node::ready].
+pub type Ready = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A filesystem, mounted with [mount].
+pub trait FileSystem: Send + Sync {
+    /// The type of the filesystem, e.g. `tarfs`.
+    fn name(&self) -> &'static str;
+
+    /// The root directory of the filesystem.
+    fn root(&self) -> Arc<dyn Node>;
+}
+
+/// A mounted filesystem, as listed by [mounts].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    /// The mount point.
+    pub path: String,
+    /// The type of the filesystem.
+    pub fs: &'static str,
+}
+
+/// The components of the absolute path `path`, resolved lexically.
+fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+/// The path made of `components`.
+fn join<S: AsRef<str>>(components: &[S]) -> String {
+    if components.is_empty() {
+        return "/".to_owned();
+    }
+    let mut path = String::new();
+    for component in components {
+        path.push('/');
+        path.push_str(component.as_ref());
+    }
+    path
+}
+
+/// Whether the path of `components` is below `prefix` or `prefix` itself.
+fn is_below(components: &[&str], prefix: &[String]) -> bool {
+    components.len() >= prefix.len() && prefix.iter().zip(components).all(|(a, b)| a == b)
+}
+
+/// Mount `fs` at `path`, which may be a node of another filesystem or not exist at all.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let components: Vec<String> = components(path)?
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .collect();
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.components == components) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount { components, fs });
+    Ok(())
+}
+
+/// Mount the block device `device`, e.g. `sda`, at `path`: its FAT32 volume, see
+/// [fat::FatFs::from_device], or else the archive at its start, see [tarfs::TarFs::from_device].
+/// Halts until the device is read, see [task::block_on].
+pub fn mount_device(device: &str, path: &str) -> Result<(), FsError> {
+    let device = block::get(device).ok_or(FsError::NotFound)?;
+    let fat = task::block_on(fat::FatFs::from_device(Arc::new(device)));
+    let fs: Arc<dyn FileSystem> = match fat {
+        Ok(fs) => Arc::new(fs),
+        Err(fat::NO_VOLUME) => Arc::new(task::block_on(tarfs::TarFs::from_device(device))?),
+        Err(err) => return Err(err),
+    };
+    mount(path, fs)
+}
+
+/// Unmount the filesystem mounted at `path`, returns it.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let components = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.components == components)
+        .ok_or(FsError::NotMounted)?;
+    Ok(mounts.remove(index).fs)
+}
+
+/// The mounted filesystems, sorted by mount point.
+pub fn mounts() -> Vec<MountInfo> {
+    let mut mounts: Vec<MountInfo> = MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| MountInfo {
+            path: join(&mount.components),
+            fs: mount.fs.name(),
+        })
+        .collect();
+    mounts.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    mounts
+}
+
+/// The node at the absolute path `path`.
+pub fn lookup(path: &str) -> Result<Arc<dyn Node>, FsError> {
+    let components = components(path)?;
+    let (root, rest) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|mount| is_below(&components, &mount.components))
+            .max_by_key(|mount| mount.components.len());
+        match mount {
+            Some(mount) => (mount.fs.root(), &components[mount.components.len()..]),
+            None => {
+                let node = MountPoints::new(&mounts, &components).ok_or(FsError::NotFound)?;
+                return Ok(Arc::new(node));
+            }
+        }
+    };
+
+    let mut node = root;
+    for component in rest {
+        node = node.lookup(component)?;
+    }
+    Ok(node)
+}
+
+/// Create the node of `kind` at `path` in the directory of its parent.
+pub fn create(path: &str, kind: NodeKind) -> Result<Arc<dyn Node>, FsError> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(FsError::AlreadyExists)?;
+    lookup(&join(&components))?.create(name, kind)
+}
+
+/// Read the whole file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    let node = lookup(path)?;
+    let metadata = node.metadata();
+    if metadata.kind == NodeKind::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    let mut data = alloc::vec![0; metadata.size as usize];
+    let mut len = 0;
+    while len < data.len() {
+        match node.read_at(len as u64, &mut data[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// The entries of the directory at `path`, sorted by name.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path)?.read_dir()
+}
+
+/// A directory outside of any filesystem leading to mount points, holding the next component of
+/// each of them.
+struct MountPoints {
+    names: Vec<String>,
+}
+
+impl MountPoints {
+    /// The directory at `components`, `None` if no mount point is below it.
+    fn new(mounts: &[Mount], components: &[&str]) -> Option<Self> {
+        let mut names: Vec<String> = mounts
+            .iter()
+            .filter(|mount| {
+                mount.components.len() > components.len()
+                    && is_below(components, &mount.components[..components.len()])
+            })
+            .map(|mount| mount.components[components.len()].clone())
+            .collect();
+        if names.is_empty() {
+            return None;
+        }
+        names.sort_unstable();
+        names.dedup();
+        Some(MountPoints { names })
+    }
+}
+
+impl Node for MountPoints {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            size: 0,
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .names
+            .iter()
+            .map(|name| DirEntry {
+                name: name.clone(),
+                kind: NodeKind::Directory,
+            })
+            .collect())
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Node>, FsError> {
+        // paths are resolved by [lookup] from the mount table
+        Err(FsError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty filesystem.
+    struct Empty;
+
+    impl FileSystem for Empty {
+        fn name(&self) -> &'static str {
+            "empty"
+        }
+
+        fn root(&self) -> Arc<dyn Node> {
+            Arc::new(MountPoints { names: Vec::new() })
+        }
+    }
+
+    #[test_case]
+    fn paths_resolved_lexically() {
+        assert_eq!(components("/").unwrap(), Vec::<&str>::new());
+        assert_eq!(components("//a/./b/").unwrap(), ["a", "b"]);
+        assert_eq!(components("/a/../../b/..").unwrap(), Vec::<&str>::new());
+        assert_eq!(components("a/b"), Err(FsError::InvalidPath));
+        assert_eq!(join::<&str>(&[]), "/");
+        assert_eq!(join(&["a".to_owned(), "b".to_owned()]), "/a/b");
+    }
+
+    #[test_case]
+    fn mounted_and_unmounted() {
+        mount("/test/mnt/./empty", Arc::new(Empty)).unwrap();
+        assert_eq!(
+            mount("/test/mnt/empty/", Arc::new(Empty)).unwrap_err(),
+            FsError::Busy
+        );
+        assert!(mounts().contains(&MountInfo {
+            path: "/test/mnt/empty".to_owned(),
+            fs: "empty",
+        }));
+
+        // the directories leading to the mount point
+        let entries = read_dir("/test/mnt").unwrap();
+        assert_eq!(
+            entries,
+            [DirEntry {
+                name: "empty".to_owned(),
+                kind: NodeKind::Directory,
+            }]
+        );
+        assert!(read_dir("/")
+            .unwrap()
+            .iter()
+            .any(|entry| entry.name == "test"));
+        assert_eq!(read_dir("/test/mnt/empty").unwrap(), []);
+        assert_eq!(lookup("/test/other").err(), Some(FsError::NotFound));
+        assert_eq!(read("/test/mnt/empty").unwrap_err(), FsError::IsADirectory);
+
+        assert_eq!(unmount("/test/mnt/empty").unwrap().name(), "empty");
+        assert!(matches!(
+            unmount("/test/mnt/empty"),
+            Err(FsError::NotMounted)
+        ));
+        assert_eq!(lookup("/test").err(), Some(FsError::NotFound));
+    }
+}
@@ -0,0 +1,62 @@
+//! The console as a character device, the standard streams of the descriptor tables.
+//!
+//! Writes go to the consoles of the kernel command line, the VGA text buffer and the serial
+//! terminal, see [crate::cmdline::Console]. The keys typed are read by the shell sessions, a read
+//! of the console has nothing to return and fails with [FsError::WouldBlock].
+
+use core::{fmt::Write, str};
+
+use super::{FsError, Metadata, Node, NodeKind};
+use crate::{cmdline, print, shell::console::SerialConsole};
+
+/// The console.
+pub struct Console;
+
+impl Console {
+    fn write_str(s: &str) {
+        let console = cmdline::config().console;
+        if console.vga() {
+            print!("{}", s);
+        }
+        if console.serial() {
+            // translates line feeds for terminals in raw mode
+            SerialConsole.write_str(s).unwrap();
+        }
+    }
+}
+
+impl Node for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::WouldBlock)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        // invalid UTF-8 is printed as replacement characters
+        let mut rest = buf;
+        while !rest.is_empty() {
+            match str::from_utf8(rest) {
+                Ok(s) => {
+                    Console::write_str(s);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    // # Safety
+                    // The bytes up to `valid_up_to` are valid UTF-8.
+                    Console::write_str(unsafe { str::from_utf8_unchecked(valid) });
+                    Console::write_str("\u{fffd}");
+                    let skipped = err.error_len().unwrap_or(invalid.len());
+                    rest = &invalid[skipped..];
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+}
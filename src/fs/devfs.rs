@@ -0,0 +1,313 @@
+//! The device filesystem mounted at [MOUNT_POINT], the drivers as character devices:
+//!
+//! - `console`: the console, see [super::console]
+//! - `serial0`: the first serial port, written as is without translating line feeds
+//! - `null`: reads nothing, discards writes
+//! - `zero`: reads zeros, discards writes
+//! - `random`: reads random bytes, see [crate::random], writes are mixed into the entropy pool
+//! - `kmsg`: reads the log records, one per line as printed by `dmesg`, from the oldest still in
+//!   the ring on for each open file, writes are logged at the info level with the target `kmsg`
+//!
+//! The input of the console and of the serial port is read by the shell sessions, reads of these
+//! devices fail with [FsError::WouldBlock].
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{fmt::Write, str};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{console::Console, DirEntry, FileSystem, FsError, Metadata, Node, NodeKind};
+use crate::{
+    entropy,
+    klog::{self, Level},
+    random,
+    serial::SERIAL1,
+    warn,
+};
+
+/// Where the device filesystem is mounted.
+pub const MOUNT_POINT: &str = "/dev";
+
+const DEVICE: Metadata = Metadata {
+    kind: NodeKind::CharDevice,
+    size: 0,
+};
+
+/// The device filesystem.
+pub struct DevFs {
+    root: Arc<Directory>,
+}
+
+impl DevFs {
+    /// The filesystem with all the devices.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mut devices: BTreeMap<&'static str, Arc<dyn Node>> = BTreeMap::new();
+        devices.insert("console", Arc::new(Console));
+        devices.insert("serial0", Arc::new(Serial));
+        devices.insert("null", Arc::new(Null));
+        devices.insert("zero", Arc::new(Zero));
+        devices.insert("random", Arc::new(Random));
+        devices.insert("kmsg", Arc::new(Kmsg));
+        DevFs {
+            root: Arc::new(Directory { devices }),
+        }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        self.root.clone()
+    }
+}
+
+/// Mount the device filesystem at [MOUNT_POINT].
+pub fn init() {
+    if let Err(err) = super::mount(MOUNT_POINT, Arc::new(DevFs::new())) {
+        warn!("devfs not mounted: {}", err);
+    }
+}
+
+struct Directory {
+    devices: BTreeMap<&'static str, Arc<dyn Node>>,
+}
+
+impl Node for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        self.devices.get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .devices
+            .keys()
+            .map(|&name| DirEntry {
+                name: String::from(name),
+                kind: NodeKind::CharDevice,
+            })
+            .collect())
+    }
+}
+
+struct Serial;
+
+impl Node for Serial {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::WouldBlock)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        // the port is also locked by the serial prints of interrupt handlers
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.lock();
+            for &byte in buf {
+                serial.send(byte);
+            }
+        });
+        Ok(buf.len())
+    }
+}
+
+struct Null;
+
+impl Node for Null {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+struct Zero;
+
+impl Node for Zero {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+struct Random;
+
+impl Node for Random {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        random::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        entropy::add(buf);
+        Ok(buf.len())
+    }
+}
+
+/// The log, each open file reads it from its own position.
+struct Kmsg;
+
+impl Node for Kmsg {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn open(&self) -> Result<Option<Arc<dyn Node>>, FsError> {
+        Ok(Some(Arc::new(KmsgReader {
+            next_seq: Mutex::new(0),
+        })))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        // without an open file, e.g. [super::read], from the oldest record
+        KmsgReader {
+            next_seq: Mutex::new(0),
+        }
+        .read_at(offset, buf)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        for line in buf.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            // written records pass the filters, as on Linux
+            klog::_log(
+                Level::Info,
+                "kmsg",
+                format_args!("{}", String::from_utf8_lossy(line)),
+            );
+        }
+        Ok(buf.len())
+    }
+}
+
+struct KmsgReader {
+    /// The sequence number of the next record to read.
+    next_seq: Mutex<u64>,
+}
+
+impl Node for KmsgReader {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    /// Read as many whole records as fit into `buf`, a record longer than `buf` is truncated.
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut next_seq = self.next_seq.lock();
+        let mut len = 0;
+        for record in klog::read_since(*next_seq) {
+            let mut line = String::new();
+            writeln!(line, "{}", record).unwrap();
+            let line = line.as_bytes();
+            if len + line.len() > buf.len() {
+                if len == 0 {
+                    len = buf.len();
+                    buf.copy_from_slice(&line[..len]);
+                    *next_seq = record.seq + 1;
+                }
+                break;
+            }
+            buf[len..len + line.len()].copy_from_slice(line);
+            len += line.len();
+            *next_seq = record.seq + 1;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Kmsg.write_at(offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fd::{FdTable, OpenFlags};
+
+    #[test_case]
+    fn devices_listed() {
+        let names: Vec<String> = crate::fs::read_dir(MOUNT_POINT)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(
+            names,
+            ["console", "kmsg", "null", "random", "serial0", "zero"]
+        );
+    }
+
+    #[test_case]
+    fn null_zero_and_random_read() {
+        let mut table = FdTable::new();
+        let null = table.open("/dev/null", OpenFlags::READ_WRITE).unwrap();
+        let zero = table.open("/dev/zero", OpenFlags::READ_WRITE).unwrap();
+        let random = table.open("/dev/random", OpenFlags::READ).unwrap();
+
+        let mut buf = [0xaa; 64];
+        assert_eq!(table.read(null, &mut buf), Ok(0));
+        assert_eq!(table.write(null, &buf), Ok(64));
+        assert_eq!(table.read(zero, &mut buf), Ok(64));
+        assert_eq!(buf, [0; 64]);
+        assert_eq!(table.read(random, &mut buf), Ok(64));
+        // all zeros with a probability of 2^-512
+        assert_ne!(buf, [0; 64]);
+    }
+
+    #[test_case]
+    fn kmsg_read_from_each_file() {
+        let mut table = FdTable::new();
+        let first = table.open("/dev/kmsg", OpenFlags::READ_WRITE).unwrap();
+        let second = table.open("/dev/kmsg", OpenFlags::READ).unwrap();
+
+        let mut buf = alloc::vec![0; 64 * 1024];
+        while table.read(first, &mut buf).unwrap() > 0 {}
+        table.write(first, b"written to kmsg\n").unwrap();
+        let len = table.read(first, &mut buf).unwrap();
+        let log = str::from_utf8(&buf[..len]).unwrap();
+        assert!(log.ends_with(": written to kmsg\n"));
+        assert_eq!(log.lines().count(), 1);
+
+        // the second file starts at the oldest record
+        let len = table.read(second, &mut buf).unwrap();
+        assert!(str::from_utf8(&buf[..len]).unwrap().lines().count() > 1);
+
+        // a record longer than the buffer is truncated
+        let mut small = [0; 4];
+        table.write(first, b"another record\n").unwrap();
+        assert_eq!(table.read(first, &mut small), Ok(4));
+        assert_eq!(table.read(first, &mut small), Ok(0));
+    }
+}
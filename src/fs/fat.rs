@@ -0,0 +1,922 @@
+//! A read-only FAT32 filesystem on a block device.
+//!
+//! [FatFs::from_device] finds the volume at the start of the device, or in the first FAT32
+//! partition of its MBR, e.g. a disk image made by `mkfs.fat -F 32` or partitioned by `fdisk`.
+//! Nothing but the boot sector is read on mount: the directories are read whenever they're looked
+//! up or listed, the files cluster by cluster following their chains in the FAT.
+//!
+//! Long file names are assembled from the VFAT entries preceding the short entry they belong to,
+//! those which don't match its checksum are dropped in favor of the 8.3 name, lowercased as
+//! Windows NT flags it. Names are looked up as written first, then ignoring the ASCII case as on
+//! Windows.
+//!
+//! The methods of [Node] aren't async, the nodes read the device with [task::block_on].
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::{char, convert::TryFrom};
+
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FsError, Metadata, Node, NodeKind};
+use crate::{
+    block::{BlockDevice, SECTOR_SIZE},
+    task,
+};
+
+/// Neither the device nor the first partition of its MBR holds a FAT32 volume.
+pub const NO_VOLUME: FsError = FsError::Corrupted("no FAT32 volume");
+
+/// The signature ending boot sectors and MBRs.
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// The offset of the partition table in an MBR, 4 entries of [PARTITION_ENTRY_SIZE] bytes.
+const PARTITION_TABLE: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+/// The partition types of FAT32 volumes, addressed in CHS or LBA.
+const PARTITION_TYPES: [u8; 2] = [0x0b, 0x0c];
+
+/// The size of a directory entry.
+const ENTRY_SIZE: usize = 32;
+/// The first byte of the name of the entry ending a directory.
+const ENTRY_END: u8 = 0x00;
+/// The first byte of the name of a deleted entry.
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stands for 0xe5 as the first byte of a name, which would mark the entry deleted.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// The attributes of the entries of long file names.
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// Set in the order of the last entry of a long name, the first one in the directory.
+const LONG_NAME_LAST: u8 = 0x40;
+/// The number of UTF-16 units of a long name in an entry.
+const LONG_NAME_UNITS: usize = 13;
+/// Set in the NT flags of an entry if its 8.3 name, respectively extension, is lowercase.
+const NT_LOWERCASE_NAME: u8 = 0x08;
+const NT_LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// The bits of a FAT entry, the upper 4 are reserved.
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+/// The FAT entries from this one on end a chain.
+const FAT_END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// The first valid cluster, the first two FAT entries are reserved.
+const FIRST_CLUSTER: u32 = 2;
+
+const INVALID_CHAIN: FsError = FsError::Corrupted("invalid cluster chain");
+
+/// A FAT32 volume mounted as a filesystem.
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+impl FatFs {
+    /// Mount the FAT32 volume at the start of `device`, or else in the first FAT32 partition of
+    /// its MBR. Fails with [NO_VOLUME] if there is none.
+    pub async fn from_device(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut sector = [0; SECTOR_SIZE];
+        read_sector(&*device, 0, &mut sector).await?;
+        if let Some(volume) = Volume::new(device.clone(), 0, &sector)? {
+            return Ok(FatFs::with_volume(volume));
+        }
+
+        for start in partitions(&sector) {
+            read_sector(&*device, start, &mut sector).await?;
+            if let Some(volume) = Volume::new(device.clone(), start, &sector)? {
+                return Ok(FatFs::with_volume(volume));
+            }
+        }
+        Err(NO_VOLUME)
+    }
+
+    fn with_volume(volume: Volume) -> Self {
+        FatFs {
+            volume: Arc::new(volume),
+        }
+    }
+
+    /// The first sector of the volume on the device.
+    pub fn start(&self) -> u64 {
+        self.volume.start
+    }
+
+    /// The size of a cluster in bytes.
+    pub fn cluster_size(&self) -> usize {
+        self.volume.cluster_size
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        Arc::new(Directory {
+            volume: self.volume.clone(),
+            cluster: self.volume.root_cluster,
+        })
+    }
+}
+
+async fn read_sector(
+    device: &dyn BlockDevice,
+    lba: u64,
+    sector: &mut [u8; SECTOR_SIZE],
+) -> Result<(), FsError> {
+    device.read(lba, sector).await.map_err(FsError::Device)
+}
+
+/// The first sectors of the FAT32 partitions of the MBR `sector`, none if it isn't an MBR.
+fn partitions(sector: &[u8; SECTOR_SIZE]) -> Vec<u64> {
+    let entries = if sector[510..] == BOOT_SIGNATURE {
+        4
+    } else {
+        0
+    };
+    (0..entries)
+        .filter_map(|index| {
+            let entry = &sector[PARTITION_TABLE + index * PARTITION_ENTRY_SIZE..];
+            let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+            if PARTITION_TYPES.contains(&entry[4]) && start > 0 {
+                Some(u64::from(start))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// The layout of a volume, from its boot sector. The sectors are those of the device.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    /// The first sector of the volume.
+    start: u64,
+    /// The first sector of the first FAT, relative to [Volume::start].
+    fat_start: u64,
+    /// The first sector of cluster 2, relative to [Volume::start].
+    data_start: u64,
+    sectors_per_cluster: u64,
+    cluster_size: usize,
+    /// The number of clusters, numbered from [FIRST_CLUSTER] on.
+    cluster_count: u32,
+    root_cluster: u32,
+    /// The last sector of the FAT read, relative to [Volume::start], sequential reads of a file
+    /// look up their clusters in the same sector.
+    fat_cache: Mutex<Option<(u64, [u8; SECTOR_SIZE])>>,
+}
+
+impl Volume {
+    /// The volume of the boot sector `sector` at `start` of `device`, `None` if `sector` isn't the
+    /// boot sector of a FAT32 volume.
+    fn new(
+        device: Arc<dyn BlockDevice>,
+        start: u64,
+        sector: &[u8; SECTOR_SIZE],
+    ) -> Result<Option<Self>, FsError> {
+        let bytes_per_sector = read_u16(sector, 11);
+        let sectors_per_cluster = sector[13];
+        let reserved_sectors = read_u16(sector, 14);
+        let fat_count = sector[16];
+        let root_entries = read_u16(sector, 17);
+        let total_sectors_16 = read_u16(sector, 19);
+        let fat_size_16 = read_u16(sector, 22);
+        let total_sectors = read_u32(sector, 32);
+        let fat_size = read_u32(sector, 36);
+        let root_cluster = read_u32(sector, 44);
+
+        // a jump to the boot code, then a BPB of FAT32: no root directory region nor 16-bit FAT
+        let is_fat32 = sector[510..] == BOOT_SIGNATURE
+            && (sector[0] == 0xeb || sector[0] == 0xe9)
+            && bytes_per_sector.is_power_of_two()
+            && usize::from(bytes_per_sector) >= SECTOR_SIZE
+            && bytes_per_sector <= 4096
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fat_count > 0
+            && fat_size > 0
+            && root_entries == 0
+            && total_sectors_16 == 0
+            && fat_size_16 == 0;
+        if !is_fat32 {
+            return Ok(None);
+        }
+
+        let scale = u64::from(bytes_per_sector) / SECTOR_SIZE as u64;
+        let fat_start = u64::from(reserved_sectors);
+        let data_start = fat_start + u64::from(fat_count) * u64::from(fat_size);
+        let clusters = u64::from(total_sectors)
+            .checked_sub(data_start)
+            .ok_or(FsError::Corrupted("FATs larger than the volume"))?
+            / u64::from(sectors_per_cluster);
+        // the FAT must hold an entry for each cluster
+        let fat_entries = u64::from(fat_size) * u64::from(bytes_per_sector) / 4;
+        let cluster_count = clusters.min(fat_entries.saturating_sub(u64::from(FIRST_CLUSTER)));
+        let cluster_count = u32::try_from(cluster_count)
+            .ok()
+            .filter(|&count| count < FAT_END_OF_CHAIN - FIRST_CLUSTER)
+            .ok_or(FsError::Corrupted("too many clusters"))?;
+        if start + u64::from(total_sectors) * scale > device.sector_count() {
+            return Err(FsError::Corrupted("volume larger than the device"));
+        }
+
+        let volume = Volume {
+            device,
+            start,
+            fat_start: fat_start * scale,
+            data_start: data_start * scale,
+            sectors_per_cluster: u64::from(sectors_per_cluster) * scale,
+            cluster_size: usize::from(sectors_per_cluster) * usize::from(bytes_per_sector),
+            cluster_count,
+            root_cluster,
+            fat_cache: Mutex::new(None),
+        };
+        if !volume.is_valid(root_cluster) {
+            return Err(FsError::Corrupted("invalid root cluster"));
+        }
+        Ok(Some(volume))
+    }
+
+    /// Read the sectors from `lba` on, relative to [Volume::start], into `buf`.
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        task::block_on(self.device.read(self.start + lba, buf)).map_err(FsError::Device)
+    }
+
+    fn is_valid(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    /// Read `cluster` into `buf`, [Volume::cluster_size] bytes.
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if !self.is_valid(cluster) {
+            return Err(INVALID_CHAIN);
+        }
+        let lba = self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.sectors_per_cluster;
+        self.read(lba, buf)
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end of the chain.
+    fn next(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        if !self.is_valid(cluster) {
+            return Err(INVALID_CHAIN);
+        }
+        let offset = u64::from(cluster) * 4;
+        let lba = self.fat_start + offset / SECTOR_SIZE as u64;
+        let within = (offset % SECTOR_SIZE as u64) as usize;
+
+        let cached = match *self.fat_cache.lock() {
+            Some((cached, ref sector)) if cached == lba => Some(read_u32(sector, within)),
+            _ => None,
+        };
+        let entry = match cached {
+            Some(entry) => entry,
+            None => {
+                let mut sector = [0; SECTOR_SIZE];
+                self.read(lba, &mut sector)?;
+                *self.fat_cache.lock() = Some((lba, sector));
+                read_u32(&sector, within)
+            }
+        };
+
+        match entry & FAT_ENTRY_MASK {
+            entry if entry >= FAT_END_OF_CHAIN => Ok(None),
+            entry if self.is_valid(entry) => Ok(Some(entry)),
+            // free or bad clusters in the middle of a chain
+            _ => Err(INVALID_CHAIN),
+        }
+    }
+
+    /// The `n`th cluster (from 0) of the chain starting at `cluster`.
+    fn nth(&self, mut cluster: u32, n: u64) -> Result<u32, FsError> {
+        for _ in 0..n {
+            cluster = self.next(cluster)?.ok_or(INVALID_CHAIN)?;
+        }
+        Ok(cluster)
+    }
+
+    /// The content of the chain starting at `cluster`.
+    fn read_chain(&self, cluster: u32) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut next = Some(cluster);
+        while let Some(cluster) = next {
+            // a chain longer than the volume loops
+            if data.len() / self.cluster_size >= self.cluster_count as usize {
+                return Err(INVALID_CHAIN);
+            }
+            let start = data.len();
+            data.resize(start + self.cluster_size, 0);
+            self.read_cluster(cluster, &mut data[start..])?;
+            next = self.next(cluster)?;
+        }
+        Ok(data)
+    }
+}
+
+/// An entry of a directory, with its long name if it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    name: String,
+    kind: NodeKind,
+    /// The first cluster, 0 for an empty file.
+    cluster: u32,
+    size: u32,
+}
+
+/// The long name being assembled from the entries preceding a short entry.
+struct LongName {
+    /// The UTF-16 units, [LONG_NAME_UNITS] per entry in the order of the name.
+    units: Vec<u16>,
+    /// The order of the entry expected next, counting down to 1.
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    /// Add the long name entry `entry`, returns `false` if it isn't the one expected.
+    fn add(&mut self, entry: &[u8]) -> bool {
+        let order = entry[0] & !LONG_NAME_LAST;
+        if order != self.next || order == 0 || entry[13] != self.checksum {
+            return false;
+        }
+        let units = [1..11, 14..26, 28..32]
+            .iter()
+            .flat_map(|range| range.clone().step_by(2))
+            .map(|offset| read_u16(entry, offset));
+        let start = usize::from(order - 1) * LONG_NAME_UNITS;
+        for (slot, unit) in self.units[start..start + LONG_NAME_UNITS]
+            .iter_mut()
+            .zip(units)
+        {
+            *slot = unit;
+        }
+        self.next -= 1;
+        true
+    }
+
+    /// The name, if all of its entries were found for the short entry `entry`.
+    fn finish(&self, entry: &[u8]) -> Option<String> {
+        if self.next != 0 || self.checksum != checksum(&entry[..11]) {
+            return None;
+        }
+        // the name is terminated by a NUL and padded with 0xffff if it doesn't fill the entries
+        let len = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(self.units.len());
+        let name: String = char::decode_utf16(self.units[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some(name).filter(|name| !name.is_empty())
+    }
+}
+
+/// The checksum of the 8.3 name `name` stored in the entries of its long name.
+fn checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// The 8.3 name of the short entry `entry`, e.g. `README.TXT`.
+fn short_name(entry: &[u8]) -> String {
+    let lowercase = |bytes: &[u8], flag: u8| -> String {
+        let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        bytes[..len]
+            .iter()
+            .map(|&b| {
+                // code page 437 is assumed to be ASCII
+                let c = char::from(b);
+                if entry[12] & flag != 0 {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                }
+            })
+            .collect()
+    };
+    let mut base = entry[..8].to_vec();
+    if base[0] == ENTRY_KANJI_E5 {
+        base[0] = ENTRY_DELETED;
+    }
+    let mut name = lowercase(&base, NT_LOWERCASE_NAME);
+    let extension = lowercase(&entry[8..11], NT_LOWERCASE_EXTENSION);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// The entries of the directory `data`, without `.` and `..` nor the volume label.
+fn parse_directory(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for entry in data.chunks_exact(ENTRY_SIZE) {
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attributes = entry[11];
+        if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            if entry[0] & LONG_NAME_LAST != 0 {
+                let count = entry[0] & !LONG_NAME_LAST;
+                long_name = Some(LongName {
+                    units: vec![0; usize::from(count) * LONG_NAME_UNITS],
+                    next: count,
+                    checksum: entry[13],
+                });
+            }
+            if !long_name.as_mut().map_or(false, |name| name.add(entry)) {
+                long_name = None;
+            }
+            continue;
+        }
+
+        let long_name = long_name.take().and_then(|name| name.finish(entry));
+        if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+            continue;
+        }
+        entries.push(Entry {
+            name: long_name.unwrap_or_else(|| short_name(entry)),
+            kind: if attributes & ATTR_DIRECTORY != 0 {
+                NodeKind::Directory
+            } else {
+                NodeKind::File
+            },
+            cluster: u32::from(read_u16(entry, 20)) << 16 | u32::from(read_u16(entry, 26)),
+            size: read_u32(entry, 28),
+        });
+    }
+    entries
+}
+
+struct Directory {
+    volume: Arc<Volume>,
+    cluster: u32,
+}
+
+impl Directory {
+    fn entries(&self) -> Result<Vec<Entry>, FsError> {
+        Ok(parse_directory(&self.volume.read_chain(self.cluster)?))
+    }
+}
+
+impl Node for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        let entries = self.entries()?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .or_else(|| {
+                entries
+                    .iter()
+                    .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            })
+            .ok_or(FsError::NotFound)?;
+        let volume = self.volume.clone();
+        Ok(match entry.kind {
+            NodeKind::Directory => Arc::new(Directory {
+                // the parent entries of the subdirectories of the root point to cluster 0
+                cluster: match entry.cluster {
+                    0 => volume.root_cluster,
+                    cluster => cluster,
+                },
+                volume,
+            }),
+            _ => Arc::new(File {
+                volume,
+                cluster: entry.cluster,
+                size: entry.size,
+            }),
+        })
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut entries: Vec<DirEntry> = self
+            .entries()?
+            .into_iter()
+            .map(|entry| DirEntry {
+                name: entry.name,
+                kind: entry.kind,
+            })
+            .collect();
+        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+struct File {
+    volume: Arc<Volume>,
+    /// The first cluster, 0 for an empty file.
+    cluster: u32,
+    size: u32,
+}
+
+impl Node for File {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::File,
+            size: u64::from(self.size),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = u64::from(self.size);
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let cluster_size = self.volume.cluster_size;
+        let mut cluster = self
+            .volume
+            .nth(self.cluster, offset / cluster_size as u64)?;
+        let mut data = vec![0; cluster_size];
+        let mut done = 0;
+        loop {
+            self.volume.read_cluster(cluster, &mut data)?;
+            let within = ((offset + done as u64) % cluster_size as u64) as usize;
+            let piece = (len - done).min(cluster_size - within);
+            buf[done..done + piece].copy_from_slice(&data[within..within + piece]);
+            done += piece;
+            if done == len {
+                return Ok(len);
+            }
+            cluster = self.volume.next(cluster)?.ok_or(INVALID_CHAIN)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{BlockError, BlockFuture},
+        fs,
+    };
+    use alloc::boxed::Box;
+
+    /// A disk in memory.
+    struct RamDisk(Vec<u8>);
+
+    impl BlockDevice for RamDisk {
+        fn sector_count(&self) -> u64 {
+            (self.0.len() / SECTOR_SIZE) as u64
+        }
+
+        fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+            Box::pin(async move {
+                let start = lba as usize * SECTOR_SIZE;
+                buf.copy_from_slice(&self.0[start..start + buf.len()]);
+                Ok(())
+            })
+        }
+
+        fn write<'a>(&'a self, _lba: u64, _buf: &'a [u8]) -> BlockFuture<'a> {
+            Box::pin(async { Err(BlockError::OutOfRange) })
+        }
+    }
+
+    const RESERVED_SECTORS: usize = 4;
+    /// The size of each of the two FATs, 128 entries.
+    const FAT_SECTORS: usize = 1;
+    const VOLUME_SECTORS: usize = 96;
+    const DATA_START: usize = RESERVED_SECTORS + 2 * FAT_SECTORS;
+    const ROOT_CLUSTER: u32 = 2;
+
+    /// An entry of a directory being built: its 8.3 name, its long name, its attributes, its first
+    /// cluster and its size.
+    type TestEntry<'a> = (&'a [u8; 11], Option<&'a str>, u8, u32, u32);
+
+    /// A FAT32 volume of clusters of one sector, the root directory fits in one.
+    struct Image {
+        data: Vec<u8>,
+        next_cluster: u32,
+    }
+
+    impl Image {
+        fn new() -> Self {
+            let mut data = vec![0; VOLUME_SECTORS * SECTOR_SIZE];
+            data[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+            data[3..11].copy_from_slice(b"mkfs.fat");
+            data[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+            data[13] = 1;
+            data[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+            data[16] = 2;
+            data[21] = 0xf8;
+            data[32..36].copy_from_slice(&(VOLUME_SECTORS as u32).to_le_bytes());
+            data[36..40].copy_from_slice(&(FAT_SECTORS as u32).to_le_bytes());
+            data[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+            data[82..90].copy_from_slice(b"FAT32   ");
+            data[510..].copy_from_slice(&BOOT_SIGNATURE);
+            let mut image = Image {
+                data,
+                next_cluster: ROOT_CLUSTER + 1,
+            };
+            image.set_fat(0, 0x0fff_fff8);
+            image.set_fat(1, FAT_ENTRY_MASK);
+            image.set_fat(ROOT_CLUSTER, FAT_ENTRY_MASK);
+            image
+        }
+
+        /// Set the entry of `cluster` in both FATs.
+        fn set_fat(&mut self, cluster: u32, entry: u32) {
+            for fat in 0..2 {
+                let offset =
+                    (RESERVED_SECTORS + fat * FAT_SECTORS) * SECTOR_SIZE + cluster as usize * 4;
+                self.data[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+
+        fn cluster(&mut self, cluster: u32) -> &mut [u8] {
+            let start = (DATA_START + (cluster - ROOT_CLUSTER) as usize) * SECTOR_SIZE;
+            &mut self.data[start..start + SECTOR_SIZE]
+        }
+
+        /// Store `data` in a chain of clusters, returns the first one. Every other cluster is
+        /// skipped, the chains aren't contiguous.
+        fn store(&mut self, data: &[u8]) -> u32 {
+            let first = self.next_cluster + 1;
+            let chunks: Vec<&[u8]> = data.chunks(SECTOR_SIZE).collect();
+            for (index, chunk) in chunks.iter().enumerate() {
+                let cluster = self.next_cluster + 1;
+                self.next_cluster += 2;
+                self.cluster(cluster)[..chunk.len()].copy_from_slice(chunk);
+                let next = if index + 1 == chunks.len() {
+                    FAT_ENTRY_MASK
+                } else {
+                    self.next_cluster + 1
+                };
+                self.set_fat(cluster, next);
+            }
+            first
+        }
+
+        fn set_root(&mut self, entries: &[TestEntry]) {
+            let root = directory(entries);
+            self.cluster(ROOT_CLUSTER)[..root.len()].copy_from_slice(&root);
+        }
+
+        fn disk(self) -> Arc<dyn BlockDevice> {
+            Arc::new(RamDisk(self.data))
+        }
+    }
+
+    /// The entries of a directory, each preceded by the entries of its long name.
+    fn directory(entries: &[TestEntry]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &(short, long, attributes, cluster, size) in entries {
+            if let Some(long) = long {
+                let mut units: Vec<u16> = long.encode_utf16().collect();
+                if units.len() % LONG_NAME_UNITS != 0 {
+                    units.push(0);
+                }
+                while units.len() % LONG_NAME_UNITS != 0 {
+                    units.push(0xffff);
+                }
+                let count = units.len() / LONG_NAME_UNITS;
+                for order in (1..=count).rev() {
+                    let mut entry = [0; ENTRY_SIZE];
+                    entry[0] = order as u8 | if order == count { LONG_NAME_LAST } else { 0 };
+                    entry[11] = ATTR_LONG_NAME;
+                    entry[13] = checksum(short);
+                    let offsets = (1..11).step_by(2).chain((14..26).step_by(2));
+                    let offsets = offsets.chain((28..32).step_by(2));
+                    let part = &units[(order - 1) * LONG_NAME_UNITS..order * LONG_NAME_UNITS];
+                    for (offset, unit) in offsets.zip(part) {
+                        entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+                    }
+                    data.extend_from_slice(&entry);
+                }
+            }
+            let mut entry = [0; ENTRY_SIZE];
+            entry[..11].copy_from_slice(short);
+            entry[11] = attributes;
+            entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+            entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+            entry[28..32].copy_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&entry);
+        }
+        data
+    }
+
+    /// The content of the long file of [sample].
+    fn long_content() -> Vec<u8> {
+        (0..1300).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A volume with files in the root directory and a subdirectory.
+    fn sample() -> Image {
+        let mut image = Image::new();
+        let readme = image.store(b"hello");
+        let long = image.store(&long_content());
+        let nested = image.store(b"nested");
+        let sub = image.next_cluster + 1;
+        let sub_entries = directory(&[
+            (b".          ", None, ATTR_DIRECTORY, sub, 0),
+            (b"..         ", None, ATTR_DIRECTORY, 0, 0),
+            (b"NESTED  BIN", Some("nested.bin"), 0x20, nested, 6),
+            (b"EMPTY      ", None, 0x20, 0, 0),
+        ]);
+        assert_eq!(image.store(&sub_entries), sub);
+        image.set_root(&[
+            (b"TESTVOL    ", None, ATTR_VOLUME_ID, 0, 0),
+            (b"README  TXT", None, 0x20, readme, 5),
+            (b"\xe5ELETED TXT", Some("deleted.txt"), 0x20, 0, 0),
+            (
+                b"ALONGF~1TXT",
+                Some("A long file name.txt"),
+                0x20,
+                long,
+                1300,
+            ),
+            (b"SUB        ", None, ATTR_DIRECTORY, sub, 0),
+        ]);
+        image
+    }
+
+    fn mount(image: Image) -> FatFs {
+        task::block_on(FatFs::from_device(image.disk())).unwrap()
+    }
+
+    fn read(fs: &FatFs, path: &[&str]) -> Result<Vec<u8>, FsError> {
+        let mut node = fs.root();
+        for name in path {
+            node = node.lookup(name)?;
+        }
+        let mut data = vec![0; node.metadata().size as usize + 1];
+        let len = node.read_at(0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn names(node: &Arc<dyn Node>) -> Vec<String> {
+        node.read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    #[test_case]
+    fn files_and_directories_read() {
+        let fs = mount(sample());
+        assert_eq!(fs.start(), 0);
+        assert_eq!(fs.cluster_size(), SECTOR_SIZE);
+        assert_eq!(
+            names(&fs.root()),
+            ["A long file name.txt", "README.TXT", "SUB"]
+        );
+        assert_eq!(
+            names(&fs.root().lookup("SUB").unwrap()),
+            ["EMPTY", "nested.bin"]
+        );
+
+        assert_eq!(read(&fs, &["README.TXT"]).unwrap(), b"hello");
+        assert_eq!(read(&fs, &["readme.txt"]).unwrap(), b"hello");
+        assert_eq!(
+            read(&fs, &["A long file name.txt"]).unwrap(),
+            long_content()
+        );
+        assert_eq!(read(&fs, &["ALONGF~1.TXT"]), Err(FsError::NotFound));
+        assert_eq!(read(&fs, &["sub", "nested.bin"]).unwrap(), b"nested");
+        assert_eq!(read(&fs, &["SUB", "EMPTY"]).unwrap(), b"");
+        assert_eq!(read(&fs, &["SUB"]), Err(FsError::IsADirectory));
+        assert_eq!(read(&fs, &["deleted.txt"]), Err(FsError::NotFound));
+
+        // across the boundary between two clusters which aren't contiguous
+        let long = fs.root().lookup("A long file name.txt").unwrap();
+        let mut buf = [0; 10];
+        assert_eq!(long.read_at(507, &mut buf), Ok(10));
+        assert_eq!(buf[..], long_content()[507..517]);
+        assert_eq!(long.read_at(1295, &mut buf), Ok(5));
+        assert_eq!(long.read_at(1300, &mut buf), Ok(0));
+        assert_eq!(long.write_at(0, b"x"), Err(FsError::ReadOnly));
+        assert_eq!(
+            fs.root().create("new", NodeKind::File).err(),
+            Some(FsError::ReadOnly)
+        );
+    }
+
+    #[test_case]
+    fn mounted_in_the_tree() {
+        fs::mount("/test/fat", Arc::new(mount(sample()))).unwrap();
+        assert!(fs::mounts()
+            .iter()
+            .any(|mount| mount.path == "/test/fat" && mount.fs == "fat32"));
+        assert_eq!(fs::read("/test/fat/SUB/nested.bin").unwrap(), b"nested");
+        assert_eq!(fs::read("/test/fat/sub/../README.TXT").unwrap(), b"hello");
+        fs::unmount("/test/fat").unwrap();
+    }
+
+    #[test_case]
+    fn partition_found() {
+        // an MBR with a Linux partition, then a FAT32 one at sector 8
+        let mut disk = vec![0; 8 * SECTOR_SIZE];
+        let mut add_partition = |index: usize, kind: u8, start: u32| {
+            let entry = PARTITION_TABLE + index * PARTITION_ENTRY_SIZE;
+            disk[entry + 4] = kind;
+            disk[entry + 8..entry + 12].copy_from_slice(&start.to_le_bytes());
+            disk[entry + 12..entry + 16].copy_from_slice(&(VOLUME_SECTORS as u32).to_le_bytes());
+        };
+        add_partition(0, 0x83, 4);
+        add_partition(1, 0x0c, 8);
+        disk[510..SECTOR_SIZE].copy_from_slice(&BOOT_SIGNATURE);
+        disk.extend_from_slice(&sample().data);
+
+        let fs = task::block_on(FatFs::from_device(Arc::new(RamDisk(disk)))).unwrap();
+        assert_eq!(fs.start(), 8);
+        assert_eq!(read(&fs, &["SUB", "nested.bin"]).unwrap(), b"nested");
+
+        let blank = Arc::new(RamDisk(vec![0; 8 * SECTOR_SIZE]));
+        assert_eq!(
+            task::block_on(FatFs::from_device(blank)).err(),
+            Some(NO_VOLUME)
+        );
+    }
+
+    #[test_case]
+    fn short_names_and_long_names() {
+        let mut entries = directory(&[
+            (b"LOWER   TXT", None, 0x20, 0, 0),
+            (b"\x05AB     TXT", None, 0x20, 0, 0),
+            (b"NOEXT      ", None, 0x20, 0, 0),
+            (b"CHECKSUMTXT", Some("checksum mismatch.txt"), 0x20, 0, 0),
+            (
+                b"UNICODE TXT",
+                Some("\u{e9}t\u{e9} \u{1f600}.txt"),
+                0x20,
+                0,
+                0,
+            ),
+        ]);
+        // the NT flags of the first entry, a lowercase name and extension
+        entries[12] = NT_LOWERCASE_NAME | NT_LOWERCASE_EXTENSION;
+        // the checksum of the first long name entry of the fourth entry
+        let mismatched = 3 * ENTRY_SIZE;
+        assert_eq!(entries[mismatched + 11], ATTR_LONG_NAME);
+        entries[mismatched + 13] ^= 1;
+
+        let names: Vec<String> = parse_directory(&entries)
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "lower.txt",
+                "\u{e5}AB.TXT",
+                "NOEXT",
+                "CHECKSUM.TXT",
+                "\u{e9}t\u{e9} \u{1f600}.txt"
+            ]
+        );
+        assert_eq!(checksum(b"README  TXT"), 0x73);
+    }
+
+    #[test_case]
+    fn corrupted_volumes_refused() {
+        let mut image = sample();
+        // the chain of the long file, clusters 6, 8 and 10, ends in a free cluster after the first
+        image.set_fat(6, 0);
+        let fs = mount(image);
+        assert_eq!(read(&fs, &["A long file name.txt"]), Err(INVALID_CHAIN));
+        assert_eq!(read(&fs, &["README.TXT"]).unwrap(), b"hello");
+
+        // the root directory loops on itself
+        let mut image = sample();
+        image.set_fat(ROOT_CLUSTER, ROOT_CLUSTER);
+        assert_eq!(mount(image).root().read_dir(), Err(INVALID_CHAIN));
+
+        // the volume is larger than the device
+        let mut image = sample();
+        image.data.truncate(VOLUME_SECTORS / 2 * SECTOR_SIZE);
+        assert!(matches!(
+            task::block_on(FatFs::from_device(image.disk())),
+            Err(FsError::Corrupted("volume larger than the device"))
+        ));
+    }
+}
@@ -0,0 +1,444 @@
+//! File descriptors, the POSIX-ish interface to the files of the filesystems.
+//!
+//! A task owns a [FdTable], e.g. a shell session, later each process. A descriptor refers to an
+//! open file: a node of a filesystem with the offset of the next read or write and the access
+//! granted on [FdTable::open]. Descriptors duplicated with [FdTable::dup] share the open file and
+//! its offset, as on POSIX, and so do the descriptors of a table and of its copy made by
+//! [FdTable::inherit] for a child process. [FdTable::with_console] creates a table with the
+//! standard streams [STDIN], [STDOUT] and [STDERR] on the console. [FdTable::pipe] opens the two
+//! ends of a [pipe](super::pipe).
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+
+use spin::Mutex;
+
+use super::{
+    console::Console,
+    mmap::{self, MapFlags, MappedFile},
+    pipe, FsError, Node, NodeKind, Ready,
+};
+
+/// The largest number of descriptors open in a table.
+pub const MAX_FDS: usize = 64;
+
+/// The standard input.
+pub const STDIN: Fd = Fd(0);
+/// The standard output.
+pub const STDOUT: Fd = Fd(1);
+/// The standard error.
+pub const STDERR: Fd = Fd(2);
+
+/// A file descriptor, the index of an open file in a [FdTable].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(pub usize);
+
+impl fmt::Display for Fd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The access to an open file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags {
+    /// The file may be read.
+    pub read: bool,
+    /// The file may be written.
+    pub write: bool,
+    /// Every write goes to the end of the file.
+    pub append: bool,
+}
+
+impl OpenFlags {
+    /// Read only, `O_RDONLY`.
+    pub const READ: OpenFlags = OpenFlags {
+        read: true,
+        write: false,
+        append: false,
+    };
+    /// Write only, `O_WRONLY`.
+    pub const WRITE: OpenFlags = OpenFlags {
+        read: false,
+        write: true,
+        append: false,
+    };
+    /// Read and write, `O_RDWR`.
+    pub const READ_WRITE: OpenFlags = OpenFlags {
+        read: true,
+        write: true,
+        append: false,
+    };
+}
+
+/// The origin of [FdTable::seek].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// From the start of the file.
+    Start(u64),
+    /// From the current offset.
+    Current(i64),
+    /// From the end of the file.
+    End(i64),
+}
+
+/// A node opened by [FdTable::open].
+struct OpenFile {
+    node: Arc<dyn Node>,
+    flags: OpenFlags,
+    offset: u64,
+}
+
+impl OpenFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.read {
+            return Err(FsError::PermissionDenied);
+        }
+        let len = self.node.read_at(self.offset, buf)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.write {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.flags.append {
+            self.offset = self.node.metadata().size;
+        }
+        let len = self.node.write_at(self.offset, buf)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let metadata = self.node.metadata();
+        if metadata.kind == NodeKind::CharDevice {
+            return Err(FsError::NotSeekable);
+        }
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (metadata.size, delta),
+        };
+        let offset = if delta < 0 {
+            base.checked_sub(delta.unsigned_abs())
+        } else {
+            base.checked_add(delta as u64)
+        };
+        // seeking past the end is allowed, reads there return nothing
+        self.offset = offset.ok_or(FsError::InvalidSeek)?;
+        Ok(self.offset)
+    }
+}
+
+/// The files opened by a task, indexed by their descriptor.
+pub struct FdTable {
+    files: Vec<Option<Arc<Mutex<OpenFile>>>>,
+}
+
+impl FdTable {
+    /// An empty table.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// A table with the console open as [STDIN], [STDOUT] and [STDERR].
+    pub fn with_console() -> Self {
+        let mut table = FdTable::new();
+        let console: Arc<dyn Node> = Arc::new(Console);
+        for (fd, flags) in [
+            (STDIN, OpenFlags::READ),
+            (STDOUT, OpenFlags::WRITE),
+            (STDERR, OpenFlags::WRITE),
+        ]
+        .iter()
+        {
+            let installed = table.install(OpenFile {
+                node: console.clone(),
+                flags: *flags,
+                offset: 0,
+            });
+            assert_eq!(installed, Ok(*fd));
+        }
+        table
+    }
+
+    /// Put `file` at the lowest free descriptor, as on POSIX.
+    fn install(&mut self, file: OpenFile) -> Result<Fd, FsError> {
+        self.install_shared(Arc::new(Mutex::new(file)))
+    }
+
+    fn install_shared(&mut self, file: Arc<Mutex<OpenFile>>) -> Result<Fd, FsError> {
+        if let Some(index) = self.files.iter().position(Option::is_none) {
+            self.files[index] = Some(file);
+            return Ok(Fd(index));
+        }
+        if self.files.len() == MAX_FDS {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok(Fd(self.files.len() - 1))
+    }
+
+    fn get(&self, fd: Fd) -> Result<&Arc<Mutex<OpenFile>>, FsError> {
+        self.files
+            .get(fd.0)
+            .and_then(Option::as_ref)
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    /// Open the node at `path` with the access `flags`. Directories can't be opened for writing.
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Fd, FsError> {
+        let node = super::lookup(path)?;
+        if flags.write && node.metadata().kind == NodeKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let node = node.open()?.unwrap_or(node);
+        self.install(OpenFile {
+            node,
+            flags,
+            offset: 0,
+        })
+    }
+
+    /// Open a new pipe, returns the descriptors of its reading and of its writing end.
+    pub fn pipe(&mut self) -> Result<(Fd, Fd), FsError> {
+        let (reader, writer) = pipe::pipe();
+        let reader = self.install(OpenFile {
+            node: reader,
+            flags: OpenFlags::READ,
+            offset: 0,
+        })?;
+        let writer = self.install(OpenFile {
+            node: writer,
+            flags: OpenFlags::WRITE,
+            offset: 0,
+        });
+        match writer {
+            Ok(writer) => Ok((reader, writer)),
+            Err(err) => {
+                self.close(reader)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Read from `fd` into `buf` at its offset, returns the number of bytes read, 0 at the end of
+    /// the file.
+    pub fn read(&self, fd: Fd, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.get(fd)?.lock().read(buf)
+    }
+
+    /// Write `buf` to `fd` at its offset, returns the number of bytes written.
+    pub fn write(&self, fd: Fd, buf: &[u8]) -> Result<usize, FsError> {
+        self.get(fd)?.lock().write(buf)
+    }
+
+    /// A future ready once `fd` can be read, or written if `write` is set, without failing with
+    /// [FsError::WouldBlock], see [Node::ready]. `None` if the file never becomes ready by itself.
+    pub fn ready(&self, fd: Fd, write: bool) -> Result<Option<Ready>, FsError> {
+        Ok(self.get(fd)?.lock().node.ready(write))
+    }
+
+    /// Move the offset of `fd` to `pos`, returns the new offset from the start of the file.
+    pub fn seek(&self, fd: Fd, pos: SeekFrom) -> Result<u64, FsError> {
+        self.get(fd)?.lock().seek(pos)
+    }
+
+    /// Map `len` bytes of the file of `fd` from `offset` on, see [mmap::map]. The file must be
+    /// open for reading, and for writing as well to write a shared mapping back. The mapping
+    /// outlives the descriptor.
+    pub fn map(
+        &self,
+        fd: Fd,
+        offset: u64,
+        len: u64,
+        flags: MapFlags,
+    ) -> Result<MappedFile, FsError> {
+        let file = self.get(fd)?.lock();
+        if !file.flags.read || (flags.write && flags.shared && !file.flags.write) {
+            return Err(FsError::PermissionDenied);
+        }
+        mmap::map(file.node.clone(), offset, len, flags)
+    }
+
+    /// Close `fd`, the file stays open as long as another descriptor refers to it.
+    pub fn close(&mut self, fd: Fd) -> Result<(), FsError> {
+        let slot = self.files.get_mut(fd.0).ok_or(FsError::BadDescriptor)?;
+        slot.take().ok_or(FsError::BadDescriptor)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(())
+    }
+
+    /// A new descriptor, the lowest free, for the file of `fd`. Both share the offset.
+    pub fn dup(&mut self, fd: Fd) -> Result<Fd, FsError> {
+        let file = self.get(fd)?.clone();
+        self.install_shared(file)
+    }
+
+    /// Make `to` refer to the file of `fd`, closing the file `to` referred to, as `dup2` does.
+    /// Both share the offset.
+    pub fn dup_to(&mut self, fd: Fd, to: Fd) -> Result<Fd, FsError> {
+        let file = self.get(fd)?.clone();
+        if to.0 >= MAX_FDS {
+            return Err(FsError::BadDescriptor);
+        }
+        if self.files.len() <= to.0 {
+            self.files.resize(to.0 + 1, None);
+        }
+        self.files[to.0] = Some(file);
+        Ok(to)
+    }
+
+    /// A copy of the table for a child process, its descriptors refer to the same open files.
+    pub fn inherit(&self) -> Self {
+        FdTable {
+            files: self.files.clone(),
+        }
+    }
+
+    /// The number of open descriptors.
+    pub fn len(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+
+    /// Whether no descriptor is open.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Write for FdTable {
+    /// Write to [STDOUT], e.g. with `write!`.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(STDOUT, s.as_bytes())
+            .map(|_| ())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::initrd;
+    use alloc::format;
+
+    fn motd_path() -> alloc::string::String {
+        format!("{}/etc/motd", initrd::MOUNT_POINT)
+    }
+
+    #[test_case]
+    fn files_read_and_seeked() {
+        let motd = crate::fs::read(&motd_path()).unwrap();
+        let mut table = FdTable::new();
+        let fd = table.open(&motd_path(), OpenFlags::READ).unwrap();
+        assert_eq!(fd, Fd(0));
+
+        let mut buf = [0; 7];
+        assert_eq!(table.read(fd, &mut buf), Ok(7));
+        assert_eq!(buf, motd[..7]);
+        assert_eq!(table.seek(fd, SeekFrom::Current(-3)), Ok(4));
+        assert_eq!(table.read(fd, &mut buf[..3]), Ok(3));
+        assert_eq!(buf[..3], motd[4..7]);
+        assert_eq!(table.seek(fd, SeekFrom::End(0)), Ok(motd.len() as u64));
+        assert_eq!(table.read(fd, &mut buf), Ok(0));
+        assert_eq!(
+            table.seek(fd, SeekFrom::Current(-100)),
+            Err(FsError::InvalidSeek)
+        );
+
+        assert_eq!(table.write(fd, b"x"), Err(FsError::PermissionDenied));
+        let rw = table.open(&motd_path(), OpenFlags::READ_WRITE).unwrap();
+        assert_eq!(table.write(rw, b"x"), Err(FsError::ReadOnly));
+        assert_eq!(
+            table.open("/boot/initrd/nope", OpenFlags::READ).err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(
+            table.open("/boot/initrd", OpenFlags::WRITE).err(),
+            Some(FsError::IsADirectory)
+        );
+    }
+
+    #[test_case]
+    fn descriptors_allocated_lowest_first() {
+        let mut table = FdTable::with_console();
+        assert_eq!(table.len(), 3);
+        let a = table.open(&motd_path(), OpenFlags::READ).unwrap();
+        let b = table.dup(a).unwrap();
+        assert_eq!((a, b), (Fd(3), Fd(4)));
+
+        // duplicated descriptors share the offset
+        let mut buf = [0; 4];
+        table.read(a, &mut buf).unwrap();
+        assert_eq!(table.seek(b, SeekFrom::Current(0)), Ok(4));
+
+        table.close(a).unwrap();
+        assert_eq!(table.close(a), Err(FsError::BadDescriptor));
+        assert_eq!(table.read(b, &mut buf), Ok(4));
+        assert_eq!(table.open(&motd_path(), OpenFlags::READ), Ok(Fd(3)));
+        assert_eq!(
+            table.read(Fd(MAX_FDS), &mut buf),
+            Err(FsError::BadDescriptor)
+        );
+
+        while table.len() < MAX_FDS {
+            table.open(&motd_path(), OpenFlags::READ).unwrap();
+        }
+        assert_eq!(
+            table.open(&motd_path(), OpenFlags::READ),
+            Err(FsError::TooManyOpenFiles)
+        );
+    }
+
+    #[test_case]
+    fn standard_streams_on_console() {
+        let mut table = FdTable::with_console();
+        // nothing is printed in the middle of the test output
+        assert_eq!(table.write(STDOUT, b""), Ok(0));
+        assert_eq!(table.read(STDIN, &mut [0; 4]), Err(FsError::WouldBlock));
+        assert_eq!(table.write(STDIN, b"x"), Err(FsError::PermissionDenied));
+        assert_eq!(
+            table.seek(STDERR, SeekFrom::Start(0)),
+            Err(FsError::NotSeekable)
+        );
+        table.close(STDIN).unwrap();
+        assert_eq!(table.dup(STDERR), Ok(STDIN));
+    }
+
+    #[test_case]
+    fn pipes_opened_and_inherited() {
+        let mut table = FdTable::with_console();
+        let (reader, writer) = table.pipe().unwrap();
+        assert_eq!((reader, writer), (Fd(3), Fd(4)));
+        assert!(table.ready(reader, false).unwrap().is_some());
+        assert!(table.ready(STDIN, false).unwrap().is_none());
+        assert_eq!(
+            table.seek(reader, SeekFrom::Start(0)),
+            Err(FsError::NotSeekable)
+        );
+
+        // the child writes to the pipe on its standard output
+        let mut child = table.inherit();
+        assert_eq!(child.dup_to(writer, STDOUT), Ok(STDOUT));
+        child.close(writer).unwrap();
+        assert_eq!(child.write(STDOUT, b"abc"), Ok(3));
+        assert_eq!(
+            table.dup_to(writer, Fd(MAX_FDS)),
+            Err(FsError::BadDescriptor)
+        );
+        assert_eq!(table.dup_to(writer, Fd(9)), Ok(Fd(9)));
+        table.close(Fd(9)).unwrap();
+
+        let mut buf = [0; 4];
+        assert_eq!(table.read(reader, &mut buf), Ok(3));
+        table.close(writer).unwrap();
+        assert_eq!(table.read(reader, &mut buf), Err(FsError::WouldBlock));
+        drop(child);
+        assert_eq!(table.read(reader, &mut buf), Ok(0));
+    }
+}
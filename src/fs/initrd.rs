@@ -0,0 +1,77 @@
+//! The initial ramdisk, an archive of the files the kernel needs before any disk is mounted, e.g.
+//! user programs, fonts, keymaps and shell scripts.
+//!
+//! The bootloader loads nothing but the kernel, instead the archive is embedded into the kernel at
+//! build time: the build script packs the directory `initrd` at the root of the crate into a ustar
+//! archive, or takes the archive at the path in the environment variable `INITRD`, in the ustar
+//! or cpio format, e.g.
+//!
+//! ```text
+//! INITRD=/path/to/initramfs.cpio cargo run
+//! ```
+//!
+//! The archive is mounted read-only at [MOUNT_POINT] on boot. The default archive holds the test
+//! programs under `bin`, built from the assembly under `src`: flat binaries and `elfhello`, a
+//! static ELF executable.
+
+use alloc::sync::Arc;
+
+use super::tarfs::TarFs;
+use crate::{info, warn};
+
+/// Where the initial ramdisk is mounted.
+pub const MOUNT_POINT: &str = "/boot/initrd";
+
+/// The archive embedded by the build script.
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd"));
+
+/// The embedded archive, in the ustar or cpio format.
+pub fn archive() -> &'static [u8] {
+    ARCHIVE
+}
+
+/// Mount the embedded archive at [MOUNT_POINT], must be called once after the heap is
+/// initialized. An invalid archive is reported and left unmounted.
+pub fn init() {
+    let fs = match TarFs::new(ARCHIVE) {
+        Ok(fs) => fs,
+        Err(err) => {
+            warn!("initrd of {} bytes not mounted: {}", ARCHIVE.len(), err);
+            return;
+        }
+    };
+    info!(
+        "initrd of {} bytes with {} files mounted at {}",
+        ARCHIVE.len(),
+        fs.file_count(),
+        MOUNT_POINT
+    );
+    if let Err(err) = super::mount(MOUNT_POINT, Arc::new(fs)) {
+        warn!("initrd not mounted: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{self, NodeKind};
+
+    #[test_case]
+    fn mounted_on_boot() {
+        assert!(fs::mounts()
+            .iter()
+            .any(|mount| mount.path == MOUNT_POINT && mount.fs == "tarfs"));
+        let boot = fs::read_dir("/boot").unwrap();
+        assert!(boot
+            .iter()
+            .any(|entry| entry.name == "initrd" && entry.kind == NodeKind::Directory));
+    }
+
+    #[test_case]
+    fn motd_shipped() {
+        // initrd/etc/motd in the crate
+        let motd = fs::read("/boot/initrd/etc/motd").unwrap();
+        assert!(motd.starts_with(b"Welcome"));
+        assert_eq!(fs::read("/boot/initrd/etc/../etc/./motd").unwrap(), motd);
+    }
+}
@@ -0,0 +1,490 @@
+//! Files mapped into the address space, paged in on demand and written back when dirty.
+//!
+//! [map] reserves a range of virtual memory for a part of a file without reading anything, the
+//! first access to each page faults and [handle_page_fault] fills a frame from the file. A shared
+//! mapping sees the pages of the other shared mappings of the same node: the frames of the pages
+//! already mapped are the page cache of the file, a page is read once however many mappings
+//! share it and freed with its last mapping. The pages written through a shared writable mapping
+//! are dirty, [MappedFile::sync] and the drop of the mapping write them back to the file, without
+//! extending it. A private mapping gets its own copy of each page, its writes never reach the
+//! file.
+//!
+//! Writes to a file with [Node::write_at] aren't seen by its pages already mapped. Pages are
+//! filled under the locks of the faulting code, copying between a mapping and the node it maps,
+//! e.g. `node.write_at(0, mapping.as_slice())` on the first access to the mapping, deadlocks.
+
+use alloc::sync::Arc;
+use core::{
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{FsError, Node, NodeKind};
+use crate::{memory, metrics::Counter, warn};
+
+/// Start of the virtual memory region where files are mapped.
+pub const MMAP_START: u64 = 0x6666_6666_0000;
+/// The largest number of mappings at once.
+pub const MAX_MAPPINGS: usize = 64;
+const PAGE_SIZE: u64 = 4096;
+
+/// The next free virtual address in the mapping region, ranges are never reused.
+static MMAP_NEXT: AtomicU64 = AtomicU64::new(MMAP_START);
+/// The live mappings, without heap allocations so that page faults never allocate.
+static REGIONS: Mutex<[Option<Region>; MAX_MAPPINGS]> = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: Option<Region> = None;
+    Mutex::new([NONE; MAX_MAPPINGS])
+};
+
+static PAGE_INS: Counter = Counter::new("mmap.page_ins");
+static WRITE_BACKS: Counter = Counter::new("mmap.write_backs");
+
+/// The access to a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapFlags {
+    /// The mapping may be written.
+    pub write: bool,
+    /// The pages are shared with the other shared mappings of the file, writes are written back.
+    pub shared: bool,
+}
+
+impl MapFlags {
+    /// Read only, `PROT_READ` and `MAP_SHARED`.
+    pub const READ: MapFlags = MapFlags {
+        write: false,
+        shared: true,
+    };
+    /// Written back to the file, `PROT_READ | PROT_WRITE` and `MAP_SHARED`.
+    pub const SHARED_WRITE: MapFlags = MapFlags {
+        write: true,
+        shared: true,
+    };
+    /// Copied on access and never written back, `PROT_READ | PROT_WRITE` and `MAP_PRIVATE`.
+    pub const PRIVATE_WRITE: MapFlags = MapFlags {
+        write: true,
+        shared: false,
+    };
+}
+
+/// A range of virtual memory mapping a part of a file.
+#[derive(Clone)]
+struct Region {
+    start: VirtAddr,
+    pages: u64,
+    node: Arc<dyn Node>,
+    /// The offset in the file of the first page.
+    offset: u64,
+    flags: MapFlags,
+}
+
+impl Region {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.start + self.pages * PAGE_SIZE
+    }
+
+    /// The page mapping the page of the file at `offset`, if any.
+    fn page_at(&self, node: &Arc<dyn Node>, offset: u64) -> Option<Page> {
+        let same_node = Arc::as_ptr(&self.node) as *const u8 == Arc::as_ptr(node) as *const u8;
+        if !same_node || offset < self.offset || offset >= self.offset + self.pages * PAGE_SIZE {
+            return None;
+        }
+        Some(Page::containing_address(
+            self.start + (offset - self.offset),
+        ))
+    }
+}
+
+/// The frame of the page of `node` at `offset` mapped by one of the shared `regions`, if any.
+fn cached_frame(regions: &[Option<Region>], node: &Arc<dyn Node>, offset: u64) -> Option<PhysAddr> {
+    regions
+        .iter()
+        .flatten()
+        .filter(|region| region.flags.shared)
+        .filter_map(|region| region.page_at(node, offset))
+        .find_map(|page| memory::translate(page.start_address()))
+        .map(|(phys, _)| phys)
+}
+
+/// A mapping of a file, unmapped on drop after its dirty pages are written back.
+pub struct MappedFile {
+    slot: usize,
+    region: Region,
+    len: u64,
+}
+
+/// Map `len` bytes of the file `node` from `offset` on, a multiple of the page size. Nothing is
+/// read until the mapping is accessed, the bytes past the end of the file read as zeros.
+pub fn map(
+    node: Arc<dyn Node>,
+    offset: u64,
+    len: u64,
+    flags: MapFlags,
+) -> Result<MappedFile, FsError> {
+    match node.metadata().kind {
+        NodeKind::File => {}
+        NodeKind::Directory => return Err(FsError::IsADirectory),
+        NodeKind::CharDevice => return Err(FsError::NotMappable),
+    }
+    if len == 0 || offset % PAGE_SIZE != 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+    offset
+        .checked_add(pages * PAGE_SIZE)
+        .ok_or(FsError::InvalidArgument)?;
+
+    let mut regions = REGIONS.lock();
+    let slot = regions
+        .iter()
+        .position(Option::is_none)
+        .ok_or(FsError::OutOfMemory)?;
+    let start = VirtAddr::new(MMAP_NEXT.fetch_add(pages * PAGE_SIZE, Ordering::Relaxed));
+    let region = Region {
+        start,
+        pages,
+        node,
+        offset,
+        flags,
+    };
+    regions[slot] = Some(region.clone());
+    Ok(MappedFile { slot, region, len })
+}
+
+impl MappedFile {
+    /// The virtual address of the first byte of the mapping.
+    pub fn start(&self) -> VirtAddr {
+        self.region.start
+    }
+
+    /// The length of the mapping in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the mapping is empty, never true.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The access to the mapping.
+    pub fn flags(&self) -> MapFlags {
+        self.region.flags
+    }
+
+    /// The mapped bytes, the other shared mappings of the file may write them as well.
+    pub fn as_slice(&self) -> &[u8] {
+        // # Safety
+        // The range is reserved to the mapping until it's dropped, the pages are mapped on the
+        // first access by [handle_page_fault].
+        unsafe { slice::from_raw_parts(self.start().as_ptr(), self.len as usize) }
+    }
+
+    /// The mapped bytes, the other shared mappings of the file may access them as well.
+    ///
+    /// # Panics
+    /// Panics if the mapping isn't writable.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        assert!(self.region.flags.write, "mapping is read only");
+        // # Safety
+        // As in [MappedFile::as_slice], the pages are mapped writable.
+        unsafe { slice::from_raw_parts_mut(self.start().as_mut_ptr(), self.len as usize) }
+    }
+
+    /// Write the dirty pages of a shared mapping back to the file, up to the end of the file.
+    pub fn sync(&self) -> Result<(), FsError> {
+        let region = &self.region;
+        if !region.flags.shared || !region.flags.write {
+            return Ok(());
+        }
+
+        for index in 0..region.pages {
+            let page = Page::<Size4KiB>::containing_address(region.start + index * PAGE_SIZE);
+            let phys = match memory::translate(page.start_address()) {
+                Some((phys, flags)) if flags.contains(PageTableFlags::DIRTY) => phys,
+                _ => continue,
+            };
+            // cleared first, the writes during the write-back dirty the page again
+            memory::with_paging(|paging| {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                // # Safety
+                // Only the dirty bit is cleared.
+                if let Ok(flush) = unsafe { paging.mapper.update_flags(page, flags) } {
+                    flush.flush();
+                }
+            });
+
+            let offset = region.offset + index * PAGE_SIZE;
+            let size = region.node.metadata().size;
+            if offset >= size {
+                continue;
+            }
+            let len = (size - offset).min(PAGE_SIZE) as usize;
+            // # Safety
+            // The frame stays mapped by this mapping until it's dropped.
+            let bytes = unsafe { slice::from_raw_parts(memory::phys_to_virt(phys).as_ptr(), len) };
+            region.node.write_at(offset, bytes)?;
+            WRITE_BACKS.inc();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            warn!("dirty pages of a mapped file lost: {}", err);
+        }
+
+        let mut regions = REGIONS.lock();
+        regions[self.slot] = None;
+        let region = &self.region;
+        for index in 0..region.pages {
+            let page = Page::<Size4KiB>::containing_address(region.start + index * PAGE_SIZE);
+            let frame = memory::with_paging(|paging| {
+                let (frame, flush) = paging.mapper.unmap(page).ok()?;
+                flush.flush();
+                Some(frame)
+            })
+            .flatten();
+            let frame = match frame {
+                Some(frame) => frame,
+                None => continue,
+            };
+
+            let offset = region.offset + index * PAGE_SIZE;
+            let cached = region.flags.shared
+                && cached_frame(&*regions, &region.node, offset) == Some(frame.start_address());
+            if !cached {
+                memory::with_paging(|paging| {
+                    // # Safety
+                    // The frame was mapped by this mapping only and is no longer mapped.
+                    unsafe { paging.frames.deallocate_frame(frame) }
+                });
+            }
+        }
+    }
+}
+
+/// Map the page of a file mapping at `addr` on a page fault, returns whether the fault is handled,
+/// the access can be retried. Faults outside of the mappings, writes to read only mappings and
+/// accesses from user mode aren't handled.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code
+        .intersects(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE)
+    {
+        return false;
+    }
+
+    let regions = REGIONS.lock();
+    let region = match regions
+        .iter()
+        .flatten()
+        .find(|region| region.contains(addr))
+    {
+        Some(region) => region,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !region.flags.write {
+        return false;
+    }
+
+    let page = Page::containing_address(addr);
+    let offset = region.offset + (page.start_address() - region.start);
+    let cached = cached_frame(&*regions, &region.node, offset);
+    let frame = match cached {
+        Some(phys) if region.flags.shared => PhysFrame::containing_address(phys),
+        _ => match page_in(region, offset, cached) {
+            Some(frame) => frame,
+            None => return false,
+        },
+    };
+
+    let mut flags = PageTableFlags::PRESENT;
+    if region.flags.write {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let mapped = memory::with_paging(|paging| {
+        // # Safety
+        // The frame holds the page of the file, the page is reserved to the mapping.
+        let result = unsafe { paging.mapper.map_to(page, frame, flags, &mut paging.frames) };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(err) => {
+                warn!("page of a mapped file not mapped: {:?}", err);
+                if cached.is_none() || !region.flags.shared {
+                    // # Safety
+                    // The frame was allocated for the page which isn't mapped.
+                    unsafe { paging.frames.deallocate_frame(frame) };
+                }
+                false
+            }
+        }
+    });
+    mapped.unwrap_or(false)
+}
+
+/// A new frame holding the page of the file of `region` at `offset`, copied from the frame
+/// `cached` if the page is already mapped.
+fn page_in(region: &Region, offset: u64, cached: Option<PhysAddr>) -> Option<PhysFrame> {
+    let frame = memory::with_paging(|paging| paging.frames.allocate_frame()).flatten()?;
+    let virt = memory::phys_to_virt(frame.start_address());
+    // # Safety
+    // The frame is freshly allocated, it's accessed through the mapping of the physical memory.
+    let bytes = unsafe { slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), PAGE_SIZE as usize) };
+
+    if let Some(phys) = cached {
+        // # Safety
+        // The cached frame is mapped by another mapping, both frames are full pages.
+        unsafe {
+            let src = memory::phys_to_virt(phys).as_ptr::<u8>();
+            ptr::copy_nonoverlapping(src, bytes.as_mut_ptr(), bytes.len());
+        }
+        return Some(frame);
+    }
+
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match region
+            .node
+            .read_at(offset + filled as u64, &mut bytes[filled..])
+        {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) => {
+                warn!("page of a mapped file not read: {}", err);
+                memory::with_paging(|paging| {
+                    // # Safety
+                    // The frame was allocated above and never mapped.
+                    unsafe { paging.frames.deallocate_frame(frame) }
+                });
+                return None;
+            }
+        }
+    }
+    // past the end of the file
+    bytes[filled..].fill(0);
+    PAGE_INS.inc();
+    Some(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fs::{
+            self,
+            fd::{FdTable, OpenFlags},
+            initrd,
+        },
+        testing,
+    };
+    use alloc::{format, vec::Vec};
+
+    /// A file in /tmp of `len` bytes counting from 0, kept after the test.
+    fn counting_file(path: &str, len: usize) -> Arc<dyn Node> {
+        testing::keep_allocations(|| {
+            let node = fs::create(path, NodeKind::File).unwrap();
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            node.write_at(0, &data).unwrap();
+            node
+        })
+    }
+
+    #[test_case]
+    fn pages_mapped_on_demand() {
+        let node = counting_file("/tmp/test-mmap-demand", 2 * PAGE_SIZE as usize + 10);
+        let mapping = map(node, PAGE_SIZE, 2 * PAGE_SIZE, MapFlags::READ).unwrap();
+        assert!(mapping.start().as_u64() >= MMAP_START);
+        assert!(memory::translate(mapping.start()).is_none());
+
+        let bytes = mapping.as_slice();
+        assert_eq!(bytes[0], (PAGE_SIZE % 256) as u8);
+        assert!(memory::translate(mapping.start()).is_some());
+        assert!(memory::translate(mapping.start() + PAGE_SIZE).is_none());
+        // past the end of the file
+        assert_eq!(bytes[9], 9 + (PAGE_SIZE % 256) as u8);
+        assert_eq!(
+            bytes[10 + PAGE_SIZE as usize..],
+            [0; PAGE_SIZE as usize - 10][..]
+        );
+
+        let start = mapping.start();
+        drop(mapping);
+        assert!(memory::translate(start).is_none());
+    }
+
+    #[test_case]
+    fn shared_pages_written_back() {
+        let node = counting_file("/tmp/test-mmap-shared", 100);
+        let mut writer = map(node.clone(), 0, 100, MapFlags::SHARED_WRITE).unwrap();
+        let reader = map(node.clone(), 0, 100, MapFlags::READ).unwrap();
+        let mut private = map(node, 0, 100, MapFlags::PRIVATE_WRITE).unwrap();
+
+        writer.as_mut_slice()[..5].copy_from_slice(b"hello");
+        // the page is in the page cache of the file
+        assert_eq!(&reader.as_slice()[..5], b"hello");
+        assert_eq!(&private.as_slice()[..5], b"hello");
+        private.as_mut_slice()[..3].copy_from_slice(b"bye");
+        assert_eq!(&reader.as_slice()[..5], b"hello");
+
+        let path = "/tmp/test-mmap-shared";
+        assert_eq!(fs::read(path).unwrap()[..5], [0, 1, 2, 3, 4]);
+        writer.sync().unwrap();
+        assert_eq!(&fs::read(path).unwrap()[..5], b"hello");
+        drop(private);
+        writer.as_mut_slice()[99] = b'!';
+        drop(writer);
+        // the file isn't extended past its end
+        let data = fs::read(path).unwrap();
+        assert_eq!((data.len(), data[99]), (100, b'!'));
+        assert_eq!(&reader.as_slice()[..5], b"hello");
+    }
+
+    #[test_case]
+    fn invalid_mappings_refused() {
+        let motd = fs::lookup(&format!("{}/etc/motd", initrd::MOUNT_POINT)).unwrap();
+        assert_eq!(
+            map(motd.clone(), 1, 10, MapFlags::READ).err(),
+            Some(FsError::InvalidArgument)
+        );
+        assert_eq!(
+            map(motd.clone(), 0, 0, MapFlags::READ).err(),
+            Some(FsError::InvalidArgument)
+        );
+        let dev = fs::lookup("/dev/zero").unwrap();
+        assert_eq!(
+            map(dev, 0, 10, MapFlags::READ).err(),
+            Some(FsError::NotMappable)
+        );
+        let mapping = map(motd, 0, 10, MapFlags::READ).unwrap();
+        assert_eq!(&mapping.as_slice()[..7], b"Welcome");
+
+        let mut table = FdTable::new();
+        let fd = table
+            .open(
+                &format!("{}/etc/motd", initrd::MOUNT_POINT),
+                OpenFlags::READ,
+            )
+            .unwrap();
+        assert_eq!(
+            table.map(fd, 0, 10, MapFlags::SHARED_WRITE).err(),
+            Some(FsError::PermissionDenied)
+        );
+        assert!(table.map(fd, 0, 10, MapFlags::PRIVATE_WRITE).is_ok());
+    }
+}
@@ -0,0 +1,215 @@
+//! Pipes: a buffer of [PIPE_SIZE] bytes written at one end and read at the other, in order.
+//!
+//! [pipe] returns the two ends as nodes, opened by [FdTable::pipe](super::fd::FdTable::pipe). A
+//! read of an empty pipe fails with [FsError::WouldBlock], and so does a write to a full one, until
+//! the other end makes room: the future of [Node::ready] waits for it. Once the writing end is
+//! dropped, with the last descriptor referring to it, the reads past the buffered bytes return 0,
+//! the end of the file. Once the reading end is dropped, the writes fail with
+//! [FsError::BrokenPipe].
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::task::{Poll, Waker};
+
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+use super::{FsError, Metadata, Node, NodeKind, Ready};
+
+/// The number of bytes a pipe buffers.
+pub const PIPE_SIZE: usize = 4096;
+
+struct Pipe {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+    /// Woken when the pipe has bytes to read or no writer anymore.
+    readers_waiting: Vec<Waker>,
+    /// Woken when the pipe has room or no reader anymore.
+    writers_waiting: Vec<Waker>,
+}
+
+fn wake_all(wakers: &mut Vec<Waker>) {
+    for waker in wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+/// The reading end of a pipe.
+struct Reader(Arc<Mutex<Pipe>>);
+/// The writing end of a pipe.
+struct Writer(Arc<Mutex<Pipe>>);
+
+/// A new pipe, returns its reading and its writing end.
+pub fn pipe() -> (Arc<dyn Node>, Arc<dyn Node>) {
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buffer: VecDeque::with_capacity(PIPE_SIZE),
+        reader_open: true,
+        writer_open: true,
+        readers_waiting: Vec::new(),
+        writers_waiting: Vec::new(),
+    }));
+    (Arc::new(Reader(pipe.clone())), Arc::new(Writer(pipe)))
+}
+
+fn metadata() -> Metadata {
+    Metadata {
+        kind: NodeKind::CharDevice,
+        size: 0,
+    }
+}
+
+impl Node for Reader {
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut pipe = self.0.lock();
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if pipe.buffer.is_empty() {
+            return if pipe.writer_open {
+                Err(FsError::WouldBlock)
+            } else {
+                Ok(0)
+            };
+        }
+        let len = buf.len().min(pipe.buffer.len());
+        for (byte, read) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+            *byte = read;
+        }
+        wake_all(&mut pipe.writers_waiting);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn ready(&self, write: bool) -> Option<Ready> {
+        if write {
+            return None;
+        }
+        let pipe = self.0.clone();
+        Some(Box::pin(poll_fn(move |cx| {
+            let mut pipe = pipe.lock();
+            if !pipe.buffer.is_empty() || !pipe.writer_open {
+                return Poll::Ready(());
+            }
+            pipe.readers_waiting.push(cx.waker().clone());
+            Poll::Pending
+        })))
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut pipe = self.0.lock();
+        pipe.reader_open = false;
+        wake_all(&mut pipe.writers_waiting);
+    }
+}
+
+impl Node for Writer {
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut pipe = self.0.lock();
+        if !pipe.reader_open {
+            return Err(FsError::BrokenPipe);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(PIPE_SIZE - pipe.buffer.len());
+        if len == 0 {
+            return Err(FsError::WouldBlock);
+        }
+        pipe.buffer.extend(&buf[..len]);
+        wake_all(&mut pipe.readers_waiting);
+        Ok(len)
+    }
+
+    fn ready(&self, write: bool) -> Option<Ready> {
+        if !write {
+            return None;
+        }
+        let pipe = self.0.clone();
+        Some(Box::pin(poll_fn(move |cx| {
+            let mut pipe = pipe.lock();
+            if pipe.buffer.len() < PIPE_SIZE || !pipe.reader_open {
+                return Poll::Ready(());
+            }
+            pipe.writers_waiting.push(cx.waker().clone());
+            Poll::Pending
+        })))
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let mut pipe = self.0.lock();
+        pipe.writer_open = false;
+        wake_all(&mut pipe.readers_waiting);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{future::Future, task::Context};
+    use futures_util::task::noop_waker_ref;
+
+    fn poll(ready: &mut Ready) -> Poll<()> {
+        ready
+            .as_mut()
+            .poll(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test_case]
+    fn bytes_passed_in_order() {
+        let (reader, writer) = pipe();
+        let mut buf = [0; 8];
+        assert_eq!(reader.read_at(0, &mut buf), Err(FsError::WouldBlock));
+        let mut readable = reader.ready(false).unwrap();
+        assert_eq!(poll(&mut readable), Poll::Pending);
+        assert!(reader.ready(true).is_none());
+
+        assert_eq!(writer.write_at(0, b"hello"), Ok(5));
+        assert_eq!(writer.write_at(0, b" pipe"), Ok(5));
+        assert_eq!(poll(&mut readable), Poll::Ready(()));
+        assert_eq!(reader.read_at(0, &mut buf), Ok(8));
+        assert_eq!(&buf, b"hello pi");
+        assert_eq!(reader.read_at(0, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"pe");
+
+        // the end of the file once the writer is gone
+        drop(readable);
+        drop(writer);
+        assert_eq!(reader.read_at(0, &mut buf), Ok(0));
+    }
+
+    #[test_case]
+    fn writes_wait_for_room() {
+        let (reader, writer) = pipe();
+        let data = [7; PIPE_SIZE + 1];
+        assert_eq!(writer.write_at(0, &data), Ok(PIPE_SIZE));
+        assert_eq!(writer.write_at(0, &data), Err(FsError::WouldBlock));
+        let mut writable = writer.ready(true).unwrap();
+        assert_eq!(poll(&mut writable), Poll::Pending);
+
+        assert_eq!(reader.read_at(0, &mut [0; 16]), Ok(16));
+        assert_eq!(poll(&mut writable), Poll::Ready(()));
+        assert_eq!(writer.write_at(0, &data), Ok(16));
+
+        drop(reader);
+        assert_eq!(writer.write_at(0, &data), Err(FsError::BrokenPipe));
+    }
+}
@@ -0,0 +1,191 @@
+//! A writable filesystem in memory, mounted at [MOUNT_POINT] for scratch files. Its content is
+//! lost on reboot and kept on the kernel heap, files are limited to [MAX_FILE_SIZE] bytes.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::convert::TryFrom;
+
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FsError, Metadata, Node, NodeKind};
+use crate::warn;
+
+/// Where the scratch filesystem is mounted.
+pub const MOUNT_POINT: &str = "/tmp";
+/// The largest file, the heap is small.
+pub const MAX_FILE_SIZE: u64 = 64 * 1024;
+
+/// A filesystem in memory, empty when created.
+pub struct RamFs {
+    root: Arc<Directory>,
+}
+
+impl RamFs {
+    /// An empty filesystem.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        RamFs {
+            root: Arc::new(Directory::default()),
+        }
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        self.root.clone()
+    }
+}
+
+/// Mount an empty filesystem at [MOUNT_POINT].
+pub fn init() {
+    if let Err(err) = super::mount(MOUNT_POINT, Arc::new(RamFs::new())) {
+        warn!("ramfs not mounted at {}: {}", MOUNT_POINT, err);
+    }
+}
+
+#[derive(Default)]
+struct Directory {
+    entries: Mutex<BTreeMap<String, Arc<dyn Node>>>,
+}
+
+impl Node for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        self.entries
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .entries
+            .lock()
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.metadata().kind,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Node>, FsError> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        let node: Arc<dyn Node> = match kind {
+            NodeKind::File => Arc::new(File::default()),
+            NodeKind::Directory => Arc::new(Directory::default()),
+            NodeKind::CharDevice => return Err(FsError::PermissionDenied),
+        };
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+}
+
+#[derive(Default)]
+struct File {
+    data: Mutex<Vec<u8>>,
+}
+
+/// `len` as a size of a file, if not over [MAX_FILE_SIZE].
+fn checked_size(len: u64) -> Result<usize, FsError> {
+    match usize::try_from(len) {
+        Ok(len) if len as u64 <= MAX_FILE_SIZE => Ok(len),
+        _ => Err(FsError::FileTooLarge),
+    }
+}
+
+impl Node for File {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::File,
+            size: self.data.lock().len() as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data.lock();
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let end = checked_size(offset.saturating_add(buf.len() as u64))?;
+        let start = end - buf.len();
+        let mut data = self.data.lock();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn set_len(&self, len: u64) -> Result<(), FsError> {
+        let len = checked_size(len)?;
+        self.data.lock().resize(len, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fs, testing};
+
+    #[test_case]
+    fn files_created_and_written() {
+        // the files stay in the filesystem after the test
+        let file = testing::keep_allocations(|| {
+            fs::create("/tmp/test-ramfs", NodeKind::Directory).unwrap();
+            fs::create("/tmp/test-ramfs/a", NodeKind::File).unwrap()
+        });
+        assert_eq!(
+            fs::create("/tmp/test-ramfs/a", NodeKind::File).err(),
+            Some(FsError::AlreadyExists)
+        );
+
+        assert_eq!(
+            testing::keep_allocations(|| file.write_at(4, b"data")),
+            Ok(4)
+        );
+        assert_eq!(fs::read("/tmp/test-ramfs/a").unwrap(), b"\0\0\0\0data");
+        file.set_len(2).unwrap();
+        assert_eq!(fs::read("/tmp/test-ramfs/a").unwrap(), b"\0\0");
+        assert_eq!(
+            file.write_at(MAX_FILE_SIZE, b"x"),
+            Err(FsError::FileTooLarge)
+        );
+
+        let entries = fs::read_dir("/tmp/test-ramfs").unwrap();
+        assert_eq!(
+            entries,
+            [DirEntry {
+                name: String::from("a"),
+                kind: NodeKind::File,
+            }]
+        );
+        assert_eq!(
+            fs::create("/tmp/test-ramfs/a/b", NodeKind::File).err(),
+            Some(FsError::NotADirectory)
+        );
+    }
+}
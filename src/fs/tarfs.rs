@@ -0,0 +1,592 @@
+//! A read-only filesystem over an archive in memory, in the ustar format of `tar` or in the "new"
+//! ASCII format of `cpio`, told apart by their magic.
+//!
+//! The archive is parsed once into a tree of directories, the files point into the archive, which
+//! is never copied. Directories missing from the archive are created for the files in them, the
+//! entries other than files and directories, e.g. symbolic links, are skipped.
+//!
+//! An archive may also be read from a block device, e.g. a disk image made by `tar` attached to
+//! QEMU, see [TarFs::from_device]. The archive is then kept on the heap.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{convert::TryFrom, ops::Range, str};
+
+use super::{DirEntry, FileSystem, FsError, Metadata, Node, NodeKind};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+
+/// The size of the headers and the unit of padding of ustar archives.
+const BLOCK_SIZE: usize = 512;
+/// The magic of ustar archives at [USTAR_MAGIC_OFFSET] of a header, followed by a NUL or a space.
+const USTAR_MAGIC: &[u8] = b"ustar";
+const USTAR_MAGIC_OFFSET: usize = 257;
+
+/// The magic of the "new" ASCII format of cpio, the first field of each header.
+const CPIO_MAGIC: &[u8] = b"070701";
+/// The size of a cpio header: the magic and 13 fields of 8 hexadecimal digits.
+const CPIO_HEADER_SIZE: usize = 110;
+/// The name of the entry ending a cpio archive.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+/// The file type bits of the mode of a cpio entry.
+const CPIO_TYPE_MASK: u32 = 0o170_000;
+const CPIO_TYPE_FILE: u32 = 0o100_000;
+const CPIO_TYPE_DIRECTORY: u32 = 0o040_000;
+
+/// The archive ends before its last entry or its end marker.
+const TRUNCATED: FsError = FsError::Corrupted("archive truncated");
+/// The largest archive read from a block device, it's kept on the heap.
+pub const MAX_DEVICE_ARCHIVE: usize = 512 * 1024;
+/// The size of the reads of an archive from a block device.
+const DEVICE_CHUNK: usize = 32 * 1024;
+
+/// The bytes of an archive, embedded into the kernel or on the heap.
+type Archive = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// The formats of archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// POSIX tar.
+    Ustar,
+    /// The "new" ASCII format of cpio, e.g. initramfs images of Linux.
+    Cpio,
+}
+
+/// The format of `archive`, `None` if it's in neither.
+pub fn detect(archive: &[u8]) -> Option<Format> {
+    if archive.starts_with(CPIO_MAGIC) {
+        Some(Format::Cpio)
+    } else if archive.get(USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + USTAR_MAGIC.len())
+        == Some(USTAR_MAGIC)
+    {
+        Some(Format::Ustar)
+    } else {
+        None
+    }
+}
+
+/// An archive mounted as a filesystem.
+pub struct TarFs {
+    root: Arc<Directory>,
+    file_count: usize,
+}
+
+impl TarFs {
+    /// Parse `archive`, in either format.
+    pub fn new(archive: &'static [u8]) -> Result<Self, FsError> {
+        Self::parse(Arc::new(archive))
+    }
+
+    /// Parse `archive` read at run time, in either format.
+    pub fn from_vec(archive: Vec<u8>) -> Result<Self, FsError> {
+        Self::parse(Arc::new(archive))
+    }
+
+    /// Read the archive at the start of `device`, in either format, of at most
+    /// [MAX_DEVICE_ARCHIVE] bytes.
+    pub async fn from_device(device: &dyn BlockDevice) -> Result<Self, FsError> {
+        let device_size = usize::try_from(device.sector_count())
+            .unwrap_or(usize::MAX)
+            .saturating_mul(SECTOR_SIZE);
+        let mut archive = Vec::new();
+        // the archive is read until it parses in full
+        loop {
+            let len = (archive.len() + DEVICE_CHUNK)
+                .min(device_size)
+                .min(MAX_DEVICE_ARCHIVE);
+            if len == archive.len() {
+                return Err(TRUNCATED);
+            }
+            let lba = (archive.len() / SECTOR_SIZE) as u64;
+            let start = archive.len();
+            archive.resize(len, 0);
+            device
+                .read(lba, &mut archive[start..])
+                .await
+                .map_err(FsError::Device)?;
+            match parse(&archive) {
+                Err(TRUNCATED) => {}
+                Err(err) => return Err(err),
+                Ok(_) => return Self::from_vec(archive),
+            }
+        }
+    }
+
+    fn parse(archive: Archive) -> Result<Self, FsError> {
+        let tree = parse((*archive).as_ref())?;
+        Ok(TarFs {
+            file_count: tree.file_count(),
+            root: Arc::new(tree.into_directory(&archive)),
+        })
+    }
+
+    /// The number of files in the archive.
+    pub fn file_count(&self) -> usize {
+        self.file_count
+    }
+}
+
+impl FileSystem for TarFs {
+    fn name(&self) -> &'static str {
+        "tarfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        self.root.clone()
+    }
+}
+
+/// A directory being built from the entries of an archive.
+#[derive(Default)]
+struct Tree {
+    entries: BTreeMap<String, TreeEntry>,
+}
+
+enum TreeEntry {
+    /// A file with its data in the archive.
+    File(Range<usize>),
+    Directory(Tree),
+}
+
+impl Tree {
+    /// The directory at `path` relative to the root of the archive, created along with its
+    /// parents if missing.
+    fn directory(&mut self, path: &str) -> Result<&mut Tree, FsError> {
+        let mut directory = self;
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            if component == ".." {
+                return Err(FsError::Corrupted("path escaping the archive"));
+            }
+            let entry = directory
+                .entries
+                .entry(String::from(component))
+                .or_insert_with(|| TreeEntry::Directory(Tree::default()));
+            directory = match entry {
+                TreeEntry::Directory(tree) => tree,
+                TreeEntry::File(_) => return Err(FsError::Corrupted("file used as a directory")),
+            };
+        }
+        Ok(directory)
+    }
+
+    /// Add the file at `path` with `data`, a later entry of the same path replaces the earlier.
+    fn add_file(&mut self, path: &str, data: Range<usize>) -> Result<(), FsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rfind('/') {
+            Some(slash) => (&path[..slash], &path[slash + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FsError::Corrupted("invalid file name"));
+        }
+        let parent = self.directory(parent)?;
+        if let Some(TreeEntry::Directory(_)) = parent.entries.get(name) {
+            return Err(FsError::Corrupted("directory used as a file"));
+        }
+        parent
+            .entries
+            .insert(String::from(name), TreeEntry::File(data));
+        Ok(())
+    }
+
+    fn file_count(&self) -> usize {
+        self.entries
+            .values()
+            .map(|entry| match entry {
+                TreeEntry::File(_) => 1,
+                TreeEntry::Directory(tree) => tree.file_count(),
+            })
+            .sum()
+    }
+
+    fn into_directory(self, archive: &Archive) -> Directory {
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|(name, entry)| {
+                let node: Arc<dyn Node> = match entry {
+                    TreeEntry::File(data) => Arc::new(File {
+                        archive: archive.clone(),
+                        data,
+                    }),
+                    TreeEntry::Directory(tree) => Arc::new(tree.into_directory(archive)),
+                };
+                (name, node)
+            })
+            .collect();
+        Directory { entries }
+    }
+}
+
+struct Directory {
+    entries: BTreeMap<String, Arc<dyn Node>>,
+}
+
+impl Node for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        self.entries.get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.metadata().kind,
+            })
+            .collect())
+    }
+}
+
+struct File {
+    archive: Archive,
+    /// The range of the data of the file in the archive.
+    data: Range<usize>,
+}
+
+impl Node for File {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::File,
+            size: self.data.len() as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = &(*self.archive).as_ref()[self.data.clone()];
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+}
+
+/// The string in the NUL padded field `field`.
+fn field_str(field: &[u8]) -> Result<&str, FsError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| FsError::Corrupted("name not in UTF-8"))
+}
+
+/// The number in the field `field` in base `radix`, padded with NULs or spaces.
+fn field_number(field: &[u8], radix: u32) -> Result<u64, FsError> {
+    let digits = field_str(field)
+        .map_err(|_| FsError::Corrupted("invalid number"))?
+        .trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, radix).map_err(|_| FsError::Corrupted("invalid number"))
+}
+
+/// The range of the `len` bytes of `archive` from `offset` on.
+fn bytes(archive: &[u8], offset: usize, len: u64) -> Result<Range<usize>, FsError> {
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len))
+        .filter(|&end| end <= archive.len())
+        .map(|end| offset..end)
+        .ok_or(TRUNCATED)
+}
+
+/// Parse `archive`, in either format, into a tree of the ranges of the files.
+fn parse(archive: &[u8]) -> Result<Tree, FsError> {
+    let mut tree = Tree::default();
+    match detect(archive) {
+        Some(Format::Ustar) => parse_ustar(archive, &mut tree)?,
+        Some(Format::Cpio) => parse_cpio(archive, &mut tree)?,
+        None => return Err(FsError::Corrupted("unknown archive format")),
+    }
+    Ok(tree)
+}
+
+fn parse_ustar(archive: &[u8], tree: &mut Tree) -> Result<(), FsError> {
+    const NAME: (usize, usize) = (0, 100);
+    const SIZE: (usize, usize) = (124, 12);
+    const CHECKSUM: (usize, usize) = (148, 8);
+    const TYPE: usize = 156;
+    const PREFIX: (usize, usize) = (345, 155);
+    fn field(header: &[u8], (offset, len): (usize, usize)) -> &[u8] {
+        &header[offset..offset + len]
+    }
+
+    let mut offset = 0;
+    // the archive ends with two zeroed blocks, a single one is enough
+    loop {
+        let header = &archive[bytes(archive, offset, BLOCK_SIZE as u64)?];
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        if header[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + USTAR_MAGIC.len()] != *USTAR_MAGIC {
+            return Err(FsError::Corrupted("header without the ustar magic"));
+        }
+        // the sum of the bytes of the header, those of the checksum counted as spaces
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (CHECKSUM.0..CHECKSUM.0 + CHECKSUM.1).contains(&i) {
+                    u64::from(b' ')
+                } else {
+                    u64::from(b)
+                }
+            })
+            .sum();
+        if field_number(field(header, CHECKSUM), 8)? != sum {
+            return Err(FsError::Corrupted("header checksum mismatch"));
+        }
+
+        let prefix = field_str(field(header, PREFIX))?;
+        let name = field_str(field(header, NAME))?;
+        let mut path = String::from(prefix);
+        if !prefix.is_empty() {
+            path.push('/');
+        }
+        path.push_str(name);
+        let size = field_number(field(header, SIZE), 8)?;
+        let data = bytes(archive, offset + BLOCK_SIZE, size)?;
+
+        match header[TYPE] {
+            b'0' | 0 => tree.add_file(&path, data.clone())?,
+            b'5' => {
+                tree.directory(&path)?;
+            }
+            // links, devices, extended headers, ...
+            _ => {}
+        }
+        offset = (data.end + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+}
+
+fn parse_cpio(archive: &[u8], tree: &mut Tree) -> Result<(), FsError> {
+    const MODE: usize = 1;
+    const FILE_SIZE: usize = 6;
+    const NAME_SIZE: usize = 11;
+    let align = |offset: usize| (offset + 3) & !3;
+
+    let mut offset = 0;
+    loop {
+        let header = &archive[bytes(archive, offset, CPIO_HEADER_SIZE as u64)?];
+        if !header.starts_with(CPIO_MAGIC) {
+            return Err(FsError::Corrupted("header without the cpio magic"));
+        }
+        let field = |index: usize| {
+            let start = CPIO_MAGIC.len() + index * 8;
+            field_number(&header[start..start + 8], 16)
+        };
+        let mode = field(MODE)? as u32;
+        let size = field(FILE_SIZE)?;
+        let name_size = field(NAME_SIZE)?;
+
+        // the name is NUL terminated and the data aligned on 4 bytes from the start of the header
+        let name = field_str(&archive[bytes(archive, offset + CPIO_HEADER_SIZE, name_size)?])?;
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let data_offset = align(offset + CPIO_HEADER_SIZE + name_size as usize);
+        let data = bytes(archive, data_offset, size)?;
+
+        match mode & CPIO_TYPE_MASK {
+            CPIO_TYPE_FILE => tree.add_file(name, data.clone())?,
+            CPIO_TYPE_DIRECTORY => {
+                tree.directory(name)?;
+            }
+            _ => {}
+        }
+        offset = align(data.end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{BlockError, BlockFuture},
+        task::block_on,
+    };
+    use alloc::{boxed::Box, format, vec};
+
+    /// A ustar header of an entry of `kind` at `name` with `size` bytes of data.
+    fn ustar_header(name: &str, kind: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    fn ustar(entries: &[(&str, Option<&[u8]>)]) -> &'static [u8] {
+        let mut archive = Vec::new();
+        for (name, data) in entries {
+            match data {
+                Some(data) => {
+                    archive.extend(ustar_header(name, b'0', data.len()));
+                    archive.extend_from_slice(data);
+                    archive.resize(
+                        (archive.len() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE,
+                        0,
+                    );
+                }
+                None => archive.extend(ustar_header(name, b'5', 0)),
+            }
+        }
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+        Vec::leak(archive)
+    }
+
+    fn cpio(entries: &[(&str, u32, &[u8])]) -> &'static [u8] {
+        let mut archive = Vec::new();
+        let trailer = (CPIO_TRAILER, 0, &[][..]);
+        for (name, mode, data) in entries.iter().chain(core::iter::once(&trailer)) {
+            archive.extend_from_slice(CPIO_MAGIC);
+            let fields = [0, *mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+            for field in fields.iter() {
+                archive.extend(format!("{:08x}", field).bytes());
+            }
+            archive.extend(format!("{:08x}{:08x}", name.len() + 1, 0).bytes());
+            archive.extend(name.bytes());
+            archive.push(0);
+            archive.resize((archive.len() + 3) & !3, 0);
+            archive.extend_from_slice(data);
+            archive.resize((archive.len() + 3) & !3, 0);
+        }
+        Vec::leak(archive)
+    }
+
+    fn read(fs: &TarFs, path: &[&str]) -> Result<Vec<u8>, FsError> {
+        let mut node = fs.root();
+        for name in path {
+            node = node.lookup(name)?;
+        }
+        let mut data = vec![0; node.metadata().size as usize + 1];
+        let len = node.read_at(0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    #[test_case]
+    fn ustar_parsed() {
+        let archive = ustar(&[
+            ("./etc/", None),
+            ("./etc/motd", Some(&b"hello"[..])),
+            ("bin/big", Some(&[7; 700][..])),
+            ("empty/", None),
+        ]);
+        assert_eq!(detect(archive), Some(Format::Ustar));
+        let fs = TarFs::new(archive).unwrap();
+        assert_eq!(fs.file_count(), 2);
+
+        let names: Vec<String> = fs
+            .root()
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["bin", "empty", "etc"]);
+        assert_eq!(read(&fs, &["etc", "motd"]).unwrap(), b"hello");
+        assert_eq!(read(&fs, &["bin", "big"]).unwrap(), [7; 700]);
+        assert_eq!(read(&fs, &["etc", "nope"]), Err(FsError::NotFound));
+        assert_eq!(read(&fs, &["etc"]), Err(FsError::IsADirectory));
+
+        let motd = fs.root().lookup("etc").unwrap().lookup("motd").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(motd.read_at(3, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(motd.read_at(10, &mut buf), Ok(0));
+        assert_eq!(motd.write_at(0, b"x"), Err(FsError::ReadOnly));
+    }
+
+    #[test_case]
+    fn cpio_parsed() {
+        let archive = cpio(&[
+            (".", CPIO_TYPE_DIRECTORY | 0o755, &b""[..]),
+            ("init", CPIO_TYPE_FILE | 0o755, &b"#!/bin/sh\n"[..]),
+            ("lib/modules/a", CPIO_TYPE_FILE | 0o644, &b"abc"[..]),
+            ("link", 0o120_000 | 0o777, &b"init"[..]),
+        ]);
+        assert_eq!(detect(archive), Some(Format::Cpio));
+        let fs = TarFs::new(archive).unwrap();
+        assert_eq!(fs.file_count(), 2);
+        assert_eq!(read(&fs, &["init"]).unwrap(), b"#!/bin/sh\n");
+        assert_eq!(read(&fs, &["lib", "modules", "a"]).unwrap(), b"abc");
+        assert_eq!(read(&fs, &["link"]), Err(FsError::NotFound));
+    }
+
+    #[test_case]
+    fn corrupted_archives_refused() {
+        assert!(matches!(TarFs::new(&[0; 1024]), Err(FsError::Corrupted(_))));
+
+        let mut archive = ustar(&[("a", Some(&b"data"[..]))]).to_vec();
+        archive[0] = b'b';
+        assert!(matches!(
+            TarFs::from_vec(archive),
+            Err(FsError::Corrupted("header checksum mismatch"))
+        ));
+
+        let archive = ustar(&[("../a", Some(&b"data"[..]))]);
+        assert!(matches!(
+            TarFs::new(archive),
+            Err(FsError::Corrupted("path escaping the archive"))
+        ));
+
+        // the end markers are cut off
+        let archive = cpio(&[("a", CPIO_TYPE_FILE, &b"data"[..])]);
+        assert!(matches!(
+            TarFs::new(&archive[..archive.len() - 4]),
+            Err(TRUNCATED)
+        ));
+        let archive = ustar(&[("a", Some(&b"data"[..]))]);
+        assert!(matches!(
+            TarFs::new(&archive[..archive.len() - 2 * BLOCK_SIZE]),
+            Err(TRUNCATED)
+        ));
+    }
+
+    /// A disk in memory.
+    struct RamDisk(Vec<u8>);
+
+    impl BlockDevice for RamDisk {
+        fn sector_count(&self) -> u64 {
+            (self.0.len() / SECTOR_SIZE) as u64
+        }
+
+        fn read<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+            Box::pin(async move {
+                let start = lba as usize * SECTOR_SIZE;
+                buf.copy_from_slice(&self.0[start..start + buf.len()]);
+                Ok(())
+            })
+        }
+
+        fn write<'a>(&'a self, _lba: u64, _buf: &'a [u8]) -> BlockFuture<'a> {
+            Box::pin(async { Err(BlockError::OutOfRange) })
+        }
+    }
+
+    #[test_case]
+    fn read_from_device() {
+        // the archive spans more than one read
+        let big = vec![0x5a; DEVICE_CHUNK + 100];
+        let mut disk = ustar(&[("big", Some(&big[..])), ("small", Some(&b"abc"[..]))]).to_vec();
+        disk.resize(4 * DEVICE_CHUNK, 0);
+        let fs = block_on(TarFs::from_device(&RamDisk(disk))).unwrap();
+        assert_eq!(read(&fs, &["big"]).unwrap(), big);
+        assert_eq!(read(&fs, &["small"]).unwrap(), b"abc");
+
+        let blank = RamDisk(vec![0; 2 * DEVICE_CHUNK]);
+        assert!(matches!(
+            block_on(TarFs::from_device(&blank)),
+            Err(FsError::Corrupted("unknown archive format"))
+        ));
+    }
+}
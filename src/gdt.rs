@@ -1,5 +1,9 @@
+use alloc::{boxed::Box, vec};
+use core::ptr;
+
 use lazy_static::lazy_static;
 use x86_64::{
+    instructions::interrupts,
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
@@ -10,63 +14,158 @@ use x86_64::{
 /// The index of the double fault stack space in the Intrrupt Stack Table.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// The TSS of the bootstrap processor. The CPU reads the stack of the double fault from it, and the
+/// stack of the interrupts raised in ring 3, see [set_kernel_stack].
+///
+/// # Safety
+/// Only written by [init] before the TSS is loaded and by [set_kernel_stack] with interrupts
+/// disabled.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The size of the double fault stacks, heavy use of the stack on double fault handling results in
+/// triple fault, or worse, slient corruption of whatever memory below the stack space.
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // size of the double fault, heavy use of the stack on double fault handling results in
-            // triple fault, or worse, slient corruption of whatever memory below the stack space.
-            const STACK_SIZE: usize = 4096 * 5;
-            // the stack space is placed in DATA section, if not declared as mut the stack space may
-            // be placed in RODATA hence modification would cause segmentation fault
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // # Safety
-            // reference to STACK is unique and valid as ensured by lazy_static and the fact that
-            // STACK is not visible from outside of this scope
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            // stack grows negatively, the virtual address that should be placed in TSS is one byte
-            // beyond the end of the stack space
-            stack_start + STACK_SIZE
+    static ref GDT: Tables = {
+        // # Safety
+        // lazy_static runs this once, before the TSS is loaded by [init], nothing else refers to
+        // the TSS yet.
+        let tss = unsafe {
+            TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+                // the stack space is placed in DATA section, if not declared as mut the stack space
+                // may be placed in RODATA hence modification would cause segmentation fault
+                static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+                // reference to STACK is unique and valid as ensured by lazy_static and the fact
+                // that STACK is not visible from outside of this scope
+                let stack_start = VirtAddr::from_ptr(&STACK);
+                // stack grows negatively, the virtual address that should be placed in TSS is one
+                // byte beyond the end of the stack space
+                stack_start + DOUBLE_FAULT_STACK_SIZE
+            };
+            &TSS
         };
-        tss
+        Tables::new(tss)
     };
+}
+
+/// A GDT with the TSS of a processor.
+pub struct Tables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+}
 
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+impl Tables {
+    fn new(tss: &'static TaskStateSegment) -> Self {
+        // `syscall` and `sysret` expect the data segments right after the code segment of the
+        // kernel and right before the code segment of ring 3
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+        Tables {
+            gdt,
+            selectors: Selectors {
+                kernel_code,
+                kernel_data,
+                user_data,
+                user_code,
+                tss,
+            },
+        }
+    }
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// The segment selectors of the GDT, the selectors of ring 3 have a requested privilege level of
+/// 3.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    /// The code segment of the kernel.
+    pub kernel_code: SegmentSelector,
+    /// The data segment of the kernel, the stack segment.
+    pub kernel_data: SegmentSelector,
+    /// The data segment of ring 3, the stack segment.
+    pub user_data: SegmentSelector,
+    /// The code segment of ring 3.
+    pub user_code: SegmentSelector,
+    /// The TSS.
+    pub tss: SegmentSelector,
 }
 
 /// Initialize the GDT (Global Descriptor Table). Use a custom GDT as mitigation of:
 /// - kernel stack overflow, by switching to a separate, sufficiently large stack on double fault
 ///   interrupts, kernel stack overflow no longer causes bookkeeping on an already overflowed stack
 ///   and a fatal triple fault
+///
+/// The GDT also holds the segments of ring 3, see [crate::user].
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    load(&GDT);
+}
+
+/// Allocate the GDT and the TSS of an application processor, with its own double fault stack. The
+/// selectors are those of [selectors], the interrupts raised in ring 3 aren't supported. The
+/// tables are never freed.
+pub fn new_tables() -> &'static Tables {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    Box::leak(Box::new(Tables::new(Box::leak(Box::new(tss)))))
+}
+
+/// Load `tables` on the running processor, as [init] does with the GDT of the bootstrap processor.
+/// Each TSS may only be loaded on one processor.
+pub fn load(tables: &'static Tables) {
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    let (gdt, selectors) = &*GDT;
+    let Tables { gdt, selectors } = tables;
     gdt.load();
 
     // [GlobalDescriptorTable::load](86_64::structures::GlobalDescriptorTable::load) does not alter
-    // any of the segment registers, both the kernel code segment and the TSS must be reloaded
-    // manually.
+    // any of the segment registers, the kernel segments and the TSS must be reloaded manually.
     //
     // # Safety
-    // `selectors.code_selector` and `selectors::tss_selector` are valid segment descriptor returned
-    // by
+    // The selectors are valid segment descriptor returned by
     // [GlobalDescriptorTable::add_entry](x86_64::structures::gdt::GlobalDescriptorTable::add_entry),
-    // `selectors::tss_selector` points to a valid TSS entry in the GDT defined in lazy_static.
+    // `selectors::tss` points to a valid TSS entry in the GDT.
     unsafe {
-        set_cs(selectors.code_selector);
-        load_tss(selectors.tss_selector);
+        set_cs(selectors.kernel_code);
+        load_ss(selectors.kernel_data);
+        load_ds(selectors.kernel_data);
+        load_es(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
 }
+
+/// The segment selectors of the GDT.
+pub fn selectors() -> &'static Selectors {
+    &GDT.selectors
+}
+
+/// Set the stack the CPU switches to on the interrupts and exceptions raised in ring 3, `top` is
+/// one byte beyond its end.
+///
+/// # Safety
+/// Nothing may use the stack below `top` while code runs in ring 3.
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    interrupts::without_interrupts(|| TSS.privilege_stack_table[0] = top);
+}
+
+/// The stack of the interrupts raised in ring 3, set by [set_kernel_stack].
+pub fn kernel_stack() -> VirtAddr {
+    // # Safety
+    // The TSS is only written with interrupts disabled, a read of an aligned u64 isn't torn.
+    unsafe { TSS.privilege_stack_table[0] }
+}
+
+/// Where the TSS holds the stack set by [set_kernel_stack], for the entry of `syscall` which
+/// doesn't switch stacks by itself.
+pub(crate) fn kernel_stack_slot() -> *const VirtAddr {
+    // # Safety
+    // Only the address of the TSS is taken.
+    unsafe { ptr::addr_of!(TSS.privilege_stack_table) as *const VirtAddr }
+}
@@ -0,0 +1,388 @@
+//! The i8042 PS/2 controller, shared by the keyboard on its first port and the mouse on its
+//! second port.
+//!
+//! The controller has a single data port for both devices and a status and command port. Bytes
+//! received from either device raise their interrupt (IRQ 1 or IRQ 12) and are told apart by the
+//! status register, [handle_interrupt] hands each of them to the handler of its port, see
+//! [set_handler].
+//!
+//! Commands to the controller and to the devices are serialized by a lock, callers queue behind
+//! the command in flight. A command runs with interrupts disabled and polls for its responses, the
+//! bytes of the other port received meanwhile are still handed to their handler. Every wait on the
+//! controller is bounded, a missing or stuck device fails the command with [I8042Error::Timeout]
+//! instead of hanging the kernel.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    fault::{self, Fault},
+    io::PortRange,
+    time, trace_event,
+};
+
+const DATA_PORT: u16 = 0x60;
+/// The status register on reads, the command register on writes.
+const STATUS_PORT: u16 = 0x64;
+
+/// Set in the status register when a byte is waiting in the data port.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Set in the status register while the controller hasn't consumed the last byte written.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Set in the status register along with [STATUS_OUTPUT_FULL] if the byte is from the second port.
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND: u8 = 0xa7;
+const COMMAND_ENABLE_SECOND: u8 = 0xa8;
+const COMMAND_TEST_SECOND: u8 = 0xa9;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_TEST_FIRST: u8 = 0xab;
+const COMMAND_DISABLE_FIRST: u8 = 0xad;
+const COMMAND_ENABLE_FIRST: u8 = 0xae;
+/// Send the next byte written to the data port to the device on the second port.
+const COMMAND_WRITE_SECOND: u8 = 0xd4;
+/// Pulse the reset line of the CPU.
+const COMMAND_RESET: u8 = 0xfe;
+
+/// Set in the configuration byte to enable the interrupt of the first port.
+const CONFIG_IRQ_FIRST: u8 = 1 << 0;
+/// Set in the configuration byte to enable the interrupt of the second port.
+const CONFIG_IRQ_SECOND: u8 = 1 << 1;
+/// Set in the configuration byte while the clock of the second port is disabled.
+const CONFIG_SECOND_DISABLED: u8 = 1 << 5;
+
+/// The answer of a successful controller self test.
+const SELF_TEST_PASSED: u8 = 0x55;
+/// The answer of a successful port test.
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Sent by a device once it has received a byte.
+const DEVICE_ACK: u8 = 0xfa;
+/// Sent by a device to get the last byte again.
+const DEVICE_RESEND: u8 = 0xfe;
+/// Number of times a byte is sent to a device asking for it again.
+const RESENDS: usize = 3;
+
+/// Number of polls of the status register for a byte to be consumed or acknowledged, 10
+/// microseconds apart.
+const POLLS: usize = 5_000;
+/// Number of polls for each byte answering a device command, some take long, e.g. a reset runs the
+/// self test of the device.
+const RESPONSE_POLLS: usize = 100_000;
+/// Bytes left over in the data port discarded on initialization, at most.
+const MAX_FLUSHED: usize = 16;
+
+lazy_static! {
+    /// # Safety
+    /// The ports belong to the PS/2 controller, which doesn't access memory. The commands sent
+    /// reset the CPU at most.
+    static ref PORTS: Ports = unsafe {
+        Ports {
+            data: PortRange::claim(DATA_PORT, 1, "i8042")
+                .expect("the data port of the PS/2 controller is claimed by another driver"),
+            status: PortRange::claim(STATUS_PORT, 1, "i8042")
+                .expect("the status port of the PS/2 controller is claimed by another driver"),
+        }
+    };
+}
+
+/// Held for the whole of a command.
+static COMMAND_LOCK: Mutex<()> = Mutex::new(());
+/// The handlers of the bytes received on each port.
+static HANDLERS: Mutex<[Option<Handler>; 2]> = Mutex::new([None; 2]);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+/// Called with each byte received on a port, in interrupt context.
+pub type Handler = fn(u8);
+
+struct Ports {
+    data: PortRange,
+    status: PortRange,
+}
+
+/// A port of the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    /// The first port, of the keyboard.
+    First,
+    /// The second port, of the mouse.
+    Second,
+}
+
+impl Port {
+    fn index(self) -> usize {
+        match self {
+            Port::First => 0,
+            Port::Second => 1,
+        }
+    }
+}
+
+/// Errors of the controller and of its devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I8042Error {
+    /// The controller or the device didn't answer in time.
+    Timeout,
+    /// The controller failed its self test with the answer.
+    SelfTest(u8),
+    /// The test of the port failed with the answer.
+    PortTest(Port, u8),
+    /// The controller has no such port.
+    NoSuchPort(Port),
+    /// The device didn't acknowledge a byte, with its answer.
+    NotAcknowledged(u8),
+}
+
+impl fmt::Display for I8042Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            I8042Error::Timeout => write!(f, "timed out"),
+            I8042Error::SelfTest(answer) => write!(f, "self test failed with {:#04x}", answer),
+            I8042Error::PortTest(port, answer) => {
+                write!(f, "test of the {:?} port failed with {:#04x}", port, answer)
+            }
+            I8042Error::NoSuchPort(port) => write!(f, "no {:?} port", port),
+            I8042Error::NotAcknowledged(answer) => {
+                write!(f, "device answered {:#04x} instead of an ACK", answer)
+            }
+        }
+    }
+}
+
+fn status() -> u8 {
+    PORTS.status.read(0)
+}
+
+/// Write `byte` to the data port once the controller is ready for it.
+fn write_data(byte: u8) -> Result<(), I8042Error> {
+    wait_input_empty()?;
+    PORTS.data.write(0, byte);
+    Ok(())
+}
+
+/// Send the controller command `command`.
+fn write_command(command: u8) -> Result<(), I8042Error> {
+    wait_input_empty()?;
+    PORTS.status.write(0, command);
+    Ok(())
+}
+
+fn wait_input_empty() -> Result<(), I8042Error> {
+    for _ in 0..POLLS {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        time::delay_us(10);
+    }
+    Err(I8042Error::Timeout)
+}
+
+/// Wait for a byte from `port` for at most `polls` polls, the bytes of the other port are handed
+/// to its handler.
+fn read_from(port: Port, polls: usize) -> Result<u8, I8042Error> {
+    for _ in 0..polls {
+        if let Some((from, byte)) = try_read() {
+            if from == port {
+                return Ok(byte);
+            }
+            dispatch(from, byte);
+        } else {
+            time::delay_us(10);
+        }
+    }
+    Err(I8042Error::Timeout)
+}
+
+/// Read the byte waiting in the data port and the port it's from, `None` if there is none.
+fn try_read() -> Option<(Port, u8)> {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+    let port = if status & STATUS_SECOND_PORT != 0 {
+        Port::Second
+    } else {
+        Port::First
+    };
+    Some((port, PORTS.data.read(0)))
+}
+
+/// Send the controller command `command` and read its answer, which the controller puts in the
+/// data port as if it came from the first port.
+fn query(command: u8) -> Result<u8, I8042Error> {
+    write_command(command)?;
+    read_from(Port::First, POLLS)
+}
+
+fn write_config(config: u8) -> Result<(), I8042Error> {
+    write_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+fn dispatch(port: Port, byte: u8) {
+    trace_event!(Interrupts, "i8042 {:?} port byte {:#04x}", port, byte);
+    if fault::should_fail(Fault::Interrupt) {
+        return;
+    }
+    // the handler may set another one
+    let handler = HANDLERS.lock()[port.index()];
+    if let Some(handler) = handler {
+        handler(byte);
+    }
+}
+
+/// Run `command` with the controller to itself and interrupts disabled.
+fn with_controller<T>(command: impl FnOnce() -> T) -> T {
+    interrupts::without_interrupts(|| {
+        let _guard = COMMAND_LOCK.lock();
+        command()
+    })
+}
+
+/// Test and configure the controller, must be called once after [time::init]. Both ports are
+/// enabled with their interrupts, the devices are left as the firmware configured them, e.g. the
+/// keyboard sends scancodes of set 1 through the translation of the controller.
+pub fn init() -> Result<(), I8042Error> {
+    with_controller(|| {
+        write_command(COMMAND_DISABLE_FIRST)?;
+        write_command(COMMAND_DISABLE_SECOND)?;
+        for _ in 0..MAX_FLUSHED {
+            if try_read().is_none() {
+                break;
+            }
+        }
+
+        let config = query(COMMAND_READ_CONFIG)? & !(CONFIG_IRQ_FIRST | CONFIG_IRQ_SECOND);
+        write_config(config)?;
+        match query(COMMAND_SELF_TEST)? {
+            SELF_TEST_PASSED => {}
+            answer => return Err(I8042Error::SelfTest(answer)),
+        }
+        // the self test may reset the controller
+        write_config(config)?;
+
+        // the clock of the second port only runs once it's enabled if there is one
+        write_command(COMMAND_ENABLE_SECOND)?;
+        let mut second = query(COMMAND_READ_CONFIG)? & CONFIG_SECOND_DISABLED == 0;
+        write_command(COMMAND_DISABLE_SECOND)?;
+
+        match query(COMMAND_TEST_FIRST)? {
+            PORT_TEST_PASSED => {}
+            answer => return Err(I8042Error::PortTest(Port::First, answer)),
+        }
+        if second && query(COMMAND_TEST_SECOND)? != PORT_TEST_PASSED {
+            second = false;
+        }
+
+        let mut config = config | CONFIG_IRQ_FIRST;
+        write_command(COMMAND_ENABLE_FIRST)?;
+        if second {
+            config |= CONFIG_IRQ_SECOND;
+            write_command(COMMAND_ENABLE_SECOND)?;
+        }
+        write_config(config)?;
+
+        SECOND_PORT.store(second, Ordering::Relaxed);
+        INITIALIZED.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Whether [init] succeeded.
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Relaxed)
+}
+
+/// Whether the controller has a working second port.
+pub fn has_second_port() -> bool {
+    SECOND_PORT.load(Ordering::Relaxed)
+}
+
+/// Call `handler` on each byte received from the device on `port`, in interrupt context.
+pub fn set_handler(port: Port, handler: Handler) {
+    // the lock is also taken by the interrupt handlers
+    interrupts::without_interrupts(|| HANDLERS.lock()[port.index()] = Some(handler));
+}
+
+/// Hand the byte waiting in the data port to the handler of its port, called by the interrupt
+/// handlers of both ports. The byte may already have been taken by a command, or by the handler
+/// of the other port.
+pub fn handle_interrupt() {
+    if let Some((port, byte)) = try_read() {
+        dispatch(port, byte);
+    }
+}
+
+/// Send the device command `command` to the device on `port`, each byte acknowledged, then read
+/// the `response` of the device.
+pub fn send(port: Port, command: &[u8], response: &mut [u8]) -> Result<(), I8042Error> {
+    if port == Port::Second && !has_second_port() {
+        return Err(I8042Error::NoSuchPort(port));
+    }
+
+    with_controller(|| {
+        for &byte in command {
+            let mut answer = DEVICE_RESEND;
+            for _ in 0..RESENDS {
+                if port == Port::Second {
+                    write_command(COMMAND_WRITE_SECOND)?;
+                }
+                write_data(byte)?;
+                answer = read_from(port, POLLS)?;
+                if answer != DEVICE_RESEND {
+                    break;
+                }
+            }
+            if answer != DEVICE_ACK {
+                return Err(I8042Error::NotAcknowledged(answer));
+            }
+        }
+        for byte in response.iter_mut() {
+            *byte = read_from(port, RESPONSE_POLLS)?;
+        }
+        Ok(())
+    })
+}
+
+/// Pulse the reset line of the CPU. The command in flight isn't waited for, the machine is reset
+/// anyway, so that a panic in the middle of a command can still reboot.
+pub fn pulse_reset() {
+    interrupts::without_interrupts(|| {
+        // a stuck controller is given the command anyway
+        let _ = wait_input_empty();
+        PORTS.status.write(0, COMMAND_RESET);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ask the device for its ID.
+    const DEVICE_IDENTIFY: u8 = 0xf2;
+    /// The first byte of the ID of MF2 keyboards.
+    const KEYBOARD_ID: u8 = 0xab;
+
+    #[test_case]
+    fn controller_initialized() {
+        assert!(is_initialized());
+        // QEMU emulates a mouse on the second port
+        assert!(has_second_port());
+    }
+
+    #[test_case]
+    fn keyboard_identified() {
+        let mut id = [0; 2];
+        send(Port::First, &[DEVICE_IDENTIFY], &mut id).unwrap();
+        assert_eq!(id[0], KEYBOARD_ID);
+    }
+}
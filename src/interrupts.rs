@@ -1,11 +1,25 @@
-use crate::{hlt_loop, print, println};
+use crate::{
+    apic, error,
+    fault::{self, Fault},
+    fs, hlt_loop, i8042, ioapic,
+    metrics::Counter,
+    scheduler,
+    smp::{self, Ipi},
+    task::{executor, watchdog},
+    testing, time, trace_event, unwind,
+    user::{self, Exit},
+    warn,
+};
 
 use crate::gdt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    PrivilegeLevel, VirtAddr,
+};
 
 /// Offset of the first PIC (Programmable Interrupt Controller).
 ///
@@ -15,6 +29,13 @@ pub const PIC_1_OFFSET: u8 = 32;
 /// Offset of the second PIC (Programmable Interrupt Controller).
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Number of times each interrupt vector has been handled since boot.
+static INTERRUPT_COUNTS: [AtomicU64; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
 static PICS: Mutex<ChainedPics> = {
     // # Safety
     // [pic8259_simple] didn't specify why this function is unsafe. One possible reason is the two
@@ -31,8 +52,12 @@ lazy_static! {
         // suitable as CPU interruption may be triggered by any instruction and change the state of
         // CPU flags.
 
-        // CPU interrupts
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // CPU interrupts, the programs in ring 3 may raise a breakpoint to exit
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler).set_privilege_level(PrivilegeLevel::Ring3);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         // Switch to the separate stack space on double fault.
         //
         // # Safety
@@ -44,24 +69,51 @@ lazy_static! {
         }
         idt.page_fault.set_handler_fn(page_fault_handler);
 
+        // system calls of the programs in ring 3
+        idt[usize::from(user::syscall::INTERRUPT_VECTOR)]
+            .set_handler_fn(user::syscall::interrupt_handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+
         // hardware interrupts
         idt[InterruptIndex::Timer.to_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.to_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial1.to_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Rtc.to_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Mouse.to_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::ApicTimer.to_usize()].set_handler_fn(apic_timer_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        idt[usize::from(PIC_1_OFFSET + 5)].set_handler_fn(irq5_handler);
+        idt[usize::from(PIC_1_OFFSET + 9)].set_handler_fn(irq9_handler);
+        idt[usize::from(PIC_1_OFFSET + 10)].set_handler_fn(irq10_handler);
+        idt[usize::from(PIC_1_OFFSET + 11)].set_handler_fn(irq11_handler);
+        idt[usize::from(PIC_1_OFFSET + 14)].set_handler_fn(irq14_handler);
+        idt[usize::from(PIC_1_OFFSET + 15)].set_handler_fn(irq15_handler);
 
-        idt
-    };
+        // inter-processor interrupts
+        idt[usize::from(Ipi::Reschedule.vector())].set_handler_fn(reschedule_ipi_handler);
+        idt[usize::from(Ipi::TlbShootdown.vector())].set_handler_fn(tlb_shootdown_ipi_handler);
+        idt[usize::from(Ipi::Halt.vector())].set_handler_fn(halt_ipi_handler);
 
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = {
-        let keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-        Mutex::new(keyboard)
+        idt
     };
 }
 
 /// Initialize the Interrupt Description Table. Currently the following handlers are defined:
-/// - breakpoint
+/// - divide error, invalid opcode, stack segment fault and general protection fault, which end the
+///   program raising them in ring 3 and panic in the kernel
+/// - breakpoint, allowed in ring 3
 /// - double fault
+/// - page fault, which maps the pages reserved by the programs in ring 3 on demand
+/// - `int 0x80`, the system calls of [user::syscall], allowed in ring 3
 /// - timer
 /// - keyboard
+/// - first serial port
+/// - RTC
+/// - mouse
+/// - local APIC timer
+/// - local APIC spurious interrupt
+/// - the PCI and ATA interrupt lines, see [register_irq_handler]
+/// - the inter-processor interrupts of [smp::Ipi]
 ///
 /// # Safety
 /// This function is unsafe because the IDT refers to an entry in the Interrupt Stack Table which
@@ -71,7 +123,15 @@ pub unsafe fn init_idt() {
     IDT.load();
 }
 
+/// The IDT loaded by [init_idt].
+pub(crate) fn kernel_idt() -> &'static InterruptDescriptorTable {
+    &IDT
+}
+
 /// Initialize and enable hardware interrupts in the CPU.
+///
+/// If [ioapic::init] succeeded, the PICs are masked and the legacy interrupt lines are routed
+/// through the I/O APIC to the same vectors instead.
 pub fn init_pics() {
     // # Safety
     // Again [pic8259_simple] didn't specify why this function is unsafe. It may be its unsafe usage
@@ -81,10 +141,131 @@ pub fn init_pics() {
         PICS.lock().initialize();
     }
 
+    if ioapic::is_initialized() {
+        // the PICs are remapped above the exceptions anyway, their spurious interrupts are harmless
+        set_pic_masks(0xff, 0xff);
+        let lines = [0, 1, 4, 8, 12].iter().chain(&PCI_IRQS).chain(&ATA_IRQS);
+        for &irq in lines {
+            if !ioapic::route_irq(irq, PIC_1_OFFSET + irq) {
+                warn!("IRQ {} not routed through the I/O APIC", irq);
+            }
+        }
+        // the firmware leaves the keyboard unmasked on the PICs, all lines start masked here
+        unmask_irq(InterruptIndex::Keyboard.to_u8() - PIC_1_OFFSET);
+    }
+
+    // the firmware leaves the serial port masked, the original masks are restored by
+    // [ChainedPics::initialize]
+    unmask_irq(InterruptIndex::Serial1.to_u8() - PIC_1_OFFSET);
+    // masked by the firmware as well, the mouse only sends packets once enabled by
+    // [crate::task::mouse::init]
+    unmask_irq(InterruptIndex::Mouse.to_u8() - PIC_1_OFFSET);
+    if let Some(tick_irq) = time::tick_source().irq() {
+        unmask_irq(tick_irq);
+    }
+
     // enable hardware interrupts in the CPU by `sti` instruction
     x86_64::instructions::interrupts::enable();
 }
 
+/// The name of the controller delivering the legacy interrupt lines, "ioapic" or "pic".
+pub fn controller_name() -> &'static str {
+    if ioapic::is_initialized() {
+        "ioapic"
+    } else {
+        "pic"
+    }
+}
+
+const PIC_1_DATA_PORT: u16 = 0x21;
+const PIC_2_DATA_PORT: u16 = 0xa1;
+
+/// Overwrite the interrupt masks of both PICs.
+fn set_pic_masks(first: u8, second: u8) {
+    use x86_64::instructions::port::Port;
+    // # Safety
+    // Outside of initialization the data ports of the PICs read and write the interrupt masks.
+    unsafe {
+        Port::<u8>::new(PIC_1_DATA_PORT).write(first);
+        Port::<u8>::new(PIC_2_DATA_PORT).write(second);
+    }
+}
+
+/// Unmask the hardware interrupt line `irq` (0 - 15) on the I/O APIC, or on the PICs without one.
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    assert!(irq < 16, "the chained PICs only have 16 interrupt lines");
+    if ioapic::is_initialized() {
+        if !ioapic::set_masked(irq, false) {
+            warn!("IRQ {} is not routed through the I/O APIC", irq);
+        }
+        return;
+    }
+
+    let (port, bit) = if irq < 8 {
+        (PIC_1_DATA_PORT, irq)
+    } else {
+        (PIC_2_DATA_PORT, irq - 8)
+    };
+
+    let mut port = Port::<u8>::new(port);
+    // # Safety
+    // Outside of initialization the data ports of the PICs read and write the interrupt masks.
+    // Interrupts are disabled so that the read-modify-write is not interleaved by handlers.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mask = port.read();
+        port.write(mask & !(1 << bit));
+    });
+    if irq >= 8 {
+        // interrupts of the second PIC reach the CPU through the cascade on IRQ 2
+        unmask_irq(2);
+    }
+}
+
+/// The legacy interrupt lines the firmware routes PCI interrupts to, SeaBIOS on QEMU uses 10 and
+/// 11 for the i440FX machine and 5, 9, 10 and 11 for the Q35 machine.
+pub const PCI_IRQS: [u8; 4] = [5, 9, 10, 11];
+/// The legacy interrupt lines of the primary and the secondary ATA channels.
+pub const ATA_IRQS: [u8; 2] = [14, 15];
+/// Maximum number of handlers sharing an interrupt line.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// The handlers sharing an interrupt line.
+type SharedHandlers = [Option<fn()>; MAX_SHARED_HANDLERS];
+
+/// The handlers registered on each legacy interrupt line, only those in [PCI_IRQS] and [ATA_IRQS]
+/// are used.
+static IRQ_HANDLERS: Mutex<[SharedHandlers; 16]> = Mutex::new([[None; MAX_SHARED_HANDLERS]; 16]);
+
+/// Call `handler` on each interrupt on the interrupt line `irq` and unmask the line.
+///
+/// PCI interrupts are level-triggered and shared, all the handlers registered on the line are
+/// called in turn. A handler must check whether its device raised the interrupt and acknowledge
+/// it on the device, otherwise the line stays asserted.
+///
+/// # Panics
+/// Panics if `irq` isn't one of [PCI_IRQS] or [ATA_IRQS], or too many handlers share it.
+pub fn register_irq_handler(irq: u8, handler: fn()) {
+    assert!(
+        PCI_IRQS.contains(&irq) || ATA_IRQS.contains(&irq),
+        "IRQ {} has no dispatcher",
+        irq
+    );
+
+    // the lock is also taken by the interrupt handlers of the line
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[usize::from(irq)]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .unwrap_or_else(|| panic!("too many handlers share IRQ {}", irq));
+        *slot = Some(handler);
+    });
+
+    unmask_irq(irq);
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 /// Indices into the Interrupt Descriptor Table of the interrupts originated from outside of the
@@ -93,6 +274,11 @@ pub fn init_pics() {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    Serial1 = PIC_1_OFFSET + 4,
+    Rtc = PIC_2_OFFSET,
+    Mouse = PIC_2_OFFSET + 4,
+    /// The local APIC follows the PICs.
+    ApicTimer = PIC_2_OFFSET + 8,
 }
 
 impl InterruptIndex {
@@ -111,17 +297,201 @@ impl InterruptIndex {
     }
 }
 
+/// Vector number of the divide error exception.
+pub const DIVIDE_ERROR_VECTOR: u8 = 0;
+/// Vector number of the breakpoint exception.
+pub const BREAKPOINT_VECTOR: u8 = 3;
+/// Vector number of the invalid opcode exception.
+pub const INVALID_OPCODE_VECTOR: u8 = 6;
+/// Vector number of the double fault exception.
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+/// Vector number of the stack segment fault exception.
+pub const STACK_SEGMENT_FAULT_VECTOR: u8 = 12;
+/// Vector number of the general protection fault exception.
+pub const GENERAL_PROTECTION_FAULT_VECTOR: u8 = 13;
+/// Vector number of the page fault exception.
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+
+/// Human readable name of an interrupt vector with a handler defined in the IDT.
+pub fn vector_name(vector: u8) -> Option<&'static str> {
+    let name = match vector {
+        DIVIDE_ERROR_VECTOR => "divide error",
+        BREAKPOINT_VECTOR => "breakpoint",
+        INVALID_OPCODE_VECTOR => "invalid opcode",
+        DOUBLE_FAULT_VECTOR => "double fault",
+        STACK_SEGMENT_FAULT_VECTOR => "stack segment fault",
+        GENERAL_PROTECTION_FAULT_VECTOR => "general protection fault",
+        PAGE_FAULT_VECTOR => "page fault",
+        v if v == InterruptIndex::Timer.to_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.to_u8() => "keyboard",
+        v if v == InterruptIndex::Serial1.to_u8() => "serial",
+        v if v == InterruptIndex::Rtc.to_u8() => "rtc",
+        v if v == InterruptIndex::Mouse.to_u8() => "mouse",
+        v if v == InterruptIndex::ApicTimer.to_u8() => "apic timer",
+        apic::SPURIOUS_VECTOR => "spurious",
+        v if PCI_IRQS.iter().any(|&irq| v == PIC_1_OFFSET + irq) => "pci",
+        v if ATA_IRQS.iter().any(|&irq| v == PIC_1_OFFSET + irq) => "ata",
+        v if v == Ipi::Reschedule.vector() => "ipi reschedule",
+        v if v == Ipi::TlbShootdown.vector() => "ipi tlb shootdown",
+        v if v == Ipi::Halt.vector() => "ipi halt",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Number of times the interrupt `vector` has been handled since boot.
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+static TOTAL_INTERRUPTS: Counter = Counter::new("interrupts.total");
+/// Number of interrupt handlers on the stack.
+static NESTING: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of interrupt handlers on the stack, 0 outside of interrupt handlers.
+pub fn nesting() -> usize {
+    NESTING.load(Ordering::Relaxed)
+}
+
+/// Marks an interrupt handler on the stack until dropped.
+struct HandlerGuard;
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        NESTING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Forget the interrupt handlers on the stack above the `nesting` outermost ones, for code resuming
+/// execution below their frames.
+///
+/// # Safety
+/// The frames of the forgotten handlers must be discarded and never returned to.
+pub(crate) unsafe fn abandon_handlers(nesting: usize) {
+    NESTING.store(nesting, Ordering::Relaxed);
+}
+
+/// Set the number of interrupt handlers on the stack to `nesting`, for the scheduler resuming a
+/// thread with `nesting` handlers on its stack.
+///
+/// # Safety
+/// The stack switched to must hold exactly `nesting` handler frames.
+pub(crate) unsafe fn switch_handlers(nesting: usize) {
+    NESTING.store(nesting, Ordering::Relaxed);
+}
+
+/// Signal the end of the hardware interrupt `vector` to its interrupt controller: the local APIC for
+/// its own interrupts and those delivered by the I/O APIC, the PICs otherwise.
+///
+/// # Safety
+/// `vector` must be the hardware interrupt being handled.
+pub(crate) unsafe fn end_of_interrupt(vector: u8) {
+    if vector == InterruptIndex::ApicTimer.to_u8() || ioapic::is_initialized() {
+        apic::eoi();
+    } else {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+/// Run `f` as if in an interrupt handler, for tests of code behaving differently there.
+#[cfg(test)]
+pub(crate) fn as_handler<R>(f: impl FnOnce() -> R) -> R {
+    NESTING.fetch_add(1, Ordering::Relaxed);
+    let _guard = HandlerGuard;
+    f()
+}
+
+/// Count the interrupt and mark its handler on the stack, must be called first in each handler.
+fn enter_handler(vector: u8) -> HandlerGuard {
+    count_interrupt(vector);
+    NESTING.fetch_add(1, Ordering::Relaxed);
+    HandlerGuard
+}
+
+fn count_interrupt(vector: u8) {
+    TOTAL_INTERRUPTS.inc();
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// End the program which raised the exception `vector` in ring 3, or panic if the kernel raised it.
+fn fault(
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    address: Option<VirtAddr>,
+) -> ! {
+    let exit = Exit::Exception {
+        vector,
+        error_code,
+        instruction: stack_frame.instruction_pointer,
+        address,
+    };
+    if user::from_user(stack_frame) {
+        user::leave(exit);
+    }
+    panic!(
+        "EXCEPTION: {}\n{:#?}\n{}",
+        exit,
+        stack_frame,
+        unwind::exception_backtrace(stack_frame)
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(DIVIDE_ERROR_VECTOR);
+    fault(DIVIDE_ERROR_VECTOR, &stack_frame, None, None);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    let _guard = enter_handler(BREAKPOINT_VECTOR);
+    if user::from_user(&stack_frame) {
+        user::leave(Exit::Breakpoint);
+    }
+    warn!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(INVALID_OPCODE_VECTOR);
+    fault(INVALID_OPCODE_VECTOR, &stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _guard = enter_handler(STACK_SEGMENT_FAULT_VECTOR);
+    fault(
+        STACK_SEGMENT_FAULT_VECTOR,
+        &stack_frame,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _guard = enter_handler(GENERAL_PROTECTION_FAULT_VECTOR);
+    fault(
+        GENERAL_PROTECTION_FAULT_VECTOR,
+        &stack_frame,
+        Some(error_code),
+        None,
+    );
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    let _guard = enter_handler(DOUBLE_FAULT_VECTOR);
     panic!(
-        "EXCEPTION: DOUBLE FAULT\nerror code: {}\n{:#?}",
-        error_code, stack_frame
+        "EXCEPTION: DOUBLE FAULT\nerror code: {}\n{:#?}\n{}",
+        error_code,
+        stack_frame,
+        unwind::exception_backtrace(&stack_frame)
     );
 }
 
@@ -131,56 +501,216 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    let _guard = enter_handler(PAGE_FAULT_VECTOR);
+
+    let addr = Cr2::read();
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            && user::handle_page_fault(
+                addr,
+                error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            )
+        {
+            return;
+        }
+        fault(
+            PAGE_FAULT_VECTOR,
+            &stack_frame,
+            Some(error_code.bits()),
+            Some(addr),
+        );
+    }
+    if fs::mmap::handle_page_fault(addr, error_code) {
+        return;
+    }
+    error!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}\n{}",
+        addr,
+        error_code,
+        stack_frame,
+        unwind::exception_backtrace(&stack_frame)
+    );
     hlt_loop();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(InterruptIndex::Timer.to_u8());
     // print!(".");
 
+    let ticked = time::tick(InterruptIndex::Timer.to_u8());
+    if ticked {
+        check_watchdog(&stack_frame, InterruptIndex::Timer.to_u8());
+    }
+
     // # Safety
     // Timer is exactly the interrupt handled by this handler.
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.to_u8());
+        end_of_interrupt(InterruptIndex::Timer.to_u8());
+    }
+    if ticked {
+        scheduler::preempt(&mut stack_frame);
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-    const PS2_KEYBOARD_PORT: u16 = 0x60;
+extern "x86-interrupt" fn rtc_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(InterruptIndex::Rtc.to_u8());
 
-    // let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::<u8>::new(PS2_KEYBOARD_PORT);
+    // the RTC fires no more interrupt until this one is acknowledged
+    time::rtc::acknowledge();
+    let ticked = time::tick(InterruptIndex::Rtc.to_u8());
+    if ticked {
+        check_watchdog(&stack_frame, InterruptIndex::Rtc.to_u8());
+    }
 
     // # Safety
-    // 0x60 is the PS/2 controller data port, the port has data size of 1.
-    let scancode = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    // // Processing a byte read from the PS/2 data port may not always be successful: the scancode may
-    // // be invalid, the scancode may lead to an impossible state assuming the keyboard layout, the
-    // // scancode may be corrupted by transmission, etc. Processing a byte may also not return a key
-    // // event, e.g. the escape byte before extended keycode.
-    // if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-    //     // Press and release are two separate events in IBM XT. Here only key presses are mapped to
-    //     // characters.
-    //     if let Some(key) = keyboard.process_keyevent(key_event) {
-    //         match key {
-    //             DecodedKey::RawKey(key) => print!("{:?}", key),
-    //             DecodedKey::Unicode(code) => print!("{}", code),
-    //         }
-    //     }
-    // }
+    // The RTC is exactly the interrupt handled by this handler.
+    unsafe {
+        end_of_interrupt(InterruptIndex::Rtc.to_u8());
+    }
+    if ticked {
+        scheduler::preempt(&mut stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(InterruptIndex::ApicTimer.to_u8());
+
+    let ticked = time::tick(InterruptIndex::ApicTimer.to_u8());
+    if ticked {
+        check_watchdog(&stack_frame, InterruptIndex::ApicTimer.to_u8());
+    }
+
+    apic::eoi();
+    if ticked {
+        scheduler::preempt(&mut stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // spurious interrupts are not acknowledged
+    let _guard = enter_handler(apic::SPURIOUS_VECTOR);
+}
+
+/// Call the handlers registered on the interrupt line `irq`.
+fn dispatch_irq(irq: u8) {
+    // the second PIC follows the first one, the vectors of both are contiguous
+    let vector = PIC_1_OFFSET + irq;
+    let _guard = enter_handler(vector);
+
+    let handlers = IRQ_HANDLERS.lock()[usize::from(irq)];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+
+    // # Safety
+    // `vector` is exactly the interrupt being handled.
+    unsafe {
+        end_of_interrupt(vector);
+    }
+}
+
+extern "x86-interrupt" fn irq5_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(5);
+}
+
+extern "x86-interrupt" fn irq9_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(9);
+}
+
+extern "x86-interrupt" fn irq10_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(10);
+}
+
+extern "x86-interrupt" fn irq11_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(11);
+}
+
+extern "x86-interrupt" fn irq14_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(14);
+}
+
+extern "x86-interrupt" fn irq15_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(15);
+}
+
+/// Handle an IPI, received by any processor. [NESTING] only counts the handlers of the BSP, the
+/// IPI handlers are counted but not marked on the stack.
+fn dispatch_ipi(ipi: Ipi, stack_frame: &InterruptStackFrame) {
+    count_interrupt(ipi.vector());
+    smp::handle_ipi(ipi, stack_frame);
+    apic::eoi();
+}
+
+extern "x86-interrupt" fn reschedule_ipi_handler(stack_frame: InterruptStackFrame) {
+    dispatch_ipi(Ipi::Reschedule, &stack_frame);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_ipi_handler(stack_frame: InterruptStackFrame) {
+    dispatch_ipi(Ipi::TlbShootdown, &stack_frame);
+}
+
+extern "x86-interrupt" fn halt_ipi_handler(stack_frame: InterruptStackFrame) {
+    dispatch_ipi(Ipi::Halt, &stack_frame);
+}
+
+/// Report a stalled executor or a hung test on a timer tick, inlined so that the backtrace starts
+/// at the handler.
+#[inline(always)]
+fn check_watchdog(stack_frame: &InterruptStackFrame, vector: u8) {
+    testing::check_timeout(stack_frame, vector);
+
+    if let Some(stalled) = watchdog::check() {
+        error!(
+            "WATCHDOG: executor stalled for {} ms\n{}{}",
+            time::ticks_to_duration(stalled).as_millis(),
+            executor::StallReport,
+            unwind::exception_backtrace(stack_frame)
+        );
+    }
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(InterruptIndex::Keyboard.to_u8());
+
+    // the scancode is handed to the keyboard task by the handler of the first port
+    i8042::handle_interrupt();
 
     // # Safety
     // Keyboard is exactly the interrupt handled by this handler.
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.to_u8());
+        end_of_interrupt(InterruptIndex::Keyboard.to_u8());
+    }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(InterruptIndex::Mouse.to_u8());
+
+    // the byte is handed to the mouse task by the handler of the second port
+    i8042::handle_interrupt();
+
+    // # Safety
+    // Mouse is exactly the interrupt handled by this handler.
+    unsafe {
+        end_of_interrupt(InterruptIndex::Mouse.to_u8());
+    }
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = enter_handler(InterruptIndex::Serial1.to_u8());
+
+    // the UART may hold more than one byte in its FIFO, all of them are lost with the interrupt
+    let lost = fault::should_fail(Fault::Interrupt);
+    while let Some(byte) = crate::serial::try_receive() {
+        trace_event!(Interrupts, "serial byte {:#04x}", byte);
+        if !lost {
+            crate::task::serial::add_byte(byte);
+        }
+    }
+
+    // # Safety
+    // The first serial port is exactly the interrupt handled by this handler.
+    unsafe {
+        end_of_interrupt(InterruptIndex::Serial1.to_u8());
     }
 }
 
@@ -0,0 +1,227 @@
+//! Ownership of the I/O ports.
+//!
+//! A driver [claims](PortRange::claim) the ports of its device once, which fails if another driver
+//! claimed any of them, then reads and writes them through the returned [PortRange] without
+//! `unsafe`. The claim is released when the range is dropped. Claims are kept in a fixed table so
+//! that ports can be claimed before the heap is initialized, e.g. by the serial port.
+
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::port::{PortRead, PortWrite},
+};
+
+/// The largest number of ranges claimed at the same time.
+pub const MAX_CLAIMS: usize = 32;
+
+/// The claimed ranges, in no particular order.
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+/// A claimed range of I/O ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Claim {
+    /// The first port of the range.
+    pub base: u16,
+    /// The number of ports of the range.
+    pub count: u16,
+    /// The name of the driver owning the range.
+    pub owner: &'static str,
+}
+
+impl Claim {
+    /// The ports of the range.
+    pub fn ports(&self) -> Range<u32> {
+        u32::from(self.base)..u32::from(self.base) + u32::from(self.count)
+    }
+}
+
+/// Errors of [PortRange::claim].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// Some of the ports are owned by another range.
+    Claimed(Claim),
+    /// The range is empty or reaches past the last port.
+    InvalidRange,
+    /// [MAX_CLAIMS] ranges are already claimed.
+    TooManyClaims,
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoError::Claimed(claim) => write!(
+                f,
+                "ports {:#x} - {:#x} owned by {}",
+                claim.base,
+                claim.ports().end - 1,
+                claim.owner
+            ),
+            IoError::InvalidRange => write!(f, "invalid range of ports"),
+            IoError::TooManyClaims => write!(f, "too many claimed ranges"),
+        }
+    }
+}
+
+/// A range of I/O ports owned by a driver, accessed by their offset from the first port.
+#[derive(Debug)]
+pub struct PortRange {
+    claim: Claim,
+}
+
+impl PortRange {
+    /// Claim the `count` ports from `base` on for the driver `owner`, fails if any of them is
+    /// already claimed.
+    ///
+    /// # Safety
+    /// The ports must be those of the device driven by the caller, and accessing them through the
+    /// range must not break memory safety, e.g. by pointing the device at memory the caller
+    /// doesn't own.
+    pub unsafe fn claim(base: u16, count: u16, owner: &'static str) -> Result<Self, IoError> {
+        let claim = Claim { base, count, owner };
+        if count == 0 || claim.ports().end > u32::from(u16::MAX) + 1 {
+            return Err(IoError::InvalidRange);
+        }
+
+        // a handler may claim its ports on its first interrupt
+        interrupts::without_interrupts(|| {
+            let mut claims = CLAIMS.lock();
+            let overlaps = |other: &Claim| {
+                let (ports, other_ports) = (claim.ports(), other.ports());
+                ports.start < other_ports.end && other_ports.start < ports.end
+            };
+            if let Some(other) = claims.iter().flatten().find(|other| overlaps(other)) {
+                return Err(IoError::Claimed(*other));
+            }
+            let free = claims
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(IoError::TooManyClaims)?;
+            *free = Some(claim);
+            Ok(PortRange { claim })
+        })
+    }
+
+    /// The first port of the range.
+    pub fn base(&self) -> u16 {
+        self.claim.base
+    }
+
+    /// The port at `offset` of the range, panics if the access of `size` bytes isn't within it.
+    fn port(&self, offset: u16, size: usize) -> u16 {
+        assert!(
+            u32::from(offset) + size as u32 <= u32::from(self.claim.count),
+            "access of {} bytes at offset {:#x} outside of the {} ports of {}",
+            size,
+            offset,
+            self.claim.count,
+            self.claim.owner
+        );
+        self.claim.base + offset
+    }
+
+    /// Read the port at `offset`, e.g. a `u16` for a word-sized register. Panics if the register
+    /// isn't within the range.
+    pub fn read<T: PortRead>(&self, offset: u16) -> T {
+        let port = self.port(offset, core::mem::size_of::<T>());
+        // # Safety
+        // The port is owned by the range, see [PortRange::claim].
+        unsafe { T::read_from_port(port) }
+    }
+
+    /// Write `value` to the port at `offset`, with the same requirements as [PortRange::read].
+    pub fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        let port = self.port(offset, core::mem::size_of::<T>());
+        // # Safety
+        // As above.
+        unsafe { T::write_to_port(port, value) }
+    }
+}
+
+impl Drop for PortRange {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut claims = CLAIMS.lock();
+            if let Some(slot) = claims.iter_mut().find(|slot| **slot == Some(self.claim)) {
+                *slot = None;
+            }
+        });
+    }
+}
+
+/// The claimed ranges, sorted by their first port, e.g. for diagnostics.
+pub fn claims() -> Vec<Claim> {
+    let mut claims: Vec<Claim> =
+        interrupts::without_interrupts(|| CLAIMS.lock().iter().flatten().copied().collect());
+    claims.sort_unstable_by_key(|claim| claim.base);
+    claims
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{should_panic, ShouldPanic};
+
+    /// The ports of COM3 and COM4, which QEMU doesn't emulate by default.
+    const UNUSED_BASE: u16 = 0x3e8;
+    /// The claim of a test expected to panic is never released.
+    const LEAKED_BASE: u16 = 0x2e8;
+
+    #[test_case]
+    fn double_claim_refused() {
+        // # Safety
+        // No device answers on the ports.
+        let range = unsafe { PortRange::claim(UNUSED_BASE, 8, "test") }.unwrap();
+        let overlapping = unsafe { PortRange::claim(UNUSED_BASE + 4, 8, "other") };
+        assert_eq!(
+            overlapping.unwrap_err(),
+            IoError::Claimed(Claim {
+                base: UNUSED_BASE,
+                count: 8,
+                owner: "test"
+            })
+        );
+        // nothing on the bus, the lines float high
+        assert_eq!(range.read::<u8>(7), 0xff);
+
+        drop(range);
+        let again = unsafe { PortRange::claim(UNUSED_BASE + 4, 8, "other") };
+        assert!(again.is_ok());
+    }
+
+    #[test_case]
+    fn invalid_ranges_refused() {
+        // # Safety
+        // The claims fail.
+        assert_eq!(
+            unsafe { PortRange::claim(UNUSED_BASE, 0, "test") }.unwrap_err(),
+            IoError::InvalidRange
+        );
+        assert_eq!(
+            unsafe { PortRange::claim(u16::MAX, 2, "test") }.unwrap_err(),
+            IoError::InvalidRange
+        );
+    }
+
+    #[test_case]
+    fn drivers_claimed() {
+        // the serial port is claimed on the first print, long before the tests
+        let claims = claims();
+        assert!(claims
+            .iter()
+            .any(|claim| claim.owner == "serial" && claim.base == 0x3f8));
+        assert!(claims.windows(2).all(|pair| pair[0].base < pair[1].base));
+    }
+
+    fn access_outside() {
+        // # Safety
+        // No device answers on the ports.
+        let range = unsafe { PortRange::claim(LEAKED_BASE, 2, "test") }.unwrap();
+        range.read::<u32>(0);
+    }
+
+    #[test_case]
+    const ACCESS_OUTSIDE: ShouldPanic = should_panic!(access_outside);
+}
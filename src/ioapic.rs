@@ -0,0 +1,272 @@
+//! The I/O APICs, which deliver the external interrupts to the local APIC in place of the legacy
+//! PICs.
+//!
+//! The MADT lists the I/O APICs with the first global system interrupt (GSI) each one serves, and
+//! the ISA IRQs which aren't identity mapped to GSIs with their polarity and trigger mode, e.g. the
+//! PIT on GSI 2 and the level-triggered PCI lines on QEMU. [route_irq] programs the redirection
+//! entry of the GSI of an ISA IRQ to deliver a vector to the bootstrap processor, masked until
+//! [set_masked] unmasks it. The interrupts delivered through an I/O APIC are acknowledged to the
+//! local APIC with [apic::eoi], which forwards the end of the level-triggered ones.
+
+use conquer_once::spin::OnceCell;
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    acpi::{
+        self,
+        madt::{MadtEntry, INTI_ACTIVE_LOW, INTI_LEVEL_TRIGGERED},
+    },
+    apic, memory, warn,
+};
+
+/// The largest number of I/O APICs used.
+pub const MAX_IO_APICS: usize = 4;
+/// The number of ISA IRQs.
+const ISA_IRQS: usize = 16;
+
+const REG_SELECT: u64 = 0x00;
+const REG_WINDOW: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+/// An I/O APIC mapped by [init].
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    /// The first GSI it serves.
+    gsi_base: u32,
+    /// The number of GSIs it serves.
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        // # Safety
+        // The register page is mapped by [init], the registers are accessed through the select and
+        // the window registers under the lock of [STATE].
+        unsafe {
+            ptr::write_volatile((self.base + REG_SELECT).as_mut_ptr(), reg);
+            ptr::read_volatile((self.base + REG_WINDOW).as_ptr())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        // # Safety
+        // As above.
+        unsafe {
+            ptr::write_volatile((self.base + REG_SELECT).as_mut_ptr(), reg);
+            ptr::write_volatile((self.base + REG_WINDOW).as_mut_ptr(), value);
+        }
+    }
+
+    fn serves(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    /// The redirection entry of `gsi`, which it must serve.
+    fn redirection(&self, gsi: u32) -> u64 {
+        let reg = REG_REDIRECTION + 2 * (gsi - self.gsi_base);
+        u64::from(self.read(reg)) | u64::from(self.read(reg + 1)) << 32
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = REG_REDIRECTION + 2 * (gsi - self.gsi_base);
+        // masked while half written
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// The I/O APICs and the GSIs the ISA IRQs are routed on.
+struct State {
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    routes: [Option<u32>; ISA_IRQS],
+}
+
+impl State {
+    fn serving(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .flatten()
+            .find(|io_apic| io_apic.serves(gsi))
+    }
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    io_apics: [None; MAX_IO_APICS],
+    routes: [None; ISA_IRQS],
+});
+static INITIALIZED: OnceCell<()> = OnceCell::uninit();
+
+/// Map the registers of the I/O APICs listed by the MADT and mask all their interrupts. Must be
+/// called after [apic::init]. Returns `false` if the local APIC isn't initialized, or if the MADT
+/// lists no I/O APIC that can be mapped.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> bool {
+    if !apic::is_initialized() {
+        return false;
+    }
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+
+    let mut state = STATE.lock();
+    let mut count = 0;
+    for entry in madt.entries() {
+        let (address, gsi_base) = match entry {
+            MadtEntry::IoApic {
+                address, gsi_base, ..
+            } => (address, gsi_base),
+            _ => continue,
+        };
+        if count == MAX_IO_APICS {
+            warn!(
+                "more than {} I/O APICs, ignoring {:?}",
+                MAX_IO_APICS, address
+            );
+            break;
+        }
+        // # Safety
+        // The register page of an I/O APIC is not memory handed out by the frame allocator.
+        let base = match unsafe { memory::map_mmio(mapper, frame_allocator, address, 4096) } {
+            Ok(base) => base,
+            Err(err) => {
+                warn!("I/O APIC at {:?} not mapped: {:?}", address, err);
+                continue;
+            }
+        };
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = (io_apic.read(REG_VERSION) >> 16 & 0xff) + 1;
+        for gsi in gsi_base..gsi_base + io_apic.entries {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        state.io_apics[count] = Some(io_apic);
+        count += 1;
+    }
+
+    count > 0 && INITIALIZED.try_init_once(|| ()).is_ok()
+}
+
+/// Whether [init] succeeded, the external interrupts are then routed through the I/O APICs.
+pub fn is_initialized() -> bool {
+    INITIALIZED.is_initialized()
+}
+
+/// Deliver the ISA IRQ `irq` (0 - 15) as the interrupt `vector` to the bootstrap processor, on the
+/// GSI and with the polarity and the trigger mode listed by the MADT. The interrupt stays masked.
+/// Returns `false` if no I/O APIC serves the GSI.
+pub fn route_irq(irq: u8, vector: u8) -> bool {
+    assert!(usize::from(irq) < ISA_IRQS, "IRQ {} is not an ISA IRQ", irq);
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    let gsi = madt.irq_to_gsi(irq);
+    let flags = madt.irq_flags(irq);
+
+    let mut entry = u64::from(vector)
+        | REDIRECTION_MASKED
+        | u64::from(apic::id()) << REDIRECTION_DESTINATION_SHIFT;
+    if flags & INTI_ACTIVE_LOW == INTI_ACTIVE_LOW {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if flags & INTI_LEVEL_TRIGGERED == INTI_LEVEL_TRIGGERED {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        match state.serving(gsi) {
+            Some(io_apic) => io_apic.set_redirection(gsi, entry),
+            None => return false,
+        }
+        state.routes[usize::from(irq)] = Some(gsi);
+        true
+    })
+}
+
+/// Mask or unmask the ISA IRQ `irq` routed by [route_irq]. Returns `false` if it isn't routed.
+pub fn set_masked(irq: u8, masked: bool) -> bool {
+    interrupts::without_interrupts(|| {
+        let state = STATE.lock();
+        let gsi = match state.routes.get(usize::from(irq)).copied().flatten() {
+            Some(gsi) => gsi,
+            None => return false,
+        };
+        let io_apic = match state.serving(gsi) {
+            Some(io_apic) => io_apic,
+            None => return false,
+        };
+        let entry = io_apic.redirection(gsi);
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+        io_apic.set_redirection(gsi, entry);
+        true
+    })
+}
+
+/// The vector the ISA IRQ `irq` is delivered as and whether it's masked, `None` if it isn't
+/// routed.
+pub fn route(irq: u8) -> Option<(u8, bool)> {
+    interrupts::without_interrupts(|| {
+        let state = STATE.lock();
+        let gsi = state.routes.get(usize::from(irq)).copied().flatten()?;
+        let entry = state.serving(gsi)?.redirection(gsi);
+        Some((entry as u8, entry & REDIRECTION_MASKED != 0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interrupts::{InterruptIndex, PCI_IRQS},
+        time,
+    };
+
+    #[test_case]
+    fn legacy_interrupts_routed() {
+        // QEMU always emulates an I/O APIC
+        assert!(is_initialized());
+        let (vector, masked) = route(0).unwrap();
+        assert_eq!(vector, InterruptIndex::Timer.to_u8());
+        assert_eq!(masked, time::tick_source().irq() != Some(0));
+        assert_eq!(route(1), Some((InterruptIndex::Keyboard.to_u8(), false)));
+        assert_eq!(route(4), Some((InterruptIndex::Serial1.to_u8(), false)));
+        assert_eq!(route(2), None);
+
+        // the PIT is on GSI 2, the PCI lines are level triggered
+        let state = STATE.lock();
+        assert_eq!(state.routes[0], Some(2));
+        let gsi = state.routes[usize::from(PCI_IRQS[3])].unwrap();
+        let entry = state.serving(gsi).unwrap().redirection(gsi);
+        assert_eq!(
+            entry & REDIRECTION_LEVEL_TRIGGERED,
+            REDIRECTION_LEVEL_TRIGGERED
+        );
+    }
+}
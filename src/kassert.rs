@@ -0,0 +1,144 @@
+//! Assertions with kernel context.
+//!
+//! On failure [kassert] and [kassert_eq] panic like their `core` counterparts, with the task being
+//! polled and the interrupt nesting depth appended to the message. [kassert_warn] and
+//! [kassert_eq_warn] instead log the failure at the error level and continue, for invariants worth
+//! watching without halting the kernel. They are only checked in debug builds.
+
+use core::{fmt, panic::Location};
+
+use crate::{error, interrupts, task::executor};
+
+/// The context of a failed assertion.
+struct Context;
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match executor::current_task() {
+            Some(id) => {
+                let name = executor::current_task_name().unwrap_or("<unknown>");
+                writeln!(f, "  task: {} {}", id, name)?;
+            }
+            None => writeln!(f, "  task: none")?,
+        }
+        write!(f, "  interrupt nesting: {}", interrupts::nesting())
+    }
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn _fail(args: fmt::Arguments) -> ! {
+    panic!("{}\n{}", args, Context);
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn _warn(args: fmt::Arguments) {
+    error!("{} at {}\n{}", args, Location::caller(), Context);
+}
+
+/// Asserts that a boolean expression is true, panics with the kernel context otherwise. An
+/// optional message can be supplied in the form of [format_args].
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::kassert::_fail(format_args!("assertion failed: {}", stringify!($cond)));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kassert::_fail(format_args!(
+                "assertion failed: {}: {}",
+                stringify!($cond),
+                format_args!($($arg)+)
+            ));
+        }
+    };
+}
+
+/// Asserts that two expressions are equal, panics with both values and the kernel context
+/// otherwise.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::kassert::_fail(format_args!(
+                        "assertion failed: `{} == {}`\n  left: {:?}\n  right: {:?}",
+                        stringify!($left),
+                        stringify!($right),
+                        left,
+                        right
+                    ));
+                }
+            }
+        }
+    };
+}
+
+/// Like [kassert] but logs the failure at the error level and continues. Only checked in debug
+/// builds.
+#[macro_export]
+macro_rules! kassert_warn {
+    ($cond:expr $(,)?) => {
+        if cfg!(debug_assertions) && !$cond {
+            $crate::kassert::_warn(format_args!("assertion failed: {}", stringify!($cond)));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if cfg!(debug_assertions) && !$cond {
+            $crate::kassert::_warn(format_args!(
+                "assertion failed: {}: {}",
+                stringify!($cond),
+                format_args!($($arg)+)
+            ));
+        }
+    };
+}
+
+/// Like [kassert_eq] but logs the failure at the error level and continues. Only checked in debug
+/// builds.
+#[macro_export]
+macro_rules! kassert_eq_warn {
+    ($left:expr, $right:expr $(,)?) => {
+        if cfg!(debug_assertions) {
+            match (&$left, &$right) {
+                (left, right) => {
+                    if !(*left == *right) {
+                        $crate::kassert::_warn(format_args!(
+                            "assertion failed: `{} == {}`\n  left: {:?}\n  right: {:?}",
+                            stringify!($left),
+                            stringify!($right),
+                            left,
+                            right
+                        ));
+                    }
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::klog;
+
+    #[test_case]
+    fn warn_and_continue() {
+        let start = klog::next_seq();
+        kassert!(1 + 1 == 2);
+        kassert_eq!(1 + 1, 2,);
+        kassert_warn!(1 + 1 == 3, "bad arithmetic {}", 42);
+        kassert_eq_warn!(1 + 1, 3);
+
+        let records = klog::read_since(start);
+        assert_eq!(records.len(), if cfg!(debug_assertions) { 2 } else { 0 });
+        if let Some(record) = records.first() {
+            assert!(record
+                .message()
+                .starts_with("assertion failed: 1 + 1 == 3: bad arithmetic 42"));
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Leveled kernel logging with filtering per module path.
+//!
+//! Records above [STATIC_MAX_LEVEL] are removed at compile time, the maximum level is chosen by
+//! one of the `max_level_*` cargo features. The rest are filtered at runtime by the most specific
+//! module filter set by [set_module_level], or the default level set by [set_level]. Records
+//! passing the filters are kept in a ring, see [read_since], and printed to the registered sinks,
+//! see [add_sink]. The filters can be set at boot by the `loglevel=` option on the kernel command
+//! line, see [apply_directives].
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time;
+
+pub(crate) use self::ring::Message;
+pub use self::{
+    ring::{next_seq, read_since, try_for_each_latest, Record},
+    sink::{add_sink, remove_sink, sinks, Sink, SinkError, BUILTIN, CONSOLE, SERIAL},
+};
+
+mod ring;
+mod sink;
+
+/// The level of a log record, from the most severe to the most verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// An error the kernel may not recover from.
+    Error = 1,
+    /// Something unexpected that the kernel can live with.
+    Warn,
+    /// Milestones and noteworthy events.
+    Info,
+    /// Information useful for debugging the kernel.
+    Debug,
+    /// Very verbose information tracing the execution of the kernel.
+    Trace,
+}
+
+impl Level {
+    fn from_u8(level: u8) -> Option<Level> {
+        match level {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = match s {
+            _ if s.eq_ignore_ascii_case("error") => Level::Error,
+            _ if s.eq_ignore_ascii_case("warn") => Level::Warn,
+            _ if s.eq_ignore_ascii_case("info") => Level::Info,
+            _ if s.eq_ignore_ascii_case("debug") => Level::Debug,
+            _ if s.eq_ignore_ascii_case("trace") => Level::Trace,
+            _ => return Err(ParseLevelError),
+        };
+        Ok(level)
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        // respect width and alignment of the formatter
+        f.pad(name)
+    }
+}
+
+/// Error returned on an unknown level name or a malformed filter directive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLevelError;
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected one of off, error, warn, info, debug or trace")
+    }
+}
+
+/// Parse the level of a filter, either a level name or `off`.
+pub fn parse_filter(s: &str) -> Result<Option<Level>, ParseLevelError> {
+    if s.eq_ignore_ascii_case("off") {
+        Ok(None)
+    } else {
+        s.parse().map(Some)
+    }
+}
+
+/// The most verbose level compiled into the kernel, `None` if logging is compiled out. Defaults to
+/// [Level::Trace] in debug builds and [Level::Info] in release builds.
+pub const STATIC_MAX_LEVEL: Option<Level> = if cfg!(feature = "max_level_off") {
+    None
+} else if cfg!(feature = "max_level_error") {
+    Some(Level::Error)
+} else if cfg!(feature = "max_level_warn") {
+    Some(Level::Warn)
+} else if cfg!(feature = "max_level_info") {
+    Some(Level::Info)
+} else if cfg!(feature = "max_level_debug") {
+    Some(Level::Debug)
+} else if cfg!(feature = "max_level_trace") || cfg!(debug_assertions) {
+    Some(Level::Trace)
+} else {
+    Some(Level::Info)
+};
+
+/// The runtime filters. `None` levels turn logging off.
+struct Filter {
+    default: Option<Level>,
+    /// pairs of module path and the level of that module and its submodules
+    modules: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    /// The level of the most specific filter matching `target`.
+    fn level(&self, target: &str) -> Option<Level> {
+        self.modules
+            .iter()
+            .filter(|(module, _)| is_submodule(target, module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> Option<Level> {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// Whether `target` is `module` or a submodule of `module`.
+fn is_submodule(target: &str, module: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+static FILTER: Mutex<Filter> = Mutex::new(Filter {
+    default: Some(Level::Info),
+    modules: Vec::new(),
+});
+
+/// The most verbose level of all the runtime filters, 0 if all of them are off. Checked before
+/// locking [FILTER] so that disabled records are cheap.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+fn update_filter(f: impl FnOnce(&mut Filter)) {
+    // log records may well be emitted by interrupt handlers
+    interrupts::without_interrupts(|| {
+        let mut filter = FILTER.lock();
+        f(&mut filter);
+        let max_level = filter.max_level().map_or(0, |level| level as u8);
+        MAX_LEVEL.store(max_level, Ordering::Relaxed);
+    });
+}
+
+/// Set the default level of modules without a more specific filter, `None` turns logging off.
+pub fn set_level(level: Option<Level>) {
+    update_filter(|filter| filter.default = level);
+}
+
+/// Set the level of `module` and its submodules, `None` turns logging off for them.
+pub fn set_module_level(module: &str, level: Option<Level>) {
+    update_filter(
+        |filter| match filter.modules.iter_mut().find(|(m, _)| m == module) {
+            Some((_, old)) => *old = level,
+            None => filter.modules.push((String::from(module), level)),
+        },
+    );
+}
+
+/// Remove the filter of `module` set by [set_module_level], the module then follows the filter of
+/// its parent modules.
+pub fn reset_module_level(module: &str) {
+    update_filter(|filter| filter.modules.retain(|(m, _)| m != module));
+}
+
+/// The default level and the module filters, in the order the filters were set.
+pub fn filters() -> (Option<Level>, Vec<(String, Option<Level>)>) {
+    interrupts::without_interrupts(|| {
+        let filter = FILTER.lock();
+        (filter.default, filter.modules.clone())
+    })
+}
+
+/// Apply comma separated filter directives, e.g. `info,rust_kernel::memory=trace`. A directive
+/// with only a level sets the default level, `module=level` sets the level of the module. The
+/// directives are validated before any of them is applied.
+pub fn apply_directives(directives: &str) -> Result<(), ParseLevelError> {
+    type Directive<'a> = (Option<&'a str>, Option<Level>);
+
+    fn parse(directive: &str) -> Result<Directive, ParseLevelError> {
+        match directive.split_once('=') {
+            Some((module, level)) if !module.is_empty() => Ok((Some(module), parse_filter(level)?)),
+            Some(_) => Err(ParseLevelError),
+            None => Ok((None, parse_filter(directive)?)),
+        }
+    }
+    let directives = || directives.split(',').filter(|d| !d.is_empty()).map(parse);
+
+    if let Some(err) = directives().find_map(Result::err) {
+        return Err(err);
+    }
+    for (module, level) in directives().flatten() {
+        match module {
+            Some(module) => set_module_level(module, level),
+            None => set_level(level),
+        }
+    }
+    Ok(())
+}
+
+/// Whether a record of `level` from the module `target` passes the runtime filters.
+pub fn enabled(level: Level, target: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    interrupts::without_interrupts(|| FILTER.lock().level(target) >= Some(level))
+}
+
+/// The most verbose level of all the runtime filters.
+pub fn max_level() -> Option<Level> {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, target: &'static str, args: fmt::Arguments) {
+    let timestamp = time::ticks();
+    ring::push(level, target, timestamp, args);
+    sink::dispatch(level, target, timestamp, args);
+}
+
+/// Logs a message at the given level, with the current module path as the target.
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if Some(level) <= $crate::klog::STATIC_MAX_LEVEL
+            && $crate::klog::enabled(level, module_path!())
+        {
+            $crate::klog::_log(level, module_path!(), format_args!($($arg)+));
+        }
+    }};
+}
+
+/// Logs a message at the error level.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::klog!($crate::klog::Level::Error, $($arg)+));
+}
+
+/// Logs a message at the warn level.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::klog!($crate::klog::Level::Warn, $($arg)+));
+}
+
+/// Logs a message at the info level.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::klog!($crate::klog::Level::Info, $($arg)+));
+}
+
+/// Logs a message at the debug level.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::klog!($crate::klog::Level::Debug, $($arg)+));
+}
+
+/// Logs a message at the trace level.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::klog!($crate::klog::Level::Trace, $($arg)+));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test_case]
+    fn filter_by_module() {
+        const MODULE: &str = "rust_kernel::klog::tests";
+
+        // the module filters keep their capacity after the reset
+        testing::keep_allocations(|| {
+            set_module_level("rust_kernel::klog", Some(Level::Trace));
+            set_module_level(MODULE, Some(Level::Error));
+        });
+        assert!(enabled(Level::Error, MODULE));
+        assert!(!enabled(Level::Warn, MODULE));
+        assert!(!enabled(Level::Warn, "rust_kernel::klog::tests::inner"));
+        assert!(enabled(Level::Trace, "rust_kernel::klog::testsuite"));
+        assert!(enabled(Level::Trace, "rust_kernel::klog"));
+        assert_eq!(max_level(), Some(Level::Trace));
+
+        reset_module_level(MODULE);
+        reset_module_level("rust_kernel::klog");
+        assert!(!enabled(Level::Debug, MODULE));
+        assert_eq!(max_level(), Some(Level::Info));
+    }
+
+    #[test_case]
+    fn records_printed_to_sinks() {
+        use core::sync::atomic::AtomicUsize;
+
+        struct Counter(AtomicUsize);
+
+        impl Sink for Counter {
+            fn name(&self) -> &'static str {
+                "counter"
+            }
+
+            fn log(&self, level: Level, target: &'static str, _: u64, _: fmt::Arguments) {
+                if level == Level::Warn && target == "rust_kernel::klog::tests" {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        static COUNTER: Counter = Counter(AtomicUsize::new(0));
+
+        assert_eq!(add_sink(&CONSOLE), Err(SinkError::Registered));
+        assert_eq!(add_sink(&COUNTER), Ok(()));
+        crate::warn!("counted");
+        crate::debug!("filtered out");
+        assert!(remove_sink("counter"));
+        crate::warn!("not counted");
+
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 1);
+        assert!(!remove_sink("counter"));
+        assert_eq!(sinks().first(), Some(&"console"));
+    }
+
+    #[test_case]
+    fn parse_directives() {
+        const MODULE: &str = "rust_kernel::klog::tests";
+
+        assert_eq!(apply_directives("info,=debug"), Err(ParseLevelError));
+        assert_eq!(apply_directives("loud"), Err(ParseLevelError));
+        assert_eq!(
+            testing::keep_allocations(|| apply_directives("WARN,rust_kernel::klog::tests=off")),
+            Ok(())
+        );
+        assert!(!enabled(Level::Info, "rust_kernel::memory"));
+        assert!(!enabled(Level::Error, MODULE));
+
+        reset_module_level(MODULE);
+        set_level(Some(Level::Info));
+        assert_eq!(filters(), (Some(Level::Info), Vec::new()));
+    }
+}
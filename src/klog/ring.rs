@@ -0,0 +1,206 @@
+//! A fixed-size ring of the most recent log records.
+//!
+//! Records are emitted from interrupt handlers as well, where allocating on the kernel heap may
+//! deadlock on the allocator lock. The ring is therefore a static array of fixed-size records,
+//! messages longer than [MESSAGE_SIZE] are truncated.
+
+use alloc::vec::Vec;
+use core::{fmt, str};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::Level;
+use crate::time;
+
+/// Number of records kept in the ring, the oldest record is overwritten first.
+const RING_SIZE: usize = 256;
+/// Maximum length of a message in bytes.
+const MESSAGE_SIZE: usize = 120;
+
+/// A log record kept in the ring.
+#[derive(Clone)]
+pub struct Record {
+    /// The sequence number of the record, starting from 0 on boot.
+    pub seq: u64,
+    /// Number of timer ticks since boot when the record was emitted.
+    pub timestamp: u64,
+    /// The level of the record.
+    pub level: Level,
+    /// The module path where the record was emitted.
+    pub target: &'static str,
+    message: Message<MESSAGE_SIZE>,
+}
+
+impl Record {
+    /// The message of the record, possibly truncated.
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+impl fmt::Display for Record {
+    /// The record as printed by `dmesg`: sequence number, time since boot, level, target and
+    /// message.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = time::ticks_to_duration(self.timestamp);
+        write!(
+            f,
+            "{:>6} [{:>5}.{:03}] {:<5} {}: {}",
+            self.seq,
+            time.as_secs(),
+            time.subsec_millis(),
+            self.level,
+            self.target,
+            self.message()
+        )
+    }
+}
+
+/// A message of at most `N` bytes formatted without allocation, also used by
+/// [crate::tracepoint].
+#[derive(Clone)]
+pub(crate) struct Message<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Message<N> {
+    /// An empty message.
+    pub(crate) const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    /// The message, possibly truncated.
+    pub(crate) fn as_str(&self) -> &str {
+        // only complete characters are copied into the message, see [Message::write_str]
+        str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    /// The whole buffer, zeroed past the end of the message.
+    pub(crate) fn buffer(&self) -> &[u8; N] {
+        &self.bytes
+    }
+}
+
+impl<const N: usize> fmt::Write for Message<N> {
+    /// Append as much of `s` as fits in the message, never splits a character.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(N - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+struct Ring {
+    records: [Option<Record>; RING_SIZE],
+    next_seq: u64,
+}
+
+const EMPTY: Option<Record> = None;
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    records: [EMPTY; RING_SIZE],
+    next_seq: 0,
+});
+
+/// Append a record to the ring, overwriting the oldest record if the ring is full.
+pub(super) fn push(level: Level, target: &'static str, timestamp: u64, args: fmt::Arguments) {
+    use fmt::Write;
+
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        let seq = ring.next_seq;
+        ring.next_seq += 1;
+
+        let mut record = Record {
+            seq,
+            timestamp,
+            level,
+            target,
+            message: Message::new(),
+        };
+        // formatting can't fail on a [Message], a formatting trait returning error is ignored
+        let _ = record.message.write_fmt(args);
+        ring.records[seq as usize % RING_SIZE] = Some(record);
+    });
+}
+
+/// Copy out all the records with sequence numbers no less than `seq` still in the ring, in
+/// ascending order of sequence numbers. Records overwritten before the call are lost, which is
+/// detectable by a gap between `seq` and the sequence number of the first record returned.
+pub fn read_since(seq: u64) -> Vec<Record> {
+    // collect the records with interrupts disabled but allocate outside, see the module docs
+    let mut records = Vec::with_capacity(RING_SIZE);
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let first = ring.next_seq.saturating_sub(RING_SIZE as u64).max(seq);
+        for seq in first..ring.next_seq {
+            if let Some(record) = &ring.records[seq as usize % RING_SIZE] {
+                records.push(record.clone());
+            }
+        }
+    });
+    records
+}
+
+/// Call `f` on the latest `count` records in ascending order of sequence numbers, without
+/// allocating nor waiting for the lock, for use in the panic handler. Returns `false` if the ring
+/// is locked, e.g. by the panicking code.
+pub fn try_for_each_latest(count: usize, mut f: impl FnMut(&Record)) -> bool {
+    let ring = match RING.try_lock() {
+        Some(ring) => ring,
+        None => return false,
+    };
+
+    let first = ring.next_seq.saturating_sub(count.min(RING_SIZE) as u64);
+    for seq in first..ring.next_seq {
+        if let Some(record) = &ring.records[seq as usize % RING_SIZE] {
+            f(record);
+        }
+    }
+    true
+}
+
+/// The sequence number of the next record.
+pub fn next_seq() -> u64 {
+    interrupts::without_interrupts(|| RING.lock().next_seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ring_wraps_around() {
+        let start = next_seq();
+        for i in 0..RING_SIZE + 10 {
+            push(Level::Info, "test", 0, format_args!("record {}", i));
+        }
+
+        let records = read_since(start);
+        assert_eq!(records.len(), RING_SIZE);
+        assert_eq!(records[0].seq, start + 10);
+        assert_eq!(records[0].message(), "record 10");
+        assert_eq!(records.last().unwrap().seq, start + RING_SIZE as u64 + 9);
+    }
+
+    #[test_case]
+    fn truncate_long_message() {
+        let start = next_seq();
+        // multi-byte characters must not be split by truncation
+        push(Level::Info, "test", 0, format_args!("{:é>200}", ""));
+
+        let records = read_since(start);
+        assert_eq!(records[0].message().len(), MESSAGE_SIZE);
+        assert!(records[0].message().chars().all(|c| c == 'é'));
+    }
+}
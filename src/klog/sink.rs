@@ -0,0 +1,144 @@
+//! The destinations where log records passing the filters are printed.
+//!
+//! Every record is kept in the ring of [super::ring] for `dmesg`, sinks are registered on top of it
+//! with [add_sink]. The kernel starts with [CONSOLE] alone, [SERIAL] mirrors the records to the
+//! host. Sinks are called with interrupts disabled and the table locked, so they must neither
+//! block nor log themselves.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::Level;
+use crate::{serial, time, vga_buffer};
+
+/// The maximum number of sinks registered at the same time.
+const MAX_SINKS: usize = 8;
+
+/// A destination of log records.
+pub trait Sink: Sync {
+    /// The name of the sink, unique among the registered sinks.
+    fn name(&self) -> &'static str;
+
+    /// Print a record of `level` emitted by the module `target` at `timestamp` ticks since boot.
+    fn log(&self, level: Level, target: &'static str, timestamp: u64, args: fmt::Arguments);
+}
+
+/// Errors of [add_sink].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkError {
+    /// A sink of the same name is already registered.
+    Registered,
+    /// [MAX_SINKS] sinks are already registered.
+    Full,
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Registered => write!(f, "sink already registered"),
+            SinkError::Full => write!(f, "too many sinks, at most {}", MAX_SINKS),
+        }
+    }
+}
+
+/// Prints records to the VGA text buffer.
+pub struct Console;
+
+impl Sink for Console {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn log(&self, level: Level, target: &'static str, _: u64, args: fmt::Arguments) {
+        vga_buffer::_print(format_args!("[{:<5} {}] {}\n", level, target, args));
+    }
+}
+
+/// Prints records with their time since boot to the first serial port.
+pub struct Serial;
+
+impl Sink for Serial {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn log(&self, level: Level, target: &'static str, timestamp: u64, args: fmt::Arguments) {
+        let time = time::ticks_to_duration(timestamp);
+        serial::_print(format_args!(
+            "[{:>5}.{:03} {:<5} {}] {}\n",
+            time.as_secs(),
+            time.subsec_millis(),
+            level,
+            target,
+            args
+        ));
+    }
+}
+
+/// The VGA text buffer, registered on boot.
+pub static CONSOLE: Console = Console;
+/// The first serial port.
+pub static SERIAL: Serial = Serial;
+
+/// The sinks built into the kernel, which the shell may register by name.
+pub static BUILTIN: [&'static dyn Sink; 2] = [&CONSOLE, &SERIAL];
+
+const EMPTY: Option<&'static dyn Sink> = None;
+
+static SINKS: Mutex<[Option<&'static dyn Sink>; MAX_SINKS]> = Mutex::new({
+    let mut sinks = [EMPTY; MAX_SINKS];
+    sinks[0] = Some(&CONSOLE);
+    sinks
+});
+
+/// Register `sink`, records are printed to the sinks in the order of registration.
+pub fn add_sink(sink: &'static dyn Sink) -> Result<(), SinkError> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        if sinks.iter().flatten().any(|s| s.name() == sink.name()) {
+            return Err(SinkError::Registered);
+        }
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(SinkError::Full)?;
+        *slot = Some(sink);
+        Ok(())
+    })
+}
+
+/// Unregister the sink named `name`, returns whether it was registered.
+pub fn remove_sink(name: &str) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let index = match sinks.iter().flatten().position(|s| s.name() == name) {
+            Some(index) => index,
+            None => return false,
+        };
+        // keep the order of registration
+        sinks[index..].rotate_left(1);
+        sinks[MAX_SINKS - 1] = None;
+        true
+    })
+}
+
+/// The names of the registered sinks, in the order of registration.
+pub fn sinks() -> Vec<&'static str> {
+    let mut names = Vec::with_capacity(MAX_SINKS);
+    interrupts::without_interrupts(|| {
+        names.extend(SINKS.lock().iter().flatten().map(|sink| sink.name()));
+    });
+    names
+}
+
+/// Print a record to all the registered sinks.
+pub(super) fn dispatch(level: Level, target: &'static str, timestamp: u64, args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        for sink in SINKS.lock().iter().flatten() {
+            sink.log(level, target, timestamp, args);
+        }
+    });
+}
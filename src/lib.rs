@@ -5,6 +5,8 @@
 #![feature(alloc_error_handler)]
 #![feature(abi_x86_interrupt)]
 #![feature(const_mut_refs)]
+#![feature(asm)]
+#![feature(global_asm)]
 #![cfg_attr(test, no_main)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -13,15 +15,58 @@
 
 extern crate alloc;
 
+/// Timing of the boot milestones.
+pub mod boot;
+
+/// Ownership of the I/O ports by the drivers.
+pub mod io;
+
 /// A safe global interface to print text to stdout of QEMU process in form of print macros.
 pub mod serial;
 
 /// A safe global interface to the VGA text buffer in form of print macros.
 pub mod vga_buffer;
 
+/// A text console on a pixel framebuffer, where the print macros go instead of the VGA text buffer
+/// when enabled.
+pub mod framebuffer;
+
+/// Options on the kernel command line.
+pub mod cmdline;
+
+/// Leveled kernel logging in form of macros, filtered per module path.
+pub mod klog;
+
+/// Assertions reporting the kernel context in form of macros.
+pub mod kassert;
+
+/// Named counters, gauges and histograms registered by the subsystems.
+pub mod metrics;
+
+/// Lock-free event tracing in form of macros, enabled per subsystem.
+pub mod tracepoint;
+
 /// Definition and initialization of interruption handlers.
 pub mod interrupts;
 
+/// Structured crash reports written to the serial port on panic.
+pub mod crash;
+
+/// State kept across reboots in the CMOS.
+pub mod persist;
+
+/// Rebooting and powering off the machine.
+pub mod power;
+
+/// Time keeping by the timer interrupt.
+pub mod time;
+
+/// Tones on the PC speaker.
+pub mod speaker;
+
+/// The PS/2 controller shared by the keyboard and the mouse.
+pub mod i8042;
+
 /// Definition and initialization of the Global Descriptor Table.
 pub mod gdt;
 
@@ -29,19 +74,78 @@ pub mod gdt;
 /// addresses to physical addresses.
 pub mod memory;
 
+/// Physically contiguous memory shared with devices.
+pub mod dma;
+
+/// Lookup of the ACPI tables provided by the firmware.
+pub mod acpi;
+
+/// The local APIC and its timer.
+pub mod apic;
+/// The I/O APICs routing the external interrupts.
+pub mod ioapic;
+/// Preemptive kernel threads scheduled on the timer tick.
+pub mod scheduler;
+/// Startup of the application processors.
+pub mod smp;
+
+/// Enumeration of the devices on the PCI bus.
+pub mod pci;
+
+/// Drivers of virtio devices.
+pub mod virtio;
+
+/// Block devices and their drivers.
+pub mod block;
+
+/// The filesystems mounted in a single tree of paths.
+pub mod fs;
+
+/// Network interfaces and the drivers of the cards without virtio.
+pub mod net;
+
+/// The kernel entropy pool.
+pub mod entropy;
+
+/// Unpredictable random numbers.
+pub mod random;
+
 pub(crate) mod locked;
 
+pub(crate) mod resume;
+
+/// Stack backtraces for the panic handler and exception handlers.
+pub mod unwind;
+
 /// A global allocator for the kernel.
 pub mod allocator;
 
+/// Fault injection for tests.
+pub mod fault;
+
 /// Bare minimum code to bootstrap asynchronous tasks as required by Rust standard library.
 pub mod task;
 
+/// Running programs in ring 3, each in its own address space.
+pub mod user;
+
+/// The processes, programs in ring 3 scheduled alongside the tasks.
+pub mod process;
+
+/// An interactive shell with commands registered by other modules.
+pub mod shell;
+
+/// The custom test framework with per-test timeouts.
+pub mod testing;
+
+pub use testing::{test_panic_handler, test_runner, Bench, ShouldPanic, Testable};
+
 #[cfg(test)]
 use bootloader::entry_point;
 use bootloader::BootInfo;
 use x86_64::VirtAddr;
 
+#[cfg(test)]
 use core::panic::PanicInfo;
 
 use memory::BootInfoFrameAllocator;
@@ -54,14 +158,39 @@ fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
-/// Exit code feed to the isa-debug-exit device of QEMU.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Exit code feed to the isa-debug-exit device of QEMU. Failures are ordered by severity, a test
+/// run exits with its most severe failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u32)]
 pub enum QemuExitCode {
     /// Exit code on successful test runs. Maps to an exit status 0x21 in host system.
     Success = 0x10,
-    /// Exit code on failed test runs. Maps to an exit status 0x23 in host system.
+    /// A test failed an assertion or panicked. Maps to an exit status 0x23 in host system.
     Failed = 0x11,
+    /// The kernel panicked outside of any test. Maps to an exit status 0x25 in host system.
+    Panic = 0x12,
+    /// A test was still running past its deadline. Maps to an exit status 0x27 in host system.
+    Timeout = 0x13,
+    /// A test left the kernel heap corrupted. Maps to an exit status 0x29 in host system.
+    HeapCorrupted = 0x14,
+}
+
+impl QemuExitCode {
+    /// The name of the outcome in the result trailer of the test runner, see [testing].
+    pub fn name(self) -> &'static str {
+        match self {
+            QemuExitCode::Success => "ok",
+            QemuExitCode::Failed => "failed",
+            QemuExitCode::Panic => "panic",
+            QemuExitCode::Timeout => "timeout",
+            QemuExitCode::HeapCorrupted => "heap_corrupted",
+        }
+    }
+
+    /// The exit status of the QEMU process in the host system.
+    pub fn exit_status(self) -> u32 {
+        (self as u32) << 1 | 1
+    }
 }
 
 /// Write the supplied exit code to the QEMU isa-debug-exit device. The QEMU process will exit (in
@@ -88,13 +217,16 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
 /// Initialize the following components of the kernel:
 /// - interruption handlers
 pub fn init(boot_info: &'static BootInfo) {
+    boot::milestone("start");
     gdt::init();
+    boot::milestone("gdt");
     // # Safety
     // GDT is initialized before this call.
     unsafe {
         interrupts::init_idt();
     }
-    interrupts::init_pics();
+    user::syscall::init();
+    boot::milestone("idt");
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     // # Safety
@@ -104,8 +236,107 @@ pub fn init(boot_info: &'static BootInfo) {
     // # Safety
     // The memory map is valid per bootloader.
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    smp::reserve_trampoline(&mut frame_allocator);
+    boot::milestone("paging");
+
+    // the HPET registers are mapped as part of the time initialization
+    let config = cmdline::config();
+    time::init(
+        config.tick_source,
+        config.clocksource,
+        &mut mapper,
+        &mut frame_allocator,
+    );
+    boot::milestone("time");
+    if config.ioapic && !ioapic::init(&mut mapper, &mut frame_allocator) {
+        warn!("no I/O APIC, external interrupts are delivered by the PICs");
+    }
+    if let Err(err) = i8042::init() {
+        warn!("PS/2 controller: {}", err);
+    }
+    i8042::set_handler(i8042::Port::First, task::keyboard::add_scancode);
+    if i8042::has_second_port() {
+        if let Err(err) = task::mouse::init() {
+            warn!("PS/2 mouse: {}", err);
+        }
+    }
+    task::keyboard::set_layout(config.keymap);
+    if config.ctrlkeys {
+        task::keyboard::set_control_handling(pc_keyboard::HandleControl::MapLettersToUnicode);
+    }
+    boot::milestone("i8042");
+    interrupts::init_pics();
+    boot::milestone("pic");
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator, config.heap_size)
+        .expect("heap initialization failed");
+    vga_buffer::init_scrollback();
+    boot::milestone("heap");
+    dma::init(&mut frame_allocator).expect("DMA pool initialization failed");
+    boot::milestone("dma");
+    smp::init(config.smp, &mut mapper, &mut frame_allocator);
+    boot::milestone("smp");
+    scheduler::init();
+    boot::milestone("scheduler");
+
+    pci::init();
+    boot::milestone("pci");
+    virtio::net::init();
+    net::e1000::init(&mut mapper, &mut frame_allocator);
+    net::loopback::init();
+    virtio::rng::init();
+    virtio::gpu::init(&mut mapper, &mut frame_allocator);
+    if config.framebuffer {
+        if let Err(err) = framebuffer::init() {
+            warn!("framebuffer console: {}", err);
+        }
+    }
+    boot::milestone("virtio");
+    block::ata::init();
+    block::ahci::init(&mut mapper, &mut frame_allocator);
+    boot::milestone("block");
+    // the pages mapped from now on are mapped on demand, e.g. of memory-mapped files
+    memory::install(mapper, frame_allocator);
+    fs::devfs::init();
+    fs::initrd::init();
+    fs::ramfs::init();
+    boot::milestone("fs");
+    entropy::init();
+    boot::milestone("entropy");
+
+    for option in config.errors() {
+        warn!("invalid option on the command line: {}", option);
+    }
+    // module filters are kept on the heap
+    if let Some(directives) = config.loglevel {
+        if let Err(err) = klog::apply_directives(directives) {
+            warn!("invalid loglevel on the command line: {}", err);
+        }
+    }
+    boot::milestone("cmdline");
+
+    info!(
+        "wall clock {}, timer tick from {}, interrupts from {}, clock source {}, TSC at {} kHz",
+        time::now(),
+        time::tick_source().name(),
+        interrupts::controller_name(),
+        time::clocksource::current().name(),
+        time::tsc::frequency_hz() / 1000
+    );
+    if !time::tsc::is_invariant() {
+        warn!("the TSC is not invariant, cycle counts may be off");
+    }
+    info!("boot timing:\n{}", boot::Report);
+}
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+/// Forcibly release the locks of the VGA text buffer and the serial port, so that a panic handler
+/// can still print when the panicking code holds them, e.g. on a detected deadlock.
+///
+/// # Safety
+/// Must only be called by panic handlers, the code holding the locks must never return.
+pub unsafe fn force_unlock_outputs() {
+    vga_buffer::WRITER.force_unlock();
+    serial::SERIAL1.force_unlock();
 }
 
 /// Put the CPU in a hlt loop, allow the CPU to enter a sleep state until an interrupt arrives and
@@ -133,38 +364,6 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
-/// The test panic handler. Output panic info to both VGA text buffer in QEMU and host system then
-/// terminate QEMU process.
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-}
-
-/// The sequential test runner. Currently global states are not reset between tests.
-pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-
-    exit_qemu(QemuExitCode::Success);
-}
-
-/// A helper trait that prints test results to the host system.
-pub trait Testable {
-    /// Run the test, print test name and result to the host system.
-    fn run(&self);
-}
-
-impl<T: Fn()> Testable for T {
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
-    }
-}
-
 #[cfg(test)]
 pub mod tests {
     #[test_case]
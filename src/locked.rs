@@ -1,6 +1,45 @@
-/// A wrapper asround [spin::Mutex] to circumvent impl restrictions of Rust.
+//! A spin lock with diagnostics for lock-related hangs.
+//!
+//! Besides guarding the data, [Locked] records the source location of its current owner, counts
+//! acquisitions and contentions and measures how long it's held in time stamp counter cycles. A
+//! lock spinning for longer than [DEADLOCK_CYCLES] is almost certainly a deadlock, e.g. printing
+//! from an interrupt handler which interrupted a print, and panics with the locations of both the
+//! owner and the waiter.
+
+use core::{
+    ops::{Deref, DerefMut},
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use crate::time::tsc::rdtsc;
+
+/// Number of cycles a lock may spin for before it's considered a deadlock, a few seconds on any
+/// processor fast enough to run the kernel.
+pub const DEADLOCK_CYCLES: u64 = 1 << 33;
+
+/// Statistics of a [Locked] since its creation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockStats {
+    /// Number of times the lock has been acquired.
+    pub acquisitions: u64,
+    /// Number of acquisitions which found the lock held and had to spin.
+    pub contentions: u64,
+    /// The longest time the lock has been held in cycles.
+    pub max_hold_cycles: u64,
+}
+
+/// A wrapper around [spin::Mutex] to circumvent impl restrictions of Rust, with diagnostics of
+/// deadlocks and hold time.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
+    /// the location where the current owner acquired the lock, null if the lock is free
+    owner: AtomicPtr<Location<'static>>,
+    acquired_at: AtomicU64,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    max_hold_cycles: AtomicU64,
 }
 
 impl<A> Locked<A> {
@@ -8,11 +47,146 @@ impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
             inner: spin::Mutex::new(inner),
+            owner: AtomicPtr::new(ptr::null_mut()),
+            acquired_at: AtomicU64::new(0),
+            acquisitions: AtomicU64::new(0),
+            contentions: AtomicU64::new(0),
+            max_hold_cycles: AtomicU64::new(0),
         }
     }
 
     /// Locks the [Locked] and returns a guard that permits mutable access to the inner data.
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    ///
+    /// # Panics
+    /// Panics if the lock can't be acquired in [DEADLOCK_CYCLES].
+    #[track_caller]
+    pub fn lock(&self) -> LockedGuard<A> {
+        let caller = Location::caller();
+        if let Some(guard) = self.try_lock_at(caller) {
+            return guard;
+        }
+
+        self.contentions.fetch_add(1, Ordering::Relaxed);
+        let start = rdtsc();
+        loop {
+            if let Some(guard) = self.try_lock_at(caller) {
+                return guard;
+            }
+
+            let spun = rdtsc().wrapping_sub(start);
+            if spun > DEADLOCK_CYCLES {
+                let held = rdtsc().wrapping_sub(self.acquired_at.load(Ordering::Relaxed));
+                match self.owner() {
+                    Some(owner) => panic!(
+                        "deadlock: lock wanted at {} is held by {} for {} cycles",
+                        caller, owner, held
+                    ),
+                    None => panic!("deadlock: lock wanted at {} is never released", caller),
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Try to lock the [Locked] without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<LockedGuard<A>> {
+        self.try_lock_at(Location::caller())
+    }
+
+    fn try_lock_at(&self, caller: &'static Location<'static>) -> Option<LockedGuard<A>> {
+        let guard = self.inner.try_lock()?;
+        let acquired_at = rdtsc();
+        self.owner
+            .store(caller as *const _ as *mut _, Ordering::Relaxed);
+        self.acquired_at.store(acquired_at, Ordering::Relaxed);
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        Some(LockedGuard {
+            lock: self,
+            guard,
+            acquired_at,
+        })
+    }
+
+    /// The location where the current owner acquired the lock, `None` if the lock is free.
+    pub fn owner(&self) -> Option<&'static Location<'static>> {
+        let owner = self.owner.load(Ordering::Relaxed);
+        // # Safety
+        // `owner` is either null or stored from a `&'static Location` by [Locked::try_lock_at].
+        unsafe { owner.as_ref() }
+    }
+
+    /// Statistics of the lock since its creation.
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contentions: self.contentions.load(Ordering::Relaxed),
+            max_hold_cycles: self.max_hold_cycles.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forcibly unlock the [Locked], e.g. in the panic handler where the owner never returns.
+    ///
+    /// # Safety
+    /// The owner of the lock must never touch the data again.
+    pub unsafe fn force_unlock(&self) {
+        self.owner.store(ptr::null_mut(), Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+}
+
+/// A guard of [Locked] giving access to the data, records the hold time on release.
+pub struct LockedGuard<'a, A> {
+    lock: &'a Locked<A>,
+    guard: spin::MutexGuard<'a, A>,
+    acquired_at: u64,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        // nothing may be logged here, the lock may well be the lock of the log output
+        let held = rdtsc().wrapping_sub(self.acquired_at);
+        self.lock.max_hold_cycles.fetch_max(held, Ordering::Relaxed);
+        // the inner guard is dropped after this, the lock is still held
+        self.lock.owner.store(ptr::null_mut(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn record_owner_and_stats() {
+        let lock = Locked::new(0);
+        assert!(lock.owner().is_none());
+
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert_eq!(lock.owner().map(Location::file), Some(file!()));
+            assert!(lock.try_lock().is_none());
+        }
+
+        assert!(lock.owner().is_none());
+        assert_eq!(*lock.lock(), 1);
+        let stats = lock.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.contentions, 0);
     }
 }
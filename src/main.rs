@@ -13,14 +13,25 @@ use core::panic::PanicInfo;
 
 use bootloader::{entry_point, BootInfo};
 use rust_kernel::println;
-use rust_kernel::task::keyboard;
-use rust_kernel::task::Task;
-use rust_kernel::{hlt_loop, init, task};
+use rust_kernel::task::{watchdog, Priority, Task};
+use rust_kernel::{cmdline, crash, hlt_loop, init, net, process, shell, smp, task, unwind};
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // the first processor to panic reports it, the others stop where they are
+    if !smp::claim_panic() {
+        smp::park();
+    }
+    smp::halt_others();
+    // # Safety
+    // This is a panic handler, the panicking code never returns.
+    unsafe {
+        rust_kernel::force_unlock_outputs();
+    }
     println!("{}", info);
+    println!("{}", unwind::backtrace());
+    crash::report(info);
     rust_kernel::hlt_loop();
 }
 
@@ -47,7 +58,24 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("It didn't crash!");
 
     let mut executor = task::executor::Executor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    let console = cmdline::config().console;
+    if console.vga() {
+        executor.spawn(Task::named("shell-vga", shell::run_vga()).with_priority(Priority::High));
+        if task::mouse::is_enabled() {
+            executor.spawn(Task::named("mouse", task::mouse::track_pointer()));
+        }
+    }
+    if console.serial() {
+        executor
+            .spawn(Task::named("shell-serial", shell::run_serial()).with_priority(Priority::High));
+    }
+    for interface in net::interfaces() {
+        executor.spawn(Task::named(interface.name(), net::receive_task(interface)));
+    }
+    executor.spawn(Task::named("tcp-timer", net::tcp::timer_task()));
+    executor.spawn(Task::named("httpd", net::http::run()));
+    executor.spawn(Task::named("processes", process::run()));
+    watchdog::arm(watchdog::DEFAULT_TIMEOUT_SECS);
     executor.run();
 
     hlt_loop();
@@ -1,10 +1,25 @@
+use alloc::vec::Vec;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+use crate::{debug, fault, warn};
+
+/// The virtual address where the complete physical memory is mapped, set by [init].
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// # Safety
@@ -27,14 +42,327 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 /// mapped to virtual memory at the passed `physical_memory_offset`. Also, this function must be
 /// only called once to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET
+        .try_init_once(|| physical_memory_offset)
+        .expect("memory::init should only be called once");
+    debug!("physical memory mapped at {:?}", physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// The page tables and the frame allocator of the kernel once the mappings of the boot are made,
+/// see [install].
+static PAGING: Mutex<Option<Paging>> = Mutex::new(None);
+
+/// The active page tables with the allocator of the frames they map.
+pub struct Paging {
+    /// The active page tables.
+    pub mapper: OffsetPageTable<'static>,
+    /// The allocator of the physical frames.
+    pub frames: BootInfoFrameAllocator,
+}
+
+/// Hand the page tables and the frame allocator over for the mappings made after the boot, e.g.
+/// the pages of memory-mapped files mapped on page faults.
+pub fn install(mapper: OffsetPageTable<'static>, frames: BootInfoFrameAllocator) {
+    *PAGING.lock() = Some(Paging { mapper, frames });
+}
+
+/// Run `f` on the page tables and the frame allocator, returns `None` before [install].
+pub fn with_paging<R>(f: impl FnOnce(&mut Paging) -> R) -> Option<R> {
+    PAGING.lock().as_mut().map(f)
+}
+
+/// A contiguous range of virtual memory mapped to a contiguous range of physical memory with the
+/// same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// Start of the virtual memory range.
+    pub virt_start: VirtAddr,
+    /// Start of the physical memory range.
+    pub phys_start: PhysAddr,
+    /// Size of the range in bytes.
+    pub size: u64,
+    /// Flags of the page table entries mapping the range, accessed and dirty bits are ignored.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// The last byte of the virtual memory range. The range may end at the top of the address
+    /// space, where one byte beyond the range is no longer a valid address.
+    pub fn virt_last(&self) -> VirtAddr {
+        self.virt_start + (self.size - 1)
+    }
+
+    /// Extend the mapping by `next` if `next` immediately follows it both in virtual and physical
+    /// memory with the same flags. Returns whether the mapping is extended.
+    fn try_merge(&mut self, next: &Mapping) -> bool {
+        let mergeable = self.virt_last().as_u64().wrapping_add(1) == next.virt_start.as_u64()
+            && self.phys_start + self.size == next.phys_start
+            && self.flags == next.flags;
+
+        if mergeable {
+            self.size += next.size;
+        }
+        mergeable
+    }
+}
+
+/// Walk the active page tables, return all the present mappings in ascending order of virtual
+/// addresses. Adjacent mappings are merged where possible.
+pub fn mappings() -> Vec<Mapping> {
+    let offset = physical_memory_offset();
+    let (level_4_table, _) = Cr3::read();
+    let mut mappings = Vec::new();
+    // # Safety
+    // The complete physical memory is mapped at `offset` per safety requirements of [init], the
+    // page tables are only read.
+    unsafe {
+        walk_table(offset, level_4_table.start_address(), 4, 0, &mut mappings);
+    }
+    mappings
+}
+
+/// Translate `addr` to a physical address by walking the active page tables. Returns the physical
+/// address and the flags of the page table entry mapping the page, the writable and the user
+/// accessible flags are only set if they're set on all levels of page tables. Returns `None` if
+/// `addr` is not mapped.
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    if fault::page_fault_at(addr) {
+        return None;
+    }
+
+    let offset = physical_memory_offset();
+    let (level_4_table, _) = Cr3::read();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table_addr = level_4_table.start_address();
+    let mut writable = true;
+    let mut user = true;
+    for (level, &index) in (1..=4).rev().zip(indices.iter()) {
+        // # Safety
+        // The complete physical memory is mapped at `offset` per safety requirements of [init],
+        // `table_addr` is either read from CR3 or from a present non-leaf page table entry.
+        let table = unsafe { &*(offset + table_addr.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        let mut flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        writable &= flags.contains(PageTableFlags::WRITABLE);
+        user &= flags.contains(PageTableFlags::USER_ACCESSIBLE);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1u64 << (12 + 9 * (level - 1));
+            let phys = entry.addr() + (addr.as_u64() & (page_size - 1));
+            flags.set(PageTableFlags::WRITABLE, writable);
+            flags.set(PageTableFlags::USER_ACCESSIBLE, user);
+            return Some((phys, flags));
+        }
+
+        table_addr = entry.addr();
+    }
+
+    unreachable!("level 1 page table entries always map pages")
+}
+
+/// Check that every byte in the `len`-byte range starting at `start` is mapped, and writable if
+/// `writable` is set.
+pub fn is_mapped(start: VirtAddr, len: u64, writable: bool) -> bool {
+    let required = if writable {
+        PageTableFlags::WRITABLE
+    } else {
+        PageTableFlags::empty()
+    };
+    is_mapped_with(start, len, required)
+}
+
+/// The flags of the pages mapped for the programs in ring 3, writable if `writable` is set.
+pub fn user_flags(writable: bool) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    flags
+}
+
+/// Check that every byte in the `len`-byte range starting at `start` is mapped for ring 3, with the
+/// [user_flags] of `writable`.
+pub fn is_user_mapped(start: VirtAddr, len: u64, writable: bool) -> bool {
+    is_mapped_with(start, len, user_flags(writable))
+}
+
+/// Check that every byte in the `len`-byte range starting at `start` is mapped with all the
+/// `required` flags, as returned by [translate].
+fn is_mapped_with(start: VirtAddr, len: u64, required: PageTableFlags) -> bool {
+    if len == 0 {
+        return true;
+    }
+
+    let last = match start.as_u64().checked_add(len - 1) {
+        Some(last) => last,
+        None => return false,
+    };
+    let last = match VirtAddr::try_new(last) {
+        // the range must not span the non-canonical hole in the middle of the address space
+        Ok(last) if last.as_u64() >> 47 == start.as_u64() >> 47 => last,
+        _ => return false,
+    };
+
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(last),
+    );
+    for page in pages {
+        match translate(page.start_address()) {
+            Some((_, flags)) if flags.contains(required) => (),
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// The virtual address of `addr` in the mapping of the complete physical memory.
+///
+/// # Panics
+/// Panics if [init] hasn't been called.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Allocate `count` physically contiguous frames from `frame_allocator`, returns the address of
+/// the first one, `None` if the allocator runs out first.
+pub fn alloc_contiguous(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    count: u64,
+) -> Option<PhysAddr> {
+    let mut allocate = || {
+        frame_allocator
+            .allocate_frame()
+            .map(PhysFrame::start_address)
+    };
+
+    // frames are handed out in ascending order within each usable region, a run is only broken
+    // at the end of a region, the frames before the break are leaked
+    let mut start = allocate()?;
+    let mut allocated = 1;
+    while allocated < count {
+        let frame = allocate()?;
+        if frame == start + allocated * 4096 {
+            allocated += 1;
+        } else {
+            start = frame;
+            allocated = 1;
+        }
+    }
+    Some(start)
+}
+
+/// Start of the virtual memory region where device registers are mapped by [map_mmio].
+pub const MMIO_START: u64 = 0x5555_5555_0000;
+
+/// The next free virtual address in the MMIO region.
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Map `size` bytes of device registers at `phys` to a fresh range of uncached virtual memory,
+/// returns the virtual address of `phys`. Mappings are never removed.
+///
+/// # Safety
+/// `phys` must be a range of device registers, not memory that may be handed out by the frame
+/// allocator.
+pub unsafe fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys: PhysAddr,
+    size: u64,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first, last);
+    let len = (last.start_address() - first.start_address()) + 4096;
+    let virt_start = VirtAddr::new(MMIO_NEXT.fetch_add(len, Ordering::Relaxed));
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    for (i, frame) in frames.enumerate() {
+        let page = Page::containing_address(virt_start + i as u64 * 4096);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+
+    Ok(virt_start + (phys - first.start_address()))
+}
+
+/// Whether [init] has been called, the page tables can't be walked before that.
+pub fn is_initialized() -> bool {
+    PHYSICAL_MEMORY_OFFSET.is_initialized()
+}
+
+fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory::init must be called before walking page tables")
+}
+
+/// Walk the page table at physical address `table` on `level`, which maps virtual memory starting
+/// from `base` (without sign extension), append the found mappings to `mappings`.
+///
+/// # Safety
+/// The complete physical memory must be mapped at `offset`, `table` must point to a valid page
+/// table on `level`.
+unsafe fn walk_table(
+    offset: VirtAddr,
+    table: PhysAddr,
+    level: u8,
+    base: u64,
+    mappings: &mut Vec<Mapping>,
+) {
+    let table = &*(offset + table.as_u64()).as_ptr::<PageTable>();
+    // each level of page table resolves 9 bits of the virtual address on top of the 12 bits of
+    // page offset
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let virt = base + i as u64 * entry_size;
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let mapping = Mapping {
+                virt_start: VirtAddr::new_truncate(virt),
+                phys_start: entry.addr(),
+                size: entry_size,
+                flags: flags - ignored,
+            };
+
+            let merged = match mappings.last_mut() {
+                Some(last) => last.try_merge(&mapping),
+                None => false,
+            };
+            if !merged {
+                mappings.push(mapping);
+            }
+        } else {
+            walk_table(offset, entry.addr(), level - 1, virt, mappings);
+        }
+    }
+}
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map. Deallocated
+/// frames are kept in a list threaded through the frames themselves and handed out first.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// The last deallocated frame, its first 8 bytes hold the physical address of the next one or
+    /// 0 at the end of the list, the frame at 0 is never usable.
+    free: Option<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -48,6 +376,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: None,
         }
     }
 
@@ -66,8 +395,35 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free {
+            // # Safety
+            // The frame was deallocated, nothing else uses it, it's accessed through the mapping
+            // of the complete physical memory.
+            let next = unsafe { ptr::read(phys_to_virt(frame.start_address()).as_ptr::<u64>()) };
+            self.free = if next == 0 {
+                None
+            } else {
+                Some(PhysFrame::containing_address(PhysAddr::new(next)))
+            };
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if frame.is_none() {
+            warn!("out of usable physical frames");
+        }
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self.free.map_or(0, |free| free.start_address().as_u64());
+        ptr::write(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u64>(),
+            next,
+        );
+        self.free = Some(frame);
+    }
+}
@@ -0,0 +1,288 @@
+//! A registry of named kernel metrics: counters, gauges and histograms.
+//!
+//! Metrics are declared as statics by the subsystems owning them and register themselves on their
+//! first update, so that updating a metric is safe anywhere including interrupt handlers:
+//!
+//! ```ignore
+//! static DROPPED: Counter = Counter::new("keyboard.dropped_scancodes");
+//! DROPPED.inc();
+//! ```
+//!
+//! The registry is an intrusive lock-free list threaded through the statics, it never allocates.
+//! [snapshot] copies out the values of all registered metrics.
+
+use alloc::vec::Vec;
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
+
+/// Number of buckets of a histogram. Bucket 0 counts the value 0, bucket `i` counts values in
+/// `[2^(i-1), 2^i)`, the last bucket also counts all the larger values.
+pub const HISTOGRAM_BUCKETS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// The common part of all metrics, also a node of the registry.
+struct Metric {
+    name: &'static str,
+    kind: Kind,
+    /// value of a counter, value of a gauge as `i64`, or sum of the samples of a histogram
+    value: AtomicU64,
+    /// number of samples of a histogram
+    count: AtomicU64,
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    registered: AtomicBool,
+    next: AtomicPtr<Metric>,
+}
+
+/// The head of the registry.
+static REGISTRY: AtomicPtr<Metric> = AtomicPtr::new(ptr::null_mut());
+
+impl Metric {
+    const fn new(name: &'static str, kind: Kind) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            name,
+            kind,
+            value: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            buckets: [ZERO; HISTOGRAM_BUCKETS],
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn register(&'static self) {
+        // cheap check on every update, the swap only happens once
+        if self.registered.load(Ordering::Relaxed) || self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let node = self as *const Metric as *mut Metric;
+        let mut head = REGISTRY.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match REGISTRY.compare_exchange_weak(head, node, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn sample(&self) -> Sample {
+        let value = match self.kind {
+            Kind::Counter => Value::Counter(self.value.load(Ordering::Relaxed)),
+            Kind::Gauge => Value::Gauge(self.value.load(Ordering::Relaxed) as i64),
+            Kind::Histogram => {
+                let mut buckets = [0; HISTOGRAM_BUCKETS];
+                for (bucket, count) in buckets.iter_mut().zip(self.buckets.iter()) {
+                    *bucket = count.load(Ordering::Relaxed);
+                }
+                Value::Histogram {
+                    count: self.count.load(Ordering::Relaxed),
+                    sum: self.value.load(Ordering::Relaxed),
+                    buckets,
+                }
+            }
+        };
+
+        Sample {
+            name: self.name,
+            value,
+        }
+    }
+}
+
+/// A monotonically increasing counter.
+pub struct Counter(Metric);
+
+impl Counter {
+    /// Create a counter named `name`, by convention `<subsystem>.<metric>`.
+    pub const fn new(name: &'static str) -> Self {
+        Self(Metric::new(name, Kind::Counter))
+    }
+
+    /// Increase the counter by one.
+    pub fn inc(&'static self) {
+        self.add(1);
+    }
+
+    /// Increase the counter by `n`.
+    pub fn add(&'static self, n: u64) {
+        self.0.register();
+        self.0.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The current value of the counter.
+    pub fn get(&self) -> u64 {
+        self.0.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.
+pub struct Gauge(Metric);
+
+impl Gauge {
+    /// Create a gauge named `name`, by convention `<subsystem>.<metric>`.
+    pub const fn new(name: &'static str) -> Self {
+        Self(Metric::new(name, Kind::Gauge))
+    }
+
+    /// Set the gauge to `value`.
+    pub fn set(&'static self, value: i64) {
+        self.0.register();
+        self.0.value.store(value as u64, Ordering::Relaxed);
+    }
+
+    /// Add `delta` to the gauge, which may be negative.
+    pub fn add(&'static self, delta: i64) {
+        self.0.register();
+        // two's complement addition is the same for signed and unsigned integers
+        self.0.value.fetch_add(delta as u64, Ordering::Relaxed);
+    }
+
+    /// The current value of the gauge.
+    pub fn get(&self) -> i64 {
+        self.0.value.load(Ordering::Relaxed) as i64
+    }
+}
+
+/// A distribution of samples in buckets of powers of 2.
+pub struct Histogram(Metric);
+
+impl Histogram {
+    /// Create a histogram named `name`, by convention `<subsystem>.<metric>`.
+    pub const fn new(name: &'static str) -> Self {
+        Self(Metric::new(name, Kind::Histogram))
+    }
+
+    /// Record a sample.
+    pub fn record(&'static self, sample: u64) {
+        self.0.register();
+        let bucket = (64 - sample.leading_zeros() as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.0.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.0.count.fetch_add(1, Ordering::Relaxed);
+        self.0.value.fetch_add(sample, Ordering::Relaxed);
+    }
+}
+
+/// The value of a metric at the time of [snapshot].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// The value of a [Counter].
+    Counter(u64),
+    /// The value of a [Gauge].
+    Gauge(i64),
+    /// The state of a [Histogram].
+    Histogram {
+        /// Number of samples.
+        count: u64,
+        /// Sum of the samples.
+        sum: u64,
+        /// Number of samples in each bucket, see [HISTOGRAM_BUCKETS].
+        buckets: [u64; HISTOGRAM_BUCKETS],
+    },
+}
+
+impl fmt::Display for Value {
+    /// Counters and gauges as plain numbers, histograms as `count=<n> sum=<n>` followed by the
+    /// non-empty buckets as `<upper bound>:<count>`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Counter(value) => write!(f, "{}", value),
+            Value::Gauge(value) => write!(f, "{}", value),
+            Value::Histogram {
+                count,
+                sum,
+                buckets,
+            } => {
+                write!(f, "count={} sum={}", count, sum)?;
+                for (i, &bucket) in buckets.iter().enumerate() {
+                    if bucket == 0 {
+                        continue;
+                    }
+                    if i == HISTOGRAM_BUCKETS - 1 {
+                        write!(f, " inf:{}", bucket)?;
+                    } else {
+                        write!(f, " {}:{}", 1u64 << i, bucket)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A named value of a metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// The name of the metric.
+    pub name: &'static str,
+    /// The value of the metric.
+    pub value: Value,
+}
+
+/// The values of all registered metrics, in ascending order of names.
+pub fn snapshot() -> Vec<Sample> {
+    let mut samples = Vec::new();
+    let mut node = REGISTRY.load(Ordering::Acquire);
+    // # Safety
+    // Nodes of the registry are always `&'static Metric`, see [Metric::register].
+    while let Some(metric) = unsafe { node.as_ref() } {
+        samples.push(metric.sample());
+        node = metric.next.load(Ordering::Relaxed);
+    }
+
+    samples.sort_unstable_by_key(|sample| sample.name);
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(name: &str) -> Option<Value> {
+        snapshot()
+            .into_iter()
+            .find(|sample| sample.name == name)
+            .map(|sample| sample.value)
+    }
+
+    #[test_case]
+    fn register_on_update() {
+        static COUNTER: Counter = Counter::new("test.counter");
+        static GAUGE: Gauge = Gauge::new("test.gauge");
+        static HISTOGRAM: Histogram = Histogram::new("test.histogram");
+
+        assert_eq!(find("test.counter"), None);
+        COUNTER.inc();
+        COUNTER.add(2);
+        GAUGE.set(1);
+        GAUGE.add(-3);
+        HISTOGRAM.record(0);
+        HISTOGRAM.record(5);
+        HISTOGRAM.record(7);
+
+        assert_eq!(find("test.counter"), Some(Value::Counter(3)));
+        assert_eq!(find("test.gauge"), Some(Value::Gauge(-2)));
+        let mut buckets = [0; HISTOGRAM_BUCKETS];
+        buckets[0] = 1;
+        buckets[3] = 2;
+        assert_eq!(
+            find("test.histogram"),
+            Some(Value::Histogram {
+                count: 3,
+                sum: 12,
+                buckets
+            })
+        );
+    }
+}
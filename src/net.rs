@@ -0,0 +1,540 @@
+//! Network interfaces, Ethernet cards sending and receiving frames.
+//!
+//! Drivers implement [NetDevice] and [register] their cards on initialization as `eth0`, `eth1`
+//! and so on in the order of registration, followed by the [loopback] interface `lo`. The
+//! protocols look interfaces up by name with [get] instead of depending on a driver, received
+//! frames are read through a [PacketStream].
+//!
+//! An [Interface] is a registered card with its IPv4 configuration, by default the static
+//! addresses of the user mode network stack of QEMU, see [USER_NETWORK], and its traffic counters
+//! registered to [crate::metrics] as `net.<interface>.<counter>`. The [receive_task] of
+//! each interface reads its frames and hands them to the protocols by EtherType, to [arp] and
+//! [ipv4], which hands them on to the sockets of [tcp] and [udp]. [http] serves a status page on
+//! top of them, [pcap] captures the frames of the interfaces.
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::{
+    convert::TryFrom,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{Stream, StreamExt};
+use spin::Mutex;
+
+use crate::metrics::Counter;
+
+pub mod arp;
+pub mod e1000;
+pub mod http;
+pub mod ipv4;
+pub mod loopback;
+pub mod pcap;
+pub mod tcp;
+pub mod udp;
+
+/// The largest Ethernet frame without the frame check sequence, which the cards neither pass nor
+/// expect.
+pub const MAX_FRAME_SIZE: usize = 1514;
+/// The prefix of the names of the interfaces.
+pub const NAME_PREFIX: &str = "eth";
+
+/// The size of the header of an Ethernet frame: destination, source and EtherType.
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+/// The EtherType of IPv4 packets.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+/// The EtherType of ARP packets.
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// The addresses of the guest on the user mode network stack of QEMU (`-netdev user`).
+pub const USER_NETWORK: Ipv4Config = Ipv4Config {
+    address: Ipv4Address([10, 0, 2, 15]),
+    prefix_len: 24,
+    gateway: Some(Ipv4Address([10, 0, 2, 2])),
+};
+
+/// The registered interfaces, in the order of registration. Interfaces are never removed.
+static INTERFACES: Mutex<Vec<&'static Interface>> = Mutex::new(Vec::new());
+
+/// A MAC address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// The broadcast address.
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// An IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    /// The unspecified address `0.0.0.0`.
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    /// The limited broadcast address `255.255.255.255`.
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// An IPv4 address with a port of UDP or TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SocketAddress {
+    /// The address of the host.
+    pub addr: Ipv4Address,
+    /// The port on the host.
+    pub port: u16,
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+/// The IPv4 configuration of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    /// The address of the interface.
+    pub address: Ipv4Address,
+    /// The length of the prefix of the subnet, 24 for `255.255.255.0`.
+    pub prefix_len: u8,
+    /// The router to the addresses outside of the subnet.
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl Ipv4Config {
+    /// The mask of the subnet.
+    pub fn netmask(&self) -> Ipv4Address {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+        Ipv4Address(mask.to_be_bytes())
+    }
+
+    /// Whether `addr` is in the subnet of the interface, reachable without the gateway.
+    pub fn is_local(&self, addr: Ipv4Address) -> bool {
+        let mask = u32::from_be_bytes(self.netmask().0);
+        u32::from_be_bytes(addr.0) & mask == u32::from_be_bytes(self.address.0) & mask
+    }
+}
+
+/// Errors of [NetDevice::send] and of the protocols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No network card has been initialized.
+    NoDevice,
+    /// The frame is larger than [MAX_FRAME_SIZE].
+    FrameTooLarge(usize),
+    /// All the transmit buffers are in flight.
+    QueueFull,
+    /// The interface has no IPv4 address.
+    NoAddress,
+    /// The host didn't answer, e.g. to ARP requests.
+    HostUnreachable,
+    /// No interface reaches the address.
+    NetworkUnreachable,
+    /// A socket is already bound to the port.
+    AddressInUse,
+    /// The operation can't complete without waiting, e.g. the next hop isn't resolved yet.
+    WouldBlock,
+    /// The host refused the connection.
+    ConnectionRefused,
+    /// The peer reset the connection.
+    ConnectionReset,
+    /// The peer stopped acknowledging what is sent.
+    TimedOut,
+    /// The connection is closed for the operation.
+    NotConnected,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::NoDevice => write!(f, "no network card"),
+            NetError::FrameTooLarge(len) => write!(
+                f,
+                "frame of {} bytes larger than {} bytes",
+                len, MAX_FRAME_SIZE
+            ),
+            NetError::QueueFull => write!(f, "transmit queue full"),
+            NetError::NoAddress => write!(f, "interface has no IPv4 address"),
+            NetError::HostUnreachable => write!(f, "host unreachable"),
+            NetError::NetworkUnreachable => write!(f, "network unreachable"),
+            NetError::AddressInUse => write!(f, "address already in use"),
+            NetError::WouldBlock => write!(f, "operation would block"),
+            NetError::ConnectionRefused => write!(f, "connection refused"),
+            NetError::ConnectionReset => write!(f, "connection reset by peer"),
+            NetError::TimedOut => write!(f, "connection timed out"),
+            NetError::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+/// An Ethernet card.
+pub trait NetDevice: Send + Sync {
+    /// The MAC address of the card.
+    fn mac_address(&self) -> MacAddress;
+
+    /// Transmit the Ethernet frame `frame` without its frame check sequence.
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Take the next received Ethernet frame without waiting, `None` if there is none.
+    fn try_receive(&self) -> Option<Vec<u8>>;
+
+    /// Take the next received Ethernet frame, or wake the task of `cx` once there is one. A card
+    /// wakes a single task, the one which polled last.
+    fn poll_receive(&self, cx: &mut Context<'_>) -> Poll<Vec<u8>>;
+
+    /// Whether the card receives the frames it sends instead of putting them on a wire. The hosts
+    /// behind it need no ARP.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+}
+
+/// A registered card with its configuration.
+pub struct Interface {
+    name: String,
+    device: &'static dyn NetDevice,
+    ipv4: Mutex<Option<Ipv4Config>>,
+    counters: Counters,
+}
+
+/// The traffic of an interface since its registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterfaceStats {
+    /// The frames received.
+    pub rx_frames: u64,
+    /// The bytes of the frames received.
+    pub rx_bytes: u64,
+    /// The frames received and dropped: too short or of an unknown EtherType.
+    pub rx_dropped: u64,
+    /// The frames sent.
+    pub tx_frames: u64,
+    /// The bytes of the frames sent.
+    pub tx_bytes: u64,
+    /// The frames the card failed to send.
+    pub tx_errors: u64,
+}
+
+/// The counters behind [InterfaceStats].
+struct Counters {
+    rx_frames: &'static Counter,
+    rx_bytes: &'static Counter,
+    rx_dropped: &'static Counter,
+    tx_frames: &'static Counter,
+    tx_bytes: &'static Counter,
+    tx_errors: &'static Counter,
+}
+
+impl Counters {
+    /// Register the counters of the interface `interface`. Interfaces are never removed, neither
+    /// are their counters.
+    fn new(interface: &str) -> Self {
+        let counter = |name: &str| -> &'static Counter {
+            let name = Box::leak(format!("net.{}.{}", interface, name).into_boxed_str());
+            let counter = Box::leak(Box::new(Counter::new(name)));
+            // listed by the metrics before any traffic
+            counter.add(0);
+            counter
+        };
+        Counters {
+            rx_frames: counter("rx_frames"),
+            rx_bytes: counter("rx_bytes"),
+            rx_dropped: counter("rx_dropped"),
+            tx_frames: counter("tx_frames"),
+            tx_bytes: counter("tx_bytes"),
+            tx_errors: counter("tx_errors"),
+        }
+    }
+}
+
+impl Interface {
+    /// The name of the interface, e.g. `eth0`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The card of the interface.
+    pub fn device(&self) -> &'static dyn NetDevice {
+        self.device
+    }
+
+    /// The IPv4 configuration of the interface, `None` if it has no address.
+    pub fn ipv4(&self) -> Option<Ipv4Config> {
+        *self.ipv4.lock()
+    }
+
+    /// Replace the IPv4 configuration of the interface.
+    pub fn set_ipv4(&self, config: Option<Ipv4Config>) {
+        *self.ipv4.lock() = config;
+    }
+
+    /// The traffic of the interface.
+    pub fn stats(&self) -> InterfaceStats {
+        InterfaceStats {
+            rx_frames: self.counters.rx_frames.get(),
+            rx_bytes: self.counters.rx_bytes.get(),
+            rx_dropped: self.counters.rx_dropped.get(),
+            tx_frames: self.counters.tx_frames.get(),
+            tx_bytes: self.counters.tx_bytes.get(),
+            tx_errors: self.counters.tx_errors.get(),
+        }
+    }
+
+    /// Send `payload` of `ethertype` to `dst` in an Ethernet frame from the card.
+    pub fn send_to(&self, dst: MacAddress, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&dst.0);
+        frame.extend_from_slice(&self.device.mac_address().0);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        match self.device.send(&frame) {
+            Ok(()) => {
+                pcap::tap(self, &frame, false);
+                self.counters.tx_frames.inc();
+                self.counters.tx_bytes.add(frame.len() as u64);
+                Ok(())
+            }
+            Err(err) => {
+                self.counters.tx_errors.inc();
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Register `device` under the next free name with the addresses of [USER_NETWORK], returns the
+/// name.
+pub fn register(device: &'static dyn NetDevice) -> String {
+    let mut interfaces = INTERFACES.lock();
+    let cards = interfaces
+        .iter()
+        .filter(|interface| interface.name.starts_with(NAME_PREFIX))
+        .count();
+    let name = format!("{}{}", NAME_PREFIX, cards);
+    interfaces.push(Box::leak(Box::new(Interface {
+        name: name.clone(),
+        device,
+        ipv4: Mutex::new(Some(USER_NETWORK)),
+        counters: Counters::new(&name),
+    })));
+    name
+}
+
+/// Register `device` as `name` with the addresses `ipv4`, for the interfaces which aren't cards
+/// such as [loopback].
+pub fn register_as(name: &str, device: &'static dyn NetDevice, ipv4: Ipv4Config) {
+    INTERFACES.lock().push(Box::leak(Box::new(Interface {
+        name: String::from(name),
+        device,
+        ipv4: Mutex::new(Some(ipv4)),
+        counters: Counters::new(name),
+    })));
+}
+
+/// The interface registered as `name`.
+pub fn interface(name: &str) -> Option<&'static Interface> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .copied()
+}
+
+/// The registered interfaces, in the order of registration.
+pub fn interfaces() -> Vec<&'static Interface> {
+    INTERFACES.lock().clone()
+}
+
+/// The card of the interface registered as `name`.
+pub fn get(name: &str) -> Option<&'static dyn NetDevice> {
+    interface(name).map(Interface::device)
+}
+
+/// The names of the registered interfaces, in the order of registration.
+pub fn names() -> Vec<String> {
+    INTERFACES
+        .lock()
+        .iter()
+        .map(|interface| interface.name.clone())
+        .collect()
+}
+
+/// Hand the Ethernet frame `frame` received by `interface` to the protocol of its EtherType,
+/// frames of other protocols are dropped.
+pub fn receive_frame(interface: &'static Interface, frame: &[u8]) {
+    let counters = &interface.counters;
+    counters.rx_frames.inc();
+    counters.rx_bytes.add(frame.len() as u64);
+    pcap::tap(interface, frame, true);
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        counters.rx_dropped.inc();
+        return;
+    }
+    let ethertype = u16::from_be_bytes(<[u8; 2]>::try_from(&frame[12..14]).unwrap());
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+    match ethertype {
+        ETHERTYPE_ARP => arp::receive(interface, payload),
+        ETHERTYPE_IPV4 => ipv4::receive(interface, payload),
+        _ => counters.rx_dropped.inc(),
+    }
+}
+
+/// Receive the frames of `interface` forever, the only reader of its card. Spawned for each
+/// interface by the kernel.
+pub async fn receive_task(interface: &'static Interface) {
+    let mut frames = PacketStream::new(interface.device);
+    while let Some(frame) = frames.next().await {
+        receive_frame(interface, &frame);
+    }
+}
+
+/// A stream of the Ethernet frames received by an interface, there should be a single one per
+/// interface.
+pub struct PacketStream {
+    device: &'static dyn NetDevice,
+}
+
+impl PacketStream {
+    /// Create a [PacketStream] of the frames received by `device`.
+    pub fn new(device: &'static dyn NetDevice) -> Self {
+        PacketStream { device }
+    }
+}
+
+impl Stream for PacketStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        self.device.poll_receive(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::time;
+    use core::future::Future;
+    use futures_util::{future, pin_mut};
+
+    /// The addresses of the guest and of the gateway on `-netdev user`.
+    const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+    pub(crate) const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+    const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+    const ARP_REPLY: [u8; 2] = [0x00, 0x02];
+
+    /// Ask the gateway of the user mode network stack of QEMU behind `device` for its MAC address
+    /// and wait for the reply.
+    pub(crate) fn gateway_answers_arp(device: &dyn NetDevice) {
+        let mac = device.mac_address().0;
+        let mut request = Vec::new();
+        request.extend_from_slice(&MacAddress::BROADCAST.0);
+        request.extend_from_slice(&mac);
+        request.extend_from_slice(&ETHERTYPE_ARP);
+        // Ethernet and IPv4 addresses, request
+        request.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01]);
+        request.extend_from_slice(&mac);
+        request.extend_from_slice(&GUEST_IP);
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&GATEWAY_IP);
+        device.send(&request).unwrap();
+
+        let is_reply = |frame: &[u8]| {
+            frame.len() >= 42
+                && frame[12..14] == ETHERTYPE_ARP
+                && frame[20..22] == ARP_REPLY
+                && frame[28..32] == GATEWAY_IP
+        };
+        for _ in 0..1000 {
+            if device.try_receive().map_or(false, |frame| is_reply(&frame)) {
+                return;
+            }
+            time::delay_ms(1);
+        }
+        panic!("no ARP reply from the gateway");
+    }
+
+    /// Run `future` to completion along with the receive tasks of the interfaces and the TCP
+    /// timer, which drive the network.
+    pub(crate) fn run<F: Future>(future: F) -> F::Output {
+        crate::task::block_on(async {
+            let network = future::join(
+                future::join_all(interfaces().into_iter().map(receive_task)),
+                tcp::timer_task(),
+            );
+            pin_mut!(future, network);
+            match future::select(future, network).await {
+                future::Either::Left((output, _)) => output,
+                future::Either::Right(_) => unreachable!("the network tasks never end"),
+            }
+        })
+    }
+
+    #[test_case]
+    fn interfaces_registered() {
+        // virtio-net is initialized first, see package.metadata.bootimage.test-args in Cargo.toml
+        assert_eq!(names(), ["eth0", "eth1", "lo"]);
+        assert!(get("eth1").is_some());
+        assert!(get("eth2").is_none());
+        assert_eq!(interface("eth0").unwrap().ipv4(), Some(USER_NETWORK));
+    }
+
+    #[test_case]
+    fn traffic_counted() {
+        let lo = interface(loopback::NAME).unwrap();
+        // the frames left behind by earlier tests
+        while lo.device().try_receive().is_some() {}
+        let before = lo.stats();
+        lo.send_to(MacAddress([0; 6]), 0x88b5, b"local experimental")
+            .unwrap();
+        let frame = lo.device().try_receive().unwrap();
+        receive_frame(lo, &frame);
+        receive_frame(lo, &frame[..4]);
+
+        let after = lo.stats();
+        let frame_len = (ETHERNET_HEADER_SIZE + 18) as u64;
+        assert_eq!(after.tx_frames - before.tx_frames, 1);
+        assert_eq!(after.tx_bytes - before.tx_bytes, frame_len);
+        assert_eq!(after.rx_frames - before.rx_frames, 2);
+        assert_eq!(after.rx_bytes - before.rx_bytes, frame_len + 4);
+        assert_eq!(after.rx_dropped - before.rx_dropped, 2);
+        assert!(crate::metrics::snapshot()
+            .iter()
+            .any(|sample| sample.name == "net.lo.rx_dropped"));
+    }
+
+    #[test_case]
+    fn subnets_masked() {
+        assert_eq!(USER_NETWORK.netmask(), Ipv4Address([255, 255, 255, 0]));
+        assert!(USER_NETWORK.is_local(Ipv4Address(GATEWAY_IP)));
+        assert!(!USER_NETWORK.is_local(Ipv4Address([10, 0, 3, 2])));
+        let everything = Ipv4Config {
+            prefix_len: 0,
+            ..USER_NETWORK
+        };
+        assert_eq!(everything.netmask(), Ipv4Address::UNSPECIFIED);
+        assert!(everything.is_local(Ipv4Address::BROADCAST));
+        assert_eq!(format!("{}", USER_NETWORK.address), "10.0.2.15");
+    }
+}
@@ -0,0 +1,371 @@
+//! The Address Resolution Protocol (RFC 826), the MAC addresses of the IPv4 hosts of a subnet.
+//!
+//! [resolve] looks the address up in the cache of the interface, or broadcasts requests until the
+//! host replies, doubling the wait after each unanswered request. Entries expire after
+//! [CACHE_TTL]. Hosts are cached from the replies to the kernel and from the requests for the
+//! address of the interface, which are answered.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use super::{Interface, Ipv4Address, MacAddress, NetError, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use crate::{metrics::Counter, time};
+
+/// How long a resolved address is cached.
+pub const CACHE_TTL: Duration = Duration::from_secs(60);
+/// How long [resolve] waits for the reply to its first request.
+pub const INITIAL_TIMEOUT: Duration = Duration::from_millis(100);
+/// The number of requests sent by [resolve] before giving up.
+pub const MAX_REQUESTS: u32 = 4;
+
+/// The size of an ARP packet for IPv4 over Ethernet.
+pub const PACKET_SIZE: usize = 28;
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+/// The cached hosts by interface name and address.
+static CACHE: Mutex<BTreeMap<(&'static str, Ipv4Address), Entry>> = Mutex::new(BTreeMap::new());
+/// The tasks waiting for a reply, woken whenever a host is cached.
+static WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+static REQUESTS_SENT: Counter = Counter::new("arp.requests_sent");
+static REPLIES_SENT: Counter = Counter::new("arp.replies_sent");
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    mac: MacAddress,
+    /// The tick the entry expires at.
+    expires: u64,
+}
+
+/// The operation of an ARP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Asks for the MAC address of the target.
+    Request,
+    /// Answers with the MAC address of the sender.
+    Reply,
+}
+
+/// An ARP packet for IPv4 over Ethernet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    /// Whether the packet is a request or a reply.
+    pub operation: Operation,
+    /// The MAC address of the sender.
+    pub sender_mac: MacAddress,
+    /// The IPv4 address of the sender.
+    pub sender_ip: Ipv4Address,
+    /// The MAC address of the target, ignored in requests.
+    pub target_mac: MacAddress,
+    /// The IPv4 address of the target.
+    pub target_ip: Ipv4Address,
+}
+
+impl Packet {
+    /// Parse the packet at the start of `bytes`, `None` if it isn't a valid ARP packet for IPv4
+    /// over Ethernet.
+    pub fn parse(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < PACKET_SIZE {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let mac_at =
+            |offset: usize| MacAddress(<[u8; 6]>::try_from(&bytes[offset..offset + 6]).unwrap());
+        let ip_at =
+            |offset: usize| Ipv4Address(<[u8; 4]>::try_from(&bytes[offset..offset + 4]).unwrap());
+
+        if u16_at(0) != HARDWARE_ETHERNET
+            || u16_at(2) != ETHERTYPE_IPV4
+            || bytes[4] != 6
+            || bytes[5] != 4
+        {
+            return None;
+        }
+        let operation = match u16_at(6) {
+            OPERATION_REQUEST => Operation::Request,
+            OPERATION_REPLY => Operation::Reply,
+            _ => return None,
+        };
+        Some(Packet {
+            operation,
+            sender_mac: mac_at(8),
+            sender_ip: ip_at(14),
+            target_mac: mac_at(18),
+            target_ip: ip_at(24),
+        })
+    }
+
+    /// The packet in its wire format.
+    pub fn to_bytes(&self) -> [u8; PACKET_SIZE] {
+        let operation = match self.operation {
+            Operation::Request => OPERATION_REQUEST,
+            Operation::Reply => OPERATION_REPLY,
+        };
+        let mut bytes = [0; PACKET_SIZE];
+        bytes[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        bytes[6..8].copy_from_slice(&operation.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac.0);
+        bytes[14..18].copy_from_slice(&self.sender_ip.0);
+        bytes[18..24].copy_from_slice(&self.target_mac.0);
+        bytes[24..28].copy_from_slice(&self.target_ip.0);
+        bytes
+    }
+}
+
+/// The cached MAC address of `addr` on `interface`, `None` if it isn't cached or has expired.
+/// The broadcast address is always known, as are the hosts behind a loopback interface.
+pub fn lookup(interface: &'static Interface, addr: Ipv4Address) -> Option<MacAddress> {
+    if addr == Ipv4Address::BROADCAST {
+        return Some(MacAddress::BROADCAST);
+    }
+    if interface.device().is_loopback() {
+        return Some(interface.device().mac_address());
+    }
+    let mut cache = CACHE.lock();
+    let key = (interface.name(), addr);
+    let entry = *cache.get(&key)?;
+    if entry.expires <= time::ticks() {
+        cache.remove(&key);
+        return None;
+    }
+    Some(entry.mac)
+}
+
+/// Cache `mac` as the address of `addr` on `interface` for [CACHE_TTL], wakes the tasks waiting
+/// in [resolve].
+pub fn insert(interface: &'static Interface, addr: Ipv4Address, mac: MacAddress) {
+    let expires = time::ticks() + time::duration_to_ticks(CACHE_TTL);
+    CACHE
+        .lock()
+        .insert((interface.name(), addr), Entry { mac, expires });
+    let waiters = core::mem::take(&mut *WAITERS.lock());
+    for waker in waiters {
+        waker.wake();
+    }
+}
+
+/// The hosts cached on `interface` and not expired, sorted by address.
+pub fn entries(interface: &'static Interface) -> Vec<(Ipv4Address, MacAddress)> {
+    let now = time::ticks();
+    CACHE
+        .lock()
+        .iter()
+        .filter(|((name, _), entry)| *name == interface.name() && entry.expires > now)
+        .map(|(&(_, addr), entry)| (addr, entry.mac))
+        .collect()
+}
+
+/// Handle the ARP packet `payload` received by `interface`: cache the sender if the packet is
+/// for the interface or the sender is already cached, answer the requests for its address.
+pub fn receive(interface: &'static Interface, payload: &[u8]) {
+    let packet = match Packet::parse(payload) {
+        Some(packet) => packet,
+        None => return,
+    };
+    let address = match interface.ipv4() {
+        Some(config) => config.address,
+        None => return,
+    };
+
+    let for_us = packet.target_ip == address;
+    let known = CACHE
+        .lock()
+        .contains_key(&(interface.name(), packet.sender_ip));
+    if (for_us || known) && packet.sender_ip != Ipv4Address::UNSPECIFIED {
+        insert(interface, packet.sender_ip, packet.sender_mac);
+    }
+
+    if for_us && packet.operation == Operation::Request {
+        let reply = Packet {
+            operation: Operation::Reply,
+            sender_mac: interface.device().mac_address(),
+            sender_ip: address,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        // the requester asks again if the reply is lost
+        if interface
+            .send_to(packet.sender_mac, ETHERTYPE_ARP, &reply.to_bytes())
+            .is_ok()
+        {
+            REPLIES_SENT.inc();
+        }
+    }
+}
+
+/// Broadcast a request for the MAC address of `addr` on `interface` without waiting for the
+/// reply.
+pub fn request(interface: &'static Interface, addr: Ipv4Address) -> Result<(), NetError> {
+    let request = Packet {
+        operation: Operation::Request,
+        sender_mac: interface.device().mac_address(),
+        sender_ip: interface.ipv4().ok_or(NetError::NoAddress)?.address,
+        target_mac: MacAddress([0; 6]),
+        target_ip: addr,
+    };
+    interface.send_to(MacAddress::BROADCAST, ETHERTYPE_ARP, &request.to_bytes())?;
+    REQUESTS_SENT.inc();
+    Ok(())
+}
+
+/// The MAC address of `addr` on the subnet of `interface`, from the cache or asked with up to
+/// [MAX_REQUESTS] broadcast requests. The replies are received by the [super::receive_task] of
+/// the interface.
+pub async fn resolve(
+    interface: &'static Interface,
+    addr: Ipv4Address,
+) -> Result<MacAddress, NetError> {
+    if let Some(mac) = lookup(interface, addr) {
+        return Ok(mac);
+    }
+
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..MAX_REQUESTS {
+        request(interface, addr)?;
+        if let Some(mac) = Reply::new(interface, addr, timeout).await {
+            return Ok(mac);
+        }
+        timeout *= 2;
+    }
+    Err(NetError::HostUnreachable)
+}
+
+/// A future ready with the MAC address of `addr` once it's cached, or with `None` at the
+/// deadline.
+struct Reply {
+    interface: &'static Interface,
+    addr: Ipv4Address,
+    deadline: u64,
+    timer_set: bool,
+}
+
+impl Reply {
+    fn new(interface: &'static Interface, addr: Ipv4Address, timeout: Duration) -> Self {
+        // the current tick is already partly over
+        Reply {
+            interface,
+            addr,
+            deadline: time::ticks() + time::duration_to_ticks(timeout) + 1,
+            timer_set: false,
+        }
+    }
+}
+
+impl Future for Reply {
+    type Output = Option<MacAddress>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(mac) = lookup(self.interface, self.addr) {
+            return Poll::Ready(Some(mac));
+        }
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(None);
+        }
+
+        let mut waiters = WAITERS.lock();
+        if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+            waiters.push(cx.waker().clone());
+        }
+        drop(waiters);
+        if !self.timer_set {
+            time::wheel::register(self.deadline, cx.waker().clone());
+            self.timer_set = true;
+        }
+
+        // the reply may have been cached after the first check
+        match lookup(self.interface, self.addr) {
+            Some(mac) => Poll::Ready(Some(mac)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{self, tests::GATEWAY_IP, USER_NETWORK},
+        task::block_on,
+        testing,
+    };
+    use futures_util::{future, pin_mut};
+
+    #[test_case]
+    fn packets_parsed() {
+        let packet = Packet {
+            operation: Operation::Request,
+            sender_mac: MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            sender_ip: USER_NETWORK.address,
+            target_mac: MacAddress([0; 6]),
+            target_ip: Ipv4Address(GATEWAY_IP),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[..8], [0, 1, 8, 0, 6, 4, 0, 1]);
+        assert_eq!(Packet::parse(&bytes), Some(packet));
+        assert_eq!(Packet::parse(&bytes[..27]), None);
+
+        let mut ipv6 = bytes;
+        ipv6[2..4].copy_from_slice(&[0x86, 0xdd]);
+        assert_eq!(Packet::parse(&ipv6), None);
+    }
+
+    #[test_case]
+    fn requests_for_us_answered_and_cached() {
+        let interface = net::interface("eth1").unwrap();
+        let sender_ip = Ipv4Address([10, 0, 2, 99]);
+        let request = Packet {
+            operation: Operation::Request,
+            sender_mac: MacAddress([2, 0, 0, 0, 0, 99]),
+            sender_ip,
+            target_mac: MacAddress([0; 6]),
+            target_ip: Ipv4Address([10, 0, 2, 98]),
+        };
+        // not for us, the sender isn't cached
+        receive(interface, &request.to_bytes());
+        assert_eq!(lookup(interface, sender_ip), None);
+
+        let request = Packet {
+            target_ip: USER_NETWORK.address,
+            ..request
+        };
+        testing::keep_allocations(|| receive(interface, &request.to_bytes()));
+        assert_eq!(lookup(interface, sender_ip), Some(request.sender_mac));
+        assert!(entries(interface).contains(&(sender_ip, request.sender_mac)));
+    }
+
+    #[test_case]
+    fn gateway_resolved() {
+        let interface = net::interface("eth0").unwrap();
+        let gateway = Ipv4Address(GATEWAY_IP);
+        let mac = testing::keep_allocations(|| {
+            block_on(async {
+                let receive = net::receive_task(interface);
+                let resolve = resolve(interface, gateway);
+                pin_mut!(receive, resolve);
+                match future::select(receive, resolve).await {
+                    future::Either::Right((mac, _)) => mac,
+                    future::Either::Left(_) => unreachable!("the receive task never ends"),
+                }
+            })
+        })
+        .unwrap();
+        assert_eq!(lookup(interface, gateway), Some(mac));
+        assert_eq!(
+            block_on(resolve(interface, Ipv4Address::BROADCAST)),
+            Ok(MacAddress::BROADCAST)
+        );
+    }
+}
@@ -0,0 +1,487 @@
+//! Driver of the Intel 8254x (e1000) Ethernet cards, `-device e1000` on QEMU.
+//!
+//! The registers of the card are mapped from its BAR 0. Frames are exchanged through two rings of
+//! descriptors in DMA memory, each pointing to a buffer of [BUFFER_SIZE] bytes: the card fills the
+//! receive descriptors from the head of their ring and the driver gives them back by moving the
+//! tail, the driver queues frames at the tail of the transmit ring and the card sets the
+//! descriptor done bit of those sent. The interrupts of received frames only wake the task reading
+//! the interface, the ring is drained in task context. Transmitted frames are reclaimed lazily by
+//! the next [NetDevice::send], their interrupts are masked.
+
+use alloc::{vec, vec::Vec};
+use core::{
+    ptr,
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{MacAddress, NetDevice, NetError, MAX_FRAME_SIZE};
+use crate::{
+    dma::{self, DmaBuffer, Pod},
+    info, interrupts, memory,
+    metrics::Counter,
+    pci::{self, Bar},
+    time, warn,
+};
+
+/// The vendor ID of Intel.
+pub const VENDOR_ID: u16 = 0x8086;
+/// The device IDs of the supported cards: the 82540EM emulated by QEMU, the 82545EM emulated by
+/// VirtualBox and VMware and the 82543GC.
+pub const DEVICE_IDS: [u16; 3] = [0x100e, 0x100f, 0x1004];
+
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00c0;
+const REG_IMS: u64 = 0x00d0;
+const REG_IMC: u64 = 0x00d8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+/// The multicast table, 128 registers.
+const REG_MTA: u64 = 0x5200;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+/// Set in the high receive address register when the address is valid.
+const RAH_AV: u32 = 1 << 31;
+
+/// Link status change, receive descriptor minimum threshold, receiver overrun and receive timer.
+const INTERRUPTS: u32 = 1 << 2 | 1 << 4 | 1 << 6 | 1 << 7;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Accept broadcast frames.
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the frame check sequence, buffers of 2048 bytes with the size bits left at 0.
+const RCTL_SECRC: u32 = 1 << 26;
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short frames.
+const TCTL_PSP: u32 = 1 << 3;
+/// The collision threshold and distance recommended for full duplex.
+const TCTL_CT: u32 = 0x0f << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// The inter packet gap recommended for copper links.
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+/// The descriptor done bit of the status of both kinds of descriptors.
+const DESC_DD: u8 = 1 << 0;
+/// The end of packet bit, of the status of receive descriptors and of the command of transmit
+/// descriptors.
+const DESC_EOP: u8 = 1 << 0;
+/// Commands of transmit descriptors: insert the frame check sequence and report the status.
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+
+/// The size of a descriptor of both rings.
+const DESC_SIZE: u64 = 16;
+/// Number of descriptors of each ring, the length of a ring must be a multiple of 128 bytes.
+const DESCRIPTORS: u16 = 32;
+/// The size of the buffer of a descriptor.
+const BUFFER_SIZE: u64 = 2048;
+/// Number of polls of the EEPROM before it's given up, 10 microseconds apart.
+const POLLS: usize = 1000;
+
+static CARD: OnceCell<E1000> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static RX_PACKETS: Counter = Counter::new("e1000.rx_packets");
+static TX_PACKETS: Counter = Counter::new("e1000.tx_packets");
+static TX_QUEUE_FULL: Counter = Counter::new("e1000.tx_queue_full");
+
+/// The initialized card.
+struct E1000 {
+    base: VirtAddr,
+    mac: MacAddress,
+    rings: Mutex<Rings>,
+}
+
+/// The descriptor rings and their buffers, the buffer of a descriptor is at the same index.
+struct Rings {
+    rx: DmaBuffer,
+    tx: DmaBuffer,
+    rx_buffers: DmaBuffer,
+    tx_buffers: DmaBuffer,
+    /// The next receive descriptor filled by the card.
+    rx_next: u16,
+    /// The next free transmit descriptor, the tail of the ring.
+    tx_next: u16,
+}
+
+/// A receive or transmit descriptor, their layouts only differ after the length.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u16,
+    /// The checksum of received frames, the checksum offset and the command of transmitted ones.
+    field: [u8; 2],
+    status: u8,
+    /// The errors of received frames, the checksum start of transmitted ones.
+    errors: u8,
+    special: u16,
+}
+
+// # Safety
+// The fields are integers laid out without padding.
+unsafe impl Pod for Descriptor {}
+
+/// The offset of the descriptor `index` in its ring.
+fn descriptor_offset(index: u16) -> u64 {
+    u64::from(index) * DESC_SIZE
+}
+
+/// The offset of the buffer of the descriptor `index` in the buffers of its ring.
+fn buffer_offset(index: u16) -> u64 {
+    u64::from(index) * BUFFER_SIZE
+}
+
+impl E1000 {
+    fn read(&self, reg: u64) -> u32 {
+        // # Safety
+        // The registers of the card are mapped at `base` by [init].
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        // # Safety
+        // As above, the card only accesses the rings and the buffers it's given.
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr::<u32>(), value) }
+    }
+
+    /// Read the word `address` of the EEPROM, `None` if the card has no EEPROM.
+    fn read_eeprom(&self, address: u8) -> Option<u16> {
+        self.write(REG_EERD, u32::from(address) << 8 | EERD_START);
+        for _ in 0..POLLS {
+            let eerd = self.read(REG_EERD);
+            if eerd & EERD_DONE != 0 {
+                return Some((eerd >> 16) as u16);
+            }
+            time::delay_us(10);
+        }
+        None
+    }
+
+    /// The MAC address in the EEPROM, or in the receive address registers if there is no EEPROM.
+    fn read_mac(&self) -> MacAddress {
+        let mut mac = [0; 6];
+        let words = (0..3)
+            .map(|i| self.read_eeprom(i))
+            .collect::<Option<Vec<_>>>();
+        match words {
+            Some(words) => {
+                for (bytes, word) in mac.chunks_mut(2).zip(words) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            None => {
+                mac[..4].copy_from_slice(&self.read(REG_RAL).to_le_bytes());
+                mac[4..].copy_from_slice(&self.read(REG_RAH).to_le_bytes()[..2]);
+            }
+        }
+        MacAddress(mac)
+    }
+
+    fn new(base: VirtAddr) -> Option<Self> {
+        let mut card = E1000 {
+            base,
+            mac: MacAddress([0; 6]),
+            rings: Mutex::new(Rings {
+                rx: dma::alloc(u64::from(DESCRIPTORS) * DESC_SIZE)?,
+                tx: dma::alloc(u64::from(DESCRIPTORS) * DESC_SIZE)?,
+                rx_buffers: dma::alloc(u64::from(DESCRIPTORS) * BUFFER_SIZE)?,
+                tx_buffers: dma::alloc(u64::from(DESCRIPTORS) * BUFFER_SIZE)?,
+                rx_next: 0,
+                tx_next: 0,
+            }),
+        };
+
+        card.write(REG_IMC, u32::MAX);
+        card.write(REG_CTRL, card.read(REG_CTRL) | CTRL_RST);
+        time::delay_ms(1);
+        card.write(REG_IMC, u32::MAX);
+        card.read(REG_ICR);
+        card.write(REG_CTRL, card.read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        card.mac = card.read_mac();
+        let [a, b, c, d, e, f] = card.mac.0;
+        card.write(REG_RAL, u32::from_le_bytes([a, b, c, d]));
+        card.write(REG_RAH, u32::from(u16::from_le_bytes([e, f])) | RAH_AV);
+        for i in 0..128 {
+            card.write(REG_MTA + 4 * i, 0);
+        }
+
+        {
+            let rings = card.rings.lock();
+            let split = |addr: PhysAddr| (addr.as_u64() as u32, (addr.as_u64() >> 32) as u32);
+            let ring_len = u32::from(DESCRIPTORS) * DESC_SIZE as u32;
+
+            // the card isn't using the rings yet
+            for i in 0..DESCRIPTORS {
+                rings.rx.write(
+                    descriptor_offset(i),
+                    Descriptor {
+                        addr: rings.rx_buffers.phys_at(buffer_offset(i)).as_u64(),
+                        len: 0,
+                        field: [0; 2],
+                        status: 0,
+                        errors: 0,
+                        special: 0,
+                    },
+                );
+                // free transmit descriptors are those done
+                rings.tx.write(
+                    descriptor_offset(i),
+                    Descriptor {
+                        addr: rings.tx_buffers.phys_at(buffer_offset(i)).as_u64(),
+                        len: 0,
+                        field: [0; 2],
+                        status: DESC_DD,
+                        errors: 0,
+                        special: 0,
+                    },
+                );
+            }
+            rings.rx.sync_for_device();
+            rings.tx.sync_for_device();
+
+            let (low, high) = split(rings.rx.phys_addr());
+            card.write(REG_RDBAL, low);
+            card.write(REG_RDBAH, high);
+            card.write(REG_RDLEN, ring_len);
+            card.write(REG_RDH, 0);
+            // all the descriptors but one are given to the card, the tail never catches the head
+            card.write(REG_RDT, u32::from(DESCRIPTORS - 1));
+            card.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+            let (low, high) = split(rings.tx.phys_addr());
+            card.write(REG_TDBAL, low);
+            card.write(REG_TDBAH, high);
+            card.write(REG_TDLEN, ring_len);
+            card.write(REG_TDH, 0);
+            card.write(REG_TDT, 0);
+            card.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+            card.write(REG_TIPG, TIPG_COPPER);
+        }
+        Some(card)
+    }
+
+    /// Whether the link is up.
+    fn link_up(&self) -> bool {
+        self.read(REG_STATUS) & STATUS_LU != 0
+    }
+}
+
+impl Rings {
+    fn receive(&mut self, card: &E1000) -> Option<Vec<u8>> {
+        let index = self.rx_next;
+        // the card is done with the descriptor once its status is written
+        let descriptor: Descriptor = self.rx.read(descriptor_offset(index));
+        if descriptor.status & DESC_DD == 0 {
+            return None;
+        }
+        self.rx.sync_for_cpu();
+
+        // frames spanning several buffers can't be larger than MAX_FRAME_SIZE, they're dropped
+        let packet = if descriptor.status & DESC_EOP != 0 && descriptor.errors == 0 {
+            let len = usize::from(descriptor.len).min(MAX_FRAME_SIZE);
+            let mut packet = vec![0; len];
+            self.rx_buffers
+                .read_bytes(buffer_offset(index), &mut packet);
+            Some(packet)
+        } else {
+            None
+        };
+
+        // the descriptor is given back to the card by the tail
+        self.rx.write(
+            descriptor_offset(index),
+            Descriptor {
+                status: 0,
+                ..descriptor
+            },
+        );
+        self.rx.sync_for_device();
+        card.write(REG_RDT, u32::from(index));
+        self.rx_next = (index + 1) % DESCRIPTORS;
+
+        match packet {
+            Some(packet) => {
+                RX_PACKETS.inc();
+                Some(packet)
+            }
+            // the next frame is looked at instead
+            None => self.receive(card),
+        }
+    }
+
+    fn send(&mut self, card: &E1000, frame: &[u8]) -> Result<(), NetError> {
+        let index = self.tx_next;
+        // the card sets the done bit once the frame is sent
+        let descriptor: Descriptor = self.tx.read(descriptor_offset(index));
+        if descriptor.status & DESC_DD == 0 {
+            TX_QUEUE_FULL.inc();
+            return Err(NetError::QueueFull);
+        }
+
+        // the descriptor and its buffer are free, they're given to the card by the tail
+        self.tx_buffers.write_bytes(buffer_offset(index), frame);
+        self.tx.write(
+            descriptor_offset(index),
+            Descriptor {
+                len: frame.len() as u16,
+                field: [0, DESC_EOP | TX_CMD_IFCS | TX_CMD_RS],
+                status: 0,
+                ..descriptor
+            },
+        );
+        self.tx.sync_for_device();
+        self.tx_next = (index + 1) % DESCRIPTORS;
+        card.write(REG_TDT, u32::from(self.tx_next));
+
+        TX_PACKETS.inc();
+        Ok(())
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge(frame.len()));
+        }
+        self.rings.lock().send(self, frame)
+    }
+
+    fn try_receive(&self) -> Option<Vec<u8>> {
+        self.rings.lock().receive(self)
+    }
+
+    fn poll_receive(&self, cx: &mut Context<'_>) -> Poll<Vec<u8>> {
+        if let Some(packet) = self.try_receive() {
+            return Poll::Ready(packet);
+        }
+
+        WAKER.register(&cx.waker());
+
+        // the interrupt may have fired after the first check
+        match self.try_receive() {
+            Some(packet) => {
+                WAKER.take();
+                Poll::Ready(packet)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Acknowledge the interrupt of the card and wake the reader of the interface.
+fn handle_interrupt() {
+    if let Ok(card) = CARD.try_get() {
+        // the line may be shared, reading the cause acknowledges it
+        if card.read(REG_ICR) != 0 {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Initialize the first e1000 card if there is one and register it, must be called once after
+/// [pci::init] and [dma::init].
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let device = match DEVICE_IDS.iter().find_map(|&id| pci::find(VENDOR_ID, id)) {
+        Some(device) => device,
+        None => return,
+    };
+    let (phys, size) = match device.bars[0] {
+        Bar::Memory { address, size, .. } => (PhysAddr::new(address), size),
+        _ => {
+            warn!("e1000 at {} has no register BAR", device.address);
+            return;
+        }
+    };
+    // # Safety
+    // The BAR is a range of device registers assigned by the firmware.
+    let base = match unsafe { memory::map_mmio(mapper, frame_allocator, phys, size) } {
+        Ok(base) => base,
+        Err(_) => {
+            warn!("failed to map the registers of e1000 at {}", device.address);
+            return;
+        }
+    };
+    device.enable_bus_mastering();
+
+    let card = match E1000::new(base) {
+        Some(card) => card,
+        None => {
+            warn!("failed to set up the rings of e1000 at {}", device.address);
+            return;
+        }
+    };
+    info!(
+        "e1000 at {} with MAC address {}, link {}",
+        device.address,
+        card.mac,
+        if card.link_up() { "up" } else { "down" }
+    );
+    let card = CARD.get_or_init(|| card);
+    match device.interrupt_line {
+        Some(irq) => {
+            interrupts::register_irq_handler(irq, handle_interrupt);
+            card.write(REG_IMS, INTERRUPTS);
+        }
+        None => warn!("e1000 at {} has no interrupt line", device.address),
+    }
+    super::register(card);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address given to the card, see package.metadata.bootimage.test-args in Cargo.toml.
+    const TEST_MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x57]);
+
+    fn card() -> &'static E1000 {
+        CARD.try_get().expect("no e1000 card")
+    }
+
+    #[test_case]
+    fn mac_from_eeprom() {
+        assert_eq!(card().mac_address(), TEST_MAC);
+        assert!(card().link_up());
+    }
+
+    #[test_case]
+    fn gateway_answers_arp() {
+        crate::net::tests::gateway_answers_arp(card());
+    }
+}
@@ -0,0 +1,292 @@
+//! A minimal HTTP/1.0 server, a demo of the network stack serving a status page of the kernel:
+//! its uptime, the statistics of the heap and the tasks of the executors.
+//!
+//! [run] listens on [PORT], forwarded from port 8080 of the host by `cargo run`. Every connection
+//! answers a single request and is closed, the connections are served concurrently by the task of
+//! the server.
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use futures_util::{
+    future::{self, Either},
+    pin_mut,
+    stream::{FuturesUnordered, StreamExt},
+};
+
+use super::{
+    tcp::{TcpListener, TcpStream},
+    NetError,
+};
+use crate::{allocator, metrics::Counter, task::executor, time, warn};
+
+/// The port served by [run].
+pub const PORT: u16 = 80;
+/// The most connections served at once, the connections accepted beyond are answered
+/// `503 Service Unavailable`.
+pub const MAX_CONNECTIONS: usize = 8;
+/// The largest request head read, longer requests are answered `431 Request Header Fields Too
+/// Large`.
+pub const MAX_REQUEST_SIZE: usize = 4096;
+
+static REQUESTS: Counter = Counter::new("http.requests");
+static ERRORS: Counter = Counter::new("http.errors");
+
+/// Listen on [PORT] and serve the connections forever.
+pub async fn run() {
+    match TcpListener::bind(PORT) {
+        Ok(listener) => serve(&listener).await,
+        Err(err) => warn!("http server failed to listen on port {}: {}", PORT, err),
+    }
+}
+
+/// Serve the connections accepted by `listener` forever, at most [MAX_CONNECTIONS] at once.
+pub async fn serve(listener: &TcpListener) {
+    let mut connections = FuturesUnordered::new();
+    loop {
+        // the empty set of connections is a finished stream, not a pending one
+        let accepted = if connections.is_empty() {
+            listener.accept().await
+        } else {
+            let accept = listener.accept();
+            pin_mut!(accept);
+            match future::select(accept, connections.next()).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => continue,
+            }
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let full = connections.len() >= MAX_CONNECTIONS;
+        connections.push(async move {
+            let result = if full {
+                reply(
+                    &stream,
+                    &response(503, "text/plain", b"too many connections\n"),
+                )
+                .await
+            } else {
+                handle(&stream).await
+            };
+            if result.is_err() {
+                ERRORS.inc();
+            }
+        });
+    }
+}
+
+/// Answer the request read from `stream` and close it.
+async fn handle(stream: &TcpStream) -> Result<(), NetError> {
+    let mut request = Vec::new();
+    let mut buf = [0; 512];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_SIZE {
+            return reply(stream, &response(431, "text/plain", b"request too large\n")).await;
+        }
+        match stream.read(&mut buf).await? {
+            // the head may end with bare newlines, or the client may give up on it
+            0 => break,
+            read => request.extend_from_slice(&buf[..read]),
+        }
+    }
+    REQUESTS.inc();
+    reply(stream, &respond(&request)).await
+}
+
+async fn reply(stream: &TcpStream, response: &[u8]) -> Result<(), NetError> {
+    stream.write_all(response).await?;
+    stream.shutdown();
+    Ok(())
+}
+
+/// The response to the request `request`, its head at least.
+pub fn respond(request: &[u8]) -> Vec<u8> {
+    let line = request
+        .split(|&byte| byte == b'\n')
+        .next()
+        .and_then(|line| core::str::from_utf8(line).ok())
+        .unwrap_or("");
+    let mut parts = line.trim_end_matches('\r').split(' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None) => (method, path, version),
+        _ => return response(400, "text/plain", b"bad request\n"),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return response(400, "text/plain", b"bad request\n");
+    }
+    if method != "GET" && method != "HEAD" {
+        return response(405, "text/plain", b"method not allowed\n");
+    }
+
+    let mut response = match path {
+        "/" => response(200, "text/html", status_page().as_bytes()),
+        _ => response(404, "text/plain", b"not found\n"),
+    };
+    if method == "HEAD" {
+        let head = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        response.truncate(head + 4);
+    }
+    response
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn response(status: u16, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.0 {} {}\r\nServer: rust_kernel\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// The status page, an HTML document.
+pub fn status_page() -> String {
+    let mut page = String::new();
+    let uptime = time::uptime();
+    let secs = uptime.as_secs();
+    writeln!(
+        page,
+        "<!DOCTYPE html>\n<html><head><title>rust_kernel</title></head><body>\n\
+         <h1>rust_kernel</h1>\n<p>up {}:{:02}:{:02}, {}</p>",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        time::now()
+    )
+    .unwrap();
+
+    let heap = allocator::stats();
+    writeln!(
+        page,
+        "<h2>Heap</h2>\n<p>{} KiB at {:#x}, fallback used {} free {}, {} allocations {} \
+         deallocations</p>",
+        heap.heap_size / 1024,
+        heap.heap_start,
+        heap.fallback_used,
+        heap.fallback_free,
+        heap.allocations,
+        heap.deallocations
+    )
+    .unwrap();
+
+    writeln!(
+        page,
+        "<h2>Tasks</h2>\n<p>{} spawned, {} completed</p>\n<table>\n\
+         <tr><th>id</th><th>name</th><th>cpu</th><th>polls</th><th>run time</th></tr>",
+        executor::spawned_tasks(),
+        executor::completed_tasks()
+    )
+    .unwrap();
+    for task in executor::task_metrics() {
+        writeln!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{} us</td></tr>",
+            task.id,
+            task.name,
+            task.cpu,
+            task.polls,
+            task.run_time.as_micros()
+        )
+        .unwrap();
+    }
+    page.push_str("</table>\n</body></html>\n");
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{self, loopback::LOOPBACK_NETWORK, SocketAddress},
+        testing,
+    };
+
+    fn status(response: &[u8]) -> &str {
+        let line = response.split(|&byte| byte == b'\r').next().unwrap();
+        core::str::from_utf8(line).unwrap()
+    }
+
+    #[test_case]
+    fn requests_answered() {
+        let page = respond(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(status(&page), "HTTP/1.0 200 OK");
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains("Content-Type: text/html\r\n") && page.contains("<h2>Tasks</h2>"));
+
+        let head = respond(b"HEAD / HTTP/1.0\r\n\r\n");
+        assert_eq!(status(&head), "HTTP/1.0 200 OK");
+        assert!(head.ends_with(b"Connection: close\r\n\r\n"));
+
+        assert_eq!(
+            status(&respond(b"GET /missing HTTP/1.0\r\n\r\n")),
+            "HTTP/1.0 404 Not Found"
+        );
+        assert_eq!(
+            status(&respond(b"POST / HTTP/1.0\r\n\r\n")),
+            "HTTP/1.0 405 Method Not Allowed"
+        );
+        assert_eq!(
+            status(&respond(b"garbage\r\n\r\n")),
+            "HTTP/1.0 400 Bad Request"
+        );
+        assert_eq!(status(&respond(b"")), "HTTP/1.0 400 Bad Request");
+    }
+
+    #[test_case]
+    fn pages_served_concurrently() {
+        // the connections linger in the table after the test
+        testing::keep_allocations(|| {
+            let listener = TcpListener::bind(8080).unwrap();
+            let get = |path: &'static str| async move {
+                let stream = TcpStream::connect(SocketAddress {
+                    addr: LOOPBACK_NETWORK.address,
+                    port: 8080,
+                })
+                .await
+                .unwrap();
+                let request = format!("GET {} HTTP/1.0\r\n\r\n", path);
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = Vec::new();
+                let mut buf = [0; 512];
+                loop {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        read => response.extend_from_slice(&buf[..read]),
+                    }
+                }
+                response
+            };
+            let clients = future::join(get("/"), get("/missing"));
+            let server = serve(&listener);
+            pin_mut!(clients, server);
+            let (page, missing) = match net::tests::run(future::select(clients, server)) {
+                Either::Left((responses, _)) => responses,
+                Either::Right(_) => unreachable!(),
+            };
+            assert_eq!(status(&page), "HTTP/1.0 200 OK");
+            assert!(page.ends_with(b"</html>\n"));
+            assert_eq!(status(&missing), "HTTP/1.0 404 Not Found");
+        });
+    }
+}
@@ -0,0 +1,261 @@
+//! IPv4 (RFC 791), packets between the interfaces and the transport protocols.
+//!
+//! Packets are sent from the interface whose subnet holds the destination, or else to the gateway
+//! of the first interface with one, see [route]. Received packets for the address of the interface
+//! or a broadcast address are handed to the protocol of their header, [super::tcp] or
+//! [super::udp]. Options are skipped, fragments are dropped: neither QEMU nor the protocols of the
+//! kernel send any.
+
+use alloc::vec::Vec;
+use core::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use super::{arp, interfaces, tcp, udp, Interface, Ipv4Address, NetError, ETHERTYPE_IPV4};
+use crate::metrics::Counter;
+
+/// The size of a header without options.
+pub const HEADER_SIZE: usize = 20;
+/// The protocol number of TCP.
+pub const PROTOCOL_TCP: u8 = 6;
+/// The protocol number of UDP.
+pub const PROTOCOL_UDP: u8 = 17;
+/// The time to live of the packets sent.
+pub const DEFAULT_TTL: u8 = 64;
+
+/// The identification of the next packet sent.
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+static RECEIVED: Counter = Counter::new("ipv4.packets_received");
+static SENT: Counter = Counter::new("ipv4.packets_sent");
+static DROPPED: Counter = Counter::new("ipv4.packets_dropped");
+
+/// What the transport protocols need of the header of a received packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// The sender.
+    pub src: Ipv4Address,
+    /// The destination.
+    pub dst: Ipv4Address,
+    /// The protocol of the payload, e.g. [PROTOCOL_UDP].
+    pub protocol: u8,
+}
+
+/// The Internet checksum (RFC 1071) of the concatenation of `parts`, the one's complement of the
+/// one's complement sum of its 16-bit big endian words, an odd last byte padded with zero.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut pending: Option<u8> = None;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match pending.take() {
+            Some(high) => sum += u32::from(u16::from_be_bytes([high, byte])),
+            None => pending = Some(byte),
+        }
+    }
+    if let Some(high) = pending {
+        sum += u32::from(high) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The pseudo header of the checksums of UDP and TCP over IPv4 for a segment of `len` bytes.
+pub fn pseudo_header(header: &Header, len: usize) -> [u8; 12] {
+    let mut bytes = [0; 12];
+    bytes[0..4].copy_from_slice(&header.src.0);
+    bytes[4..8].copy_from_slice(&header.dst.0);
+    bytes[9] = header.protocol;
+    bytes[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+    bytes
+}
+
+/// Parse the IPv4 packet `packet`, returns its header and its payload. `None` if the packet is
+/// malformed, its header checksum is wrong or it is a fragment.
+pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if header_len < HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    if checksum(&[&packet[..header_len]]) != 0 {
+        return None;
+    }
+    // more fragments or a fragment offset
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+        return None;
+    }
+
+    let address_at =
+        |offset: usize| Ipv4Address(<[u8; 4]>::try_from(&packet[offset..offset + 4]).unwrap());
+    let header = Header {
+        src: address_at(12),
+        dst: address_at(16),
+        protocol: packet[9],
+    };
+    Some((header, &packet[header_len..total_len]))
+}
+
+/// The packet carrying `payload` with the header `header`, without options.
+pub fn packet(header: &Header, payload: &[u8]) -> Vec<u8> {
+    let total_len = HEADER_SIZE + payload.len();
+    let mut packet = Vec::with_capacity(total_len);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    // don't fragment
+    packet.extend_from_slice(&[0x40, 0]);
+    packet.extend_from_slice(&[DEFAULT_TTL, header.protocol, 0, 0]);
+    packet.extend_from_slice(&header.src.0);
+    packet.extend_from_slice(&header.dst.0);
+    let checksum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// The interface to send a packet to `dst` from and the next hop on its subnet, `dst` itself or
+/// the gateway.
+pub fn route(dst: Ipv4Address) -> Result<(&'static Interface, Ipv4Address), NetError> {
+    let configured: Vec<_> = interfaces()
+        .into_iter()
+        .filter_map(|interface| Some((interface, interface.ipv4()?)))
+        .collect();
+    if let Some(&(interface, _)) = configured
+        .iter()
+        .find(|(_, config)| dst == Ipv4Address::BROADCAST || config.is_local(dst))
+    {
+        return Ok((interface, dst));
+    }
+    configured
+        .iter()
+        .find_map(|&(interface, config)| Some((interface, config.gateway?)))
+        .ok_or(NetError::NetworkUnreachable)
+}
+
+/// The source address of the packets sent to `dst`, the address of the interface they leave
+/// from.
+pub fn source_for(dst: Ipv4Address) -> Result<Ipv4Address, NetError> {
+    let (interface, _) = route(dst)?;
+    Ok(interface.ipv4().ok_or(NetError::NoAddress)?.address)
+}
+
+/// Send `payload` of `protocol` to `dst` from `src`, resolving the next hop with ARP.
+pub async fn send(
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let (interface, next_hop) = route(dst)?;
+    let mac = arp::resolve(interface, next_hop).await?;
+    let packet = packet(&Header { src, dst, protocol }, payload);
+    interface.send_to(mac, ETHERTYPE_IPV4, &packet)?;
+    SENT.inc();
+    Ok(())
+}
+
+/// Send `payload` of `protocol` to `dst` from `src` without waiting for ARP. If the next hop isn't
+/// cached, it's asked for and the packet is dropped with [NetError::WouldBlock], for the protocols
+/// retransmitting their packets such as TCP.
+pub fn try_send(
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let (interface, next_hop) = route(dst)?;
+    let mac = match arp::lookup(interface, next_hop) {
+        Some(mac) => mac,
+        None => {
+            arp::request(interface, next_hop)?;
+            return Err(NetError::WouldBlock);
+        }
+    };
+    let packet = packet(&Header { src, dst, protocol }, payload);
+    interface.send_to(mac, ETHERTYPE_IPV4, &packet)?;
+    SENT.inc();
+    Ok(())
+}
+
+/// Handle the IPv4 packet `packet` received by `interface`, hands the packets for the interface
+/// to their protocol.
+pub fn receive(interface: &'static Interface, packet: &[u8]) {
+    let config = match interface.ipv4() {
+        Some(config) => config,
+        None => return,
+    };
+    let (header, payload) = match parse(packet) {
+        Some(parsed) => parsed,
+        None => {
+            DROPPED.inc();
+            return;
+        }
+    };
+    let subnet_broadcast = Ipv4Address(
+        (u32::from_be_bytes(config.address.0) | !u32::from_be_bytes(config.netmask().0))
+            .to_be_bytes(),
+    );
+    if header.dst != config.address
+        && header.dst != Ipv4Address::BROADCAST
+        && header.dst != subnet_broadcast
+    {
+        DROPPED.inc();
+        return;
+    }
+
+    RECEIVED.inc();
+    match header.protocol {
+        PROTOCOL_TCP => tcp::receive(&header, payload),
+        PROTOCOL_UDP => udp::receive(&header, payload),
+        _ => DROPPED.inc(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{tests::GATEWAY_IP, USER_NETWORK};
+
+    #[test_case]
+    fn checksums_computed() {
+        // the example of RFC 1071
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&[&data]), !0xddf2);
+        assert_eq!(checksum(&[&data[..3], &data[3..]]), !0xddf2);
+        assert_eq!(checksum(&[&[0xab]]), !0xab00);
+    }
+
+    #[test_case]
+    fn packets_built_and_parsed() {
+        let header = Header {
+            src: USER_NETWORK.address,
+            dst: Ipv4Address(GATEWAY_IP),
+            protocol: PROTOCOL_UDP,
+        };
+        let mut packet = packet(&header, b"payload");
+        assert_eq!(packet.len(), HEADER_SIZE + 7);
+        assert_eq!(parse(&packet), Some((header, &b"payload"[..])));
+        // trailing padding of short Ethernet frames
+        packet.extend_from_slice(&[0; 4]);
+        assert_eq!(parse(&packet).unwrap().1, b"payload");
+
+        packet[8] = 1;
+        assert_eq!(parse(&packet), None);
+    }
+
+    #[test_case]
+    fn routed_through_gateway() {
+        let gateway = Ipv4Address(GATEWAY_IP);
+        let (interface, next_hop) = route(gateway).unwrap();
+        assert_eq!((interface.name(), next_hop), ("eth0", gateway));
+        let (_, next_hop) = route(Ipv4Address([192, 0, 2, 1])).unwrap();
+        assert_eq!(next_hop, gateway);
+        assert_eq!(source_for(gateway), Ok(USER_NETWORK.address));
+    }
+}
@@ -0,0 +1,202 @@
+//! The loopback interface `lo`: the frames sent to it are received by it, so the kernel can talk
+//! to itself at [LOOPBACK_NETWORK], e.g. to test its sockets without a network.
+//!
+//! Sent frames are queued and handed to the protocols later by the [super::receive_task] of the
+//! interface, never on the stack of the sender: a protocol answering a frame doesn't reenter
+//! itself.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::task::{Context, Poll};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{Ipv4Address, Ipv4Config, MacAddress, NetDevice, NetError, MAX_FRAME_SIZE};
+
+/// The name of the interface.
+pub const NAME: &str = "lo";
+/// The addresses of the interface, `127.0.0.1/8`.
+pub const LOOPBACK_NETWORK: Ipv4Config = Ipv4Config {
+    address: Ipv4Address([127, 0, 0, 1]),
+    prefix_len: 8,
+    gateway: None,
+};
+/// The most frames sent and not received yet.
+pub const QUEUE_SIZE: usize = 256;
+
+static LOOPBACK: OnceCell<Loopback> = OnceCell::uninit();
+
+/// A card whose transmitted frames are its received frames.
+pub struct Loopback {
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    /// The reader of the received frames.
+    waker: AtomicWaker,
+}
+
+/// Register the loopback interface.
+pub fn init() {
+    LOOPBACK.init_once(|| Loopback {
+        queue: Mutex::new(VecDeque::with_capacity(QUEUE_SIZE)),
+        waker: AtomicWaker::new(),
+    });
+    if let Ok(loopback) = LOOPBACK.try_get() {
+        super::register_as(NAME, loopback, LOOPBACK_NETWORK);
+    }
+}
+
+impl NetDevice for Loopback {
+    fn mac_address(&self) -> MacAddress {
+        MacAddress([0; 6])
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge(frame.len()));
+        }
+        {
+            let mut queue = self.queue.lock();
+            if queue.len() >= QUEUE_SIZE {
+                return Err(NetError::QueueFull);
+            }
+            queue.push_back(frame.to_vec());
+        }
+        self.waker.wake();
+        Ok(())
+    }
+
+    fn try_receive(&self) -> Option<Vec<u8>> {
+        self.queue.lock().pop_front()
+    }
+
+    fn poll_receive(&self, cx: &mut Context<'_>) -> Poll<Vec<u8>> {
+        if let Some(frame) = self.try_receive() {
+            return Poll::Ready(frame);
+        }
+
+        self.waker.register(cx.waker());
+
+        // a frame may have been sent after the first check
+        match self.try_receive() {
+            Some(frame) => {
+                self.waker.take();
+                Poll::Ready(frame)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{
+            self,
+            tcp::{TcpListener, TcpStream},
+            udp::UdpSocket,
+            SocketAddress,
+        },
+        testing,
+    };
+    use futures_util::future;
+
+    fn device() -> &'static dyn NetDevice {
+        let device = net::get(NAME).unwrap();
+        // the frames left behind by earlier tests
+        while device.try_receive().is_some() {}
+        device
+    }
+
+    #[test_case]
+    fn frames_reflected() {
+        let device = device();
+        assert!(device.is_loopback());
+        assert_eq!(device.try_receive(), None);
+        device.send(b"frame").unwrap();
+        assert_eq!(device.try_receive().as_deref(), Some(&b"frame"[..]));
+
+        for _ in 0..QUEUE_SIZE {
+            device.send(&[0; 60]).unwrap();
+        }
+        assert_eq!(device.send(&[0; 60]), Err(NetError::QueueFull));
+        assert_eq!(
+            device.send(&[0; MAX_FRAME_SIZE + 1]),
+            Err(NetError::FrameTooLarge(MAX_FRAME_SIZE + 1))
+        );
+        while device.try_receive().is_some() {}
+    }
+
+    #[test_case]
+    fn datagrams_looped_back() {
+        device();
+        let (a, b) = testing::keep_allocations(|| {
+            (UdpSocket::bind(0).unwrap(), UdpSocket::bind(0).unwrap())
+        });
+        let to_b = SocketAddress {
+            addr: LOOPBACK_NETWORK.address,
+            port: b.local_port(),
+        };
+        let mut buf = [0; 8];
+        let received = net::tests::run(async {
+            a.send_to(b"ping", to_b).await.unwrap();
+            b.recv_from(&mut buf).await
+        });
+        let from_a = SocketAddress {
+            addr: LOOPBACK_NETWORK.address,
+            port: a.local_port(),
+        };
+        assert_eq!(received, Ok((4, from_a)));
+        assert_eq!(&buf[..4], b"ping");
+    }
+
+    #[test_case]
+    fn streams_looped_back() {
+        device();
+        // the connections linger in the table after the test
+        testing::keep_allocations(|| {
+            let listener = TcpListener::bind(7007).unwrap();
+            let server = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        read => received.extend_from_slice(&buf[..read]),
+                    }
+                }
+                stream.write_all(&received).await.unwrap();
+                received.len()
+            };
+            let client = async {
+                let stream = TcpStream::connect(SocketAddress {
+                    addr: LOOPBACK_NETWORK.address,
+                    port: 7007,
+                })
+                .await
+                .unwrap();
+                // more than a window and a send buffer
+                let sent: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+                stream.write_all(&sent).await.unwrap();
+                stream.shutdown();
+                let mut echoed = Vec::new();
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        read => echoed.extend_from_slice(&buf[..read]),
+                    }
+                }
+                echoed == sent
+            };
+            let (received, echoed) = net::tests::run(future::join(server, client));
+            assert_eq!(received, 40_000);
+            assert!(echoed);
+        });
+    }
+}
@@ -0,0 +1,294 @@
+//! Packet capture in the pcap format of libpcap, for Wireshark or tcpdump on the host.
+//!
+//! A capture taps the frames sent and received by the interfaces, or by a single one, and writes
+//! them as pcap records to a [Target]: the second serial port, saved by QEMU to a file of the
+//! host with `-serial file:capture.pcap`, or a file of the virtual filesystem. The frames
+//! received by the loopback interface are the frames it sent, they are captured once.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+
+use super::Interface;
+use crate::{
+    fs::{self, FsError, Node, NodeKind},
+    metrics::Counter,
+    serial, time, warn,
+};
+
+/// The magic number of pcap files with timestamps in microseconds.
+pub const MAGIC: u32 = 0xa1b2_c3d4;
+/// The link type of Ethernet frames.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// The largest frame captured whole, frames are never larger.
+pub const SNAPLEN: u32 = 65535;
+/// The size of the header of a pcap file.
+pub const FILE_HEADER_SIZE: usize = 24;
+/// The size of the header of a record.
+pub const RECORD_HEADER_SIZE: usize = 16;
+
+/// Set while a capture runs, spares the frames the lock of [CAPTURE] otherwise.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+static CAPTURED: Counter = Counter::new("pcap.frames_captured");
+
+/// Where a capture writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The second serial port.
+    Serial,
+    /// The file at the path, created or truncated.
+    File(String),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Serial => write!(f, "COM2"),
+            Target::File(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+/// An error starting a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureError {
+    /// A capture is already running.
+    Busy,
+    /// The interface doesn't exist.
+    UnknownInterface,
+    /// The file can't be written.
+    Fs(FsError),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Busy => write!(f, "a capture is already running"),
+            CaptureError::UnknownInterface => write!(f, "no such interface"),
+            CaptureError::Fs(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<FsError> for CaptureError {
+    fn from(err: FsError) -> Self {
+        CaptureError::Fs(err)
+    }
+}
+
+/// A running capture, as returned by [status].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureInfo {
+    /// Where the capture writes.
+    pub target: Target,
+    /// The interface captured, `None` for all of them.
+    pub interface: Option<String>,
+    /// The frames captured so far.
+    pub frames: u64,
+}
+
+enum Sink {
+    Serial,
+    File { node: Arc<dyn Node>, len: u64 },
+}
+
+struct Capture {
+    info: CaptureInfo,
+    sink: Sink,
+}
+
+impl Sink {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), FsError> {
+        match self {
+            Sink::Serial => serial::write_com2(bytes),
+            Sink::File { node, len } => {
+                let mut written = 0;
+                while written < bytes.len() {
+                    written += node.write_at(*len + written as u64, &bytes[written..])?;
+                }
+                *len += written as u64;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The header of a pcap file of Ethernet frames, little endian as the kernel.
+pub fn file_header() -> [u8; FILE_HEADER_SIZE] {
+    let mut header = [0; FILE_HEADER_SIZE];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    // version 2.4, the time zone and the accuracy of the timestamps are 0
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// The header of the record of `frame` captured at `unix_secs` and `micros`.
+pub fn record_header(unix_secs: u64, micros: u32, frame: &[u8]) -> [u8; RECORD_HEADER_SIZE] {
+    let mut header = [0; RECORD_HEADER_SIZE];
+    header[0..4].copy_from_slice(&(unix_secs as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&micros.to_le_bytes());
+    header[8..12].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+    header
+}
+
+/// Start capturing the frames of `interface`, or of every interface if `None`, to `target`.
+pub fn start(target: Target, interface: Option<&str>) -> Result<(), CaptureError> {
+    let mut capture = CAPTURE.lock();
+    if capture.is_some() {
+        return Err(CaptureError::Busy);
+    }
+    if let Some(name) = interface {
+        super::get(name).ok_or(CaptureError::UnknownInterface)?;
+    }
+
+    let mut sink = match &target {
+        Target::Serial => Sink::Serial,
+        Target::File(path) => {
+            let node = match fs::lookup(path) {
+                Ok(node) => node,
+                Err(FsError::NotFound) => fs::create(path, NodeKind::File)?,
+                Err(err) => return Err(err.into()),
+            };
+            match node.metadata().kind {
+                NodeKind::Directory => return Err(FsError::IsADirectory.into()),
+                NodeKind::File => node.set_len(0)?,
+                NodeKind::CharDevice => {}
+            }
+            Sink::File { node, len: 0 }
+        }
+    };
+    sink.write(&file_header())?;
+
+    *capture = Some(Capture {
+        info: CaptureInfo {
+            target,
+            interface: interface.map(String::from),
+            frames: 0,
+        },
+        sink,
+    });
+    ACTIVE.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stop the running capture, returns what it captured. `None` if no capture was running.
+pub fn stop() -> Option<CaptureInfo> {
+    let capture = CAPTURE.lock().take()?;
+    ACTIVE.store(false, Ordering::Release);
+    Some(capture.info)
+}
+
+/// The running capture, `None` if there is none.
+pub fn status() -> Option<CaptureInfo> {
+    CAPTURE.lock().as_ref().map(|capture| capture.info.clone())
+}
+
+/// Capture `frame`, sent or received by `interface`, if a capture of it runs.
+pub(super) fn tap(interface: &Interface, frame: &[u8], received: bool) {
+    if !ACTIVE.load(Ordering::Acquire) || (received && interface.device().is_loopback()) {
+        return;
+    }
+    let mut capture = CAPTURE.lock();
+    let running = match capture.as_mut() {
+        Some(running) => running,
+        None => return,
+    };
+    if let Some(name) = &running.info.interface {
+        if name != interface.name() {
+            return;
+        }
+    }
+
+    let uptime = time::uptime();
+    let header = record_header(time::now().to_unix(), uptime.subsec_micros(), frame);
+    let written = running
+        .sink
+        .write(&header)
+        .and_then(|()| running.sink.write(frame));
+    match written {
+        Ok(()) => {
+            running.info.frames += 1;
+            CAPTURED.inc();
+        }
+        Err(err) => {
+            // e.g. the file is full, readers of pcap files ignore a truncated last record
+            warn!("capture to {} stopped: {}", running.info.target, err);
+            *capture = None;
+            ACTIVE.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Split a pcap file into the frames of its records, `None` if it's malformed.
+pub fn parse(file: &[u8]) -> Option<Vec<&[u8]>> {
+    if file.len() < FILE_HEADER_SIZE || file[0..4] != MAGIC.to_le_bytes() {
+        return None;
+    }
+    let mut frames = Vec::new();
+    let mut rest = &file[FILE_HEADER_SIZE..];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&rest[8..12]);
+        let len = u32::from_le_bytes(len) as usize;
+        let record = rest.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+        frames.push(record);
+        rest = &rest[RECORD_HEADER_SIZE + len..];
+    }
+    Some(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{self, loopback::LOOPBACK_NETWORK, udp::UdpSocket, SocketAddress},
+        testing,
+    };
+
+    #[test_case]
+    fn datagrams_captured_to_file() {
+        let path = "/tmp/capture.pcap";
+        assert_eq!(
+            start(Target::File(String::from(path)), Some("eth9")),
+            Err(CaptureError::UnknownInterface)
+        );
+        // the file stays in the ramfs after the test
+        testing::keep_allocations(|| {
+            start(Target::File(String::from(path)), Some("lo")).unwrap();
+            assert_eq!(start(Target::Serial, None), Err(CaptureError::Busy));
+
+            let socket = UdpSocket::bind(0).unwrap();
+            let to_self = SocketAddress {
+                addr: LOOPBACK_NETWORK.address,
+                port: socket.local_port(),
+            };
+            let mut buf = [0; 8];
+            net::tests::run(async {
+                socket.send_to(b"captured", to_self).await.unwrap();
+                socket.recv_from(&mut buf).await.unwrap()
+            });
+            drop(socket);
+
+            let info = stop().unwrap();
+            assert_eq!((info.interface.as_deref(), info.frames), (Some("lo"), 1));
+            assert_eq!(stop(), None);
+            let file = fs::read(path).unwrap();
+            let frames = parse(&file).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert!(frames[0].ends_with(b"captured"));
+        });
+    }
+}
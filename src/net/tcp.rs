@@ -0,0 +1,1469 @@
+//! TCP (RFC 793), reliable byte streams between the ports of the kernel and of remote hosts.
+//!
+//! A [TcpListener] accepts the connections to its port, [TcpStream::connect] opens one to a remote
+//! port. Each connection is a control block updated by the segments received by the receive tasks
+//! of the interfaces, by the reads and writes of its stream and by [timer_task], which retransmits
+//! the oldest unacknowledged segment with an exponential backoff. The data sent is bounded by the
+//! window advertised by the peer, the window advertised to the peer is the free space of the
+//! receive buffer. Segments received out of order are dropped and answered with the sequence
+//! number expected, the peer sends them again.
+//!
+//! Segments are sent with [ipv4::try_send] and never wait for ARP: a segment to a host not
+//! resolved yet is lost and retransmitted. Dropping a stream closes the connection, which lingers
+//! until the peer acknowledged the data written and closed its side as well.
+
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{fmt, task::Poll, task::Waker, time::Duration};
+
+use futures_util::{future, task::AtomicWaker};
+use spin::Mutex;
+
+use super::{
+    arp,
+    ipv4::{self, Header, PROTOCOL_TCP},
+    udp::EPHEMERAL_PORTS,
+    NetError, SocketAddress,
+};
+use crate::{
+    metrics::{Counter, Gauge},
+    random, time,
+};
+
+/// The size of the header of a segment without options.
+pub const HEADER_SIZE: usize = 20;
+/// The most bytes of payload in a segment, what fits in an Ethernet frame. Announced in the SYN.
+pub const MSS: usize = 1460;
+/// The most bytes of payload in a segment to a peer announcing no MSS (RFC 1122).
+pub const DEFAULT_MSS: usize = 536;
+/// The most bytes written to a stream and not acknowledged yet.
+pub const SEND_BUFFER_SIZE: usize = 16 * 1024;
+/// The most bytes received by a stream and not read yet, the largest window advertised.
+pub const RECEIVE_BUFFER_SIZE: usize = 16 * 1024;
+/// The most established connections waiting to be accepted by a listener, SYNs beyond are ignored.
+pub const BACKLOG: usize = 16;
+/// The retransmission timeout of the first retransmission (RFC 6298).
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// The retransmission timeout doubles up to this.
+pub const MAX_RTO: Duration = Duration::from_secs(16);
+/// The retransmissions of a segment before the connection is given up.
+pub const MAX_RETRANSMISSIONS: u32 = 6;
+/// How long a closed connection lingers to acknowledge a retransmitted FIN, two maximum segment
+/// lifetimes, kept short as the kernel doesn't reuse its ports quickly.
+pub const TIME_WAIT: Duration = Duration::from_secs(1);
+/// How long a connection whose stream was dropped waits for the FIN of the peer.
+pub const FIN_WAIT_2_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+type Connection = Arc<Mutex<Tcb>>;
+
+/// The connections by local port and remote address. Locked before the control blocks.
+static CONNECTIONS: Mutex<BTreeMap<(u16, SocketAddress), Connection>> = Mutex::new(BTreeMap::new());
+/// The listeners by port. Locked before [CONNECTIONS].
+static LISTENERS: Mutex<BTreeMap<u16, Arc<Listener>>> = Mutex::new(BTreeMap::new());
+/// The ephemeral port tried first by the next connect.
+static NEXT_EPHEMERAL: Mutex<u16> = Mutex::new(*EPHEMERAL_PORTS.start());
+/// Wakes [timer_task] when the retransmission deadline of a connection changed.
+static TIMER: AtomicWaker = AtomicWaker::new();
+
+static RECEIVED: Counter = Counter::new("tcp.segments_received");
+static SENT: Counter = Counter::new("tcp.segments_sent");
+static DROPPED: Counter = Counter::new("tcp.segments_dropped");
+static RETRANSMITTED: Counter = Counter::new("tcp.retransmissions");
+static RESETS_SENT: Counter = Counter::new("tcp.resets_sent");
+static LISTENING: Gauge = Gauge::new("tcp.listeners");
+static OPEN: Gauge = Gauge::new("tcp.connections");
+
+/// `a` comes before `b` in the sequence space, modulo 2^32.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// A random initial sequence number, so segments of an earlier connection between the same ports
+/// are unlikely to be accepted.
+fn initial_sequence() -> u32 {
+    random::u64() as u32
+}
+
+/// A TCP segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The port of the sender.
+    pub src_port: u16,
+    /// The port of the receiver.
+    pub dst_port: u16,
+    /// The sequence number of the first byte of the payload, or of the SYN.
+    pub seq: u32,
+    /// The next sequence number expected by the sender, if [Segment::flags] has ACK.
+    pub ack: u32,
+    /// The FIN, SYN, RST, PSH and ACK bits.
+    pub flags: u8,
+    /// The free space of the receive buffer of the sender.
+    pub window: u16,
+    /// The maximum segment size option, only in SYNs.
+    pub mss: Option<u16>,
+    /// The data.
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    /// Parse the segment `bytes` of the packet with the header `header`, `None` if it is malformed
+    /// or its checksum is wrong. Unknown options are skipped.
+    pub fn parse(header: &Header, bytes: &[u8]) -> Option<Segment> {
+        if bytes.len() < HEADER_SIZE
+            || ipv4::checksum(&[&ipv4::pseudo_header(header, bytes.len()), bytes]) != 0
+        {
+            return None;
+        }
+        let data_offset = usize::from(bytes[12] >> 4) * 4;
+        if data_offset < HEADER_SIZE || data_offset > bytes.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &bytes[HEADER_SIZE..data_offset];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = usize::from(*options.get(1)?);
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        Some(Segment {
+            src_port: u16_at(0),
+            dst_port: u16_at(2),
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: bytes[13] & (FIN | SYN | RST | PSH | ACK),
+            window: u16_at(14),
+            mss,
+            payload: bytes[data_offset..].to_vec(),
+        })
+    }
+
+    /// The segment in a packet with the header `header`, checksummed.
+    pub fn to_bytes(&self, header: &Header) -> Vec<u8> {
+        let options_len = if self.mss.is_some() { 4 } else { 0 };
+        let len = HEADER_SIZE + options_len + self.payload.len();
+        let mut bytes = Vec::with_capacity(len);
+        bytes.extend_from_slice(&self.src_port.to_be_bytes());
+        bytes.extend_from_slice(&self.dst_port.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.ack.to_be_bytes());
+        bytes.extend_from_slice(&[(((HEADER_SIZE + options_len) / 4) << 4) as u8, self.flags]);
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        // checksum and urgent pointer
+        bytes.extend_from_slice(&[0; 4]);
+        if let Some(mss) = self.mss {
+            bytes.extend_from_slice(&[OPTION_MSS, 4]);
+            bytes.extend_from_slice(&mss.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        let checksum = ipv4::checksum(&[&ipv4::pseudo_header(header, len), &bytes]);
+        bytes[16..18].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    /// The sequence numbers taken by the segment: its payload, SYN and FIN.
+    fn seq_len(&self) -> u32 {
+        self.payload.len() as u32
+            + u32::from(self.flags & SYN != 0)
+            + u32::from(self.flags & FIN != 0)
+    }
+}
+
+/// The state of a connection, named as in RFC 793.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// A SYN was sent, waiting for the SYN of the peer.
+    SynSent,
+    /// The SYN of the peer was answered, waiting for the acknowledgement of ours.
+    SynReceived,
+    /// Both sides can send.
+    Established,
+    /// The FIN of the kernel was sent, not acknowledged yet.
+    FinWait1,
+    /// The FIN of the kernel was acknowledged, waiting for the FIN of the peer.
+    FinWait2,
+    /// The peer closed its side, the kernel can still send.
+    CloseWait,
+    /// Both sides sent a FIN at the same time, waiting for the acknowledgement of ours.
+    Closing,
+    /// The peer closed first, waiting for the acknowledgement of the FIN of the kernel.
+    LastAck,
+    /// Both sides closed, acknowledging a retransmitted FIN of the peer for [TIME_WAIT].
+    TimeWait,
+    /// The connection is over.
+    Closed,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::SynSent => "SYN_SENT",
+            State::SynReceived => "SYN_RECEIVED",
+            State::Established => "ESTABLISHED",
+            State::FinWait1 => "FIN_WAIT_1",
+            State::FinWait2 => "FIN_WAIT_2",
+            State::CloseWait => "CLOSE_WAIT",
+            State::Closing => "CLOSING",
+            State::LastAck => "LAST_ACK",
+            State::TimeWait => "TIME_WAIT",
+            State::Closed => "CLOSED",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The transmission control block of a connection. The events update it and [Tcb::output] then
+/// builds the segments to send, so the state machine runs without a network in the tests.
+struct Tcb {
+    state: State,
+    local: SocketAddress,
+    remote: SocketAddress,
+
+    /// The initial sequence number of the kernel.
+    iss: u32,
+    /// The oldest sequence number not acknowledged.
+    snd_una: u32,
+    /// The next sequence number to send.
+    snd_nxt: u32,
+    /// The window advertised by the peer, from `snd_una`.
+    snd_wnd: u32,
+    /// The largest payload the peer accepts.
+    send_mss: usize,
+    /// The bytes written and not acknowledged, from `snd_una`.
+    send_buffer: VecDeque<u8>,
+    /// The stream was closed, a FIN follows the data written.
+    fin_queued: bool,
+    /// The FIN was sent, it takes the sequence number after the send buffer.
+    fin_sent: bool,
+
+    /// The next sequence number expected from the peer.
+    rcv_nxt: u32,
+    /// The bytes received and not read.
+    recv_buffer: VecDeque<u8>,
+    /// The peer closed its side, reads end once the receive buffer is empty.
+    fin_received: bool,
+    /// A segment was received that must be acknowledged.
+    ack_pending: bool,
+
+    /// The tick of the next retransmission, zero window probe or end of [State::TimeWait].
+    deadline: Option<u64>,
+    /// The retransmission timeout in ticks.
+    rto: u64,
+    /// The retransmissions of the oldest unacknowledged segment.
+    retransmissions: u32,
+    /// The timer expired, the oldest unacknowledged segment is sent again.
+    retransmit: bool,
+    /// The timer expired with a zero window, a byte beyond it is sent.
+    probe: bool,
+    /// A byte was sent beyond the zero window, it is sent again once the window opens.
+    probing: bool,
+    /// The connection was aborted, a RST is sent.
+    reset_pending: bool,
+    /// The stream was dropped, nothing reads the connection anymore.
+    orphaned: bool,
+    /// Why the connection was aborted.
+    error: Option<NetError>,
+
+    /// The task reading the stream.
+    reader: Option<Waker>,
+    /// The task writing the stream or waiting for the connection.
+    writer: Option<Waker>,
+}
+
+impl Tcb {
+    fn new(state: State, local: SocketAddress, remote: SocketAddress, iss: u32) -> Tcb {
+        Tcb {
+            state,
+            local,
+            remote,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            send_mss: DEFAULT_MSS,
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            fin_received: false,
+            ack_pending: false,
+            deadline: None,
+            rto: time::duration_to_ticks(INITIAL_RTO),
+            retransmissions: 0,
+            retransmit: false,
+            probe: false,
+            probing: false,
+            reset_pending: false,
+            orphaned: false,
+            error: None,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    /// An active open from `local` to `remote`, the SYN is sent by the first [Tcb::output].
+    fn connect(local: SocketAddress, remote: SocketAddress, iss: u32) -> Tcb {
+        Tcb::new(State::SynSent, local, remote, iss)
+    }
+
+    /// A passive open answering `syn` from `remote`, the SYN-ACK is sent by the first
+    /// [Tcb::output].
+    fn accept(local: SocketAddress, remote: SocketAddress, iss: u32, syn: &Segment) -> Tcb {
+        let mut tcb = Tcb::new(State::SynReceived, local, remote, iss);
+        tcb.rcv_nxt = syn.seq.wrapping_add(1);
+        tcb.snd_wnd = u32::from(syn.window);
+        tcb.send_mss = syn.mss.map_or(DEFAULT_MSS, usize::from).min(MSS);
+        tcb
+    }
+
+    /// The window advertised to the peer.
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER_SIZE - self.recv_buffer.len()).min(usize::from(u16::MAX)) as u16
+    }
+
+    /// The bytes of the send buffer in flight, sent and not acknowledged.
+    fn in_flight(&self) -> usize {
+        let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        sent - usize::from(self.fin_sent && sent > 0)
+    }
+
+    /// The FIN of the kernel was acknowledged.
+    fn fin_acked(&self) -> bool {
+        self.fin_sent && self.snd_una == self.snd_nxt
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.reader.take().into_iter().chain(self.writer.take()) {
+            waker.wake();
+        }
+    }
+
+    /// Give the connection up with `error`, the reads and writes of the stream then fail.
+    fn abort(&mut self, error: NetError) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.send_buffer.clear();
+        self.deadline = None;
+        self.wake_all();
+    }
+
+    /// Abort the connection and tell the peer with a RST.
+    fn reset(&mut self) {
+        self.reset_pending = true;
+        self.abort(NetError::ConnectionReset);
+    }
+
+    fn segment(&self, seq: u32, flags: u8, payload: Vec<u8>) -> Segment {
+        Segment {
+            src_port: self.local.port,
+            dst_port: self.remote.port,
+            seq,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.window(),
+            mss: None,
+            payload,
+        }
+    }
+
+    /// Update the connection with the segment `segment` received at the tick `now`.
+    fn on_segment(&mut self, segment: &Segment, now: u64) {
+        match self.state {
+            State::Closed => return,
+            State::SynSent => return self.on_syn_sent(segment),
+            _ => {}
+        }
+
+        if segment.flags & RST != 0 {
+            // only a reset within the window, so a stale one can't close the connection
+            let offset = segment.seq.wrapping_sub(self.rcv_nxt) as usize;
+            if offset < usize::from(self.window()).max(1) {
+                self.abort(NetError::ConnectionReset);
+            }
+            return;
+        }
+        if segment.flags & SYN != 0 {
+            // a retransmitted SYN, our SYN-ACK or our ACK to it was lost
+            if self.state == State::SynReceived {
+                self.retransmit = true;
+            } else {
+                self.ack_pending = true;
+            }
+            return;
+        }
+        if self.state == State::TimeWait {
+            // a retransmitted FIN, our last ACK was lost
+            self.ack_pending = true;
+            return;
+        }
+
+        // the part of the segment already received is skipped
+        let mut payload = &segment.payload[..];
+        let mut fin = segment.flags & FIN != 0;
+        let mut in_order = segment.seq == self.rcv_nxt;
+        if seq_lt(segment.seq, self.rcv_nxt) {
+            let skipped = self.rcv_nxt.wrapping_sub(segment.seq) as usize;
+            if skipped <= payload.len() {
+                payload = &payload[skipped..];
+                in_order = true;
+            } else {
+                fin = false;
+            }
+        }
+        if !in_order || (payload.is_empty() && !fin && segment.seq_len() > 0) {
+            self.ack_pending = true;
+        }
+
+        if segment.flags & ACK == 0 {
+            return;
+        }
+        self.on_ack(segment, now);
+
+        if in_order
+            && matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            let room = RECEIVE_BUFFER_SIZE - self.recv_buffer.len();
+            let accepted = payload.len().min(room);
+            if accepted > 0 {
+                self.recv_buffer.extend(&payload[..accepted]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+                if let Some(reader) = self.reader.take() {
+                    reader.wake();
+                }
+            }
+            if !payload.is_empty() {
+                self.ack_pending = true;
+            }
+            // the FIN follows the data dropped for lack of room
+            if fin && accepted == payload.len() {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.fin_received = true;
+                self.ack_pending = true;
+                self.wake_all();
+                self.state = match self.state {
+                    State::Established => State::CloseWait,
+                    State::FinWait1 if !self.fin_acked() => State::Closing,
+                    _ => {
+                        self.deadline = Some(now + time::duration_to_ticks(TIME_WAIT));
+                        State::TimeWait
+                    }
+                };
+            }
+        }
+    }
+
+    /// Handle the answer to the SYN of an active open.
+    fn on_syn_sent(&mut self, segment: &Segment) {
+        let acceptable = segment.flags & ACK != 0 && segment.ack == self.snd_nxt;
+        if segment.flags & RST != 0 {
+            if acceptable {
+                self.abort(NetError::ConnectionRefused);
+            }
+            return;
+        }
+        // simultaneous opens aren't supported
+        if !acceptable || segment.flags & SYN == 0 {
+            return;
+        }
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.snd_una = segment.ack;
+        self.snd_wnd = u32::from(segment.window);
+        self.send_mss = segment.mss.map_or(DEFAULT_MSS, usize::from).min(MSS);
+        self.state = State::Established;
+        self.deadline = None;
+        self.retransmissions = 0;
+        self.ack_pending = true;
+        self.wake_all();
+    }
+
+    /// Handle the acknowledgement and the window of `segment`.
+    fn on_ack(&mut self, segment: &Segment, now: u64) {
+        let syn_acked = self.state == State::SynReceived;
+        if syn_acked {
+            if segment.ack != self.snd_nxt {
+                return;
+            }
+            self.state = State::Established;
+        }
+
+        if seq_lt(self.snd_una, segment.ack) && seq_le(segment.ack, self.snd_nxt) {
+            // the SYN and the FIN aren't in the send buffer
+            let fin_acked = self.fin_sent && segment.ack == self.snd_nxt;
+            let acked = segment.ack.wrapping_sub(self.snd_una) as usize
+                - usize::from(syn_acked)
+                - usize::from(fin_acked);
+            self.send_buffer.drain(..acked);
+            self.snd_una = segment.ack;
+            self.retransmissions = 0;
+            self.rto = time::duration_to_ticks(INITIAL_RTO);
+            self.deadline = if self.snd_una == self.snd_nxt {
+                None
+            } else {
+                Some(now + self.rto)
+            };
+            if let Some(writer) = self.writer.take() {
+                writer.wake();
+            }
+        }
+        if seq_le(self.snd_una, segment.ack) {
+            self.snd_wnd = u32::from(segment.window);
+            // the probe wasn't accepted, it's sent with the data following it
+            if self.probing && self.snd_wnd > 0 {
+                self.probing = false;
+                self.snd_nxt = self.snd_una;
+                self.deadline = None;
+            }
+        }
+
+        if self.fin_acked() {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => {
+                    self.state = State::TimeWait;
+                    self.deadline = Some(now + time::duration_to_ticks(TIME_WAIT));
+                }
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle the expiry of the timer at the tick `now`, if it is due.
+    fn on_timer(&mut self, now: u64) {
+        match self.deadline {
+            Some(deadline) if deadline <= now => self.deadline = None,
+            _ => return,
+        }
+        match self.state {
+            State::TimeWait => self.state = State::Closed,
+            State::FinWait2 if self.orphaned => self.state = State::Closed,
+            // the window of the peer stays closed, probed for as long as it takes
+            _ if self.probing || self.snd_nxt == self.snd_una => {
+                self.snd_nxt = self.snd_una;
+                self.rto = (self.rto * 2).min(time::duration_to_ticks(MAX_RTO));
+                self.probe = true;
+            }
+            _ => {
+                self.retransmissions += 1;
+                if self.retransmissions > MAX_RETRANSMISSIONS {
+                    self.abort(NetError::TimedOut);
+                    return;
+                }
+                self.rto = (self.rto * 2).min(time::duration_to_ticks(MAX_RTO));
+                self.retransmit = true;
+            }
+        }
+    }
+
+    /// Read the received bytes into `buf`. `None` if there are none yet, `Ok(0)` once the peer
+    /// closed its side.
+    fn read(&mut self, buf: &mut [u8]) -> Option<Result<usize, NetError>> {
+        if !self.recv_buffer.is_empty() {
+            let window = self.window();
+            let len = buf.len().min(self.recv_buffer.len());
+            for (dst, src) in buf.iter_mut().zip(self.recv_buffer.drain(..len)) {
+                *dst = src;
+            }
+            // the peer stops sending below a segment, tell it there is room again
+            if usize::from(window) < MSS && usize::from(self.window()) >= MSS {
+                self.ack_pending = true;
+            }
+            return Some(Ok(len));
+        }
+        if let Some(error) = self.error {
+            return Some(Err(error));
+        }
+        if self.fin_received || self.state == State::Closed {
+            return Some(Ok(0));
+        }
+        None
+    }
+
+    /// Queue the bytes of `buf` that fit in the send buffer. `None` if it is full.
+    fn write(&mut self, buf: &[u8]) -> Option<Result<usize, NetError>> {
+        if let Some(error) = self.error {
+            return Some(Err(error));
+        }
+        if self.fin_queued || !matches!(self.state, State::Established | State::CloseWait) {
+            return Some(Err(NetError::NotConnected));
+        }
+        let len = buf.len().min(SEND_BUFFER_SIZE - self.send_buffer.len());
+        if len == 0 && !buf.is_empty() {
+            return None;
+        }
+        self.send_buffer.extend(&buf[..len]);
+        Some(Ok(len))
+    }
+
+    /// Close the sending side: a FIN follows the data written. A connection not established yet
+    /// is dropped.
+    fn close(&mut self) {
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                self.state = State::Closed;
+                self.deadline = None;
+                self.wake_all();
+            }
+            State::Established | State::CloseWait => self.fin_queued = true,
+            _ => {}
+        }
+    }
+
+    /// The segments to send at the tick `now`: the SYN, retransmissions, new data within the
+    /// window of the peer, the FIN and acknowledgements. Arms the retransmission timer.
+    fn output(&mut self, now: u64) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        if self.reset_pending {
+            self.reset_pending = false;
+            segments.push(self.segment(self.snd_nxt, RST, Vec::new()));
+        }
+        match self.state {
+            State::Closed => return segments,
+            State::SynSent | State::SynReceived => {
+                if self.snd_nxt == self.iss || self.retransmit {
+                    let flags = if self.state == State::SynSent {
+                        SYN
+                    } else {
+                        SYN | ACK
+                    };
+                    let mut syn = self.segment(self.iss, flags, Vec::new());
+                    syn.mss = Some(MSS as u16);
+                    segments.push(syn);
+                    self.snd_nxt = self.iss.wrapping_add(1);
+                    self.count_retransmission();
+                }
+                self.arm(now);
+                return segments;
+            }
+            _ => {}
+        }
+
+        if self.retransmit {
+            let len = self.in_flight().min(self.send_mss);
+            let payload: Vec<u8> = self.send_buffer.iter().take(len).copied().collect();
+            let mut flags = ACK;
+            if !payload.is_empty() {
+                flags |= PSH;
+            }
+            if self.fin_sent && len == self.in_flight() {
+                flags |= FIN;
+            }
+            segments.push(self.segment(self.snd_una, flags, payload));
+            self.ack_pending = false;
+            self.count_retransmission();
+        }
+        if self.probe {
+            self.probe = false;
+            // a byte beyond the closed window, acknowledged with the current window
+            if self.snd_wnd == 0 && !self.fin_sent && self.send_buffer.len() > self.in_flight() {
+                let byte = self.send_buffer[self.in_flight()];
+                segments.push(self.segment(self.snd_nxt, ACK | PSH, alloc::vec![byte]));
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                self.probing = true;
+                self.ack_pending = false;
+            }
+        }
+
+        if matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        ) {
+            while !self.fin_sent {
+                let in_flight = self.in_flight();
+                let unsent = self.send_buffer.len() - in_flight;
+                let usable = (self.snd_wnd as usize).saturating_sub(in_flight);
+                let len = unsent.min(usable).min(self.send_mss);
+                if len == 0 {
+                    break;
+                }
+                let payload = self
+                    .send_buffer
+                    .range(in_flight..in_flight + len)
+                    .copied()
+                    .collect();
+                segments.push(self.segment(self.snd_nxt, ACK | PSH, payload));
+                self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                self.ack_pending = false;
+            }
+        }
+        if self.fin_queued && !self.fin_sent && self.in_flight() == self.send_buffer.len() {
+            segments.push(self.segment(self.snd_nxt, FIN | ACK, Vec::new()));
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.ack_pending = false;
+            self.state = match self.state {
+                State::CloseWait => State::LastAck,
+                _ => State::FinWait1,
+            };
+        }
+        if self.ack_pending {
+            segments.push(self.segment(self.snd_nxt, ACK, Vec::new()));
+            self.ack_pending = false;
+        }
+        self.arm(now);
+        segments
+    }
+
+    fn count_retransmission(&mut self) {
+        if self.retransmit {
+            self.retransmit = false;
+            RETRANSMITTED.inc();
+        }
+    }
+
+    /// Arm the timer if it isn't: to retransmit what is in flight, to probe a zero window with
+    /// data waiting, or to give up on the FIN of the peer once nothing reads the stream.
+    fn arm(&mut self, now: u64) {
+        if self.deadline.is_some() {
+            return;
+        }
+        let unsent = self.send_buffer.len() > self.in_flight();
+        if self.snd_nxt != self.snd_una || (self.snd_wnd == 0 && unsent) {
+            self.deadline = Some(now + self.rto);
+        } else if self.state == State::FinWait2 && self.orphaned {
+            self.deadline = Some(now + time::duration_to_ticks(FIN_WAIT_2_TIMEOUT));
+        }
+    }
+
+    fn transmit(&self, segments: &[Segment]) {
+        let header = Header {
+            src: self.local.addr,
+            dst: self.remote.addr,
+            protocol: PROTOCOL_TCP,
+        };
+        for segment in segments {
+            // lost segments are retransmitted
+            if ipv4::try_send(
+                header.src,
+                header.dst,
+                PROTOCOL_TCP,
+                &segment.to_bytes(&header),
+            )
+            .is_ok()
+            {
+                SENT.inc();
+            }
+        }
+    }
+}
+
+/// Apply `f` to the control block of `connection`, send the segments it calls for and forget the
+/// connection once closed.
+fn update<R>(connection: &Connection, f: impl FnOnce(&mut Tcb) -> R) -> R {
+    let mut tcb = connection.lock();
+    let deadline = tcb.deadline;
+    let result = f(&mut tcb);
+    let segments = tcb.output(time::ticks());
+    tcb.transmit(&segments);
+    let rearmed = tcb.deadline != deadline;
+    let closed = tcb.state == State::Closed;
+    let key = (tcb.local.port, tcb.remote);
+    drop(tcb);
+
+    if closed {
+        let mut connections = CONNECTIONS.lock();
+        // the port may have been reused by a new connection
+        if connections
+            .get(&key)
+            .map_or(false, |current| Arc::ptr_eq(current, connection))
+        {
+            connections.remove(&key);
+            OPEN.add(-1);
+        }
+    }
+    if rearmed {
+        TIMER.wake();
+    }
+    result
+}
+
+/// A free ephemeral port, used by no listener and no connection.
+fn ephemeral_port(
+    listeners: &BTreeMap<u16, Arc<Listener>>,
+    connections: &BTreeMap<(u16, SocketAddress), Connection>,
+) -> Result<u16, NetError> {
+    let mut next = NEXT_EPHEMERAL.lock();
+    let count = EPHEMERAL_PORTS.len();
+    let mut candidates = EPHEMERAL_PORTS
+        .cycle()
+        .skip(usize::from(*next - EPHEMERAL_PORTS.start()))
+        .take(count);
+    let port = candidates
+        .find(|port| {
+            !listeners.contains_key(port) && !connections.keys().any(|&(local, _)| local == *port)
+        })
+        .ok_or(NetError::AddressInUse)?;
+    *next = if port == *EPHEMERAL_PORTS.end() {
+        *EPHEMERAL_PORTS.start()
+    } else {
+        port + 1
+    };
+    Ok(port)
+}
+
+/// The part of a listener shared with the receive tasks.
+struct Listener {
+    /// The established connections not accepted yet.
+    queue: Mutex<VecDeque<TcpStream>>,
+    /// The task accepting.
+    waker: AtomicWaker,
+}
+
+/// A socket listening for connections to a port of all the interfaces, unbound on drop. The
+/// connections not accepted yet are closed with it.
+pub struct TcpListener {
+    port: u16,
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    /// Listen on `port`, or on a free ephemeral port if `port` is 0.
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        let mut listeners = LISTENERS.lock();
+        let port = if port == 0 {
+            ephemeral_port(&listeners, &CONNECTIONS.lock())?
+        } else if listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        } else {
+            port
+        };
+
+        let listener = Arc::new(Listener {
+            queue: Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+        });
+        listeners.insert(port, listener.clone());
+        LISTENING.add(1);
+        Ok(TcpListener { port, listener })
+    }
+
+    /// The port listened on.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for the next established connection, returns its stream and the address of the peer.
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddress), NetError> {
+        let stream = future::poll_fn(|cx| {
+            if let Some(stream) = self.listener.queue.lock().pop_front() {
+                return Poll::Ready(stream);
+            }
+            self.listener.waker.register(cx.waker());
+            // a connection may have been queued after the first check
+            match self.listener.queue.lock().pop_front() {
+                Some(stream) => {
+                    self.listener.waker.take();
+                    Poll::Ready(stream)
+                }
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        let peer = stream.peer_addr();
+        Ok((stream, peer))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // the queued streams are dropped outside of the lock, they lock the connections
+        let listener = LISTENERS.lock().remove(&self.port);
+        LISTENING.add(-1);
+        drop(listener);
+    }
+}
+
+/// A TCP connection. Dropping it closes the connection.
+pub struct TcpStream {
+    connection: Connection,
+}
+
+impl TcpStream {
+    /// Open a connection to `remote` from an ephemeral port, once the next hop is resolved.
+    pub async fn connect(remote: SocketAddress) -> Result<TcpStream, NetError> {
+        let (interface, next_hop) = ipv4::route(remote.addr)?;
+        arp::resolve(interface, next_hop).await?;
+        let local_addr = interface.ipv4().ok_or(NetError::NoAddress)?.address;
+
+        let connection = {
+            let listeners = LISTENERS.lock();
+            let mut connections = CONNECTIONS.lock();
+            let local = SocketAddress {
+                addr: local_addr,
+                port: ephemeral_port(&listeners, &connections)?,
+            };
+            let connection = Arc::new(Mutex::new(Tcb::connect(local, remote, initial_sequence())));
+            connections.insert((local.port, remote), connection.clone());
+            OPEN.add(1);
+            connection
+        };
+        let stream = TcpStream { connection };
+
+        future::poll_fn(|cx| {
+            update(&stream.connection, |tcb| match tcb.state {
+                State::SynSent => {
+                    tcb.writer = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Closed => Poll::Ready(Err(tcb.error.unwrap_or(NetError::ConnectionReset))),
+                _ => Poll::Ready(Ok(())),
+            })
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Read the received bytes into `buf`, waiting for some. `Ok(0)` once the peer closed the
+    /// connection and everything was read.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        future::poll_fn(|cx| {
+            update(&self.connection, |tcb| match tcb.read(buf) {
+                Some(read) => Poll::Ready(read),
+                None => {
+                    tcb.reader = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Write the bytes of `buf` that fit in the send buffer, waiting for room. Returns the number
+    /// of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, NetError> {
+        future::poll_fn(|cx| {
+            update(&self.connection, |tcb| match tcb.write(buf) {
+                Some(written) => Poll::Ready(written),
+                None => {
+                    tcb.writer = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Write all of `buf`, waiting for room as often as needed.
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<(), NetError> {
+        while !buf.is_empty() {
+            let written = self.write(buf).await?;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    /// Close the sending side of the connection, the peer reads the end of the stream once it
+    /// received the data written. The stream can still be read.
+    pub fn shutdown(&self) {
+        update(&self.connection, Tcb::close);
+    }
+
+    /// The local address of the connection.
+    pub fn local_addr(&self) -> SocketAddress {
+        self.connection.lock().local
+    }
+
+    /// The address of the peer.
+    pub fn peer_addr(&self) -> SocketAddress {
+        self.connection.lock().remote
+    }
+
+    /// The state of the connection.
+    pub fn state(&self) -> State {
+        self.connection.lock().state
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        update(&self.connection, |tcb| {
+            tcb.close();
+            tcb.orphaned = true;
+        });
+    }
+}
+
+/// A connection of the table of [connections].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The local address.
+    pub local: SocketAddress,
+    /// The address of the peer.
+    pub remote: SocketAddress,
+    /// The state of the connection.
+    pub state: State,
+    /// The bytes received and not read.
+    pub recv_queue: usize,
+    /// The bytes written and not acknowledged.
+    pub send_queue: usize,
+}
+
+/// The connections not closed yet, including those lingering after their stream was dropped, in
+/// ascending order of local port.
+pub fn connections() -> Vec<ConnectionInfo> {
+    CONNECTIONS
+        .lock()
+        .values()
+        .map(|connection| {
+            let tcb = connection.lock();
+            ConnectionInfo {
+                local: tcb.local,
+                remote: tcb.remote,
+                state: tcb.state,
+                recv_queue: tcb.recv_buffer.len(),
+                send_queue: tcb.send_buffer.len(),
+            }
+        })
+        .collect()
+}
+
+/// The ports with a listener, in ascending order.
+pub fn listening_ports() -> Vec<u16> {
+    LISTENERS.lock().keys().copied().collect()
+}
+
+/// Hand the connection just established by a passive open to its listener, reset it if the
+/// listener is gone.
+fn established(connection: Connection) {
+    let port = connection.lock().local.port;
+    let listener = LISTENERS.lock().get(&port).cloned();
+    match listener {
+        Some(listener) => {
+            listener.queue.lock().push_back(TcpStream { connection });
+            listener.waker.wake();
+        }
+        None => update(&connection, Tcb::reset),
+    }
+}
+
+/// Answer the segment `segment` of the packet with the header `header` for no connection with a
+/// RST.
+fn refuse(header: &Header, segment: &Segment) {
+    let (seq, ack, flags) = if segment.flags & ACK != 0 {
+        (segment.ack, 0, RST)
+    } else {
+        (0, segment.seq.wrapping_add(segment.seq_len()), RST | ACK)
+    };
+    let reply = Segment {
+        src_port: segment.dst_port,
+        dst_port: segment.src_port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        payload: Vec::new(),
+    };
+    let header = Header {
+        src: header.dst,
+        dst: header.src,
+        protocol: PROTOCOL_TCP,
+    };
+    if ipv4::try_send(
+        header.src,
+        header.dst,
+        PROTOCOL_TCP,
+        &reply.to_bytes(&header),
+    )
+    .is_ok()
+    {
+        RESETS_SENT.inc();
+    }
+}
+
+/// Handle the segment `bytes` of the packet with the header `header`: update its connection,
+/// open a connection to a listener for a SYN, or refuse it with a RST.
+pub fn receive(header: &Header, bytes: &[u8]) {
+    let segment = match Segment::parse(header, bytes) {
+        Some(segment) => segment,
+        None => {
+            DROPPED.inc();
+            return;
+        }
+    };
+    RECEIVED.inc();
+
+    let remote = SocketAddress {
+        addr: header.src,
+        port: segment.src_port,
+    };
+    let connection = CONNECTIONS.lock().get(&(segment.dst_port, remote)).cloned();
+    if let Some(connection) = connection {
+        let (before, after) = update(&connection, |tcb| {
+            let before = tcb.state;
+            tcb.on_segment(&segment, time::ticks());
+            (before, tcb.state)
+        });
+        if before == State::SynReceived && !matches!(after, State::SynReceived | State::Closed) {
+            established(connection);
+        }
+        return;
+    }
+
+    if segment.flags & RST != 0 {
+        return;
+    }
+    if segment.flags & (SYN | ACK) == SYN {
+        let listener = LISTENERS.lock().get(&segment.dst_port).cloned();
+        if let Some(listener) = listener {
+            // the peer sends the SYN again
+            if listener.queue.lock().len() >= BACKLOG {
+                return;
+            }
+            let local = SocketAddress {
+                addr: header.dst,
+                port: segment.dst_port,
+            };
+            let tcb = Tcb::accept(local, remote, initial_sequence(), &segment);
+            let connection = Arc::new(Mutex::new(tcb));
+            CONNECTIONS
+                .lock()
+                .insert((local.port, remote), connection.clone());
+            OPEN.add(1);
+            update(&connection, |_| ());
+            return;
+        }
+    }
+    refuse(header, &segment);
+}
+
+/// Retransmit the segments of the connections and close their lingering ends when their timers
+/// expire, forever. Spawned by the kernel.
+pub async fn timer_task() {
+    // the earliest deadline registered on the timer wheel
+    let mut armed: Option<u64> = None;
+    future::poll_fn(|cx| {
+        TIMER.register(cx.waker());
+        let now = time::ticks();
+        let connections: Vec<Connection> = CONNECTIONS.lock().values().cloned().collect();
+        let mut next: Option<u64> = None;
+        for connection in connections {
+            update(&connection, |tcb| tcb.on_timer(now));
+            if let Some(deadline) = connection.lock().deadline {
+                next = Some(next.map_or(deadline, |next| next.min(deadline)));
+            }
+        }
+        if let Some(next) = next {
+            if armed.map_or(true, |armed| next < armed || armed <= now) {
+                time::wheel::register(next, cx.waker().clone());
+                armed = Some(next);
+            }
+        }
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{self, tests::GATEWAY_IP, Ipv4Address, USER_NETWORK},
+        task::block_on,
+        testing,
+    };
+
+    const CLIENT: SocketAddress = SocketAddress {
+        addr: Ipv4Address([10, 0, 2, 15]),
+        port: 50000,
+    };
+    const SERVER: SocketAddress = SocketAddress {
+        addr: Ipv4Address([10, 0, 2, 2]),
+        port: 80,
+    };
+
+    /// A connection from [CLIENT] to [SERVER] established in memory, without a network.
+    fn established_pair() -> (Tcb, Tcb) {
+        let mut client = Tcb::connect(CLIENT, SERVER, u32::MAX - 10);
+        let syn = client.output(0);
+        assert_eq!(syn.len(), 1);
+        assert_eq!((syn[0].flags, syn[0].mss), (SYN, Some(MSS as u16)));
+        let mut server = Tcb::accept(SERVER, CLIENT, 1000, &syn[0]);
+        exchange(&mut client, &mut server, 0);
+        assert_eq!(
+            (client.state, server.state),
+            (State::Established, State::Established)
+        );
+        (client, server)
+    }
+
+    /// Deliver the segments of `a` and `b` to each other until they have nothing to send.
+    fn exchange(a: &mut Tcb, b: &mut Tcb, now: u64) {
+        loop {
+            let to_b = a.output(now);
+            for segment in &to_b {
+                b.on_segment(segment, now);
+            }
+            let to_a = b.output(now);
+            for segment in &to_a {
+                a.on_segment(segment, now);
+            }
+            if to_a.is_empty() && to_b.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn read_all(tcb: &mut Tcb) -> Vec<u8> {
+        let mut buf = alloc::vec![0; RECEIVE_BUFFER_SIZE];
+        let len = tcb.read(&mut buf).unwrap().unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test_case]
+    fn segments_built_and_parsed() {
+        let header = Header {
+            src: CLIENT.addr,
+            dst: SERVER.addr,
+            protocol: PROTOCOL_TCP,
+        };
+        let segment = Segment {
+            src_port: CLIENT.port,
+            dst_port: SERVER.port,
+            seq: 1,
+            ack: 2,
+            flags: SYN | ACK,
+            window: 1024,
+            mss: Some(1460),
+            payload: b"data".to_vec(),
+        };
+        let mut bytes = segment.to_bytes(&header);
+        assert_eq!(bytes.len(), HEADER_SIZE + 4 + 4);
+        assert_eq!(Segment::parse(&header, &bytes), Some(segment.clone()));
+        assert_eq!(segment.seq_len(), 5);
+
+        bytes[HEADER_SIZE + 4] ^= 1;
+        assert_eq!(Segment::parse(&header, &bytes), None);
+
+        assert!(seq_lt(u32::MAX, 0) && !seq_lt(0, u32::MAX));
+        assert!(seq_le(7, 7) && !seq_lt(7, 7));
+    }
+
+    #[test_case]
+    fn data_transferred_and_connection_closed() {
+        let (mut client, mut server) = established_pair();
+        assert_eq!(client.write(b"hello"), Some(Ok(5)));
+        exchange(&mut client, &mut server, 0);
+        assert_eq!(read_all(&mut server), b"hello");
+        assert_eq!(server.read(&mut [0; 4]), None);
+        assert!(client.send_buffer.is_empty());
+
+        // the client closes first
+        client.close();
+        assert_eq!(client.write(b"late"), Some(Err(NetError::NotConnected)));
+        exchange(&mut client, &mut server, 0);
+        assert_eq!(
+            (client.state, server.state),
+            (State::FinWait2, State::CloseWait)
+        );
+        assert_eq!(server.read(&mut [0; 4]), Some(Ok(0)));
+
+        // the server can still answer
+        assert_eq!(server.write(b"bye"), Some(Ok(3)));
+        server.close();
+        exchange(&mut client, &mut server, 0);
+        assert_eq!(
+            (client.state, server.state),
+            (State::TimeWait, State::Closed)
+        );
+        assert_eq!(read_all(&mut client), b"bye");
+        assert_eq!(client.read(&mut [0; 4]), Some(Ok(0)));
+
+        client.on_timer(time::duration_to_ticks(TIME_WAIT));
+        assert_eq!(client.state, State::Closed);
+    }
+
+    #[test_case]
+    fn lost_segments_retransmitted() {
+        let (mut client, mut server) = established_pair();
+        client.write(b"lost").unwrap().unwrap();
+        let lost = client.output(0);
+        assert_eq!(lost.len(), 1);
+        let rto = client.deadline.unwrap();
+
+        client.on_timer(rto - 1);
+        assert!(client.output(rto - 1).is_empty());
+        client.on_timer(rto);
+        let retransmitted = client.output(rto);
+        assert_eq!(retransmitted, lost);
+        // the backoff doubles
+        assert_eq!(client.deadline, Some(rto + 2 * rto));
+        for segment in &retransmitted {
+            server.on_segment(segment, rto);
+        }
+        assert_eq!(read_all(&mut server), b"lost");
+
+        exchange(&mut client, &mut server, rto);
+        assert_eq!(client.deadline, None);
+
+        // out of order segments are dropped and the expected one asked for
+        client.send_mss = 5;
+        client.write(b"firstsecond").unwrap().unwrap();
+        let segments = client.output(rto);
+        assert_eq!(segments.len(), 3);
+        server.on_segment(&segments[1], rto);
+        let duplicate = server.output(rto);
+        assert_eq!(duplicate.len(), 1);
+        assert_eq!(duplicate[0].ack, segments[0].seq);
+        assert!(server.recv_buffer.is_empty());
+
+        // until the peer gives up
+        let mut now = rto;
+        while client.state != State::Closed {
+            now = client.deadline.unwrap();
+            client.on_timer(now);
+            client.output(now);
+        }
+        assert_eq!(client.error, Some(NetError::TimedOut));
+        assert_eq!(client.write(b"x"), Some(Err(NetError::TimedOut)));
+        assert!(now > time::duration_to_ticks(MAX_RTO));
+    }
+
+    #[test_case]
+    fn sender_bounded_by_receive_window() {
+        let (mut client, mut server) = established_pair();
+        let data: Vec<u8> = (0..RECEIVE_BUFFER_SIZE + 100).map(|i| i as u8).collect();
+        assert_eq!(client.write(&data), Some(Ok(SEND_BUFFER_SIZE)));
+        exchange(&mut client, &mut server, 0);
+        assert_eq!(server.recv_buffer.len(), RECEIVE_BUFFER_SIZE);
+        assert_eq!((server.window(), client.snd_wnd), (0, 0));
+        // the acknowledged bytes left the send buffer
+        assert_eq!(client.write(&data[SEND_BUFFER_SIZE..]), Some(Ok(100)));
+        exchange(&mut client, &mut server, 0);
+        assert_eq!(client.send_buffer.len(), 100);
+
+        // the closed window is probed
+        let probe_at = client.deadline.unwrap();
+        client.on_timer(probe_at);
+        let probe = client.output(probe_at);
+        assert_eq!(probe.len(), 1);
+        assert_eq!(probe[0].payload.len(), 1);
+        server.on_segment(&probe[0], probe_at);
+        exchange(&mut client, &mut server, probe_at);
+        assert!(client.probing && client.deadline.is_some());
+
+        // reading opens the window again
+        assert_eq!(read_all(&mut server), &data[..RECEIVE_BUFFER_SIZE]);
+        exchange(&mut client, &mut server, probe_at);
+        assert_eq!(read_all(&mut server), &data[RECEIVE_BUFFER_SIZE..]);
+        assert!(client.send_buffer.is_empty());
+    }
+
+    #[test_case]
+    fn connections_accepted_from_listener() {
+        let listener = testing::keep_allocations(|| TcpListener::bind(8080).unwrap());
+        assert_eq!(TcpListener::bind(8080).err(), Some(NetError::AddressInUse));
+        let header = Header {
+            src: SERVER.addr,
+            dst: USER_NETWORK.address,
+            protocol: PROTOCOL_TCP,
+        };
+        let peer = Segment {
+            src_port: 40000,
+            dst_port: 8080,
+            seq: 500,
+            ack: 0,
+            flags: SYN,
+            window: 4096,
+            mss: None,
+            payload: Vec::new(),
+        };
+        let send = |segment: &Segment| receive(&header, &segment.to_bytes(&header));
+        // the table of connections keeps its nodes
+        testing::keep_allocations(|| send(&peer));
+        let remote = SocketAddress {
+            addr: SERVER.addr,
+            port: 40000,
+        };
+        let iss = CONNECTIONS.lock()[&(8080, remote)].lock().iss;
+
+        send(&Segment {
+            seq: 501,
+            ack: iss.wrapping_add(1),
+            flags: ACK | PSH,
+            payload: b"GET".to_vec(),
+            ..peer.clone()
+        });
+        let (stream, addr) = block_on(listener.accept()).unwrap();
+        assert_eq!((addr, stream.state()), (remote, State::Established));
+        assert!(listening_ports().contains(&8080));
+        assert!(connections()
+            .iter()
+            .any(|info| info.remote == remote && info.state == State::Established));
+        let mut buf = [0; 8];
+        assert_eq!(block_on(stream.read(&mut buf)), Ok(3));
+        assert_eq!(&buf[..3], b"GET");
+
+        // the peer resets the connection
+        send(&Segment {
+            seq: 504,
+            flags: RST,
+            ..peer.clone()
+        });
+        assert_eq!(
+            block_on(stream.read(&mut buf)),
+            Err(NetError::ConnectionReset)
+        );
+        assert!(!CONNECTIONS.lock().contains_key(&(8080, remote)));
+    }
+
+    #[test_case]
+    fn closed_port_refused() {
+        let gateway = SocketAddress {
+            addr: Ipv4Address(GATEWAY_IP),
+            port: 1,
+        };
+        // nothing listens on the port 1 of the host, QEMU answers with a RST
+        let connected = testing::keep_allocations(|| {
+            net::tests::run(async {
+                TcpStream::connect(gateway)
+                    .await
+                    .map(|stream| stream.local_addr())
+            })
+        });
+        assert_eq!(connected, Err(NetError::ConnectionRefused));
+    }
+}
@@ -0,0 +1,345 @@
+//! UDP (RFC 768), datagrams to and from the ports of the kernel.
+//!
+//! A [UdpSocket] is bound to a port of all the interfaces. The received datagrams are demultiplexed
+//! by destination port by the receive tasks of the interfaces and queued in the socket until read,
+//! up to [RECEIVE_BUFFER_SIZE] bytes of payload, the datagrams beyond are dropped. Datagrams for a
+//! port without a socket are dropped as well.
+
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    ops::RangeInclusive,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{
+    ipv4::{self, Header, PROTOCOL_UDP},
+    NetError, SocketAddress,
+};
+use crate::metrics::{Counter, Gauge};
+
+/// The size of the header of a datagram.
+pub const HEADER_SIZE: usize = 8;
+/// The most bytes of payload queued in a socket.
+pub const RECEIVE_BUFFER_SIZE: usize = 64 * 1024;
+/// The ports given to the sockets bound to port 0, as suggested by IANA.
+pub const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// The bound sockets by port.
+static SOCKETS: Mutex<BTreeMap<u16, Arc<Shared>>> = Mutex::new(BTreeMap::new());
+/// The ephemeral port tried first by the next bind to port 0.
+static NEXT_EPHEMERAL: Mutex<u16> = Mutex::new(*EPHEMERAL_PORTS.start());
+
+static RECEIVED: Counter = Counter::new("udp.datagrams_received");
+static SENT: Counter = Counter::new("udp.datagrams_sent");
+static NO_PORT: Counter = Counter::new("udp.dropped_no_port");
+static BUFFER_FULL: Counter = Counter::new("udp.dropped_buffer_full");
+static OPEN: Gauge = Gauge::new("udp.open_sockets");
+
+/// The part of a socket shared with the receive tasks.
+struct Shared {
+    queue: Mutex<Queue>,
+    /// The task reading the socket.
+    waker: AtomicWaker,
+}
+
+#[derive(Default)]
+struct Queue {
+    datagrams: VecDeque<(SocketAddress, Vec<u8>)>,
+    /// The bytes of payload of the datagrams.
+    buffered: usize,
+}
+
+/// A UDP socket bound to a port, unbound on drop.
+pub struct UdpSocket {
+    port: u16,
+    shared: Arc<Shared>,
+}
+
+impl UdpSocket {
+    /// Bind a socket to `port`, or to a free ephemeral port if `port` is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = if port == 0 {
+            let mut next = NEXT_EPHEMERAL.lock();
+            let count = EPHEMERAL_PORTS.len();
+            let mut candidates = EPHEMERAL_PORTS
+                .cycle()
+                .skip(usize::from(*next - EPHEMERAL_PORTS.start()))
+                .take(count);
+            let port = candidates
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(NetError::AddressInUse)?;
+            *next = if port == *EPHEMERAL_PORTS.end() {
+                *EPHEMERAL_PORTS.start()
+            } else {
+                port + 1
+            };
+            port
+        } else if sockets.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        } else {
+            port
+        };
+
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            waker: AtomicWaker::new(),
+        });
+        sockets.insert(port, shared.clone());
+        OPEN.add(1);
+        Ok(UdpSocket { port, shared })
+    }
+
+    /// The port the socket is bound to.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send `buf` in a datagram to `dst`, returns the number of bytes sent.
+    pub async fn send_to(&self, buf: &[u8], dst: SocketAddress) -> Result<usize, NetError> {
+        let src = ipv4::source_for(dst.addr)?;
+        let header = Header {
+            src,
+            dst: dst.addr,
+            protocol: PROTOCOL_UDP,
+        };
+        let datagram = datagram(&header, self.port, dst.port, buf);
+        ipv4::send(src, dst.addr, PROTOCOL_UDP, &datagram).await?;
+        SENT.inc();
+        Ok(buf.len())
+    }
+
+    /// Receive the next datagram into `buf`, returns its length and its sender. The bytes of a
+    /// datagram longer than `buf` are lost.
+    pub fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFrom<'a> {
+        RecvFrom { socket: self, buf }
+    }
+
+    /// Receive the next datagram into `buf` without waiting, `None` if none is queued.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, SocketAddress)> {
+        let mut queue = self.shared.queue.lock();
+        let (src, payload) = queue.datagrams.pop_front()?;
+        queue.buffered -= payload.len();
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+        Some((len, src))
+    }
+
+    /// The bytes of payload queued in the socket.
+    pub fn buffered(&self) -> usize {
+        self.shared.queue.lock().buffered
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+        OPEN.add(-1);
+    }
+}
+
+/// The future returned by [UdpSocket::recv_from].
+pub struct RecvFrom<'a> {
+    socket: &'a UdpSocket,
+    buf: &'a mut [u8],
+}
+
+impl Future for RecvFrom<'_> {
+    type Output = Result<(usize, SocketAddress), NetError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(received) = this.socket.try_recv_from(this.buf) {
+            return Poll::Ready(Ok(received));
+        }
+        this.socket.shared.waker.register(cx.waker());
+        // a datagram may have been queued after the first check
+        match this.socket.try_recv_from(this.buf) {
+            Some(received) => {
+                this.socket.shared.waker.take();
+                Poll::Ready(Ok(received))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The datagram carrying `payload` from `src_port` to `dst_port` in a packet with the header
+/// `header`.
+pub fn datagram(header: &Header, src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let len = HEADER_SIZE + payload.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dst_port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let checksum = match ipv4::checksum(&[&ipv4::pseudo_header(header, len), &datagram]) {
+        // 0 means no checksum, sent as all ones instead
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// Queue the datagram `datagram` of the packet with the header `header` in the socket bound to its
+/// destination port.
+pub fn receive(header: &Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let u16_at = |offset: usize| u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+    let len = usize::from(u16_at(4));
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if u16_at(6) != 0 && ipv4::checksum(&[&ipv4::pseudo_header(header, len), datagram]) != 0 {
+        return;
+    }
+
+    let shared = match SOCKETS.lock().get(&u16_at(2)) {
+        Some(shared) => shared.clone(),
+        None => {
+            NO_PORT.inc();
+            return;
+        }
+    };
+    let payload = &datagram[HEADER_SIZE..];
+    let src = SocketAddress {
+        addr: header.src,
+        port: u16_at(0),
+    };
+    {
+        let mut queue = shared.queue.lock();
+        if queue.buffered + payload.len() > RECEIVE_BUFFER_SIZE {
+            BUFFER_FULL.inc();
+            return;
+        }
+        queue.buffered += payload.len();
+        queue.datagrams.push_back((src, payload.to_vec()));
+    }
+    RECEIVED.inc();
+    shared.waker.wake();
+}
+
+/// The ports with a bound socket, in ascending order.
+pub fn bound_ports() -> Vec<u16> {
+    SOCKETS.lock().keys().copied().collect()
+}
+
+/// The ports with a bound socket and the bytes of payload queued in the socket, in ascending order
+/// of ports.
+pub fn sockets() -> Vec<(u16, usize)> {
+    SOCKETS
+        .lock()
+        .iter()
+        .map(|(&port, shared)| (port, shared.queue.lock().buffered))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{self, tests::GATEWAY_IP, Ipv4Address, USER_NETWORK},
+        task::block_on,
+        testing,
+    };
+    use futures_util::{future, pin_mut};
+
+    const PEER: SocketAddress = SocketAddress {
+        addr: Ipv4Address([10, 0, 2, 2]),
+        port: 5353,
+    };
+
+    /// A datagram from [PEER] to `port` of the guest, as a received packet.
+    fn received(port: u16, payload: &[u8]) -> (Header, Vec<u8>) {
+        let header = Header {
+            src: PEER.addr,
+            dst: USER_NETWORK.address,
+            protocol: PROTOCOL_UDP,
+        };
+        let datagram = datagram(&header, PEER.port, port, payload);
+        (header, datagram)
+    }
+
+    #[test_case]
+    fn ports_bound_once() {
+        // the table of sockets keeps its nodes
+        let (socket, ephemeral) = testing::keep_allocations(|| {
+            (UdpSocket::bind(7).unwrap(), UdpSocket::bind(0).unwrap())
+        });
+        assert!(EPHEMERAL_PORTS.contains(&ephemeral.local_port()));
+        assert_eq!(UdpSocket::bind(7).err(), Some(NetError::AddressInUse));
+        assert!(bound_ports().contains(&7));
+        drop(socket);
+        assert!(!bound_ports().contains(&7));
+        assert!(UdpSocket::bind(7).is_ok());
+    }
+
+    #[test_case]
+    fn datagrams_demultiplexed_by_port() {
+        let socket = testing::keep_allocations(|| UdpSocket::bind(4000).unwrap());
+        let (header, datagram) = received(4000, b"hello");
+        receive(&header, &datagram);
+        let (header, other) = received(4001, b"elsewhere");
+        receive(&header, &other);
+
+        let mut buf = [0; 16];
+        assert_eq!(block_on(socket.recv_from(&mut buf)), Ok((5, PEER)));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(socket.try_recv_from(&mut buf), None);
+
+        // corrupted
+        let mut corrupted = datagram.clone();
+        corrupted[HEADER_SIZE] ^= 1;
+        receive(&header, &corrupted);
+        assert_eq!(socket.try_recv_from(&mut buf), None);
+    }
+
+    #[test_case]
+    fn receive_buffer_bounded() {
+        let socket = testing::keep_allocations(|| UdpSocket::bind(4002).unwrap());
+        let payload = [0; 1000];
+        let (header, datagram) = received(4002, &payload);
+        for _ in 0..RECEIVE_BUFFER_SIZE / payload.len() + 1 {
+            receive(&header, &datagram);
+        }
+        assert_eq!(socket.buffered(), RECEIVE_BUFFER_SIZE / 1000 * 1000);
+        assert!(sockets().contains(&(4002, socket.buffered())));
+
+        // truncated to the buffer
+        let mut buf = [0xff; 10];
+        assert_eq!(socket.try_recv_from(&mut buf), Some((10, PEER)));
+        assert_eq!(socket.buffered(), (RECEIVE_BUFFER_SIZE / 1000 - 1) * 1000);
+    }
+
+    #[test_case]
+    fn datagrams_sent_to_gateway() {
+        let socket = testing::keep_allocations(|| UdpSocket::bind(0).unwrap());
+        let interface = net::interface("eth0").unwrap();
+        let discard = SocketAddress {
+            addr: Ipv4Address(GATEWAY_IP),
+            port: 9,
+        };
+        let sent = testing::keep_allocations(|| {
+            block_on(async {
+                let receive = net::receive_task(interface);
+                let send = socket.send_to(b"discarded", discard);
+                pin_mut!(receive, send);
+                match future::select(receive, send).await {
+                    future::Either::Right((sent, _)) => sent,
+                    future::Either::Left(_) => unreachable!("the receive task never ends"),
+                }
+            })
+        });
+        assert_eq!(sent, Ok(9));
+    }
+}
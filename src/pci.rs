@@ -0,0 +1,444 @@
+//! Enumeration of the devices on the PCI bus.
+//!
+//! The configuration space of each function is read through the legacy configuration mechanism:
+//! the address of a dword is written to [CONFIG_ADDRESS_PORT], then the dword itself is read or
+//! written at [CONFIG_DATA_PORT]. All the 256 buses are scanned by brute force at boot, which also
+//! finds the devices behind bridges without following them. The devices found are kept for the
+//! drivers to look up by their IDs or their class, see [devices].
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::info;
+
+/// The port selecting the dword of the configuration space accessed at [CONFIG_DATA_PORT].
+pub const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+/// The port reading and writing the dword selected at [CONFIG_ADDRESS_PORT].
+pub const CONFIG_DATA_PORT: u16 = 0xcfc;
+/// Set in the configuration address to enable the access.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const OFFSET_ID: u8 = 0x00;
+const OFFSET_COMMAND: u8 = 0x04;
+const OFFSET_CLASS: u8 = 0x08;
+const OFFSET_HEADER_TYPE: u8 = 0x0c;
+const OFFSET_BAR0: u8 = 0x10;
+const OFFSET_CAPABILITIES: u8 = 0x34;
+const OFFSET_INTERRUPT: u8 = 0x3c;
+
+/// The vendor ID read from functions which don't exist.
+const NO_VENDOR: u16 = 0xffff;
+/// Set in the header type of the function 0 of devices with more than one function.
+const MULTI_FUNCTION: u8 = 0x80;
+/// Set in the command register to decode the I/O BARs.
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Set in the command register to decode the memory BARs.
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Set in the command register to let the function access memory by DMA.
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Set in the status register, the upper half of the command dword, if the function has a list of
+/// capabilities.
+const STATUS_CAPABILITIES: u32 = 1 << (16 + 4);
+/// The most capabilities which fit in the configuration space after the header.
+const MAX_CAPABILITIES: usize = 48;
+/// The ID of vendor-specific capabilities, e.g. the locations of the registers of virtio devices.
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+
+/// Set in a BAR mapping I/O ports.
+const BAR_IO: u32 = 1 << 0;
+/// The type field of a memory BAR for a 64-bit address spanning two BARs.
+const BAR_MEMORY_64: u32 = 0b10 << 1;
+/// Set in a memory BAR if the memory is prefetchable.
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// The configuration ports, one address and one data access at a time.
+static CONFIG: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS_PORT), Port::new(CONFIG_DATA_PORT)));
+
+static DEVICES: OnceCell<Vec<Device>> = OnceCell::uninit();
+
+/// The location of a function on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    /// The bus, 0 - 255.
+    pub bus: u8,
+    /// The device on the bus, 0 - 31.
+    pub device: u8,
+    /// The function of the device, 0 - 7.
+    pub function: u8,
+}
+
+impl Address {
+    /// The function `function` of the device `device` on the bus `bus`.
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        assert!(device < 32 && function < 8, "invalid PCI address");
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Read the dword at `offset` of the configuration space of the function, the lowest 2 bits of
+    /// `offset` are ignored.
+    pub fn read(self, offset: u8) -> u32 {
+        let mut ports = CONFIG.lock();
+        let (address, data) = &mut *ports;
+        // # Safety
+        // The configuration ports only access the configuration space, which reads without side
+        // effects.
+        unsafe {
+            address.write(self.config_address(offset));
+            data.read()
+        }
+    }
+
+    /// Write the dword at `offset` of the configuration space of the function.
+    ///
+    /// # Safety
+    /// Writing the configuration space reconfigures the device, e.g. moves its registers to another
+    /// address, the caller must make sure nothing relies on the old configuration.
+    pub unsafe fn write(self, offset: u8, value: u32) {
+        let mut ports = CONFIG.lock();
+        let (address, data) = &mut *ports;
+        address.write(self.config_address(offset));
+        data.write(value);
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        CONFIG_ENABLE
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xfc)
+    }
+
+    fn vendor_id(self) -> u16 {
+        self.read(OFFSET_ID) as u16
+    }
+
+    fn header_type(self) -> u8 {
+        (self.read(OFFSET_HEADER_TYPE) >> 16) as u8
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A Base Address Register, the location of a range of registers of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// The BAR is not implemented, or is the upper half of a 64-bit memory BAR.
+    None,
+    /// Registers mapped in the physical address space.
+    Memory {
+        /// The physical address of the registers.
+        address: u64,
+        /// The size of the range in bytes.
+        size: u64,
+        /// Whether reads have no side effects and may be merged or cached.
+        prefetchable: bool,
+    },
+    /// Registers in the I/O port space.
+    Io {
+        /// The first port.
+        port: u16,
+        /// The number of ports.
+        size: u16,
+    },
+}
+
+/// An entry of the list of capabilities of a function, see [Device::capabilities].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// The capability ID, e.g. [CAPABILITY_VENDOR].
+    pub id: u8,
+    /// The offset of the capability in the configuration space, its fields follow its ID and the
+    /// offset of the next capability.
+    pub offset: u8,
+}
+
+/// A function found on the PCI bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// The location of the function.
+    pub address: Address,
+    /// The vendor ID.
+    pub vendor_id: u16,
+    /// The device ID assigned by the vendor.
+    pub device_id: u16,
+    /// The base class code, e.g. 0x01 for mass storage controllers.
+    pub class: u8,
+    /// The sub-class code, e.g. 0x06 for SATA controllers.
+    pub subclass: u8,
+    /// The programming interface, e.g. 0x01 for AHCI.
+    pub prog_if: u8,
+    /// The revision ID.
+    pub revision: u8,
+    /// The header type without the multi-function bit: 0 for devices, 1 for PCI-to-PCI bridges.
+    pub header_type: u8,
+    /// The BARs, only the first 2 may be implemented by bridges.
+    pub bars: [Bar; 6],
+    /// The legacy interrupt line routed by the firmware, `None` if the function has no interrupt
+    /// pin.
+    pub interrupt_line: Option<u8>,
+}
+
+impl Device {
+    /// Read the configuration space of the function at `address`, `None` if there is no function.
+    pub fn probe(address: Address) -> Option<Self> {
+        let id = address.read(OFFSET_ID);
+        let vendor_id = id as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+
+        let class = address.read(OFFSET_CLASS);
+        let header_type = address.header_type() & !MULTI_FUNCTION;
+        let interrupt = address.read(OFFSET_INTERRUPT);
+        let interrupt_pin = (interrupt >> 8) as u8;
+        let bar_count = match header_type {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+
+        Some(Device {
+            address,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars: read_bars(address, bar_count),
+            interrupt_line: if interrupt_pin != 0 {
+                Some(interrupt as u8)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Enable the decoding of the BARs and DMA by the function, must be called by its driver before
+    /// using it. Firmwares usually leave DMA disabled.
+    pub fn enable_bus_mastering(&self) {
+        let command = self.address.read(OFFSET_COMMAND) & 0xffff;
+        // # Safety
+        // Only the command register is changed, the status bits in the upper half are written as 0
+        // which leaves them unchanged. The BARs are those found by the enumeration.
+        unsafe {
+            self.address.write(
+                OFFSET_COMMAND,
+                command | u32::from(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER),
+            );
+        }
+    }
+
+    /// The capabilities of the function, in the order of its list.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        if self.address.read(OFFSET_COMMAND) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        // the lowest 2 bits of the pointers are reserved, a malformed list may loop
+        let mut offset = self.address.read(OFFSET_CAPABILITIES) as u8 & !0b11;
+        while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+            let header = self.address.read(offset);
+            capabilities.push(Capability {
+                id: header as u8,
+                offset,
+            });
+            offset = (header >> 8) as u8 & !0b11;
+        }
+        capabilities
+    }
+
+    /// A human readable name of the class of the function.
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "mass storage controller",
+            (0x02, 0x00) => "ethernet controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x05, _) => "memory controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x08, _) => "system peripheral",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus controller",
+            (0x0c, _) => "serial bus controller",
+            _ => "unclassified device",
+        }
+    }
+}
+
+/// Read and size the first `count` BARs of the function at `address`.
+fn read_bars(address: Address, count: usize) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+
+    // the BARs are sized with decoding disabled, the device must not respond at the all-ones
+    // addresses written in between
+    let command = address.read(OFFSET_COMMAND);
+    // # Safety
+    // Decoding is restored below, no driver uses the device during the enumeration.
+    unsafe {
+        address.write(
+            OFFSET_COMMAND,
+            command & !u32::from(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+    }
+
+    let mut i = 0;
+    while i < count {
+        let offset = OFFSET_BAR0 + 4 * i as u8;
+        let (bar, raw_size) = size_bar(address, offset);
+        if bar & BAR_IO != 0 {
+            let size = !(raw_size & !0x3) as u16;
+            if raw_size != 0 {
+                bars[i] = Bar::Io {
+                    port: (bar & !0x3) as u16,
+                    size: size.wrapping_add(1),
+                };
+            }
+        } else if bar & BAR_MEMORY_64 != 0 && i + 1 < count {
+            let (high, high_size) = size_bar(address, offset + 4);
+            let mask = u64::from(high_size) << 32 | u64::from(raw_size & !0xf);
+            if mask != 0 {
+                bars[i] = Bar::Memory {
+                    address: u64::from(high) << 32 | u64::from(bar & !0xf),
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: bar & BAR_PREFETCHABLE != 0,
+                };
+            }
+            // the upper half stays None
+            i += 1;
+        } else if raw_size & !0xf != 0 {
+            bars[i] = Bar::Memory {
+                address: u64::from(bar & !0xf),
+                size: u64::from((!(raw_size & !0xf)).wrapping_add(1)),
+                prefetchable: bar & BAR_PREFETCHABLE != 0,
+            };
+        }
+        i += 1;
+    }
+
+    // # Safety
+    // The original command is restored.
+    unsafe {
+        address.write(OFFSET_COMMAND, command);
+    }
+    bars
+}
+
+/// Read the BAR at `offset` and the mask of its size, the BAR is restored afterwards.
+fn size_bar(address: Address, offset: u8) -> (u32, u32) {
+    let bar = address.read(offset);
+    // # Safety
+    // Decoding is disabled by the caller, the original value is restored right after.
+    unsafe {
+        address.write(offset, u32::MAX);
+        let size = address.read(offset);
+        address.write(offset, bar);
+        (bar, size)
+    }
+}
+
+/// Scan all the buses for functions.
+pub fn scan() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=u8::MAX {
+        for device in 0..32 {
+            let address = Address::new(bus, device, 0);
+            if address.vendor_id() == NO_VENDOR {
+                continue;
+            }
+            let functions = if address.header_type() & MULTI_FUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+            devices.extend(
+                (0..functions)
+                    .filter_map(|function| Device::probe(Address::new(bus, device, function))),
+            );
+        }
+    }
+    devices
+}
+
+/// Enumerate the PCI bus, must be called once after the heap is initialized.
+pub fn init() {
+    let devices = scan();
+    info!("found {} PCI functions", devices.len());
+    DEVICES.init_once(|| devices);
+}
+
+/// The functions found by [init], ordered by their addresses. Empty before [init].
+pub fn devices() -> &'static [Device] {
+    DEVICES.try_get().map_or(&[], Vec::as_slice)
+}
+
+/// The first function with the IDs `vendor_id` and `device_id`.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<&'static Device> {
+    devices()
+        .iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// The functions of the class `class` and the sub-class `subclass`.
+pub fn find_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static Device> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn host_bridge_found() {
+        // every PC has a host bridge at 00:00.0, on QEMU the i440FX or the Q35 one
+        let host = &devices()[0];
+        assert_eq!(host.address, Address::new(0, 0, 0));
+        assert_eq!((host.class, host.subclass), (0x06, 0x00));
+        assert_eq!(host.vendor_id, 0x8086);
+        assert!(find_class(0x06, 0x00).any(|device| device == host));
+    }
+
+    #[test_case]
+    fn capabilities_listed() {
+        // transitional virtio devices also describe their modern interface in vendor capabilities
+        let net = find(0x1af4, 0x1000).expect("no virtio-net device");
+        let capabilities = net.capabilities();
+        assert!(capabilities
+            .iter()
+            .any(|capability| capability.id == CAPABILITY_VENDOR));
+        assert!(capabilities
+            .iter()
+            .all(|capability| capability.offset >= 0x40));
+    }
+
+    #[test_case]
+    fn scan_is_stable() {
+        // sizing restores the BARs, a second scan finds the same configuration
+        assert_eq!(scan(), devices());
+    }
+}
@@ -0,0 +1,141 @@
+//! A few bytes of state kept across reboots in the CMOS NVRAM.
+//!
+//! The CMOS memory is battery-backed on real hardware and survives a reset of the machine in QEMU,
+//! unlike the RAM the bootloader and the kernel overwrite on every boot. The kernel reserves the
+//! 16 bytes from [BASE], left unused by the BIOS of QEMU:
+//!
+//! ```text
+//! +0  magic, the other bytes are only valid if it's MAGIC
+//! +1  boot stage of a multi-stage test
+//! +2  number of crashes since the CMOS was reset
+//! +4  marker written by tests, 4 bytes little endian
+//! +8  line of the last panic, 4 bytes little endian
+//! ```
+//!
+//! A crash report also records the panic here, so the next boot can tell the previous one crashed.
+
+use crate::time::rtc;
+
+/// The first CMOS register reserved by the kernel.
+pub const BASE: u8 = 0x70;
+const MAGIC: u8 = 0x6b;
+
+const MAGIC_OFFSET: u8 = 0;
+const STAGE_OFFSET: u8 = 1;
+const CRASHES_OFFSET: u8 = 2;
+const MARKER_OFFSET: u8 = 4;
+const CRASH_LINE_OFFSET: u8 = 8;
+/// Number of reserved bytes.
+const SIZE: u8 = 16;
+
+/// The last crash recorded by a previous boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashRecord {
+    /// Number of crashes since the CMOS was reset, saturating at 255.
+    pub count: u8,
+    /// The source line of the last panic, 0 if it had no location.
+    pub line: u32,
+}
+
+fn read_u8(offset: u8) -> u8 {
+    rtc::read_nvram(BASE + offset)
+}
+
+fn write_u8(offset: u8, value: u8) {
+    rtc::write_nvram(BASE + offset, value);
+}
+
+fn read_u32(offset: u8) -> u32 {
+    let mut bytes = [0; 4];
+    for (i, byte) in (0..).zip(bytes.iter_mut()) {
+        *byte = read_u8(offset + i);
+    }
+    u32::from_le_bytes(bytes)
+}
+
+fn write_u32(offset: u8, value: u32) {
+    for (i, &byte) in (0..).zip(value.to_le_bytes().iter()) {
+        write_u8(offset + i, byte);
+    }
+}
+
+/// Whether the reserved bytes have been written by the kernel since the CMOS was reset.
+fn is_valid() -> bool {
+    read_u8(MAGIC_OFFSET) == MAGIC
+}
+
+/// Make the reserved bytes valid, zeroed if they weren't valid before.
+fn validate() {
+    if !is_valid() {
+        clear();
+    }
+}
+
+/// Reset all the reserved bytes.
+pub fn clear() {
+    for offset in 1..SIZE {
+        write_u8(offset, 0);
+    }
+    write_u8(MAGIC_OFFSET, MAGIC);
+}
+
+/// The boot stage of a multi-stage test, 0 if none was set. See [crate::testing::stage].
+pub fn stage() -> u8 {
+    if is_valid() {
+        read_u8(STAGE_OFFSET)
+    } else {
+        0
+    }
+}
+
+/// Set the boot stage kept for the next boot.
+pub fn set_stage(stage: u8) {
+    validate();
+    write_u8(STAGE_OFFSET, stage);
+}
+
+/// The marker written by [set_marker], `None` if there is none.
+pub fn marker() -> Option<u32> {
+    Some(read_u32(MARKER_OFFSET)).filter(|&marker| is_valid() && marker != 0)
+}
+
+/// Keep `marker` for the next boot, 0 clears the marker.
+pub fn set_marker(marker: u32) {
+    validate();
+    write_u32(MARKER_OFFSET, marker);
+}
+
+/// The last recorded crash, `None` if no crash was recorded since the CMOS was reset.
+pub fn last_crash() -> Option<CrashRecord> {
+    if !is_valid() {
+        return None;
+    }
+    match read_u8(CRASHES_OFFSET) {
+        0 => None,
+        count => Some(CrashRecord {
+            count,
+            line: read_u32(CRASH_LINE_OFFSET),
+        }),
+    }
+}
+
+/// Record a panic at `line`, called by [crate::crash::report]. Takes no lock and doesn't allocate.
+pub(crate) fn record_crash(line: u32) {
+    validate();
+    write_u8(CRASHES_OFFSET, read_u8(CRASHES_OFFSET).saturating_add(1));
+    write_u32(CRASH_LINE_OFFSET, line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn marker_round_trip() {
+        let previous = marker();
+        set_marker(0x1234_5678);
+        assert_eq!(marker(), Some(0x1234_5678));
+        assert_eq!(read_u8(MARKER_OFFSET), 0x78);
+        set_marker(previous.unwrap_or(0));
+    }
+}
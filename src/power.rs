@@ -0,0 +1,142 @@
+//! Rebooting and powering off the machine.
+//!
+//! [reboot] tries the reset register of the FADT first, then the reset line of the 8042 keyboard
+//! controller, and finally a triple fault which resets any x86 machine.
+//!
+//! [shutdown] enters the ACPI soft-off state S5 through the PM1 control blocks of the FADT. Without
+//! usable ACPI tables it falls back to the fixed PM1a control ports of emulators, then to the
+//! isa-debug-exit device of QEMU.
+
+use core::convert::TryFrom;
+
+use x86_64::{
+    instructions::{interrupts, port::Port, tables},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::{acpi, exit_qemu, i8042, memory, time, QemuExitCode};
+
+/// Set in the PM1 control registers to enter the sleep state of `SLP_TYP`.
+const SLP_EN: u16 = 1 << 13;
+/// The bit offset of `SLP_TYP` in the PM1 control registers.
+const SLP_TYP_SHIFT: u16 = 10;
+/// Set in the PM1 control registers once the power management events are routed to the SCI, i.e.
+/// ACPI is enabled.
+const SCI_EN: u16 = 1 << 0;
+/// The soft-off sleep state.
+const S5: u8 = 5;
+
+/// PM1a control ports entering S5 by [SLP_EN] alone: QEMU since 2.0 (both the i440FX and the Q35
+/// machines), Bochs and older QEMU, VirtualBox.
+const EMULATOR_PM1A_CONTROL_PORTS: [u16; 3] = [0x604, 0xb004, 0x4004];
+
+/// Power off the machine, never returns.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let Some((pm1a, pm1b, (slp_typa, slp_typb))) = acpi_soft_off() {
+        // # Safety
+        // The firmware describes the ports as the PM1 control blocks and the values as entering
+        // S5, which powers off the machine.
+        unsafe {
+            let mut pm1a = Port::<u16>::new(pm1a);
+            let control = pm1a.read() & !(0b111 << SLP_TYP_SHIFT);
+            pm1a.write(control | u16::from(slp_typa) << SLP_TYP_SHIFT | SLP_EN);
+            if let Some(pm1b) = pm1b {
+                let mut pm1b = Port::<u16>::new(pm1b);
+                let control = pm1b.read() & !(0b111 << SLP_TYP_SHIFT);
+                pm1b.write(control | u16::from(slp_typb) << SLP_TYP_SHIFT | SLP_EN);
+            }
+        }
+        time::delay_ms(10);
+    }
+
+    for &port in EMULATOR_PM1A_CONTROL_PORTS.iter() {
+        // # Safety
+        // Only emulators are expected to reach here, where the port is either their PM1a control
+        // block or unused.
+        unsafe { Port::<u16>::new(port).write(SLP_EN) };
+    }
+    time::delay_ms(10);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// The ports of the PM1a and PM1b control blocks and the `SLP_TYP` values of S5 for them, `None`
+/// if the FADT or the DSDT can't be read or ACPI can't be enabled.
+fn acpi_soft_off() -> Option<(u16, Option<u16>, (u8, u8))> {
+    if !memory::is_initialized() {
+        return None;
+    }
+    let fadt = acpi::fadt()?;
+    let pm1a = u16::try_from(fadt.pm1a_control_block)
+        .ok()
+        .filter(|&port| port != 0)?;
+    let pm1b = u16::try_from(fadt.pm1b_control_block)
+        .ok()
+        .filter(|&port| port != 0);
+    let sleep_types = acpi::sleep_types(S5)?;
+
+    // # Safety
+    // The ports are the PM1a control block and the SMI command port described by the firmware,
+    // writing the enable value only hands the power management registers to the OS.
+    unsafe {
+        let mut control = Port::<u16>::new(pm1a);
+        if control.read() & SCI_EN == 0 && fadt.smi_command_port != 0 && fadt.acpi_enable != 0 {
+            let smi_command = u16::try_from(fadt.smi_command_port).ok()?;
+            Port::<u8>::new(smi_command).write(fadt.acpi_enable);
+            for _ in 0..300 {
+                if control.read() & SCI_EN != 0 {
+                    break;
+                }
+                time::delay_ms(1);
+            }
+        }
+    }
+
+    Some((pm1a, pm1b, sleep_types))
+}
+
+/// Reset the machine, never returns.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some((port, value)) = acpi_reset_register() {
+        // # Safety
+        // The firmware describes the port as the reset register, writing the value resets the
+        // machine.
+        unsafe { Port::<u8>::new(port).write(value) };
+        time::delay_ms(10);
+    }
+
+    i8042::pulse_reset();
+    time::delay_ms(10);
+
+    // an empty IDT turns the breakpoint into a double fault, then into a triple fault
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    // # Safety
+    // The machine resets on the next exception, nothing runs with the empty IDT.
+    unsafe {
+        tables::lidt(&empty);
+    }
+    interrupts::int3();
+    unreachable!("the machine survived a triple fault");
+}
+
+/// The I/O port of the reset register and the value resetting the machine, `None` if the FADT
+/// doesn't describe a reset register in system I/O or the tables can't be read yet.
+fn acpi_reset_register() -> Option<(u16, u8)> {
+    if !memory::is_initialized() {
+        return None;
+    }
+    let (register, value) = acpi::fadt()?.reset?;
+    if register.address_space != acpi::fadt::ADDRESS_SPACE_IO {
+        return None;
+    }
+    let port = u16::try_from(register.address).ok()?;
+    Some((port, value))
+}
@@ -0,0 +1,552 @@
+//! Processes: the programs running in ring 3 alongside the tasks of the kernel.
+//!
+//! A process is a [Program] with a kernel stack of its own, listed in the process table under its
+//! [Pid] with its parent, its name and its [State]. A future drives each process: it resumes the
+//! program in ring 3 until it leaves, awaits the future of a blocked system call as any task
+//! would, gives the other tasks a turn when the program yields, and tears the program down when it
+//! exits. The address space with its frames, the open files and the kernel stack are freed at
+//! once, the process stays in the table as a zombie until its parent reaps it with [wait] or the
+//! `wait` system call. The zombies of the kernel, the processes with no parent, wait for it the
+//! same way. The processes whose parent exited have no one to wait for them: they are reaped as
+//! soon as they exit, and so are their zombies when the parent exits.
+//!
+//! The futures of the processes are polled by [run], a task of the executor, or by
+//! [crate::testing::run_processes_until] in the tests which can't await. A program gives the other
+//! tasks and processes a turn whenever it blocks or yields, or once it has computed for a time
+//! slice without a system call: it's preempted on the next timer tick, see [user::preempt].
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use futures_util::{
+    future::poll_fn,
+    stream::{FuturesUnordered, StreamExt},
+    task::AtomicWaker,
+};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{
+    fs::fd::FdTable,
+    metrics::{Counter, Gauge},
+    user::{self, Exit, Leave, Program, UserError},
+};
+
+/// The id of a process, unique among the programs loaded since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// A new id, the ids start at 1.
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The id `pid`, as the system calls take it.
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    /// The id as a number, as `getpid` returns it.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The state of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Waiting for its turn, e.g. after a yield.
+    Ready,
+    /// In ring 3 or in a system call.
+    Running,
+    /// Waiting for a system call to complete.
+    Blocked,
+    /// Exited, waiting to be reaped.
+    Zombie(Exit),
+}
+
+/// An error of [try_wait] and [wait].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The parent has no such child.
+    NoChild,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitError::NoChild => write!(f, "no child process"),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Ready => write!(f, "ready"),
+            State::Running => write!(f, "running"),
+            State::Blocked => write!(f, "blocked"),
+            State::Zombie(_) => write!(f, "zombie"),
+        }
+    }
+}
+
+/// A process of the table, as returned by [list].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// The id of the process.
+    pub pid: Pid,
+    /// The process which spawned it, `None` for the kernel.
+    pub parent: Option<Pid>,
+    /// The name of the process, the path of its program.
+    pub name: String,
+    /// The state of the process.
+    pub state: State,
+}
+
+/// The future driving a process.
+type Driver = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+lazy_static! {
+    static ref TABLE: Mutex<BTreeMap<Pid, ProcessInfo>> = Mutex::new(BTreeMap::new());
+    /// The processes taken from [SPAWNED], shared by [run] and
+    /// [crate::testing::run_processes_until].
+    static ref PROCESSES: Mutex<FuturesUnordered<Driver>> = Mutex::new(FuturesUnordered::new());
+}
+
+/// The processes spawned since they were last polled. Separate from [PROCESSES], which is locked
+/// while the processes run and spawn.
+static SPAWNED: Mutex<Vec<Driver>> = Mutex::new(Vec::new());
+/// The waker of the task of [run].
+static SPAWNED_WAKER: AtomicWaker = AtomicWaker::new();
+/// The wakers of [wait], woken whenever a process exits.
+static WAITING: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+static SPAWNS: Counter = Counter::new("process.spawned");
+static ALIVE: Gauge = Gauge::new("process.alive");
+
+/// Load `code` as a new process named `name`, a child of `parent`, returns its id. `code` is a
+/// static ELF64 executable or raw machine code, see [Program::load_any]. The process starts once
+/// [run] polls it, with the console open as its standard streams.
+pub fn spawn(name: &str, code: &[u8], parent: Option<Pid>) -> Result<Pid, UserError> {
+    spawn_with(name, code, parent, FdTable::with_console())
+}
+
+/// Spawn `code` as [spawn] does, with the descriptors of `fds`.
+pub fn spawn_with(
+    name: &str,
+    code: &[u8],
+    parent: Option<Pid>,
+    fds: FdTable,
+) -> Result<Pid, UserError> {
+    let mut program = Program::load_any(code, 0)?.with_kernel_stack();
+    program.fds = fds;
+    let pid = program.pid();
+    TABLE.lock().insert(
+        pid,
+        ProcessInfo {
+            pid,
+            parent,
+            name: String::from(name),
+            state: State::Ready,
+        },
+    );
+    SPAWNS.inc();
+    ALIVE.add(1);
+    SPAWNED.lock().push(Box::pin(drive(program)));
+    SPAWNED_WAKER.wake();
+    Ok(pid)
+}
+
+/// The processes of the table, ordered by id.
+pub fn list() -> Vec<ProcessInfo> {
+    TABLE.lock().values().cloned().collect()
+}
+
+/// The state of `pid`, `None` if there is no such process.
+pub fn state(pid: Pid) -> Option<State> {
+    TABLE.lock().get(&pid).map(|process| process.state)
+}
+
+/// Remove the zombie `pid` from the table, returns how it exited. `None` if it hasn't exited.
+pub fn reap(pid: Pid) -> Option<Exit> {
+    let mut table = TABLE.lock();
+    match table.get(&pid)?.state {
+        State::Zombie(exit) => {
+            table.remove(&pid);
+            Some(exit)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `process` is the child `pid` of `parent`, any of its children if `pid` is `None`.
+fn is_child(process: &ProcessInfo, parent: Option<Pid>, pid: Option<Pid>) -> bool {
+    process.parent == parent && pid.map_or(true, |pid| process.pid == pid)
+}
+
+/// Reap the child `pid` of `parent`, or any child of `parent` if `pid` is `None`, if it exited.
+/// Returns the child reaped and how it exited, `None` if the children are all alive.
+pub fn try_wait(parent: Option<Pid>, pid: Option<Pid>) -> Result<Option<(Pid, Exit)>, WaitError> {
+    let mut table = TABLE.lock();
+    let mut children = table
+        .values()
+        .filter(|process| is_child(process, parent, pid))
+        .peekable();
+    if children.peek().is_none() {
+        return Err(WaitError::NoChild);
+    }
+    let zombie = children.find_map(|process| match process.state {
+        State::Zombie(exit) => Some((process.pid, exit)),
+        _ => None,
+    });
+    if let Some((pid, _)) = zombie {
+        table.remove(&pid);
+    }
+    Ok(zombie)
+}
+
+/// Ready once the child `pid` of `parent` exited, or any of its children if `pid` is `None`, or if
+/// there is no such child. Reaps nothing, see [wait].
+pub fn child_exited(parent: Option<Pid>, pid: Option<Pid>) -> impl Future<Output = ()> {
+    poll_fn(move |cx| {
+        // registered under the lock of the table: an exit after the check wakes it
+        let table = TABLE.lock();
+        let mut children = table
+            .values()
+            .filter(|process| is_child(process, parent, pid))
+            .peekable();
+        if children.peek().is_none()
+            || children.any(|process| matches!(process.state, State::Zombie(_)))
+        {
+            return Poll::Ready(());
+        }
+        WAITING.lock().push(cx.waker().clone());
+        Poll::Pending
+    })
+}
+
+/// Wait for the child `pid` of `parent` to exit, or for any of its children if `pid` is `None`,
+/// and reap it. Returns the child reaped and how it exited.
+pub async fn wait(parent: Option<Pid>, pid: Option<Pid>) -> Result<(Pid, Exit), WaitError> {
+    loop {
+        if let Some(reaped) = try_wait(parent, pid)? {
+            return Ok(reaped);
+        }
+        child_exited(parent, pid).await;
+    }
+}
+
+fn set_state(pid: Pid, state: State) {
+    if let Some(process) = TABLE.lock().get_mut(&pid) {
+        process.state = state;
+    }
+}
+
+/// Turn `pid` into a zombie, or remove it if no one waits for it, and wake the waiters.
+fn exited(pid: Pid, exit: Exit) {
+    {
+        let mut table = TABLE.lock();
+        let orphan = match table.get(&pid).and_then(|process| process.parent) {
+            Some(parent) => matches!(
+                table.get(&parent).map(|parent| parent.state),
+                None | Some(State::Zombie(_))
+            ),
+            None => false,
+        };
+        // the zombie children of the process have no one to wait for them anymore
+        table.retain(|_, process| {
+            !(process.parent == Some(pid) && matches!(process.state, State::Zombie(_)))
+        });
+        if orphan {
+            table.remove(&pid);
+        } else if let Some(process) = table.get_mut(&pid) {
+            process.state = State::Zombie(exit);
+        }
+    }
+    // taken out of the lock: a waker may poll at once, or be the last one of a task
+    let waiting = core::mem::take(&mut *WAITING.lock());
+    for waker in waiting {
+        waker.wake();
+    }
+}
+
+/// Resume `program` until it exits, then free it.
+async fn drive(mut program: Program) {
+    let pid = program.pid();
+    let exit = loop {
+        set_state(pid, State::Running);
+        match user::resume(&mut program) {
+            Leave::Exited(exit) => break exit,
+            Leave::Blocked(wait) => {
+                set_state(pid, State::Blocked);
+                let completion = wait.await;
+                program.complete(completion);
+            }
+            Leave::Yielded => {
+                set_state(pid, State::Ready);
+                YieldNow(false).await;
+            }
+        }
+    };
+    // the address space with its frames, the open files and the kernel stack
+    drop(program);
+    ALIVE.add(-1);
+    exited(pid, exit);
+}
+
+/// Pending once, woken at once: the other tasks ready run before it's polled again.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Poll the processes with the waker of `cx`, the new ones included.
+pub(crate) fn poll_processes(cx: &mut Context<'_>) {
+    let mut processes = PROCESSES.lock();
+    processes.extend(core::mem::take(&mut *SPAWNED.lock()));
+    // the empty set of processes is a finished stream, not a pending one
+    while let Poll::Ready(Some(())) = processes.poll_next_unpin(cx) {}
+}
+
+/// Drive the processes, forever.
+pub async fn run() {
+    poll_fn(|cx| {
+        SPAWNED_WAKER.register(cx.waker());
+        poll_processes(cx);
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+/// Wake the task of [run], which takes the processes back after they were polled by another
+/// waker.
+pub(crate) fn wake_run() {
+    SPAWNED_WAKER.wake();
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        fs::{
+            self,
+            fd::{STDIN, STDOUT},
+            NodeKind,
+        },
+        testing,
+        time::tsc,
+        user::{
+            self,
+            syscall::{
+                self,
+                tests::{Code, DATA, RAX, RDI, RSI},
+            },
+            STACK_TOP,
+        },
+    };
+
+    /// Write `code` to the file at `path`.
+    fn install(path: &str, code: &Code) {
+        fs::create(path, NodeKind::File)
+            .unwrap()
+            .write_at(0, &code.0)
+            .unwrap();
+    }
+
+    /// A program spawning the program at `path`, then going on with `rax` holding the result.
+    fn spawner(path: &[u8]) -> Code {
+        Code::with_data(path)
+            .mov(RDI, DATA)
+            .mov(RSI, path.len() as u64)
+            .mov(RAX, syscall::SPAWN)
+            .syscall()
+    }
+
+    #[test_case]
+    fn processes_coexist() {
+        // the table and the timer wheel keep their nodes
+        testing::keep_allocations(|| {
+            let sleeper = Code::new()
+                .mov(RDI, 50)
+                .mov(RAX, syscall::SLEEP)
+                .syscall()
+                .mov(RDI, 1)
+                .mov(RAX, syscall::EXIT)
+                .syscall();
+            let quick = Code::new().mov(RDI, 2).mov(RAX, syscall::EXIT).syscall();
+            let sleeper = spawn("sleeper", &sleeper.0, None).unwrap();
+            let quick = spawn("quick", &quick.0, None).unwrap();
+            assert_eq!(state(quick), Some(State::Ready));
+            assert!(list().iter().any(|process| process.name == "sleeper"));
+
+            // the quick process exits while the other one sleeps
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(quick))),
+                Ok((quick, Exit::Exited(2)))
+            );
+            assert_eq!(state(sleeper), Some(State::Blocked));
+            assert_eq!(reap(sleeper), None);
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(sleeper))),
+                Ok((sleeper, Exit::Exited(1)))
+            );
+            assert_eq!(reap(quick), None);
+            assert_eq!(state(sleeper), None);
+            assert_eq!(try_wait(None, Some(quick)), Err(WaitError::NoChild));
+        });
+    }
+
+    #[test_case]
+    fn spinning_process_preempted() {
+        testing::keep_allocations(|| {
+            // many time slices without a system call
+            let spinner = Code::new()
+                .mov(RDI, tsc::nanos_to_cycles(200_000_000))
+                .op(user::tests::SPIN);
+            let quick = Code::new().mov(RDI, 3).mov(RAX, syscall::EXIT).syscall();
+            let spinner = spawn("spinner", &spinner.0, None).unwrap();
+            let quick = spawn("quick", &quick.0, None).unwrap();
+
+            // the quick process runs while the other one spins
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(quick))),
+                Ok((quick, Exit::Exited(3)))
+            );
+            assert_eq!(state(spinner), Some(State::Ready));
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(spinner))),
+                Ok((spinner, Exit::Breakpoint))
+            );
+        });
+    }
+
+    #[test_case]
+    fn children_waited_for() {
+        testing::keep_allocations(|| {
+            let child = Code::new()
+                .mov(RDI, 20)
+                .mov(RAX, syscall::SLEEP)
+                .syscall()
+                .mov(RDI, 5)
+                .mov(RAX, syscall::EXIT)
+                .syscall();
+            install("/tmp/waited", &child);
+            // wait for the child, then for any child, and exit with the status of the first
+            let parent = spawner(b"/tmp/waited")
+                // mov rdi, rax
+                .op(&[0x48, 0x89, 0xc7])
+                .mov(RSI, STACK_TOP - 8)
+                .mov(RAX, syscall::WAIT)
+                .syscall()
+                .mov(RDI, 0)
+                .mov(RAX, syscall::WAIT)
+                .syscall()
+                // cmp rax, -10 (ECHILD); jne +2; mov edi, [rsi]
+                .op(&[0x48, 0x83, 0xf8, 0xf6, 0x75, 0x02, 0x8b, 0x3e])
+                .mov(RAX, syscall::EXIT)
+                .syscall();
+            let parent = spawn("parent", &parent.0, None).unwrap();
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(parent))),
+                Ok((parent, Exit::Exited(5)))
+            );
+            assert!(list().iter().all(|process| process.name != "/tmp/waited"));
+        });
+    }
+
+    #[test_case]
+    fn orphans_reaped() {
+        testing::keep_allocations(|| {
+            let child = Code::new()
+                .mov(RDI, 20)
+                .mov(RAX, syscall::SLEEP)
+                .syscall()
+                .mov(RDI, 3)
+                .mov(RAX, syscall::EXIT)
+                .syscall();
+            install("/tmp/orphan", &child);
+            let parent = spawn(
+                "parent",
+                &spawner(b"/tmp/orphan").exit_with_result().0,
+                None,
+            )
+            .unwrap();
+            let child = match testing::run_processes_until(wait(None, Some(parent))) {
+                Ok((_, Exit::Exited(pid))) => Pid(pid as u64),
+                exit => panic!("spawn failed: {:?}", exit),
+            };
+            let orphan = list()
+                .into_iter()
+                .find(|process| process.pid == child)
+                .unwrap();
+            assert_eq!(orphan.parent, Some(parent));
+            assert_eq!(orphan.name, "/tmp/orphan");
+            // no one waits for the child, it's gone as soon as it exits
+            testing::run_processes_until(child_exited(Some(parent), Some(child)));
+            assert_eq!(state(child), None);
+        });
+    }
+
+    #[test_case]
+    fn pipes_connect_processes() {
+        testing::keep_allocations(|| {
+            let hello = fs::read("/boot/initrd/bin/hello").unwrap();
+            let upcase = fs::read("/boot/initrd/bin/upcase").unwrap();
+            let mut fds = FdTable::new();
+            let (input, to_upcase) = fds.pipe().unwrap();
+            let (output, from_upcase) = fds.pipe().unwrap();
+            let mut stage = FdTable::new();
+            stage.dup_to(to_upcase, STDOUT).unwrap();
+            let hello = spawn_with("hello", &hello, None, stage).unwrap();
+            let mut stage = FdTable::new();
+            stage.dup_to(input, STDIN).unwrap();
+            stage.dup_to(from_upcase, STDOUT).unwrap();
+            let upcase = spawn_with("upcase", &upcase, None, stage).unwrap();
+            for fd in [input, to_upcase, from_upcase].iter() {
+                fds.close(*fd).unwrap();
+            }
+
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(hello)))
+                    .unwrap()
+                    .1,
+                Exit::Exited(0)
+            );
+            assert_eq!(
+                testing::run_processes_until(wait(None, Some(upcase)))
+                    .unwrap()
+                    .1,
+                Exit::Exited(0)
+            );
+            let mut buf = [0; 32];
+            let len = fds.read(output, &mut buf).unwrap();
+            assert_eq!(&buf[..len], b"HELLO FROM RING 3!\n");
+            assert_eq!(fds.read(output, &mut buf), Ok(0));
+        });
+    }
+}
@@ -0,0 +1,36 @@
+//! Unpredictable random numbers for the kernel, e.g. for KASLR, TCP initial sequence numbers and
+//! keys, drawn from the [entropy] pool.
+//!
+//! The pool is a ChaCha20 generator seeded from RDSEED and RDRAND when the processor has them
+//! (retried as recommended by Intel), from the virtio entropy device and from timing jitter of the
+//! time stamp counter, so random numbers are available on any machine and at any point of the
+//! boot. Tests wanting reproducible sequences should use [crate::testing::Rng] seeded by
+//! [crate::testing::seed] instead.
+
+use crate::entropy;
+
+/// Fill `buf` with random bytes. Must not be called from interrupt handlers.
+pub fn fill(buf: &mut [u8]) {
+    entropy::fill(buf);
+}
+
+/// A random `u64`.
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn outputs_differ() {
+        let (mut a, mut b) = ([0; 33], [0; 33]);
+        fill(&mut a);
+        fill(&mut b);
+        assert_ne!(a, b);
+        assert_ne!(u64(), u64());
+    }
+}
@@ -0,0 +1,128 @@
+//! Resuming execution at a recorded point, e.g. the test framework after a panic or a timeout, or
+//! the kernel after a program in ring 3 raised an exception.
+//!
+//! [ResumePoint::catch] records a resume point before calling a closure, the panic handler or an
+//! interrupt handler jumps back to it with [ResumePoint::resume] instead of returning. The target
+//! has no unwinding, the frames of the abandoned code are simply discarded: destructors never run
+//! and locks held by those frames are never released.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::interrupts;
+
+/// The state restored on a resume, everything else is clobbered by [ResumePoint::catch].
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    rsp: u64,
+    rbp: u64,
+    rbx: u64,
+    rip: u64,
+    rflags: u64,
+    /// number of interrupt handlers on the stack when the resume point was recorded
+    nesting: usize,
+}
+
+extern "C" fn trampoline(f: *const &dyn Fn()) {
+    // # Safety
+    // `f` points to the closure borrowed by [ResumePoint::catch] for the duration of the call.
+    unsafe { (*f)() }
+}
+
+/// A slot for the innermost resume point of one kind, e.g. of the running test.
+pub(crate) struct ResumePoint(AtomicPtr<Context>);
+
+impl ResumePoint {
+    /// An empty slot.
+    pub(crate) const fn new() -> Self {
+        Self(AtomicPtr::new(ptr::null_mut()))
+    }
+
+    /// Call `f`, returns `true` if it was abandoned and resumed here.
+    pub(crate) fn catch(&self, f: &dyn Fn()) -> bool {
+        let mut context = Context {
+            nesting: interrupts::nesting(),
+            ..Context::default()
+        };
+        let previous = self.0.swap(&mut context, Ordering::SeqCst);
+
+        let resumed: u64;
+        // # Safety
+        // The stack pointer and the callee saved registers LLVM doesn't allow as operands are saved
+        // in `context` and restored on both paths, the other registers are declared as clobbered.
+        // The resume path enters at label 2 with r12 pointing to `context` again, as after the call.
+        unsafe {
+            asm!(
+                "lea rax, [rip + 2f]",
+                "mov [r12 + 24], rax",
+                "pushfq",
+                "pop qword ptr [r12 + 32]",
+                "mov [r12], rsp",
+                "mov [r12 + 8], rbp",
+                "mov [r12 + 16], rbx",
+                // the call may come from anywhere in the frame of this function
+                "and rsp, -16",
+                "call {trampoline}",
+                "mov rsp, [r12]",
+                "xor eax, eax",
+                "jmp 3f",
+                "2:",
+                "mov eax, 1",
+                "3:",
+                trampoline = in(reg) trampoline as extern "C" fn(*const &dyn Fn()),
+                inout("rdi") &f as *const &dyn Fn() => _,
+                inout("r12") &mut context as *mut Context => _,
+                out("rax") resumed,
+                lateout("rcx") _,
+                lateout("rdx") _,
+                lateout("rsi") _,
+                lateout("r8") _,
+                lateout("r9") _,
+                lateout("r10") _,
+                lateout("r11") _,
+                lateout("r13") _,
+                lateout("r14") _,
+                lateout("r15") _,
+            );
+        }
+
+        self.0.store(previous, Ordering::SeqCst);
+        resumed != 0
+    }
+
+    /// Forget the resume point without resuming, e.g. when its frame is abandoned by an outer
+    /// resume point.
+    pub(crate) fn clear(&self) {
+        self.0.store(ptr::null_mut(), Ordering::SeqCst);
+    }
+
+    /// Jump back to the innermost resume point of [ResumePoint::catch], returns if there is none.
+    /// Interrupt handlers entered since the resume point are forgotten, the interrupt flag is
+    /// restored to its state at the resume point.
+    ///
+    /// # Safety
+    /// All the frames since the resume point are discarded, the code running them must never
+    /// expect to return, e.g. the panic handler.
+    pub(crate) unsafe fn resume(&self) {
+        let context = self.0.swap(ptr::null_mut(), Ordering::SeqCst);
+        if context.is_null() {
+            return;
+        }
+
+        interrupts::abandon_handlers((*context).nesting);
+        asm!(
+            "mov rsp, [rdi]",
+            "mov rbp, [rdi + 8]",
+            "mov rbx, [rdi + 16]",
+            "push qword ptr [rdi + 32]",
+            "popfq",
+            "mov r12, rdi",
+            "jmp qword ptr [rdi + 24]",
+            in("rdi") context,
+            options(noreturn)
+        );
+    }
+}
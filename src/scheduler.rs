@@ -0,0 +1,424 @@
+//! Preemptive kernel threads, each one running a closure on a stack of its own.
+//!
+//! The threads run on the BSP, one at a time in round-robin order. A thread runs until its time
+//! slice of [TIME_SLICE_TICKS] is over, then the timer interrupt handler switches to the next ready
+//! thread, see [preempt]. A thread may also give up the rest of its slice with [yield_now], or end
+//! with [exit]. Only the code interrupted in ring 0 is preempted, the programs in ring 3 run their
+//! slices to the end of the system call or the exception leaving them.
+//!
+//! The thread which booted the kernel, the boot thread, runs the async executor on the boot stack:
+//! the executor yields to the other threads when it has no task ready instead of halting, and
+//! halts only when no other thread is ready. A context switch saves the callee-saved registers on
+//! the stack of the thread left and restores those of the thread resumed, the other registers are
+//! saved by the caller: either a function call, or the interrupt handler of the timer. The stacks
+//! of the threads exited are freed by the next thread yielding or spawning another thread, never in
+//! an interrupt handler.
+//!
+//! A spin lock held with interrupts enabled may be held by a preempted thread, the threads waiting
+//! for it spin until their slice is over. The locks also taken by interrupt handlers are held with
+//! interrupts disabled, their holders are never preempted, and so is a thread holding the lock of
+//! the heap allocator, which is also taken with interrupts disabled.
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::{
+    fmt, mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::{self, without_interrupts},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{allocator, interrupts as handlers, metrics::Counter, smp, time, user};
+
+/// The size of the stack of each thread.
+pub const STACK_SIZE: usize = 16 * 1024;
+/// The number of timer ticks a thread runs before it's preempted.
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+/// The number of threads the queues have room for before growing.
+const INITIAL_CAPACITY: usize = 8;
+/// The number of callee-saved registers pushed by [scheduler_switch].
+const SAVED_REGISTERS: usize = 6;
+
+static SWITCHES: Counter = Counter::new("scheduler.switches");
+
+// Switch from the thread whose stack pointer is saved to `[rdi]` to the one whose stack pointer is
+// `rsi`. A new thread starts in `scheduler_thread_start` with its entry in r13 and the argument of
+// its entry in r12, and an aligned stack once the return address is popped.
+global_asm!(
+    "
+.intel_syntax noprefix
+.global scheduler_switch
+scheduler_switch:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global scheduler_thread_start
+scheduler_thread_start:
+    mov rdi, r12
+    call r13
+    ud2
+.att_syntax
+"
+);
+
+extern "C" {
+    fn scheduler_switch(old_stack_pointer: *mut u64, new_stack_pointer: u64);
+    fn scheduler_thread_start();
+}
+
+/// A unique identifier of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// The boot thread.
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The closure run by a thread.
+type Entry = Box<dyn FnOnce() + Send>;
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    /// `None` for the boot thread, which runs on the boot stack.
+    stack: Option<Box<[u64]>>,
+    /// Saved by [scheduler_switch] while the thread isn't running.
+    stack_pointer: u64,
+}
+
+impl Thread {
+    /// Whether `stack_pointer` is within the stack of the thread, always true for the boot thread.
+    fn owns(&self, stack_pointer: u64) -> bool {
+        self.stack.as_ref().map_or(true, |stack| {
+            let base = stack.as_ptr() as u64;
+            (base..base + STACK_SIZE as u64).contains(&stack_pointer)
+        })
+    }
+}
+
+struct Scheduler {
+    current: Box<Thread>,
+    ready: VecDeque<Box<Thread>>,
+    /// The threads exited, freed by [reap]. Boxed as the stack pointer of the thread exiting is
+    /// saved in place while another processor may spawn a thread and grow the vector.
+    #[allow(clippy::vec_box)]
+    exited: Vec<Box<Thread>>,
+    /// The number of threads alive.
+    alive: usize,
+    /// The hardware tick the slice of the current thread ends at.
+    slice_end: u64,
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// Make the running code the boot thread. Must be called once on the BSP with the heap
+/// initialized, before any thread is spawned.
+pub fn init() {
+    let boot = Box::new(Thread {
+        id: ThreadId::BOOT,
+        name: "boot",
+        stack: None,
+        stack_pointer: 0,
+    });
+    without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            current: boot,
+            ready: VecDeque::with_capacity(INITIAL_CAPACITY),
+            exited: Vec::with_capacity(INITIAL_CAPACITY),
+            alive: 1,
+            slice_end: 0,
+        });
+    });
+}
+
+/// Start a thread named `name` running `f`, ready to run after the threads already ready. The
+/// thread exits once `f` returns.
+///
+/// # Panics
+/// Panics if [init] hasn't been called.
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> ThreadId {
+    reap();
+
+    let mut stack = vec![0u64; STACK_SIZE / 8].into_boxed_slice();
+    let base = stack.as_ptr() as usize;
+    // the return address to the start of the thread, which leaves the stack aligned, below the
+    // registers popped by the first switch to the thread
+    let start = ((base + STACK_SIZE) & !0xf) - 24;
+    let index = (start - base) / 8;
+    let entry: Box<Entry> = Box::new(Box::new(f));
+    stack[index] = scheduler_thread_start as unsafe extern "C" fn() as usize as u64;
+    // r12 and r13
+    stack[index - 3] = Box::into_raw(entry) as u64;
+    stack[index - 4] = thread_main as extern "C" fn(*mut Entry) -> ! as usize as u64;
+
+    let id = ThreadId::new();
+    let thread = Box::new(Thread {
+        id,
+        name,
+        stack: Some(stack),
+        stack_pointer: (start - SAVED_REGISTERS * 8) as u64,
+    });
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler not initialized");
+        scheduler.alive += 1;
+        // the queues never grow in the interrupt handlers switching threads
+        let alive = scheduler.alive;
+        scheduler.ready.reserve(alive);
+        scheduler.exited.reserve(alive);
+        scheduler.ready.push_back(thread);
+    });
+    id
+}
+
+/// Called by `scheduler_thread_start` on the stack of a new thread.
+extern "C" fn thread_main(entry: *mut Entry) -> ! {
+    // # Safety
+    // The thread was switched to with interrupts disabled, possibly by an interrupt handler whose
+    // frame is on the stack of another thread.
+    unsafe {
+        handlers::switch_handlers(0);
+    }
+    interrupts::enable();
+    // # Safety
+    // `entry` is the box leaked by [spawn] for this thread only.
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit();
+}
+
+/// Switch to the next ready thread, the current one exits if `exiting` is set or is queued after
+/// the ready threads otherwise. Returns once switched back, `false` right away if no other thread
+/// is ready. Must be called with interrupts disabled.
+fn switch(exiting: bool) -> bool {
+    let (old_stack_pointer, new_stack_pointer) = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = match scheduler.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return false,
+        };
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            None => return false,
+        };
+        assert!(
+            next.owns(next.stack_pointer),
+            "thread {} {} overflowed its stack",
+            next.id,
+            next.name
+        );
+        let previous = mem::replace(&mut scheduler.current, next);
+        scheduler.slice_end = time::hardware_ticks() + TIME_SLICE_TICKS;
+        let new_stack_pointer = scheduler.current.stack_pointer;
+        // the boxes don't move while in the queues, the stack pointer is saved in place
+        let previous = if exiting {
+            scheduler.alive -= 1;
+            scheduler.exited.push(previous);
+            scheduler.exited.last_mut()
+        } else {
+            scheduler.ready.push_back(previous);
+            scheduler.ready.back_mut()
+        };
+        let old_stack_pointer = &mut previous.unwrap().stack_pointer as *mut u64;
+        (old_stack_pointer, new_stack_pointer)
+    };
+
+    SWITCHES.inc();
+    let nesting = handlers::nesting();
+    // # Safety
+    // Interrupts are disabled, the new stack pointer was saved by a switch away from the thread or
+    // prepared by [spawn]. The old stack pointer is stored in the thread left, which stays alive
+    // until another thread switches back to it or reaps it after its exit.
+    unsafe {
+        scheduler_switch(old_stack_pointer, new_stack_pointer);
+        handlers::switch_handlers(nesting);
+    }
+    true
+}
+
+/// Free the stacks of the threads exited.
+fn reap() {
+    let exited = without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .map(|scheduler| scheduler.exited.drain(..).collect::<Vec<_>>())
+    });
+    drop(exited);
+}
+
+/// Switch to the next ready thread if the current one used up its slice, called by the timer
+/// interrupt handlers once they've signaled the end of the interrupt. Returns once switched back.
+/// A program interrupted in ring 3 is preempted by leaving ring 3 instead, see [user::preempt].
+pub(crate) fn preempt(stack_frame: &mut InterruptStackFrame) {
+    if user::from_user(stack_frame) {
+        user::preempt(stack_frame);
+        return;
+    }
+    // the heap is also allocated from with interrupts disabled, which would spin forever on the
+    // lock of a preempted thread
+    if smp::current() != 0 || allocator::is_locked() {
+        return;
+    }
+    let due = SCHEDULER.lock().as_ref().map_or(false, |scheduler| {
+        time::hardware_ticks() >= scheduler.slice_end && !scheduler.ready.is_empty()
+    });
+    if due {
+        switch(false);
+    }
+}
+
+/// Give up the rest of the slice of the current thread, returns `false` right away if no other
+/// thread is ready or if called on an AP. Must be called with interrupts enabled.
+pub fn yield_now() -> bool {
+    if smp::current() != 0 {
+        return false;
+    }
+    let switched = without_interrupts(|| switch(false));
+    reap();
+    switched
+}
+
+/// Whether a thread other than the current one is ready to run.
+pub fn has_ready() -> bool {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(false, |scheduler| !scheduler.ready.is_empty())
+    })
+}
+
+/// End the current thread, its stack is freed later by another thread. Must not be called by the
+/// boot thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    assert!(current() != ThreadId::BOOT, "the boot thread can't exit");
+    // the boot thread never exits, it's ready if no other thread is
+    switch(true);
+    unreachable!("an exited thread was resumed");
+}
+
+/// The thread running on the BSP, the boot thread before [init].
+pub fn current() -> ThreadId {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(ThreadId::BOOT, |scheduler| scheduler.current.id)
+    })
+}
+
+/// A thread alive, see [threads].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadInfo {
+    /// The identifier of the thread.
+    pub id: ThreadId,
+    /// The name of the thread.
+    pub name: &'static str,
+    /// Whether it's the thread running.
+    pub running: bool,
+}
+
+/// The threads alive, the running one first, then the ready ones in the order they run.
+pub fn threads() -> Vec<ThreadInfo> {
+    let info = |thread: &Thread, running| ThreadInfo {
+        id: thread.id,
+        name: thread.name,
+        running,
+    };
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or_else(Vec::new, |scheduler| {
+                core::iter::once(info(&scheduler.current, true))
+                    .chain(scheduler.ready.iter().map(|thread| info(thread, false)))
+                    .collect()
+            })
+    })
+}
+
+/// Whether the thread `id` is alive.
+pub fn is_alive(id: ThreadId) -> bool {
+    without_interrupts(|| {
+        SCHEDULER.lock().as_ref().map_or(false, |scheduler| {
+            scheduler.current.id == id || scheduler.ready.iter().any(|thread| thread.id == id)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+
+    static SPINS: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static RAN_ON: AtomicU64 = AtomicU64::new(0);
+
+    #[test_case]
+    fn threads_preempted() {
+        STOP.store(false, Ordering::Relaxed);
+        SPINS.store(0, Ordering::Relaxed);
+        let spinner = spawn("spinner", || {
+            while !STOP.load(Ordering::Relaxed) {
+                SPINS.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        assert!(is_alive(spinner));
+        assert_eq!(threads()[0].id, ThreadId::BOOT);
+
+        // the boot thread never yields, only the timer switches to the spinner
+        let deadline = time::hardware_ticks() + 100 * TIME_SLICE_TICKS;
+        while SPINS.load(Ordering::Relaxed) == 0 && time::hardware_ticks() < deadline {
+            core::hint::spin_loop();
+        }
+        assert!(SPINS.load(Ordering::Relaxed) > 0);
+
+        STOP.store(true, Ordering::Relaxed);
+        while is_alive(spinner) {
+            yield_now();
+        }
+        assert_eq!(current(), ThreadId::BOOT);
+    }
+
+    #[test_case]
+    fn yield_runs_ready_threads() {
+        RAN_ON.store(0, Ordering::Relaxed);
+        let thread = spawn("yielder", || RAN_ON.store(current().0, Ordering::Relaxed));
+        assert!(yield_now());
+        assert_eq!(RAN_ON.load(Ordering::Relaxed), thread.0);
+        assert!(!is_alive(thread));
+        assert!(!yield_now());
+    }
+}
@@ -1,17 +1,81 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::{io::PortRange, locked::Locked};
+
+/// Base I/O port of the first serial port.
+const SERIAL1_PORT: u16 = 0x3F8;
+/// Base I/O port of the second serial port.
+const SERIAL2_PORT: u16 = 0x2F8;
+/// Number of I/O ports of a serial port.
+const PORT_COUNT: u16 = 8;
+/// Offset of the line status register from the base port.
+const LINE_STATUS_OFFSET: u16 = 5;
+/// Bit in the line status register set when a received byte is ready to be read.
+const DATA_READY: u8 = 1;
+/// Bit in the line status register set when the transmitter can take another byte.
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
 lazy_static! {
+    /// The ports of the first serial port, shared by [SERIAL1] and [try_receive].
+    ///
+    /// # Safety
+    /// 0x3F8 maps to COM1 in QEMU, the UART doesn't access memory.
+    static ref COM1: PortRange = unsafe { PortRange::claim(SERIAL1_PORT, PORT_COUNT, "serial") }
+        .expect("the ports of COM1 are claimed by another driver");
+
     /// The global interface to the first serial port in QEMU.
     ///
     /// # Safety
-    /// 0x3F8 maps to COM1 in QEMU, lazy_static ensures [SERIAL1] is constructed exactly once.
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    /// The ports of COM1 are claimed, lazy_static ensures [SERIAL1] is constructed exactly once.
+    /// [SerialPort::init] also enables the interrupt on received data.
+    pub static ref SERIAL1: Locked<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1.base()) };
         serial_port.init();
-        Mutex::new(serial_port)
+        Locked::new(serial_port)
     };
+
+    /// The ports of the second serial port, a binary channel to the host, e.g. for
+    /// [crate::net::pcap]. Its interrupts are left disabled.
+    ///
+    /// # Safety
+    /// 0x2F8 maps to COM2 in QEMU, the UART doesn't access memory.
+    static ref COM2: Locked<PortRange> = {
+        let ports = unsafe { PortRange::claim(SERIAL2_PORT, PORT_COUNT, "serial") }
+            .expect("the ports of COM2 are claimed by another driver");
+        // 38400 baud, 8 data bits, no parity, one stop bit, FIFOs enabled, as COM1
+        ports.write(1, 0u8);
+        ports.write(3, 0x80u8);
+        ports.write(0, 3u8);
+        ports.write(1, 0u8);
+        ports.write(3, 0x03u8);
+        ports.write(2, 0xc7u8);
+        ports.write(4, 0x0bu8);
+        Locked::new(ports)
+    };
+}
+
+/// Write `bytes` to the second serial port as they are. Unlike [SERIAL1], which writes text and
+/// translates the backspace characters, any byte goes through. Writes to a port not connected by
+/// QEMU are lost.
+pub fn write_com2(bytes: &[u8]) {
+    let com2 = COM2.lock();
+    for &byte in bytes {
+        while com2.read::<u8>(LINE_STATUS_OFFSET) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        com2.write(0, byte);
+    }
+}
+
+/// Read a byte received by the first serial port, `None` if no byte is ready.
+pub(crate) fn try_receive() -> Option<u8> {
+    // reading the data port consumes the received byte
+    if COM1.read::<u8>(LINE_STATUS_OFFSET) & DATA_READY != 0 {
+        Some(COM1.read(0))
+    } else {
+        None
+    }
 }
 
 #[doc(hidden)]
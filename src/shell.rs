@@ -0,0 +1,475 @@
+//! A minimal interactive shell. Other modules may register their own commands at init time by
+//! [register], the shell itself knows nothing about the commands it dispatches to.
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt, future::Future, pin::Pin, str::FromStr};
+
+use futures_util::{pin_mut, Stream, StreamExt};
+use lazy_static::lazy_static;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+use self::{
+    console::{SerialConsole, VgaConsole},
+    editor::LineEditor,
+};
+pub use crate::cmdline::parse_usize;
+use crate::fs::FsError;
+
+pub mod console;
+mod diagnostics;
+pub mod editor;
+mod files;
+mod keymap;
+mod network;
+mod peek;
+mod power;
+mod programs;
+
+/// The prompt printed before each line of input.
+const PROMPT: &str = "> ";
+
+/// The signature of a command handler. Arguments following the command name are supplied in
+/// `args`, output of the command should be written to `out` instead of printed directly.
+pub type Handler = fn(args: Args, out: &mut dyn fmt::Write) -> Result<(), ShellError>;
+
+/// The rest of a command still running once its handler returns, e.g. waiting for processes, left
+/// by [Args::foreground]. The session awaits it before the next prompt while the other tasks run,
+/// it resolves to the rest of the output of the command.
+pub type Job = Pin<Box<dyn Future<Output = Result<String, ShellError>>>>;
+
+/// A command registered to the shell.
+#[derive(Clone, Copy)]
+pub struct Command {
+    /// A one-line synopsis of the arguments, e.g. `hexdump <addr> <len>`.
+    pub usage: &'static str,
+    /// A one-line description of the command printed by `help`.
+    pub help: &'static str,
+    /// The function invoked when the command is entered.
+    pub handler: Handler,
+}
+
+/// The signature of an argument completer. Returns all the possible values of the `index`-th
+/// argument (counted from 0) of a command, the shell then filters them by what has been typed.
+pub type Completer = fn(index: usize) -> Vec<String>;
+
+lazy_static! {
+    static ref COMMANDS: Mutex<BTreeMap<&'static str, Command>> = {
+        let mut commands = BTreeMap::new();
+        commands.insert(
+            "help",
+            Command {
+                usage: "help [command]",
+                help: "list all commands or show the usage of one command",
+                handler: help,
+            },
+        );
+        for &(name, command) in diagnostics::COMMANDS
+            .iter()
+            .chain(files::COMMANDS)
+            .chain(keymap::COMMANDS)
+            .chain(network::COMMANDS)
+            .chain(peek::COMMANDS)
+            .chain(power::COMMANDS)
+            .chain(programs::COMMANDS)
+        {
+            commands.insert(name, command);
+        }
+        Mutex::new(commands)
+    };
+    static ref COMPLETERS: Mutex<BTreeMap<&'static str, Completer>> = {
+        let mut completers = BTreeMap::new();
+        completers.insert("help", complete_help as Completer);
+        completers.insert("loglevel", diagnostics::complete_loglevel);
+        completers.insert("trace", diagnostics::complete_trace);
+        completers.insert("mount", files::complete_mount);
+        completers.insert("keymap", keymap::complete_keymap);
+        completers.insert("ifconfig", network::complete_ifconfig);
+        completers.insert("pcap", network::complete_pcap);
+        Mutex::new(completers)
+    };
+}
+
+/// Register a command to the shell under `name`. Registering the same name twice causes kernel
+/// panic.
+pub fn register(name: &'static str, command: Command) {
+    if COMMANDS.lock().insert(name, command).is_some() {
+        panic!("shell command {} is registered twice", name);
+    }
+}
+
+/// Register a completer for the arguments of the command `name`, replacing the previous completer
+/// of the command if there is any. The command itself doesn't have to be registered yet.
+pub fn register_completer(name: &'static str, completer: Completer) {
+    COMPLETERS.lock().insert(name, completer);
+}
+
+/// Errors returned by command handlers and the shell itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    /// No command is registered under the name.
+    UnknownCommand,
+    /// A required argument is missing, carries the name of the argument.
+    MissingArgument(&'static str),
+    /// An argument failed to parse, carries the name of the argument.
+    InvalidArgument(&'static str),
+    /// More arguments are supplied than the command accepts.
+    TooManyArguments,
+    /// The command failed for a reason specific to the command.
+    Failed(&'static str),
+    /// A file operation of the command failed.
+    Fs(FsError),
+}
+
+impl From<FsError> for ShellError {
+    fn from(err: FsError) -> Self {
+        ShellError::Fs(err)
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand => write!(f, "unknown command"),
+            ShellError::MissingArgument(name) => write!(f, "missing argument <{}>", name),
+            ShellError::InvalidArgument(name) => write!(f, "invalid argument <{}>", name),
+            ShellError::TooManyArguments => write!(f, "too many arguments"),
+            ShellError::Failed(reason) => write!(f, "{}", reason),
+            ShellError::Fs(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Whitespace separated arguments of a command, with helpers to parse them in order.
+pub struct Args<'a> {
+    inner: core::str::SplitWhitespace<'a>,
+    /// Where the job of the command is left for the session, `None` outside of [execute].
+    job: Option<&'a mut Option<Job>>,
+}
+
+impl<'a> Args<'a> {
+    /// Split `args` by whitespaces.
+    pub fn new(args: &'a str) -> Self {
+        Self {
+            inner: args.split_whitespace(),
+            job: None,
+        }
+    }
+
+    /// Leave `job` running once the handler returns, the command completes with it.
+    pub fn foreground(&mut self, job: Job) -> Result<(), ShellError> {
+        match &mut self.job {
+            Some(slot) => {
+                **slot = Some(job);
+                Ok(())
+            }
+            None => Err(ShellError::Failed("not run by a shell session")),
+        }
+    }
+
+    /// Take the next argument, `name` is only used in the error message.
+    pub fn next_str(&mut self, name: &'static str) -> Result<&'a str, ShellError> {
+        self.inner.next().ok_or(ShellError::MissingArgument(name))
+    }
+
+    /// Take the next argument and parse it by [FromStr].
+    pub fn next_parsed<T: FromStr>(&mut self, name: &'static str) -> Result<T, ShellError> {
+        self.next_str(name)?
+            .parse()
+            .map_err(|_| ShellError::InvalidArgument(name))
+    }
+
+    /// Take the next argument as an unsigned integer, either in decimal or in hexadecimal with a
+    /// `0x` prefix.
+    pub fn next_usize(&mut self, name: &'static str) -> Result<usize, ShellError> {
+        parse_usize(self.next_str(name)?).ok_or(ShellError::InvalidArgument(name))
+    }
+
+    /// Take the next argument if there is any.
+    pub fn optional(&mut self) -> Option<&'a str> {
+        self.inner.next()
+    }
+
+    /// Take all the remaining arguments.
+    pub fn rest(self) -> Vec<&'a str> {
+        self.inner.collect()
+    }
+
+    /// Ensure all the arguments are consumed.
+    pub fn finish(mut self) -> Result<(), ShellError> {
+        match self.inner.next() {
+            Some(_) => Err(ShellError::TooManyArguments),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Execute a line of input, output of the command is written to `out`. Empty lines are ignored.
+/// Completes with the job of the command, see [Job].
+pub async fn execute(line: &str, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let mut job = None;
+    start(line, &mut job, out)?;
+    if let Some(job) = job {
+        out.write_str(&job.await?).unwrap();
+    }
+    Ok(())
+}
+
+/// Run the handler of the command on `line`, which may leave a job in `job`.
+fn start(line: &str, job: &mut Option<Job>, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let line = line.trim();
+    let (name, args) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], &line[i..]),
+        None => (line, ""),
+    };
+
+    if name.is_empty() {
+        return Ok(());
+    }
+
+    // the lock must be released before running the handler, which may well look into the
+    // registry itself
+    let command = COMMANDS
+        .lock()
+        .get(name)
+        .copied()
+        .ok_or(ShellError::UnknownCommand)?;
+
+    let args = Args {
+        inner: args.split_whitespace(),
+        job: Some(job),
+    };
+    (command.handler)(args, out)
+}
+
+/// Find the completions of the last word in `line`. Returns the index into `line` where the last
+/// word starts, and all the candidates for the word in lexicographical order. The first word is
+/// completed as a command name, the others by the completer registered for the command.
+pub fn complete(line: &str) -> (usize, Vec<String>) {
+    let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+    let word = &line[start..];
+    let mut words = line[..start].split_whitespace();
+
+    let mut candidates = match words.next() {
+        None => COMMANDS
+            .lock()
+            .keys()
+            .map(|name| name.to_string())
+            .collect(),
+        Some(name) => {
+            // the lock must be released before running the completer, like command handlers
+            let completer = COMPLETERS.lock().get(name).copied();
+            match completer {
+                Some(completer) => completer(words.count()),
+                None => Vec::new(),
+            }
+        }
+    };
+
+    candidates.retain(|candidate| candidate.starts_with(word));
+    candidates.sort_unstable();
+    candidates.dedup();
+    (start, candidates)
+}
+
+fn complete_help(index: usize) -> Vec<String> {
+    if index == 0 {
+        COMMANDS
+            .lock()
+            .keys()
+            .map(|name| name.to_string())
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+fn help(mut args: Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let name = args.optional();
+    args.finish()?;
+
+    let commands = COMMANDS.lock();
+    match name {
+        Some(name) => {
+            let command = commands.get(name).ok_or(ShellError::UnknownCommand)?;
+            writeln!(out, "usage: {}", command.usage).unwrap();
+            writeln!(out, "{}", command.help).unwrap();
+        }
+        None => {
+            for (name, command) in commands.iter() {
+                writeln!(out, "{:<12}{}", name, command.help).unwrap();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A text console the shell runs on. Besides writing text, the cursor can be moved on the
+/// console to edit the current line.
+pub trait Console: fmt::Write {
+    /// Move the cursor `n` characters to the left.
+    fn cursor_left(&mut self, n: usize);
+    /// Move the cursor `n` characters to the right.
+    fn cursor_right(&mut self, n: usize);
+}
+
+/// Run a shell session on `console` until `keys` ends: read lines from `keys`, execute them as
+/// commands and write the output to `console`. Each session has its own line and history.
+pub async fn run(console: &mut dyn Console, keys: impl Stream<Item = DecodedKey>) {
+    pin_mut!(keys);
+    let mut editor = LineEditor::new(PROMPT);
+
+    editor.print_prompt(console);
+
+    while let Some(key) = keys.next().await {
+        let line = match editor.handle_key(key, console) {
+            Some(line) => line,
+            None => continue,
+        };
+
+        if let Err(err) = execute(&line, console).await {
+            writeln!(console, "error: {}", err).unwrap();
+        }
+        editor.print_prompt(console);
+    }
+}
+
+/// Run a shell session on the VGA text buffer with input from the keyboard.
+pub async fn run_vga() {
+    run(&mut VgaConsole, console::keyboard_keys()).await
+}
+
+/// Run a shell session on a terminal connected to the first serial port.
+pub async fn run_serial() {
+    run(&mut SerialConsole, console::serial_keys()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A console discarding all output.
+    pub(crate) struct Sink;
+
+    impl fmt::Write for Sink {
+        fn write_str(&mut self, _s: &str) -> fmt::Result {
+            Ok(())
+        }
+    }
+
+    impl Console for Sink {
+        fn cursor_left(&mut self, _n: usize) {}
+        fn cursor_right(&mut self, _n: usize) {}
+    }
+
+    /// Execute `line` as the sessions do, driving the processes while the command waits.
+    pub(crate) fn execute(line: &str, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+        testing::run_processes_until(super::execute(line, out))
+    }
+
+    /// Build the lazily initialized registries, they are never freed and not leaked by the test
+    /// touching them first.
+    pub(crate) fn build_registries() {
+        testing::keep_allocations(|| {
+            lazy_static::initialize(&COMMANDS);
+            lazy_static::initialize(&COMPLETERS);
+        });
+    }
+
+    #[test_case]
+    fn parse_args() {
+        let mut args = Args::new(" 0x10  42 foo ");
+        assert_eq!(args.next_usize("a"), Ok(0x10));
+        assert_eq!(args.next_parsed::<u8>("b"), Ok(42));
+        assert_eq!(args.next_usize("c"), Err(ShellError::InvalidArgument("c")));
+        assert_eq!(args.next_str("d"), Err(ShellError::MissingArgument("d")));
+    }
+
+    #[test_case]
+    fn dispatch_commands() {
+        fn echo(mut args: Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+            let word = args.next_str("word")?;
+            args.finish()?;
+            write!(out, "{}", word).unwrap();
+            Ok(())
+        }
+
+        build_registries();
+        testing::keep_allocations(|| {
+            register(
+                "test-echo",
+                Command {
+                    usage: "test-echo <word>",
+                    help: "write a single word",
+                    handler: echo,
+                },
+            )
+        });
+
+        assert_eq!(execute("", &mut Sink), Ok(()));
+        assert_eq!(execute("test-echo hello", &mut Sink), Ok(()));
+        assert_eq!(execute("help test-echo", &mut Sink), Ok(()));
+        assert_eq!(
+            execute("test-echo a b", &mut Sink),
+            Err(ShellError::TooManyArguments)
+        );
+        assert_eq!(
+            execute("no-such-command", &mut Sink),
+            Err(ShellError::UnknownCommand)
+        );
+    }
+
+    #[test_case]
+    fn jobs_awaited() {
+        use crate::task::{executor::Executor, sync::Notify, Task};
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        static DONE: Notify = Notify::new();
+
+        fn later(mut args: Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+            write!(out, "started ").unwrap();
+            args.foreground(Box::pin(async {
+                DONE.notified().await;
+                Ok(String::from("done"))
+            }))?;
+            args.finish()
+        }
+
+        build_registries();
+        testing::keep_allocations(|| {
+            register(
+                "test-later",
+                Command {
+                    usage: "test-later",
+                    help: "write a word once notified",
+                    handler: later,
+                },
+            )
+        });
+        assert_eq!(
+            Args::new("").foreground(Box::pin(async { Ok(String::new()) })),
+            Err(ShellError::Failed("not run by a shell session"))
+        );
+
+        // the other tasks run while the session waits for the job
+        let output = Rc::new(RefCell::new(String::new()));
+        let session_output = Rc::clone(&output);
+        let mut executor = Executor::new();
+        executor.spawn(Task::named("session", async move {
+            let mut out = String::new();
+            assert_eq!(super::execute("test-later", &mut out).await, Ok(()));
+            *session_output.borrow_mut() = out;
+        }));
+        executor.spawn(Task::named("notifier", async { DONE.notify() }));
+        executor.run_until_idle();
+        assert_eq!(*output.borrow(), "started done");
+    }
+}
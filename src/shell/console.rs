@@ -0,0 +1,174 @@
+//! Consoles the shell can run on, each a pair of an output [Console] and a stream of keys.
+
+use core::fmt;
+
+use futures_util::{future, Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::Console;
+use crate::{
+    print, serial_print,
+    task::{
+        keyboard::{self, ScancodeStream, ScrollKeys},
+        serial::SerialStream,
+    },
+    vga_buffer,
+};
+
+/// The VGA text buffer as a console.
+pub struct VgaConsole;
+
+impl fmt::Write for VgaConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+impl Console for VgaConsole {
+    fn cursor_left(&mut self, n: usize) {
+        vga_buffer::cursor_left(n);
+    }
+
+    fn cursor_right(&mut self, n: usize) {
+        vga_buffer::cursor_right(n);
+    }
+}
+
+/// Keys typed on the PS/2 keyboard, except Shift+PageUp and Shift+PageDown which scroll the VGA
+/// text buffer. Can only be called once, see [ScancodeStream::new].
+pub fn keyboard_keys() -> impl Stream<Item = DecodedKey> {
+    let mut keyboard = keyboard::decoder();
+    let mut scroll_keys = ScrollKeys::new();
+
+    ScancodeStream::new().filter_map(move |scancode| {
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+            _ => None,
+        };
+        future::ready(scroll_keys.filter(scancode, key))
+    })
+}
+
+/// A serial terminal on the first serial port as a console. The terminal is expected to
+/// understand ANSI escape sequences.
+pub struct SerialConsole;
+
+impl fmt::Write for SerialConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // the terminal is most likely in raw mode, where a line feed doesn't return the carriage
+        let mut lines = s.split('\n');
+        if let Some(line) = lines.next() {
+            serial_print!("{}", line);
+        }
+        for line in lines {
+            serial_print!("\r\n{}", line);
+        }
+        Ok(())
+    }
+}
+
+impl Console for SerialConsole {
+    fn cursor_left(&mut self, n: usize) {
+        if n > 0 {
+            serial_print!("\x1b[{}D", n);
+        }
+    }
+
+    fn cursor_right(&mut self, n: usize) {
+        if n > 0 {
+            serial_print!("\x1b[{}C", n);
+        }
+    }
+}
+
+/// State of the decoder of bytes received from a serial terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    /// not in an escape sequence
+    Ground,
+    /// after ESC
+    Escape,
+    /// after ESC [ with an optional numeric parameter
+    ControlSequence(u8),
+}
+
+/// Keys typed on the serial terminal. Can only be called once, see [SerialStream::new].
+pub fn serial_keys() -> impl Stream<Item = DecodedKey> {
+    let mut state = DecoderState::Ground;
+
+    SerialStream::new().filter_map(move |byte| {
+        let (next, key) = decode(state, byte);
+        state = next;
+        future::ready(key)
+    })
+}
+
+/// Decode a byte received from a serial terminal in `state`, returns the next state and
+/// possibly a decoded key. Unrecognized escape sequences are dropped.
+fn decode(state: DecoderState, byte: u8) -> (DecoderState, Option<DecodedKey>) {
+    const ESC: u8 = 0x1b;
+
+    match (state, byte) {
+        (DecoderState::Ground, ESC) => (DecoderState::Escape, None),
+        // terminals send carriage returns on the enter key
+        (DecoderState::Ground, b'\r') => (DecoderState::Ground, Some(DecodedKey::Unicode('\n'))),
+        // most terminals send DEL on the backspace key
+        (DecoderState::Ground, 0x7f) => (DecoderState::Ground, Some(DecodedKey::Unicode('\u{8}'))),
+        (DecoderState::Ground, byte) if byte.is_ascii() => (
+            DecoderState::Ground,
+            Some(DecodedKey::Unicode(char::from(byte))),
+        ),
+        (DecoderState::Ground, _) => (DecoderState::Ground, None),
+        (DecoderState::Escape, b'[') => (DecoderState::ControlSequence(0), None),
+        (DecoderState::Escape, _) => (DecoderState::Ground, None),
+        (DecoderState::ControlSequence(param), b'0'..=b'9') => {
+            let param = param.saturating_mul(10).saturating_add(byte - b'0');
+            (DecoderState::ControlSequence(param), None)
+        }
+        (DecoderState::ControlSequence(param), byte) => {
+            let code = match (byte, param) {
+                (b'A', _) => Some(KeyCode::ArrowUp),
+                (b'B', _) => Some(KeyCode::ArrowDown),
+                (b'C', _) => Some(KeyCode::ArrowRight),
+                (b'D', _) => Some(KeyCode::ArrowLeft),
+                (b'H', _) | (b'~', 1) | (b'~', 7) => Some(KeyCode::Home),
+                (b'F', _) | (b'~', 4) | (b'~', 8) => Some(KeyCode::End),
+                (b'~', 3) => Some(KeyCode::Delete),
+                _ => None,
+            };
+            (DecoderState::Ground, code.map(DecodedKey::RawKey))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Option<DecodedKey> {
+        let mut state = DecoderState::Ground;
+        let mut last = None;
+        for &byte in bytes {
+            let (next, key) = decode(state, byte);
+            state = next;
+            last = key;
+        }
+        last
+    }
+
+    #[test_case]
+    fn decode_serial_input() {
+        assert_eq!(decode_all(b"a"), Some(DecodedKey::Unicode('a')));
+        assert_eq!(decode_all(b"\r"), Some(DecodedKey::Unicode('\n')));
+        assert_eq!(
+            decode_all(b"\x1b[D"),
+            Some(DecodedKey::RawKey(KeyCode::ArrowLeft))
+        );
+        assert_eq!(
+            decode_all(b"\x1b[3~"),
+            Some(DecodedKey::RawKey(KeyCode::Delete))
+        );
+        assert_eq!(decode_all(b"\x1b[99Z"), None);
+    }
+}
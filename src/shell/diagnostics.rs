@@ -0,0 +1,606 @@
+//! Built-in diagnostic commands rendering statistics of the kernel subsystems.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use super::{parse_usize, Args, Command, ShellError};
+use crate::{
+    allocator, boot, interrupts, klog, memory, metrics, pci, serial, smp,
+    task::executor,
+    time,
+    tracepoint::{self, Subsystem},
+    vga_buffer,
+};
+
+/// The diagnostic commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "meminfo",
+        Command {
+            usage: "meminfo",
+            help: "show statistics of the kernel heap allocator",
+            handler: meminfo,
+        },
+    ),
+    (
+        "ps",
+        Command {
+            usage: "ps",
+            help: "list the tasks alive on the executors",
+            handler: ps,
+        },
+    ),
+    (
+        "irqstat",
+        Command {
+            usage: "irqstat",
+            help: "show the number of handled interrupts per vector",
+            handler: irqstat,
+        },
+    ),
+    (
+        "cpus",
+        Command {
+            usage: "cpus",
+            help: "list the processors with their local APIC IDs",
+            handler: cpus,
+        },
+    ),
+    (
+        "uptime",
+        Command {
+            usage: "uptime",
+            help: "show the wall-clock time and the time elapsed since boot",
+            handler: uptime,
+        },
+    ),
+    (
+        "boottime",
+        Command {
+            usage: "boottime",
+            help: "show the time spent on each step of the kernel initialization",
+            handler: boottime,
+        },
+    ),
+    (
+        "metrics",
+        Command {
+            usage: "metrics [prefix]",
+            help:
+                "show the registered metrics, only those with names starting with prefix if given",
+            handler: metrics,
+        },
+    ),
+    (
+        "loglevel",
+        Command {
+            usage: "loglevel [[module] <level|off|reset>]",
+            help: "show or set the default log level or the log level of a module",
+            handler: loglevel,
+        },
+    ),
+    (
+        "logsink",
+        Command {
+            usage: "logsink [<sink> <on|off>]",
+            help: "list the sinks printing the log records, or register or unregister one",
+            handler: logsink,
+        },
+    ),
+    (
+        "locks",
+        Command {
+            usage: "locks",
+            help: "show acquisitions, contentions and the longest hold time of the global locks",
+            handler: locks,
+        },
+    ),
+    (
+        "dmesg",
+        Command {
+            usage: "dmesg [seq]",
+            help: "print the buffered log records, from the sequence number seq if given",
+            handler: dmesg,
+        },
+    ),
+    (
+        "trace",
+        Command {
+            usage: "trace [enable|disable <subsystem>|clear]",
+            help: "print the trace events, or enable, disable and clear the tracepoints",
+            handler: trace,
+        },
+    ),
+    (
+        "vmmap",
+        Command {
+            usage: "vmmap",
+            help: "list the mappings in the active page tables",
+            handler: vmmap,
+        },
+    ),
+    (
+        "lspci",
+        Command {
+            usage: "lspci",
+            help: "list the functions on the PCI bus with their BARs",
+            handler: lspci,
+        },
+    ),
+];
+
+fn meminfo(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    let stats = allocator::stats();
+    writeln!(
+        out,
+        "heap {:#x} - {:#x} ({} KiB)",
+        stats.heap_start,
+        stats.heap_start + stats.heap_size,
+        stats.heap_size / 1024
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "fallback used {:>8} free {:>8}",
+        stats.fallback_used, stats.fallback_free
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "allocations {:>8} deallocations {:>8}",
+        stats.allocations, stats.deallocations
+    )
+    .unwrap();
+
+    writeln!(out, "{:>10} {:>10}", "block", "free").unwrap();
+    for (size, free) in stats.free_blocks.iter() {
+        writeln!(out, "{:>10} {:>10}", size, free).unwrap();
+    }
+
+    Ok(())
+}
+
+fn ps(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    writeln!(
+        out,
+        "spawned {} completed {}",
+        executor::spawned_tasks(),
+        executor::completed_tasks()
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "{:>8} {:>4} {:>8} {:>10} {:>12} name",
+        "id", "cpu", "priority", "polls", "time (us)"
+    )
+    .unwrap();
+    for metrics in executor::task_metrics() {
+        writeln!(
+            out,
+            "{:>8} {:>4} {:>8} {:>10} {:>12} {}",
+            metrics.id,
+            metrics.cpu,
+            metrics.priority.name(),
+            metrics.polls,
+            metrics.run_time.as_micros(),
+            metrics.name
+        )
+        .unwrap();
+    }
+
+    Ok(())
+}
+
+fn irqstat(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    writeln!(out, "{:>6} {:<14} {:>12}", "vector", "name", "count").unwrap();
+    for vector in 0..=u8::MAX {
+        let count = interrupts::interrupt_count(vector);
+        let name = interrupts::vector_name(vector);
+        // vectors without handlers are only shown if they somehow got triggered
+        if name.is_some() || count > 0 {
+            writeln!(
+                out,
+                "{:>6} {:<14} {:>12}",
+                vector,
+                name.unwrap_or("-"),
+                count
+            )
+            .unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+fn cpus(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    let current = smp::current();
+    writeln!(out, "{:>4} {:>8} state", "cpu", "apic id").unwrap();
+    for cpu in smp::cpus() {
+        writeln!(
+            out,
+            "{:>4} {:>8} {}{}",
+            cpu.index,
+            cpu.apic_id,
+            if cpu.online { "online" } else { "offline" },
+            if cpu.index == current {
+                ", current"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+
+    Ok(())
+}
+
+fn uptime(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    let uptime = time::uptime();
+    let secs = uptime.as_secs();
+    writeln!(
+        out,
+        "{} up {}:{:02}:{:02}.{:03}, {} ticks",
+        time::now(),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        uptime.subsec_millis(),
+        time::ticks()
+    )
+    .unwrap();
+    Ok(())
+}
+
+fn boottime(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+    write!(out, "{}", boot::Report).unwrap();
+    Ok(())
+}
+
+fn metrics(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let prefix = args.optional().unwrap_or("");
+    args.finish()?;
+
+    for sample in metrics::snapshot() {
+        if sample.name.starts_with(prefix) {
+            writeln!(out, "{:<28} {}", sample.name, sample.value).unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+fn loglevel(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    fn show(level: Option<klog::Level>) -> &'static str {
+        match level {
+            Some(klog::Level::Error) => "error",
+            Some(klog::Level::Warn) => "warn",
+            Some(klog::Level::Info) => "info",
+            Some(klog::Level::Debug) => "debug",
+            Some(klog::Level::Trace) => "trace",
+            None => "off",
+        }
+    }
+
+    let first = args.optional();
+    let second = args.optional();
+    args.finish()?;
+
+    match (first, second) {
+        (None, _) => {
+            let (default, modules) = klog::filters();
+            writeln!(out, "default: {}", show(default)).unwrap();
+            for (module, level) in modules {
+                writeln!(out, "{}: {}", module, show(level)).unwrap();
+            }
+        }
+        (Some(level), None) => {
+            let level =
+                klog::parse_filter(level).map_err(|_| ShellError::InvalidArgument("level"))?;
+            klog::set_level(level);
+        }
+        (Some(module), Some("reset")) => klog::reset_module_level(module),
+        (Some(module), Some(level)) => {
+            let level =
+                klog::parse_filter(level).map_err(|_| ShellError::InvalidArgument("level"))?;
+            klog::set_module_level(module, level);
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) fn complete_loglevel(index: usize) -> Vec<String> {
+    let levels = ["off", "error", "warn", "info", "debug", "trace"]
+        .iter()
+        .map(|level| level.to_string());
+    match index {
+        0 => {
+            let (_, modules) = klog::filters();
+            levels
+                .chain(modules.into_iter().map(|(module, _)| module))
+                .collect()
+        }
+        1 => levels.chain(Some(String::from("reset"))).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn logsink(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let name = args.optional();
+    let state = match name {
+        Some(_) => Some(args.next_str("on|off")?),
+        None => None,
+    };
+    args.finish()?;
+
+    match (name, state) {
+        (Some(name), Some("on")) => {
+            let sink = klog::BUILTIN
+                .iter()
+                .find(|sink| sink.name() == name)
+                .ok_or(ShellError::InvalidArgument("sink"))?;
+            klog::add_sink(*sink).map_err(|_| ShellError::Failed("sink already registered"))?;
+        }
+        (Some(name), Some("off")) => {
+            if !klog::remove_sink(name) {
+                return Err(ShellError::Failed("sink not registered"));
+            }
+        }
+        (Some(_), Some(_)) => return Err(ShellError::InvalidArgument("on|off")),
+        _ => {
+            let registered = klog::sinks();
+            for sink in klog::BUILTIN.iter() {
+                let state = if registered.contains(&sink.name()) {
+                    "on"
+                } else {
+                    "off"
+                };
+                writeln!(out, "{}: {}", sink.name(), state).unwrap();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn locks(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    // the locks are sampled before printing, the output itself takes the VGA or the serial lock
+    let locks = [
+        ("heap", allocator::lock_stats()),
+        ("vga", vga_buffer::WRITER.stats()),
+        ("serial", serial::SERIAL1.stats()),
+    ];
+    writeln!(
+        out,
+        "{:<8} {:>12} {:>12} {:>16}",
+        "lock", "acquisitions", "contentions", "max hold cycles"
+    )
+    .unwrap();
+    for (name, stats) in locks.iter() {
+        writeln!(
+            out,
+            "{:<8} {:>12} {:>12} {:>16}",
+            name, stats.acquisitions, stats.contentions, stats.max_hold_cycles
+        )
+        .unwrap();
+    }
+
+    Ok(())
+}
+
+fn dmesg(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let seq = match args.optional() {
+        Some(seq) => parse_usize(seq).ok_or(ShellError::InvalidArgument("seq"))? as u64,
+        None => 0,
+    };
+    args.finish()?;
+
+    let records = klog::read_since(seq);
+    if let Some(first) = records.first() {
+        if first.seq > seq {
+            writeln!(out, "({} records lost)", first.seq - seq).unwrap();
+        }
+    }
+
+    for record in records {
+        writeln!(out, "{}", record).unwrap();
+    }
+
+    Ok(())
+}
+
+fn trace(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let action = args.optional();
+    let subsystem = match action {
+        Some("enable") | Some("disable") => {
+            let name = args.next_str("subsystem")?;
+            Some(Subsystem::from_name(name).ok_or(ShellError::InvalidArgument("subsystem"))?)
+        }
+        _ => None,
+    };
+    args.finish()?;
+
+    match (action, subsystem) {
+        (None, _) => {
+            for subsystem in Subsystem::ALL.iter() {
+                let state = if tracepoint::is_enabled(*subsystem) {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                writeln!(out, "{:<10} {}", subsystem, state).unwrap();
+            }
+            for event in tracepoint::events() {
+                writeln!(out, "{}", event).unwrap();
+            }
+        }
+        (Some("enable"), Some(subsystem)) => tracepoint::set_enabled(subsystem, true),
+        (Some("disable"), Some(subsystem)) => tracepoint::set_enabled(subsystem, false),
+        (Some("clear"), _) => tracepoint::clear(),
+        _ => return Err(ShellError::InvalidArgument("action")),
+    }
+
+    Ok(())
+}
+
+pub(super) fn complete_trace(index: usize) -> Vec<String> {
+    match index {
+        0 => ["enable", "disable", "clear"]
+            .iter()
+            .map(|action| action.to_string())
+            .collect(),
+        1 => Subsystem::ALL
+            .iter()
+            .map(|subsystem| subsystem.name().to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn vmmap(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    use x86_64::structures::paging::PageTableFlags;
+
+    args.finish()?;
+
+    writeln!(
+        out,
+        "{:<18} {:<18} {:<14} {:>10} flags",
+        "start", "last", "physical", "KiB"
+    )
+    .unwrap();
+    for mapping in memory::mappings() {
+        let flag = |flag, c| {
+            if mapping.flags.contains(flag) {
+                c
+            } else {
+                '-'
+            }
+        };
+
+        writeln!(
+            out,
+            "{:<#18x} {:<#18x} {:<#14x} {:>10} {}{}{}",
+            mapping.virt_start.as_u64(),
+            mapping.virt_last().as_u64(),
+            mapping.phys_start.as_u64(),
+            mapping.size / 1024,
+            flag(PageTableFlags::WRITABLE, 'w'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            // no-execute bit is inverted to read like the other flags
+            if mapping.flags.contains(PageTableFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            },
+        )
+        .unwrap();
+    }
+
+    Ok(())
+}
+
+fn lspci(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    for device in pci::devices() {
+        writeln!(
+            out,
+            "{} {:04x}:{:04x} {:02x}{:02x}{:02x} rev {:02x} {}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.revision,
+            device.class_name()
+        )
+        .unwrap();
+        if let Some(line) = device.interrupt_line {
+            writeln!(out, "    irq {}", line).unwrap();
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            match *bar {
+                pci::Bar::None => {}
+                pci::Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                } => writeln!(
+                    out,
+                    "    bar{} memory {:#x} size {:#x}{}",
+                    i,
+                    address,
+                    size,
+                    if prefetchable { " prefetchable" } else { "" }
+                )
+                .unwrap(),
+                pci::Bar::Io { port, size } => {
+                    writeln!(out, "    bar{} io {:#x} size {:#x}", i, port, size).unwrap()
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        tests::{build_registries, execute, Sink},
+        ShellError,
+    };
+
+    #[test_case]
+    fn run_diagnostics() {
+        build_registries();
+        for command in [
+            "meminfo", "ps", "irqstat", "cpus", "uptime", "boottime", "metrics", "loglevel",
+            "logsink", "locks", "dmesg", "trace", "vmmap", "lspci",
+        ]
+        .iter()
+        {
+            assert_eq!(execute(command, &mut Sink), Ok(()));
+        }
+    }
+
+    #[test_case]
+    fn log_sinks_switched() {
+        build_registries();
+        assert_eq!(execute("logsink serial on", &mut Sink), Ok(()));
+        assert_eq!(
+            execute("logsink serial on", &mut Sink),
+            Err(ShellError::Failed("sink already registered"))
+        );
+        assert_eq!(execute("logsink serial off", &mut Sink), Ok(()));
+        assert_eq!(
+            execute("logsink serial off", &mut Sink),
+            Err(ShellError::Failed("sink not registered"))
+        );
+        assert_eq!(
+            execute("logsink vga on", &mut Sink),
+            Err(ShellError::InvalidArgument("sink"))
+        );
+        assert_eq!(
+            execute("logsink serial", &mut Sink),
+            Err(ShellError::MissingArgument("on|off"))
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Line editing with cursor movement and command history.
+
+use alloc::{collections::VecDeque, string::String};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::{complete, Console};
+
+/// Maximum number of lines kept in the history, the oldest line is dropped first.
+const HISTORY_SIZE: usize = 32;
+
+const TAB: char = '\t';
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+
+/// A single line of input being edited on a [Console], with a history of previously submitted
+/// lines. Only printable ASCII characters are accepted, so that every character in the line takes
+/// exactly one cell on the console.
+pub struct LineEditor {
+    prompt: &'static str,
+    line: String,
+    /// index into `line` of the character under the cursor, `line.len()` at the end of the line
+    cursor: usize,
+    history: VecDeque<String>,
+    /// index into `history` of the line currently shown, `None` if not browsing the history
+    browsing: Option<usize>,
+    /// the unfinished line before browsing the history
+    stash: String,
+}
+
+impl LineEditor {
+    /// Create an empty [LineEditor] with an empty history. `prompt` is printed before each line.
+    pub fn new(prompt: &'static str) -> Self {
+        Self {
+            prompt,
+            line: String::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+            stash: String::new(),
+        }
+    }
+
+    /// Print the prompt to `console`, should be called before editing each line.
+    pub fn print_prompt(&self, console: &mut dyn Console) {
+        console.write_str(self.prompt).unwrap();
+    }
+
+    /// Handle a decoded key, echo the change to `console`. Returns the line when the key submits
+    /// it, the line is then recorded in the history and the editor starts over with an empty
+    /// line.
+    pub fn handle_key(&mut self, key: DecodedKey, console: &mut dyn Console) -> Option<String> {
+        match key {
+            DecodedKey::Unicode('\n') => return Some(self.submit(console)),
+            DecodedKey::Unicode(TAB) => self.complete(console),
+            DecodedKey::Unicode(BACKSPACE) => self.backspace(console),
+            DecodedKey::Unicode(DELETE) | DecodedKey::RawKey(KeyCode::Delete) => {
+                self.delete(console)
+            }
+            DecodedKey::Unicode(c) if is_printable(c) => {
+                let mut buf = [0; 4];
+                self.insert(c.encode_utf8(&mut buf), console)
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) if self.cursor > 0 => {
+                self.cursor -= 1;
+                console.cursor_left(1);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowRight) if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                console.cursor_right(1);
+            }
+            DecodedKey::RawKey(KeyCode::Home) => {
+                console.cursor_left(self.cursor);
+                self.cursor = 0;
+            }
+            DecodedKey::RawKey(KeyCode::End) => {
+                console.cursor_right(self.line.len() - self.cursor);
+                self.cursor = self.line.len();
+            }
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_previous(console),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(console),
+            _ => (),
+        }
+
+        None
+    }
+
+    /// The line being edited.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Insert `s` at the cursor, `s` must only contain printable ASCII characters.
+    fn insert(&mut self, s: &str, console: &mut dyn Console) {
+        self.line.insert_str(self.cursor, s);
+        console.write_str(&self.line[self.cursor..]).unwrap();
+        self.cursor += s.len();
+        console.cursor_left(self.line.len() - self.cursor);
+    }
+
+    /// Complete the word before the cursor. A unique candidate is inserted in whole followed by a
+    /// space; otherwise the longest common prefix of the candidates is inserted, or if there's
+    /// nothing to insert, the candidates are listed below the line.
+    fn complete(&mut self, console: &mut dyn Console) {
+        let (start, mut candidates) = complete(&self.line[..self.cursor]);
+        candidates.retain(|candidate| candidate.chars().all(is_printable));
+        let typed = self.cursor - start;
+
+        match candidates.as_slice() {
+            [] => (),
+            [candidate] => {
+                let mut completion = String::from(&candidate[typed..]);
+                completion.push(' ');
+                self.insert(&completion, console);
+            }
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.len(), |len, candidate| {
+                    first
+                        .bytes()
+                        .zip(candidate.bytes())
+                        .take(len)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                });
+
+                if common > typed {
+                    self.insert(&first[typed..common], console);
+                } else {
+                    console.cursor_right(self.line.len() - self.cursor);
+                    console.write_char('\n').unwrap();
+                    for candidate in candidates.iter() {
+                        write!(console, "{}  ", candidate).unwrap();
+                    }
+                    write!(console, "\n{}{}", self.prompt, self.line).unwrap();
+                    console.cursor_left(self.line.len() - self.cursor);
+                }
+            }
+        }
+    }
+
+    fn backspace(&mut self, console: &mut dyn Console) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            console.cursor_left(1);
+            self.delete(console);
+        }
+    }
+
+    fn delete(&mut self, console: &mut dyn Console) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+            // shift the rest of the line to the left, overwrite the last character by a space
+            console.write_str(&self.line[self.cursor..]).unwrap();
+            console.write_char(' ').unwrap();
+            console.cursor_left(self.line.len() - self.cursor + 1);
+        }
+    }
+
+    fn submit(&mut self, console: &mut dyn Console) -> String {
+        console.cursor_right(self.line.len() - self.cursor);
+        console.write_char('\n').unwrap();
+
+        let line = core::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.browsing = None;
+        self.stash.clear();
+
+        let is_repeated = self.history.back().map(String::as_str) == Some(line.as_str());
+        if !line.trim().is_empty() && !is_repeated {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+
+        line
+    }
+
+    fn history_previous(&mut self, console: &mut dyn Console) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.stash = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+
+        self.browsing = Some(index);
+        let line = self.history[index].clone();
+        self.replace_line(line, console);
+    }
+
+    fn history_next(&mut self, console: &mut dyn Console) {
+        let line = match self.browsing {
+            None => return,
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.history[index + 1].clone()
+            }
+            Some(_) => {
+                self.browsing = None;
+                core::mem::take(&mut self.stash)
+            }
+        };
+
+        self.replace_line(line, console);
+    }
+
+    /// Replace the whole line by `line`, leaving the cursor at the end of the new line.
+    fn replace_line(&mut self, line: String, console: &mut dyn Console) {
+        console.cursor_left(self.cursor);
+        console.write_str(&line).unwrap();
+        // overwrite the rest of the old line by spaces
+        let padding = self.line.len().saturating_sub(line.len());
+        for _ in 0..padding {
+            console.write_char(' ').unwrap();
+        }
+        console.cursor_left(padding);
+
+        self.cursor = line.len();
+        self.line = line;
+    }
+}
+
+/// Whether `c` takes exactly one cell on the console.
+fn is_printable(c: char) -> bool {
+    c.is_ascii() && !c.is_ascii_control()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, Sink};
+    use super::*;
+
+    fn type_str(editor: &mut LineEditor, s: &str) {
+        for c in s.chars() {
+            editor.handle_key(DecodedKey::Unicode(c), &mut Sink);
+        }
+    }
+
+    #[test_case]
+    fn edit_in_line() {
+        let mut editor = LineEditor::new("> ");
+        type_str(&mut editor, "helo");
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowLeft), &mut Sink);
+        type_str(&mut editor, "l");
+        editor.handle_key(DecodedKey::RawKey(KeyCode::Home), &mut Sink);
+        editor.handle_key(DecodedKey::Unicode(DELETE), &mut Sink);
+        editor.handle_key(DecodedKey::RawKey(KeyCode::End), &mut Sink);
+        type_str(&mut editor, "!\u{8}?");
+        assert_eq!(editor.line(), "ello?");
+    }
+
+    #[test_case]
+    fn browse_history() {
+        let mut editor = LineEditor::new("> ");
+        type_str(&mut editor, "first");
+        let line = editor.handle_key(DecodedKey::Unicode('\n'), &mut Sink);
+        assert_eq!(line.as_deref(), Some("first"));
+        type_str(&mut editor, "second\n");
+        type_str(&mut editor, "draft");
+
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowUp), &mut Sink);
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowUp), &mut Sink);
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowUp), &mut Sink);
+        assert_eq!(editor.line(), "first");
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowDown), &mut Sink);
+        assert_eq!(editor.line(), "second");
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowDown), &mut Sink);
+        assert_eq!(editor.line(), "draft");
+    }
+
+    #[test_case]
+    fn complete_commands() {
+        build_registries();
+        let mut editor = LineEditor::new("> ");
+        type_str(&mut editor, "hel\t");
+        assert_eq!(editor.line(), "help ");
+        type_str(&mut editor, "vm\t");
+        assert_eq!(editor.line(), "help vmmap ");
+        editor.handle_key(DecodedKey::Unicode('\n'), &mut Sink);
+
+        // "help" and "hexdump" share no more than the typed prefix
+        type_str(&mut editor, "he\t");
+        assert_eq!(editor.line(), "he");
+    }
+}
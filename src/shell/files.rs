@@ -0,0 +1,318 @@
+//! Commands on the files of the mounted filesystems. Paths are absolute, there is no working
+//! directory.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::fmt::Write;
+
+use super::{
+    parse_usize,
+    peek::{self, BYTES_PER_ROW},
+    Args, Command, ShellError,
+};
+use crate::{
+    block,
+    fs::{
+        self,
+        fd::{FdTable, OpenFlags},
+        ramfs::RamFs,
+        FsError, NodeKind,
+    },
+};
+
+/// The most bytes printed by `cat` and `hexdump-file`, devices such as `/dev/zero` never end.
+const MAX_OUTPUT: usize = 64 * 1024;
+/// The size of the reads of `cat` and `hexdump-file`.
+const CHUNK_SIZE: usize = 512;
+/// Mounts an empty scratch filesystem instead of a device.
+const RAMFS: &str = "ramfs";
+
+/// The file commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "ls",
+        Command {
+            usage: "ls [path]",
+            help: "list a directory, / by default",
+            handler: ls,
+        },
+    ),
+    (
+        "cat",
+        Command {
+            usage: "cat <path>",
+            help: "print a file as text",
+            handler: cat,
+        },
+    ),
+    (
+        "hexdump-file",
+        Command {
+            usage: "hexdump-file <path> [offset] [len]",
+            help: "dump a file in hexadecimal and ASCII",
+            handler: hexdump_file,
+        },
+    ),
+    (
+        "write",
+        Command {
+            usage: "write <path> [text...]",
+            help: "replace the content of a file with a line of text, creating the file if missing",
+            handler: write,
+        },
+    ),
+    (
+        "mount",
+        Command {
+            usage: "mount [<device|ramfs> <path>]",
+            help: "list the mounted filesystems or mount a FAT32 volume or an archive",
+            handler: mount,
+        },
+    ),
+    (
+        "umount",
+        Command {
+            usage: "umount <path>",
+            help: "unmount the filesystem mounted at a path",
+            handler: umount,
+        },
+    ),
+];
+
+/// Complete the devices of `mount`.
+pub(super) fn complete_mount(index: usize) -> Vec<String> {
+    if index == 0 {
+        let mut candidates = block::names();
+        candidates.push(String::from(RAMFS));
+        candidates
+    } else {
+        Vec::new()
+    }
+}
+
+/// Read the file at `path` from offset `offset` on, at most `len` bytes, passing each chunk read
+/// to `f`. Stops at the end of the file or when nothing can be read without waiting.
+fn read_file(
+    path: &str,
+    offset: u64,
+    len: usize,
+    mut f: impl FnMut(&[u8]),
+) -> Result<(), ShellError> {
+    let mut files = FdTable::new();
+    let fd = files.open(path, OpenFlags::READ)?;
+    match files.seek(fd, fs::fd::SeekFrom::Start(offset)) {
+        // streams are read from where they are
+        Ok(_) | Err(FsError::NotSeekable) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut buf = [0; CHUNK_SIZE];
+    let mut total = 0;
+    while total < len {
+        let chunk = CHUNK_SIZE.min(len - total);
+        let read = match files.read(fd, &mut buf[..chunk]) {
+            Ok(0) | Err(FsError::WouldBlock) => break,
+            Ok(read) => read,
+            Err(err) => return Err(err.into()),
+        };
+        f(&buf[..read]);
+        total += read;
+    }
+    Ok(())
+}
+
+fn ls(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let path = args.optional().unwrap_or("/");
+    args.finish()?;
+
+    for entry in fs::read_dir(path)? {
+        match entry.kind {
+            NodeKind::Directory => writeln!(out, "{:>10}  {}/", "", entry.name).unwrap(),
+            NodeKind::CharDevice => writeln!(out, "{:>10}  {}", "device", entry.name).unwrap(),
+            NodeKind::File => {
+                let mut path = String::from(path.trim_end_matches('/'));
+                path.push('/');
+                path.push_str(&entry.name);
+                let size = fs::lookup(&path)?.metadata().size;
+                writeln!(out, "{:>10}  {}", size, entry.name).unwrap();
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cat(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let path = args.next_str("path")?;
+    args.finish()?;
+
+    // a character may be split across chunks
+    let mut text = Vec::new();
+    read_file(path, 0, MAX_OUTPUT, |chunk| text.extend_from_slice(chunk))?;
+    let text = String::from_utf8_lossy(&text);
+    write!(out, "{}", text).unwrap();
+    if !text.is_empty() && !text.ends_with('\n') {
+        writeln!(out).unwrap();
+    }
+    Ok(())
+}
+
+fn hexdump_file(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let path = args.next_str("path")?;
+    let offset = match args.optional() {
+        Some(offset) => parse_usize(offset).ok_or(ShellError::InvalidArgument("offset"))?,
+        None => 0,
+    };
+    let len = match args.optional() {
+        Some(len) => parse_usize(len).ok_or(ShellError::InvalidArgument("len"))?,
+        None => MAX_OUTPUT,
+    };
+    args.finish()?;
+
+    let mut row = Vec::with_capacity(BYTES_PER_ROW);
+    let mut row_offset = offset;
+    read_file(path, offset as u64, len.min(MAX_OUTPUT), |chunk| {
+        for &byte in chunk {
+            row.push(byte);
+            if row.len() == BYTES_PER_ROW {
+                peek::write_row(out, row_offset, &row);
+                row_offset += BYTES_PER_ROW;
+                row.clear();
+            }
+        }
+    })?;
+    if !row.is_empty() {
+        peek::write_row(out, row_offset, &row);
+    }
+    Ok(())
+}
+
+fn write(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let path = args.next_str("path")?;
+    let mut text = args.rest().join(" ");
+    text.push('\n');
+
+    let node = match fs::lookup(path) {
+        Ok(node) => node,
+        Err(FsError::NotFound) => fs::create(path, NodeKind::File)?,
+        Err(err) => return Err(err.into()),
+    };
+    match node.metadata().kind {
+        NodeKind::Directory => return Err(FsError::IsADirectory.into()),
+        NodeKind::File => node.set_len(0)?,
+        // devices are written as a stream
+        NodeKind::CharDevice => {}
+    }
+    let written = node.write_at(0, text.as_bytes())?;
+    writeln!(out, "{} bytes written to {}", written, path).unwrap();
+    Ok(())
+}
+
+fn mount(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let device = match args.optional() {
+        Some(device) => device,
+        None => {
+            for mount in fs::mounts() {
+                writeln!(out, "{:<8} {}", mount.fs, mount.path).unwrap();
+            }
+            return Ok(());
+        }
+    };
+    let path = args.next_str("path")?;
+    args.finish()?;
+
+    if device == RAMFS {
+        fs::mount(path, Arc::new(RamFs::new()))?;
+    } else {
+        fs::mount_device(device, path)?;
+    }
+    writeln!(out, "{} mounted at {}", device, path).unwrap();
+    Ok(())
+}
+
+fn umount(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let path = args.next_str("path")?;
+    args.finish()?;
+
+    let fs = fs::unmount(path)?;
+    writeln!(out, "{} unmounted from {}", fs.name(), path).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, execute};
+    use super::*;
+    use crate::testing;
+
+    fn run(line: &str) -> Result<String, ShellError> {
+        let mut out = String::new();
+        execute(line, &mut out)?;
+        Ok(out)
+    }
+
+    #[test_case]
+    fn files_listed_and_printed() {
+        build_registries();
+        let root = run("ls").unwrap();
+        assert!(root.contains("boot/") && root.contains("dev/") && root.contains("tmp/"));
+        assert!(run("ls /dev").unwrap().contains("device  null"));
+        assert!(run("ls /boot/initrd/etc").unwrap().contains("  motd"));
+        assert!(run("cat /boot/initrd/etc/motd")
+            .unwrap()
+            .starts_with("Welcome"));
+        assert_eq!(
+            run("cat /boot/initrd/etc"),
+            Err(ShellError::Fs(FsError::IsADirectory))
+        );
+        assert_eq!(run("ls /nowhere"), Err(ShellError::Fs(FsError::NotFound)));
+
+        let dump = run("hexdump-file /dev/zero 0x10 20").unwrap();
+        let rows: Vec<&str> = dump.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("0000000000000010  00 00"));
+        assert!(rows[1].starts_with("0000000000000020  00 00 00 00    "));
+    }
+
+    #[test_case]
+    fn files_written() {
+        build_registries();
+        // the file stays in /tmp after the test
+        assert_eq!(
+            testing::keep_allocations(|| run("write /tmp/test-shell hello  world")).unwrap(),
+            "12 bytes written to /tmp/test-shell\n"
+        );
+        assert_eq!(run("cat /tmp/test-shell").unwrap(), "hello world\n");
+        run("write /tmp/test-shell bye").unwrap();
+        assert_eq!(run("cat /tmp/test-shell").unwrap(), "bye\n");
+        assert_eq!(
+            run("write /boot/initrd/etc/motd x"),
+            Err(ShellError::Fs(FsError::ReadOnly))
+        );
+    }
+
+    #[test_case]
+    fn filesystems_mounted() {
+        build_registries();
+        assert_eq!(
+            run("mount ramfs /test/shell-mnt").unwrap(),
+            "ramfs mounted at /test/shell-mnt\n"
+        );
+        assert!(run("mount").unwrap().contains("ramfs    /test/shell-mnt"));
+        // the blank test disk holds no archive
+        assert_eq!(
+            run("mount sda /test/sda"),
+            Err(ShellError::Fs(FsError::Corrupted("unknown archive format")))
+        );
+        assert_eq!(
+            run("mount sdz /test/sdz"),
+            Err(ShellError::Fs(FsError::NotFound))
+        );
+        assert_eq!(
+            run("umount /test/shell-mnt").unwrap(),
+            "ramfs unmounted from /test/shell-mnt\n"
+        );
+        assert_eq!(
+            run("umount /test/shell-mnt"),
+            Err(ShellError::Fs(FsError::NotMounted))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Commands choosing how the keyboard is decoded.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use pc_keyboard::HandleControl;
+
+use super::{Args, Command, ShellError};
+use crate::task::keyboard::{self, Layout};
+
+/// The keyboard commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "keymap",
+        Command {
+            usage: "keymap [us|uk|dvorak|azerty]",
+            help: "show or set the layout of the keyboard",
+            handler: keymap,
+        },
+    ),
+    (
+        "ctrlkeys",
+        Command {
+            usage: "ctrlkeys [on|off]",
+            help: "show or set whether Ctrl+letter types a control character",
+            handler: ctrlkeys,
+        },
+    ),
+];
+
+fn keymap(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let name = args.optional();
+    args.finish()?;
+
+    match name {
+        Some(name) => {
+            let layout = Layout::from_name(name).ok_or(ShellError::InvalidArgument("layout"))?;
+            keyboard::set_layout(layout);
+        }
+        None => writeln!(out, "{}", keyboard::layout().name()).unwrap(),
+    }
+
+    Ok(())
+}
+
+fn ctrlkeys(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let state = args.optional();
+    args.finish()?;
+
+    match state {
+        Some("on") => keyboard::set_control_handling(HandleControl::MapLettersToUnicode),
+        Some("off") => keyboard::set_control_handling(HandleControl::Ignore),
+        Some(_) => return Err(ShellError::InvalidArgument("on|off")),
+        None => {
+            let state = match keyboard::control_handling() {
+                HandleControl::MapLettersToUnicode => "on",
+                HandleControl::Ignore => "off",
+            };
+            writeln!(out, "{}", state).unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) fn complete_keymap(index: usize) -> Vec<String> {
+    match index {
+        0 => Layout::ALL
+            .iter()
+            .map(|layout| layout.name().to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, execute, Sink};
+    use super::*;
+
+    #[test_case]
+    fn layout_selected() {
+        build_registries();
+        assert_eq!(execute("keymap azerty", &mut Sink), Ok(()));
+        assert_eq!(keyboard::layout(), Layout::Azerty);
+        assert_eq!(
+            execute("keymap qwertz", &mut Sink),
+            Err(ShellError::InvalidArgument("layout"))
+        );
+        assert_eq!(execute("keymap us", &mut Sink), Ok(()));
+        assert_eq!(keyboard::layout(), Layout::Us);
+
+        assert_eq!(execute("ctrlkeys on", &mut Sink), Ok(()));
+        assert_eq!(
+            keyboard::control_handling(),
+            HandleControl::MapLettersToUnicode
+        );
+        assert_eq!(
+            execute("ctrlkeys yes", &mut Sink),
+            Err(ShellError::InvalidArgument("on|off"))
+        );
+        assert_eq!(execute("ctrlkeys off", &mut Sink), Ok(()));
+        assert_eq!(keyboard::control_handling(), HandleControl::Ignore);
+    }
+}
@@ -0,0 +1,299 @@
+//! Commands showing the network interfaces, the sockets and the statistics of the protocols.
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use super::{Args, Command, ShellError};
+use crate::{
+    metrics,
+    net::{
+        self,
+        pcap::{self, CaptureError, Target},
+        tcp, udp, Interface, Ipv4Address, SocketAddress,
+    },
+};
+
+/// The prefixes of the metrics of the protocols, shown by `netstat -s`.
+const PROTOCOL_METRICS: &[&str] = &["arp.", "ipv4.", "tcp.", "udp."];
+
+/// The network commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "ifconfig",
+        Command {
+            usage: "ifconfig [interface]",
+            help: "show the address and the traffic of the network interfaces",
+            handler: ifconfig,
+        },
+    ),
+    (
+        "netstat",
+        Command {
+            usage: "netstat [-s]",
+            help: "list the TCP and UDP sockets, or show the statistics of the protocols with -s",
+            handler: netstat,
+        },
+    ),
+    (
+        "pcap",
+        Command {
+            usage: "pcap [serial | <path> [interface] | stop]",
+            help: "capture the frames of the interfaces in pcap format to COM2 or a file",
+            handler: capture,
+        },
+    ),
+];
+
+/// Complete the interfaces of `ifconfig`.
+pub(super) fn complete_ifconfig(index: usize) -> Vec<String> {
+    if index == 0 {
+        net::names()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Complete the target and the interface of `pcap`.
+pub(super) fn complete_pcap(index: usize) -> Vec<String> {
+    match index {
+        0 => ["serial", "stop"]
+            .iter()
+            .map(|&word| String::from(word))
+            .collect(),
+        1 => net::names(),
+        _ => Vec::new(),
+    }
+}
+
+fn write_interface(out: &mut dyn Write, interface: &Interface) {
+    writeln!(
+        out,
+        "{:<6}HWaddr {}",
+        interface.name(),
+        interface.device().mac_address()
+    )
+    .unwrap();
+    match interface.ipv4() {
+        Some(config) => {
+            write!(out, "      inet {}/{}", config.address, config.prefix_len).unwrap();
+            if let Some(gateway) = config.gateway {
+                write!(out, " gateway {}", gateway).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+        None => writeln!(out, "      no address").unwrap(),
+    }
+    let stats = interface.stats();
+    writeln!(
+        out,
+        "      RX frames {} bytes {} dropped {}",
+        stats.rx_frames, stats.rx_bytes, stats.rx_dropped
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "      TX frames {} bytes {} errors {}",
+        stats.tx_frames, stats.tx_bytes, stats.tx_errors
+    )
+    .unwrap();
+}
+
+fn ifconfig(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let name = args.optional();
+    args.finish()?;
+
+    match name {
+        Some(name) => {
+            let interface = net::interface(name).ok_or(ShellError::InvalidArgument("interface"))?;
+            write_interface(out, interface);
+        }
+        None => {
+            for interface in net::interfaces() {
+                write_interface(out, interface);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn netstat(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let statistics = match args.optional() {
+        Some("-s") => true,
+        Some(_) => return Err(ShellError::InvalidArgument("-s")),
+        None => false,
+    };
+    args.finish()?;
+
+    if statistics {
+        for sample in metrics::snapshot() {
+            if PROTOCOL_METRICS
+                .iter()
+                .any(|prefix| sample.name.starts_with(prefix))
+            {
+                writeln!(out, "{:<28} {}", sample.name, sample.value).unwrap();
+            }
+        }
+        return Ok(());
+    }
+
+    // the addresses are formatted first, their Display ignores the width
+    let mut row =
+        |proto: &str, recv_q: &str, send_q: &str, local: &str, remote: &str, state: &str| {
+            writeln!(
+                out,
+                "{:<6}{:>7}{:>7}  {:<22} {:<22} {}",
+                proto, recv_q, send_q, local, remote, state
+            )
+            .unwrap()
+        };
+    let any = |port: u16| {
+        format!(
+            "{}",
+            SocketAddress {
+                addr: Ipv4Address::UNSPECIFIED,
+                port
+            }
+        )
+    };
+    row(
+        "Proto",
+        "Recv-Q",
+        "Send-Q",
+        "Local address",
+        "Remote address",
+        "State",
+    );
+    for port in tcp::listening_ports() {
+        row("tcp", "0", "0", &any(port), "0.0.0.0:*", "LISTEN");
+    }
+    for connection in tcp::connections() {
+        row(
+            "tcp",
+            &format!("{}", connection.recv_queue),
+            &format!("{}", connection.send_queue),
+            &format!("{}", connection.local),
+            &format!("{}", connection.remote),
+            &format!("{}", connection.state),
+        );
+    }
+    for (port, buffered) in udp::sockets() {
+        row(
+            "udp",
+            &format!("{}", buffered),
+            "0",
+            &any(port),
+            "0.0.0.0:*",
+            "",
+        );
+    }
+    Ok(())
+}
+
+fn capture(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let target = match args.optional() {
+        None => {
+            args.finish()?;
+            match pcap::status() {
+                Some(info) => writeln!(
+                    out,
+                    "capturing {} to {}, {} frames",
+                    info.interface.as_deref().unwrap_or("all interfaces"),
+                    info.target,
+                    info.frames
+                )
+                .unwrap(),
+                None => writeln!(out, "no capture running").unwrap(),
+            }
+            return Ok(());
+        }
+        Some("stop") => {
+            args.finish()?;
+            let info = pcap::stop().ok_or(ShellError::Failed("no capture running"))?;
+            writeln!(out, "{} frames captured to {}", info.frames, info.target).unwrap();
+            return Ok(());
+        }
+        Some("serial") => Target::Serial,
+        Some(path) => Target::File(String::from(path)),
+    };
+    let interface = args.optional();
+    args.finish()?;
+
+    pcap::start(target, interface).map_err(|err| match err {
+        CaptureError::Busy => ShellError::Failed("a capture is already running"),
+        CaptureError::UnknownInterface => ShellError::InvalidArgument("interface"),
+        CaptureError::Fs(err) => ShellError::Fs(err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, execute};
+    use super::*;
+    use crate::testing;
+
+    fn run(line: &str) -> Result<String, ShellError> {
+        let mut out = String::new();
+        execute(line, &mut out)?;
+        Ok(out)
+    }
+
+    #[test_case]
+    fn interfaces_shown() {
+        build_registries();
+        let all = run("ifconfig").unwrap();
+        assert!(
+            all.contains("eth0  HWaddr ") && all.contains("inet 10.0.2.15/24 gateway 10.0.2.2")
+        );
+        assert!(all.contains("lo    HWaddr 00:00:00:00:00:00\n      inet 127.0.0.1/8\n"));
+        let lo = run("ifconfig lo").unwrap();
+        assert_eq!(lo.lines().count(), 4);
+        assert!(lo.lines().nth(2).unwrap().starts_with("      RX frames "));
+        assert_eq!(
+            run("ifconfig eth9"),
+            Err(ShellError::InvalidArgument("interface"))
+        );
+    }
+
+    #[test_case]
+    fn sockets_listed() {
+        build_registries();
+        let (listener, socket) = testing::keep_allocations(|| {
+            (
+                tcp::TcpListener::bind(7071).unwrap(),
+                udp::UdpSocket::bind(7072).unwrap(),
+            )
+        });
+        let table = run("netstat").unwrap();
+        assert!(table.starts_with("Proto  Recv-Q Send-Q  Local address"));
+        assert!(table.contains(
+            "tcp         0      0  0.0.0.0:7071           0.0.0.0:*              LISTEN"
+        ));
+        assert!(table.contains("udp         0      0  0.0.0.0:7072"));
+        drop((listener, socket));
+        assert!(!run("netstat").unwrap().contains(":7071"));
+
+        assert!(run("netstat -s").unwrap().contains("tcp.listeners"));
+        assert_eq!(run("netstat -x"), Err(ShellError::InvalidArgument("-s")));
+    }
+
+    #[test_case]
+    fn captures_started_and_stopped() {
+        build_registries();
+        assert_eq!(run("pcap").unwrap(), "no capture running\n");
+        assert_eq!(
+            run("pcap serial eth9"),
+            Err(ShellError::InvalidArgument("interface"))
+        );
+        testing::keep_allocations(|| run("pcap serial lo")).unwrap();
+        assert_eq!(run("pcap").unwrap(), "capturing lo to COM2, 0 frames\n");
+        assert_eq!(
+            run("pcap serial"),
+            Err(ShellError::Failed("a capture is already running"))
+        );
+        assert_eq!(run("pcap stop").unwrap(), "0 frames captured to COM2\n");
+        assert_eq!(
+            run("pcap stop"),
+            Err(ShellError::Failed("no capture running"))
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Commands inspecting and modifying arbitrary memory. Addresses are validated against the active
+//! page tables, unmapped addresses are refused instead of page faulting the shell.
+
+use core::{fmt::Write, ptr};
+
+use x86_64::VirtAddr;
+
+use super::{Args, Command, ShellError};
+use crate::memory;
+
+/// Number of bytes displayed in a row of `hexdump`.
+pub(super) const BYTES_PER_ROW: usize = 16;
+
+/// The memory commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "hexdump",
+        Command {
+            usage: "hexdump <addr> <len>",
+            help: "dump memory in hexadecimal and ASCII",
+            handler: hexdump,
+        },
+    ),
+    (
+        "rd",
+        Command {
+            usage: "rd <addr> [1|2|4|8]",
+            help: "read an integer of the given width in bytes from memory, 8 by default",
+            handler: rd,
+        },
+    ),
+    (
+        "wr",
+        Command {
+            usage: "wr <addr> <value> [1|2|4|8]",
+            help: "write an integer of the given width in bytes to memory, 8 by default",
+            handler: wr,
+        },
+    ),
+];
+
+/// Parse a virtual address and ensure the `len`-byte range starting from it is mapped (and
+/// writable if `writable` is set).
+fn checked_addr(addr: usize, len: usize, writable: bool) -> Result<VirtAddr, ShellError> {
+    let addr = VirtAddr::try_new(addr as u64).map_err(|_| ShellError::InvalidArgument("addr"))?;
+    if memory::is_mapped(addr, len as u64, writable) {
+        Ok(addr)
+    } else if writable {
+        Err(ShellError::Failed(
+            "address range not mapped or not writable",
+        ))
+    } else {
+        Err(ShellError::Failed("address range not mapped"))
+    }
+}
+
+/// Parse the optional width argument, ensure `addr` is aligned to the width.
+fn width(args: &mut Args, addr: usize) -> Result<usize, ShellError> {
+    let width = match args.optional() {
+        Some(width) => match width {
+            "1" | "2" | "4" | "8" => width.parse().unwrap(),
+            _ => return Err(ShellError::InvalidArgument("width")),
+        },
+        None => 8,
+    };
+
+    if addr % width != 0 {
+        return Err(ShellError::Failed("address not aligned to the width"));
+    }
+    Ok(width)
+}
+
+fn hexdump(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let addr = args.next_usize("addr")?;
+    let len = args.next_usize("len")?;
+    args.finish()?;
+
+    let start = checked_addr(addr, len, false)?;
+    let start = start.as_u64() as usize;
+
+    for row_start in (start..start + len).step_by(BYTES_PER_ROW) {
+        let row_len = BYTES_PER_ROW.min(start + len - row_start);
+        let mut bytes = [0u8; BYTES_PER_ROW];
+        for (i, byte) in bytes.iter_mut().take(row_len).enumerate() {
+            // # Safety
+            // The whole range is checked to be mapped. Volatile reads as the range may well be
+            // memory-mapped IO.
+            *byte = unsafe { ptr::read_volatile((row_start + i) as *const u8) };
+        }
+        write_row(out, row_start, &bytes[..row_len]);
+    }
+
+    Ok(())
+}
+
+/// Write a row of `hexdump` of at most [BYTES_PER_ROW] `bytes` found at `addr`.
+pub(super) fn write_row(out: &mut dyn Write, addr: usize, bytes: &[u8]) {
+    write!(out, "{:016x} ", addr).unwrap();
+    for i in 0..BYTES_PER_ROW {
+        match bytes.get(i) {
+            Some(byte) => write!(out, " {:02x}", byte).unwrap(),
+            None => write!(out, "   ").unwrap(),
+        }
+    }
+    write!(out, "  ").unwrap();
+    for &byte in bytes {
+        let c = if byte.is_ascii_graphic() || byte == b' ' {
+            char::from(byte)
+        } else {
+            '.'
+        };
+        write!(out, "{}", c).unwrap();
+    }
+    writeln!(out).unwrap();
+}
+
+fn rd(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let addr = args.next_usize("addr")?;
+    let width = width(&mut args, addr)?;
+    args.finish()?;
+
+    let addr = checked_addr(addr, width, false)?;
+    // # Safety
+    // The range is checked to be mapped and the address is aligned to the width.
+    let value = unsafe {
+        match width {
+            1 => u64::from(ptr::read_volatile(addr.as_ptr::<u8>())),
+            2 => u64::from(ptr::read_volatile(addr.as_ptr::<u16>())),
+            4 => u64::from(ptr::read_volatile(addr.as_ptr::<u32>())),
+            _ => ptr::read_volatile(addr.as_ptr::<u64>()),
+        }
+    };
+
+    writeln!(out, "{:#0width$x}", value, width = width * 2 + 2).unwrap();
+    Ok(())
+}
+
+fn wr(mut args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    let addr = args.next_usize("addr")?;
+    let value = args.next_usize("value")? as u64;
+    let width = width(&mut args, addr)?;
+    args.finish()?;
+
+    if width < 8 && value >> (width * 8) != 0 {
+        return Err(ShellError::InvalidArgument("value"));
+    }
+
+    let addr = checked_addr(addr, width, true)?;
+    // # Safety
+    // The range is checked to be mapped and writable, the address is aligned to the width. What
+    // the write does to the rest of the kernel is the responsibility of the user.
+    unsafe {
+        match width {
+            1 => ptr::write_volatile(addr.as_mut_ptr::<u8>(), value as u8),
+            2 => ptr::write_volatile(addr.as_mut_ptr::<u16>(), value as u16),
+            4 => ptr::write_volatile(addr.as_mut_ptr::<u32>(), value as u32),
+            _ => ptr::write_volatile(addr.as_mut_ptr::<u64>(), value),
+        }
+    }
+
+    writeln!(out, "{:#x} <- {:#x}", addr.as_u64(), value).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, execute, Sink};
+    use super::*;
+    use alloc::{boxed::Box, format};
+
+    #[test_case]
+    fn peek_and_poke() {
+        build_registries();
+        let mut value = Box::new(0u64);
+        let ptr = &mut *value as *mut u64;
+        let addr = ptr as usize;
+
+        assert_eq!(
+            execute(&format!("wr {:#x} 0xbeef 2", addr), &mut Sink),
+            Ok(())
+        );
+        assert_eq!(unsafe { ptr::read_volatile(ptr) }, 0xbeef);
+        assert_eq!(execute(&format!("rd {:#x}", addr), &mut Sink), Ok(()));
+        assert_eq!(
+            execute(&format!("hexdump {:#x} 8", addr), &mut Sink),
+            Ok(())
+        );
+        assert_eq!(
+            execute(&format!("rd {:#x} 8", addr + 1), &mut Sink),
+            Err(ShellError::Failed("address not aligned to the width"))
+        );
+    }
+
+    #[test_case]
+    fn refuse_unmapped() {
+        build_registries();
+        assert_eq!(
+            execute("rd 0x0", &mut Sink),
+            Err(ShellError::Failed("address range not mapped"))
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Commands rebooting and powering off the machine.
+
+use core::fmt::Write;
+
+use super::{Args, Command, ShellError};
+use crate::{info, power};
+
+/// The power commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "reboot",
+        Command {
+            usage: "reboot",
+            help: "reset the machine",
+            handler: reboot,
+        },
+    ),
+    (
+        "shutdown",
+        Command {
+            usage: "shutdown",
+            help: "power off the machine",
+            handler: shutdown,
+        },
+    ),
+];
+
+fn reboot(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    writeln!(out, "rebooting").unwrap();
+    info!("reboot requested from the shell");
+    power::reboot();
+}
+
+fn shutdown(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    writeln!(out, "powering off").unwrap();
+    info!("shutdown requested from the shell");
+    power::shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, execute, Sink};
+    use super::*;
+
+    #[test_case]
+    fn refuse_arguments() {
+        build_registries();
+        assert_eq!(
+            execute("reboot now", &mut Sink),
+            Err(ShellError::TooManyArguments)
+        );
+        assert_eq!(
+            execute("shutdown now", &mut Sink),
+            Err(ShellError::TooManyArguments)
+        );
+    }
+}
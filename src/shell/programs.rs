@@ -0,0 +1,174 @@
+//! Commands running the programs of the filesystem as processes.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt::Write;
+
+use super::{Args, Command, ShellError};
+use crate::{
+    fs::{
+        self,
+        fd::{FdTable, STDIN, STDOUT},
+    },
+    process::{self, Pid},
+};
+
+/// The program commands, registered to the shell on its initialization.
+pub(super) const COMMANDS: &[(&str, Command)] = &[
+    (
+        "exec",
+        Command {
+            usage: "exec <path> [| <path>]...",
+            help:
+                "run the programs, each reading the output of the previous one, and wait for them",
+            handler: exec,
+        },
+    ),
+    (
+        "procs",
+        Command {
+            usage: "procs",
+            help: "list the processes",
+            handler: procs,
+        },
+    ),
+];
+
+fn exec(mut args: Args, _out: &mut dyn Write) -> Result<(), ShellError> {
+    let mut paths = alloc::vec![args.next_str("path")?];
+    while let Some(separator) = args.optional() {
+        if separator != "|" {
+            return Err(ShellError::InvalidArgument("|"));
+        }
+        paths.push(args.next_str("path")?);
+    }
+    let codes = paths
+        .iter()
+        .map(|path| fs::read(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut pids = Vec::new();
+    let spawned = spawn_pipeline(&paths, &codes, &mut pids);
+    // the session waits in the foreground, the processes and the other tasks run meanwhile
+    args.foreground(Box::pin(async move {
+        let mut out = String::new();
+        for pid in pids {
+            let (_, exit) = process::wait(None, Some(pid))
+                .await
+                .map_err(|_| ShellError::Failed("the process was reaped elsewhere"))?;
+            writeln!(out, "process {} {}", pid, exit).unwrap();
+        }
+        spawned.map(|()| out)
+    }))
+}
+
+/// Spawn the programs `codes` at `paths`, the standard output of each one piped to the standard
+/// input of the next one. Pushes the processes spawned to `pids`, even if a later one fails.
+fn spawn_pipeline(
+    paths: &[&str],
+    codes: &[Vec<u8>],
+    pids: &mut Vec<Pid>,
+) -> Result<(), ShellError> {
+    // the processes inherit the console, the shell keeps the reading end of the last pipe until
+    // the next process inherits it, its ends are closed on return
+    let mut fds = FdTable::with_console();
+    let mut input = None;
+    for (i, (path, code)) in paths.iter().zip(codes).enumerate() {
+        let pipe = if i + 1 < paths.len() {
+            Some(fds.pipe()?)
+        } else {
+            None
+        };
+        let mut stage = fds.inherit();
+        if let Some(reader) = input {
+            stage.dup_to(reader, STDIN)?;
+            stage.close(reader)?;
+            fds.close(reader)?;
+        }
+        if let Some((reader, writer)) = pipe {
+            stage.dup_to(writer, STDOUT)?;
+            stage.close(writer)?;
+            stage.close(reader)?;
+            fds.close(writer)?;
+            input = Some(reader);
+        }
+        let pid = process::spawn_with(path, code, None, stage)
+            .map_err(|_| ShellError::Failed("not a program"))?;
+        pids.push(pid);
+    }
+    Ok(())
+}
+
+fn procs(args: Args, out: &mut dyn Write) -> Result<(), ShellError> {
+    args.finish()?;
+
+    writeln!(out, "{:>6} {:>6} {:<8} name", "pid", "parent", "state").unwrap();
+    for process in process::list() {
+        let parent = process.parent.map_or(0, |parent| parent.as_u64());
+        writeln!(
+            out,
+            "{:>6} {:>6} {:<8} {}",
+            process.pid, parent, process.state, process.name
+        )
+        .unwrap();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build_registries, execute};
+    use super::*;
+    use crate::testing;
+    use alloc::{string::String, vec::Vec};
+
+    #[test_case]
+    fn programs_piped() {
+        build_registries();
+        let mut out = String::new();
+        testing::keep_allocations(|| {
+            execute(
+                "exec /boot/initrd/bin/hello | /boot/initrd/bin/upcase",
+                &mut out,
+            )
+        })
+        .unwrap();
+        let exits: Vec<&str> = out.lines().collect();
+        assert_eq!(exits.len(), 2);
+        assert!(exits
+            .iter()
+            .all(|exit| exit.ends_with(" exited with status 0")));
+    }
+
+    #[test_case]
+    fn program_run_from_initrd() {
+        build_registries();
+        let mut out = String::new();
+        // the process table and the set of processes keep their nodes
+        testing::keep_allocations(|| execute("exec /boot/initrd/bin/hello", &mut out)).unwrap();
+        assert!(out.starts_with("process ") && out.ends_with(" exited with status 0\n"));
+        assert!(process::list()
+            .iter()
+            .all(|process| process.name != "/boot/initrd/bin/hello"));
+
+        assert_eq!(
+            execute("exec /boot/initrd/etc", &mut out),
+            Err(ShellError::Fs(fs::FsError::IsADirectory))
+        );
+        assert_eq!(
+            execute("exec /boot/initrd/bin/hello > /tmp/out", &mut out),
+            Err(ShellError::InvalidArgument("|"))
+        );
+        let mut listing = String::new();
+        assert_eq!(execute("procs", &mut listing), Ok(()));
+        assert!(listing.starts_with("   pid parent state"));
+    }
+
+    #[test_case]
+    fn elf_program_run_from_initrd() {
+        build_registries();
+        let mut out = String::new();
+        testing::keep_allocations(|| execute("exec /boot/initrd/bin/elfhello", &mut out)).unwrap();
+        // one write counted in `.data`, plus the zeroed `.bss`
+        assert!(out.starts_with("process ") && out.ends_with(" exited with status 1\n"));
+    }
+}
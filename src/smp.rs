@@ -0,0 +1,687 @@
+//! The application processors (APs), started by the bootstrap processor (BSP) with an INIT and
+//! startup IPIs.
+//!
+//! An AP starts in real mode on a page below 1 MiB, reserved by [reserve_trampoline], where [init]
+//! copies the trampoline: it loads a temporary GDT, enters protected mode, then long mode with the
+//! page tables, EFER and control registers of the BSP, and calls [ap_entry] on a stack of its own.
+//! The trampoline page is identity mapped while the APs start, one at a time as they share it.
+//!
+//! Each AP loads its own GDT and TSS, the IDT shared by all processors and enables its local APIC,
+//! then runs an executor of its own with interrupts enabled, polling the tasks spawned on it by
+//! [spawn_on](crate::task::executor::spawn_on). The programs in ring 3 and the tasks of the kernel
+//! itself only run on the BSP, only the BSP gets the timer and the device interrupts.
+//!
+//! The processors started interrupt each other with the inter-processor interrupts of [Ipi], sent
+//! by [send_ipi] and [broadcast]: a reschedule calls the handlers of [register_ipi_handler], a TLB
+//! shootdown flushes the TLB entries of the page tables changed by [shootdown], and a halt stops
+//! the processors for good, see [halt_others].
+//!
+//! The first processor to panic claims the panic with [claim_panic] and halts the others before
+//! reporting it, a processor panicking meanwhile parks right away. The halted processors record
+//! where they were interrupted, included in the crash report one after the other, see [halted].
+//!
+//! The processors are numbered from 0, the BSP, then in the order of the MADT, see [cpus].
+
+use alloc::vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    mem, ptr,
+    sync::atomic::{fence, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr0, Cr3, Cr4},
+    registers::model_specific::Efer,
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use crate::{
+    acpi, apic, gdt, hlt_loop, info, interrupts, memory,
+    task::executor::Executor,
+    time::{delay, tsc},
+    warn,
+};
+use spin::Mutex;
+
+/// The largest number of processors used, the others are never started.
+pub const MAX_CPUS: usize = 16;
+
+/// The size of the stack of each AP.
+const AP_STACK_SIZE: usize = 16 * 1024;
+/// The startup IPI only reaches pages below 1 MiB.
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+/// The time an AP takes to reset after the INIT IPI.
+const INIT_DELAY_MS: u64 = 10;
+/// The time an AP takes to start after a startup IPI, before the second one is sent.
+const STARTUP_DELAY_US: u64 = 200;
+/// The time an AP has to reach [ap_entry] after the startup IPIs.
+const START_TIMEOUT_MS: u64 = 100;
+
+/// Maximum number of handlers registered on an IPI.
+const MAX_IPI_HANDLERS: usize = 4;
+/// The time the other processors have to halt on panic.
+const HALT_TIMEOUT_MS: u64 = 100;
+/// The address in [SHOOTDOWN_ADDRESS] flushing the whole TLB.
+const FLUSH_ALL: u64 = u64::MAX;
+
+/// Long mode can't be enabled with PCIDs enabled.
+const CR4_PCIDE: u64 = 1 << 17;
+/// Set by the processor in long mode.
+const EFER_LMA: u64 = 1 << 10;
+
+// The trampoline, copied to the page of [reserve_trampoline] which the startup IPI makes the AP
+// start on in real mode, with CS holding the page and IP 0. Everything is addressed relative to the
+// page: its address is kept in ebx, the base of the temporary GDT is patched in the copy.
+global_asm!(
+    "
+.intel_syntax noprefix
+.section .text.ap_trampoline, \"ax\"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    mov ss, ax
+    mov sp, 4096
+    movzx ebx, ax
+    shl ebx, 4
+    add dword ptr [TRAMPOLINE_GDT_POINTER + 2], ebx
+    lgdt [TRAMPOLINE_GDT_POINTER]
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    # far return to the 32-bit code segment
+    lea eax, [ebx + TRAMPOLINE_32]
+    mov ecx, 0x08
+    push ecx
+    push eax
+    retf
+
+.code32
+ap_trampoline_32:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    lea esp, [ebx + 4096]
+    mov eax, [ebx + TRAMPOLINE_DATA + 8]
+    mov cr4, eax
+    mov eax, [ebx + TRAMPOLINE_DATA]
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    mov eax, [ebx + TRAMPOLINE_DATA + 16]
+    mov edx, [ebx + TRAMPOLINE_DATA + 20]
+    wrmsr
+    # pushed before paging is enabled, the identity mapping may be read-only
+    lea eax, [ebx + TRAMPOLINE_64]
+    push 0x18
+    push eax
+    mov eax, [ebx + TRAMPOLINE_DATA + 24]
+    mov cr0, eax
+    retf
+
+.code64
+ap_trampoline_64:
+    mov ebx, ebx
+    xor eax, eax
+    mov ds, eax
+    mov es, eax
+    mov ss, eax
+    mov rsp, [rbx + TRAMPOLINE_DATA + 32]
+    mov rdi, [rbx + TRAMPOLINE_DATA + 48]
+    mov rsi, [rbx + TRAMPOLINE_DATA + 56]
+    call [rbx + TRAMPOLINE_DATA + 40]
+    ud2
+
+.balign 8
+ap_trampoline_gdt:
+    .quad 0
+    # flat 32-bit code, flat data, 64-bit code
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_trampoline_gdt_pointer:
+    .word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1
+    .long ap_trampoline_gdt - ap_trampoline_start
+
+.balign 8
+.global ap_trampoline_data
+ap_trampoline_data:
+    .fill 64, 1, 0
+.global ap_trampoline_end
+ap_trampoline_end:
+
+.set TRAMPOLINE_GDT_POINTER, ap_trampoline_gdt_pointer - ap_trampoline_start
+.set TRAMPOLINE_32, ap_trampoline_32 - ap_trampoline_start
+.set TRAMPOLINE_64, ap_trampoline_64 - ap_trampoline_start
+.set TRAMPOLINE_DATA, ap_trampoline_data - ap_trampoline_start
+.previous
+.att_syntax
+"
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The fields read by the trampoline at `ap_trampoline_data`, in the order of its offsets.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    cr0: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+    tables: u64,
+}
+
+static TRAMPOLINE: OnceCell<PhysFrame> = OnceCell::uninit();
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_APIC_ID: AtomicU8 = AtomicU8::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// The local APIC IDs of the processors, only written by [init] before the processors start.
+static APIC_IDS: [AtomicU8; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+/// Set by each processor once it's started.
+static ONLINE: [AtomicBool; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+/// The number of processors in [APIC_IDS].
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A processor, see [cpus].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    /// The number of the processor, 0 for the BSP.
+    pub index: usize,
+    /// The ID of its local APIC.
+    pub apic_id: u8,
+    /// Whether it has started.
+    pub online: bool,
+}
+
+/// Reserve the page the APs start on. Must be called before any other frame is allocated from
+/// `frame_allocator`, which hands out the lowest usable frames first. The APs aren't started if no
+/// frame below 1 MiB is usable.
+pub fn reserve_trampoline(frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    match frame_allocator.allocate_frame() {
+        Some(frame) if frame.start_address().as_u64() < TRAMPOLINE_LIMIT => {
+            let _ = TRAMPOLINE.try_init_once(|| frame);
+        }
+        // the frame is leaked, one page doesn't matter
+        _ => warn!("no usable page below 1 MiB to start the application processors on"),
+    }
+}
+
+fn register(apic_id: u8) -> usize {
+    let index = CPU_COUNT.load(Ordering::Relaxed);
+    APIC_IDS[index].store(apic_id, Ordering::Relaxed);
+    CPU_COUNT.store(index + 1, Ordering::Release);
+    index
+}
+
+/// Register the BSP and start the APs listed by the MADT if `start_aps` is set, returns the number
+/// of processors online. Must be called once after [apic::init] and [reserve_trampoline], with the
+/// heap initialized.
+pub fn init(
+    start_aps: bool,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> usize {
+    let bsp = if apic::is_initialized() {
+        apic::id()
+    } else {
+        0
+    };
+    ONLINE[register(bsp)].store(true, Ordering::Release);
+    if !start_aps || !apic::is_initialized() {
+        return 1;
+    }
+    let (madt, frame) = match (acpi::madt(), TRAMPOLINE.try_get()) {
+        (Some(madt), Ok(&frame)) => (madt, frame),
+        _ => return 1,
+    };
+    // the trampoline loads CR3 in protected mode
+    if Cr3::read().0.start_address().as_u64() > u64::from(u32::MAX) {
+        warn!("the page tables are above 4 GiB, the application processors can't use them");
+        return 1;
+    }
+
+    for apic_id in madt.processors().filter(|&apic_id| apic_id != bsp) {
+        if CPU_COUNT.load(Ordering::Relaxed) == MAX_CPUS {
+            warn!(
+                "more than {} processors, the others aren't started",
+                MAX_CPUS
+            );
+            break;
+        }
+        register(apic_id);
+    }
+    let count = CPU_COUNT.load(Ordering::Relaxed);
+    if count == 1 {
+        return 1;
+    }
+
+    // paging is enabled by the trampoline before it jumps to the kernel
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let mapped = match memory::translate(page.start_address()) {
+        Some((phys, flags))
+            if phys == frame.start_address() && !flags.contains(PageTableFlags::NO_EXECUTE) =>
+        {
+            false
+        }
+        Some(_) => {
+            warn!("trampoline page mapped elsewhere, the application processors aren't started");
+            return 1;
+        }
+        None => {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            // # Safety
+            // The frame is reserved for the trampoline, nothing else refers to it.
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    warn!("trampoline page not mapped: {:?}", err);
+                    return 1;
+                }
+            }
+            true
+        }
+    };
+
+    for (cpu, apic_id) in APIC_IDS.iter().enumerate().take(count).skip(1) {
+        let apic_id = apic_id.load(Ordering::Relaxed);
+        if !start(cpu, apic_id, frame) {
+            // a late AP would read the trampoline prepared for the next one
+            warn!(
+                "processor {} (APIC {}) didn't start, the others aren't started",
+                cpu, apic_id
+            );
+            break;
+        }
+    }
+
+    if mapped {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+
+    let online = online_cpus();
+    info!("{} of {} processors online", online, count);
+    online
+}
+
+/// Start the AP `cpu` with the local APIC `apic_id` on the trampoline copied to `frame`, returns
+/// `false` if it doesn't reach [ap_entry] in time.
+fn start(cpu: usize, apic_id: u8, frame: PhysFrame) -> bool {
+    // aligned down below, the bytes past the top are wasted
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let stack_top = (VirtAddr::from_ptr(stack.as_ptr()) + AP_STACK_SIZE).align_down(16u64);
+    let tables = gdt::new_tables();
+
+    let trampoline = memory::phys_to_virt(frame.start_address());
+    // # Safety
+    // The trampoline is a few hundred bytes of code and data between the symbols, the frame is
+    // reserved for it and mapped with the complete physical memory. No AP runs the trampoline.
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        let data_offset = &ap_trampoline_data as *const u8 as usize - start as usize;
+        assert!(
+            len <= 4096 - 16,
+            "the trampoline doesn't fit in a page with its stack"
+        );
+        assert!(data_offset + mem::size_of::<TrampolineData>() <= len);
+        ptr::copy_nonoverlapping(start, trampoline.as_mut_ptr(), len);
+        ptr::write(
+            (trampoline + data_offset).as_mut_ptr(),
+            TrampolineData {
+                cr3: Cr3::read().0.start_address().as_u64(),
+                cr4: Cr4::read_raw() & !CR4_PCIDE,
+                efer: Efer::read_raw() & !EFER_LMA,
+                cr0: Cr0::read_raw(),
+                stack_top: stack_top.as_u64(),
+                entry: ap_entry as extern "C" fn(usize, &'static gdt::Tables) -> ! as usize as u64,
+                cpu: cpu as u64,
+                tables: tables as *const gdt::Tables as u64,
+            },
+        );
+    }
+    fence(Ordering::SeqCst);
+
+    let page = (frame.start_address().as_u64() >> 12) as u8;
+    apic::send_init(apic_id);
+    delay::delay_ms(INIT_DELAY_MS);
+    // the second startup IPI is only needed if the first is lost
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        delay::delay_us(STARTUP_DELAY_US);
+        if ONLINE[cpu].load(Ordering::Acquire) {
+            return true;
+        }
+    }
+
+    let deadline = tsc::rdtsc() + tsc::nanos_to_cycles(START_TIMEOUT_MS * 1_000_000);
+    while tsc::rdtsc() < deadline {
+        if ONLINE[cpu].load(Ordering::Acquire) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Called by the trampoline in long mode on the stack of the AP `cpu`.
+extern "C" fn ap_entry(cpu: usize, tables: &'static gdt::Tables) -> ! {
+    gdt::load(tables);
+    // # Safety
+    // The GDT holding the double fault stack of the IDT is loaded.
+    unsafe { interrupts::init_idt() };
+    apic::init_ap();
+    ONLINE[cpu].store(true, Ordering::Release);
+
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run();
+}
+
+/// The processors listed by the MADT, started or not, the BSP first.
+pub fn cpus() -> impl Iterator<Item = Cpu> {
+    (0..CPU_COUNT.load(Ordering::Acquire)).map(|index| Cpu {
+        index,
+        apic_id: APIC_IDS[index].load(Ordering::Relaxed),
+        online: ONLINE[index].load(Ordering::Acquire),
+    })
+}
+
+/// The number of processors started, the BSP included.
+pub fn online_cpus() -> usize {
+    cpus().filter(|cpu| cpu.online).count()
+}
+
+/// The number of the running processor.
+pub fn current() -> usize {
+    if !apic::is_initialized() {
+        return 0;
+    }
+    let apic_id = apic::id();
+    cpus()
+        .find(|cpu| cpu.apic_id == apic_id)
+        .map_or(0, |cpu| cpu.index)
+}
+
+/// The inter-processor interrupts, each one a vector above those of the hardware interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Ipi {
+    /// Calls the handlers registered with [register_ipi_handler], for the schedulers.
+    Reschedule = 0xf0,
+    /// Flushes the TLB entries invalidated by [shootdown].
+    TlbShootdown = 0xf1,
+    /// Halts the processor with interrupts disabled, never to resume, see [halt_others].
+    Halt = 0xf2,
+}
+
+impl Ipi {
+    /// The interrupt vector of the IPI.
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The handlers registered on an IPI.
+type IpiHandlers = [Option<fn()>; MAX_IPI_HANDLERS];
+
+/// The handlers registered on [Ipi::Reschedule].
+static RESCHEDULE_HANDLERS: Mutex<IpiHandlers> = Mutex::new([None; MAX_IPI_HANDLERS]);
+
+/// The processor which claimed the panic, [NO_CPU] if none did.
+static PANICKING: AtomicUsize = AtomicUsize::new(NO_CPU);
+const NO_CPU: usize = usize::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_REGISTER: AtomicU64 = AtomicU64::new(0);
+/// Set by each processor halted by [halt_others] once it recorded its registers.
+static HALTED: [AtomicBool; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+static HALTED_RIP: [AtomicU64; MAX_CPUS] = [NO_REGISTER; MAX_CPUS];
+static HALTED_RSP: [AtomicU64; MAX_CPUS] = [NO_REGISTER; MAX_CPUS];
+
+/// Held for the duration of a [shootdown], the processors take one at a time.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+/// The page to flush in the running shootdown, or [FLUSH_ALL].
+static SHOOTDOWN_ADDRESS: AtomicU64 = AtomicU64::new(FLUSH_ALL);
+/// The number of processors yet to flush their TLB in the running shootdown.
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Send `ipi` to the processor `cpu`, returns `false` if it isn't online or is the running
+/// processor.
+pub fn send_ipi(cpu: usize, ipi: Ipi) -> bool {
+    let target = match cpus().nth(cpu) {
+        Some(target) if target.online && cpu != current() => target,
+        _ => return false,
+    };
+    apic::send_fixed(target.apic_id, ipi.vector());
+    true
+}
+
+/// Send `ipi` to every processor online but the running one, returns the number of processors it
+/// was sent to.
+pub fn broadcast(ipi: Ipi) -> usize {
+    let current = current();
+    let mut sent = 0;
+    for cpu in cpus().filter(|cpu| cpu.online && cpu.index != current) {
+        apic::send_fixed(cpu.apic_id, ipi.vector());
+        sent += 1;
+    }
+    sent
+}
+
+/// Call `handler` on the receiving processor on each `ipi`, with interrupts disabled. Only
+/// [Ipi::Reschedule] calls its handlers, the other IPIs are handled by this module alone.
+///
+/// # Panics
+/// Panics if `ipi` isn't [Ipi::Reschedule] or too many handlers are registered on it.
+pub fn register_ipi_handler(ipi: Ipi, handler: fn()) {
+    assert_eq!(ipi, Ipi::Reschedule, "{:?} takes no handler", ipi);
+
+    // the lock is also taken by the interrupt handlers
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = RESCHEDULE_HANDLERS.lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .unwrap_or_else(|| panic!("too many handlers registered on {:?}", ipi));
+        *slot = Some(handler);
+    });
+}
+
+/// Handle `ipi` received by the running processor in the interrupted context `stack_frame`, called
+/// by its interrupt handler which signals the end of the interrupt afterwards.
+pub(crate) fn handle_ipi(ipi: Ipi, stack_frame: &InterruptStackFrame) {
+    match ipi {
+        Ipi::Reschedule => {
+            let handlers = *RESCHEDULE_HANDLERS.lock();
+            for handler in handlers.iter().flatten() {
+                handler();
+            }
+        }
+        Ipi::TlbShootdown => {
+            match SHOOTDOWN_ADDRESS.load(Ordering::Acquire) {
+                FLUSH_ALL => tlb::flush_all(),
+                address => tlb::flush(VirtAddr::new(address)),
+            }
+            SHOOTDOWN_PENDING.fetch_sub(1, Ordering::Release);
+        }
+        Ipi::Halt => {
+            let cpu = current();
+            HALTED_RIP[cpu].store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+            HALTED_RSP[cpu].store(stack_frame.stack_pointer.as_u64(), Ordering::Relaxed);
+            HALTED[cpu].store(true, Ordering::Release);
+            park();
+        }
+    }
+}
+
+/// Flush the TLB entries of the page at `address`, or the whole TLB if `None`, on every processor
+/// online, returns once they're all flushed. Must be called after changing the page tables and
+/// with interrupts enabled, a processor waiting for another shootdown handles this one meanwhile.
+pub fn shootdown(address: Option<VirtAddr>) {
+    let _shootdown = SHOOTDOWN.lock();
+    match address {
+        Some(address) => tlb::flush(address),
+        None => tlb::flush_all(),
+    }
+    if online_cpus() == 1 {
+        return;
+    }
+
+    SHOOTDOWN_ADDRESS.store(
+        address.map_or(FLUSH_ALL, VirtAddr::as_u64),
+        Ordering::Relaxed,
+    );
+    // counted before sending, a processor may flush before the next one is interrupted
+    let current = current();
+    let targets = cpus()
+        .filter(|cpu| cpu.online && cpu.index != current)
+        .count();
+    SHOOTDOWN_PENDING.store(targets, Ordering::Release);
+    broadcast(Ipi::TlbShootdown);
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Claim the panic for the running processor, called first by the panic handlers. Returns `false`
+/// if another processor panicked first, the running one must then [park] without touching
+/// anything shared, the first one reports the panic. A processor panicking again claims it again.
+pub fn claim_panic() -> bool {
+    let cpu = current();
+    match PANICKING.compare_exchange(NO_CPU, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => true,
+        Err(panicking) => panicking == cpu,
+    }
+}
+
+/// Stop the running processor for good with interrupts disabled, it's then offline.
+pub fn park() -> ! {
+    x86_64::instructions::interrupts::disable();
+    ONLINE[current()].store(false, Ordering::Release);
+    hlt_loop();
+}
+
+/// Halt every other processor online, called on panic so that nothing else runs while the panic is
+/// reported. Each one records where it was interrupted, see [halted], then parks. Returns once they
+/// are all offline, or after a timeout if some have interrupts disabled. Does nothing if the APs
+/// aren't started.
+pub fn halt_others() {
+    if !apic::is_initialized() || broadcast(Ipi::Halt) == 0 {
+        return;
+    }
+    let current = current();
+    let deadline = tsc::rdtsc() + tsc::nanos_to_cycles(HALT_TIMEOUT_MS * 1_000_000);
+    while cpus().any(|cpu| cpu.online && cpu.index != current) && tsc::rdtsc() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// A processor halted by [halt_others].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Halted {
+    /// The number of the processor.
+    pub cpu: usize,
+    /// The instruction it was interrupted at.
+    pub instruction_pointer: VirtAddr,
+    /// Its stack pointer when interrupted.
+    pub stack_pointer: VirtAddr,
+}
+
+/// The processors halted by [halt_others], in order.
+pub fn halted() -> impl Iterator<Item = Halted> {
+    (0..MAX_CPUS)
+        .filter(|&cpu| HALTED[cpu].load(Ordering::Acquire))
+        .map(|cpu| Halted {
+            cpu,
+            instruction_pointer: VirtAddr::new(HALTED_RIP[cpu].load(Ordering::Relaxed)),
+            stack_pointer: VirtAddr::new(HALTED_RSP[cpu].load(Ordering::Relaxed)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        task::{block_on, executor, timer},
+        testing::keep_allocations,
+    };
+    use core::time::Duration;
+
+    #[test_case]
+    fn processors_started() {
+        let madt = acpi::madt().expect("no MADT");
+        assert_eq!(online_cpus(), madt.processors().count().min(MAX_CPUS));
+        assert_eq!(
+            cpus().next(),
+            Some(Cpu {
+                index: 0,
+                apic_id: apic::id(),
+                online: true,
+            })
+        );
+        assert_eq!(current(), 0);
+    }
+
+    static RESCHEDULED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_reschedule() {
+        RESCHEDULED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Wait up to [START_TIMEOUT_MS] for `condition`.
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = tsc::rdtsc() + tsc::nanos_to_cycles(START_TIMEOUT_MS * 1_000_000);
+        while tsc::rdtsc() < deadline {
+            if condition() {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    }
+
+    #[test_case]
+    fn ipis_handled() {
+        // the tests run with two processors
+        assert!(online_cpus() > 1);
+        assert!(!send_ipi(0, Ipi::Reschedule));
+        assert!(!send_ipi(MAX_CPUS, Ipi::Reschedule));
+
+        register_ipi_handler(Ipi::Reschedule, count_reschedule);
+        let before = RESCHEDULED.load(Ordering::Relaxed);
+        assert!(send_ipi(1, Ipi::Reschedule));
+        assert!(wait_for(|| RESCHEDULED.load(Ordering::Relaxed) > before));
+        let before = RESCHEDULED.load(Ordering::Relaxed);
+        assert_eq!(broadcast(Ipi::Reschedule), online_cpus() - 1);
+        assert!(wait_for(
+            || RESCHEDULED.load(Ordering::Relaxed) == before + online_cpus() - 1
+        ));
+
+        shootdown(None);
+        shootdown(Some(VirtAddr::new(0x1000)));
+        assert_eq!(SHOOTDOWN_PENDING.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            online_cpus(),
+            acpi::madt().unwrap().processors().count().min(MAX_CPUS)
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! The PC speaker, a square wave of PIT channel 2 gated through the speaker port.
+//!
+//! Tones are played one at a time: [beep] waits for the tone playing to end before starting its
+//! own, and waits on the timer wheel for the duration of the tone instead of spinning.
+
+use core::time::Duration;
+
+use crate::{
+    task::{mutex::AsyncMutex, timer},
+    time::pit,
+};
+
+/// The lowest frequency channel 2 can generate, with the largest reload value.
+pub const MIN_FREQUENCY_HZ: u32 = (pit::BASE_FREQUENCY_HZ / u16::MAX as u64 + 1) as u32;
+/// The highest frequency played, well above the hearing range.
+pub const MAX_FREQUENCY_HZ: u32 = 40_000;
+
+/// Held for the whole of a tone.
+static SPEAKER: AsyncMutex<()> = AsyncMutex::new(());
+
+/// Start a tone of `hz` Hz, clamped to the frequencies the speaker can play. Returns at once, the
+/// tone plays until [silence] is called.
+pub fn tone(hz: u32) {
+    let hz = hz.clamp(MIN_FREQUENCY_HZ, MAX_FREQUENCY_HZ);
+    pit::set_speaker(Some(pit::divisor(u64::from(hz))));
+}
+
+/// Stop the tone playing if any.
+pub fn silence() {
+    pit::set_speaker(None);
+}
+
+/// Whether a tone is playing.
+pub fn is_playing() -> bool {
+    pit::speaker_enabled()
+}
+
+/// Play a tone of `hz` Hz for `duration`, a frequency of 0 is a rest. The tone stops early if the
+/// future is dropped.
+pub async fn beep(hz: u32, duration: Duration) {
+    /// Silences the speaker when the tone ends or is cancelled.
+    struct Playing;
+
+    impl Drop for Playing {
+        fn drop(&mut self) {
+            silence();
+        }
+    }
+
+    let _guard = SPEAKER.lock().await;
+    let _playing = if hz != 0 {
+        tone(hz);
+        Some(Playing)
+    } else {
+        None
+    };
+    timer::sleep(duration).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{task::block_on, time::Instant};
+
+    #[test_case]
+    fn beep_lasts_its_duration() {
+        let start = Instant::now();
+        block_on(beep(440, Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(!is_playing());
+    }
+
+    #[test_case]
+    fn tone_until_silenced() {
+        tone(0);
+        assert!(is_playing());
+        silence();
+        assert!(!is_playing());
+    }
+}
@@ -1,19 +1,32 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, task::Wake};
 use core::{
+    fmt,
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
-    task::{Context, Poll},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
 
+use futures_util::{future, pin_mut};
+use spin::Mutex;
+
 pub mod executor;
 pub mod keyboard;
+pub mod mouse;
+pub mod mutex;
+pub mod serial;
 pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+pub mod watchdog;
 
 /// An asynchronous task.
 pub struct Task {
     /// A globally unique task id.
     id: TaskId,
+    /// A human readable name shown in diagnostics.
+    name: &'static str,
+    priority: Priority,
     /// a pinned, heap allocated, and dynamically dispatched future with no output.
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
@@ -21,19 +34,202 @@ pub struct Task {
 impl Task {
     /// Create a [Task] from a future with no return value.
     pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self::named("<unnamed>", future)
+    }
+
+    /// Create a [Task] named `name` from a future with no return value.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Self {
         Self {
             id: TaskId::new(),
+            name,
+            priority: Priority::Normal,
             future: Box::pin(future),
         }
     }
 
+    /// Poll the task at `priority` instead of [Priority::Normal].
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Create a [Task] named `name` from a future with an output, which is awaited through the
+    /// [JoinHandle].
+    pub fn with_handle<T: 'static>(
+        name: &'static str,
+        future: impl Future<Output = T> + 'static,
+    ) -> (Self, JoinHandle<T>) {
+        let state = Arc::new(Mutex::new(JoinState {
+            result: None,
+            aborted: false,
+            task: None,
+            handle: None,
+        }));
+        let mut completion = Completion {
+            state: Arc::clone(&state),
+            output: None,
+        };
+
+        let task = Self::named(name, async move {
+            pin_mut!(future);
+            let state = Arc::clone(&completion.state);
+            completion.output = future::poll_fn(|cx| {
+                let mut state = state.lock();
+                if state.aborted {
+                    return Poll::Ready(None);
+                }
+                state.task = Some(cx.waker().clone());
+                drop(state);
+                future.as_mut().poll(cx).map(Some)
+            })
+            .await;
+        });
+
+        (task, JoinHandle { state })
+    }
+
+    /// The id of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// The name of the task.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The priority of the task.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+/// The priority of a task, the executor polls the ready tasks of a higher priority first.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub enum Priority {
+    /// Tasks waiting for the user, e.g. the shells reading the keyboard.
+    High,
+    /// The default.
+    Normal,
+    /// Background work, still polled now and then while higher priorities are busy.
+    Low,
+}
+
+impl Priority {
+    /// All the priorities, from the highest.
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// The name of the priority in diagnostics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Why a [JoinHandle] resolved without the output of its task. A panicking task halts the kernel
+/// instead, there's no unwinding to report it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was dropped before completion: aborted by [JoinHandle::abort], cancelled by
+    /// [executor::Executor::cancel], never spawned or dropped along with its executor.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task cancelled"),
+        }
+    }
+}
+
+struct JoinState<T> {
+    /// Set once the task completes or is dropped, taken by the [JoinHandle].
+    result: Option<Result<T, JoinError>>,
+    aborted: bool,
+    /// The waker of the task, woken once aborted.
+    task: Option<Waker>,
+    /// The waker of the [JoinHandle], woken once the result is set.
+    handle: Option<Waker>,
+}
+
+/// Owned by the future of a task created by [Task::with_handle], hands its output to the
+/// [JoinHandle] when the future is dropped, completed or not.
+struct Completion<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+    output: Option<T>,
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let result = self.output.take().ok_or(JoinError::Cancelled);
+        let handle = {
+            let mut state = self.state.lock();
+            state.result = Some(result);
+            state.task = None;
+            state.handle.take()
+        };
+        if let Some(waker) = handle {
+            waker.wake();
+        }
+    }
+}
+
+/// A future resolving to the output of a task created by [Task::with_handle]. Dropping the handle
+/// detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stop the task at its next poll, the handle then resolves to [JoinError::Cancelled] unless
+    /// the task has already completed.
+    pub fn abort(&self) {
+        let task = {
+            let mut state = self.state.lock();
+            state.aborted = true;
+            state.task.take()
+        };
+        if let Some(waker) = task {
+            waker.wake();
+        }
+    }
+
+    /// Whether the task has completed or has been dropped, the handle is then ready.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().result.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.handle = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A globally unique task id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -42,3 +238,46 @@ impl TaskId {
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Run `future` to completion on the current thread of execution, halting until the next
+/// interrupt whenever it's pending and not woken. For initialization code and tests outside of
+/// the executor, interrupts must be enabled. Timers due are expired between polls.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    use x86_64::instructions::interrupts;
+
+    struct FlagWaker(AtomicBool);
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(Arc::clone(&flag));
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        crate::time::wheel::expire(crate::time::ticks());
+        // the same race with interrupts as in the executor, see `Executor::sleep_if_idle`
+        interrupts::disable();
+        if flag.0.swap(false, Ordering::SeqCst) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
@@ -1,44 +1,466 @@
 //! A non-spinning executor.
+//!
+//! Each processor runs an executor of its own, the BSP in `kernel_main` and the APs once started,
+//! see [crate::smp]. A task stays on the processor it's spawned on: the tasks spawned by
+//! [Executor::spawn] on the executor of the running processor, those of [spawn_on] on the one of a
+//! given processor, e.g. the keyboard and console tasks stay on the BSP. A task woken on another
+//! processor is queued on its own one, which is interrupted out of halt by a reschedule IPI.
+//!
+//! Ready tasks are queued by their [Priority] and polled from the highest one, except that a ready
+//! task of the lowest priority is polled at least every [STARVATION_LIMIT] polls. The queue of each
+//! priority is a lock-free ring, tasks queued while it's full spill into a growable queue. A task
+//! is queued at most once however many times it's woken before its next poll, the growable queue
+//! keeps room for every task so that the wakers in interrupt handlers never allocate.
+//!
+//! The running tasks have no access to their executor, they spawn tasks through a [Spawner]
+//! instead, see [spawner].
+//!
+//! A task is cancelled by dropping its future, [Executor::cancel] or [super::JoinHandle::abort].
+//! The future observes it through the destructors of what it holds, e.g. a lock guard is
+//! released, there's no other notification.
 
-use core::task::{Context, Poll, Waker};
+use core::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{future::Future, sync::atomic::AtomicBool};
 use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
 
-use super::{Task, TaskId};
+use super::{watchdog, JoinHandle, Priority, Task, TaskId};
+use crate::{
+    fault::{self, Fault},
+    metrics::{Counter, Gauge},
+    scheduler,
+    smp::{self, Ipi, MAX_CPUS},
+    time::{self, Instant},
+    trace_event,
+};
 
 const QUEUE_SIZE: usize = 100;
+/// The number of polls of higher priorities in a row after which a ready task of the lowest
+/// priority is polled.
+pub const STARVATION_LIMIT: usize = 16;
 
-/// A non-spinning, FIFO executor that makes proper use of wakers.
+/// Metrics of a task alive on an [Executor].
+#[derive(Debug, Clone, Copy)]
+pub struct TaskMetrics {
+    /// The id of the task.
+    pub id: TaskId,
+    /// The name of the task.
+    pub name: &'static str,
+    /// The processor the task runs on.
+    pub cpu: usize,
+    /// The priority of the task.
+    pub priority: Priority,
+    /// Number of times the task has been polled.
+    pub polls: u64,
+    /// Total time spent polling the task.
+    pub run_time: Duration,
+}
+
+lazy_static! {
+    /// Metrics of all the tasks alive on any [Executor]. Never locked in interrupt handlers.
+    static ref TASK_METRICS: Mutex<BTreeMap<TaskId, TaskMetrics>> = Mutex::new(BTreeMap::new());
+}
+
+static SPAWNED_TASKS: AtomicU64 = AtomicU64::new(0);
+static COMPLETED_TASKS: AtomicU64 = AtomicU64::new(0);
+static CANCELLED_TASKS: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+/// The id of the task being polled on each processor, [NO_TASK] between polls.
+static CURRENT_TASKS: [AtomicU64; MAX_CPUS] = [NO_CURRENT_TASK; MAX_CPUS];
+const NO_TASK: u64 = u64::MAX;
+
+/// A task queued in the inbox of a processor by [spawn_on] or by a [Spawner].
+struct Queued(Task);
+
+// # Safety
+// [spawn_on] only builds queued tasks from `Send` futures, a [Spawner] is not `Send` and only
+// queues tasks on the processor it's used on.
+unsafe impl Send for Queued {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_INBOX: Mutex<Vec<Queued>> = Mutex::new(Vec::new());
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_RUNNING: AtomicBool = AtomicBool::new(false);
+/// The tasks spawned on each processor by [spawn_on] and [Spawner::spawn], taken by its executor on
+/// its next iteration.
+static INBOXES: [Mutex<Vec<Queued>>; MAX_CPUS] = [EMPTY_INBOX; MAX_CPUS];
+/// Set once the executor of each processor runs.
+static RUNNING: [AtomicBool; MAX_CPUS] = [NOT_RUNNING; MAX_CPUS];
+
+static POLLS: Counter = Counter::new("executor.polls");
+static SPILLED: Counter = Counter::new("executor.spilled");
+static ALIVE_TASKS: Gauge = Gauge::new("executor.tasks");
+
+/// Metrics of all the tasks alive on any [Executor], in ascending order of task ids.
+pub fn task_metrics() -> Vec<TaskMetrics> {
+    TASK_METRICS.lock().values().copied().collect()
+}
+
+/// Number of tasks spawned on any [Executor] since boot.
+pub fn spawned_tasks() -> u64 {
+    SPAWNED_TASKS.load(Ordering::Relaxed)
+}
+
+/// Number of tasks completed on any [Executor] since boot.
+pub fn completed_tasks() -> u64 {
+    COMPLETED_TASKS.load(Ordering::Relaxed)
+}
+
+/// Number of tasks cancelled by [Executor::cancel] on any [Executor] since boot.
+pub fn cancelled_tasks() -> u64 {
+    CANCELLED_TASKS.load(Ordering::Relaxed)
+}
+
+/// The id of the task being polled by the executor of the running processor, `None` between polls.
+pub fn current_task() -> Option<TaskId> {
+    match CURRENT_TASKS[smp::current()].load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+/// The name of the task being polled by the executor of the running processor, `None` between
+/// polls or if the name can't be looked up without waiting for a lock.
+pub fn current_task_name() -> Option<&'static str> {
+    let id = current_task()?;
+    TASK_METRICS.try_lock()?.get(&id).map(|task| task.name)
+}
+
+/// A report of the state of the executors, rendered by the watchdog from the timer interrupt
+/// handler. Formatting it neither allocates nor waits for locks.
+pub struct StallReport;
+
+impl fmt::Display for StallReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "tasks spawned: {}, completed: {}",
+            spawned_tasks(),
+            completed_tasks()
+        )?;
+
+        // the stalled task may well be holding the lock
+        let metrics = match TASK_METRICS.try_lock() {
+            Some(metrics) => metrics,
+            None => return writeln!(f, "task metrics are locked"),
+        };
+        match current_task() {
+            Some(id) => {
+                let name = metrics.get(&id).map_or("<completed>", |task| task.name);
+                writeln!(f, "current task: {} {}", id, name)?;
+            }
+            None => writeln!(f, "current task: none")?,
+        }
+        for task in metrics.values() {
+            writeln!(f, "{:>8} {:>10} {}", task.id, task.polls, task.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawn a task named `name` on the executor of the processor `cpu`, which polls it on its next
+/// iteration. Returns `false` if that executor isn't running.
+pub fn spawn_on(
+    cpu: usize,
+    name: &'static str,
+    future: impl Future<Output = ()> + Send + 'static,
+) -> bool {
+    if !RUNNING
+        .get(cpu)
+        .map_or(false, |running| running.load(Ordering::Acquire))
+    {
+        return false;
+    }
+    // the lock is also taken by the executor with interrupts disabled
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        INBOXES[cpu].lock().push(Queued(Task::named(name, future)));
+    });
+    if cpu != smp::current() {
+        smp::send_ipi(cpu, Ipi::Reschedule);
+    }
+    true
+}
+
+/// A handle spawning tasks on the executor of the processor it's created on, usable by the tasks
+/// of that executor. The tasks are polled from the next iteration of the executor, unlike
+/// [spawn_on] even if it doesn't run yet.
+#[derive(Debug, Clone)]
+pub struct Spawner {
+    cpu: usize,
+    /// Keeps the handle and the futures it spawns on their processor.
+    _local: PhantomData<*const ()>,
+}
+
+impl Spawner {
+    /// Spawn a task named `name` polling `future`.
+    pub fn spawn(&self, name: &'static str, future: impl Future<Output = ()> + 'static) {
+        self.push(Task::named(name, future));
+    }
+
+    /// Spawn a task named `name` polling `future`, its output is awaited through the returned
+    /// handle.
+    pub fn spawn_with_handle<T: 'static>(
+        &self,
+        name: &'static str,
+        future: impl Future<Output = T> + 'static,
+    ) -> JoinHandle<T> {
+        let (task, handle) = Task::with_handle(name, future);
+        self.push(task);
+        handle
+    }
+
+    fn push(&self, task: Task) {
+        // the lock is also taken by the executor with interrupts disabled
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            INBOXES[self.cpu].lock().push(Queued(task));
+        });
+    }
+}
+
+/// A [Spawner] on the executor of the running processor.
+pub fn spawner() -> Spawner {
+    Spawner {
+        cpu: smp::current(),
+        _local: PhantomData,
+    }
+}
+
+/// A queue of ready tasks: a lock-free ring, spilling into a growable queue once full.
+struct ReadyQueue {
+    ring: ArrayQueue<TaskId>,
+    /// The tasks queued while the ring was full or not empty, polled after those of the ring. Its
+    /// spare capacity is kept by [ReadyQueue::reserve] for the interrupt handlers, which can't
+    /// allocate.
+    spill: Mutex<VecDeque<TaskId>>,
+    /// The length of `spill`, read without the lock.
+    spilled: AtomicUsize,
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        ReadyQueue {
+            ring: ArrayQueue::new(QUEUE_SIZE),
+            spill: Mutex::new(VecDeque::new()),
+            spilled: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queue `task_id` to be polled. Never allocates in an interrupt handler as long as the task
+    /// isn't already queued, see [ReadyQueue::reserve].
+    fn push(&self, task_id: TaskId) {
+        // the tasks stay in order as long as anything is spilled
+        if self.spilled.load(Ordering::Acquire) == 0
+            && !fault::should_fail(Fault::TaskQueue)
+            && self.ring.push(task_id).is_ok()
+        {
+            return;
+        }
+
+        // the lock is also taken by the wakers in interrupt handlers
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut spill = self.spill.lock();
+            spill.push_back(task_id);
+            self.spilled.store(spill.len(), Ordering::Release);
+        });
+        SPILLED.inc();
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        if let Some(task_id) = self.ring.pop() {
+            return Some(task_id);
+        }
+        if self.spilled.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut spill = self.spill.lock();
+            let task_id = spill.pop_front();
+            self.spilled.store(spill.len(), Ordering::Release);
+            task_id
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ring.is_empty() && self.spilled.load(Ordering::Acquire) == 0
+    }
+
+    /// Keep room in the spill queue for `tasks` more tasks, called outside of interrupt handlers
+    /// with the number of tasks on the executor. Each of them is queued at most once until it's
+    /// polled, the tasks woken by interrupt handlers in the meantime always fit.
+    fn reserve(&self, tasks: usize) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.spill.lock().reserve(tasks);
+        });
+    }
+}
+
+/// The queues of the ready tasks, one per [Priority].
+struct ReadyQueues {
+    queues: [Arc<ReadyQueue>; 3],
+    /// The number of polls in a row of higher priorities while tasks of the lowest one are ready.
+    passed_over: usize,
+}
+
+impl ReadyQueues {
+    fn new() -> Self {
+        ReadyQueues {
+            queues: [
+                Arc::new(ReadyQueue::new()),
+                Arc::new(ReadyQueue::new()),
+                Arc::new(ReadyQueue::new()),
+            ],
+            passed_over: 0,
+        }
+    }
+
+    fn queue(&self, priority: Priority) -> &Arc<ReadyQueue> {
+        &self.queues[priority.index()]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    fn reserve(&self, tasks: usize) {
+        for queue in self.queues.iter() {
+            queue.reserve(tasks);
+        }
+    }
+
+    /// The next task to poll, from the highest priority unless the lowest one has been passed
+    /// over [STARVATION_LIMIT] times.
+    fn pop(&mut self) -> Option<TaskId> {
+        let lowest = Priority::Low;
+        if self.passed_over >= STARVATION_LIMIT {
+            if let Some(task_id) = self.queue(lowest).pop() {
+                self.passed_over = 0;
+                return Some(task_id);
+            }
+        }
+
+        let (priority, task_id) = Priority::ALL
+            .iter()
+            .find_map(|&priority| Some((priority, self.queue(priority).pop()?)))?;
+        if priority == lowest || self.queue(lowest).is_empty() {
+            self.passed_over = 0;
+        } else {
+            self.passed_over += 1;
+        }
+        Some(task_id)
+    }
+}
+
+/// A non-spinning executor that makes proper use of wakers, polling the tasks of the processor it's
+/// created on, FIFO within each [Priority].
 pub struct Executor {
+    cpu: usize,
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    ready: ReadyQueues,
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
 }
 
 impl Executor {
-    /// Create a new empty [Executor] with the default capacity.
+    /// Create a new empty [Executor] with the default capacity on the running processor.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
+            cpu: smp::current(),
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(QUEUE_SIZE)),
+            ready: ReadyQueues::new(),
             waker_cache: BTreeMap::new(),
         }
     }
 
+    /// The processor the executor runs on.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// A [Spawner] on the executor, for its tasks.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            cpu: self.cpu,
+            _local: PhantomData,
+        }
+    }
+
     /// Spawn a new task onto the executor.
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let name = task.name;
+        let priority = task.priority;
         if self.tasks.insert(task.id, task).is_some() {
             panic!("the same task is spawned twice, should be impossible as spawn() takes ownership of the task");
         }
-        self.task_queue.push(task_id).expect("task queue is full");
+        let queue = Arc::clone(self.ready.queue(priority));
+        let task_waker = Arc::new(TaskWaker::new(task_id, self.cpu, queue));
+        self.waker_cache.insert(
+            task_id,
+            CachedWaker {
+                waker: Waker::from(Arc::clone(&task_waker)),
+                task_waker,
+            },
+        );
+        self.ready.queue(priority).push(task_id);
+        self.ready.reserve(self.tasks.len());
+
+        TASK_METRICS.lock().insert(
+            task_id,
+            TaskMetrics {
+                id: task_id,
+                name,
+                cpu: self.cpu,
+                priority,
+                polls: 0,
+                run_time: Duration::from_secs(0),
+            },
+        );
+        SPAWNED_TASKS.fetch_add(1, Ordering::Relaxed);
+        ALIVE_TASKS.add(1);
+        trace_event!(Executor, "spawn task {}", task_id);
+    }
+
+    /// Cancel the task `task_id` by dropping its future, a [JoinHandle] of the task then resolves
+    /// to [super::JoinError::Cancelled]. Returns `false` if the task isn't on the executor, e.g.
+    /// it has completed.
+    pub fn cancel(&mut self, task_id: TaskId) -> bool {
+        let task = match self.tasks.remove(&task_id) {
+            Some(task) => task,
+            None => return false,
+        };
+        // the task may still be queued, the executor skips the tasks it doesn't have
+        if let Some(cached) = self.waker_cache.remove(&task_id) {
+            cached.retire();
+        }
+        TASK_METRICS.lock().remove(&task_id);
+        CANCELLED_TASKS.fetch_add(1, Ordering::Relaxed);
+        ALIVE_TASKS.add(-1);
+        trace_event!(Executor, "task {} cancelled", task_id);
+        // the destructors of the future may do anything, e.g. wake other tasks
+        drop(task);
+        true
     }
 
     /// Kick start the executor, poll all the tasks in FIFO order.
     pub fn run(&mut self) -> ! {
+        RUNNING[self.cpu].store(true, Ordering::Release);
         loop {
+            self.begin_iteration();
             // sleep_if_idle() must also check the task queue because ...
             self.sleep_if_idle();
             self.run_ready_tasks();
@@ -46,15 +468,66 @@ impl Executor {
         }
     }
 
+    /// Run the executor until all of its tasks, including those they spawn, have completed or have
+    /// been cancelled. Halts while the tasks wait, interrupts must be enabled.
+    pub fn run_until_idle(&mut self) {
+        RUNNING[self.cpu].store(true, Ordering::Release);
+        loop {
+            self.begin_iteration();
+            if self.tasks.is_empty() {
+                break;
+            }
+            self.sleep_if_idle();
+            self.run_ready_tasks();
+        }
+        RUNNING[self.cpu].store(false, Ordering::Release);
+    }
+
+    /// Expire the timers and take the tasks spawned from outside, before the ready tasks are
+    /// polled.
+    fn begin_iteration(&mut self) {
+        // only the BSP gets timer interrupts, an idle executor still beats at least once per
+        // timer interrupt
+        if self.cpu == 0 {
+            watchdog::heartbeat();
+        }
+        time::wheel::expire(time::ticks());
+        self.spawn_queued_tasks();
+        self.ready.reserve(self.tasks.len());
+    }
+
+    fn spawn_queued_tasks(&mut self) {
+        let queued = x86_64::instructions::interrupts::without_interrupts(|| {
+            core::mem::take(&mut *INBOXES[self.cpu].lock())
+        });
+        for Queued(task) in queued {
+            self.spawn(task);
+        }
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts;
 
+        // the executor of the BSP runs on the boot thread, which lets the other kernel threads run
+        // rather than halting
+        if self.cpu == 0 && self.ready.is_empty() && scheduler::yield_now() {
+            return;
+        }
+
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.ready.is_empty() && INBOXES[self.cpu].lock().is_empty() {
             // a hardware interrupt may happen between the condition check and hlt(), interrupts
             // must be disabled in between, otherwise the computer will halt until the next
             // interrupt
+            let bsp = self.cpu == 0;
+            if bsp {
+                // the tick only runs on the BSP
+                time::enter_idle();
+            }
             interrupts::enable_and_hlt();
+            if bsp {
+                time::exit_idle();
+            }
         } else {
             interrupts::enable();
         }
@@ -62,53 +535,104 @@ impl Executor {
 
     fn run_ready_tasks(&mut self) {
         let Self {
+            cpu,
             tasks,
-            task_queue,
+            ready,
             waker_cache,
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                // futures may register the waker spuriously after completion
-                None => continue,
+        while let Some(task_id) = ready.pop() {
+            let (task, cached) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id)) {
+                (Some(task), Some(cached)) => (task, cached),
+                // the task was cancelled while queued
+                _ => continue,
             };
 
-            let waker = waker_cache.entry(task_id).or_insert_with(|| {
-                let waker = TaskWaker::new(task_id, Arc::clone(task_queue));
-                Waker::from(Arc::new(waker))
-            });
+            // a wakeup from now on must poll the task again
+            cached.task_waker.queued.store(false, Ordering::Release);
+            let mut context = Context::from_waker(&cached.waker);
 
-            let mut context = Context::from_waker(&waker);
+            trace_event!(Executor, "poll task {}", task_id);
+            CURRENT_TASKS[*cpu].store(task_id.0, Ordering::Relaxed);
+            POLLS.inc();
+            let start = Instant::now();
+            let poll = task.poll(&mut context);
+            let run_time = start.elapsed();
+            CURRENT_TASKS[*cpu].store(NO_TASK, Ordering::Relaxed);
 
-            match task.poll(&mut context) {
+            let mut metrics = TASK_METRICS.lock();
+            match poll {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    if let Some(cached) = waker_cache.remove(&task_id) {
+                        cached.retire();
+                    }
+                    metrics.remove(&task_id);
+                    COMPLETED_TASKS.fetch_add(1, Ordering::Relaxed);
+                    ALIVE_TASKS.add(-1);
+                    trace_event!(Executor, "task {} completed", task_id);
+                }
+                Poll::Pending => {
+                    if let Some(metrics) = metrics.get_mut(&task_id) {
+                        metrics.polls += 1;
+                        metrics.run_time += run_time;
+                    }
                 }
-                Poll::Pending => (),
             }
         }
     }
 }
 
+/// The waker of a task on an [Executor], created on spawn.
+struct CachedWaker {
+    task_waker: Arc<TaskWaker>,
+    waker: Waker,
+}
+
+impl CachedWaker {
+    /// Stop queueing the task, which has left the executor. Futures may still hold its waker,
+    /// e.g. a timer registered before completion.
+    fn retire(self) {
+        self.task_waker.queued.store(true, Ordering::Release);
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// The processor of the executor of the task.
+    cpu: usize,
+    task_queue: Arc<ReadyQueue>,
+    /// Set while the task is in `task_queue` or once it has left the executor, cleared before each
+    /// poll.
+    queued: AtomicBool,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Self {
+    /// A waker of a task queued on spawn.
+    fn new(task_id: TaskId, cpu: usize, task_queue: Arc<ReadyQueue>) -> Self {
         Self {
             task_id,
+            cpu,
             task_queue,
+            queued: AtomicBool::new(true),
         }
     }
 
     fn wake_task(&self) {
-        self.task_queue
-            .push(self.task_id)
-            .expect("task queue is full");
+        trace_event!(Executor, "wake task {}", self.task_id);
+        // the task takes a single slot however many times it's woken before its next poll
+        if self
+            .queued
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+        self.task_queue.push(self.task_id);
+        // the executor may be halted on its processor
+        if self.cpu != smp::current() {
+            smp::send_ipi(self.cpu, Ipi::Reschedule);
+        }
     }
 }
 
@@ -121,3 +645,190 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn tasks_spawned_by_tasks() {
+        static CHILDREN: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+        executor.spawn(Task::named("parent", async move {
+            for _ in 0..2 {
+                spawner.spawn("child", async {
+                    CHILDREN.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        }));
+
+        executor.run_ready_tasks();
+        assert_eq!(CHILDREN.load(Ordering::Relaxed), 0);
+        executor.spawn_queued_tasks();
+        executor.run_ready_tasks();
+        assert_eq!(CHILDREN.load(Ordering::Relaxed), 2);
+        assert!(executor.tasks.is_empty());
+    }
+
+    #[test_case]
+    fn priorities_ordered() {
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        let polled = Rc::new(RefCell::new(Vec::new()));
+        let mut executor = Executor::new();
+        let spawn = |executor: &mut Executor, priority: Priority, index: usize| {
+            let polled = Rc::clone(&polled);
+            let task = Task::named("ordered", async move {
+                polled.borrow_mut().push((priority, index));
+            });
+            executor.spawn(task.with_priority(priority));
+        };
+        spawn(&mut executor, Priority::Low, 0);
+        spawn(&mut executor, Priority::Normal, 0);
+        spawn(&mut executor, Priority::High, 0);
+        spawn(&mut executor, Priority::Normal, 1);
+        executor.run_ready_tasks();
+        assert_eq!(
+            *polled.borrow(),
+            [
+                (Priority::High, 0),
+                (Priority::Normal, 0),
+                (Priority::Normal, 1),
+                (Priority::Low, 0)
+            ]
+        );
+
+        // the low priority task cuts in after the limit
+        polled.borrow_mut().clear();
+        spawn(&mut executor, Priority::Low, 0);
+        for index in 0..STARVATION_LIMIT + 1 {
+            spawn(&mut executor, Priority::High, index);
+        }
+        executor.run_ready_tasks();
+        assert_eq!(polled.borrow()[STARVATION_LIMIT], (Priority::Low, 0));
+        assert_eq!(polled.borrow().len(), STARVATION_LIMIT + 2);
+    }
+
+    #[test_case]
+    fn wakeup_burst_spilled() {
+        /// Wakes itself `WAKES` times on its first poll.
+        struct Burst(bool);
+
+        const WAKES: usize = 3;
+
+        impl Future for Burst {
+            type Output = ();
+
+            fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                if self.0 {
+                    return Poll::Ready(());
+                }
+                self.0 = true;
+                for _ in 0..WAKES {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        }
+
+        let mut executor = Executor::new();
+        let spilled = SPILLED.get();
+        for _ in 0..2 * QUEUE_SIZE {
+            executor.spawn(Task::named("burst", Burst(false)));
+        }
+        executor.run_ready_tasks();
+        assert!(executor.tasks.is_empty());
+        assert!(executor.ready.is_empty());
+        assert!(SPILLED.get() > spilled);
+
+        // woken by interrupt handlers more times than the ring and the spill queue would hold
+        let task = Task::named("woken", futures_util::future::pending::<()>());
+        let task_id = task.id();
+        executor.spawn(task);
+        executor.run_ready_tasks();
+        let waker = executor.waker_cache[&task_id].waker.clone();
+        interrupts::as_handler(|| {
+            for _ in 0..2 * QUEUE_SIZE + 1 {
+                waker.wake_by_ref();
+            }
+        });
+        let queue = executor.ready.queue(Priority::Normal);
+        assert_eq!(queue.ring.len(), 1);
+        assert_eq!(queue.spilled.load(Ordering::Relaxed), 0);
+
+        assert!(executor.cancel(task_id));
+        executor.run_ready_tasks();
+        assert!(executor.ready.is_empty());
+        // a retired waker queues nothing
+        waker.wake();
+        assert!(executor.ready.is_empty());
+    }
+
+    #[test_case]
+    fn spawn_on_full_queue() {
+        let mut executor = Executor::new();
+        fault::inject(Fault::TaskQueue, 0, 1);
+        let (task, handle) = Task::with_handle("spilled", async { 42 });
+        executor.spawn(task);
+        assert!(executor.ready.queue(Priority::Normal).ring.is_empty());
+        executor.run_ready_tasks();
+        assert_eq!(super::super::block_on(handle), Ok(42));
+    }
+
+    #[test_case]
+    fn run_until_idle() {
+        use super::super::{block_on, timer, JoinError};
+        use futures_util::future;
+
+        let mut executor = Executor::new();
+        let (task, sleeper) = Task::with_handle("sleeper", async {
+            timer::sleep(Duration::from_millis(10)).await;
+            spawner().spawn_with_handle("child", async { 7 }).await
+        });
+        executor.spawn(task);
+        let (task, pending) = Task::with_handle("pending", future::pending::<()>());
+        let pending_id = task.id();
+        executor.spawn(task);
+
+        let cancelled = cancelled_tasks();
+        assert!(executor.cancel(pending_id));
+        assert!(!executor.cancel(pending_id));
+        assert_eq!(cancelled_tasks(), cancelled + 1);
+        assert_eq!(block_on(pending), Err(JoinError::Cancelled));
+
+        executor.run_until_idle();
+        assert!(executor.tasks.is_empty());
+        assert_eq!(block_on(sleeper), Ok(Ok(7)));
+    }
+
+    #[test_case]
+    fn task_results_joined() {
+        use super::super::{block_on, JoinError};
+        use futures_util::future;
+
+        let mut executor = Executor::new();
+        let (task, answer) = Task::with_handle("answer", async { 42 });
+        executor.spawn(task);
+        let (task, aborted) = Task::with_handle("aborted", future::pending::<()>());
+        executor.spawn(task);
+        let (task, dropped) = Task::with_handle("dropped", future::pending::<()>());
+
+        executor.run_ready_tasks();
+        assert!(answer.is_finished() && !aborted.is_finished());
+        assert_eq!(block_on(answer), Ok(42));
+
+        // the aborted task is woken to stop
+        aborted.abort();
+        executor.run_ready_tasks();
+        assert!(executor.tasks.is_empty());
+        assert_eq!(block_on(aborted), Err(JoinError::Cancelled));
+
+        drop(task);
+        assert_eq!(block_on(dropped), Err(JoinError::Cancelled));
+    }
+}
@@ -1,32 +1,223 @@
 //! Asynchronous keyboard input handling.
+//!
+//! Scancodes are decoded with the [Layout] chosen by the `keymap=` option on the kernel command
+//! line or the `keymap` shell command, see [set_layout], and the control characters handling set
+//! by [set_control_handling].
 
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
     task::{Context, Poll},
 };
 
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use futures_util::{task::AtomicWaker, Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, KeyboardLayout, Modifiers,
+    ScancodeSet1,
+};
 
-use crate::{print, println};
+use crate::{info, metrics::Counter, power, print, vga_buffer, warn};
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 const QUEUE_SIZE: usize = 100;
+static DROPPED_SCANCODES: Counter = Counter::new("keyboard.dropped_scancodes");
+
+/// The modifiers of the Ctrl+Alt+Del hotkey held down, see [track_hotkey].
+static HOTKEY_MODIFIERS: AtomicU8 = AtomicU8::new(0);
+const MODIFIER_CTRL: u8 = 1 << 0;
+const MODIFIER_ALT: u8 = 1 << 1;
+
+/// Scancodes of set 1 pressing and releasing the modifiers, the right keys share them after an
+/// 0xe0 prefix.
+const SCANCODE_CTRL_PRESSED: u8 = 0x1d;
+const SCANCODE_CTRL_RELEASED: u8 = 0x9d;
+const SCANCODE_ALT_PRESSED: u8 = 0x38;
+const SCANCODE_ALT_RELEASED: u8 = 0xb8;
+/// The Delete key after an 0xe0 prefix, the keypad Delete without.
+const SCANCODE_DELETE_PRESSED: u8 = 0x53;
+const SCANCODE_LEFT_SHIFT_PRESSED: u8 = 0x2a;
+const SCANCODE_LEFT_SHIFT_RELEASED: u8 = 0xaa;
+const SCANCODE_RIGHT_SHIFT_PRESSED: u8 = 0x36;
+const SCANCODE_RIGHT_SHIFT_RELEASED: u8 = 0xb6;
+const MODIFIER_LEFT_SHIFT: u8 = 1 << 2;
+const MODIFIER_RIGHT_SHIFT: u8 = 1 << 3;
+
+/// The number of rows scrolled by Shift+PageUp and Shift+PageDown, half a screen.
+const SCROLL_ROWS: usize = 12;
+
+/// A keyboard layout from [pc_keyboard].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    /// The US 104-key layout.
+    Us,
+    /// The UK 105-key layout.
+    Uk,
+    /// The Dvorak layout on a 104-key keyboard.
+    Dvorak,
+    /// The French AZERTY layout.
+    Azerty,
+}
+
+impl Layout {
+    /// All the layouts.
+    pub const ALL: [Layout; 4] = [Layout::Us, Layout::Uk, Layout::Dvorak, Layout::Azerty];
+
+    /// The name of the layout on the command line and in the shell.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    /// The layout named `name` on the command line and in the shell.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+
+    fn from_u8(layout: u8) -> Layout {
+        Self::ALL[usize::from(layout)]
+    }
+}
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+/// Whether Ctrl+letter is decoded to the control characters U+0001 to U+001A.
+static MAP_CONTROL: AtomicBool = AtomicBool::new(false);
+
+/// Decode the keys with `layout` from now on, including in the decoders already created by
+/// [decoder].
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// The layout the keys are decoded with.
+pub fn layout() -> Layout {
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+/// Decode Ctrl+letter to a control character with `HandleControl::MapLettersToUnicode`, or to the
+/// letter itself with `HandleControl::Ignore`, the default.
+pub fn set_control_handling(handle_ctrl: HandleControl) {
+    MAP_CONTROL.store(
+        handle_ctrl == HandleControl::MapLettersToUnicode,
+        Ordering::Relaxed,
+    );
+}
+
+/// How Ctrl+letter is decoded, see [set_control_handling].
+pub fn control_handling() -> HandleControl {
+    if MAP_CONTROL.load(Ordering::Relaxed) {
+        HandleControl::MapLettersToUnicode
+    } else {
+        HandleControl::Ignore
+    }
+}
+
+/// The [KeyboardLayout] switching to the global [layout] and [control_handling] on every key.
+pub struct Configured;
+
+impl KeyboardLayout for Configured {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, _: HandleControl) -> DecodedKey {
+        let handle_ctrl = control_handling();
+        match layout() {
+            Layout::Us => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// A decoder of scancodes following the global [layout] and [control_handling].
+pub fn decoder() -> Keyboard<Configured, ScancodeSet1> {
+    Keyboard::new(Configured, ScancodeSet1, control_handling())
+}
+
+/// Update the `held` modifiers with `scancode`, returns the new modifiers and whether the hotkey
+/// has been pressed.
+fn track_hotkey(held: u8, scancode: u8) -> (u8, bool) {
+    match scancode {
+        SCANCODE_CTRL_PRESSED => (held | MODIFIER_CTRL, false),
+        SCANCODE_CTRL_RELEASED => (held & !MODIFIER_CTRL, false),
+        SCANCODE_ALT_PRESSED => (held | MODIFIER_ALT, false),
+        SCANCODE_ALT_RELEASED => (held & !MODIFIER_ALT, false),
+        SCANCODE_DELETE_PRESSED => (held, held == MODIFIER_CTRL | MODIFIER_ALT),
+        _ => (held, false),
+    }
+}
+
+/// Update the Shift keys `held` with `scancode`, returns the new Shift keys held.
+fn track_shift(held: u8, scancode: u8) -> u8 {
+    match scancode {
+        SCANCODE_LEFT_SHIFT_PRESSED => held | MODIFIER_LEFT_SHIFT,
+        SCANCODE_LEFT_SHIFT_RELEASED => held & !MODIFIER_LEFT_SHIFT,
+        SCANCODE_RIGHT_SHIFT_PRESSED => held | MODIFIER_RIGHT_SHIFT,
+        SCANCODE_RIGHT_SHIFT_RELEASED => held & !MODIFIER_RIGHT_SHIFT,
+        _ => held,
+    }
+}
+
+/// Scrolls the VGA text buffer back on Shift+PageUp and forwards on Shift+PageDown, for the tasks
+/// reading the keyboard.
+pub struct ScrollKeys {
+    shift: u8,
+}
+
+impl ScrollKeys {
+    /// Start with the Shift keys released.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        ScrollKeys { shift: 0 }
+    }
+
+    /// Track the Shift keys with `scancode`, then scroll if `key` decoded from it is a scroll
+    /// hotkey. Returns `key` unless it scrolled.
+    pub fn filter(&mut self, scancode: u8, key: Option<DecodedKey>) -> Option<DecodedKey> {
+        self.shift = track_shift(self.shift, scancode);
+        match key {
+            Some(DecodedKey::RawKey(KeyCode::PageUp)) if self.shift != 0 => {
+                vga_buffer::scroll_up(SCROLL_ROWS);
+                None
+            }
+            Some(DecodedKey::RawKey(KeyCode::PageDown)) if self.shift != 0 => {
+                vga_buffer::scroll_down(SCROLL_ROWS);
+                None
+            }
+            key => key,
+        }
+    }
+}
 
 pub(crate) fn add_scancode(scancode: u8) {
+    // the hotkey is handled here instead of by the consumer of the scancodes, it still reboots
+    // when no task reads the keyboard or the executor is stuck
+    let (held, pressed) = track_hotkey(HOTKEY_MODIFIERS.load(Ordering::Relaxed), scancode);
+    HOTKEY_MODIFIERS.store(held, Ordering::Relaxed);
+    if pressed {
+        info!("Ctrl+Alt+Del pressed");
+        power::reboot();
+    }
+
     let queue = match SCANCODE_QUEUE.try_get() {
         Ok(queue) => queue,
         Err(_) => {
-            println!("WARNING: scancode queue uninitialized");
+            warn!("scancode queue uninitialized");
             return;
         }
     };
 
     if queue.push(scancode).is_err() {
-        println!("WARNING: scancode full; dropping keyboard input");
+        DROPPED_SCANCODES.inc();
+        warn!("scancode queue full; dropping keyboard input");
         return;
     }
 
@@ -34,26 +225,165 @@ pub(crate) fn add_scancode(scancode: u8) {
     WAKER.wake();
 }
 
+/// Discard the scancodes queued but not yet consumed, returns the number of discarded scancodes.
+pub fn drain_scancodes() -> usize {
+    match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => core::iter::from_fn(|| queue.pop()).count(),
+        Err(_) => 0,
+    }
+}
+
 /// print key events
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut keyboard = decoder();
+    let mut scroll_keys = ScrollKeys::new();
 
     while let Some(scancode) = scancodes.next().await {
         // Processing a byte read from the PS/2 data port may not always be successful: the scancode
         // may be invalid, the scancode may lead to an impossible state assuming the keyboard
         // layout, the scancode may be corrupted by transmission, etc. Processing a byte may also
         // not return a key event, e.g. the escape byte before extended keycode.
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            // Press and release are two separate events in IBM XT. Here only key presses are mapped
-            // to characters.
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                    DecodedKey::Unicode(code) => print!("{}", code),
+        // Press and release are two separate events in IBM XT. Here only key presses are mapped to
+        // characters.
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+            _ => None,
+        };
+        match scroll_keys.filter(scancode, key) {
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            Some(DecodedKey::Unicode(code)) => print!("{}", code),
+            None => {}
+        }
+    }
+}
+
+/// The modifier keys held down, left and right keys alike.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModifierKeys(u8);
+
+impl ModifierKeys {
+    const LEFT_SHIFT: u8 = 1 << 0;
+    const RIGHT_SHIFT: u8 = 1 << 1;
+    const LEFT_CTRL: u8 = 1 << 2;
+    const RIGHT_CTRL: u8 = 1 << 3;
+    const LEFT_ALT: u8 = 1 << 4;
+    const RIGHT_ALT: u8 = 1 << 5;
+
+    /// The modifiers after `code` changed to `state`.
+    fn update(self, code: KeyCode, state: KeyState) -> Self {
+        let bit = match code {
+            KeyCode::ShiftLeft => Self::LEFT_SHIFT,
+            KeyCode::ShiftRight => Self::RIGHT_SHIFT,
+            KeyCode::ControlLeft => Self::LEFT_CTRL,
+            KeyCode::ControlRight => Self::RIGHT_CTRL,
+            KeyCode::AltLeft => Self::LEFT_ALT,
+            KeyCode::AltRight => Self::RIGHT_ALT,
+            _ => return self,
+        };
+        match state {
+            KeyState::Down => ModifierKeys(self.0 | bit),
+            KeyState::Up => ModifierKeys(self.0 & !bit),
+        }
+    }
+
+    /// Whether either Shift key is held down.
+    pub fn shift(self) -> bool {
+        self.0 & (Self::LEFT_SHIFT | Self::RIGHT_SHIFT) != 0
+    }
+
+    /// Whether either Ctrl key is held down.
+    pub fn ctrl(self) -> bool {
+        self.0 & (Self::LEFT_CTRL | Self::RIGHT_CTRL) != 0
+    }
+
+    /// Whether either Alt key is held down, the right one being AltGr on some layouts.
+    pub fn alt(self) -> bool {
+        self.0 & (Self::LEFT_ALT | Self::RIGHT_ALT) != 0
+    }
+}
+
+/// A key pressed or released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The key, independent of the layout.
+    pub code: KeyCode,
+    /// Whether the key has been pressed or released.
+    pub state: KeyState,
+    /// The modifiers held down after the event, a Ctrl key pressed is already included.
+    pub modifiers: ModifierKeys,
+    /// The key decoded with the global [layout] on presses, `None` on releases and for the
+    /// modifiers themselves.
+    pub key: Option<DecodedKey>,
+}
+
+/// Decodes scancodes to [KeyEvent]s, keeping track of the modifiers.
+pub struct KeyEventDecoder {
+    keyboard: Keyboard<Configured, ScancodeSet1>,
+    modifiers: ModifierKeys,
+}
+
+impl KeyEventDecoder {
+    /// Start with all the keys released.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        KeyEventDecoder {
+            keyboard: decoder(),
+            modifiers: ModifierKeys::default(),
+        }
+    }
+
+    /// Feed a scancode, returns the event it completes if any. Invalid scancodes are dropped, as
+    /// are the prefixes of the extended keys.
+    pub fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let (code, state) = (event.code, event.state);
+        self.modifiers = self.modifiers.update(code, state);
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers,
+            key: self.keyboard.process_keyevent(event),
+        })
+    }
+}
+
+/// A stream of every key pressed or released on the keyboard, for the consumers reacting to the
+/// modifiers or to releases, e.g. hotkeys.
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+    decoder: KeyEventDecoder,
+}
+
+impl KeyEventStream {
+    /// Create the [KeyEventStream] on top of the [ScancodeStream], only one of them can be created,
+    /// see [ScancodeStream::new].
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        KeyEventStream {
+            scancodes: ScancodeStream::new(),
+            decoder: KeyEventDecoder::new(),
+        }
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // both fields are Unpin
+        let this = &mut *self;
+        while let Poll::Ready(scancode) = Pin::new(&mut this.scancodes).poll_next(cx) {
+            match scancode {
+                Some(scancode) => {
+                    if let Some(event) = this.decoder.add_byte(scancode) {
+                        return Poll::Ready(Some(event));
+                    }
                 }
+                None => return Poll::Ready(None),
             }
         }
+        Poll::Pending
     }
 }
 
@@ -100,3 +430,115 @@ impl Stream for ScancodeStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(scancodes: &[u8]) -> bool {
+        let mut held = 0;
+        scancodes.iter().any(|&scancode| {
+            let (next, pressed) = track_hotkey(held, scancode);
+            held = next;
+            pressed
+        })
+    }
+
+    /// Decode the key pressed by `scancodes` with a fresh decoder.
+    fn decode(scancodes: &[u8]) -> Option<DecodedKey> {
+        let mut keyboard = decoder();
+        scancodes
+            .iter()
+            .filter_map(|&scancode| match keyboard.add_byte(scancode) {
+                Ok(Some(event)) => keyboard.process_keyevent(event),
+                _ => None,
+            })
+            .last()
+    }
+
+    #[test_case]
+    fn layouts_switched() {
+        for layout in Layout::ALL.iter().copied() {
+            assert_eq!(Layout::from_name(layout.name()), Some(layout));
+        }
+        assert_eq!(Layout::from_name("qwertz"), None);
+
+        // the key right of Tab
+        let q = [0x10];
+        let expected = [
+            (Layout::Us, 'q'),
+            (Layout::Uk, 'q'),
+            (Layout::Dvorak, '\''),
+            (Layout::Azerty, 'a'),
+        ];
+        for &(selected, c) in expected.iter() {
+            set_layout(selected);
+            assert_eq!(layout(), selected);
+            assert_eq!(decode(&q), Some(DecodedKey::Unicode(c)));
+        }
+        set_layout(Layout::Us);
+    }
+
+    #[test_case]
+    fn control_characters_mapped() {
+        // Ctrl pressed, then C
+        let ctrl_c = [0x1d, 0x2e];
+        assert_eq!(decode(&ctrl_c), Some(DecodedKey::Unicode('c')));
+        set_control_handling(HandleControl::MapLettersToUnicode);
+        assert_eq!(control_handling(), HandleControl::MapLettersToUnicode);
+        assert_eq!(decode(&ctrl_c), Some(DecodedKey::Unicode('\u{3}')));
+        set_control_handling(HandleControl::Ignore);
+    }
+
+    #[test_case]
+    fn key_events_decoded() {
+        let mut decoder = KeyEventDecoder::new();
+        let mut feed = |scancodes: &[u8]| {
+            scancodes
+                .iter()
+                .filter_map(|&scancode| decoder.add_byte(scancode))
+                .last()
+                .unwrap()
+        };
+
+        // left Ctrl, then right Ctrl after an 0xe0 prefix
+        let event = feed(&[0x1d, 0xe0, 0x1d]);
+        assert_eq!(event.code, KeyCode::ControlRight);
+        assert_eq!(event.state, KeyState::Down);
+        assert_eq!(event.key, None);
+        let event = feed(&[0x9d, 0x2e]);
+        assert_eq!(event.code, KeyCode::C);
+        assert!(event.modifiers.ctrl() && !event.modifiers.shift() && !event.modifiers.alt());
+        assert_eq!(event.key, Some(DecodedKey::Unicode('c')));
+
+        let event = feed(&[0xae]);
+        assert_eq!(
+            (event.code, event.state, event.key),
+            (KeyCode::C, KeyState::Up, None)
+        );
+        let event = feed(&[0xe0, 0x9d]);
+        assert_eq!(event.modifiers, ModifierKeys::default());
+    }
+
+    #[test_case]
+    fn shift_tracked() {
+        let held = [0x2a, 0x36, 0xaa]
+            .iter()
+            .fold(0, |held, &s| track_shift(held, s));
+        assert_eq!(held, MODIFIER_RIGHT_SHIFT);
+        assert_eq!(track_shift(held, 0xb6), 0);
+        // the scancodes of other keys leave the Shift keys alone
+        assert_eq!(track_shift(held, 0x49), held);
+    }
+
+    #[test_case]
+    fn ctrl_alt_del() {
+        assert!(feed(&[0x1d, 0x38, 0xe0, 0x53]));
+        // right Ctrl and right Alt, keypad Delete
+        assert!(feed(&[0xe0, 0x1d, 0xe0, 0x38, 0x53]));
+        // Ctrl released before Delete
+        assert!(!feed(&[0x1d, 0x38, 0x9d, 0xe0, 0x53]));
+        assert!(!feed(&[0x1d, 0xe0, 0x53]));
+        assert!(!feed(&[0x38, 0xe0, 0x53]));
+    }
+}
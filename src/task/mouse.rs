@@ -0,0 +1,250 @@
+//! The PS/2 mouse on the second port of the i8042 controller, read asynchronously.
+//!
+//! The mouse sends a packet of 3 bytes on each movement or change of its buttons, the bytes are
+//! queued by the interrupt handler of IRQ 12 and assembled into [MouseEvent]s by [MouseStream].
+//! [track_pointer] moves a pointer over the VGA text buffer along.
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{task::AtomicWaker, Stream, StreamExt};
+
+use crate::{
+    i8042::{self, I8042Error, Port},
+    metrics::Counter,
+    vga_buffer,
+};
+
+/// Restore the sample rate, the resolution and the scaling of the mouse, with reporting disabled.
+const COMMAND_SET_DEFAULTS: u8 = 0xf6;
+/// Start sending a packet on each movement.
+const COMMAND_ENABLE_REPORTING: u8 = 0xf4;
+
+/// Bits of the first byte of a packet.
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+/// Always set, a first byte without it is out of sync.
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// The movement in counts moving the pointer by a column and by a row, about the aspect ratio of
+/// a character cell.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+/// A few packets, the bytes of a packet arrive in quick succession.
+const QUEUE_SIZE: usize = 96;
+static DROPPED_BYTES: Counter = Counter::new("mouse.dropped_bytes");
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A packet of the mouse, the movement since the previous packet and the buttons held down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// The movement to the right in counts, negative to the left.
+    pub dx: i16,
+    /// The movement up in counts, negative down.
+    pub dy: i16,
+    /// Whether the left button is held down.
+    pub left: bool,
+    /// Whether the right button is held down.
+    pub right: bool,
+    /// Whether the middle button is held down.
+    pub middle: bool,
+}
+
+impl MouseEvent {
+    /// Decode a packet, a movement overflowing the 9 bits of the packet is dropped.
+    fn decode(packet: [u8; 3]) -> Self {
+        let [flags, x, y] = packet;
+        let movement = |value: u8, sign: u8, overflow: u8| {
+            if flags & overflow != 0 {
+                0
+            } else if flags & sign != 0 {
+                i16::from(value) - 0x100
+            } else {
+                i16::from(value)
+            }
+        };
+
+        MouseEvent {
+            dx: movement(x, PACKET_X_SIGN, PACKET_X_OVERFLOW),
+            dy: movement(y, PACKET_Y_SIGN, PACKET_Y_OVERFLOW),
+            left: flags & PACKET_LEFT != 0,
+            right: flags & PACKET_RIGHT != 0,
+            middle: flags & PACKET_MIDDLE != 0,
+        }
+    }
+}
+
+/// Assembles the bytes of the mouse into packets.
+#[derive(Debug, Default)]
+struct Packets {
+    packet: [u8; 3],
+    len: usize,
+}
+
+impl Packets {
+    /// Feed a byte, returns the event once a packet is complete. Bytes are skipped until one
+    /// looks like the first byte of a packet, e.g. after bytes lost to a full queue.
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet.len() {
+            return None;
+        }
+        self.len = 0;
+        Some(MouseEvent::decode(self.packet))
+    }
+}
+
+/// Restore the defaults of the mouse and enable its packets, the bytes received are queued for
+/// the [MouseStream]. Must be called once after [i8042::init].
+pub fn init() -> Result<(), I8042Error> {
+    i8042::set_handler(Port::Second, add_byte);
+    i8042::send(Port::Second, &[COMMAND_SET_DEFAULTS], &mut [])?;
+    i8042::send(Port::Second, &[COMMAND_ENABLE_REPORTING], &mut [])?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether [init] succeeded.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn add_byte(byte: u8) {
+    // the mouse moves whether or not a task reads it, the bytes are dropped until one does
+    let queue = match BYTE_QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return,
+    };
+
+    if queue.push(byte).is_err() {
+        DROPPED_BYTES.inc();
+        return;
+    }
+
+    WAKER.wake();
+}
+
+/// A stream of the packets of the mouse, produced asynchronously by hardware interrupts.
+pub struct MouseStream {
+    packets: Packets,
+}
+
+impl MouseStream {
+    /// Create the [MouseStream]. Creating more than one [MouseStream] causes kernel panic.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(QUEUE_SIZE))
+            .expect("MouseStream::new should only be called once");
+
+        MouseStream {
+            packets: Packets::default(),
+        }
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = BYTE_QUEUE.try_get().expect("BYTE_QUEUE not initialized");
+
+        loop {
+            while let Some(byte) = queue.pop() {
+                if let Some(event) = self.packets.add_byte(byte) {
+                    return Poll::Ready(Some(event));
+                }
+            }
+
+            // the same second check as [super::keyboard::ScancodeStream], the interrupt handler may
+            // have queued a byte after the queue has been emptied
+            WAKER.register(&cx.waker());
+            if queue.is_empty() {
+                return Poll::Pending;
+            }
+            WAKER.take();
+        }
+    }
+}
+
+/// Move a pointer over the VGA text buffer with the mouse, starting at the center of the screen.
+/// Can only be called once, see [MouseStream::new].
+pub async fn track_pointer() {
+    let (rows, columns) = vga_buffer::size();
+    let max_x = columns as i32 * COUNTS_PER_COLUMN - 1;
+    let max_y = rows as i32 * COUNTS_PER_ROW - 1;
+    let (mut x, mut y) = (max_x / 2, max_y / 2);
+
+    let mut events = MouseStream::new();
+    loop {
+        let (column, row) = (x / COUNTS_PER_COLUMN, y / COUNTS_PER_ROW);
+        vga_buffer::set_mouse_pointer(Some((row as usize, column as usize)));
+
+        let event = match events.next().await {
+            Some(event) => event,
+            None => break,
+        };
+        x = (x + i32::from(event.dx)).max(0).min(max_x);
+        // the rows count down the screen
+        y = (y - i32::from(event.dy)).max(0).min(max_y);
+    }
+
+    vga_buffer::set_mouse_pointer(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn mouse_enabled() {
+        assert!(is_enabled());
+    }
+
+    #[test_case]
+    fn packets_decoded() {
+        let mut packets = Packets::default();
+        // a byte without the always set bit is skipped to resynchronize
+        let events: Vec<_> = [0x00, 0x09, 0x05, 0x03]
+            .iter()
+            .map(|&byte| packets.add_byte(byte))
+            .collect();
+        assert_eq!(events[..3], [None, None, None]);
+        assert_eq!(
+            events[3],
+            Some(MouseEvent {
+                dx: 5,
+                dy: 3,
+                left: true,
+                right: false,
+                middle: false,
+            })
+        );
+
+        // left and down, with the right and the middle buttons held down
+        let event = MouseEvent::decode([0x3e, 0xfe, 0x80]);
+        assert_eq!((event.dx, event.dy), (-2, -128));
+        assert!(!event.left && event.right && event.middle);
+        // an overflowing movement is dropped
+        let event = MouseEvent::decode([0x48, 0xff, 0x01]);
+        assert_eq!((event.dx, event.dy), (0, 1));
+    }
+}
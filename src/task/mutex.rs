@@ -0,0 +1,132 @@
+//! A mutex for tasks, held across await points.
+//!
+//! A spin lock held across an await point deadlocks the cooperative executor as soon as another
+//! task tries to take it: the task spinning never yields to the task holding the lock. Tasks
+//! waiting on an [AsyncMutex] are suspended instead and woken when it's released.
+
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+/// A mutual exclusion primitive whose [AsyncMutex::lock] suspends the task until the lock is free.
+pub struct AsyncMutex<T> {
+    locked: AtomicBool,
+    /// The tasks waiting for the lock, all woken when it's released.
+    waiters: Mutex<Vec<Waker>>,
+    data: UnsafeCell<T>,
+}
+
+// # Safety
+// The data is only accessed through a guard, of which there is at most one at a time.
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    /// Create an unlocked [AsyncMutex] protecting `data`.
+    pub const fn new(data: T) -> Self {
+        AsyncMutex {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Take the lock without waiting, `None` if it's held.
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(AsyncMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Take the lock, waiting for it to be released if it's held.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock { mutex: self }
+    }
+}
+
+/// The future returned by [AsyncMutex::lock].
+pub struct Lock<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+
+        self.mutex.waiters.lock().push(cx.waker().clone());
+
+        // the lock may have been released after the first attempt
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Access to the data of a locked [AsyncMutex], the lock is released when the guard is dropped.
+pub struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<T> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // # Safety
+        // The guard holds the lock.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // # Safety
+        // The guard holds the lock.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        // the waiters race for the lock, the losers register again
+        let waiters = core::mem::take(&mut *self.mutex.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+
+    #[test_case]
+    fn exclusive_until_dropped() {
+        let mutex = AsyncMutex::new(0);
+        let mut guard = block_on(mutex.lock());
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert_eq!(*mutex.try_lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,108 @@
+//! Asynchronous serial input handling.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{task::AtomicWaker, Stream};
+
+use crate::{metrics::Counter, warn};
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+const QUEUE_SIZE: usize = 100;
+static DROPPED_BYTES: Counter = Counter::new("serial.dropped_bytes");
+
+pub(crate) fn add_byte(byte: u8) {
+    let queue = match BYTE_QUEUE.try_get() {
+        Ok(queue) => queue,
+        // nobody is listening to the serial port, the input is simply discarded
+        Err(_) => return,
+    };
+
+    if queue.push(byte).is_err() {
+        DROPPED_BYTES.inc();
+        warn!("serial input queue full; dropping serial input");
+        return;
+    }
+
+    WAKER.wake();
+}
+
+/// Discard the bytes received but not yet consumed, returns the number of discarded bytes.
+pub fn drain_bytes() -> usize {
+    match BYTE_QUEUE.try_get() {
+        Ok(queue) => core::iter::from_fn(|| queue.pop()).count(),
+        Err(_) => 0,
+    }
+}
+
+/// A stream of bytes received from the first serial port, produced asynchronously by hardware
+/// interrupts.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    /// Create the [SerialStream]. Creating more than one [SerialStream] this way causes kernel
+    /// panic.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(QUEUE_SIZE))
+            .expect("SerialStream::new should only be called once");
+        // the receive interrupt is enabled on the initialization of the serial port
+        lazy_static::initialize(&crate::serial::SERIAL1);
+
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = BYTE_QUEUE.try_get().expect("BYTE_QUEUE not initialized");
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+
+        // same as the keyboard, the interrupt handler may have filled the queue after the first
+        // check
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+    use futures_util::StreamExt;
+
+    #[test_case]
+    fn bytes_streamed_and_drained() {
+        // no shell reads the serial port in the tests, this is the only stream
+        let mut bytes = SerialStream::new();
+        for &byte in b"abc" {
+            add_byte(byte);
+        }
+        assert_eq!(block_on(bytes.next()), Some(b'a'));
+        assert_eq!(drain_bytes(), 2);
+        assert_eq!(drain_bytes(), 0);
+
+        add_byte(b'd');
+        assert_eq!(block_on(bytes.next()), Some(b'd'));
+    }
+}
@@ -0,0 +1,121 @@
+//! Synchronization between tasks that suspends the waiting task instead of spinning.
+//!
+//! A spin lock or a flag polled in a loop never lets the cooperative executor run the task that
+//! would release it. The primitives here return futures instead: [AsyncMutex] for data shared
+//! across await points, [Notify] for an event and [oneshot] for a single value. [Notify] and
+//! [oneshot] are lock-free, they can be signalled from interrupt handlers.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::task::AtomicWaker;
+
+pub use super::mutex::{AsyncMutex, AsyncMutexGuard};
+
+pub mod oneshot;
+
+/// An event a single task waits for, [Notify::notify] wakes the task waiting in
+/// [Notify::notified]. A notification without a task waiting is kept for the next wait, further
+/// notifications are merged into it.
+pub struct Notify {
+    notified: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Notify {
+    /// Create a [Notify] without a pending notification.
+    pub const fn new() -> Self {
+        Notify {
+            notified: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Wake the task waiting for the event, or let its next wait return at once.
+    pub fn notify(&self) {
+        self.notified.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Wait for a notification, consuming it. Only one task may wait at a time, a second one
+    /// replaces the waker of the first.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The future returned by [Notify::notified].
+pub struct Notified<'a> {
+    notify: &'a Notify,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let notify = self.notify;
+        if notify.notified.swap(false, Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+
+        notify.waker.register(cx.waker());
+
+        // the same second check as [super::keyboard::ScancodeStream], the notification may have
+        // come after the first check
+        if notify.notified.swap(false, Ordering::Acquire) {
+            notify.waker.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{block_on, executor::Executor, Task};
+    use alloc::sync::Arc;
+
+    #[test_case]
+    fn notifications_merged() {
+        let notify = Notify::new();
+        notify.notify();
+        notify.notify();
+        block_on(notify.notified());
+        assert!(!notify.notified.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn waiter_woken() {
+        let notify = Arc::new(Notify::new());
+        let mutex = Arc::new(AsyncMutex::new(0));
+        let mut executor = Executor::new();
+
+        let (waiter_notify, waiter_mutex) = (Arc::clone(&notify), Arc::clone(&mutex));
+        let (task, waiter) = Task::with_handle("waiter", async move {
+            waiter_notify.notified().await;
+            *waiter_mutex.lock().await
+        });
+        executor.spawn(task);
+        executor.spawn(Task::named("notifier", async move {
+            // the waiter is woken while the lock is still held
+            let mut value = mutex.lock().await;
+            notify.notify();
+            *value = 42;
+        }));
+
+        executor.run_until_idle();
+        assert_eq!(block_on(waiter), Ok(42));
+    }
+}
@@ -0,0 +1,185 @@
+//! A channel carrying a single value from a [Sender] to a [Receiver].
+//!
+//! The value is handed over without a lock: the sender writes it once and publishes it with a
+//! flag, the receiver, the only reader, takes it once the flag is set.
+
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::task::AtomicWaker;
+
+/// Create a channel, the [Receiver] resolves to the value sent by the [Sender].
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: UnsafeCell::new(None),
+        sent: AtomicBool::new(false),
+        sender_dropped: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Returned by the [Receiver] when the [Sender] is dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+struct Shared<T> {
+    /// Written by the sender before `sent` is set, taken by the receiver after.
+    value: UnsafeCell<Option<T>>,
+    sent: AtomicBool,
+    sender_dropped: AtomicBool,
+    receiver_dropped: AtomicBool,
+    /// The waker of the task awaiting the [Receiver].
+    waker: AtomicWaker,
+}
+
+// # Safety
+// `value` is written once by the only sender before `sent` is set with release ordering, and only
+// read by the only receiver after `sent` is cleared with acquire ordering.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    /// Take the value if it has been sent, called by the receiver only.
+    fn take(&self) -> Option<T> {
+        if self.sent.swap(false, Ordering::Acquire) {
+            // # Safety
+            // The sender has finished writing the value, the receiver is the only one to read it.
+            unsafe { (*self.value.get()).take() }
+        } else {
+            None
+        }
+    }
+}
+
+/// The sending half of a channel.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value` to the [Receiver], the value is given back if the receiver is dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.shared.receiver_dropped.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        // # Safety
+        // The sender is consumed, the value is written once and the receiver doesn't read it
+        // before `sent` is set.
+        unsafe {
+            *self.shared.value.get() = Some(value);
+        }
+        self.shared.sent.store(true, Ordering::Release);
+        // the receiver is woken as the sender is dropped
+        Ok(())
+    }
+
+    /// Whether the [Receiver] has been dropped, a value sent would be given back.
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_dropped.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.sender_dropped.store(true, Ordering::Release);
+        self.shared.waker.wake();
+    }
+}
+
+/// The receiving half of a channel, a future resolving to the value sent.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The value if it has been sent, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.take()
+    }
+
+    /// Resolve the poll if the value has been sent or the sender is gone.
+    fn check(&self) -> Option<Result<T, RecvError>> {
+        // the value is sent before the sender is dropped, it's seen once the drop is
+        let dropped = self.shared.sender_dropped.load(Ordering::Acquire);
+        match self.shared.take() {
+            Some(value) => Some(Ok(value)),
+            None if dropped => Some(Err(RecvError)),
+            None => None,
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.check() {
+            return Poll::Ready(result);
+        }
+
+        self.shared.waker.register(cx.waker());
+
+        match self.check() {
+            Some(result) => {
+                self.shared.waker.take();
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+    use alloc::boxed::Box;
+
+    #[test_case]
+    fn value_received() {
+        let (sender, mut receiver) = channel();
+        assert_eq!(receiver.try_recv(), None);
+        sender.send(Box::new(42)).unwrap();
+        assert_eq!(block_on(receiver), Ok(Box::new(42)));
+    }
+
+    #[test_case]
+    fn closed_halves_reported() {
+        let (sender, receiver) = channel::<u8>();
+        drop(sender);
+        assert_eq!(block_on(receiver), Err(RecvError));
+
+        let (sender, receiver) = channel();
+        assert!(!sender.is_closed());
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(7), Err(7));
+    }
+}
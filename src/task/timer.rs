@@ -0,0 +1,107 @@
+//! Asynchronous waits for the timer tick.
+//!
+//! The timer interrupt handlers count the ticks of the monotonic clock, see [time::ticks]. Tasks
+//! wait for a tick with the futures of this module instead of polling the clock: each one registers
+//! its waker on the timer [wheel], which the executor expires between polls. [sleep] and
+//! [sleep_until] are ready once, an [Interval] is a stream ready once every period.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::Stream;
+
+use crate::time::{self, wheel::Delay};
+
+/// A future ready once `duration` has passed, rounded up to the next timer tick.
+pub fn sleep(duration: Duration) -> Delay {
+    Delay::new(duration)
+}
+
+/// A future ready once [time::ticks] reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Delay {
+    Delay::until(deadline)
+}
+
+/// A stream of the ticks one `period` apart, see [interval].
+pub struct Interval {
+    period: u64,
+    delay: Delay,
+}
+
+/// A stream yielding the tick every `period` from now on, the first one a period from now. Ticks
+/// missed by a late consumer are skipped rather than yielded in a burst. A period shorter than a
+/// tick is rounded up to a tick.
+pub fn interval(period: Duration) -> Interval {
+    let period = time::duration_to_ticks(period).max(1);
+    Interval {
+        period,
+        delay: Delay::until(time::ticks() + period),
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u64>> {
+        if Pin::new(&mut self.delay).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let tick = self.delay.deadline();
+        let now = time::ticks();
+        let missed = (now - tick) / self.period;
+        self.delay = Delay::until(tick + (missed + 1) * self.period);
+        Poll::Ready(Some(tick))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        task::block_on,
+        testing::keep_allocations,
+        time::{mock, Instant},
+    };
+    use futures_util::task::noop_waker_ref;
+
+    fn poll_next(interval: &mut Interval) -> Poll<Option<u64>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        // the slots of the wheel keep their capacity
+        keep_allocations(|| Pin::new(interval).poll_next(&mut cx))
+    }
+
+    #[test_case]
+    fn sleep_lasts_its_duration() {
+        let start = Instant::now();
+        keep_allocations(|| block_on(sleep(Duration::from_millis(5))));
+        assert!(start.elapsed() >= Duration::from_millis(5));
+
+        let deadline = time::ticks() + 2;
+        keep_allocations(|| block_on(sleep_until(deadline)));
+        assert!(time::ticks() >= deadline);
+    }
+
+    #[test_case]
+    fn interval_skips_missed_ticks() {
+        mock::enable();
+        let start = time::ticks();
+        let mut ticks = interval(Duration::from_millis(10));
+        let period = time::duration_to_ticks(Duration::from_millis(10));
+        assert_eq!(poll_next(&mut ticks), Poll::Pending);
+
+        mock::advance_ticks(period);
+        assert_eq!(poll_next(&mut ticks), Poll::Ready(Some(start + period)));
+        assert_eq!(poll_next(&mut ticks), Poll::Pending);
+
+        mock::advance_ticks(3 * period + 1);
+        assert_eq!(poll_next(&mut ticks), Poll::Ready(Some(start + 2 * period)));
+        assert_eq!(poll_next(&mut ticks), Poll::Pending);
+        mock::advance_ticks(period - 1);
+        assert_eq!(poll_next(&mut ticks), Poll::Ready(Some(start + 5 * period)));
+    }
+}
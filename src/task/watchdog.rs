@@ -0,0 +1,90 @@
+//! A watchdog detecting a wedged executor.
+//!
+//! The executor bumps a heartbeat counter on each iteration of its loop, which happens at least
+//! once per timer interrupt even when idle. The timer interrupt handler checks the counter by
+//! [check], a heartbeat stalled for longer than the timeout means a task never returns from its
+//! poll, or an interrupt storm starves the executor.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::time;
+
+/// The default timeout of the watchdog in seconds.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+/// Timeout in timer ticks, 0 if the watchdog is disarmed.
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+/// The heartbeat seen by the last check and the number of ticks it has stayed the same. Only
+/// accessed by the timer interrupt handler, atomics are used for interior mutability.
+static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+static STALLED_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Set once a stall is reported, so that each stall is only reported once.
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+/// Signal that the executor is making progress.
+pub fn heartbeat() {
+    HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Arm the watchdog to fire when the heartbeat stalls for `timeout_secs` seconds.
+pub fn arm(timeout_secs: u64) {
+    let ticks = time::duration_to_ticks(Duration::from_secs(timeout_secs)).max(1);
+    STALLED_TICKS.store(0, Ordering::Relaxed);
+    FIRED.store(false, Ordering::Relaxed);
+    TIMEOUT_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+/// Disarm the watchdog.
+pub fn disarm() {
+    TIMEOUT_TICKS.store(0, Ordering::Relaxed);
+}
+
+/// Check the heartbeat on a timer tick. Returns the number of ticks the heartbeat has stalled for
+/// when the timeout is first exceeded, the caller is then expected to report the stall.
+pub(crate) fn check() -> Option<u64> {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return None;
+    }
+
+    let heartbeat = HEARTBEAT.load(Ordering::Relaxed);
+    if LAST_HEARTBEAT.swap(heartbeat, Ordering::Relaxed) != heartbeat {
+        STALLED_TICKS.store(0, Ordering::Relaxed);
+        // the executor recovered, report the next stall again
+        FIRED.store(false, Ordering::Relaxed);
+        return None;
+    }
+
+    let stalled = STALLED_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled >= timeout && !FIRED.swap(true, Ordering::Relaxed) {
+        Some(stalled)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::instructions::interrupts;
+
+    #[test_case]
+    fn fire_once_on_stall() {
+        // the timer interrupt handler checks the heartbeat as well
+        interrupts::without_interrupts(|| {
+            arm(0);
+            heartbeat();
+            assert_eq!(check(), None);
+            assert_eq!(check(), Some(1));
+            assert_eq!(check(), None);
+            heartbeat();
+            assert_eq!(check(), None);
+            assert_eq!(check(), Some(1));
+            disarm();
+        });
+    }
+}
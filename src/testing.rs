@@ -0,0 +1,547 @@
+//! The custom test framework shared by the unit tests and the integration tests.
+//!
+//! Tests run sequentially in the order they're collected, each printing its name and result to the
+//! first serial port. Global state is not reset between tests, instead each test runs between the
+//! setups and teardowns of its [fixtures](fixture) which reset the screen and the keyboard input
+//! and fail tests corrupting or leaking heap memory. Each test is bounded by a deadline checked on
+//! every timer tick, a test still running past its deadline is reported with a backtrace. A test
+//! spinning with interrupts disabled can't be interrupted and still hangs the run. The `test=`
+//! option of the kernel command line runs only the tests with names containing its value, the
+//! `test_output=` option chooses how much is printed, see [Verbosity] and [crate::cmdline].
+//!
+//! A failed or timed out test doesn't stop the run: the panic handler or the timer interrupt
+//! handler jumps back to the runner, which goes on with the next test and summarizes the failures
+//! at the end. The frames of the abandoned test are discarded without running destructors, locks
+//! it held are never released and may fail the following tests.
+//!
+//! The last line before QEMU exits is a trailer of space separated fields for the host, followed
+//! by the counts of the tests if the runner got to the end of the run:
+//!
+//! ```text
+//! result status=<status> exit_code=<hex> [passed=<n> failed=<n> timed_out=<n> heap_corrupted=<n> skipped=<n>]
+//! ```
+//!
+//! The status is the [name](QemuExitCode::name) of the exit code, the most severe failure of the
+//! run. Failed tests also count in `failed` when they timed out or corrupted the heap.
+//!
+//! Benchmarks are collected and run like tests, see [Bench]. Tests of fault paths raise CPU
+//! exceptions on purpose with [catch_exception] and its variants. Tests of state surviving a
+//! reboot run over several boots, see [stage].
+//!
+//! Tests expected to panic are declared with [should_panic!](crate::should_panic), the panic
+//! handler resumes the test itself after the panic, see [ShouldPanic]:
+//!
+//! ```ignore
+//! #[test_case]
+//! const DOUBLE_FREE: ShouldPanic = should_panic!(double_free);
+//! ```
+
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use futures_util::{future::poll_fn, pin_mut};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    cmdline, crash, exit_qemu, force_unlock_outputs, interrupts, klog, metrics, println, process,
+    serial_print, serial_println, smp, task, time, unwind, QemuExitCode,
+};
+
+pub use self::{
+    bench::{Bench, BenchResult},
+    exception::{
+        catch_exception, expect_exception, expect_general_protection_fault, expect_page_fault,
+        Exception,
+    },
+    fixture::{keep_allocations, TestFixture},
+    rng::{seed, Rng},
+};
+
+mod bench;
+mod exception;
+pub mod fixture;
+mod rng;
+pub mod stage;
+
+use crate::resume::ResumePoint;
+
+/// The time a test may run for before it's considered hung.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The tick at which the running test times out, [NO_DEADLINE] between tests.
+static DEADLINE: AtomicU64 = AtomicU64::new(NO_DEADLINE);
+/// The tick at which the running test started.
+static STARTED: AtomicU64 = AtomicU64::new(0);
+const NO_DEADLINE: u64 = u64::MAX;
+
+/// A test collected by the test framework.
+pub trait Testable {
+    /// Run the test, panics if the test fails.
+    fn run(&self);
+
+    /// The name of the test.
+    fn name(&self) -> &'static str;
+
+    /// The time the test may run for.
+    fn timeout(&self) -> Duration {
+        DEFAULT_TIMEOUT
+    }
+
+    /// The fixtures run around the test, setups in order and teardowns in reverse order.
+    fn fixtures(&self) -> &'static [TestFixture] {
+        fixture::DEFAULT
+    }
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        self();
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// A test passing only if it panics, the frames of the panicking test are discarded without
+/// running destructors. Created by [should_panic!](crate::should_panic).
+pub struct ShouldPanic {
+    name: &'static str,
+    test: fn(),
+}
+
+impl ShouldPanic {
+    /// A test named `name` expected to panic.
+    pub const fn new(name: &'static str, test: fn()) -> Self {
+        Self { name, test }
+    }
+}
+
+impl Testable for ShouldPanic {
+    fn run(&self) {
+        if !SHOULD_PANIC.catch(&self.test) {
+            panic!("test did not panic");
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn fixtures(&self) -> &'static [TestFixture] {
+        fixture::NO_LEAK_CHECK
+    }
+}
+
+/// Declare a test expected to panic, e.g. `#[test_case] const X: ShouldPanic = should_panic!(f);`
+/// where `f` is a `fn()`.
+#[macro_export]
+macro_rules! should_panic {
+    ($test:ident) => {
+        $crate::testing::ShouldPanic::new(concat!(module_path!(), "::", stringify!($test)), $test)
+    };
+}
+
+/// Drive the processes on the current stack until `future` completes, returns its output. For the
+/// tests waiting for processes, the task of [process::run] isn't polled in the meantime. The
+/// processes spawned meanwhile start on the next interrupt.
+pub fn run_processes_until<F: Future>(future: F) -> F::Output {
+    pin_mut!(future);
+    let output = task::block_on(poll_fn(|cx| {
+        process::poll_processes(cx);
+        future.as_mut().poll(cx)
+    }));
+    // the processes hold the waker of this call, the task of [process::run] takes them back
+    process::wake_run();
+    output
+}
+
+/// Whether a test named `name` passes the filter, tests pass if there is no filter.
+fn matches(filter: Option<&str>, name: &str) -> bool {
+    filter.map_or(true, |filter| name.contains(filter))
+}
+
+/// Number of failed tests listed by name at the end of a run.
+const MAX_LISTED_FAILURES: usize = 16;
+
+/// How the running test failed, [QemuExitCode::Success] while it hasn't.
+static FAILURE: AtomicU32 = AtomicU32::new(QemuExitCode::Success as u32);
+
+/// Classify the failure of the running test as `code`, unless it already failed in a more severe
+/// way. Called right before the test panics or is abandoned.
+pub fn classify_failure(code: QemuExitCode) {
+    FAILURE.fetch_max(code as u32, Ordering::SeqCst);
+}
+
+/// How the running test failed.
+fn failure() -> QemuExitCode {
+    let code = FAILURE.load(Ordering::SeqCst);
+    [
+        QemuExitCode::Failed,
+        QemuExitCode::Panic,
+        QemuExitCode::Timeout,
+        QemuExitCode::HeapCorrupted,
+    ]
+    .iter()
+    .copied()
+    .find(|failure| *failure as u32 == code)
+    .unwrap_or(QemuExitCode::Success)
+}
+
+/// The counts of the tests of a run.
+#[derive(Debug, Default)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    timed_out: usize,
+    heap_corrupted: usize,
+    skipped: usize,
+}
+
+/// Print the trailer line and exit QEMU with `code`.
+fn exit_with_trailer(code: QemuExitCode, summary: Option<&Summary>) -> ! {
+    serial_print!("result status={} exit_code={:#x}", code.name(), code as u32);
+    if let Some(summary) = summary {
+        serial_print!(
+            " passed={} failed={} timed_out={} heap_corrupted={} skipped={}",
+            summary.passed,
+            summary.failed,
+            summary.timed_out,
+            summary.heap_corrupted,
+            summary.skipped
+        );
+    }
+    serial_println!();
+    exit_qemu(code);
+}
+
+/// The resume point of the running test, taken on a failure of the test.
+static TEST: ResumePoint = ResumePoint::new();
+/// The resume point of the running [ShouldPanic] test, taken on a panic of the test.
+static SHOULD_PANIC: ResumePoint = ResumePoint::new();
+
+/// How much the test runner prints, set by the `test_output=` option of the kernel command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only the failed tests and the summary of the run.
+    Quiet,
+    /// A line per test with its result.
+    Normal,
+    /// A line per test followed by the log records it emitted, with debug records enabled.
+    Verbose,
+}
+
+impl Verbosity {
+    /// The verbosity named `name` on the kernel command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quiet" => Some(Verbosity::Quiet),
+            "normal" => Some(Verbosity::Normal),
+            "verbose" => Some(Verbosity::Verbose),
+            _ => None,
+        }
+    }
+}
+
+fn verbosity() -> Verbosity {
+    cmdline::config().test_output
+}
+
+/// The name of the running test, printed late by quiet runs once the test failed.
+static RUNNING: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Print the name of the failed test if quiet runs haven't printed it yet, called before reporting
+/// a failure.
+fn announce_failure() {
+    if verbosity() != Verbosity::Quiet {
+        return;
+    }
+    // the runner never holds the lock while a test runs
+    if let Some(name) = RUNNING.try_lock().and_then(|name| *name) {
+        serial_print!("{}...\t", name);
+    }
+}
+
+/// Print the log records emitted since `seq`, indented under the line of the test.
+fn print_records_since(seq: u64) {
+    let count = klog::next_seq().saturating_sub(seq) as usize;
+    // a log record emitted by an interrupt handler would wait for the ring forever
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        klog::try_for_each_latest(count, |record| {
+            serial_println!(
+                "    [{:<5} {}] {}",
+                record.level,
+                record.target,
+                record.message()
+            );
+        });
+    });
+}
+
+/// Run a single test between the setups and teardowns of its fixtures, returns how it failed or
+/// [QemuExitCode::Success] if it passed.
+fn run_one(test: &dyn Testable) -> QemuExitCode {
+    let now = time::hardware_ticks();
+    let timeout = time::duration_to_ticks(test.timeout()).max(1);
+    STARTED.store(now, Ordering::Relaxed);
+    DEADLINE.store(now + timeout, Ordering::Relaxed);
+    *RUNNING.lock() = Some(test.name());
+    if verbosity() != Verbosity::Quiet {
+        serial_print!("{}...\t", test.name());
+    }
+    let first_record = klog::next_seq();
+    FAILURE.store(QemuExitCode::Success as u32, Ordering::SeqCst);
+
+    let failed = TEST.catch(&|| {
+        for fixture in test.fixtures() {
+            (fixture.setup)();
+        }
+        test.run();
+        for fixture in test.fixtures().iter().rev() {
+            (fixture.teardown)();
+        }
+    });
+
+    DEADLINE.store(NO_DEADLINE, Ordering::Relaxed);
+    *RUNNING.lock() = None;
+    if !failed && verbosity() != Verbosity::Quiet {
+        serial_println!("[ok]");
+    }
+    if verbosity() == Verbosity::Verbose {
+        print_records_since(first_record);
+    }
+    if failed {
+        failure().max(QemuExitCode::Failed)
+    } else {
+        QemuExitCode::Success
+    }
+}
+
+/// The sequential test runner. A failed test is reported and abandoned, the run goes on with the
+/// next test and fails at the end. Only the tests with names containing the `test=` option of the
+/// kernel command line are run, the skipped tests are listed at the end of the run.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let filter = cmdline::config().test_filter;
+    if verbosity() == Verbosity::Verbose {
+        klog::set_level(Some(klog::Level::Debug));
+    }
+    let selected = tests
+        .iter()
+        .filter(|test| matches(filter, test.name()))
+        .count();
+    if let Some(filter) = filter {
+        serial_println!(
+            "Running {} of {} tests matching \"{}\"",
+            selected,
+            tests.len(),
+            filter
+        );
+    } else {
+        serial_println!("Running {} tests", tests.len());
+    }
+
+    let mut failures = [""; MAX_LISTED_FAILURES];
+    let mut summary = Summary {
+        skipped: tests.len() - selected,
+        ..Summary::default()
+    };
+    let mut result = QemuExitCode::Success;
+    for test in tests.iter().filter(|test| matches(filter, test.name())) {
+        let outcome = run_one(*test);
+        match outcome {
+            QemuExitCode::Success => summary.passed += 1,
+            QemuExitCode::Timeout => summary.timed_out += 1,
+            QemuExitCode::HeapCorrupted => summary.heap_corrupted += 1,
+            _ => {}
+        }
+        if outcome != QemuExitCode::Success {
+            if summary.failed < MAX_LISTED_FAILURES {
+                failures[summary.failed] = test.name();
+            }
+            summary.failed += 1;
+        }
+        result = result.max(outcome);
+    }
+    let failed = summary.failed;
+
+    if selected < tests.len() && verbosity() != Verbosity::Quiet {
+        serial_println!("Skipped {} tests:", tests.len() - selected);
+        for test in tests.iter().filter(|test| !matches(filter, test.name())) {
+            serial_println!("    {}", test.name());
+        }
+    }
+
+    bench::report();
+    for sample in metrics::snapshot() {
+        serial_println!("metric {} {}", sample.name, sample.value);
+    }
+
+    if failed > 0 {
+        serial_println!("Failed {} tests:", failed);
+        for name in &failures[..failed.min(MAX_LISTED_FAILURES)] {
+            serial_println!("    {}", name);
+        }
+        if failed > MAX_LISTED_FAILURES {
+            serial_println!("    ...");
+        }
+    }
+    serial_println!(
+        "test result: {}. {} passed; {} failed; {} skipped",
+        if failed == 0 { "ok" } else { "FAILED" },
+        summary.passed,
+        failed,
+        summary.skipped
+    );
+
+    exit_with_trailer(result, Some(&summary));
+}
+
+/// The test panic handler. Reboot if [stage::reboot_on_panic] is armed, resume a [ShouldPanic]
+/// test if one is running, otherwise output panic info to both VGA text buffer in QEMU and host
+/// system, then resume the test runner with the next test or terminate QEMU process if no test is
+/// running. The tests run on the BSP, a panic on an AP halts the other processors and terminates
+/// QEMU.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let on_ap = smp::current() != 0;
+    if on_ap {
+        if !smp::claim_panic() {
+            smp::park();
+        }
+        smp::halt_others();
+    }
+    // # Safety
+    // This is a panic handler, the panicking code never returns.
+    unsafe {
+        force_unlock_outputs();
+    }
+    if !on_ap {
+        stage::reboot_if_armed(info);
+        // # Safety
+        // As above.
+        unsafe {
+            SHOULD_PANIC.resume();
+        }
+    }
+    classify_failure(QemuExitCode::Failed);
+    announce_failure();
+    println!("[failed]\n");
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    crash::report(info);
+    if !on_ap {
+        // # Safety
+        // As above.
+        unsafe {
+            TEST.resume();
+        }
+    }
+    exit_with_trailer(QemuExitCode::Panic, None);
+}
+
+/// Fail the running test if it's past its deadline, called by the timer interrupt handlers with the
+/// stack frame of the interrupted test and the vector of the interrupt.
+#[inline(always)]
+pub(crate) fn check_timeout(stack_frame: &InterruptStackFrame, vector: u8) {
+    let now = time::hardware_ticks();
+    if now < DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // # Safety
+    // The test never resumes, the runner goes on with the next test or QEMU exits below.
+    unsafe {
+        force_unlock_outputs();
+    }
+    let elapsed = time::ticks_to_duration(now - STARTED.load(Ordering::Relaxed));
+    announce_failure();
+    println!("[timeout]\n");
+    serial_println!("[timeout]\n");
+    serial_println!(
+        "Error: test still running after {} ms\n{}",
+        elapsed.as_millis(),
+        unwind::exception_backtrace(stack_frame)
+    );
+
+    classify_failure(QemuExitCode::Timeout);
+    // # Safety
+    // The handler never returns, the interrupt is acknowledged here instead. A [ShouldPanic] test
+    // is abandoned together with the test runner frames it's in.
+    unsafe {
+        interrupts::end_of_interrupt(vector);
+        SHOULD_PANIC.clear();
+        TEST.resume();
+    }
+    exit_with_trailer(QemuExitCode::Timeout, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn panics() {
+        panic!("expected panic");
+    }
+
+    #[test_case]
+    const PANIC_RESUMES: ShouldPanic = should_panic!(panics);
+
+    #[test_case]
+    fn catch_without_panic() {
+        let calls = core::cell::Cell::new(0);
+        assert!(!SHOULD_PANIC.catch(&|| calls.set(calls.get() + 1)));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test_case]
+    fn keep_allocations_not_leaked() {
+        use alloc::boxed::Box;
+
+        let kept: &'static mut u64 = keep_allocations(|| Box::leak(Box::new(1)));
+        assert_eq!(*kept, 1);
+    }
+
+    fn empty() {}
+
+    #[test_case]
+    fn bench_overhead_subtracted() {
+        let result = Bench::new("empty", 16, empty).measure();
+        assert_eq!(result.iterations, 16);
+        assert!(result.min <= result.median);
+        // an empty call takes a handful of cycles at most once the TSC reads are subtracted
+        assert!(result.min < 1000);
+    }
+
+    #[test_case]
+    fn filter_by_name() {
+        assert!(matches(None, "rust_kernel::allocator::tests::alloc"));
+        assert!(matches(
+            Some("alloc"),
+            "rust_kernel::allocator::tests::alloc"
+        ));
+        assert!(!matches(
+            Some("heap"),
+            "rust_kernel::allocator::tests::alloc"
+        ));
+    }
+
+    #[test_case]
+    fn most_severe_failure_kept() {
+        classify_failure(QemuExitCode::Timeout);
+        classify_failure(QemuExitCode::Failed);
+        assert_eq!(failure(), QemuExitCode::Timeout);
+        FAILURE.store(QemuExitCode::Success as u32, Ordering::SeqCst);
+        assert_eq!(QemuExitCode::Timeout.exit_status(), 0x27);
+    }
+
+    #[test_case]
+    fn deadline_armed() {
+        let deadline = DEADLINE.load(Ordering::Relaxed);
+        assert_ne!(deadline, NO_DEADLINE);
+        assert!(deadline > time::hardware_ticks());
+    }
+}
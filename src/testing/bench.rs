@@ -0,0 +1,137 @@
+//! Benchmarks run by the test framework.
+//!
+//! A benchmark is a `fn()` run for a number of iterations, each iteration timed in TSC cycles.
+//! Benchmarks are collected and run like tests, the results are printed after all the tests in
+//! lines of space separated fields:
+//!
+//! ```text
+//! bench <name> iterations=<n> min=<cycles> median=<cycles>
+//! ```
+//!
+//! The cycles spent reading the TSC are subtracted from each sample. Interrupts are left enabled,
+//! a timer tick landing in an iteration inflates that sample but rarely the median.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::Testable;
+use crate::{serial_println, time::tsc};
+
+/// Number of benchmark results kept for the report, the results of further benchmarks are
+/// dropped.
+const MAX_RESULTS: usize = 32;
+/// Number of untimed iterations run before the timed ones, warming up caches and lazy state.
+const WARMUP_ITERATIONS: usize = 32;
+
+/// The summary of the samples of a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    /// The name of the benchmark.
+    pub name: &'static str,
+    /// Number of timed iterations.
+    pub iterations: usize,
+    /// The fastest iteration in cycles.
+    pub min: u64,
+    /// The median iteration in cycles.
+    pub median: u64,
+}
+
+static RESULTS: Mutex<([Option<BenchResult>; MAX_RESULTS], usize)> =
+    Mutex::new(([None; MAX_RESULTS], 0));
+
+/// A benchmark collected by the test framework. Created by [bench!](crate::bench).
+pub struct Bench {
+    name: &'static str,
+    iterations: usize,
+    f: fn(),
+}
+
+impl Bench {
+    /// A benchmark named `name` timing `iterations` calls of `f`.
+    pub const fn new(name: &'static str, iterations: usize, f: fn()) -> Self {
+        Self {
+            name,
+            iterations,
+            f,
+        }
+    }
+
+    /// Run the benchmark, returns the summary of the samples.
+    pub fn measure(&self) -> BenchResult {
+        assert!(self.iterations > 0, "benchmark without iterations");
+
+        let overhead = (0..WARMUP_ITERATIONS)
+            .map(|_| {
+                let start = tsc::rdtsc_ordered();
+                tsc::rdtsc_ordered().wrapping_sub(start)
+            })
+            .min()
+            .unwrap_or(0);
+
+        for _ in 0..WARMUP_ITERATIONS {
+            (self.f)();
+        }
+
+        // allocated before timing, the samples must not disturb allocator benchmarks
+        let mut samples = Vec::with_capacity(self.iterations);
+        for _ in 0..self.iterations {
+            let start = tsc::rdtsc_ordered();
+            (self.f)();
+            let cycles = tsc::rdtsc_ordered().wrapping_sub(start);
+            samples.push(cycles.saturating_sub(overhead));
+        }
+
+        samples.sort_unstable();
+        BenchResult {
+            name: self.name,
+            iterations: self.iterations,
+            min: samples[0],
+            median: samples[samples.len() / 2],
+        }
+    }
+}
+
+impl Testable for Bench {
+    fn run(&self) {
+        let result = self.measure();
+        let mut results = RESULTS.lock();
+        let (slots, count) = &mut *results;
+        if let Some(slot) = slots.get_mut(*count) {
+            *slot = Some(result);
+            *count += 1;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Declare a benchmark timing `iterations` calls of `f`, e.g.
+/// `#[test_case] const X: Bench = bench!(f, 1000);` where `f` is a `fn()`.
+#[macro_export]
+macro_rules! bench {
+    ($f:ident, $iterations:expr) => {
+        $crate::testing::Bench::new(
+            concat!(module_path!(), "::", stringify!($f)),
+            $iterations,
+            $f,
+        )
+    };
+}
+
+/// Print the results of the benchmarks run so far.
+pub(crate) fn report() {
+    let results = RESULTS.lock();
+    let (slots, _) = &*results;
+    for result in slots.iter().flatten() {
+        serial_println!(
+            "bench {} iterations={} min={} median={}",
+            result.name,
+            result.iterations,
+            result.min,
+            result.median
+        );
+    }
+}
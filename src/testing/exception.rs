@@ -0,0 +1,265 @@
+//! Catching CPU exceptions raised on purpose by tests.
+//!
+//! [catch_exception] runs a closure with the handlers of the exceptions below replaced by ones
+//! recording the exception and jumping back out of the closure, then puts the kernel handlers back:
+//!
+//! ```ignore
+//! let exception = expect_page_fault(addr, || unsafe { ptr::read_volatile(addr.as_ptr::<u8>()) });
+//! ```
+//!
+//! Caught exceptions: divide error, breakpoint, invalid opcode, double fault, general protection
+//! fault and page fault. Hardware interrupts keep their handlers. Like a panic in a test, the frames
+//! of the faulting closure are discarded without running destructors.
+
+use core::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    gdt,
+    interrupts::{
+        self, BREAKPOINT_VECTOR, DIVIDE_ERROR_VECTOR, DOUBLE_FAULT_VECTOR,
+        GENERAL_PROTECTION_FAULT_VECTOR, INVALID_OPCODE_VECTOR, PAGE_FAULT_VECTOR,
+    },
+    resume::ResumePoint,
+};
+
+/// An exception caught by [catch_exception].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exception {
+    /// The vector of the exception.
+    pub vector: u8,
+    /// The error code pushed by the CPU, `None` for exceptions without error codes.
+    pub error_code: Option<u64>,
+    /// The address of the faulting instruction, or the one after it for traps like breakpoints.
+    pub instruction: VirtAddr,
+    /// The accessed address of a page fault.
+    pub address: Option<VirtAddr>,
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:?}", name(self.vector), self.instruction)?;
+        if let Some(error_code) = self.error_code {
+            write!(f, ", error code {:#x}", error_code)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, ", accessing {:?}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of the exception `vector`.
+fn name(vector: u8) -> &'static str {
+    interrupts::vector_name(vector).unwrap_or("unknown exception")
+}
+
+static CATCHING: ResumePoint = ResumePoint::new();
+/// Set while [CATCHING_IDT] is loaded.
+static LOADED: AtomicBool = AtomicBool::new(false);
+static CAUGHT: Mutex<Option<Exception>> = Mutex::new(None);
+
+lazy_static! {
+    /// The kernel IDT with the handlers of the caught exceptions replaced.
+    static ref CATCHING_IDT: InterruptDescriptorTable = {
+        let mut idt = interrupts::kernel_idt().clone();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        // # Safety
+        // As in the kernel IDT, the stack overflows of a test end up in a double fault.
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt
+    };
+}
+
+/// Run `f`, returns the exception it raised or `None` if it returned normally. The kernel IDT is
+/// loaded afterwards, [crate::gdt::init] must have been called.
+pub fn catch_exception(f: impl FnOnce()) -> Option<Exception> {
+    let f = Cell::new(Some(f));
+    *CAUGHT.lock() = None;
+    LOADED.store(true, Ordering::SeqCst);
+    CATCHING_IDT.load();
+
+    let raised = CATCHING.catch(&|| {
+        if let Some(f) = f.take() {
+            f();
+        }
+    });
+
+    restore();
+    if raised {
+        CAUGHT.lock().take()
+    } else {
+        None
+    }
+}
+
+/// Run `f`, panics unless it raised the exception `vector`.
+pub fn expect_exception(vector: u8, f: impl FnOnce()) -> Exception {
+    match catch_exception(f) {
+        Some(exception) if exception.vector == vector => exception,
+        Some(exception) => panic!("expected a {}, got a {}", name(vector), exception),
+        None => panic!("expected a {}, got no exception", name(vector)),
+    }
+}
+
+/// Run `f`, panics unless it raised a page fault accessing `address`.
+pub fn expect_page_fault(address: VirtAddr, f: impl FnOnce()) -> Exception {
+    let exception = expect_exception(PAGE_FAULT_VECTOR, f);
+    assert_eq!(
+        exception.address,
+        Some(address),
+        "page fault at the wrong address: {}",
+        exception
+    );
+    exception
+}
+
+/// Run `f`, panics unless it raised a general protection fault.
+pub fn expect_general_protection_fault(f: impl FnOnce()) -> Exception {
+    expect_exception(GENERAL_PROTECTION_FAULT_VECTOR, f)
+}
+
+/// Put the kernel handlers back if a test was abandoned in [catch_exception].
+pub(crate) fn restore() {
+    CATCHING.clear();
+    if LOADED.swap(false, Ordering::SeqCst) {
+        // # Safety
+        // [CATCHING_IDT] was loaded, the GDT is initialized.
+        unsafe {
+            interrupts::init_idt();
+        }
+    }
+}
+
+/// Record the exception and leave the faulting closure, never returns.
+fn caught(
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    address: Option<VirtAddr>,
+) -> ! {
+    *CAUGHT.lock() = Some(Exception {
+        vector,
+        error_code,
+        instruction: stack_frame.instruction_pointer,
+        address,
+    });
+    // # Safety
+    // The closure faulted, its frames and the frame of the handler are never returned to.
+    unsafe {
+        CATCHING.resume();
+    }
+    panic!("{} outside of catch_exception", name(vector));
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    caught(DIVIDE_ERROR_VECTOR, &stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    caught(BREAKPOINT_VECTOR, &stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    caught(INVALID_OPCODE_VECTOR, &stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    caught(DOUBLE_FAULT_VECTOR, &stack_frame, Some(error_code), None);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    caught(
+        GENERAL_PROTECTION_FAULT_VECTOR,
+        &stack_frame,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    caught(
+        PAGE_FAULT_VECTOR,
+        &stack_frame,
+        Some(error_code.bits()),
+        Some(Cr2::read()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory;
+    use core::ptr;
+
+    #[test_case]
+    fn no_exception() {
+        assert_eq!(catch_exception(|| {}), None);
+    }
+
+    #[test_case]
+    fn unmapped_page_fault() {
+        let addr = VirtAddr::new(0x_dead_beef_0000);
+        assert!(!memory::is_mapped(addr, 1, false));
+        // # Safety
+        // The address is not mapped, the read faults.
+        let exception = expect_page_fault(addr, || unsafe {
+            ptr::read_volatile(addr.as_ptr::<u8>());
+        });
+        assert_eq!(
+            exception
+                .error_code
+                .map(PageFaultErrorCode::from_bits_truncate),
+            Some(PageFaultErrorCode::empty())
+        );
+    }
+
+    #[test_case]
+    fn non_canonical_address() {
+        // # Safety
+        // The address is not canonical, the read raises a general protection fault.
+        expect_general_protection_fault(|| unsafe {
+            ptr::read_volatile(0x8000_0000_0000 as *const u8);
+        });
+    }
+
+    #[test_case]
+    fn invalid_opcode() {
+        // # Safety
+        // `ud2` raises an invalid opcode exception and nothing else.
+        let exception = catch_exception(|| unsafe { asm!("ud2") });
+        assert_eq!(exception.map(|e| e.vector), Some(INVALID_OPCODE_VECTOR));
+
+        // the kernel handler is back, it returns from the breakpoint
+        x86_64::instructions::interrupts::int3();
+    }
+}
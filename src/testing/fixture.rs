@@ -0,0 +1,133 @@
+//! Setup and teardown hooks run around each test.
+//!
+//! The kernel is not rebooted between tests, the fixtures reset the state a test may observe from
+//! the previous tests and check the state a test leaves behind. A teardown fails the test by
+//! panicking.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    allocator, fault,
+    task::{keyboard, serial},
+    time::mock,
+    vga_buffer, QemuExitCode,
+};
+
+/// A pair of hooks run before and after each test.
+pub struct TestFixture {
+    /// The name of the fixture.
+    pub name: &'static str,
+    /// Run before the test.
+    pub setup: fn(),
+    /// Run after the test, panics if the test left the kernel in a bad state.
+    pub teardown: fn(),
+}
+
+fn nothing() {}
+
+/// Starts each test on a blank screen.
+pub const VGA: TestFixture = TestFixture {
+    name: "vga",
+    setup: vga_buffer::clear_screen,
+    teardown: nothing,
+};
+
+/// Discards the keyboard and serial input queued before each test.
+pub const INPUT: TestFixture = TestFixture {
+    name: "input",
+    setup: drain_input,
+    teardown: nothing,
+};
+
+fn drain_input() {
+    keyboard::drain_scancodes();
+    serial::drain_bytes();
+}
+
+/// Checks the consistency of the kernel heap after each test.
+pub const HEAP_INTEGRITY: TestFixture = TestFixture {
+    name: "heap integrity",
+    setup: nothing,
+    teardown: check_heap,
+};
+
+fn check_heap() {
+    if let Err(err) = allocator::check() {
+        super::classify_failure(QemuExitCode::HeapCorrupted);
+        panic!("heap corrupted: {}", err);
+    }
+}
+
+/// Fails tests which don't free all their allocations, except those made in [keep_allocations].
+pub const HEAP_BALANCE: TestFixture = TestFixture {
+    name: "heap balance",
+    setup: record_live_allocations,
+    teardown: check_live_allocations,
+};
+
+/// Number of live allocations when the running test started, plus those kept by the test.
+static LIVE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn live_allocations() -> usize {
+    let stats = allocator::stats();
+    stats.allocations - stats.deallocations
+}
+
+fn record_live_allocations() {
+    LIVE_ALLOCATIONS.store(live_allocations(), Ordering::Relaxed);
+}
+
+fn check_live_allocations() {
+    let expected = LIVE_ALLOCATIONS.load(Ordering::Relaxed);
+    let live = live_allocations();
+    if live > expected {
+        panic!("test leaked {} allocations", live - expected);
+    }
+}
+
+/// Run `f`, the allocations it leaves behind are not counted as leaks of the running test. For
+/// tests which intentionally grow global state, e.g. register a shell command.
+pub fn keep_allocations<R>(f: impl FnOnce() -> R) -> R {
+    let before = live_allocations();
+    let result = f();
+    let kept = live_allocations().saturating_sub(before);
+    LIVE_ALLOCATIONS.fetch_add(kept, Ordering::Relaxed);
+    result
+}
+
+/// Disarms the faults injected by a test, also before the test in case the previous one failed
+/// before its teardown. See [crate::fault].
+pub const FAULTS: TestFixture = TestFixture {
+    name: "faults",
+    setup: fault::clear_all,
+    teardown: fault::clear_all,
+};
+
+/// Puts the clock back on the timer tick after a test which enabled the [mock] clock.
+pub const CLOCK: TestFixture = TestFixture {
+    name: "clock",
+    setup: mock::disable,
+    teardown: mock::disable,
+};
+
+/// Puts the kernel exception handlers back after a test abandoned in
+/// [catch_exception](super::catch_exception).
+pub const EXCEPTIONS: TestFixture = TestFixture {
+    name: "exceptions",
+    setup: super::exception::restore,
+    teardown: super::exception::restore,
+};
+
+/// The fixtures of ordinary tests.
+pub const DEFAULT: &[TestFixture] = &[
+    VGA,
+    INPUT,
+    HEAP_INTEGRITY,
+    HEAP_BALANCE,
+    FAULTS,
+    CLOCK,
+    EXCEPTIONS,
+];
+
+/// The fixtures of tests discarding their frames on panic, their allocations are never freed.
+pub const NO_LEAK_CHECK: &[TestFixture] = &[VGA, INPUT, HEAP_INTEGRITY, FAULTS, CLOCK, EXCEPTIONS];
@@ -0,0 +1,47 @@
+//! A small seeded pseudo random number generator for randomized tests.
+//!
+//! Not suitable for anything but tests: xorshift64* is fast and reproducible, nothing more.
+
+use crate::{cmdline, random};
+
+/// A xorshift64* generator.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// A generator seeded with `seed`, the same seed always produces the same sequence.
+    pub fn new(seed: u64) -> Self {
+        // the state must not be 0, scramble the seed so that small seeds are fine as well
+        let state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        Self { state }
+    }
+
+    /// The next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A random number in `0..bound`, `bound` must not be 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "empty range");
+        // the multiply-shift reduction is slightly biased, which doesn't matter for tests
+        ((u128::from(self.next_u64()) * u128::from(bound)) >> 64) as u64
+    }
+
+    /// `true` with a probability of `numerator / denominator`.
+    pub fn chance(&mut self, numerator: u64, denominator: u64) -> bool {
+        self.below(denominator) < numerator
+    }
+}
+
+/// The seed of randomized tests, the `seed=` option of the kernel command line or a [random]
+/// number if the option is not set. Randomized tests print their seed, a failure is reproduced by
+/// passing the printed seed on the command line.
+pub fn seed() -> u64 {
+    cmdline::config().test_seed.unwrap_or_else(random::u64)
+}
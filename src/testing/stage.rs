@@ -0,0 +1,60 @@
+//! Tests spanning several boots of the same machine.
+//!
+//! A multi-stage test branches on the [current] stage at the start of the kernel, kept in the CMOS
+//! across reboots by [crate::persist]. The first boot is stage 0; a stage ends with [reboot_into],
+//! or with a panic once [reboot_on_panic] is armed, which also exercises the crash report:
+//!
+//! ```ignore
+//! match stage::current() {
+//!     0 => {
+//!         persist::set_marker(MARKER);
+//!         stage::reboot_on_panic(1);
+//!         panic!("crash before the reboot");
+//!     }
+//!     _ => test_main(),
+//! }
+//! ```
+//!
+//! QEMU must not be started with `-no-reboot`, which turns the reboot into an exit.
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{crash, persist, power, serial_println};
+
+/// The stage the panic handler reboots into, [DISARMED] if it doesn't.
+static REBOOT_ON_PANIC: AtomicU8 = AtomicU8::new(DISARMED);
+/// Stage 0 is only entered on the first boot, it's never rebooted into.
+const DISARMED: u8 = 0;
+
+/// The stage of the running boot, 0 on the first boot.
+pub fn current() -> u8 {
+    persist::stage()
+}
+
+/// Reboot the machine into `stage`.
+pub fn reboot_into(stage: u8) -> ! {
+    assert_ne!(stage, DISARMED, "stage 0 is the first boot");
+    persist::set_stage(stage);
+    serial_println!("rebooting into stage {}", stage);
+    power::reboot();
+}
+
+/// Reboot into `stage` on the next panic after writing its crash report, instead of failing the
+/// running test.
+pub fn reboot_on_panic(stage: u8) {
+    assert_ne!(stage, DISARMED, "stage 0 is the first boot");
+    REBOOT_ON_PANIC.store(stage, Ordering::SeqCst);
+}
+
+/// Reboot if [reboot_on_panic] is armed, returns otherwise. Called by the test panic handler.
+pub(crate) fn reboot_if_armed(info: &PanicInfo) {
+    let stage = REBOOT_ON_PANIC.swap(DISARMED, Ordering::SeqCst);
+    if stage != DISARMED {
+        serial_println!("Error: {}\n", info);
+        crash::report(info);
+        reboot_into(stage);
+    }
+}
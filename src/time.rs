@@ -0,0 +1,359 @@
+//! Time keeping.
+//!
+//! The timer tick is driven by a [TickSource] chosen by the `tick=` option on the kernel command
+//! line, by default the PIT at [TICK_HZ]. The ticks counted by the interrupt handler make a
+//! monotonic clock since boot. With the tickless [TickSource::TscDeadline] the ticks are derived
+//! from the TSC instead and the timer only fires while the executor is busy or at the next expiry
+//! of the [wheel]. The wall-clock time is read from the RTC once at boot and advanced by the
+//! monotonic clock, see [now]. Finer measurements use [Instant] read from the
+//! [clocksource] selected at boot. Tests may swap the monotonic clock for a manually advanced
+//! [mock] clock.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+use crate::{apic, interrupts::InterruptIndex, warn};
+
+pub use self::{
+    datetime::DateTime,
+    delay::{delay, delay_ms, delay_us},
+    instant::Instant,
+    stopwatch::{Elapsed, Stopwatch},
+};
+
+pub mod clocksource;
+pub mod datetime;
+pub mod delay;
+pub mod hpet;
+pub mod instant;
+pub mod mock;
+pub mod pit;
+pub mod rtc;
+pub mod stopwatch;
+pub mod tsc;
+pub mod wheel;
+
+/// The nominal frequency of the timer tick driven by the PIT or the HPET.
+pub const TICK_HZ: u64 = 1000;
+
+/// The reload value of the PIT for [TICK_HZ].
+const PIT_DIVISOR: u16 = pit::divisor(TICK_HZ);
+
+/// The actual period of the timer interrupt in nanoseconds, slightly off from `1 / TICK_HZ` as the
+/// PIT can only divide its base frequency by an integer.
+pub const PIT_TICK_PERIOD_NS: u64 = PIT_DIVISOR as u64 * 1_000_000_000 / pit::BASE_FREQUENCY_HZ;
+
+/// The rate of the RTC periodic interrupt when it drives the tick, 1024 Hz.
+const RTC_RATE: u8 = 6;
+
+/// The frequency of the RTC periodic interrupt when it drives the tick.
+pub const RTC_HZ: u64 = 32_768 >> (RTC_RATE - 1);
+
+/// A timer device able to drive the timer tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+    /// Channel 0 of the PIT on IRQ 0.
+    Pit,
+    /// The periodic interrupt of the RTC on IRQ 8.
+    Rtc,
+    /// Comparator 0 of the HPET, routed to IRQ 0 in place of the PIT.
+    Hpet,
+    /// The timer of the local APIC in periodic mode.
+    Apic,
+    /// The timer of the local APIC in TSC-deadline mode. The tick stops while the executor is
+    /// idle, the timer then only fires at the next expiry of the timer wheel.
+    TscDeadline,
+}
+
+impl TickSource {
+    /// All the tick sources.
+    pub const ALL: [TickSource; 5] = [
+        TickSource::Pit,
+        TickSource::Rtc,
+        TickSource::Hpet,
+        TickSource::Apic,
+        TickSource::TscDeadline,
+    ];
+
+    /// The name of the tick source on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            TickSource::Pit => "pit",
+            TickSource::Rtc => "rtc",
+            TickSource::Hpet => "hpet",
+            TickSource::Apic => "apic",
+            TickSource::TscDeadline => "tsc-deadline",
+        }
+    }
+
+    /// The tick source named `name` on the command line.
+    pub fn from_name(name: &str) -> Option<TickSource> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|source| source.name() == name)
+    }
+
+    fn from_u8(source: u8) -> TickSource {
+        Self::ALL[usize::from(source)]
+    }
+
+    /// The hardware interrupt line (0 - 15) on the PICs the tick source fires on, `None` for the
+    /// timers of the local APIC.
+    pub fn irq(self) -> Option<u8> {
+        match self {
+            TickSource::Pit | TickSource::Hpet => Some(0),
+            TickSource::Rtc => Some(8),
+            TickSource::Apic | TickSource::TscDeadline => None,
+        }
+    }
+
+    /// The interrupt vector the tick source fires on.
+    pub fn vector(self) -> u8 {
+        let index = match self {
+            TickSource::Pit | TickSource::Hpet => InterruptIndex::Timer,
+            TickSource::Rtc => InterruptIndex::Rtc,
+            TickSource::Apic | TickSource::TscDeadline => InterruptIndex::ApicTimer,
+        };
+        index.to_u8()
+    }
+
+    /// Whether the machine has the tick source, only known after [init].
+    pub fn is_available(self) -> bool {
+        match self {
+            TickSource::Pit | TickSource::Rtc => true,
+            TickSource::Hpet => hpet::get().map_or(false, hpet::Hpet::has_legacy_route),
+            TickSource::Apic => apic::is_initialized(),
+            TickSource::TscDeadline => apic::is_initialized() && apic::supports_tsc_deadline(),
+        }
+    }
+
+    /// Start the periodic interrupt of the tick source, returns the period in nanoseconds or
+    /// `None` if the tick source can't be started.
+    fn start(self) -> Option<u64> {
+        if !self.is_available() {
+            return None;
+        }
+
+        let nominal_period = Duration::from_nanos(1_000_000_000 / TICK_HZ);
+        match self {
+            // the PIT is always programmed as a fallback
+            TickSource::Pit => Some(PIT_TICK_PERIOD_NS),
+            TickSource::Rtc => {
+                rtc::enable_periodic_interrupt(RTC_RATE);
+                Some(rtc::periodic_period_ns(RTC_RATE))
+            }
+            TickSource::Hpet => {
+                let hpet = hpet::get()?;
+                let period = hpet.duration_to_counts(nominal_period);
+                if !hpet.set_periodic(hpet::LEGACY_IRQ0_TIMER, period)
+                    || !hpet.enable_legacy_route()
+                {
+                    hpet.disable(hpet::LEGACY_IRQ0_TIMER);
+                    return None;
+                }
+                Some(hpet.counts_to_duration(period).as_nanos() as u64)
+            }
+            TickSource::Apic => {
+                let period = apic::set_timer_periodic(self.vector(), nominal_period);
+                Some(period.as_nanos() as u64)
+            }
+            TickSource::TscDeadline => {
+                // ticks are derived from the TSC from now on, the ticks counted before are kept
+                let cycles_per_tick = tsc::frequency_hz() / TICK_HZ;
+                let epoch = tsc::rdtsc() - ticks() * cycles_per_tick;
+                TSC_EPOCH.store(epoch, Ordering::Relaxed);
+                CYCLES_PER_TICK.store(cycles_per_tick, Ordering::Relaxed);
+                apic::set_timer_tsc_deadline(self.vector());
+                arm_next_tick();
+                Some(nominal_period.as_nanos() as u64)
+            }
+        }
+    }
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+static TICK_PERIOD_NS: AtomicU64 = AtomicU64::new(PIT_TICK_PERIOD_NS);
+/// The wall-clock time read from the RTC at boot, in seconds since the Unix epoch.
+static BOOT_UNIX_SECS: AtomicU64 = AtomicU64::new(0);
+/// Ticks of a tickless tick source are derived from the TSC, tick 0 is at this TSC value.
+static TSC_EPOCH: AtomicU64 = AtomicU64::new(0);
+/// Number of TSC cycles per tick of a tickless tick source.
+static CYCLES_PER_TICK: AtomicU64 = AtomicU64::new(0);
+/// Set while the executor sleeps with the tick stopped.
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+/// Locate the HPET, calibrate the TSC and the timer of the local APIC, start the timer tick from
+/// `source`, select the clock source and read the wall-clock time from the RTC. Falls back to the
+/// PIT if `source` is not available, and to the best clock source if `clocksource` is not. The PIT
+/// is always programmed to [TICK_HZ], its interrupt is simply not counted if another tick source
+/// drives the tick. Must be called with interrupts disabled.
+pub fn init(
+    source: TickSource,
+    clocksource: Option<&str>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    hpet::init(mapper, frame_allocator);
+    tsc::calibrate();
+    // the APIC timer is calibrated against the TSC
+    apic::init(mapper, frame_allocator);
+    pit::set_divisor(PIT_DIVISOR);
+
+    let (source, period_ns) = match source.start() {
+        Some(period_ns) => (source, period_ns),
+        None => {
+            warn!("tick source {} is not available, using pit", source.name());
+            (TickSource::Pit, PIT_TICK_PERIOD_NS)
+        }
+    };
+    TICK_PERIOD_NS.store(period_ns, Ordering::Relaxed);
+    SOURCE.store(source as u8, Ordering::Relaxed);
+
+    // the PIT and the APIC timer can only be read while they drive the tick
+    if !clocksource::select(clocksource) {
+        warn!(
+            "clock source {} is not available, using {}",
+            clocksource.unwrap_or_default(),
+            clocksource::current().name()
+        );
+    }
+
+    let boot = rtc::read().to_unix();
+    BOOT_UNIX_SECS.store(boot.saturating_sub(uptime().as_secs()), Ordering::Relaxed);
+}
+
+/// The tick source driving the timer tick.
+pub fn tick_source() -> TickSource {
+    TickSource::from_u8(SOURCE.load(Ordering::Relaxed))
+}
+
+/// Whether the tick stops while the executor is idle.
+pub fn is_tickless() -> bool {
+    tick_source() == TickSource::TscDeadline
+}
+
+/// The TSC value at the start of the tick `tick` of a tickless tick source.
+fn tick_to_tsc(tick: u64) -> u64 {
+    TSC_EPOCH.load(Ordering::Relaxed) + tick * CYCLES_PER_TICK.load(Ordering::Relaxed)
+}
+
+/// Program the TSC deadline to the start of the next tick.
+fn arm_next_tick() {
+    apic::set_tsc_deadline(tick_to_tsc(hardware_ticks() + 1));
+}
+
+/// Count a timer tick on the interrupt `vector`, called by the interrupt handlers of all the clock
+/// sources. Returns `false` if the tick source on `vector` doesn't drive the tick or the tick
+/// only woke an idle executor, the watchdog is then not checked.
+pub(crate) fn tick(vector: u8) -> bool {
+    let source = tick_source();
+    if vector != source.vector() {
+        return false;
+    }
+
+    if source != TickSource::TscDeadline {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    // the idle executor hasn't beaten for a while, the tick is rearmed once it's running again
+    !IDLE.load(Ordering::Relaxed) && {
+        arm_next_tick();
+        true
+    }
+}
+
+/// Called by the executor with interrupts disabled right before it halts. On a tickless clock
+/// source the tick stops, the timer only fires at the next expiry of the timer wheel.
+pub fn enter_idle() {
+    if !is_tickless() {
+        return;
+    }
+    IDLE.store(true, Ordering::Relaxed);
+    // the timers of a virtual clock never expire on their own, the tick goes on
+    let deadline = match wheel::next_expiry() {
+        _ if mock::is_enabled() => tick_to_tsc(hardware_ticks() + 1),
+        Some(deadline) => tick_to_tsc(deadline.saturating_sub(mock::offset())),
+        None => 0,
+    };
+    apic::set_tsc_deadline(deadline);
+}
+
+/// Called by the executor after it wakes up from halt, restarts the tick stopped by [enter_idle].
+pub fn exit_idle() {
+    if !is_tickless() {
+        return;
+    }
+    IDLE.store(false, Ordering::Relaxed);
+    arm_next_tick();
+}
+
+/// Number of timer ticks since boot, or the time of the [mock] clock while it's enabled.
+pub fn ticks() -> u64 {
+    mock::now().unwrap_or_else(|| hardware_ticks() + mock::offset())
+}
+
+/// Number of timer ticks counted by the tick source, unaffected by the [mock] clock.
+pub(crate) fn hardware_ticks() -> u64 {
+    match CYCLES_PER_TICK.load(Ordering::Relaxed) {
+        0 => TICKS.load(Ordering::Relaxed),
+        cycles_per_tick => (tsc::rdtsc() - TSC_EPOCH.load(Ordering::Relaxed)) / cycles_per_tick,
+    }
+}
+
+/// The actual period of the timer tick in nanoseconds.
+pub fn tick_period_ns() -> u64 {
+    TICK_PERIOD_NS.load(Ordering::Relaxed)
+}
+
+/// The time span of `ticks` timer ticks.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * tick_period_ns())
+}
+
+/// The number of whole timer ticks in `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() / u128::from(tick_period_ns())) as u64
+}
+
+/// Time elapsed since the timer tick started, in the resolution of the timer tick.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// The current wall-clock time in UTC, the time read from the RTC at boot advanced by [uptime].
+pub fn now() -> DateTime {
+    DateTime::from_unix(BOOT_UNIX_SECS.load(Ordering::Relaxed) + uptime().as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ticks_advance() {
+        assert_eq!(PIT_TICK_PERIOD_NS, 999_847);
+        assert_eq!(rtc::periodic_period_ns(RTC_RATE), 976_562);
+        let start = ticks();
+        // interrupts are enabled by init, hlt returns on the next interrupt
+        while ticks() < start + 2 {
+            x86_64::instructions::hlt();
+        }
+        assert!(uptime() >= ticks_to_duration(start + 2));
+        assert_eq!(duration_to_ticks(Duration::from_millis(10)), 10);
+    }
+
+    #[test_case]
+    fn wall_clock_follows_rtc() {
+        let rtc = rtc::read().to_unix();
+        let now = now().to_unix();
+        // the RTC and the tick may disagree by a second at the boundary
+        assert!(now + 1 >= rtc && now <= rtc + 1);
+    }
+}
@@ -0,0 +1,225 @@
+//! Free-running counters measuring time, ranked and selected at boot.
+//!
+//! Unlike a [super::TickSource] which fires interrupts, a [ClockSource] is only read. The kernel
+//! reads the active clock source for [super::Instant], it's chosen at boot by the `clocksource=`
+//! option on the kernel command line if that one is available, or otherwise the available clock
+//! source with the highest [ClockSource::rating]. An invariant TSC is the best choice, on machines
+//! without one (e.g. QEMU without KVM) the HPET is preferred over a TSC that may change rate.
+
+use core::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+use super::{hpet, pit, tsc, TickSource, PIT_DIVISOR};
+use crate::apic;
+
+/// A free-running counter.
+pub trait ClockSource: Sync {
+    /// The name of the clock source on the command line.
+    fn name(&self) -> &'static str;
+
+    /// The quality of the clock source, the available clock source with the highest rating is
+    /// selected by default.
+    fn rating(&self) -> u32;
+
+    /// Whether the clock source can be read, only known after [super::init].
+    fn is_available(&self) -> bool;
+
+    /// The current value of the counter, monotonic while the clock source is available.
+    fn read(&self) -> u64;
+
+    /// The rate of the counter.
+    fn frequency_hz(&self) -> u64;
+
+    /// The time span of a single count.
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 / self.frequency_hz().max(1))
+    }
+
+    /// The time span of `counts` counts.
+    fn counts_to_duration(&self, counts: u64) -> Duration {
+        let nanos = u128::from(counts) * 1_000_000_000 / u128::from(self.frequency_hz().max(1));
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// The number of whole counts in `duration`.
+    fn duration_to_counts(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * u128::from(self.frequency_hz()) / 1_000_000_000) as u64
+    }
+}
+
+/// Keeps a counter pieced together from a tick count and the count of a timer monotonic, the
+/// tick count lags behind while the timer interrupt is pending.
+struct Monotonic(AtomicU64);
+
+impl Monotonic {
+    fn clamp(&self, value: u64) -> u64 {
+        self.0.fetch_max(value, Ordering::Relaxed).max(value)
+    }
+}
+
+/// The time stamp counter.
+pub struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if tsc::is_invariant() {
+            300
+        } else {
+            100
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        tsc::frequency_hz() != 0
+    }
+
+    fn read(&self) -> u64 {
+        tsc::rdtsc()
+    }
+
+    fn frequency_hz(&self) -> u64 {
+        tsc::frequency_hz()
+    }
+}
+
+/// The main counter of the HPET.
+pub struct Hpet;
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn is_available(&self) -> bool {
+        hpet::get().is_some()
+    }
+
+    fn read(&self) -> u64 {
+        hpet::get().map_or(0, hpet::Hpet::counter)
+    }
+
+    fn frequency_hz(&self) -> u64 {
+        hpet::get().map_or(0, hpet::Hpet::frequency_hz)
+    }
+}
+
+/// The timer of the local APIC, only while it drives the tick in periodic mode.
+pub struct ApicTimer;
+
+static APIC_LAST: Monotonic = Monotonic(AtomicU64::new(0));
+
+impl ClockSource for ApicTimer {
+    fn name(&self) -> &'static str {
+        "apic"
+    }
+
+    fn rating(&self) -> u32 {
+        150
+    }
+
+    fn is_available(&self) -> bool {
+        super::tick_source() == TickSource::Apic
+    }
+
+    fn read(&self) -> u64 {
+        let value = interrupts::without_interrupts(|| {
+            let (initial, current) = apic::timer_counts();
+            super::hardware_ticks() * u64::from(initial) + u64::from(initial - current)
+        });
+        APIC_LAST.clamp(value)
+    }
+
+    fn frequency_hz(&self) -> u64 {
+        apic::timer_frequency_hz()
+    }
+}
+
+/// Channel 0 of the PIT, only while it drives the tick.
+pub struct Pit;
+
+static PIT_LAST: Monotonic = Monotonic(AtomicU64::new(0));
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        110
+    }
+
+    fn is_available(&self) -> bool {
+        super::tick_source() == TickSource::Pit
+    }
+
+    fn read(&self) -> u64 {
+        let divisor = u64::from(PIT_DIVISOR);
+        let value = interrupts::without_interrupts(|| {
+            super::hardware_ticks() * divisor + (divisor - u64::from(pit::read_channel_0()))
+        });
+        PIT_LAST.clamp(value)
+    }
+
+    fn frequency_hz(&self) -> u64 {
+        pit::BASE_FREQUENCY_HZ
+    }
+}
+
+/// All the clock sources.
+pub static ALL: [&dyn ClockSource; 4] = [&Tsc, &Hpet, &ApicTimer, &Pit];
+
+/// Index of the active clock source in [ALL], the TSC until [select].
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Select the clock source named `preferred` if it's available, or the available clock source with
+/// the highest rating. Returns `false` if `preferred` is not available.
+pub fn select(preferred: Option<&str>) -> bool {
+    let index = |name: &str| {
+        ALL.iter()
+            .position(|source| source.name() == name && source.is_available())
+    };
+    let best = ALL
+        .iter()
+        .enumerate()
+        .filter(|(_, source)| source.is_available())
+        .max_by_key(|(_, source)| source.rating())
+        .map_or(0, |(i, _)| i);
+
+    let preferred = preferred.map(index);
+    CURRENT.store(preferred.flatten().unwrap_or(best), Ordering::Relaxed);
+    preferred.map_or(true, |index| index.is_some())
+}
+
+/// The active clock source.
+pub fn current() -> &'static dyn ClockSource {
+    ALL[CURRENT.load(Ordering::Relaxed)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::delay_us;
+
+    #[test_case]
+    fn available_sources_advance() {
+        assert!(current().is_available());
+        for source in ALL.iter().filter(|source| source.is_available()) {
+            let start = source.read();
+            delay_us(100);
+            assert!(source.read() > start, "{} stands still", source.name());
+            assert!(source.resolution() <= Duration::from_micros(1));
+        }
+    }
+}
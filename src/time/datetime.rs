@@ -0,0 +1,112 @@
+//! Calendar date and time of day.
+
+use core::fmt;
+
+/// A date and time of day in UTC, the RTC is assumed to keep UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    /// The year, e.g. 2021.
+    pub year: u16,
+    /// The month, 1 to 12.
+    pub month: u8,
+    /// The day of the month, 1 to 31.
+    pub day: u8,
+    /// The hour, 0 to 23.
+    pub hour: u8,
+    /// The minute, 0 to 59.
+    pub minute: u8,
+    /// The second, 0 to 59.
+    pub second: u8,
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar.
+const UNIX_EPOCH_DAYS: u64 = 719_468;
+/// Days in a 400-year era.
+const DAYS_PER_ERA: u64 = 146_097;
+
+impl DateTime {
+    /// The date and time `secs` seconds after 1970-01-01T00:00:00Z.
+    pub fn from_unix(secs: u64) -> Self {
+        // the calendar is shifted to start from March so that the leap day is the last day of a
+        // year, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = secs / SECS_PER_DAY + UNIX_EPOCH_DAYS;
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days % DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+        let secs_of_day = secs % SECS_PER_DAY;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+    /// Seconds from 1970-01-01T00:00:00Z to the date and time, which must not be earlier.
+    pub fn to_unix(&self) -> u64 {
+        // see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let month = u64::from(self.month);
+        let year = u64::from(self.year) - u64::from(month <= 2);
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * shifted_month + 2) / 5 + u64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS;
+
+        days * SECS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// ISO 8601, e.g. `2021-04-20T13:37:00Z`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn convert_unix_time() {
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 58,
+        };
+        assert_eq!(leap_day.to_unix(), 1_709_251_198);
+        assert_eq!(DateTime::from_unix(1_709_251_198), leap_day);
+        assert_eq!(DateTime::from_unix(0).to_unix(), 0);
+        assert_eq!(
+            DateTime::from_unix(951_782_400).to_string(),
+            "2000-02-29T00:00:00Z"
+        );
+    }
+}
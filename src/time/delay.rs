@@ -0,0 +1,57 @@
+//! Busy-wait delays for device initialization sequences.
+//!
+//! The delays spin on the TSC once it's calibrated and on PIT channel 2 before, so they're usable
+//! at any point of the boot and with interrupts disabled. They never sleep, anything longer than a
+//! few milliseconds in a task should wait on the timer wheel instead.
+
+use core::time::Duration;
+
+use super::{pit, tsc};
+
+/// Spin for at least `duration`.
+pub fn delay(duration: Duration) {
+    let nanos = duration.as_nanos().min(u128::from(u64::MAX)) as u64;
+    if tsc::frequency_hz() != 0 {
+        let deadline = tsc::rdtsc().saturating_add(tsc::nanos_to_cycles(nanos));
+        while tsc::rdtsc() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    // the PIT counts at about 1.19 MHz, round up so that the delay is never shorter
+    let mut counts =
+        (u128::from(nanos) * u128::from(pit::BASE_FREQUENCY_HZ) + 999_999_999) / 1_000_000_000;
+    while counts > 0 {
+        let chunk = counts.min(u128::from(u16::MAX));
+        pit::wait_channel_2(chunk as u16, || ());
+        counts -= chunk;
+    }
+}
+
+/// Spin for at least `us` microseconds.
+pub fn delay_us(us: u64) {
+    delay(Duration::from_micros(us));
+}
+
+/// Spin for at least `ms` milliseconds.
+pub fn delay_ms(ms: u64) {
+    delay(Duration::from_millis(ms));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::Instant;
+
+    #[test_case]
+    fn delay_at_least() {
+        let start = Instant::now();
+        delay_us(500);
+        assert!(start.elapsed() >= Duration::from_micros(499));
+
+        let start = Instant::now();
+        delay_ms(2);
+        assert!(start.elapsed() >= Duration::from_micros(1999));
+    }
+}
@@ -0,0 +1,229 @@
+//! The High Precision Event Timer.
+//!
+//! The HPET is located by its ACPI table and its registers are mapped as uncached memory. Its main
+//! counter runs at a fixed frequency of at least 10 MHz, which makes it a much better reference
+//! than the PIT for [super::tsc::calibrate]. Each comparator fires an interrupt when the main
+//! counter reaches it, either once for a deadline or periodically. Without an I/O APIC the only
+//! usable routing is the legacy replacement, which connects comparator 0 to IRQ 0 in place of the
+//! PIT and comparator 1 to IRQ 8 in place of the RTC.
+
+use conquer_once::spin::OnceCell;
+use core::{ptr, time::Duration};
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{acpi, memory};
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0f0;
+const REG_TIMER_BASE: u64 = 0x100;
+const TIMER_STRIDE: u64 = 0x20;
+const TIMER_CONFIG: u64 = 0x00;
+const TIMER_COMPARATOR: u64 = 0x08;
+/// Size of the register block.
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// Capability of the legacy replacement routing.
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// Set with a write to the comparator of a periodic timer to also set its period.
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+/// Offset of the address of the register block in the ACPI table.
+const TABLE_BASE_ADDRESS_OFFSET: u64 = 44;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// The comparator routed to IRQ 0 by the legacy replacement.
+pub const LEGACY_IRQ0_TIMER: u8 = 0;
+/// The comparator routed to IRQ 8 by the legacy replacement.
+pub const LEGACY_IRQ8_TIMER: u8 = 1;
+
+/// A mapped HPET.
+#[derive(Debug)]
+pub struct Hpet {
+    base: VirtAddr,
+    period_fs: u64,
+    timers: u8,
+    legacy_route: bool,
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// Locate the HPET, map its registers and start its main counter. Returns `false` if the machine
+/// has no HPET or its registers can't be mapped.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> bool {
+    let (table, _) = match acpi::find_table(b"HPET") {
+        Some(table) => table,
+        None => return false,
+    };
+    // # Safety
+    // The table has been validated by its checksum, the address is part of the fixed layout.
+    let phys = unsafe {
+        ptr::read_unaligned(memory::phys_to_virt(table + TABLE_BASE_ADDRESS_OFFSET).as_ptr::<u64>())
+    };
+    // # Safety
+    // The firmware reserves the register block of the HPET for the device.
+    let base = match unsafe {
+        memory::map_mmio(mapper, frame_allocator, PhysAddr::new(phys), REGISTERS_SIZE)
+    } {
+        Ok(base) => base,
+        Err(_) => return false,
+    };
+
+    let mut hpet = Hpet {
+        base,
+        period_fs: 0,
+        timers: 0,
+        legacy_route: false,
+    };
+    let capabilities = hpet.read(REG_CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    hpet.timers = ((capabilities >> 8) & 0x1f) as u8 + 1;
+    hpet.legacy_route = capabilities & CAP_LEGACY_ROUTE != 0;
+    // the spec caps the period at 100 ns, anything else is not an HPET
+    if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+        return false;
+    }
+
+    let config = hpet.read(REG_CONFIG);
+    hpet.write(REG_CONFIG, config | CONFIG_ENABLE);
+    HPET.try_init_once(|| hpet).is_ok()
+}
+
+/// The HPET, `None` before [init] or if the machine has none.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.try_get().ok()
+}
+
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        // # Safety
+        // `reg` is an offset into the mapped register block, registers are accessed as a whole.
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr()) }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        // # Safety
+        // Same as [Hpet::read].
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr(), value) }
+    }
+
+    fn timer_reg(timer: u8, reg: u64) -> u64 {
+        REG_TIMER_BASE + u64::from(timer) * TIMER_STRIDE + reg
+    }
+
+    /// The current value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+
+    /// The frequency of the main counter.
+    pub fn frequency_hz(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period_fs
+    }
+
+    /// Number of comparators.
+    pub fn timers(&self) -> u8 {
+        self.timers
+    }
+
+    /// Whether the comparators can be routed to IRQ 0 and IRQ 8 in place of the PIT and the RTC.
+    pub fn has_legacy_route(&self) -> bool {
+        self.legacy_route
+    }
+
+    /// The number of counter periods in `duration`.
+    pub fn duration_to_counts(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * 1_000_000 / u128::from(self.period_fs)) as u64
+    }
+
+    /// The time span of `counts` counter periods.
+    pub fn counts_to_duration(&self, counts: u64) -> Duration {
+        Duration::from_nanos((u128::from(counts) * u128::from(self.period_fs) / 1_000_000) as u64)
+    }
+
+    /// Busy-wait until the main counter reaches `deadline`.
+    pub fn wait_until(&self, deadline: u64) {
+        while self.counter() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Route comparators 0 and 1 to IRQ 0 and IRQ 8, which disconnects the PIT and the RTC from
+    /// the PICs. Returns `false` if the HPET can't route legacy interrupts.
+    pub fn enable_legacy_route(&self) -> bool {
+        if self.legacy_route {
+            let config = self.read(REG_CONFIG);
+            self.write(REG_CONFIG, config | CONFIG_LEGACY_ROUTE);
+        }
+        self.legacy_route
+    }
+
+    /// Fire the interrupt of `timer` once when the main counter reaches `deadline`.
+    pub fn set_oneshot(&self, timer: u8, deadline: u64) {
+        assert!(timer < self.timers, "HPET has no comparator {}", timer);
+        let reg = Self::timer_reg(timer, TIMER_CONFIG);
+        let config = self.read(reg) & !TIMER_PERIODIC;
+        self.write(reg, config | TIMER_INTERRUPT_ENABLE);
+        self.write(Self::timer_reg(timer, TIMER_COMPARATOR), deadline);
+    }
+
+    /// Fire the interrupt of `timer` every `period` counter periods. Returns `false` if the
+    /// comparator has no periodic mode.
+    pub fn set_periodic(&self, timer: u8, period: u64) -> bool {
+        assert!(timer < self.timers, "HPET has no comparator {}", timer);
+        let reg = Self::timer_reg(timer, TIMER_CONFIG);
+        let config = self.read(reg);
+        if config & TIMER_PERIODIC_CAP == 0 {
+            return false;
+        }
+
+        self.write(
+            reg,
+            config | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET,
+        );
+        // with the value set bit the first write sets the comparator, the second the period
+        self.write(
+            Self::timer_reg(timer, TIMER_COMPARATOR),
+            self.counter() + period,
+        );
+        self.write(Self::timer_reg(timer, TIMER_COMPARATOR), period);
+        true
+    }
+
+    /// Stop the interrupt of `timer`.
+    pub fn disable(&self, timer: u8) {
+        let reg = Self::timer_reg(timer, TIMER_CONFIG);
+        let config = self.read(reg);
+        self.write(reg, config & !TIMER_INTERRUPT_ENABLE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn counter_advances() {
+        // QEMU emulates an HPET on both of its PC machines
+        let hpet = get().expect("no HPET");
+        assert!(hpet.frequency_hz() >= 10_000_000);
+        assert!(hpet.timers() >= 3);
+
+        let start = hpet.counter();
+        let deadline = start + hpet.duration_to_counts(Duration::from_micros(100));
+        hpet.wait_until(deadline);
+        assert!(hpet.counts_to_duration(hpet.counter() - start) >= Duration::from_micros(99));
+    }
+}
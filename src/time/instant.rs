@@ -0,0 +1,99 @@
+//! A monotonic point in time read from the active clock source.
+
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    time::Duration,
+};
+
+use super::clocksource;
+
+/// A measurement of the active [clocksource::ClockSource], for profiling and benchmarks. Unlike
+/// [super::uptime] which advances once per timer tick, the resolution is a few nanoseconds with
+/// the TSC or the HPET.
+///
+/// Instants are only comparable while the same clock source is active, the clock source is only
+/// changed by [super::init].
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time.
+    #[inline(always)]
+    pub fn now() -> Self {
+        Instant(clocksource::current().read())
+    }
+
+    /// The value of the clock source at this instant.
+    pub fn counts(self) -> u64 {
+        self.0
+    }
+
+    /// The time elapsed from `earlier` to this instant, zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// The time elapsed from `earlier` to this instant, `None` if `earlier` is later.
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        let counts = self.0.checked_sub(earlier.0)?;
+        Some(clocksource::current().counts_to_duration(counts))
+    }
+
+    /// The time elapsed since this instant.
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        let counts = clocksource::current().duration_to_counts(duration);
+        Instant(self.0.saturating_add(counts))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Instant({} {} counts)",
+            self.0,
+            clocksource::current().name()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{ticks, ticks_to_duration};
+
+    #[test_case]
+    fn measure_tick() {
+        assert!(clocksource::current().frequency_hz() > 0);
+
+        let start = Instant::now();
+        let tick = ticks();
+        while ticks() < tick + 2 {
+            x86_64::instructions::hlt();
+        }
+        let elapsed = start.elapsed();
+        // at least one whole tick passed, generously bounded for slow emulation
+        assert!(elapsed >= ticks_to_duration(1));
+        assert!(elapsed < Duration::from_secs(1));
+
+        assert_eq!(start.duration_since(Instant::now()), Duration::from_secs(0));
+        assert!(start + Duration::from_millis(1) > start);
+    }
+}
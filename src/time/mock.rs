@@ -0,0 +1,132 @@
+//! A manually advanced clock for deterministic timer tests.
+//!
+//! While enabled, [super::ticks] stops following the timer tick and only moves when a test calls
+//! [advance], which also expires the due timers of the [wheel]. Code sleeping on the wheel or
+//! reading the uptime then runs in a few microseconds regardless of the durations involved:
+//!
+//! ```ignore
+//! mock::enable();
+//! wheel::register(time::ticks() + 60_000, waker);
+//! // wakes `waker` right away
+//! mock::advance(Duration::from_secs(60));
+//! ```
+//!
+//! The timer tick itself keeps running underneath: test timeouts, the clock sources and the
+//! tickless deadline stay on the hardware ticks. Virtual time advanced past the hardware ticks is
+//! kept once the clock is disabled, so [super::ticks] never goes backwards. The test framework
+//! disables the clock around each test, see [crate::testing::fixture].
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::{duration_to_ticks, hardware_ticks, wheel};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The virtual tick count while enabled.
+static NOW: AtomicU64 = AtomicU64::new(0);
+/// Ticks the virtual clock ran ahead of the hardware ticks, added to them once disabled.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Freeze [super::ticks] at its current value, it only advances with [advance] from now on. Does
+/// nothing if the clock is already enabled.
+pub fn enable() {
+    if is_enabled() {
+        return;
+    }
+    NOW.store(super::ticks(), Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Let [super::ticks] follow the timer tick again, from the later of the virtual and the hardware
+/// time.
+pub fn disable() {
+    if !ENABLED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let real = hardware_ticks() + offset();
+    let ahead = NOW.load(Ordering::SeqCst).saturating_sub(real);
+    OFFSET.fetch_add(ahead, Ordering::SeqCst);
+}
+
+/// Whether the virtual clock replaces the timer tick.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Advance the virtual clock by the whole ticks in `duration`, see [advance_ticks].
+pub fn advance(duration: Duration) {
+    advance_ticks(duration_to_ticks(duration));
+}
+
+/// Advance the virtual clock by `ticks` and wake the timers due by then. The woken tasks run the
+/// next time their executor polls. Panics if the clock isn't enabled. Must not be called in
+/// interrupt handlers.
+pub fn advance_ticks(ticks: u64) {
+    assert!(is_enabled(), "the virtual clock is not enabled");
+    let now = NOW.fetch_add(ticks, Ordering::SeqCst) + ticks;
+    wheel::expire(now);
+}
+
+/// The virtual tick count, `None` if the clock is disabled.
+pub(crate) fn now() -> Option<u64> {
+    if is_enabled() {
+        Some(NOW.load(Ordering::SeqCst))
+    } else {
+        None
+    }
+}
+
+/// Ticks to add to the hardware ticks for [super::ticks].
+pub(crate) fn offset() -> u64 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::fixture::keep_allocations, time::ticks};
+    use alloc::{sync::Arc, task::Wake};
+    use core::task::Waker;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test_case]
+    fn frozen_until_advanced() {
+        enable();
+        let start = ticks();
+        let tick = hardware_ticks();
+        while hardware_ticks() < tick + 2 {
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(ticks(), start);
+
+        advance(Duration::from_secs(3600));
+        assert_eq!(
+            ticks(),
+            start + duration_to_ticks(Duration::from_secs(3600))
+        );
+        disable();
+        assert!(ticks() >= start + duration_to_ticks(Duration::from_secs(3600)));
+    }
+
+    #[test_case]
+    fn wake_timers_when_due() {
+        enable();
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        // the slots of the wheel keep their capacity
+        keep_allocations(|| wheel::register(ticks() + 5, Waker::from(Arc::clone(&flag))));
+
+        advance_ticks(4);
+        assert!(!flag.0.load(Ordering::SeqCst));
+        advance_ticks(1);
+        assert!(flag.0.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,111 @@
+//! The Programmable Interval Timer, channel 0 of which drives the timer interrupt.
+
+use lazy_static::lazy_static;
+
+use crate::io::PortRange;
+
+/// Frequency of the oscillator driving the PIT.
+pub const BASE_FREQUENCY_HZ: u64 = 1_193_182;
+
+/// The first port of the PIT, the data port of channel 0.
+const BASE_PORT: u16 = 0x40;
+/// Offsets of the ports from [BASE_PORT].
+const CHANNEL_0_DATA: u16 = 0;
+const CHANNEL_2_DATA: u16 = 2;
+const COMMAND: u16 = 3;
+/// Port of the PC speaker, which also gates channel 2 and reads its output.
+const SPEAKER_PORT: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 0x01;
+const SPEAKER_ENABLE: u8 = 0x02;
+const CHANNEL_2_OUTPUT: u8 = 0x20;
+
+/// Channel 0, access mode lobyte/hibyte, mode 2 (rate generator), binary counting.
+const RATE_GENERATOR_COMMAND: u8 = 0b0011_0100;
+/// Latch the current count of channel 0.
+const LATCH_CHANNEL_0_COMMAND: u8 = 0b0000_0000;
+/// Channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count), binary counting.
+const ONE_SHOT_COMMAND: u8 = 0b1011_0000;
+/// Channel 2, access mode lobyte/hibyte, mode 3 (square wave generator), binary counting.
+const SQUARE_WAVE_COMMAND: u8 = 0b1011_0110;
+
+lazy_static! {
+    /// # Safety
+    /// The ports belong to the PIT and the PC speaker, neither accesses memory. Channel 0 drives
+    /// the timer interrupt, channel 2 nothing but the speaker.
+    static ref PIT: PortRange = unsafe { PortRange::claim(BASE_PORT, 4, "pit") }
+        .expect("the ports of the PIT are claimed by another driver");
+    static ref SPEAKER: PortRange = unsafe { PortRange::claim(SPEAKER_PORT, 1, "pit") }
+        .expect("the port of the PC speaker is claimed by another driver");
+}
+
+/// The reload value of channel 0 closest to the frequency `hz`.
+pub const fn divisor(hz: u64) -> u16 {
+    let divisor = (BASE_FREQUENCY_HZ + hz / 2) / hz;
+    // a reload value of 0 stands for 65536, the slowest rate
+    if divisor > u16::MAX as u64 {
+        0
+    } else {
+        divisor as u16
+    }
+}
+
+/// Program channel 0 to fire with the reload value `divisor`.
+pub fn set_divisor(divisor: u16) {
+    let [low, high] = divisor.to_le_bytes();
+    PIT.write(COMMAND, RATE_GENERATOR_COMMAND);
+    PIT.write(CHANNEL_0_DATA, low);
+    PIT.write(CHANNEL_0_DATA, high);
+}
+
+/// The current count of channel 0, counting down from the reload value to 1. Must be called with
+/// interrupts disabled, otherwise an interrupt handler may access the PIT between the latch and
+/// the read.
+pub fn read_channel_0() -> u16 {
+    // latching the count doesn't disturb the countdown
+    PIT.write(COMMAND, LATCH_CHANNEL_0_COMMAND);
+    let low = PIT.read(CHANNEL_0_DATA);
+    let high = PIT.read(CHANNEL_0_DATA);
+    u16::from_le_bytes([low, high])
+}
+
+/// Drive the PC speaker with a square wave of channel 2 with the reload value `divisor`, silence
+/// it with `None`.
+pub fn set_speaker(divisor: Option<u16>) {
+    let control = SPEAKER.read::<u8>(0) & !(SPEAKER_ENABLE | CHANNEL_2_GATE);
+    match divisor {
+        Some(divisor) => {
+            let [low, high] = divisor.to_le_bytes();
+            PIT.write(COMMAND, SQUARE_WAVE_COMMAND);
+            PIT.write(CHANNEL_2_DATA, low);
+            PIT.write(CHANNEL_2_DATA, high);
+            SPEAKER.write(0, control | CHANNEL_2_GATE | SPEAKER_ENABLE);
+        }
+        None => SPEAKER.write(0, control),
+    }
+}
+
+/// Whether the PC speaker is driven by channel 2.
+pub fn speaker_enabled() -> bool {
+    SPEAKER.read::<u8>(0) & SPEAKER_ENABLE != 0
+}
+
+/// Busy-wait for `count` periods of the PIT base frequency on channel 2, which is not connected to
+/// any interrupt. Silences the PC speaker. `start` is called right after the countdown started, e.g. to read a counter that
+/// is compared with another read after the wait returns.
+pub fn wait_channel_2(count: u16, start: impl FnOnce()) {
+    let [low, high] = count.to_le_bytes();
+
+    // the speaker is kept disabled
+    let control = SPEAKER.read::<u8>(0) & !(SPEAKER_ENABLE | CHANNEL_2_GATE);
+    SPEAKER.write(0, control);
+    PIT.write(COMMAND, ONE_SHOT_COMMAND);
+    PIT.write(CHANNEL_2_DATA, low);
+    PIT.write(CHANNEL_2_DATA, high);
+    // a rising edge of the gate starts the countdown
+    SPEAKER.write(0, control | CHANNEL_2_GATE);
+    start();
+    while SPEAKER.read::<u8>(0) & CHANNEL_2_OUTPUT == 0 {
+        core::hint::spin_loop();
+    }
+    SPEAKER.write(0, control);
+}
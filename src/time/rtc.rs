@@ -0,0 +1,217 @@
+//! The CMOS real-time clock.
+//!
+//! The RTC keeps the wall-clock time while the machine is off. Its registers are read through the
+//! CMOS index and data ports, the date and time may be in BCD and the hour in 12-hour format
+//! depending on status register B. The RTC can also fire a periodic interrupt on IRQ 8, which
+//! serves as an alternative source of the timer tick. The rest of the CMOS memory is exposed as
+//! bytes of NVRAM, see [read_nvram].
+
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+
+use super::DateTime;
+use crate::io::PortRange;
+
+/// The index port of the CMOS, followed by its data port.
+const CMOS_BASE_PORT: u16 = 0x70;
+const CMOS_ADDRESS: u16 = 0;
+const CMOS_DATA: u16 = 1;
+/// Set in the index written to the address port to keep NMIs disabled.
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+/// The first CMOS register past the RTC registers.
+const NVRAM_START: u8 = 0x0e;
+/// Number of CMOS registers.
+const CMOS_SIZE: u8 = 0x80;
+
+/// Set in status register A while the RTC is updating the time registers.
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Set in status register B if the hour is in 24-hour format.
+const HOUR_24: u8 = 0x02;
+/// Set in status register B if the time registers are in binary instead of BCD.
+const BINARY: u8 = 0x04;
+/// Set in status register B to enable the periodic interrupt.
+const PERIODIC_INTERRUPT: u8 = 0x40;
+/// Set in the hour register for PM in 12-hour format.
+const HOUR_PM: u8 = 0x80;
+
+/// Frequency of the oscillator driving the periodic interrupt.
+const BASE_FREQUENCY_HZ: u64 = 32_768;
+
+lazy_static! {
+    /// # Safety
+    /// The ports belong to the CMOS, which doesn't access memory. Only the RTC status registers
+    /// configuring the RTC itself and the NVRAM bytes are written.
+    static ref CMOS: PortRange = unsafe { PortRange::claim(CMOS_BASE_PORT, 2, "rtc") }
+        .expect("the ports of the CMOS are claimed by another driver");
+}
+
+/// Read the CMOS register `reg`. Must be called with interrupts disabled, otherwise an interrupt
+/// handler may select another register in between.
+fn read_register(reg: u8) -> u8 {
+    // reading status register C acknowledges the interrupt of the RTC
+    CMOS.write(CMOS_ADDRESS, NMI_DISABLE | reg);
+    CMOS.read(CMOS_DATA)
+}
+
+/// Write the CMOS register `reg`, with the same requirements as [read_register].
+fn write_register(reg: u8, value: u8) {
+    CMOS.write(CMOS_ADDRESS, NMI_DISABLE | reg);
+    CMOS.write(CMOS_DATA, value);
+}
+
+/// Read the raw time registers once no update is in progress.
+fn read_time_registers() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0f)
+}
+
+/// Convert the raw time registers to a [DateTime] by the format in status register B.
+fn decode(raw: [u8; 6], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = raw;
+    let pm = hour & HOUR_PM != 0;
+    let convert = |value: u8| {
+        if status_b & BINARY != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    let mut hour = convert(hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    DateTime {
+        // the century register is only located by ACPI, the 21st century is assumed
+        year: 2000 + u16::from(convert(year)),
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Read the current date and time from the RTC. Reads the time registers until two consecutive
+/// reads agree, as an update may still start in the middle of a read.
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let mut raw = read_time_registers();
+        loop {
+            let again = read_time_registers();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        decode(raw, read_register(REG_STATUS_B))
+    })
+}
+
+/// Read the byte `reg` of the battery-backed CMOS memory past the RTC registers, from
+/// [NVRAM_START] to `0x7f`. The content survives reboots but its layout is up to the firmware,
+/// only bytes unused by the firmware may be written.
+pub fn read_nvram(reg: u8) -> u8 {
+    assert!(
+        (NVRAM_START..CMOS_SIZE).contains(&reg),
+        "CMOS register {:#x} is not NVRAM",
+        reg
+    );
+    interrupts::without_interrupts(|| read_register(reg))
+}
+
+/// Write the byte `reg` of the CMOS memory, see [read_nvram].
+pub fn write_nvram(reg: u8, value: u8) {
+    assert!(
+        (NVRAM_START..CMOS_SIZE).contains(&reg),
+        "CMOS register {:#x} is not NVRAM",
+        reg
+    );
+    interrupts::without_interrupts(|| write_register(reg, value));
+}
+
+/// Period of the periodic interrupt at `rate` (3 to 15) in nanoseconds.
+pub const fn periodic_period_ns(rate: u8) -> u64 {
+    (1_000_000_000 << (rate - 1)) / BASE_FREQUENCY_HZ
+}
+
+/// Enable the periodic interrupt at `rate` (3 to 15), which fires at `32768 >> (rate - 1)` Hz.
+pub fn enable_periodic_interrupt(rate: u8) {
+    assert!(
+        (3..=15).contains(&rate),
+        "invalid RTC periodic rate {}",
+        rate
+    );
+
+    interrupts::without_interrupts(|| {
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | PERIODIC_INTERRUPT);
+        // an interrupt pending from before would block all the following ones
+        acknowledge();
+    });
+}
+
+/// Acknowledge an interrupt of the RTC, must be called by its interrupt handler. The RTC fires no
+/// more interrupt until status register C is read.
+pub(crate) fn acknowledge() {
+    read_register(REG_STATUS_C);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decode_formats() {
+        let expected = DateTime {
+            year: 2021,
+            month: 4,
+            day: 20,
+            hour: 0,
+            minute: 37,
+            second: 9,
+        };
+        // BCD with 12-hour format, 12 AM
+        assert_eq!(decode([0x09, 0x37, 0x12, 0x20, 0x04, 0x21], 0), expected);
+        // binary with 24-hour format
+        assert_eq!(decode([9, 37, 0, 20, 4, 21], BINARY | HOUR_24), expected);
+        // BCD with 12-hour format, 1 PM
+        assert_eq!(decode([0x09, 0x37, 0x81, 0x20, 0x04, 0x21], 0).hour, 13);
+    }
+
+    #[test_case]
+    fn read_plausible_time() {
+        let now = read();
+        assert!(now.year >= 2021);
+        assert!((1..=12).contains(&now.month));
+    }
+}
@@ -0,0 +1,124 @@
+//! Ad-hoc measurement of elapsed time, see [measure!](crate::measure).
+
+use core::{fmt, time::Duration};
+
+use super::{tsc, Instant};
+
+/// Measures the time elapsed since it was started, both in TSC cycles and in the active clock
+/// source.
+///
+/// ```ignore
+/// let mut stopwatch = Stopwatch::start();
+/// parse_tables();
+/// info!("parsed in {}", stopwatch.lap());
+/// load_drivers();
+/// info!("loaded in {}, total {}", stopwatch.lap(), stopwatch);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    start: Instant,
+    start_cycles: u64,
+    lap: Instant,
+    lap_cycles: u64,
+}
+
+/// A time span measured by a [Stopwatch].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed {
+    /// The elapsed TSC cycles.
+    pub cycles: u64,
+    /// The elapsed time by the active clock source.
+    pub duration: Duration,
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} cycles, {} ns", self.cycles, self.duration.as_nanos())
+    }
+}
+
+impl Stopwatch {
+    /// Start a stopwatch at the current time.
+    pub fn start() -> Self {
+        let now = Instant::now();
+        let cycles = tsc::rdtsc();
+        Stopwatch {
+            start: now,
+            start_cycles: cycles,
+            lap: now,
+            lap_cycles: cycles,
+        }
+    }
+
+    /// The time elapsed since the stopwatch was started.
+    pub fn elapsed(&self) -> Elapsed {
+        Elapsed {
+            cycles: tsc::rdtsc().wrapping_sub(self.start_cycles),
+            duration: self.start.elapsed(),
+        }
+    }
+
+    /// The time elapsed since the previous lap or the start, and start the next lap.
+    pub fn lap(&mut self) -> Elapsed {
+        let now = Instant::now();
+        let cycles = tsc::rdtsc();
+        let lap = Elapsed {
+            cycles: cycles.wrapping_sub(self.lap_cycles),
+            duration: now.duration_since(self.lap),
+        };
+        self.lap = now;
+        self.lap_cycles = cycles;
+        lap
+    }
+
+    /// Restart the stopwatch at the current time.
+    pub fn restart(&mut self) {
+        *self = Stopwatch::start();
+    }
+}
+
+impl fmt::Display for Stopwatch {
+    /// The time elapsed since the start.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.elapsed().fmt(f)
+    }
+}
+
+/// Evaluates a block and logs the time it took at the info level, with the current module path as
+/// the target. Evaluates to the value of the block.
+///
+/// ```ignore
+/// let frames = measure!("frame scan", { allocator.usable_frames().count() });
+/// ```
+#[macro_export]
+macro_rules! measure {
+    ($label:expr, $body:block) => {{
+        let stopwatch = $crate::time::Stopwatch::start();
+        let value = $body;
+        $crate::info!("{}: {}", $label, stopwatch.elapsed());
+        value
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::delay_us;
+
+    #[test_case]
+    fn measure_laps() {
+        let mut stopwatch = Stopwatch::start();
+        delay_us(100);
+        let first = stopwatch.lap();
+        let value = crate::measure!("test block", {
+            delay_us(100);
+            42
+        });
+        let second = stopwatch.lap();
+
+        assert_eq!(value, 42);
+        assert!(first.duration >= Duration::from_micros(99));
+        assert!(second.duration >= Duration::from_micros(99));
+        assert!(stopwatch.elapsed().cycles >= first.cycles + second.cycles);
+    }
+}
@@ -0,0 +1,106 @@
+//! The time stamp counter, calibrated against the HPET or the PIT at boot.
+//!
+//! The TSC is the cheapest clock there is, a single instruction without any port access. Its
+//! frequency is unknown to the kernel and measured by [calibrate] over a known number of HPET or
+//! PIT periods. On processors without an invariant TSC the counter may change rate with the power
+//! state of the core, [is_invariant] tells whether measurements in cycles can be trusted.
+
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::{hpet, pit};
+
+/// The time the TSC is calibrated over.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+/// Number of PIT periods in [CALIBRATION_TIME].
+const CALIBRATION_PIT_COUNT: u16 = (pit::BASE_FREQUENCY_HZ / 100) as u16;
+
+/// Calibrated frequency of the TSC, 0 before calibration.
+static FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Read the time stamp counter.
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    // # Safety
+    // RDTSC is available on all x86_64 processors and has no side effect.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Read the time stamp counter after all the preceding instructions completed, and before any of
+/// the following instructions starts. For measuring short spans of code.
+#[inline(always)]
+pub fn rdtsc_ordered() -> u64 {
+    // # Safety
+    // LFENCE only orders instructions, it's available on all x86_64 processors.
+    unsafe {
+        asm!("lfence", options(nomem, nostack, preserves_flags));
+    }
+    let tsc = rdtsc();
+    // # Safety
+    // As above.
+    unsafe {
+        asm!("lfence", options(nomem, nostack, preserves_flags));
+    }
+    tsc
+}
+
+/// Whether the TSC runs at a constant rate in all power states, reported by CPUID.
+pub fn is_invariant() -> bool {
+    const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+    const INVARIANT_TSC: u32 = 1 << 8;
+
+    // # Safety
+    // CPUID is available on all x86_64 processors, the leaf is checked against the highest
+    // supported extended leaf before use.
+    unsafe {
+        if __cpuid(0x8000_0000).eax < ADVANCED_POWER_MANAGEMENT {
+            return false;
+        }
+        __cpuid(ADVANCED_POWER_MANAGEMENT).edx & INVARIANT_TSC != 0
+    }
+}
+
+/// Measure the frequency of the TSC over [CALIBRATION_TIME], by the main counter of the HPET if
+/// there is one or PIT channel 2 otherwise. Interrupts should be disabled so that no handler
+/// stretches the measurement.
+pub fn calibrate() -> u64 {
+    let hz = match hpet::get() {
+        Some(hpet) => {
+            let start_count = hpet.counter();
+            let start = rdtsc();
+            hpet.wait_until(start_count + hpet.duration_to_counts(CALIBRATION_TIME));
+            let cycles = rdtsc() - start;
+            let counts = hpet.counter() - start_count;
+            (u128::from(cycles) * u128::from(hpet.frequency_hz()) / u128::from(counts)) as u64
+        }
+        None => {
+            let mut start = 0;
+            pit::wait_channel_2(CALIBRATION_PIT_COUNT, || start = rdtsc());
+            let cycles = rdtsc() - start;
+            cycles * pit::BASE_FREQUENCY_HZ / u64::from(CALIBRATION_PIT_COUNT)
+        }
+    };
+    FREQUENCY_HZ.store(hz, Ordering::Relaxed);
+    hz
+}
+
+/// The calibrated frequency of the TSC, 0 before [calibrate].
+pub fn frequency_hz() -> u64 {
+    FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+/// Convert `cycles` of the TSC to nanoseconds, 0 before [calibrate].
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    match frequency_hz() {
+        0 => 0,
+        hz => (u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64,
+    }
+}
+
+/// Convert `nanos` nanoseconds to cycles of the TSC, 0 before [calibrate].
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    (u128::from(nanos) * u128::from(frequency_hz()) / 1_000_000_000) as u64
+}
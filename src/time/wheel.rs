@@ -0,0 +1,197 @@
+//! A timer wheel waking tasks at deadlines in timer ticks.
+//!
+//! Timers are hashed into [SLOTS] slots by their deadline, expiring the timers of a tick only
+//! looks at a single slot. Timers further than a lap of the wheel away stay in their slot until
+//! the lap they expire in. Timers are expired by the executor between polls rather than by the
+//! timer interrupt handler, so that waking and dropping wakers never happens in interrupt context.
+//! Interrupt handlers only read [next_expiry] to program a one-shot deadline.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Number of slots of the wheel.
+const SLOTS: usize = 256;
+
+/// Deadline of the earliest timer, [NO_TIMER] if there is none.
+static NEXT_EXPIRY: AtomicU64 = AtomicU64::new(NO_TIMER);
+const NO_TIMER: u64 = u64::MAX;
+
+struct Wheel {
+    slots: Vec<Vec<(u64, Waker)>>,
+    /// The last tick whose timers have been expired.
+    expired: u64,
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Wheel {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            expired: 0,
+        }
+    }
+
+    fn insert(&mut self, deadline: u64, waker: Waker) {
+        // timers already due go to the slot swept next
+        let tick = deadline.max(self.expired + 1);
+        self.slots[tick as usize % SLOTS].push((deadline, waker));
+    }
+
+    /// Move the wakers of the timers due at `now` to `expired`.
+    fn expire(&mut self, now: u64, expired: &mut Vec<Waker>) {
+        if now <= self.expired {
+            return;
+        }
+
+        let lap = (now - self.expired).min(SLOTS as u64);
+        for tick in now + 1 - lap..=now {
+            let slot = &mut self.slots[tick as usize % SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= now {
+                    expired.push(slot.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.expired = now;
+    }
+
+    fn next_expiry(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|(deadline, _)| *deadline)
+            .min()
+    }
+}
+
+lazy_static! {
+    static ref WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+}
+
+/// Wake `waker` once [expire] is called at or after the tick `deadline`. A deadline already passed
+/// wakes on the next tick.
+pub fn register(deadline: u64, waker: Waker) {
+    interrupts::without_interrupts(|| {
+        WHEEL.lock().insert(deadline, waker);
+        NEXT_EXPIRY.fetch_min(deadline, Ordering::Relaxed);
+    });
+}
+
+/// The deadline of the earliest timer, `None` if there is no timer. Never blocks, safe to call in
+/// interrupt handlers.
+pub fn next_expiry() -> Option<u64> {
+    match NEXT_EXPIRY.load(Ordering::Relaxed) {
+        NO_TIMER => None,
+        deadline => Some(deadline),
+    }
+}
+
+/// Wake the timers with deadlines at or before `now`. Must not be called in interrupt handlers.
+pub fn expire(now: u64) {
+    if next_expiry().map_or(true, |deadline| deadline > now) {
+        return;
+    }
+
+    let mut expired = Vec::new();
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        wheel.expire(now, &mut expired);
+        let next = wheel.next_expiry().unwrap_or(NO_TIMER);
+        NEXT_EXPIRY.store(next, Ordering::Relaxed);
+    });
+
+    // wakers may do anything, they're called without the lock
+    for waker in expired {
+        waker.wake();
+    }
+}
+
+/// A future ready once a duration has passed, woken by the timer wheel.
+pub struct Delay {
+    deadline: u64,
+    registered: bool,
+}
+
+impl Delay {
+    /// Ready once `duration` has passed.
+    pub fn new(duration: Duration) -> Self {
+        // the current tick is already partly over
+        Delay {
+            deadline: super::ticks() + super::duration_to_ticks(duration) + 1,
+            registered: false,
+        }
+    }
+
+    /// Ready once [super::ticks] reaches `deadline`.
+    pub fn until(deadline: u64) -> Self {
+        Delay {
+            deadline,
+            registered: false,
+        }
+    }
+
+    /// The tick the delay is ready at.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if super::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        if !self.registered {
+            register(self.deadline, cx.waker().clone());
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{sync::Arc, task::Wake};
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test_case]
+    fn expire_in_order() {
+        let waker = Waker::from(Arc::new(Noop));
+        let mut wheel = Wheel::new();
+        let mut expired = Vec::new();
+
+        wheel.insert(10, waker.clone());
+        wheel.insert(10 + SLOTS as u64, waker.clone());
+        assert_eq!(wheel.next_expiry(), Some(10));
+
+        wheel.expire(10, &mut expired);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(wheel.next_expiry(), Some(10 + SLOTS as u64));
+
+        // a timer already due expires on the next tick
+        wheel.insert(5, waker);
+        wheel.expire(10 + SLOTS as u64, &mut expired);
+        assert_eq!(expired.len(), 3);
+        assert_eq!(wheel.next_expiry(), None);
+    }
+}
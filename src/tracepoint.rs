@@ -0,0 +1,299 @@
+//! Lightweight event tracing for debugging the ordering of events between interrupt handlers, the
+//! executor and drivers.
+//!
+//! Events are emitted by [trace_event] into a fixed-size ring without locking, so tracepoints can
+//! be placed anywhere including interrupt handlers. Each subsystem is enabled separately, a
+//! disabled tracepoint costs one atomic load.
+//!
+//! Each slot of the ring is guarded by a sequence number in the fashion of a seqlock: the writer
+//! clears the sequence number before writing the slot and sets it afterwards, the reader discards
+//! the slot if the sequence number changed while reading it.
+
+use alloc::vec::Vec;
+use core::{
+    fmt, ptr, str,
+    sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{klog::Message, time::tsc};
+
+/// Number of events kept in the ring, the oldest event is overwritten first.
+const RING_SIZE: usize = 1024;
+/// Number of words of the formatted message of an event.
+const MESSAGE_WORDS: usize = 6;
+/// Maximum length of the formatted message of an event in bytes.
+pub const MESSAGE_SIZE: usize = MESSAGE_WORDS * 8;
+
+/// The subsystems tracepoints are grouped into, each can be enabled separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Subsystem {
+    /// Interrupt and exception handlers.
+    Interrupts,
+    /// The task executor.
+    Executor,
+    /// Memory management.
+    Memory,
+    /// Device drivers.
+    Drivers,
+}
+
+impl Subsystem {
+    /// All the subsystems.
+    pub const ALL: [Subsystem; 4] = [
+        Subsystem::Interrupts,
+        Subsystem::Executor,
+        Subsystem::Memory,
+        Subsystem::Drivers,
+    ];
+
+    /// The lowercase name of the subsystem.
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Interrupts => "interrupts",
+            Subsystem::Executor => "executor",
+            Subsystem::Memory => "memory",
+            Subsystem::Drivers => "drivers",
+        }
+    }
+
+    /// Find a subsystem by its name.
+    pub fn from_name(name: &str) -> Option<Subsystem> {
+        Subsystem::ALL.iter().copied().find(|s| s.name() == name)
+    }
+
+    fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// A tracepoint, one static instance per invocation of [trace_event]. Its address identifies the
+/// events emitted by it.
+#[derive(Debug)]
+pub struct Site {
+    /// The subsystem of the tracepoint.
+    pub subsystem: Subsystem,
+    /// The format string of the tracepoint.
+    pub format: &'static str,
+    /// The source file of the tracepoint.
+    pub file: &'static str,
+    /// The source line of the tracepoint.
+    pub line: u32,
+}
+
+impl Site {
+    /// The event id of the tracepoint, unique among all tracepoints.
+    pub fn id(&'static self) -> usize {
+        self as *const Site as usize
+    }
+}
+
+/// An event read from the ring.
+#[derive(Clone)]
+pub struct Event {
+    /// The sequence number of the event, starting from 0 on boot.
+    pub seq: u64,
+    /// The time stamp counter when the event was emitted.
+    pub timestamp: u64,
+    /// The processor which emitted the event.
+    pub cpu: u32,
+    /// The tracepoint which emitted the event.
+    pub site: &'static Site,
+    message: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Event {
+    /// The formatted message of the event, possibly truncated.
+    pub fn message(&self) -> &str {
+        str::from_utf8(&self.message[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} {:>16} cpu{} {:<10} {}:{} {}",
+            self.seq,
+            self.timestamp,
+            self.cpu,
+            self.site.subsystem,
+            self.site.file,
+            self.site.line,
+            self.message()
+        )
+    }
+}
+
+struct Slot {
+    /// the sequence number of the event plus one, 0 while the slot is being written
+    seq: AtomicU64,
+    timestamp: AtomicU64,
+    cpu: AtomicU32,
+    site: AtomicPtr<Site>,
+    len: AtomicUsize,
+    message: [AtomicU64; MESSAGE_WORDS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    Slot {
+        seq: AtomicU64::new(0),
+        timestamp: AtomicU64::new(0),
+        cpu: AtomicU32::new(0),
+        site: AtomicPtr::new(ptr::null_mut()),
+        len: AtomicUsize::new(0),
+        message: [ZERO; MESSAGE_WORDS],
+    }
+};
+
+static RING: [Slot; RING_SIZE] = [EMPTY; RING_SIZE];
+/// The sequence number of the next event.
+static HEAD: AtomicU64 = AtomicU64::new(0);
+/// Bit mask of the enabled subsystems, indexed by [Subsystem].
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Enable or disable the tracepoints of `subsystem`.
+pub fn set_enabled(subsystem: Subsystem, enabled: bool) {
+    if enabled {
+        ENABLED.fetch_or(subsystem.mask(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!subsystem.mask(), Ordering::Relaxed);
+    }
+}
+
+/// Whether the tracepoints of `subsystem` are enabled.
+pub fn is_enabled(subsystem: Subsystem) -> bool {
+    ENABLED.load(Ordering::Relaxed) & subsystem.mask() != 0
+}
+
+#[doc(hidden)]
+pub fn _emit(site: &'static Site, args: fmt::Arguments) {
+    use fmt::Write;
+
+    // format before claiming a slot, the slot is then written in a short window
+    let mut message = Message::<MESSAGE_SIZE>::new();
+    let _ = message.write_fmt(args);
+
+    let timestamp = tsc::rdtsc();
+    let seq = HEAD.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING[seq as usize % RING_SIZE];
+
+    slot.seq.store(0, Ordering::Relaxed);
+    // readers must see the cleared sequence number before any of the new content
+    fence(Ordering::Release);
+    slot.timestamp.store(timestamp, Ordering::Relaxed);
+    // single processor until the application processors are brought up
+    slot.cpu.store(0, Ordering::Relaxed);
+    slot.site
+        .store(site as *const Site as *mut Site, Ordering::Relaxed);
+    slot.len.store(message.as_str().len(), Ordering::Relaxed);
+    for (word, chunk) in slot.message.iter().zip(message.buffer().chunks_exact(8)) {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(chunk);
+        word.store(u64::from_ne_bytes(bytes), Ordering::Relaxed);
+    }
+    slot.seq.store(seq + 1, Ordering::Release);
+}
+
+/// Read a consistent copy of `slot`, `None` if the slot is empty or being written.
+fn read_slot(slot: &Slot) -> Option<Event> {
+    let seq = slot.seq.load(Ordering::Acquire);
+    if seq == 0 {
+        return None;
+    }
+
+    let timestamp = slot.timestamp.load(Ordering::Relaxed);
+    let cpu = slot.cpu.load(Ordering::Relaxed);
+    let site = slot.site.load(Ordering::Relaxed);
+    let len = slot.len.load(Ordering::Relaxed);
+    let mut message = [0; MESSAGE_SIZE];
+    for (chunk, word) in message.chunks_exact_mut(8).zip(slot.message.iter()) {
+        chunk.copy_from_slice(&word.load(Ordering::Relaxed).to_ne_bytes());
+    }
+
+    // the content read above must not be reordered after the second check
+    fence(Ordering::Acquire);
+    if slot.seq.load(Ordering::Relaxed) != seq {
+        return None;
+    }
+
+    // # Safety
+    // `site` of a slot with a non-zero sequence number is always a `&'static Site` stored by
+    // [_emit], the sequence number didn't change while the slot is read.
+    let site = unsafe { &*site };
+    Some(Event {
+        seq: seq - 1,
+        timestamp,
+        cpu,
+        site,
+        message,
+        len,
+    })
+}
+
+/// Copy out the events still in the ring in ascending order of sequence numbers. Events being
+/// written during the call are skipped.
+pub fn events() -> Vec<Event> {
+    let mut events: Vec<Event> = RING.iter().filter_map(read_slot).collect();
+    events.sort_unstable_by_key(|event| event.seq);
+    events
+}
+
+/// Discard all the events in the ring.
+pub fn clear() {
+    for slot in RING.iter() {
+        slot.seq.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Emit a trace event from `subsystem`, formatted like [format_args]. The message is truncated to
+/// [MESSAGE_SIZE] bytes.
+///
+/// ```ignore
+/// trace_event!(Executor, "poll task {}", task_id);
+/// ```
+#[macro_export]
+macro_rules! trace_event {
+    ($subsystem:ident, $format:literal $($arg:tt)*) => {{
+        if $crate::tracepoint::is_enabled($crate::tracepoint::Subsystem::$subsystem) {
+            static SITE: $crate::tracepoint::Site = $crate::tracepoint::Site {
+                subsystem: $crate::tracepoint::Subsystem::$subsystem,
+                format: $format,
+                file: file!(),
+                line: line!(),
+            };
+            $crate::tracepoint::_emit(&SITE, format_args!($format $($arg)*));
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn emit_when_enabled() {
+        let start = HEAD.load(Ordering::Relaxed);
+        trace_event!(Drivers, "disabled {}", 0);
+        set_enabled(Subsystem::Drivers, true);
+        trace_event!(Drivers, "enabled {}", 1);
+        set_enabled(Subsystem::Drivers, false);
+
+        let events: Vec<Event> = events()
+            .into_iter()
+            .filter(|event| event.seq >= start)
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message(), "enabled 1");
+        assert_eq!(events[0].site.subsystem, Subsystem::Drivers);
+    }
+}
@@ -0,0 +1,181 @@
+//! Stack backtraces by walking the chain of saved frame pointers.
+//!
+//! The kernel is built with frame pointers (see `.cargo/config.toml`), so every function starts by
+//! pushing the frame pointer of its caller and pointing RBP at it:
+//!
+//! ```text
+//! [rbp + 8]  return address into the caller
+//! [rbp]      RBP of the caller
+//! ```
+//!
+//! Walking this chain requires no allocation nor locking, which makes it usable from the panic
+//! handler and exception handlers. Any frame pointer that doesn't point to mapped memory ends the
+//! walk instead of faulting.
+
+use core::fmt;
+
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+use crate::memory;
+
+/// Maximum number of frames walked, in case the chain of frame pointers is corrupted into a loop.
+pub const MAX_DEPTH: usize = 64;
+
+/// An iterator over the return addresses on the stack, from the innermost frame to the outermost.
+#[derive(Debug, Clone)]
+pub struct Frames {
+    /// the address of the instruction that raised an exception, reported before the frames
+    first: Option<VirtAddr>,
+    rbp: u64,
+    depth: usize,
+}
+
+impl Frames {
+    fn new(first: Option<VirtAddr>, rbp: u64) -> Self {
+        Self {
+            first,
+            rbp,
+            depth: 0,
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = VirtAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(first) = self.first.take() {
+            return Some(first);
+        }
+
+        if self.depth >= MAX_DEPTH {
+            return None;
+        }
+        let (caller_rbp, return_address) = read_frame(self.rbp)?;
+        let return_address = VirtAddr::try_new(return_address).ok()?;
+        if return_address.is_null() {
+            return None;
+        }
+
+        // the stack grows downwards, frames of callers are always at higher addresses
+        self.rbp = if caller_rbp > self.rbp { caller_rbp } else { 0 };
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+impl fmt::Display for Frames {
+    /// One return address per line, intended to be symbolized on the host by `addr2line`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, address) in self.clone().enumerate() {
+            writeln!(f, "{:>4}: {:#018x}", i, address.as_u64())?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the saved RBP and return address of the frame at `rbp`, `None` if `rbp` doesn't point to
+/// a readable frame.
+fn read_frame(rbp: u64) -> Option<(u64, u64)> {
+    if rbp == 0 || rbp % 8 != 0 {
+        return None;
+    }
+    let frame = VirtAddr::try_new(rbp).ok()?;
+    // before the page tables can be inspected the walk can only trust the boot stack
+    if memory::is_initialized() && !memory::is_mapped(frame, 16, false) {
+        return None;
+    }
+
+    let frame = frame.as_ptr::<u64>();
+    // # Safety
+    // `frame` is aligned and points to mapped memory. The content may be garbage if the chain is
+    // corrupted, which is dealt with by the checks of the caller.
+    unsafe { Some((frame.read_volatile(), frame.add(1).read_volatile())) }
+}
+
+/// Read the frame pointer of the current function.
+#[inline(always)]
+fn read_rbp() -> u64 {
+    let rbp: u64;
+    // # Safety
+    // Reading a register has no side effect.
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// The return addresses on the stack, starting from the caller of this function.
+#[inline(never)]
+pub fn backtrace() -> Frames {
+    // never inlined, RBP points to the frame of this function whose return address is in the
+    // caller. The frame is gone once this function returns, it's read now and the iterator starts
+    // from the frame of the caller.
+    match read_frame(read_rbp()) {
+        Some((caller_rbp, return_address)) => {
+            Frames::new(VirtAddr::try_new(return_address).ok(), caller_rbp)
+        }
+        None => Frames::new(None, 0),
+    }
+}
+
+/// The return addresses on the stack of the code interrupted by an exception, starting from the
+/// faulting instruction. Must be called directly from the exception handler.
+#[inline(never)]
+pub fn exception_backtrace(stack_frame: &InterruptStackFrame) -> Frames {
+    // the return address of the handler frame is either the interrupt stack frame or the error
+    // code pushed by the CPU, skip to the frame of the interrupted code
+    let interrupted_rbp = read_frame(read_rbp())
+        .and_then(|(handler_rbp, _)| read_frame(handler_rbp))
+        .map_or(0, |(interrupted_rbp, _)| interrupted_rbp);
+    Frames::new(Some(stack_frame.instruction_pointer), interrupted_rbp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{format, string::String};
+
+    #[inline(never)]
+    fn nested(depth: usize) -> usize {
+        if depth == 0 {
+            backtrace().count()
+        } else {
+            let count = nested(depth - 1);
+            // keep the call from being a tail call, the frame of this function must stay on the
+            // stack
+            //
+            // # Safety
+            // `count` is a valid local variable.
+            unsafe { core::ptr::read_volatile(&count) }
+        }
+    }
+
+    #[inline(never)]
+    fn format_backtrace() -> String {
+        // the formatting calls reuse the stack below the frame of this function
+        format!("{}", backtrace())
+    }
+
+    #[test_case]
+    fn first_frame_in_caller() {
+        let output = format_backtrace();
+        let first = output
+            .lines()
+            .nth(1)
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|address| u64::from_str_radix(address.trim_start_matches("0x"), 16).ok())
+            .expect("no frame in the backtrace");
+        let caller = format_backtrace as usize as u64;
+        assert!(first > caller && first < caller + 0x1000);
+    }
+
+    #[test_case]
+    fn walk_nested_frames() {
+        let shallow = nested(0);
+        let deep = nested(5);
+        assert!(shallow >= 1);
+        assert_eq!(deep, (shallow + 5).min(MAX_DEPTH));
+    }
+}
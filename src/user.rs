@@ -0,0 +1,697 @@
+//! Running programs in ring 3.
+//!
+//! A [Program] runs in its own [AddressSpace]: the mappings of the kernel, out of reach of ring 3,
+//! and its private pages in the user region, raw machine code loaded at [PROGRAM_START] by [load]
+//! or a static ELF64 executable loaded by [loader]. [resume] switches to the address space and
+//! enters ring 3 with `iretq`, restoring the [Registers] of the program. The interrupts raised in
+//! ring 3 are handled on the kernel stack of the program, or below the frame of [resume] for
+//! programs without one, and return to the program.
+//!
+//! The program leaves ring 3 through the handlers of the exceptions and of the
+//! [system calls](syscall), which jump back to [resume] with [leave] instead of returning: when it
+//! exits, faults or executes a breakpoint, `int3`, and when a system call blocks or yields, its
+//! registers saved to go on later. A program running for a whole time slice without a system call
+//! is preempted: the timer interrupt handler returns to [preempted] in ring 0 instead, which saves
+//! the registers and leaves as a yield does, see [preempt]. [resume] switches back to the address
+//! space of the kernel and returns why the program left, a blocked call with the future it waits
+//! on. [run] drives a program to its exit on the current stack, [crate::process] drives the
+//! processes as tasks.
+//!
+//! The system calls act on the [Program] being resumed: its process id, its descriptor table, the
+//! console open as its standard input and outputs, and its [Heap], the pages of which are mapped
+//! when the program first faults on them.
+
+use alloc::{boxed::Box, vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::segmentation,
+    registers::{control::Cr3, rflags::RFlags},
+    structures::idt::InterruptStackFrame,
+    PrivilegeLevel, VirtAddr,
+};
+
+use crate::{
+    fs::fd::FdTable, gdt, interrupts, metrics::Counter, process::Pid, resume::ResumePoint,
+    scheduler::TIME_SLICE_TICKS, task, time,
+};
+
+pub mod address_space;
+pub mod heap;
+pub mod loader;
+pub mod syscall;
+
+pub use address_space::{AddressSpace, USER_SIZE, USER_START};
+pub use heap::Heap;
+
+/// Where [load] loads a program.
+pub const PROGRAM_START: u64 = USER_START + 0x40_0000;
+/// The size of the stack [load] maps for a program.
+pub const STACK_SIZE: u64 = 16 * 1024;
+/// One byte beyond the end of the stack [load] maps for a program, a page below the end of the
+/// user region.
+pub const STACK_TOP: u64 = USER_START + USER_SIZE - 4096;
+/// The size of the kernel stack of a program, see [Program::with_kernel_stack].
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// The flags of a program entering ring 3: interrupts enabled and the reserved bit 1.
+const ENTRY_FLAGS: u64 = 0x202;
+/// The length of both `syscall` and `int 0x80`, stepped back over to restart a system call.
+const SYSCALL_LENGTH: u64 = 2;
+
+/// The innermost program running.
+static RUNNING: ResumePoint = ResumePoint::new();
+static LEFT: Mutex<Option<Leave>> = Mutex::new(None);
+/// The program being resumed, see [with_program].
+static CURRENT: AtomicPtr<Program> = AtomicPtr::new(ptr::null_mut());
+/// The tick at which the program being resumed is preempted, see [preempt].
+static SLICE_END: AtomicU64 = AtomicU64::new(0);
+
+static PREEMPTIONS: Counter = Counter::new("user.preemptions");
+
+/// An error setting up the address space of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    /// No physical frame is left.
+    OutOfMemory,
+    /// The range is empty or not within the user region.
+    OutOfRange,
+    /// A page of the range is mapped already.
+    AlreadyMapped,
+    /// A page of the range isn't mapped.
+    NotMapped,
+    /// The executable is malformed or not for this machine, see [loader].
+    BadExecutable(&'static str),
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserError::OutOfMemory => write!(f, "out of physical memory"),
+            UserError::OutOfRange => write!(f, "range outside of the user region"),
+            UserError::AlreadyMapped => write!(f, "page already mapped"),
+            UserError::NotMapped => write!(f, "page not mapped"),
+            UserError::BadExecutable(reason) => write!(f, "bad executable: {}", reason),
+        }
+    }
+}
+
+/// How a program left ring 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The program called `exit` with the status.
+    Exited(i32),
+    /// The program executed `int3`.
+    Breakpoint,
+    /// The program raised an exception.
+    Exception {
+        /// The vector of the exception.
+        vector: u8,
+        /// The error code pushed by the CPU, `None` for exceptions without error codes.
+        error_code: Option<u64>,
+        /// The address of the faulting instruction.
+        instruction: VirtAddr,
+        /// The accessed address of a page fault.
+        address: Option<VirtAddr>,
+    },
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Exit::Exited(status) => write!(f, "exited with status {}", status),
+            Exit::Breakpoint => write!(f, "breakpoint"),
+            Exit::Exception {
+                vector,
+                error_code,
+                instruction,
+                address,
+            } => {
+                let name = interrupts::vector_name(vector).unwrap_or("exception");
+                write!(f, "{} at {:?}", name, instruction)?;
+                if let Some(error_code) = error_code {
+                    write!(f, ", error code {:#x}", error_code)?;
+                }
+                if let Some(address) = address {
+                    write!(f, ", accessing {:?}", address)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Exit {
+    /// The exit status as shells report it: the status passed to `exit`, 0 after a breakpoint and
+    /// 128 plus the vector after an exception.
+    pub fn status(&self) -> i32 {
+        match *self {
+            Exit::Exited(status) => status,
+            Exit::Breakpoint => 0,
+            Exit::Exception { vector, .. } => 128 + i32::from(vector),
+        }
+    }
+}
+
+/// The registers of a program in ring 3, in the order the entries of the system calls push them
+/// below the interrupt frame. The fields are named after the registers.
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    /// The number of a system call, then its result.
+    pub rax: u64,
+    /// The address of the next instruction.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Registers {
+    /// The registers of a program starting at `entry` with the stack `stack` and `arg` in `rdi`,
+    /// interrupts enabled, nothing of the kernel left elsewhere.
+    pub fn new(entry: VirtAddr, stack: VirtAddr, arg: u64) -> Self {
+        let selectors = gdt::selectors();
+        Registers {
+            rdi: arg,
+            rip: entry.as_u64(),
+            cs: u64::from(selectors.user_code.0),
+            rflags: ENTRY_FLAGS,
+            rsp: stack.as_u64(),
+            ss: u64::from(selectors.user_data.0),
+            ..Registers::default()
+        }
+    }
+}
+
+/// How a blocked system call goes on once what it waited for happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Make the call again, e.g. to read the data which arrived.
+    Restart,
+    /// Return the value from the call.
+    Return(u64),
+}
+
+/// The future a blocked system call waits on.
+pub type Wait = Pin<Box<dyn Future<Output = Completion> + Send>>;
+
+/// Why a program left ring 3.
+pub enum Leave {
+    /// The program ended.
+    Exited(Exit),
+    /// A system call waits on the future, see [Program::complete].
+    Blocked(Wait),
+    /// The program gave the CPU up, it can be resumed at once.
+    Yielded,
+}
+
+/// A program with what its system calls act on.
+pub struct Program {
+    pid: Pid,
+    /// The address space.
+    pub space: AddressSpace,
+    /// The registers of the program, saved when it last left ring 3 in a system call.
+    pub registers: Registers,
+    /// The open files, the console on the standard descriptors at first.
+    pub fds: FdTable,
+    /// The heap and the anonymous mappings, in [Program::space].
+    pub heap: Heap,
+    kernel_stack: Option<Box<[u8]>>,
+}
+
+impl Program {
+    /// Load `code` as [load] does, to start at its first byte with `arg` in `rdi`. The program gets
+    /// a new process id.
+    pub fn load(code: &[u8], arg: u64) -> Result<Self, UserError> {
+        Ok(Program {
+            pid: Pid::next(),
+            space: load(code)?,
+            registers: Registers::new(VirtAddr::new(PROGRAM_START), VirtAddr::new(STACK_TOP), arg),
+            fds: FdTable::with_console(),
+            heap: Heap::new(VirtAddr::new(PROGRAM_START + code.len() as u64)),
+            kernel_stack: None,
+        })
+    }
+
+    /// Load the static ELF64 executable `image` as [loader::load] does, to start at its entry point
+    /// with `arg` in `rdi`. The heap starts after its highest segment. The program gets a new
+    /// process id.
+    pub fn load_elf(image: &[u8], arg: u64) -> Result<Self, UserError> {
+        let loaded = loader::load(image)?;
+        Ok(Program {
+            pid: Pid::next(),
+            space: loaded.space,
+            registers: Registers::new(loaded.entry, loaded.stack, arg),
+            fds: FdTable::with_console(),
+            heap: Heap::new(loaded.end),
+            kernel_stack: None,
+        })
+    }
+
+    /// Load `image` with [Program::load_elf] if it's an ELF file, see [loader::is_elf], as raw
+    /// machine code with [Program::load] otherwise.
+    pub fn load_any(image: &[u8], arg: u64) -> Result<Self, UserError> {
+        if loader::is_elf(image) {
+            Program::load_elf(image, arg)
+        } else {
+            Program::load(image, arg)
+        }
+    }
+
+    /// Give the program a kernel stack of [KERNEL_STACK_SIZE] bytes for the interrupts and the
+    /// system calls raised in ring 3, instead of the stack of the caller of [resume].
+    pub fn with_kernel_stack(mut self) -> Self {
+        self.kernel_stack = Some(vec![0; KERNEL_STACK_SIZE].into_boxed_slice());
+        self
+    }
+
+    /// The process id.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Go on with the system call which blocked once its future is ready with `completion`.
+    pub fn complete(&mut self, completion: Completion) {
+        match completion {
+            Completion::Restart => self.registers.rip -= SYSCALL_LENGTH,
+            Completion::Return(value) => self.registers.rax = value,
+        }
+    }
+
+    fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.kernel_stack
+            .as_ref()
+            .map(|stack| (VirtAddr::from_ptr(stack.as_ptr()) + stack.len()).align_down(16u64))
+    }
+}
+
+/// Call `f` on the program being resumed, `None` if there is none. For the system calls of the
+/// program, `f` must not call it again.
+pub(crate) fn with_program<R>(f: impl FnOnce(&mut Program) -> R) -> Option<R> {
+    // # Safety
+    // [resume] borrows the program mutably for as long as it's the current one and doesn't touch
+    // it in the meantime. The system calls of the program run one at a time.
+    unsafe { CURRENT.load(Ordering::SeqCst).as_mut() }.map(f)
+}
+
+/// Map the page of `addr` for the program being resumed if it reserved it, see
+/// [AddressSpace::fault_in]. Returns whether the access which faulted can be retried.
+pub fn handle_page_fault(addr: VirtAddr, write: bool) -> bool {
+    with_program(|program| program.space.fault_in(addr, write)).unwrap_or(false)
+}
+
+/// Whether the interrupt of `stack_frame` was raised in ring 3.
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == PrivilegeLevel::Ring3 as u64
+}
+
+/// Load `code` at [PROGRAM_START] into a new address space, read-only, with a stack of
+/// [STACK_SIZE] bytes below [STACK_TOP].
+pub fn load(code: &[u8]) -> Result<AddressSpace, UserError> {
+    let mut space = AddressSpace::new()?;
+    space.map(VirtAddr::new(PROGRAM_START), code.len() as u64, false)?;
+    space.write(VirtAddr::new(PROGRAM_START), code)?;
+    map_stack(&mut space)?;
+    Ok(space)
+}
+
+/// Map the stack of a program, [STACK_SIZE] bytes below [STACK_TOP], into `space`.
+fn map_stack(space: &mut AddressSpace) -> Result<(), UserError> {
+    space.map(VirtAddr::new(STACK_TOP - STACK_SIZE), STACK_SIZE, true)
+}
+
+/// Run `code` as a program until it exits, see [Program::load]. The blocked system calls wait on
+/// the current stack.
+pub fn run(code: &[u8], arg: u64) -> Result<(Program, Exit), UserError> {
+    Ok(run_program(Program::load(code, arg)?))
+}
+
+/// Run `program` until it exits, as [run] does.
+pub fn run_program(mut program: Program) -> (Program, Exit) {
+    loop {
+        match resume(&mut program) {
+            Leave::Exited(exit) => return (program, exit),
+            Leave::Blocked(wait) => {
+                let completion = task::block_on(wait);
+                program.complete(completion);
+            }
+            Leave::Yielded => {}
+        }
+    }
+}
+
+/// Switch to the address space of `program` and go on running it in ring 3 with its registers,
+/// returns once it leaves ring 3.
+pub fn resume(program: &mut Program) -> Leave {
+    let (kernel_table, flags) = Cr3::read();
+    let kernel_stack = gdt::kernel_stack();
+    *LEFT.lock() = None;
+    let registers: *const Registers = &program.registers;
+    let stack = program.kernel_stack_top();
+    // # Safety
+    // The address space maps the kernel as the active one does.
+    unsafe { Cr3::write(program.space.level_4_frame(), flags) };
+    let outer = CURRENT.swap(program, Ordering::SeqCst);
+    SLICE_END.store(time::hardware_ticks() + TIME_SLICE_TICKS, Ordering::Relaxed);
+
+    RUNNING.catch(&|| {
+        // # Safety
+        // The stack of the closure isn't used anymore once in ring 3, its frames are discarded by
+        // [leave].
+        unsafe { restore(registers, stack) }
+    });
+    // # Safety
+    // The address space of the kernel was active before, the program doesn't run anymore. The
+    // interrupts raised in ring 3 left a null stack segment behind.
+    unsafe {
+        Cr3::write(kernel_table, flags);
+        gdt::set_kernel_stack(kernel_stack);
+        segmentation::load_ss(gdt::selectors().kernel_data);
+    }
+    CURRENT.store(outer, Ordering::SeqCst);
+
+    LEFT.lock()
+        .take()
+        .expect("a program left ring 3 without a reason")
+}
+
+/// Enter ring 3 with `registers`, the interrupts raised there are handled on `kernel_stack`, or
+/// below the current frame if `None`.
+///
+/// # Safety
+/// The active address space must map the code and the stack of the registers for ring 3, the
+/// frames on the current stack are only left through [leave].
+unsafe fn restore(registers: *const Registers, kernel_stack: Option<VirtAddr>) -> ! {
+    let rsp: u64;
+    asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    // the interrupt frames of ring 3 are pushed below this frame, which is never returned to
+    gdt::set_kernel_stack(kernel_stack.unwrap_or_else(|| VirtAddr::new(rsp)));
+
+    // the registers are popped off their saved copy, no interrupt frame may be pushed onto it
+    x86_64::instructions::interrupts::disable();
+    asm!(
+        "mov rsp, {}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdi",
+        "pop rsi",
+        "pop rbp",
+        "pop rbx",
+        "pop rdx",
+        "pop rcx",
+        "pop rax",
+        "iretq",
+        in(reg) registers,
+        options(noreturn)
+    );
+}
+
+/// End the running program with `exit`, called by the handler of an exception raised in ring 3 and
+/// by the `exit` system call. Jumps back to [resume], panics if no program runs.
+pub(crate) fn leave(exit: Exit) -> ! {
+    leave_with(Leave::Exited(exit))
+}
+
+/// Leave ring 3 until `wait` is ready, called by the system calls which block after saving the
+/// registers of the program. Jumps back to [resume], panics if no program runs.
+pub(crate) fn block(wait: Wait) -> ! {
+    leave_with(Leave::Blocked(wait))
+}
+
+/// Leave ring 3 to let others run, as [block].
+pub(crate) fn yield_cpu() -> ! {
+    leave_with(Leave::Yielded)
+}
+
+/// Preempt the program which ran in ring 3 for [TIME_SLICE_TICKS], called by the timer interrupt
+/// handlers on the ticks raised in ring 3. The handler keeps the registers of the program in a
+/// layout of its own until it returns, so the program can't leave from the handler: its interrupt
+/// frame is rewritten to return to [preempted] in ring 0 instead, with the registers intact.
+pub(crate) fn preempt(stack_frame: &mut InterruptStackFrame) {
+    if time::hardware_ticks() < SLICE_END.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let selectors = gdt::selectors();
+    let entry = preempted_entry();
+    // the frames on the kernel stack end with the one of the handler, which returns right away
+    let stack = gdt::kernel_stack();
+    // # Safety
+    // The frame returns to the stub of [preempted_entry] in ring 0 with interrupts disabled, on the
+    // stack nothing else uses while the program runs. The program is resumed later from the part
+    // of the frame saved to its registers.
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            with_program(|program| {
+                program.registers.rip = frame.instruction_pointer.as_u64();
+                program.registers.cs = frame.code_segment;
+                program.registers.rflags = frame.cpu_flags;
+                program.registers.rsp = frame.stack_pointer.as_u64();
+                program.registers.ss = frame.stack_segment;
+            });
+            frame.instruction_pointer = entry;
+            frame.code_segment = u64::from(selectors.kernel_code.0);
+            frame.cpu_flags &=
+                !(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits();
+            frame.stack_pointer = stack;
+            frame.stack_segment = u64::from(selectors.kernel_data.0);
+        });
+    }
+}
+
+/// Save the general purpose registers of the program preempted in `frame`, the others were saved
+/// by [preempt], and leave ring 3 as [yield_cpu] does.
+extern "C" fn preempted(frame: &Registers) -> ! {
+    with_program(|program| {
+        let Registers {
+            rip,
+            cs,
+            rflags,
+            rsp,
+            ss,
+            ..
+        } = program.registers;
+        program.registers = Registers {
+            rip,
+            cs,
+            rflags,
+            rsp,
+            ss,
+            ..*frame
+        };
+    });
+    PREEMPTIONS.inc();
+    x86_64::instructions::interrupts::enable();
+    yield_cpu()
+}
+
+/// The entry of a preempted program in ring 0, a stub of assembly in the body of this function as
+/// the entry points of the [system calls](syscall). It pushes the registers of the program as the
+/// entry of `int 0x80` does, below room left for the interrupt frame, and calls [preempted].
+#[inline(never)]
+fn preempted_entry() -> VirtAddr {
+    let entry: u64;
+    // # Safety
+    // Only the address of the stub is taken, the stub isn't run here.
+    unsafe {
+        asm!(
+            "lea {entry}, [rip + 2f]",
+            "jmp 3f",
+            "2:",
+            // rip, cs, rflags, rsp and ss, saved by [preempt]
+            "sub rsp, 40",
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rbx",
+            "push rbp",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "cld",
+            "mov rdi, rsp",
+            "call {preempted}",
+            "ud2",
+            "3:",
+            entry = out(reg) entry,
+            preempted = sym preempted,
+        );
+    }
+    VirtAddr::new(entry)
+}
+
+fn leave_with(leave: Leave) -> ! {
+    *LEFT.lock() = Some(leave);
+    // # Safety
+    // The handler was entered from ring 3, its frame is on the kernel stack of the program, below
+    // the frames of [resume] if the program has none. Neither is returned to.
+    unsafe { RUNNING.resume() };
+    panic!("left ring 3 without a running program");
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        allocator::HEAP_START,
+        interrupts::{GENERAL_PROTECTION_FAULT_VECTOR, PAGE_FAULT_VECTOR},
+        memory,
+        time::tsc,
+    };
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    /// Spin for `rdi` TSC cycles, then `int3`.
+    pub(crate) const SPIN: &[u8] = &[
+        0x0f, 0x31, // rdtsc
+        0x48, 0xc1, 0xe2, 0x20, // shl rdx, 32
+        0x48, 0x09, 0xd0, // or rax, rdx
+        0x48, 0x89, 0xc6, // mov rsi, rax
+        0x0f, 0x31, // 1: rdtsc
+        0x48, 0xc1, 0xe2, 0x20, // shl rdx, 32
+        0x48, 0x09, 0xd0, // or rax, rdx
+        0x48, 0x29, 0xf0, // sub rax, rsi
+        0x48, 0x39, 0xf8, // cmp rax, rdi
+        0x72, 0xef, // jb 1b
+        0xcc, // int3
+    ];
+
+    fn interrupts_handled() -> u64 {
+        (0..=255).map(interrupts::interrupt_count).sum()
+    }
+
+    #[test_case]
+    fn interrupts_return_to_ring_3() {
+        let (kernel_table, _) = Cr3::read();
+        let before = interrupts_handled();
+        // long enough for a few timer ticks
+        let cycles = tsc::nanos_to_cycles(50_000_000);
+        let preemptions = PREEMPTIONS.get();
+        let (_, exit) = run(SPIN, cycles).unwrap();
+        assert_eq!(exit, Exit::Breakpoint);
+        assert!(interrupts_handled() > before + 1);
+        // the registers of the spinning loop survive the preemptions
+        assert!(PREEMPTIONS.get() > preemptions);
+        assert_eq!(Cr3::read().0, kernel_table);
+        assert_eq!(memory::translate(VirtAddr::new(PROGRAM_START)), None);
+    }
+
+    #[test_case]
+    fn kernel_not_accessible_from_ring_3() {
+        let value = 0u64;
+        let kernel = VirtAddr::from_ptr(&value);
+        assert!(memory::is_mapped(kernel, 8, true));
+        assert!(!memory::is_user_mapped(kernel, 8, false));
+        assert!(!memory::is_user_mapped(
+            VirtAddr::new(HEAP_START as u64),
+            8,
+            false
+        ));
+        assert!(memory::is_user_mapped(kernel, 0, true));
+    }
+
+    #[test_case]
+    fn memory_written_in_ring_3() {
+        // mov qword ptr [rsp - 8], 42; int3
+        let code = [0x48, 0xc7, 0x44, 0x24, 0xf8, 0x2a, 0, 0, 0, 0xcc];
+        let (mut program, exit) = run(&code, 0).unwrap();
+        assert_eq!(exit, Exit::Breakpoint);
+        let mut value = [0; 8];
+        program
+            .space
+            .read(VirtAddr::new(STACK_TOP - 8), &mut value)
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(value), 42);
+    }
+
+    #[test_case]
+    fn kernel_out_of_reach() {
+        // movabs rax, HEAP_START; mov byte ptr [rax], 1
+        let mut code = [0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0xc6, 0x00, 0x01];
+        code[2..10].copy_from_slice(&(HEAP_START as u64).to_le_bytes());
+        let (_, exit) = run(&code, 0).unwrap();
+        let error_code = PageFaultErrorCode::USER_MODE
+            | PageFaultErrorCode::PROTECTION_VIOLATION
+            | PageFaultErrorCode::CAUSED_BY_WRITE;
+        assert_eq!(
+            exit,
+            Exit::Exception {
+                vector: PAGE_FAULT_VECTOR,
+                error_code: Some(error_code.bits()),
+                instruction: VirtAddr::new(PROGRAM_START + 10),
+                address: Some(VirtAddr::new(HEAP_START as u64)),
+            }
+        );
+
+        // hlt is privileged
+        let (_, exit) = run(&[0xf4], 0).unwrap();
+        assert_eq!(
+            exit,
+            Exit::Exception {
+                vector: GENERAL_PROTECTION_FAULT_VECTOR,
+                error_code: Some(0),
+                instruction: VirtAddr::new(PROGRAM_START),
+                address: None,
+            }
+        );
+    }
+
+    #[test_case]
+    fn code_read_only() {
+        let mut space = load(&[0xcc]).unwrap();
+        assert_eq!(
+            space.map(VirtAddr::new(PROGRAM_START), 1, true),
+            Err(UserError::AlreadyMapped)
+        );
+        assert_eq!(
+            space.map(VirtAddr::new(HEAP_START as u64), 1, true),
+            Err(UserError::OutOfRange)
+        );
+        // mov byte ptr [rip - 7], 0x90, a write to its own first byte
+        let code = [0xc6, 0x05, 0xf9, 0xff, 0xff, 0xff, 0x90];
+        let (_, exit) = run(&code, 0).unwrap();
+        assert!(matches!(
+            exit,
+            Exit::Exception {
+                vector: PAGE_FAULT_VECTOR,
+                ..
+            }
+        ));
+    }
+}
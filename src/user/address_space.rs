@@ -0,0 +1,369 @@
+//! The address spaces of the programs: the mappings of the kernel, shared by all of them, and the
+//! private pages of the program in the [USER_START] region.
+//!
+//! The pages of the user region are mapped at once by [AddressSpace::map], or reserved by
+//! [AddressSpace::reserve] and mapped to a zeroed frame on their first access, when the program
+//! faults on them, see [AddressSpace::fault_in].
+
+use alloc::vec::Vec;
+use core::{ops::Range, ptr};
+
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame,
+        Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::UserError;
+use crate::{
+    memory::{self, BootInfoFrameAllocator},
+    metrics::Counter,
+};
+
+/// The start of the region of the private pages of a program, the region covered by one entry of
+/// the level 4 page table that the kernel doesn't use.
+pub const USER_START: u64 = 0x2000_0000_0000;
+/// The size of the region of the private pages of a program.
+pub const USER_SIZE: u64 = 1 << 39;
+
+const PAGE_SIZE: u64 = 4096;
+
+static DEMAND_PAGES: Counter = Counter::new("user.demand_pages");
+
+/// The entry of the level 4 page table mapping the user region.
+fn user_entry() -> usize {
+    usize::from(VirtAddr::new(USER_START).p4_index())
+}
+
+/// The page tables of a program. The entries of the kernel are copied from the page table of the
+/// kernel on creation, they point to the same lower level tables: the mappings the kernel adds to
+/// those later are shared, the new entries of the level 4 table aren't.
+///
+/// The frames of the pages mapped in the user region and of the page tables mapping them belong to
+/// the address space, they are freed when it's dropped.
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    /// The frames of the user region, pages and page tables alike.
+    frames: Vec<PhysFrame>,
+    /// The ranges mapped on demand, page aligned and disjoint.
+    reserved: Vec<Reserved>,
+}
+
+/// A range of pages mapped on their first access.
+#[derive(Debug, Clone)]
+struct Reserved {
+    pages: Range<u64>,
+    writable: bool,
+}
+
+/// Records the frames it allocates, e.g. for the page tables created by a mapping.
+struct RecordingAllocator<'a> {
+    frames: &'a mut BootInfoFrameAllocator,
+    allocated: &'a mut Vec<PhysFrame>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for RecordingAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.frames.allocate_frame()?;
+        self.allocated.push(frame);
+        Some(frame)
+    }
+}
+
+/// The page tables of the address space of `level_4`, edited through the mapping of the complete
+/// physical memory.
+///
+/// # Safety
+/// The address space must be borrowed for as long as the page tables are used, mutably to edit
+/// them.
+unsafe fn page_table(level_4: PhysFrame) -> OffsetPageTable<'static> {
+    let table = &mut *memory::phys_to_virt(level_4.start_address()).as_mut_ptr::<PageTable>();
+    OffsetPageTable::new(table, memory::phys_to_virt(PhysAddr::new(0)))
+}
+
+/// Fill `frame` with zeros.
+fn zero(frame: PhysFrame) {
+    // # Safety
+    // The frame was just allocated, nothing else refers to it.
+    unsafe {
+        ptr::write_bytes(
+            memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            PAGE_SIZE as usize,
+        );
+    }
+}
+
+impl AddressSpace {
+    /// Create an address space with the mappings of the kernel and nothing in the user region.
+    ///
+    /// # Panics
+    /// Panics if the kernel maps something in the user region, or before [memory::install].
+    pub fn new() -> Result<Self, UserError> {
+        memory::with_paging(|paging| {
+            let kernel_table = paging.mapper.level_4_table();
+            assert!(
+                kernel_table[user_entry()].is_unused(),
+                "the kernel maps the user region at {:#x}",
+                USER_START
+            );
+
+            let level_4 = paging
+                .frames
+                .allocate_frame()
+                .ok_or(UserError::OutOfMemory)?;
+            // # Safety
+            // The frame was just allocated, it's accessed through the mapping of the complete
+            // physical memory.
+            let table = unsafe {
+                &mut *memory::phys_to_virt(level_4.start_address()).as_mut_ptr::<PageTable>()
+            };
+            *table = kernel_table.clone();
+            Ok(AddressSpace {
+                level_4,
+                frames: Vec::new(),
+                reserved: Vec::new(),
+            })
+        })
+        .expect("memory::install must be called before creating address spaces")
+    }
+
+    /// The frame of the level 4 page table, for CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    /// The number of frames of the address space, the level 4 page table aside.
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The end of the `len` bytes at `start`, if they are within the user region.
+    fn check_range(start: VirtAddr, len: u64) -> Result<u64, UserError> {
+        match start.as_u64().checked_add(len) {
+            Some(end)
+                if len > 0 && start.as_u64() >= USER_START && end <= USER_START + USER_SIZE =>
+            {
+                Ok(end)
+            }
+            _ => Err(UserError::OutOfRange),
+        }
+    }
+
+    /// The pages of the `len` bytes at `start`, checked to be in the user region.
+    fn pages(start: VirtAddr, len: u64) -> Result<impl Iterator<Item = Page>, UserError> {
+        let end = Self::check_range(start, len)?;
+        Ok(Page::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        ))
+    }
+
+    /// Map the `len` bytes at `start` to fresh zeroed pages, accessible from ring 3 and writable if
+    /// `writable` is set.
+    pub fn map(&mut self, start: VirtAddr, len: u64, writable: bool) -> Result<(), UserError> {
+        let flags = memory::user_flags(writable);
+
+        for page in Self::pages(start, len)? {
+            let level_4 = self.level_4;
+            let allocated = &mut self.frames;
+            memory::with_paging(|paging| {
+                // the frames are recorded even if the mapping fails, to be freed with the rest
+                let mut frames = RecordingAllocator {
+                    frames: &mut paging.frames,
+                    allocated,
+                };
+                let frame = frames.allocate_frame().ok_or(UserError::OutOfMemory)?;
+                zero(frame);
+                // # Safety
+                // The address space is borrowed mutably. The frame is fresh, the page is in the
+                // user region which only this address space maps.
+                let mapped = unsafe { page_table(level_4).map_to(page, frame, flags, &mut frames) };
+                match mapped {
+                    // the page wasn't present, the TLB has nothing to flush
+                    Ok(flush) => {
+                        flush.ignore();
+                        Ok(())
+                    }
+                    Err(MapToError::FrameAllocationFailed) => Err(UserError::OutOfMemory),
+                    Err(_) => Err(UserError::AlreadyMapped),
+                }
+            })
+            .expect("memory::install must be called before creating address spaces")?;
+        }
+        Ok(())
+    }
+
+    /// Reserve the pages of the `len` bytes at `start`, mapped to fresh zeroed pages on their first
+    /// access, accessible from ring 3 and writable if `writable` is set.
+    pub fn reserve(&mut self, start: VirtAddr, len: u64, writable: bool) -> Result<(), UserError> {
+        let end = Self::check_range(start, len)?;
+        let pages =
+            start.align_down(PAGE_SIZE).as_u64()..VirtAddr::new(end).align_up(PAGE_SIZE).as_u64();
+        if self
+            .reserved
+            .iter()
+            .any(|reserved| reserved.pages.start < pages.end && pages.start < reserved.pages.end)
+        {
+            return Err(UserError::AlreadyMapped);
+        }
+        self.reserved.push(Reserved { pages, writable });
+        Ok(())
+    }
+
+    /// Give up the reserved pages of the `len` bytes at `start`, those mapped already are unmapped
+    /// and their frames freed. The pages which aren't reserved are left alone.
+    pub fn release(&mut self, start: VirtAddr, len: u64) -> Result<(), UserError> {
+        let end = Self::check_range(start, len)?;
+        let (start, end) = (
+            start.align_down(PAGE_SIZE).as_u64(),
+            VirtAddr::new(end).align_up(PAGE_SIZE).as_u64(),
+        );
+        let mut released = Vec::new();
+        let mut kept = Vec::new();
+        for reserved in self.reserved.drain(..) {
+            let pages = &reserved.pages;
+            if pages.end <= start || end <= pages.start {
+                kept.push(reserved);
+                continue;
+            }
+            if pages.start < start {
+                kept.push(Reserved {
+                    pages: pages.start..start,
+                    writable: reserved.writable,
+                });
+            }
+            if end < pages.end {
+                kept.push(Reserved {
+                    pages: end..pages.end,
+                    writable: reserved.writable,
+                });
+            }
+            released.push(pages.start.max(start)..pages.end.min(end));
+        }
+        self.reserved = kept;
+
+        for pages in released {
+            for page in Self::pages(VirtAddr::new(pages.start), pages.end - pages.start)? {
+                self.unmap(page);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap `page` if it's mapped and free its frame.
+    fn unmap(&mut self, page: Page) {
+        // # Safety
+        // The address space is borrowed mutably, the page is in the user region.
+        let unmapped = unsafe { page_table(self.level_4).unmap(page) };
+        if let Ok((frame, flush)) = unmapped {
+            // the address space may be the active one
+            flush.flush();
+            if let Some(index) = self.frames.iter().position(|&owned| owned == frame) {
+                self.frames.swap_remove(index);
+            }
+            memory::with_paging(|paging| {
+                // # Safety
+                // The frame belonged to the page, which isn't mapped anymore.
+                unsafe { paging.frames.deallocate_frame(frame) };
+            });
+        }
+    }
+
+    /// Map the page of `addr` if it's reserved and not mapped yet, for a write if `write` is set.
+    /// Returns whether the page was mapped, `false` if the access faults.
+    pub fn fault_in(&mut self, addr: VirtAddr, write: bool) -> bool {
+        let writable = match self
+            .reserved
+            .iter()
+            .find(|reserved| reserved.pages.contains(&addr.as_u64()))
+        {
+            Some(reserved) if reserved.writable || !write => reserved.writable,
+            _ => return false,
+        };
+        let mapped = self
+            .map(addr.align_down(PAGE_SIZE), PAGE_SIZE, writable)
+            .is_ok();
+        if mapped {
+            DEMAND_PAGES.inc();
+        }
+        mapped
+    }
+
+    /// Call `f` on each piece of the `len` bytes at `addr` within a page, with the address of the
+    /// piece in the mapping of the complete physical memory and its offset in the range.
+    fn for_each_piece(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(VirtAddr, Range<usize>),
+    ) -> Result<(), UserError> {
+        if len == 0 {
+            return Ok(());
+        }
+        Self::check_range(addr, len as u64)?;
+        // # Safety
+        // The address space is borrowed mutably, the page tables are only read.
+        let page_table = unsafe { page_table(self.level_4) };
+        let mut done = 0;
+        while done < len {
+            let at = addr + done as u64;
+            let phys = match page_table.translate(at) {
+                TranslateResult::Mapped { frame, offset, .. } => frame.start_address() + offset,
+                _ => return Err(UserError::NotMapped),
+            };
+            let piece = (len - done).min((PAGE_SIZE - at.as_u64() % PAGE_SIZE) as usize);
+            f(memory::phys_to_virt(phys), done..done + piece);
+            done += piece;
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` to the pages mapped at `addr`, whatever their flags.
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), UserError> {
+        self.for_each_piece(addr, bytes.len(), |dst, range| {
+            // # Safety
+            // The piece is within a page of the address space.
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    bytes[range.clone()].as_ptr(),
+                    dst.as_mut_ptr::<u8>(),
+                    range.len(),
+                )
+            }
+        })
+    }
+
+    /// Copy the bytes of the pages mapped at `addr` to `buf`.
+    pub fn read(&mut self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), UserError> {
+        self.for_each_piece(addr, buf.len(), |src, range| {
+            // # Safety
+            // As above.
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    src.as_ptr::<u8>(),
+                    buf[range.clone()].as_mut_ptr(),
+                    range.len(),
+                )
+            }
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let frames = core::mem::take(&mut self.frames);
+        let level_4 = self.level_4;
+        memory::with_paging(|paging| {
+            // # Safety
+            // The frames belong to the address space, which is no longer active: nothing refers to
+            // them anymore.
+            for frame in frames.into_iter().chain(core::iter::once(level_4)) {
+                unsafe { paging.frames.deallocate_frame(frame) };
+            }
+        });
+    }
+}
@@ -0,0 +1,163 @@
+//! The memory of a program beyond its code and its stack: the heap, grown and shrunk by moving its
+//! end, the break, and the anonymous mappings. Both only reserve their pages in the address space,
+//! which maps them on their first access, see [AddressSpace::reserve].
+//!
+//! The heap starts on the page after the code and may grow up to [MMAP_START]. The mappings are
+//! placed one after the other from [MMAP_START] up to the stack, their ranges are never reused.
+
+use x86_64::VirtAddr;
+
+use super::{AddressSpace, UserError, STACK_SIZE, STACK_TOP, USER_SIZE, USER_START};
+
+/// The start of the anonymous mappings, the end of the heap.
+pub const MMAP_START: u64 = USER_START + USER_SIZE / 2;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The heap and the mappings of a program.
+#[derive(Debug)]
+pub struct Heap {
+    start: u64,
+    brk: u64,
+    next_mapping: u64,
+}
+
+impl Heap {
+    /// An empty heap starting on the page of `start`, or the next one if `start` isn't page
+    /// aligned.
+    pub fn new(start: VirtAddr) -> Self {
+        let start = start.align_up(PAGE_SIZE).as_u64();
+        Heap {
+            start,
+            brk: start,
+            next_mapping: MMAP_START,
+        }
+    }
+
+    /// The start of the heap.
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(self.start)
+    }
+
+    /// The end of the heap.
+    pub fn brk(&self) -> VirtAddr {
+        VirtAddr::new(self.brk)
+    }
+
+    /// Move the end of the heap to `brk`, reserving the pages it grows by in `space` and releasing
+    /// those it shrinks by.
+    pub fn set_brk(&mut self, space: &mut AddressSpace, brk: VirtAddr) -> Result<(), UserError> {
+        let brk = brk.as_u64();
+        if brk < self.start {
+            return Err(UserError::OutOfRange);
+        }
+        if brk > MMAP_START {
+            return Err(UserError::OutOfMemory);
+        }
+        let old_end = VirtAddr::new(self.brk).align_up(PAGE_SIZE).as_u64();
+        let new_end = VirtAddr::new(brk).align_up(PAGE_SIZE).as_u64();
+        if new_end > old_end {
+            space.reserve(VirtAddr::new(old_end), new_end - old_end, true)?;
+        } else if new_end < old_end {
+            space.release(VirtAddr::new(new_end), old_end - new_end)?;
+        }
+        self.brk = brk;
+        Ok(())
+    }
+
+    /// Reserve `len` bytes of zeroed pages in `space`, writable if `writable` is set, returns their
+    /// start.
+    pub fn map(
+        &mut self,
+        space: &mut AddressSpace,
+        len: u64,
+        writable: bool,
+    ) -> Result<VirtAddr, UserError> {
+        if len == 0 {
+            return Err(UserError::OutOfRange);
+        }
+        let start = self.next_mapping;
+        let end = start
+            .checked_add(len)
+            .map(|end| VirtAddr::new(end).align_up(PAGE_SIZE).as_u64())
+            .filter(|&end| end <= STACK_TOP - STACK_SIZE)
+            .ok_or(UserError::OutOfMemory)?;
+        space.reserve(VirtAddr::new(start), end - start, writable)?;
+        self.next_mapping = end;
+        Ok(VirtAddr::new(start))
+    }
+
+    /// Unmap the pages of the `len` bytes at `addr` from the mappings in `space`, `addr` must be
+    /// page aligned.
+    pub fn unmap(
+        &mut self,
+        space: &mut AddressSpace,
+        addr: VirtAddr,
+        len: u64,
+    ) -> Result<(), UserError> {
+        let start = addr.as_u64();
+        match start.checked_add(len) {
+            Some(end)
+                if addr.is_aligned(PAGE_SIZE)
+                    && len > 0
+                    && start >= MMAP_START
+                    && end <= self.next_mapping =>
+            {
+                space.release(addr, len)
+            }
+            _ => Err(UserError::OutOfRange),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn pages_reserved_and_released() {
+        let mut space = AddressSpace::new().unwrap();
+        let mut heap = Heap::new(VirtAddr::new(USER_START + 0x1234));
+        assert_eq!(heap.start(), VirtAddr::new(USER_START + 0x2000));
+        assert_eq!(heap.brk(), heap.start());
+
+        let brk = heap.start() + (3 * PAGE_SIZE + 8);
+        heap.set_brk(&mut space, brk).unwrap();
+        assert_eq!(heap.brk(), brk);
+        assert_eq!(space.frames(), 0);
+        // the pages and their page tables
+        assert!(space.fault_in(brk - 1u64, true));
+        assert!(space.fault_in(heap.start(), false));
+        assert_eq!(space.frames(), 5);
+        assert!(!space.fault_in(brk + PAGE_SIZE, false));
+
+        heap.set_brk(&mut space, heap.start() + 8u64).unwrap();
+        assert_eq!(space.frames(), 4);
+        assert!(!space.fault_in(brk - 1u64, false));
+        assert_eq!(
+            heap.set_brk(&mut space, heap.start() - 1u64),
+            Err(UserError::OutOfRange)
+        );
+        assert_eq!(
+            heap.set_brk(&mut space, VirtAddr::new(MMAP_START + 1)),
+            Err(UserError::OutOfMemory)
+        );
+
+        let mapping = heap.map(&mut space, 2 * PAGE_SIZE, false).unwrap();
+        assert_eq!(mapping, VirtAddr::new(MMAP_START));
+        assert!(!space.fault_in(mapping, true));
+        assert!(space.fault_in(mapping + PAGE_SIZE, false));
+        assert_eq!(
+            heap.unmap(&mut space, mapping + 1u64, PAGE_SIZE),
+            Err(UserError::OutOfRange)
+        );
+        heap.unmap(&mut space, mapping + PAGE_SIZE, PAGE_SIZE)
+            .unwrap();
+        assert!(!space.fault_in(mapping + PAGE_SIZE, false));
+        assert!(space.fault_in(mapping, false));
+        assert_eq!(
+            heap.map(&mut space, 2 * PAGE_SIZE, true).unwrap(),
+            mapping + 2 * PAGE_SIZE
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Loading static ELF64 executables into an address space.
+//!
+//! [parse] checks that the image is a little-endian x86_64 executable, linked at fixed addresses,
+//! and reads its `PT_LOAD` segments, the other program headers, e.g. `PT_GNU_STACK`, are ignored.
+//! [load] maps each segment into a fresh [AddressSpace]: the bytes of the segment in the file are
+//! copied, the rest of its size in memory, e.g. `.bss`, is left zeroed. The segments are writable
+//! from ring 3 if they have the `PF_W` flag, all of them are executable. Two segments may not share
+//! a page. The program gets the stack of [super::load] below [STACK_TOP].
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use x86_64::VirtAddr;
+
+use super::{AddressSpace, UserError, STACK_SIZE, STACK_TOP, USER_START};
+
+/// The first bytes of every ELF file.
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+/// The class, the data encoding and the version of the file follow the magic.
+const OFFSET_CLASS: usize = 4;
+const OFFSET_TYPE: usize = 16;
+const OFFSET_MACHINE: usize = 18;
+const OFFSET_ENTRY: usize = 24;
+const OFFSET_PHOFF: usize = 32;
+const OFFSET_PHENTSIZE: usize = 54;
+const OFFSET_PHNUM: usize = 56;
+
+/// The size of a program header of ELF64.
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The image ends before a header or the bytes of a segment.
+const TRUNCATED: UserError = UserError::BadExecutable("truncated");
+
+/// A `PT_LOAD` segment of an executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Where the bytes of the segment start in the image.
+    pub offset: u64,
+    /// Where the segment is mapped.
+    pub address: VirtAddr,
+    /// The number of bytes of the segment in the image.
+    pub file_size: u64,
+    /// The number of bytes of the segment in memory, those beyond [Segment::file_size] are zeroed.
+    pub memory_size: u64,
+    /// Whether the segment is writable.
+    pub writable: bool,
+}
+
+impl Segment {
+    /// One byte beyond the end of the segment in memory.
+    pub fn end(&self) -> VirtAddr {
+        self.address + self.memory_size
+    }
+}
+
+/// The parsed headers of an executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executable {
+    /// The address of the first instruction.
+    pub entry: VirtAddr,
+    /// The `PT_LOAD` segments, in the order of the program headers.
+    pub segments: Vec<Segment>,
+}
+
+/// An executable loaded into its address space.
+pub struct Loaded {
+    /// The address space, with the segments and the stack mapped.
+    pub space: AddressSpace,
+    /// The address of the first instruction.
+    pub entry: VirtAddr,
+    /// The initial stack pointer, [STACK_TOP].
+    pub stack: VirtAddr,
+    /// One byte beyond the end of the highest segment, where the heap may start.
+    pub end: VirtAddr,
+}
+
+/// Whether `image` starts with the magic of ELF files, whatever follows.
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(MAGIC)
+}
+
+/// The `N` bytes at `offset` of `image`.
+fn field<const N: usize>(image: &[u8], offset: usize) -> Result<[u8; N], UserError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| image.get(offset..end))
+        .and_then(|bytes| <[u8; N]>::try_from(bytes).ok())
+        .ok_or(TRUNCATED)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, UserError> {
+    field(image, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, UserError> {
+    field(image, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, UserError> {
+    field(image, offset).map(u64::from_le_bytes)
+}
+
+/// Parse the headers of the static executable `image`, the segments are checked to be in the user
+/// region below the stack.
+pub fn parse(image: &[u8]) -> Result<Executable, UserError> {
+    if !is_elf(image) {
+        return Err(UserError::BadExecutable("not an ELF file"));
+    }
+    let ident: [u8; 3] = field(image, OFFSET_CLASS)?;
+    if ident != [CLASS_64, DATA_LITTLE_ENDIAN, VERSION_CURRENT] {
+        return Err(UserError::BadExecutable("not a little-endian ELF64 file"));
+    }
+    if read_u16(image, OFFSET_MACHINE)? != MACHINE_X86_64 {
+        return Err(UserError::BadExecutable("not an x86_64 executable"));
+    }
+    if read_u16(image, OFFSET_TYPE)? != TYPE_EXECUTABLE {
+        return Err(UserError::BadExecutable("not a static executable"));
+    }
+
+    let entry = read_u64(image, OFFSET_ENTRY)?;
+    let phoff = usize::try_from(read_u64(image, OFFSET_PHOFF)?).map_err(|_| TRUNCATED)?;
+    let phentsize = usize::from(read_u16(image, OFFSET_PHENTSIZE)?);
+    let phnum = usize::from(read_u16(image, OFFSET_PHNUM)?);
+    if phnum > 0 && phentsize < PROGRAM_HEADER_SIZE {
+        return Err(UserError::BadExecutable("program headers too small"));
+    }
+
+    let mut segments = Vec::new();
+    for index in 0..phnum {
+        let header = index
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .ok_or(TRUNCATED)?;
+        if read_u32(image, header)? != PT_LOAD {
+            continue;
+        }
+        let segment = Segment {
+            offset: read_u64(image, header + 8)?,
+            address: VirtAddr::try_new(read_u64(image, header + 16)?)
+                .map_err(|_| UserError::OutOfRange)?,
+            file_size: read_u64(image, header + 32)?,
+            memory_size: read_u64(image, header + 40)?,
+            writable: read_u32(image, header + 4)? & PF_W != 0,
+        };
+        if segment.file_size > segment.memory_size {
+            return Err(UserError::BadExecutable(
+                "segment larger in the file than in memory",
+            ));
+        }
+        match segment.offset.checked_add(segment.file_size) {
+            Some(end) if end <= image.len() as u64 => {}
+            _ => return Err(TRUNCATED),
+        }
+        match segment.address.as_u64().checked_add(segment.memory_size) {
+            Some(end)
+                if segment.address.as_u64() >= USER_START && end <= STACK_TOP - STACK_SIZE => {}
+            _ => return Err(UserError::OutOfRange),
+        }
+        segments.push(segment);
+    }
+
+    let entry = VirtAddr::try_new(entry).map_err(|_| UserError::OutOfRange)?;
+    if !segments
+        .iter()
+        .any(|segment| segment.address <= entry && entry < segment.end())
+    {
+        return Err(UserError::BadExecutable(
+            "entry point outside of the segments",
+        ));
+    }
+    Ok(Executable { entry, segments })
+}
+
+/// Load the static executable `image` into a new address space, with a stack of
+/// [STACK_SIZE] bytes below [STACK_TOP].
+pub fn load(image: &[u8]) -> Result<Loaded, UserError> {
+    let executable = parse(image)?;
+    let mut space = AddressSpace::new()?;
+    let mut end = VirtAddr::new(USER_START);
+    for segment in &executable.segments {
+        if segment.memory_size == 0 {
+            continue;
+        }
+        space.map(segment.address, segment.memory_size, segment.writable)?;
+        let start = segment.offset as usize;
+        space.write(
+            segment.address,
+            &image[start..start + segment.file_size as usize],
+        )?;
+        end = end.max(segment.end());
+    }
+    super::map_stack(&mut space)?;
+    Ok(Loaded {
+        space,
+        entry: executable.entry,
+        stack: VirtAddr::new(STACK_TOP),
+        end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interrupts::PAGE_FAULT_VECTOR,
+        user::{self, Exit, Program, PROGRAM_START},
+    };
+    use alloc::vec;
+
+    const CODE_FLAGS: u32 = 5;
+    const DATA_FLAGS: u32 = 6;
+    /// Where the data segments of the tests are loaded.
+    const DATA_START: u64 = PROGRAM_START + 0x1000;
+
+    /// An executable starting at `entry` with a segment per `(address, flags, bytes, memory size)`.
+    fn executable(entry: u64, segments: &[(u64, u32, &[u8], u64)]) -> Vec<u8> {
+        let phoff = 64;
+        let mut image = vec![0; phoff + segments.len() * PROGRAM_HEADER_SIZE];
+        image[..4].copy_from_slice(MAGIC);
+        image[OFFSET_CLASS..OFFSET_CLASS + 3].copy_from_slice(&[
+            CLASS_64,
+            DATA_LITTLE_ENDIAN,
+            VERSION_CURRENT,
+        ]);
+        image[OFFSET_TYPE..OFFSET_TYPE + 2].copy_from_slice(&TYPE_EXECUTABLE.to_le_bytes());
+        image[OFFSET_MACHINE..OFFSET_MACHINE + 2].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+        image[OFFSET_ENTRY..OFFSET_ENTRY + 8].copy_from_slice(&entry.to_le_bytes());
+        image[OFFSET_PHOFF..OFFSET_PHOFF + 8].copy_from_slice(&(phoff as u64).to_le_bytes());
+        image[OFFSET_PHENTSIZE..OFFSET_PHENTSIZE + 2]
+            .copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        image[OFFSET_PHNUM..OFFSET_PHNUM + 2]
+            .copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        for (index, &(address, flags, bytes, memory_size)) in segments.iter().enumerate() {
+            let offset = image.len() as u64;
+            let header = phoff + index * PROGRAM_HEADER_SIZE;
+            let fields = [
+                u64::from(PT_LOAD) | u64::from(flags) << 32,
+                offset,
+                address,
+                address,
+                bytes.len() as u64,
+                memory_size,
+                0x1000,
+            ];
+            for (i, field) in fields.iter().enumerate() {
+                image[header + i * 8..header + i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+            }
+            image.extend_from_slice(bytes);
+        }
+        image
+    }
+
+    /// `movabs rax, addr; mov byte ptr [rax], 1; int3`
+    fn write_to(addr: u64) -> [u8; 14] {
+        let mut code = [0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0xc6, 0x00, 0x01, 0xcc];
+        code[2..10].copy_from_slice(&addr.to_le_bytes());
+        code
+    }
+
+    #[test_case]
+    fn headers_parsed() {
+        let image = executable(
+            PROGRAM_START + 1,
+            &[
+                (PROGRAM_START, CODE_FLAGS, &[0x90, 0xcc], 2),
+                (DATA_START, DATA_FLAGS, &[1, 2, 3], 16),
+            ],
+        );
+        let executable = parse(&image).unwrap();
+        assert_eq!(executable.entry, VirtAddr::new(PROGRAM_START + 1));
+        assert_eq!(executable.segments.len(), 2);
+        assert!(!executable.segments[0].writable);
+        assert_eq!(
+            executable.segments[1],
+            Segment {
+                offset: 64 + 2 * PROGRAM_HEADER_SIZE as u64 + 2,
+                address: VirtAddr::new(DATA_START),
+                file_size: 3,
+                memory_size: 16,
+                writable: true,
+            }
+        );
+    }
+
+    #[test_case]
+    fn malformed_rejected() {
+        let code: &[u8] = &[0xcc];
+        let image = executable(PROGRAM_START, &[(PROGRAM_START, CODE_FLAGS, code, 1)]);
+        assert!(parse(&image).is_ok());
+        assert!(matches!(parse(code), Err(UserError::BadExecutable(_))));
+        assert_eq!(parse(&image[..40]), Err(TRUNCATED));
+        assert_eq!(parse(&image[..image.len() - 1]), Err(TRUNCATED));
+
+        let mut elf32 = image.clone();
+        elf32[OFFSET_CLASS] = 1;
+        assert!(matches!(parse(&elf32), Err(UserError::BadExecutable(_))));
+        let mut shared = image.clone();
+        shared[OFFSET_TYPE] = 3;
+        assert!(matches!(parse(&shared), Err(UserError::BadExecutable(_))));
+
+        let outside = executable(DATA_START, &[(PROGRAM_START, CODE_FLAGS, code, 1)]);
+        assert!(matches!(parse(&outside), Err(UserError::BadExecutable(_))));
+        let kernel = executable(0x1000, &[(0x1000, CODE_FLAGS, code, 1)]);
+        assert_eq!(parse(&kernel), Err(UserError::OutOfRange));
+        let stack = STACK_TOP - STACK_SIZE;
+        let over_stack = executable(stack, &[(stack, CODE_FLAGS, code, 1)]);
+        assert_eq!(parse(&over_stack), Err(UserError::OutOfRange));
+        let larger = executable(PROGRAM_START, &[(PROGRAM_START, CODE_FLAGS, &[0xcc, 0], 1)]);
+        assert!(matches!(parse(&larger), Err(UserError::BadExecutable(_))));
+    }
+
+    #[test_case]
+    fn segments_loaded() {
+        let image = executable(
+            PROGRAM_START,
+            &[
+                (PROGRAM_START, CODE_FLAGS, &[0xcc], 1),
+                (DATA_START + 8, DATA_FLAGS, &[1, 2, 3], 0x2000),
+            ],
+        );
+        let mut loaded = load(&image).unwrap();
+        assert_eq!(loaded.entry, VirtAddr::new(PROGRAM_START));
+        assert_eq!(loaded.stack, VirtAddr::new(STACK_TOP));
+        assert_eq!(loaded.end, VirtAddr::new(DATA_START + 0x2008));
+
+        let mut data = [0xff; 6];
+        loaded
+            .space
+            .read(VirtAddr::new(DATA_START + 8), &mut data)
+            .unwrap();
+        assert_eq!(data, [1, 2, 3, 0, 0, 0]);
+        let mut last = [0xff];
+        loaded
+            .space
+            .read(VirtAddr::new(DATA_START + 0x2007), &mut last)
+            .unwrap();
+        assert_eq!(last, [0]);
+        assert_eq!(
+            loaded.space.map(VirtAddr::new(STACK_TOP - 1), 1, true),
+            Err(UserError::AlreadyMapped)
+        );
+    }
+
+    #[test_case]
+    fn permissions_kept_in_ring_3() {
+        let data: &[u8] = &[0; 8];
+        let writes_data = write_to(DATA_START);
+        let image = executable(
+            PROGRAM_START,
+            &[
+                (PROGRAM_START, CODE_FLAGS, &writes_data, 14),
+                (DATA_START, DATA_FLAGS, data, 8),
+            ],
+        );
+        let (mut program, exit) = user::run_program(Program::load_elf(&image, 0).unwrap());
+        assert_eq!(exit, Exit::Breakpoint);
+        let mut written = [0];
+        program
+            .space
+            .read(VirtAddr::new(DATA_START), &mut written)
+            .unwrap();
+        assert_eq!(written, [1]);
+        assert_eq!(program.heap.start(), VirtAddr::new(DATA_START + 0x1000));
+
+        let writes_code = write_to(PROGRAM_START);
+        let image = executable(
+            PROGRAM_START,
+            &[(PROGRAM_START, CODE_FLAGS, &writes_code, 14)],
+        );
+        let (_, exit) = user::run_program(Program::load_elf(&image, 0).unwrap());
+        assert!(matches!(
+            exit,
+            Exit::Exception {
+                vector: PAGE_FAULT_VECTOR,
+                address: Some(address),
+                ..
+            } if address == VirtAddr::new(PROGRAM_START)
+        ));
+    }
+}
@@ -0,0 +1,943 @@
+//! The system calls of the programs in ring 3.
+//!
+//! A program puts the number of the call in `rax` and its arguments in `rdi`, `rsi`, `rdx`, `r10`,
+//! `r8` and `r9`, as on Linux, then executes `syscall` or `int 0x80`. The result comes back in
+//! `rax`, a negative error code on failure, see [SyscallError::code]. Every other register is
+//! preserved but `rcx` and `r11` after `syscall`, which the CPU overwrites with the return address
+//! and the flags.
+//!
+//! Both entry points push the [Registers] of the program on its kernel stack and call [dispatch],
+//! which saves them to the [Program](super::Program) and looks the number up in the table of
+//! handlers. The handlers run with interrupts enabled. A call which has to wait, e.g. `sleep`,
+//! leaves ring 3 with the future it waits on, see [super::block]. The pointers passed by the
+//! program are checked to be in the user region and mapped for ring 3 by walking the page tables
+//! before the kernel touches them, the pages reserved by the program are mapped on the way.
+
+use alloc::boxed::Box;
+use core::{
+    convert::TryFrom,
+    fmt, slice, str,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::idt::HandlerFunc,
+    VirtAddr,
+};
+
+use super::{Completion, Exit, Registers, UserError, USER_SIZE, USER_START};
+use crate::{
+    fs::{self, fd::Fd, FsError},
+    gdt, memory,
+    metrics::Counter,
+    process::{self, Pid, WaitError},
+    task::timer,
+};
+
+/// The vector of `int 0x80`.
+pub const INTERRUPT_VECTOR: u8 = 0x80;
+
+/// `read(fd, buf, len)`: read from the descriptor `fd` into `buf`, returns the number of bytes
+/// read. Waits for the files which become ready by themselves, e.g. pipes, when there is nothing to
+/// read.
+pub const READ: u64 = 0;
+/// `write(fd, buf, len)`: write `buf` to the descriptor `fd`, returns the number of bytes written.
+/// Waits for the files which become ready by themselves when they are full.
+pub const WRITE: u64 = 1;
+/// `exit(status)`: end the program with the status, never returns.
+pub const EXIT: u64 = 2;
+/// `sleep(millis)`: wait for at least `millis` milliseconds, returns 0.
+pub const SLEEP: u64 = 3;
+/// `spawn(path, len)`: start the program at the path of the filesystem, a static ELF64 executable
+/// or raw machine code loaded as by [super::load], as a child process with a copy of the
+/// descriptors. Returns the process id of the child.
+pub const SPAWN: u64 = 4;
+/// `getpid()`: returns the process id of the program.
+pub const GETPID: u64 = 5;
+/// `yield()`: let the other tasks and processes run, returns 0.
+pub const YIELD: u64 = 6;
+/// `wait(pid, status)`: wait for the child `pid` to exit, for any child if `pid` is 0, and reap it.
+/// Writes its status, see [Exit::status], to the `i32` at `status` unless it's null. Returns the
+/// process id of the child.
+pub const WAIT: u64 = 7;
+/// `brk(addr)`: move the end of the heap to `addr`. Returns the end of the heap, which stays where
+/// it was if `addr` is 0 or out of range.
+pub const BRK: u64 = 8;
+/// `sbrk(increment)`: grow the heap by `increment` bytes, shrink it if `increment` is negative.
+/// Returns the previous end of the heap.
+pub const SBRK: u64 = 9;
+/// `mmap(addr, len, prot, flags, fd, offset)`: map `len` bytes of zeroed pages, writable if `prot`
+/// has [PROT_WRITE]. Only anonymous mappings are supported: `flags` must be
+/// [MAP_PRIVATE]` | `[MAP_ANONYMOUS], `addr`, `fd` and `offset` are ignored. Returns the start of
+/// the mapping.
+pub const MMAP: u64 = 10;
+/// `munmap(addr, len)`: unmap the pages of the `len` bytes at `addr`, which must be page aligned.
+/// Returns 0.
+pub const MUNMAP: u64 = 11;
+/// `pipe(fds)`: open a pipe, writes the descriptors of its reading and of its writing end to the
+/// two `i32` at `fds`. Returns 0.
+pub const PIPE: u64 = 12;
+/// `close(fd)`: close the descriptor `fd`, returns 0.
+pub const CLOSE: u64 = 13;
+/// `dup2(fd, to)`: make the descriptor `to` refer to the file of `fd`, returns `to`.
+pub const DUP2: u64 = 14;
+
+/// The mapping of `mmap` may be written.
+pub const PROT_WRITE: u64 = 0x2;
+/// The mapping of `mmap` is private to the program.
+pub const MAP_PRIVATE: u64 = 0x2;
+/// The mapping of `mmap` isn't backed by a file.
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// The stack of the program on `syscall`, until the entry switches to the kernel stack.
+static USER_STACK: AtomicU64 = AtomicU64::new(0);
+/// The address of the kernel stack in the TSS, see [gdt::kernel_stack_slot].
+static KERNEL_STACK_SLOT: AtomicU64 = AtomicU64::new(0);
+/// The selectors of ring 3, for the interrupt frame the entry of `syscall` builds.
+static USER_CODE: AtomicU64 = AtomicU64::new(0);
+static USER_DATA: AtomicU64 = AtomicU64::new(0);
+
+static CALLS: Counter = Counter::new("syscall.calls");
+static FAILURES: Counter = Counter::new("syscall.failures");
+
+/// An error of a system call, returned to the program as the negation of its [code].
+///
+/// [code]: SyscallError::code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// No system call has the number.
+    NoSuchCall,
+    /// A buffer isn't mapped for the program, or isn't writable when it should be.
+    BadAddress,
+    /// An argument is out of range, e.g. a path which isn't UTF-8.
+    InvalidArgument,
+    /// The file operation failed.
+    Fs(FsError),
+    /// The program to spawn couldn't be loaded.
+    User(UserError),
+    /// The program has no such child to wait for.
+    NoChild,
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallError::NoSuchCall => write!(f, "function not implemented"),
+            SyscallError::BadAddress => write!(f, "bad address"),
+            SyscallError::InvalidArgument => write!(f, "invalid argument"),
+            SyscallError::Fs(err) => write!(f, "{}", err),
+            SyscallError::User(err) => write!(f, "{}", err),
+            SyscallError::NoChild => write!(f, "{}", WaitError::NoChild),
+        }
+    }
+}
+
+impl From<FsError> for SyscallError {
+    fn from(err: FsError) -> Self {
+        SyscallError::Fs(err)
+    }
+}
+
+impl From<UserError> for SyscallError {
+    fn from(err: UserError) -> Self {
+        SyscallError::User(err)
+    }
+}
+
+impl From<WaitError> for SyscallError {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::NoChild => SyscallError::NoChild,
+        }
+    }
+}
+
+impl SyscallError {
+    /// The code of the error, the `errno` of Linux for the same condition.
+    pub fn code(self) -> i64 {
+        match self {
+            SyscallError::NoSuchCall => 38,
+            SyscallError::BadAddress => 14,
+            SyscallError::InvalidArgument => 22,
+            SyscallError::Fs(err) => match err {
+                FsError::NotFound => 2,
+                FsError::NotADirectory => 20,
+                FsError::IsADirectory => 21,
+                FsError::ReadOnly => 30,
+                FsError::InvalidPath | FsError::InvalidSeek | FsError::InvalidArgument => 22,
+                FsError::Busy => 16,
+                FsError::NotMounted | FsError::NotMappable => 19,
+                FsError::Corrupted(_) | FsError::Device(_) => 5,
+                FsError::PermissionDenied | FsError::BadDescriptor => 9,
+                FsError::TooManyOpenFiles => 24,
+                FsError::NotSeekable => 29,
+                FsError::WouldBlock => 11,
+                FsError::BrokenPipe => 32,
+                FsError::AlreadyExists => 17,
+                FsError::FileTooLarge => 27,
+                FsError::OutOfMemory => 12,
+            },
+            SyscallError::User(UserError::OutOfMemory) => 12,
+            // the file is empty or too large to be a program
+            SyscallError::User(_) => 8,
+            SyscallError::NoChild => 10,
+        }
+    }
+}
+
+/// The arguments of a system call, in the order of the registers.
+#[derive(Debug, Clone, Copy)]
+pub struct Args(pub [u64; 6]);
+
+impl Args {
+    fn fd(&self, index: usize) -> Fd {
+        Fd(usize::try_from(self.0[index]).unwrap_or(usize::MAX))
+    }
+
+    /// The buffer of the program at the argument `index` with the length at `index + 1`.
+    fn buffer(&self, index: usize) -> Result<&'static [u8], SyscallError> {
+        let (addr, len) = self.user_range(index, false)?;
+        // # Safety
+        // The range is mapped for ring 3 in the active address space, which stays active until the
+        // call returns.
+        Ok(unsafe { slice::from_raw_parts(addr.as_ptr(), len) })
+    }
+
+    /// The writable buffer of the program at the argument `index` with the length at `index + 1`.
+    #[allow(clippy::mut_from_ref)]
+    fn buffer_mut(&self, index: usize) -> Result<&'static mut [u8], SyscallError> {
+        let (addr, len) = self.user_range(index, true)?;
+        // # Safety
+        // As above, the range is writable, the kernel holds no other reference to it.
+        Ok(unsafe { slice::from_raw_parts_mut(addr.as_mut_ptr(), len) })
+    }
+
+    fn user_range(&self, index: usize, writable: bool) -> Result<(VirtAddr, usize), SyscallError> {
+        let (addr, len) = (self.0[index], self.0[index + 1]);
+        check_user(addr, len, writable)?;
+        let len = usize::try_from(len).map_err(|_| SyscallError::BadAddress)?;
+        // the null pointer of an empty buffer isn't dereferenced but must still be non-null
+        let addr = if len == 0 { USER_START } else { addr };
+        Ok((VirtAddr::new(addr), len))
+    }
+}
+
+/// Check that the `len` bytes at `addr` are in the user region and mapped for ring 3 in the active
+/// address space, writable if `writable` is set.
+pub fn check_user(addr: u64, len: u64, writable: bool) -> Result<(), SyscallError> {
+    if len == 0 {
+        return Ok(());
+    }
+    match addr.checked_add(len) {
+        Some(end) if addr >= USER_START && end <= USER_START + USER_SIZE => {}
+        _ => return Err(SyscallError::BadAddress),
+    }
+    let mut page = addr & !0xfff;
+    while page < addr + len {
+        let mapped = memory::translate(VirtAddr::new(page)).or_else(|| {
+            if super::handle_page_fault(VirtAddr::new(page), writable) {
+                memory::translate(VirtAddr::new(page))
+            } else {
+                None
+            }
+        });
+        match mapped {
+            Some((_, flags)) if flags.contains(memory::user_flags(writable)) => {}
+            _ => return Err(SyscallError::BadAddress),
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// A handler of a system call.
+type Handler = fn(Args) -> Result<u64, SyscallError>;
+
+/// The handlers of the system calls indexed by their number, with their names.
+static TABLE: [(&str, Handler); 15] = [
+    ("read", read),
+    ("write", write),
+    ("exit", exit),
+    ("sleep", sleep),
+    ("spawn", spawn),
+    ("getpid", getpid),
+    ("yield", yield_cpu),
+    ("wait", wait),
+    ("brk", brk),
+    ("sbrk", sbrk),
+    ("mmap", mmap),
+    ("munmap", munmap),
+    ("pipe", pipe),
+    ("close", close),
+    ("dup2", dup2),
+];
+
+/// The name of the system call `number`, `None` if there is no such call.
+pub fn name(number: u64) -> Option<&'static str> {
+    let index = usize::try_from(number).ok()?;
+    TABLE.get(index).map(|&(name, _)| name)
+}
+
+/// Run the system call `number` for the running program.
+pub fn call(number: u64, args: Args) -> Result<u64, SyscallError> {
+    let index = usize::try_from(number).map_err(|_| SyscallError::NoSuchCall)?;
+    let &(_, handler) = TABLE.get(index).ok_or(SyscallError::NoSuchCall)?;
+    handler(args)
+}
+
+fn read(args: Args) -> Result<u64, SyscallError> {
+    let buf = args.buffer_mut(1)?;
+    let read = super::with_program(|program| program.fds.read(args.fd(0), buf))
+        .ok_or(FsError::BadDescriptor)?;
+    match read {
+        Err(FsError::WouldBlock) => wait_ready(args.fd(0), false),
+        read => Ok(read? as u64),
+    }
+}
+
+fn write(args: Args) -> Result<u64, SyscallError> {
+    let buf = args.buffer(1)?;
+    let written = super::with_program(|program| program.fds.write(args.fd(0), buf))
+        .ok_or(FsError::BadDescriptor)?;
+    match written {
+        Err(FsError::WouldBlock) => wait_ready(args.fd(0), true),
+        written => Ok(written? as u64),
+    }
+}
+
+/// Block until `fd` is ready to be read, or written if `write` is set, then start the call over.
+/// Fails with [FsError::WouldBlock] if the file never becomes ready by itself.
+fn wait_ready(fd: Fd, write: bool) -> Result<u64, SyscallError> {
+    let ready = super::with_program(|program| program.fds.ready(fd, write))
+        .ok_or(FsError::BadDescriptor)??
+        .ok_or(FsError::WouldBlock)?;
+    super::block(Box::pin(async move {
+        ready.await;
+        Completion::Restart
+    }))
+}
+
+fn exit(args: Args) -> Result<u64, SyscallError> {
+    super::leave(Exit::Exited(args.0[0] as i32))
+}
+
+fn sleep(args: Args) -> Result<u64, SyscallError> {
+    let delay = timer::sleep(Duration::from_millis(args.0[0]));
+    super::block(Box::pin(async move {
+        delay.await;
+        Completion::Return(0)
+    }))
+}
+
+fn spawn(args: Args) -> Result<u64, SyscallError> {
+    let path = str::from_utf8(args.buffer(0)?).map_err(|_| SyscallError::InvalidArgument)?;
+    let code = fs::read(path)?;
+    let (parent, fds) = super::with_program(|program| (program.pid(), program.fds.inherit()))
+        .ok_or(FsError::BadDescriptor)?;
+    let pid = process::spawn_with(path, &code, Some(parent), fds)?;
+    Ok(pid.as_u64())
+}
+
+fn getpid(_: Args) -> Result<u64, SyscallError> {
+    let pid = super::with_program(|program| program.pid());
+    Ok(pid.map_or(0, Pid::as_u64))
+}
+
+fn yield_cpu(_: Args) -> Result<u64, SyscallError> {
+    super::with_program(|program| program.registers.rax = 0);
+    super::yield_cpu()
+}
+
+fn wait(args: Args) -> Result<u64, SyscallError> {
+    let parent = super::with_program(|program| program.pid());
+    let pid = match args.0[0] {
+        0 => None,
+        pid => Some(Pid::from_u64(pid)),
+    };
+    let status = args.0[1];
+    if status != 0 {
+        check_user(status, 4, true)?;
+    }
+    match process::try_wait(parent, pid)? {
+        Some((pid, exit)) => {
+            if status != 0 {
+                // # Safety
+                // The `i32` is mapped writable for ring 3 in the active address space.
+                unsafe { (status as *mut i32).write_unaligned(exit.status()) };
+            }
+            Ok(pid.as_u64())
+        }
+        // the call starts over to reap the child in the address space of the program
+        None => super::block(Box::pin(async move {
+            process::child_exited(parent, pid).await;
+            Completion::Restart
+        })),
+    }
+}
+
+fn brk(args: Args) -> Result<u64, SyscallError> {
+    let brk = super::with_program(|program| {
+        if args.0[0] != 0 {
+            // out of range, the heap doesn't move
+            let _ = VirtAddr::try_new(args.0[0])
+                .map(|brk| program.heap.set_brk(&mut program.space, brk));
+        }
+        program.heap.brk()
+    });
+    Ok(brk.ok_or(SyscallError::NoSuchCall)?.as_u64())
+}
+
+fn sbrk(args: Args) -> Result<u64, SyscallError> {
+    let increment = args.0[0] as i64;
+    let old = super::with_program(|program| {
+        let old = program.heap.brk();
+        let brk = if increment < 0 {
+            old.as_u64().checked_sub(increment.unsigned_abs())
+        } else {
+            old.as_u64().checked_add(increment as u64)
+        };
+        let brk = brk
+            .and_then(|brk| VirtAddr::try_new(brk).ok())
+            .ok_or(UserError::OutOfMemory)?;
+        program.heap.set_brk(&mut program.space, brk)?;
+        Ok(old)
+    })
+    .ok_or(SyscallError::NoSuchCall)?
+    .map_err(memory_error)?;
+    Ok(old.as_u64())
+}
+
+fn mmap(args: Args) -> Result<u64, SyscallError> {
+    let [_, len, prot, flags, ..] = args.0;
+    if flags != MAP_PRIVATE | MAP_ANONYMOUS {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let start = super::with_program(|program| {
+        program
+            .heap
+            .map(&mut program.space, len, prot & PROT_WRITE != 0)
+    })
+    .ok_or(SyscallError::NoSuchCall)?
+    .map_err(memory_error)?;
+    Ok(start.as_u64())
+}
+
+fn munmap(args: Args) -> Result<u64, SyscallError> {
+    let addr = VirtAddr::try_new(args.0[0]).map_err(|_| SyscallError::InvalidArgument)?;
+    super::with_program(|program| program.heap.unmap(&mut program.space, addr, args.0[1]))
+        .ok_or(SyscallError::NoSuchCall)?
+        .map_err(memory_error)?;
+    Ok(0)
+}
+
+fn pipe(args: Args) -> Result<u64, SyscallError> {
+    let fds = args.0[0];
+    check_user(fds, 8, true)?;
+    let (reader, writer) =
+        super::with_program(|program| program.fds.pipe()).ok_or(FsError::BadDescriptor)??;
+    // # Safety
+    // The two `i32` are mapped writable for ring 3 in the active address space.
+    unsafe {
+        let fds = fds as *mut i32;
+        fds.write_unaligned(reader.0 as i32);
+        fds.add(1).write_unaligned(writer.0 as i32);
+    }
+    Ok(0)
+}
+
+fn close(args: Args) -> Result<u64, SyscallError> {
+    super::with_program(|program| program.fds.close(args.fd(0)))
+        .ok_or(FsError::BadDescriptor)??;
+    Ok(0)
+}
+
+fn dup2(args: Args) -> Result<u64, SyscallError> {
+    let to = super::with_program(|program| program.fds.dup_to(args.fd(0), args.fd(1)))
+        .ok_or(FsError::BadDescriptor)??;
+    Ok(to.0 as u64)
+}
+
+/// The error of a call on the memory of the program: out of memory, or an invalid range.
+fn memory_error(err: UserError) -> SyscallError {
+    match err {
+        UserError::OutOfMemory => SyscallError::User(err),
+        _ => SyscallError::InvalidArgument,
+    }
+}
+
+/// Run the system call in `frame`, its result replaces the number in `rax`.
+extern "C" fn dispatch(frame: &mut Registers) {
+    CALLS.inc();
+    // for the calls which leave ring 3 and go on later
+    super::with_program(|program| program.registers = *frame);
+    let args = Args([
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ]);
+    interrupts::enable();
+    let result = call(frame.rax, args);
+    interrupts::disable();
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(err) => {
+            FAILURES.inc();
+            (-err.code()) as u64
+        }
+    };
+}
+
+/// The entry points of `syscall` and of `int 0x80`. They are stubs of assembly in the body of this
+/// function, which jumps over them: no calling convention of Rust hands the registers of the
+/// program over.
+///
+/// The entry of `syscall` switches to the kernel stack read from the TSS, and builds the interrupt
+/// frame `int 0x80` would have pushed out of the return address in `rcx` and the flags in `r11`.
+/// Both return with `iretq`.
+#[inline(never)]
+fn entry_points() -> (VirtAddr, VirtAddr) {
+    let (syscall_entry, interrupt_entry): (u64, u64);
+    // # Safety
+    // Only the addresses of the stubs are taken, the stubs aren't run here.
+    unsafe {
+        asm!(
+            "lea {syscall}, [rip + 2f]",
+            "lea {interrupt}, [rip + 3f]",
+            "jmp 4f",
+            // syscall: interrupts are masked by SFMASK, nothing else runs until the stack switch
+            "2:",
+            "mov [rip + {user_stack}], rsp",
+            "mov rsp, [rip + {stack_slot}]",
+            "mov rsp, [rsp]",
+            "and rsp, -16",
+            "push qword ptr [rip + {user_data}]",
+            "push qword ptr [rip + {user_stack}]",
+            "push r11",
+            "push qword ptr [rip + {user_code}]",
+            "push rcx",
+            // int 0x80: the CPU pushed the interrupt frame
+            "3:",
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rbx",
+            "push rbp",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // the direction flag of the program isn't cleared by interrupt gates
+            "cld",
+            "mov rdi, rsp",
+            "call {dispatch}",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdi",
+            "pop rsi",
+            "pop rbp",
+            "pop rbx",
+            "pop rdx",
+            "pop rcx",
+            "pop rax",
+            "iretq",
+            "4:",
+            syscall = out(reg) syscall_entry,
+            interrupt = out(reg) interrupt_entry,
+            user_stack = sym USER_STACK,
+            stack_slot = sym KERNEL_STACK_SLOT,
+            user_code = sym USER_CODE,
+            user_data = sym USER_DATA,
+            dispatch = sym dispatch,
+        );
+    }
+    (VirtAddr::new(syscall_entry), VirtAddr::new(interrupt_entry))
+}
+
+/// The entry of `int 0x80` as a handler of the IDT, though it isn't an `x86-interrupt` function.
+pub(crate) fn interrupt_handler() -> HandlerFunc {
+    let (_, entry) = entry_points();
+    // # Safety
+    // The stub returns with `iretq` as interrupt handlers do.
+    unsafe { core::mem::transmute::<u64, HandlerFunc>(entry.as_u64()) }
+}
+
+/// Enable the `syscall` instruction with its entry point. Must be called after [gdt::init].
+pub fn init() {
+    let selectors = gdt::selectors();
+    USER_CODE.store(u64::from(selectors.user_code.0), Ordering::Relaxed);
+    USER_DATA.store(u64::from(selectors.user_data.0), Ordering::Relaxed);
+    KERNEL_STACK_SLOT.store(gdt::kernel_stack_slot() as u64, Ordering::Relaxed);
+
+    let (entry, _) = entry_points();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("the GDT has the layout of syscall and sysret");
+    LStar::write(entry);
+    // the entry runs on the stack of the program until it switches
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    // # Safety
+    // The entry point and the selectors it uses are set.
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        allocator::HEAP_START,
+        testing,
+        time::Instant,
+        user::{heap::MMAP_START, run, PROGRAM_START, STACK_TOP},
+    };
+    use alloc::{vec, vec::Vec};
+
+    pub(crate) const RAX: u8 = 0;
+    pub(crate) const RDX: u8 = 2;
+    pub(crate) const RBX: u8 = 3;
+    pub(crate) const RSI: u8 = 6;
+    pub(crate) const RDI: u8 = 7;
+    pub(crate) const R10: u8 = 10;
+
+    /// Where [Code::with_data] puts its data.
+    pub(crate) const DATA: u64 = PROGRAM_START + 2;
+
+    /// A program in machine code, built instruction by instruction.
+    pub(crate) struct Code(pub(crate) Vec<u8>);
+
+    impl Code {
+        pub(crate) fn new() -> Self {
+            Code(Vec::new())
+        }
+
+        /// Start with `data` at [DATA], jumped over.
+        pub(crate) fn with_data(data: &[u8]) -> Self {
+            let mut code = vec![0xeb, data.len() as u8];
+            code.extend_from_slice(data);
+            Code(code)
+        }
+
+        /// `movabs reg, value`.
+        pub(crate) fn mov(mut self, reg: u8, value: u64) -> Self {
+            self.0
+                .extend_from_slice(&[0x48 | reg >> 3, 0xb8 + (reg & 7)]);
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        pub(crate) fn op(mut self, bytes: &[u8]) -> Self {
+            self.0.extend_from_slice(bytes);
+            self
+        }
+
+        pub(crate) fn syscall(self) -> Self {
+            self.op(&[0x0f, 0x05])
+        }
+
+        pub(crate) fn int_0x80(self) -> Self {
+            self.op(&[0xcd, INTERRUPT_VECTOR])
+        }
+
+        /// Exit with the result of the last call as status.
+        pub(crate) fn exit_with_result(self) -> Self {
+            // mov rdi, rax
+            self.op(&[0x48, 0x89, 0xc7]).mov(RAX, EXIT).syscall()
+        }
+
+        pub(crate) fn run(&self) -> Exit {
+            run(&self.0, 0).unwrap().1
+        }
+    }
+
+    fn error(err: SyscallError) -> Exit {
+        Exit::Exited(-err.code() as i32)
+    }
+
+    #[test_case]
+    fn both_entries_exit() {
+        let code = Code::new().mov(RDI, 7).mov(RAX, EXIT).syscall();
+        assert_eq!(code.run(), Exit::Exited(7));
+        let code = Code::new().mov(RDI, 9).mov(RAX, EXIT).int_0x80();
+        assert_eq!(code.run(), Exit::Exited(9));
+        let code = Code::new().mov(RAX, 99).syscall().exit_with_result();
+        assert_eq!(code.run(), error(SyscallError::NoSuchCall));
+        assert_eq!(name(EXIT), Some("exit"));
+    }
+
+    #[test_case]
+    fn registers_preserved() {
+        // failed calls through both entries, then exit with rdx
+        let code = Code::new()
+            .mov(RDX, 42)
+            .mov(RAX, 99)
+            .int_0x80()
+            .mov(RAX, 99)
+            .syscall()
+            // mov rdi, rdx
+            .op(&[0x48, 0x89, 0xd7])
+            .mov(RAX, EXIT)
+            .syscall();
+        assert_eq!(code.run(), Exit::Exited(42));
+    }
+
+    #[test_case]
+    fn written_to_console() {
+        let message = b"hello from ring 3\n";
+        let code = Code::with_data(message)
+            .mov(RDI, 1)
+            .mov(RSI, DATA)
+            .mov(RDX, message.len() as u64)
+            .mov(RAX, WRITE)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), Exit::Exited(message.len() as i32));
+
+        let code = Code::with_data(message)
+            .mov(RDI, 9)
+            .mov(RSI, DATA)
+            .mov(RDX, 1)
+            .mov(RAX, WRITE)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), error(SyscallError::Fs(FsError::BadDescriptor)));
+
+        // the console has nothing to read without waiting, into the stack
+        let code = Code::new()
+            .mov(RDI, 0)
+            .mov(RSI, STACK_TOP - 8)
+            .mov(RDX, 8)
+            .mov(RAX, READ)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), error(SyscallError::Fs(FsError::WouldBlock)));
+    }
+
+    #[test_case]
+    fn user_pointers_checked() {
+        let write_from = |addr: u64| {
+            Code::new()
+                .mov(RDI, 1)
+                .mov(RSI, addr)
+                .mov(RDX, 8)
+                .mov(RAX, WRITE)
+                .syscall()
+                .exit_with_result()
+                .run()
+        };
+        let bad_address = error(SyscallError::BadAddress);
+        // the kernel, a hole of the user region and the end of the address space
+        assert_eq!(write_from(HEAP_START as u64), bad_address);
+        assert_eq!(write_from(USER_START), bad_address);
+        assert_eq!(write_from(u64::MAX - 4), bad_address);
+
+        // the code is read-only
+        let code = Code::new()
+            .mov(RDI, 0)
+            .mov(RSI, PROGRAM_START)
+            .mov(RDX, 8)
+            .mov(RAX, READ)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), bad_address);
+
+        assert_eq!(check_user(HEAP_START as u64, 0, true), Ok(()));
+        assert_eq!(
+            check_user(USER_START, 1, false),
+            Err(SyscallError::BadAddress)
+        );
+    }
+
+    #[test_case]
+    fn process_ids_distinct() {
+        let getpid = || match Code::new()
+            .mov(RAX, GETPID)
+            .syscall()
+            .exit_with_result()
+            .run()
+        {
+            Exit::Exited(pid) => pid,
+            exit => panic!("getpid failed: {}", exit),
+        };
+        let first = getpid();
+        assert!(first > 0);
+        assert!(getpid() > first);
+    }
+
+    #[test_case]
+    fn sleeps_and_yields() {
+        let start = Instant::now();
+        let code = Code::new()
+            .mov(RDI, 20)
+            .mov(RAX, SLEEP)
+            .syscall()
+            .exit_with_result();
+        // the timer wheel keeps its slot
+        let exit = testing::keep_allocations(|| code.run());
+        assert_eq!(exit, Exit::Exited(0));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let code = Code::new().mov(RAX, YIELD).syscall().exit_with_result();
+        assert_eq!(code.run(), Exit::Exited(0));
+    }
+
+    #[test_case]
+    fn missing_program_not_spawned() {
+        let missing = Code::with_data(b"/tmp/missing")
+            .mov(RDI, DATA)
+            .mov(RSI, 12)
+            .mov(RAX, SPAWN)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(missing.run(), error(SyscallError::Fs(FsError::NotFound)));
+    }
+
+    #[test_case]
+    fn heap_grown_and_shrunk() {
+        let code = Code::new()
+            .mov(RDI, 0)
+            .mov(RAX, BRK)
+            .syscall()
+            // mov rbx, rax; lea rdi, [rbx + 0x3000]
+            .op(&[0x48, 0x89, 0xc3, 0x48, 0x8d, 0xbb, 0x00, 0x30, 0x00, 0x00])
+            .mov(RAX, BRK)
+            .syscall()
+            // mov qword ptr [rbx + 0x2ff8], 42; mov rdi, [rbx + 0x2ff8]
+            .op(&[
+                0x48, 0xc7, 0x83, 0xf8, 0x2f, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00,
+            ])
+            .op(&[0x48, 0x8b, 0xbb, 0xf8, 0x2f, 0x00, 0x00])
+            .mov(RAX, EXIT)
+            .syscall();
+        assert_eq!(code.run(), Exit::Exited(42));
+
+        // the heap shrinks back to its start, its first page is gone
+        let code = Code::new()
+            .mov(RDI, 0x1000)
+            .mov(RAX, SBRK)
+            .syscall()
+            // mov rbx, rax; mov byte ptr [rbx], 1
+            .op(&[0x48, 0x89, 0xc3, 0xc6, 0x03, 0x01])
+            .mov(RDI, (-0x1000i64) as u64)
+            .mov(RAX, SBRK)
+            .syscall()
+            // mov byte ptr [rbx], 1
+            .op(&[0xc6, 0x03, 0x01]);
+        match code.run() {
+            Exit::Exception {
+                vector, address, ..
+            } => {
+                assert_eq!(vector, crate::interrupts::PAGE_FAULT_VECTOR);
+                assert!(address.unwrap().as_u64() > PROGRAM_START);
+            }
+            exit => panic!("the heap is still mapped: {}", exit),
+        }
+
+        let code = Code::new()
+            .mov(RDI, (-0x1000i64) as u64)
+            .mov(RAX, SBRK)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), error(SyscallError::InvalidArgument));
+    }
+
+    #[test_case]
+    fn anonymous_pages_mapped() {
+        let mmap = |len: u64, flags: u64| {
+            Code::new()
+                .mov(RDI, 0)
+                .mov(RSI, len)
+                .mov(RDX, PROT_WRITE)
+                .mov(R10, flags)
+                .mov(RAX, MMAP)
+        };
+        // the kernel reads from the console into the 16 pages, mapped by then
+        let code = mmap(0x10000, MAP_PRIVATE | MAP_ANONYMOUS)
+            .syscall()
+            // mov rsi, rax
+            .op(&[0x48, 0x89, 0xc6])
+            .mov(RDI, 0)
+            .mov(RDX, 0x10000)
+            .mov(RAX, READ)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), error(SyscallError::Fs(FsError::WouldBlock)));
+
+        // written, then gone once unmapped
+        let code = mmap(0x10000, MAP_PRIVATE | MAP_ANONYMOUS)
+            .syscall()
+            // mov rbx, rax; mov qword ptr [rbx + 0x8000], 7; mov rdi, rbx
+            .op(&[0x48, 0x89, 0xc3])
+            .op(&[
+                0x48, 0xc7, 0x83, 0x00, 0x80, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+            ])
+            .op(&[0x48, 0x89, 0xdf])
+            .mov(RSI, 0x10000)
+            .mov(RAX, MUNMAP)
+            .syscall()
+            // mov rdi, [rbx + 0x8000]
+            .op(&[0x48, 0x8b, 0xbb, 0x00, 0x80, 0x00, 0x00]);
+        match code.run() {
+            Exit::Exception { address, .. } => {
+                assert_eq!(address, Some(VirtAddr::new(MMAP_START + 0x8000)))
+            }
+            exit => panic!("the mapping is still there: {}", exit),
+        }
+
+        let code = mmap(0x1000, MAP_ANONYMOUS).syscall().exit_with_result();
+        assert_eq!(code.run(), error(SyscallError::InvalidArgument));
+    }
+
+    #[test_case]
+    fn pipe_read_back() {
+        let message = b"ping";
+        let code = Code::with_data(message)
+            .mov(RBX, STACK_TOP - 16)
+            .mov(RDI, STACK_TOP - 16)
+            .mov(RAX, PIPE)
+            .syscall()
+            // mov edi, [rbx + 4]
+            .op(&[0x8b, 0x7b, 0x04])
+            .mov(RSI, DATA)
+            .mov(RDX, message.len() as u64)
+            .mov(RAX, WRITE)
+            .syscall()
+            .op(&[0x8b, 0x7b, 0x04])
+            .mov(RAX, CLOSE)
+            .syscall()
+            // mov edi, [rbx]
+            .op(&[0x8b, 0x3b])
+            .mov(RSI, STACK_TOP - 64)
+            .mov(RDX, 16)
+            .mov(RAX, READ)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), Exit::Exited(message.len() as i32));
+
+        let code = Code::new()
+            .mov(RDI, 1)
+            .mov(RSI, 7)
+            .mov(RAX, DUP2)
+            .syscall()
+            .exit_with_result();
+        assert_eq!(code.run(), Exit::Exited(7));
+    }
+}
@@ -1,30 +1,68 @@
+use alloc::{boxed::Box, vec};
 use core::fmt;
 
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
+use crate::{io::PortRange, locked::Locked};
+
 /// The physical memory address of memory-mapped VGA buffer, which is identity-mapped to the same
 /// virtual memory address by the bootloader.
 pub const VGA_PHYSICAL_ADDR: u64 = 0xb8000;
 
+/// The index and data ports of the CRT controller, whose registers hold the hardware cursor.
+const CRTC_PORT: u16 = 0x3d4;
+const CRTC_INDEX: u16 = 0;
+const CRTC_DATA: u16 = 1;
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_END: u8 = 0x0b;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+/// Set in [CURSOR_START] to hide the cursor.
+const CURSOR_DISABLED: u8 = 1 << 5;
+/// The first and last scanlines of the 16 of a character cell covered by the cursor, an underline
+/// blinking as long as the cursor is shown.
+const CURSOR_SCANLINES: (u8, u8) = (14, 15);
+
 lazy_static! {
     /// A global interface to the VGA text buffer. Unlike in the blog posts text starts from the top
     /// left of the screen.
-    pub static ref WRITER: Mutex<Writer> = {
+    pub static ref WRITER: Locked<Writer> = {
+        // # Safety
+        // 0xb8000 is the address to the memory mapped VGA text buffer, memory layout is ensured
+        // by repr(C) or repr(transparent) on corresponding types, the buffer is bounded by the
+        // [Buffer] type, by lazy_static and Locked the buffer is never concurrently accessed.
+        let buffer: &'static mut Buffer = unsafe { &mut *(VGA_PHYSICAL_ADDR as *mut Buffer) };
+        // the shadow starts as what the firmware and the bootloader left on the screen
+        let mut shadow = [[ScreenChar {
+            cp437_code: b' ',
+            color_code: ColorCode::new(Color::Yellow, Color::Black),
+        }; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        for (chars, cells) in shadow.iter_mut().zip(buffer.chars.iter()) {
+            for (char, cell) in chars.iter_mut().zip(cells.iter()) {
+                *char = cell.read();
+            }
+        }
+
         let writer = Writer {
             row_position: 0,
             column_position: 0,
             color_code: ColorCode::new(Color::Yellow, Color::Black),
+            scrollback: None,
+            cursor_visible: true,
             /// # Safety
-            /// 0xb8000 is the address to the memory mapped VGA text buffer, memory layout is
-            /// ensured by repr(C) or repr(transparent) on corresponding types, the buffer is
-            /// bounded by the [Buffer] type, by lazy_static and Mutex the buffer is never
-            /// concurrently accessed.
-            buffer: unsafe { &mut *(VGA_PHYSICAL_ADDR as *mut Buffer) },
+            /// The ports of the CRT controller only control the display, lazy_static ensures they
+            /// are claimed once.
+            crtc: unsafe { PortRange::claim(CRTC_PORT, 2, "vga") }
+                .expect("the ports of the CRT controller are claimed by another driver"),
+            buffer,
+            shadow,
+            dirty: None,
+            batching: false,
+            pointer: None,
         };
 
-        Mutex::new(writer)
+        Locked::new(writer)
     };
 }
 
@@ -82,6 +120,11 @@ impl ColorCode {
     pub fn blink(self) -> Self {
         Self(self.0 | Self::BLINK_BIT)
     }
+
+    /// Swap the foreground and the background colors, the foreground loses its light variant.
+    fn inverted(self) -> Self {
+        Self((self.0 & 0x07) << 4 | (self.0 >> 4) & 0x07)
+    }
 }
 
 /// A code page 437 character with color code. repr(C) ensures the order of fields is not messed by
@@ -95,27 +138,116 @@ struct ScreenChar {
 
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+/// The distance between two tab stops.
+const TAB_WIDTH: usize = 8;
+const BACKSPACE: u8 = 0x08;
+
+/// The number of screens of rows kept after they scroll off the top of the screen.
+pub const SCROLLBACK_SCREENS: usize = 4;
 
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+/// The rows scrolled off the top of the screen, allocated once by [init_scrollback] so that
+/// printing never allocates, e.g. in interrupt handlers.
+struct Scrollback {
+    /// A ring of rows, the oldest row is overwritten first.
+    rows: Box<[Row]>,
+    /// The index of the oldest row in `rows`.
+    start: usize,
+    len: usize,
+    /// The number of rows the view is scrolled back, 0 when the screen shows the latest output.
+    offset: usize,
+}
+
+impl Scrollback {
+    fn new(screens: usize) -> Self {
+        let blank = [ScreenChar {
+            cp437_code: b' ',
+            color_code: ColorCode::new(Color::Yellow, Color::Black),
+        }; BUFFER_WIDTH];
+
+        Scrollback {
+            rows: vec![blank; screens * BUFFER_HEIGHT].into_boxed_slice(),
+            start: 0,
+            len: 0,
+            offset: 0,
+        }
+    }
+
+    fn push(&mut self, row: Row) {
+        let capacity = self.rows.len();
+        if self.len < capacity {
+            self.rows[(self.start + self.len) % capacity] = row;
+            self.len += 1;
+        } else {
+            self.rows[self.start] = row;
+            self.start = (self.start + 1) % capacity;
+        }
+    }
+
+    /// The row `index` from the oldest row kept, followed by the rows of `screen`.
+    fn row<'a>(&'a self, screen: &'a [Row], index: usize) -> &'a Row {
+        if index < self.len {
+            &self.rows[(self.start + index) % self.rows.len()]
+        } else {
+            &screen[index - self.len]
+        }
+    }
+}
+
 #[doc(hidden)]
 pub struct Writer {
     row_position: usize,
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    /// The screen edited in ordinary memory, the rows changed are copied to `buffer` by
+    /// [Writer::flush] instead of editing the slow memory-mapped buffer in place.
+    shadow: [Row; BUFFER_HEIGHT],
+    /// The first and the last rows of `shadow` changed since the last flush.
+    dirty: Option<(usize, usize)>,
+    /// Set during a `write_fmt` call, which is flushed once at its end.
+    batching: bool,
+    /// The row and the column of the mouse pointer, drawn over `shadow` in inverted colors.
+    pointer: Option<(usize, usize)>,
+    scrollback: Option<Scrollback>,
+    /// Whether the hardware cursor is shown while the latest output is on the screen.
+    cursor_visible: bool,
+    crtc: PortRange,
 }
 
 impl Writer {
     /// If `byte` is '\n' or current row is full, switch to a next line by possibly moving all
-    /// previous rows upwards; otherwise write a byte as a code page 437 character to the VGA text
-    /// buffer with the stored color code.
+    /// previous rows upwards; a backspace erases the previous cell, possibly on the previous row,
+    /// and a tab advances to the next stop every [TAB_WIDTH] columns; otherwise write a byte as a
+    /// code page 437 character to the VGA text buffer with the stored color code.
     fn write_byte(&mut self, byte: u8) {
+        // new output brings the view back to the bottom, as on the Linux console
+        self.scroll_to_bottom();
+
         match byte {
             b'\n' => self.new_line(),
+            BACKSPACE => {
+                if let Some(position) = self.linear_position().checked_sub(1) {
+                    self.set_linear_position(position);
+                    let (row, col) = (self.row_position, self.column_position);
+                    self.put(row, col, b' ');
+                }
+            }
+            b'\t' => {
+                if self.column_position >= BUFFER_WIDTH {
+                    self.new_line();
+                }
+                let stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < stop.min(BUFFER_WIDTH) {
+                    self.write_byte(b' ');
+                }
+            }
             _ => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -123,12 +255,7 @@ impl Writer {
 
                 let row = self.row_position;
                 let col = self.column_position;
-
-                self.buffer.chars[row][col].write(ScreenChar {
-                    cp437_code: byte,
-                    color_code: self.color_code,
-                });
-
+                self.put(row, col, byte);
                 self.column_position += 1;
             }
         }
@@ -138,28 +265,228 @@ impl Writer {
         if self.row_position < BUFFER_HEIGHT - 1 {
             self.row_position += 1;
         } else {
-            for row in 1..BUFFER_HEIGHT {
-                for col in 0..BUFFER_WIDTH {
-                    let char = self.buffer.chars[row][col].read();
-                    self.buffer.chars[row - 1][col].write(char);
-                }
+            if let Some(scrollback) = &mut self.scrollback {
+                scrollback.push(self.shadow[0]);
             }
-
+            self.shadow.copy_within(1.., 0);
+            self.dirty = Some((0, BUFFER_HEIGHT - 1));
             self.clear_row(BUFFER_HEIGHT - 1);
         }
 
         self.column_position = 0;
     }
 
+    /// Move the position of the next character `n` cells backwards, possibly to previous rows.
+    /// Stops at the top left of the screen.
+    pub fn move_left(&mut self, n: usize) {
+        let position = self.linear_position().saturating_sub(n);
+        self.set_linear_position(position);
+    }
+
+    /// Move the position of the next character `n` cells forwards, possibly to following rows.
+    /// Stops at the bottom right of the screen.
+    pub fn move_right(&mut self, n: usize) {
+        let position = self.linear_position().saturating_add(n);
+        self.set_linear_position(position.min(BUFFER_HEIGHT * BUFFER_WIDTH - 1));
+    }
+
+    /// The position of the next character counted from the top left of the screen row by row. A
+    /// full row (`column_position == BUFFER_WIDTH`) is the same as the start of the next row.
+    fn linear_position(&self) -> usize {
+        self.row_position * BUFFER_WIDTH + self.column_position
+    }
+
+    fn set_linear_position(&mut self, position: usize) {
+        self.row_position = position / BUFFER_WIDTH;
+        self.column_position = position % BUFFER_WIDTH;
+        self.update_cursor();
+    }
+
+    /// The row and the column of the next character, from the top left of the screen.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /// Move the position of the next character to `row` and `column`, clamped to the screen.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        let row = row.min(BUFFER_HEIGHT - 1);
+        let column = column.min(BUFFER_WIDTH - 1);
+        self.set_linear_position(row * BUFFER_WIDTH + column);
+    }
+
+    /// Move the position of the next character by `rows` rows and `columns` columns, clamped to
+    /// the screen.
+    pub fn move_position(&mut self, rows: isize, columns: isize) {
+        let offset = |position: usize, delta: isize| {
+            if delta < 0 {
+                position.saturating_sub(delta.unsigned_abs())
+            } else {
+                position.saturating_add(delta as usize)
+            }
+        };
+        self.set_position(
+            offset(self.row_position, rows),
+            offset(self.column_position, columns),
+        );
+    }
+
+    /// Show or hide the hardware cursor. It's hidden anyway while the screen is scrolled back.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        self.update_cursor();
+    }
+
+    fn crtc_write(&self, index: u8, value: u8) {
+        self.crtc.write(CRTC_INDEX, index);
+        self.crtc.write(CRTC_DATA, value);
+    }
+
+    fn crtc_read(&self, index: u8) -> u8 {
+        self.crtc.write(CRTC_INDEX, index);
+        self.crtc.read(CRTC_DATA)
+    }
+
+    /// Put the hardware cursor at the position of the next character. The controller blinks the
+    /// cursor by itself.
+    fn update_cursor(&mut self) {
+        let scrolled = matches!(&self.scrollback, Some(scrollback) if scrollback.offset > 0);
+        let (top, bottom) = CURSOR_SCANLINES;
+        if !self.cursor_visible || scrolled {
+            self.crtc_write(CURSOR_START, top | CURSOR_DISABLED);
+            return;
+        }
+
+        // a full row is shown as the start of the next row, or past the screen on the last row
+        let position = self.linear_position() as u16;
+        self.crtc_write(CURSOR_START, top);
+        self.crtc_write(CURSOR_END, bottom);
+        self.crtc_write(CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        self.crtc_write(CURSOR_LOCATION_LOW, position as u8);
+    }
+
+    /// Blank the whole screen, the next character is written to the top left. The rows scrolled
+    /// back are kept.
+    fn clear(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_linear_position(0);
+        self.flush();
+    }
+
+    /// Write `code` with the stored color code to the cell in `row` and `col` of the shadow.
+    fn put(&mut self, row: usize, col: usize, code: u8) {
+        self.shadow[row][col] = ScreenChar {
+            cp437_code: code,
+            color_code: self.color_code,
+        };
+        self.touch(row);
+    }
+
+    fn touch(&mut self, row: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((top, bottom)) => (top.min(row), bottom.max(row)),
+            None => (row, row),
+        });
+    }
+
+    /// Copy the rows changed since the last flush to the VGA text buffer, unless the view is
+    /// scrolled back, and move the hardware cursor.
+    fn flush(&mut self) {
+        let scrolled = matches!(&self.scrollback, Some(scrollback) if scrollback.offset > 0);
+        if let (Some((top, bottom)), false) = (self.dirty, scrolled) {
+            let rows = self.buffer.chars[top..=bottom].iter_mut();
+            for (cells, chars) in rows.zip(self.shadow[top..=bottom].iter()) {
+                for (cell, &char) in cells.iter_mut().zip(chars.iter()) {
+                    cell.write(char);
+                }
+            }
+            self.dirty = None;
+            self.draw_pointer();
+        }
+        self.update_cursor();
+    }
+
+    /// Move the mouse pointer to the cell in `row` and `column`, clamped to the screen, or hide it.
+    pub fn set_pointer(&mut self, pointer: Option<(usize, usize)>) {
+        // the rows are copied again from the shadow, erasing the previous pointer
+        if let Some((row, _)) = self.pointer {
+            self.touch(row);
+        }
+        self.pointer =
+            pointer.map(|(row, column)| (row.min(BUFFER_HEIGHT - 1), column.min(BUFFER_WIDTH - 1)));
+        if let Some((row, _)) = self.pointer {
+            self.touch(row);
+        }
+        self.flush();
+    }
+
+    fn draw_pointer(&mut self) {
+        if let Some((row, column)) = self.pointer {
+            let char = self.shadow[row][column];
+            self.buffer.chars[row][column].write(ScreenChar {
+                color_code: char.color_code.inverted(),
+                ..char
+            });
+        }
+    }
+
+    /// Scroll the view `n` rows back into the rows scrolled off the screen, stops at the oldest
+    /// row kept. Returns the number of rows the view is now scrolled back.
+    pub fn scroll_up(&mut self, n: usize) -> usize {
+        self.scroll_to(|scrollback| scrollback.offset.saturating_add(n).min(scrollback.len))
+    }
+
+    /// Scroll the view `n` rows forwards, stops at the latest output. Returns the number of rows
+    /// the view is still scrolled back.
+    pub fn scroll_down(&mut self, n: usize) -> usize {
+        self.scroll_to(|scrollback| scrollback.offset.saturating_sub(n))
+    }
+
+    fn scroll_to_bottom(&mut self) {
+        if matches!(&self.scrollback, Some(scrollback) if scrollback.offset > 0) {
+            self.scroll_to(|_| 0);
+        }
+    }
+
+    /// Show the screen scrolled back by the offset `f` computes from the scrollback.
+    fn scroll_to(&mut self, f: impl FnOnce(&Scrollback) -> usize) -> usize {
+        let mut scrollback = match self.scrollback.take() {
+            Some(scrollback) => scrollback,
+            None => return 0,
+        };
+
+        // the latest output stays in the shadow while the view is scrolled back
+        let offset = f(&scrollback);
+        if offset != scrollback.offset {
+            let top = scrollback.len - offset;
+            for (row, cells) in self.buffer.chars.iter_mut().enumerate() {
+                let chars = scrollback.row(&self.shadow, top + row);
+                for (cell, &char) in cells.iter_mut().zip(chars.iter()) {
+                    cell.write(char);
+                }
+            }
+            scrollback.offset = offset;
+            if offset == 0 {
+                self.dirty = None;
+                self.draw_pointer();
+            }
+        }
+
+        self.scrollback = Some(scrollback);
+        self.update_cursor();
+        offset
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank: ScreenChar = ScreenChar {
             cp437_code: b' ',
             color_code: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
-        }
+        self.shadow[row] = [blank; BUFFER_WIDTH];
+        self.touch(row);
     }
 }
 
@@ -169,20 +496,32 @@ impl fmt::Write for Writer {
 
         for byte in s.bytes() {
             let code = match byte {
-                0x20..=0x7e | b'\n' => byte,
+                0x20..=0x7e | b'\n' | b'\t' | BACKSPACE => byte,
                 _ => UNPRINTABLE,
             };
 
             self.write_byte(code);
         }
+        if !self.batching {
+            self.flush();
+        }
 
         Ok(())
     }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+        // the pieces of the arguments are copied to the screen at once
+        self.batching = true;
+        let result = fmt::write(self, args);
+        self.batching = false;
+        self.flush();
+        result
+    }
 }
 
 #[macro_export]
-/// Prints to the VGA text buffer. When the current line is full switch to a next line by possibly
-/// moving all previous rows upwards.
+/// Prints to the VGA text buffer, or to the framebuffer console once enabled. When the current
+/// line is full switch to a next line by possibly moving all previous rows upwards.
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
 }
@@ -199,6 +538,12 @@ pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    // the framebuffer replaces the text buffer once enabled
+    if crate::framebuffer::is_active() {
+        crate::framebuffer::_print(args);
+        return;
+    }
+
     // An interrupt when the WRITER is locked may trigger a handler that itself invokes `print!`,
     // hence try to acquire the mutex again and deadlock.
     interrupts::without_interrupts(|| {
@@ -206,6 +551,126 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Move the position of the next character printed to the VGA text buffer `n` cells backwards.
+pub fn cursor_left(n: usize) {
+    use x86_64::instructions::interrupts;
+
+    if crate::framebuffer::is_active() {
+        crate::framebuffer::cursor_left(n);
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().move_left(n);
+    });
+}
+
+/// Move the position of the next character printed to the VGA text buffer `n` cells forwards.
+pub fn cursor_right(n: usize) {
+    use x86_64::instructions::interrupts;
+
+    if crate::framebuffer::is_active() {
+        crate::framebuffer::cursor_right(n);
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().move_right(n);
+    });
+}
+
+/// Move the position of the next character printed to the VGA text buffer, along with the
+/// hardware cursor, to `row` and `column` from the top left. Clamped to the screen.
+pub fn set_cursor_position(row: usize, column: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_position(row, column);
+    });
+}
+
+/// Move the position of the next character printed to the VGA text buffer by `rows` rows and
+/// `columns` columns, negative towards the top left. Clamped to the screen.
+pub fn move_cursor(rows: isize, columns: isize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().move_position(rows, columns);
+    });
+}
+
+/// The row and the column of the next character printed to the VGA text buffer.
+pub fn cursor_position() -> (usize, usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().position())
+}
+
+/// Show or hide the blinking hardware cursor of the VGA text buffer, shown on boot.
+pub fn show_cursor(visible: bool) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_cursor_visible(visible);
+    });
+}
+
+/// Keep the rows scrolled off the top of the VGA text buffer, up to [SCROLLBACK_SCREENS] screens,
+/// for [scroll_up]. Called once the kernel heap is initialized.
+pub fn init_scrollback() {
+    use x86_64::instructions::interrupts;
+
+    // allocated and freed outside of the lock taken by every print
+    let scrollback = Scrollback::new(SCROLLBACK_SCREENS);
+    let _unused = interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        match writer.scrollback {
+            Some(_) => Some(scrollback),
+            None => writer.scrollback.replace(scrollback),
+        }
+    });
+}
+
+/// Scroll the VGA text buffer `n` rows back, returns the number of rows it is scrolled back. Any
+/// output scrolls it back to the bottom.
+pub fn scroll_up(n: usize) -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().scroll_up(n))
+}
+
+/// Scroll the VGA text buffer `n` rows forwards, returns the number of rows it is still scrolled
+/// back.
+pub fn scroll_down(n: usize) -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().scroll_down(n))
+}
+
+/// The number of rows and columns of the VGA text buffer.
+pub fn size() -> (usize, usize) {
+    (BUFFER_HEIGHT, BUFFER_WIDTH)
+}
+
+/// Draw the mouse pointer over the cell in `row` and `column` of the VGA text buffer in inverted
+/// colors, or hide it with `None`. Hidden on boot.
+pub fn set_mouse_pointer(pointer: Option<(usize, usize)>) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_pointer(pointer);
+    });
+}
+
+/// Blank the VGA text buffer, the next character is printed to the top left.
+pub fn clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear();
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -239,4 +704,184 @@ mod tests {
             }
         })
     }
+    #[test_case]
+    fn test_cursor_movement() {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writeln!(writer).expect("writeln failed");
+            let row = writer.row_position;
+            write!(writer, "abc").expect("write failed");
+            writer.move_left(2);
+            write!(writer, "x").expect("write failed");
+            writer.move_right(1);
+            write!(writer, "y").expect("write failed");
+
+            for (i, c) in "axcy".chars().enumerate() {
+                let screen_char = writer.buffer.chars[row][i].read();
+                assert_eq!(char::from(screen_char.cp437_code), c);
+            }
+        })
+    }
+
+    #[test_case]
+    fn test_scrollback() {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+
+        fn top_row_starts_with(writer: &Writer, s: &str) -> bool {
+            s.bytes()
+                .zip(writer.buffer.chars[0].iter())
+                .all(|(byte, cell)| cell.read().cp437_code == byte)
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            // the screen is cleared before each test, lines 0 to 2 scroll off the top
+            for i in 0..BUFFER_HEIGHT + 2 {
+                writeln!(writer, "scrollback {}", i).expect("writeln failed");
+            }
+            assert!(top_row_starts_with(&writer, "scrollback 3"));
+
+            assert_eq!(writer.scroll_up(1), 1);
+            assert!(top_row_starts_with(&writer, "scrollback 2"));
+            assert!(writer.scroll_up(usize::MAX) >= 3);
+            assert_eq!(writer.scroll_down(usize::MAX), 0);
+            assert!(top_row_starts_with(&writer, "scrollback 3"));
+
+            // new output scrolls back to the bottom
+            writer.scroll_up(2);
+            write!(writer, "x").expect("write failed");
+            assert!(top_row_starts_with(&writer, "scrollback 3"));
+        })
+    }
+
+    #[test_case]
+    fn test_hardware_cursor() {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+
+        fn location(writer: &Writer) -> usize {
+            let high = writer.crtc_read(CURSOR_LOCATION_HIGH) as usize;
+            let low = writer.crtc_read(CURSOR_LOCATION_LOW) as usize;
+            high << 8 | low
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.set_position(3, 10);
+            assert_eq!(location(&writer), 3 * BUFFER_WIDTH + 10);
+            write!(writer, "abc").expect("write failed");
+            assert_eq!(writer.position(), (3, 13));
+            assert_eq!(location(&writer), 3 * BUFFER_WIDTH + 13);
+
+            writer.move_position(-5, 100);
+            assert_eq!(writer.position(), (0, BUFFER_WIDTH - 1));
+            writer.set_position(BUFFER_HEIGHT, 0);
+            assert_eq!(location(&writer), (BUFFER_HEIGHT - 1) * BUFFER_WIDTH);
+
+            writer.set_cursor_visible(false);
+            assert_ne!(writer.crtc_read(CURSOR_START) & CURSOR_DISABLED, 0);
+            writer.set_cursor_visible(true);
+            assert_eq!(writer.crtc_read(CURSOR_START) & CURSOR_DISABLED, 0);
+        })
+    }
+
+    #[test_case]
+    fn test_backspace_and_tab() {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+
+        fn cell(writer: &Writer, row: usize, col: usize) -> char {
+            char::from(writer.buffer.chars[row][col].read().cp437_code)
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writeln!(writer).expect("writeln failed");
+            let row = writer.row_position;
+            write!(writer, "ab\tc\x08d").expect("write failed");
+            assert_eq!(cell(&writer, row, 1), 'b');
+            assert_eq!(cell(&writer, row, 8), 'd');
+            assert_eq!(writer.position(), (row, 9));
+
+            // the last cell of the previous row is erased
+            writer.set_position(row, BUFFER_WIDTH - 1);
+            write!(writer, "ef").expect("write failed");
+            assert_eq!(writer.position(), (row + 1, 1));
+            write!(writer, "\x08\x08").expect("write failed");
+            assert_eq!(writer.position(), (row, BUFFER_WIDTH - 1));
+            assert_eq!(cell(&writer, row, BUFFER_WIDTH - 1), ' ');
+            assert_eq!(cell(&writer, row + 1, 0), ' ');
+
+            // a tab in the last stop fills the row
+            writer.set_position(row, BUFFER_WIDTH - 3);
+            write!(writer, "\tg").expect("write failed");
+            assert_eq!(writer.position(), (row + 1, 1));
+            assert_eq!(cell(&writer, row + 1, 0), 'g');
+        })
+    }
+
+    #[test_case]
+    fn test_shadow_flushed() {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writeln!(writer).expect("writeln failed");
+            let row = writer.row_position;
+
+            // edits stay in the shadow until flushed
+            writer.batching = true;
+            writer.write_str("z").expect("write failed");
+            assert_eq!(writer.shadow[row][0].cp437_code, b'z');
+            assert_ne!(writer.buffer.chars[row][0].read().cp437_code, b'z');
+            writer.batching = false;
+            writer.flush();
+            assert_eq!(writer.buffer.chars[row][0].read().cp437_code, b'z');
+            assert_eq!(writer.dirty, None);
+
+            // scrolling copies the whole screen
+            for _ in 0..BUFFER_HEIGHT {
+                writeln!(writer, "line").expect("writeln failed");
+            }
+            for (cells, chars) in writer.buffer.chars.iter().zip(writer.shadow.iter()) {
+                assert!(cells
+                    .iter()
+                    .zip(chars.iter())
+                    .all(|(cell, &char)| cell.read() == char));
+            }
+        })
+    }
+
+    #[test_case]
+    fn test_mouse_pointer() {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let shown = |writer: &Writer, row: usize, column: usize| {
+                writer.buffer.chars[row][column].read().color_code
+            };
+
+            writer.set_pointer(Some((3, 100)));
+            let color = writer.shadow[3][BUFFER_WIDTH - 1].color_code;
+            assert_eq!(shown(&writer, 3, BUFFER_WIDTH - 1), color.inverted());
+
+            // the pointer stays on its cell as the screen scrolls
+            writer.set_pointer(Some((5, 7)));
+            assert_eq!(shown(&writer, 3, BUFFER_WIDTH - 1), color);
+            writer.row_position = BUFFER_HEIGHT - 1;
+            writer.new_line();
+            writer.flush();
+            let color = writer.shadow[5][7].color_code;
+            assert_eq!(shown(&writer, 5, 7), color.inverted());
+
+            writer.set_pointer(None);
+            assert_eq!(shown(&writer, 5, 7), color);
+        })
+    }
 }
@@ -0,0 +1,408 @@
+//! Virtio devices on the PCI bus.
+//!
+//! Two interfaces are driven: the legacy one of virtio 0.9.5, which QEMU still offers on its
+//! transitional devices, and the modern one of virtio 1.0 of the devices without a legacy
+//! interface such as the GPU. Legacy registers are I/O ports in BAR 0, modern registers are memory
+//! mapped in the BARs pointed at by vendor-specific PCI capabilities, see [Transport]. Both use
+//! the same virtqueue layout in physical memory, see [Virtqueue]. Transitional devices have the
+//! vendor ID [VENDOR_ID] and a device ID of 0x1000 - 0x103f, e.g. 0x1000 for network cards, modern
+//! devices a device ID of 0x1040 plus their type, e.g. 0x1050 for GPUs.
+//!
+//! A driver finds its device with [pci::find], wraps it in a [Transport] and initializes it in the
+//! order the specification requires:
+//!
+//! ```ignore
+//! let transport = Transport::new(device)?;
+//! let features = transport.begin_init(SUPPORTED_FEATURES)?;
+//! let queue = Virtqueue::new(transport, 0)?;
+//! transport.finish_init();
+//! ```
+
+use core::ptr;
+
+use x86_64::{
+    instructions::port::Port,
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    memory,
+    pci::{self, Bar},
+};
+
+pub use self::queue::Virtqueue;
+
+pub mod gpu;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+/// The vendor ID of all virtio devices.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+/// The device-specific configuration follows the common registers when MSI-X is disabled.
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+/// Offsets of the registers of the common configuration of modern devices.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// The types of the vendor-specific capabilities locating the registers of modern devices.
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Feature bit 32, the first of the upper half of the features, which modern devices offer and
+/// drivers must accept.
+const FEATURE_VERSION_1: u32 = 1 << 0;
+
+/// Set in the ISR status when a virtqueue has been used.
+pub const ISR_QUEUE: u8 = 1 << 0;
+/// Set in the ISR status when the device configuration has changed.
+pub const ISR_CONFIG: u8 = 1 << 1;
+
+/// The registers of a virtio device on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transport {
+    registers: Registers,
+}
+
+/// The location of the registers of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Registers {
+    /// The I/O ports of a legacy device.
+    Legacy { base: u16 },
+    /// The memory mapped structures of a modern device.
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        /// The distance between the notification registers of two queues, in units of their
+        /// notification offsets.
+        notify_multiplier: u32,
+        isr: VirtAddr,
+        device: VirtAddr,
+    },
+}
+
+impl Transport {
+    /// The registers of `device`, `None` if its BAR 0 isn't in the I/O space, i.e. it's a modern
+    /// device without the legacy interface. DMA is enabled on the device.
+    pub fn new(device: &pci::Device) -> Option<Self> {
+        match device.bars[0] {
+            Bar::Io { port, .. } => {
+                device.enable_bus_mastering();
+                Some(Transport {
+                    registers: Registers::Legacy { base: port },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The modern registers of `device`, mapped to virtual memory. `None` if the device doesn't
+    /// describe them or they can't be mapped. DMA is enabled on the device.
+    pub fn new_modern(
+        device: &pci::Device,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Option<Self> {
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in device.capabilities() {
+            if capability.id != pci::CAPABILITY_VENDOR {
+                continue;
+            }
+            // the type, the BAR, then the offset and the length of the structure in the BAR
+            let read = |offset: u8| device.address.read(capability.offset + offset);
+            let kind = (read(0) >> 24) as u8;
+            let bar = read(4) as u8;
+            let (offset, length) = (u64::from(read(8)), u64::from(read(12)));
+            let location = match device.bars.get(usize::from(bar)) {
+                Some(&Bar::Memory { address, size, .. }) if offset + length <= size => {
+                    (PhysAddr::new(address + offset), length)
+                }
+                _ => continue,
+            };
+            // the first capability of each type is the preferred one
+            let slot = match kind {
+                CAP_COMMON_CFG => &mut common,
+                CAP_NOTIFY_CFG => {
+                    if notify.is_none() {
+                        notify_multiplier = read(16);
+                    }
+                    &mut notify
+                }
+                CAP_ISR_CFG => &mut isr,
+                CAP_DEVICE_CFG => &mut config,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = Some(location);
+            }
+        }
+
+        let mut map = |location: Option<(PhysAddr, u64)>| {
+            let (phys, length) = location?;
+            // # Safety
+            // The structure is in a memory BAR assigned by the firmware.
+            unsafe { memory::map_mmio(mapper, frame_allocator, phys, length) }.ok()
+        };
+        let registers = Registers::Modern {
+            common: map(common)?,
+            notify: map(notify)?,
+            notify_multiplier,
+            isr: map(isr)?,
+            // devices without configuration, e.g. entropy devices, have no such structure
+            device: map(config).unwrap_or_else(VirtAddr::zero),
+        };
+        device.enable_bus_mastering();
+        Some(Transport { registers })
+    }
+
+    /// Reset the device, acknowledge it and accept the features in `supported` it offers, returns
+    /// the accepted features, `None` if a modern device refuses them. Virtqueues are set up after
+    /// this call.
+    pub fn begin_init(&self, supported: u32) -> Option<u32> {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        match self.registers {
+            Registers::Legacy { base } => {
+                // # Safety
+                // Feature negotiation has no side effect until the device is started.
+                unsafe {
+                    let features = Port::<u32>::new(base + REG_DEVICE_FEATURES).read() & supported;
+                    Port::<u32>::new(base + REG_GUEST_FEATURES).write(features);
+                    Some(features)
+                }
+            }
+            Registers::Modern { common, .. } => {
+                // # Safety
+                // As above, the common configuration is mapped by [Transport::new_modern].
+                let features = unsafe {
+                    mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 0);
+                    let features = mmio_read::<u32>(common + COMMON_DEVICE_FEATURE) & supported;
+                    mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+                    let upper = mmio_read::<u32>(common + COMMON_DEVICE_FEATURE);
+                    if upper & FEATURE_VERSION_1 == 0 {
+                        return None;
+                    }
+                    mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+                    mmio_write(common + COMMON_DRIVER_FEATURE, features);
+                    mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+                    mmio_write(common + COMMON_DRIVER_FEATURE, FEATURE_VERSION_1);
+                    features
+                };
+                let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+                self.set_status(status);
+                // the device clears the bit if it can't work with the features
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    return None;
+                }
+                Some(features)
+            }
+        }
+    }
+
+    /// Start the device once its virtqueues are set up.
+    pub fn finish_init(&self) {
+        let status = self.status() & STATUS_FEATURES_OK;
+        self.set_status(status | STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.set_status(STATUS_FAILED);
+    }
+
+    fn status(&self) -> u8 {
+        match self.registers {
+            // # Safety
+            // Reading the status has no side effect.
+            Registers::Legacy { base } => unsafe { Port::<u8>::new(base + REG_STATUS).read() },
+            // # Safety
+            // As above, the common configuration is mapped by [Transport::new_modern].
+            Registers::Modern { common, .. } => unsafe { mmio_read(common + COMMON_STATUS) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        // # Safety
+        // The status drives the initialization of the device, which holds no request yet or is
+        // being reset.
+        unsafe {
+            match self.registers {
+                Registers::Legacy { base } => Port::<u8>::new(base + REG_STATUS).write(status),
+                Registers::Modern { common, .. } => mmio_write(common + COMMON_STATUS, status),
+            }
+        }
+    }
+
+    /// Read and acknowledge the interrupt status, e.g. [ISR_QUEUE]. Reading deasserts the
+    /// interrupt line.
+    pub fn read_isr(&self) -> u8 {
+        // # Safety
+        // Reading the ISR status only acknowledges the interrupt.
+        unsafe {
+            match self.registers {
+                Registers::Legacy { base } => Port::<u8>::new(base + REG_ISR_STATUS).read(),
+                Registers::Modern { isr, .. } => mmio_read(isr),
+            }
+        }
+    }
+
+    /// Read the byte at `offset` of the device-specific configuration.
+    pub fn read_config(&self, offset: u16) -> u8 {
+        // # Safety
+        // The device configuration reads without side effects.
+        unsafe {
+            match self.registers {
+                Registers::Legacy { base } => {
+                    Port::<u8>::new(base + REG_DEVICE_CONFIG + offset).read()
+                }
+                Registers::Modern { device, .. } => {
+                    assert!(!device.is_null(), "the device has no configuration");
+                    mmio_read(device + u64::from(offset))
+                }
+            }
+        }
+    }
+
+    /// Write the byte at `offset` of the device-specific configuration.
+    pub fn write_config(&self, offset: u16, value: u8) {
+        // # Safety
+        // The writable fields of the device configuration acknowledge events or change settings of
+        // the device, neither is related to memory.
+        unsafe {
+            match self.registers {
+                Registers::Legacy { base } => {
+                    Port::<u8>::new(base + REG_DEVICE_CONFIG + offset).write(value)
+                }
+                Registers::Modern { device, .. } => {
+                    assert!(!device.is_null(), "the device has no configuration");
+                    mmio_write(device + u64::from(offset), value)
+                }
+            }
+        }
+    }
+
+    /// The size of the virtqueue `queue`, 0 if it doesn't exist.
+    fn queue_size(&self, queue: u16) -> u16 {
+        // # Safety
+        // Selecting a queue only changes which queue the queue registers refer to.
+        unsafe {
+            match self.registers {
+                Registers::Legacy { base } => {
+                    Port::<u16>::new(base + REG_QUEUE_SELECT).write(queue);
+                    Port::<u16>::new(base + REG_QUEUE_SIZE).read()
+                }
+                Registers::Modern { common, .. } => {
+                    mmio_write(common + COMMON_QUEUE_SELECT, queue);
+                    mmio_read(common + COMMON_QUEUE_SIZE)
+                }
+            }
+        }
+    }
+
+    /// Tell the device the virtqueue `queue` is at `region`, with its available ring at
+    /// `avail_offset` and its used ring at `used_offset`. Returns the offset of its notification
+    /// register, to be given to [Transport::notify].
+    ///
+    /// # Safety
+    /// The virtqueue must be a valid legacy virtqueue layout of its size, the device accesses it by
+    /// DMA from now on.
+    unsafe fn set_queue(
+        &self,
+        queue: u16,
+        region: PhysAddr,
+        avail_offset: u64,
+        used_offset: u64,
+    ) -> u64 {
+        match self.registers {
+            Registers::Legacy { base } => {
+                // legacy devices find the rings at fixed offsets from the page of the queue
+                Port::<u16>::new(base + REG_QUEUE_SELECT).write(queue);
+                Port::<u32>::new(base + REG_QUEUE_PFN)
+                    .write((region.as_u64() / queue::USED_RING_ALIGN) as u32);
+                0
+            }
+            Registers::Modern {
+                common,
+                notify_multiplier,
+                ..
+            } => {
+                mmio_write(common + COMMON_QUEUE_SELECT, queue);
+                mmio_write(common + COMMON_QUEUE_DESC, region.as_u64());
+                mmio_write(
+                    common + COMMON_QUEUE_DRIVER,
+                    (region + avail_offset).as_u64(),
+                );
+                mmio_write(
+                    common + COMMON_QUEUE_DEVICE,
+                    (region + used_offset).as_u64(),
+                );
+                mmio_write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+                let notify_off: u16 = mmio_read(common + COMMON_QUEUE_NOTIFY_OFF);
+                u64::from(notify_off) * u64::from(notify_multiplier)
+            }
+        }
+    }
+
+    /// Tell the device new buffers are available on the virtqueue `queue`, whose notification
+    /// register is at `notify_offset`.
+    fn notify(&self, queue: u16, notify_offset: u64) {
+        // # Safety
+        // The device only reads the available buffers, which have been published by the driver.
+        unsafe {
+            match self.registers {
+                Registers::Legacy { base } => {
+                    Port::<u16>::new(base + REG_QUEUE_NOTIFY).write(queue)
+                }
+                Registers::Modern { notify, .. } => mmio_write(notify + notify_offset, queue),
+            }
+        }
+    }
+}
+
+/// Read the register at `addr`.
+///
+/// # Safety
+/// `addr` must be a register of a device mapped by [memory::map_mmio].
+unsafe fn mmio_read<T>(addr: VirtAddr) -> T {
+    ptr::read_volatile(addr.as_ptr())
+}
+
+/// Write `value` to the register at `addr`.
+///
+/// # Safety
+/// As [mmio_read], and writing the register must not break memory safety.
+unsafe fn mmio_write<T>(addr: VirtAddr, value: T) {
+    ptr::write_volatile(addr.as_mut_ptr(), value)
+}
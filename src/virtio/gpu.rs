@@ -0,0 +1,606 @@
+//! Driver of the virtio GPU in 2D mode, `-device virtio-gpu-pci` on QEMU.
+//!
+//! The GPU has no legacy interface, it's driven through the modern one. The driver keeps a
+//! framebuffer in guest memory attached as the backing of a resource of the host shown on the
+//! first scanout: pixels are drawn by the CPU with [draw], then [flush] copies a rectangle of them
+//! to the resource and updates the display. Commands go one at a time through the control queue
+//! and are polled for, they complete in microseconds.
+//!
+//! The backing is reserved at boot for [MAX_WIDTH] x [MAX_HEIGHT] pixels, so that the display can
+//! be [resize]d without allocating, e.g. when the window of QEMU is resized, see
+//! [poll_display_change].
+
+use core::{fmt, mem, slice};
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Size4KiB},
+    PhysAddr,
+};
+
+use super::{Transport, Virtqueue, VENDOR_ID};
+use crate::{
+    dma::{self, DmaBuffer, Pod},
+    info, memory, pci, time, warn,
+};
+
+/// The device ID of GPUs, which are modern devices only.
+pub const DEVICE_ID: u16 = 0x1050;
+/// The widest mode of the display.
+pub const MAX_WIDTH: u32 = 1920;
+/// The tallest mode of the display.
+pub const MAX_HEIGHT: u32 = 1080;
+/// The mode of the display when the host doesn't suggest one.
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+const CONTROL_QUEUE: u16 = 0;
+/// Number of polls of the control queue before a command is given up, 10 microseconds apart.
+const POLLS: usize = 100_000;
+/// The scanout showing the framebuffer.
+const SCANOUT: u32 = 0;
+/// The number of scanouts described by the display information.
+const MAX_SCANOUTS: u64 = 16;
+const BYTES_PER_PIXEL: u64 = 4;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// 32-bit pixels of blue, green and red bytes in this order then an unused byte, i.e. `0xRRGGBB`
+/// once read as a little-endian integer.
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+/// Offsets of the fields of the device configuration.
+const CONFIG_EVENTS_READ: u16 = 0;
+const CONFIG_EVENTS_CLEAR: u16 = 4;
+/// Set in the pending events when the host changed the display configuration.
+const EVENT_DISPLAY: u8 = 1 << 0;
+
+static GPU: OnceCell<Mutex<Gpu>> = OnceCell::uninit();
+
+/// Errors of the GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuError {
+    /// No GPU has been initialized.
+    NoDevice,
+    /// The mode is empty or larger than [MAX_WIDTH] x [MAX_HEIGHT].
+    InvalidMode(u32, u32),
+    /// The device answered a command with the response type.
+    Device(u32),
+    /// The device didn't answer a command in time, it isn't used anymore.
+    Timeout,
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpuError::NoDevice => write!(f, "no GPU"),
+            GpuError::InvalidMode(width, height) => write!(
+                f,
+                "invalid mode {}x{}, at most {}x{}",
+                width, height, MAX_WIDTH, MAX_HEIGHT
+            ),
+            GpuError::Device(response) => write!(f, "command failed with {:#06x}", response),
+            GpuError::Timeout => write!(f, "GPU timed out"),
+        }
+    }
+}
+
+/// A rectangle of pixels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Rect {
+    /// The column of the left edge.
+    pub x: u32,
+    /// The row of the top edge.
+    pub y: u32,
+    /// The number of columns.
+    pub width: u32,
+    /// The number of rows.
+    pub height: u32,
+}
+
+impl Rect {
+    /// The rectangle of `width` x `height` pixels from the column `x` and the row `y` on.
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The part of the rectangle within a display of `width` x `height` pixels.
+    fn clip(self, width: u32, height: u32) -> Self {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Rect {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+}
+
+/// The header of the commands and their responses.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    context_id: u32,
+    padding: u32,
+}
+
+impl Header {
+    fn new(kind: u32) -> Self {
+        Header {
+            kind,
+            flags: 0,
+            fence_id: 0,
+            context_id: 0,
+            padding: 0,
+        }
+    }
+}
+
+/// A scanout in the response to [CMD_GET_DISPLAY_INFO].
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ResourceCreate2d {
+    header: Header,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ResourceUnref {
+    header: Header,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// Attach a backing of a single entry.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ResourceAttachBacking {
+    header: Header,
+    resource_id: u32,
+    entry_count: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct SetScanout {
+    header: Header,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct TransferToHost2d {
+    header: Header,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ResourceFlush {
+    header: Header,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+// # Safety
+// The commands are integers and rectangles of integers laid out without padding.
+unsafe impl Pod for Rect {}
+unsafe impl Pod for Header {}
+unsafe impl Pod for DisplayOne {}
+unsafe impl Pod for ResourceCreate2d {}
+unsafe impl Pod for ResourceUnref {}
+unsafe impl Pod for ResourceAttachBacking {}
+unsafe impl Pod for SetScanout {}
+unsafe impl Pod for TransferToHost2d {}
+unsafe impl Pod for ResourceFlush {}
+
+/// The pixels of the display, `0xRRGGBB` each, row after row.
+pub struct Framebuffer<'a> {
+    pixels: &'a mut [u32],
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Framebuffer<'a> {
+    /// The number of columns.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The number of rows.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// All the pixels, the pixel in the column `x` of the row `y` at `y * width + x`.
+    pub fn pixels(&mut self) -> &mut [u32] {
+        self.pixels
+    }
+
+    /// Set the pixel in the column `x` of the row `y` to `color`, pixels outside of the display
+    /// are ignored.
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: u32) {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = color;
+        }
+    }
+
+    /// Fill the part of `rect` within the display with `color`.
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) {
+        let rect = rect.clip(self.width, self.height);
+        for y in rect.y..rect.y + rect.height {
+            let start = (y * self.width + rect.x) as usize;
+            self.pixels[start..start + rect.width as usize].fill(color);
+        }
+    }
+}
+
+struct Gpu {
+    transport: Transport,
+    queue: Virtqueue,
+    request: DmaBuffer,
+    response: DmaBuffer,
+    /// The frames of the framebuffer, for [MAX_WIDTH] x [MAX_HEIGHT] pixels.
+    backing: PhysAddr,
+    width: u32,
+    height: u32,
+    /// The resource on the scanout, 0 before the first mode is set.
+    resource: u32,
+    next_resource: u32,
+    /// A command timed out and may still be processed, the buffers can't be reused.
+    stuck: bool,
+}
+
+impl Gpu {
+    fn new(
+        device: &pci::Device,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Option<Self> {
+        let transport = Transport::new_modern(device, mapper, frame_allocator)?;
+        transport.begin_init(0)?;
+        let gpu = (|| {
+            let mut queue = Virtqueue::new(transport, CONTROL_QUEUE)?;
+            queue.suppress_interrupts();
+            let frames =
+                (u64::from(MAX_WIDTH) * u64::from(MAX_HEIGHT) * BYTES_PER_PIXEL + 4095) / 4096;
+            Some(Gpu {
+                transport,
+                queue,
+                request: dma::alloc(mem::size_of::<TransferToHost2d>() as u64)?,
+                response: dma::alloc(
+                    (mem::size_of::<Header>()
+                        + MAX_SCANOUTS as usize * mem::size_of::<DisplayOne>())
+                        as u64,
+                )?,
+                backing: memory::alloc_contiguous(frame_allocator, frames)?,
+                width: 0,
+                height: 0,
+                resource: 0,
+                next_resource: 1,
+                stuck: false,
+            })
+        })();
+        match gpu {
+            Some(_) => transport.finish_init(),
+            None => transport.fail(),
+        }
+        gpu
+    }
+
+    /// Send `request` and wait for the response of `response_len` bytes, returns its type.
+    fn command<T: Pod>(&mut self, request: T, response_len: usize) -> Result<u32, GpuError> {
+        if self.stuck {
+            return Err(GpuError::Timeout);
+        }
+        self.request.write(0, request);
+        // # Safety
+        // The buffers are only used by this command, which is waited for below.
+        let head = unsafe {
+            self.queue
+                .add(
+                    &[(self.request.phys_addr(), mem::size_of::<T>() as u32)],
+                    &[(self.response.phys_addr(), response_len as u32)],
+                )
+                .expect("the only command is already in flight")
+        };
+        self.queue.notify();
+
+        for _ in 0..POLLS {
+            if let Some((used, _)) = self.queue.pop_used() {
+                debug_assert_eq!(used, head);
+                // the device is done with the response
+                self.response.sync_for_cpu();
+                return Ok(self.response.read::<Header>(0).kind);
+            }
+            time::delay_us(10);
+        }
+
+        // the command stays in flight, the device may still write the response later
+        warn!("virtio-gpu didn't answer within {} ms", POLLS / 100);
+        self.stuck = true;
+        Err(GpuError::Timeout)
+    }
+
+    /// Send `request` which is answered without data.
+    fn command_ok<T: Pod>(&mut self, request: T) -> Result<(), GpuError> {
+        match self.command(request, mem::size_of::<Header>())? {
+            RESP_OK_NODATA => Ok(()),
+            response => Err(GpuError::Device(response)),
+        }
+    }
+
+    /// The mode the host suggests for the scanout, `None` if it's disabled.
+    fn preferred_mode(&mut self) -> Result<Option<(u32, u32)>, GpuError> {
+        let len = self.response.size() as usize;
+        match self.command(Header::new(CMD_GET_DISPLAY_INFO), len)? {
+            RESP_OK_DISPLAY_INFO => {}
+            response => return Err(GpuError::Device(response)),
+        }
+        let offset = mem::size_of::<Header>() + SCANOUT as usize * mem::size_of::<DisplayOne>();
+        let display: DisplayOne = self.response.read(offset as u64);
+        if display.enabled != 0 && display.rect.width > 0 && display.rect.height > 0 {
+            Ok(Some((display.rect.width, display.rect.height)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Show a new resource of `width` x `height` pixels backed by the framebuffer, cleared to
+    /// black, on the scanout.
+    fn set_mode(&mut self, width: u32, height: u32) -> Result<(), GpuError> {
+        if width == 0 || height == 0 || width > MAX_WIDTH || height > MAX_HEIGHT {
+            return Err(GpuError::InvalidMode(width, height));
+        }
+
+        let resource = self.next_resource;
+        self.next_resource += 1;
+        self.command_ok(ResourceCreate2d {
+            header: Header::new(CMD_RESOURCE_CREATE_2D),
+            resource_id: resource,
+            format: FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        })?;
+        let shown = (|| {
+            self.command_ok(ResourceAttachBacking {
+                header: Header::new(CMD_RESOURCE_ATTACH_BACKING),
+                resource_id: resource,
+                entry_count: 1,
+                addr: self.backing.as_u64(),
+                length: (u64::from(width) * u64::from(height) * BYTES_PER_PIXEL) as u32,
+                padding: 0,
+            })?;
+            self.command_ok(SetScanout {
+                header: Header::new(CMD_SET_SCANOUT),
+                rect: Rect::new(0, 0, width, height),
+                scanout_id: SCANOUT,
+                resource_id: resource,
+            })
+        })();
+        // the resource not shown, or the one replaced, is destroyed with its attachment
+        let unused = match shown {
+            Ok(()) => mem::replace(&mut self.resource, resource),
+            Err(_) => resource,
+        };
+        if unused != 0 {
+            self.command_ok(ResourceUnref {
+                header: Header::new(CMD_RESOURCE_UNREF),
+                resource_id: unused,
+                padding: 0,
+            })?;
+        }
+        shown?;
+
+        self.width = width;
+        self.height = height;
+        self.framebuffer().pixels().fill(0);
+        self.flush(Rect::new(0, 0, width, height))
+    }
+
+    fn framebuffer(&mut self) -> Framebuffer<'_> {
+        let len = (self.width * self.height) as usize;
+        // # Safety
+        // The backing is owned by the driver and large enough for any mode, the device only reads
+        // it during the commands sent with the lock of the driver held.
+        let pixels = unsafe {
+            slice::from_raw_parts_mut(memory::phys_to_virt(self.backing).as_mut_ptr(), len)
+        };
+        Framebuffer {
+            pixels,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Copy the pixels of `rect` to the resource and update the display.
+    fn flush(&mut self, rect: Rect) -> Result<(), GpuError> {
+        let rect = rect.clip(self.width, self.height);
+        if rect.width == 0 || rect.height == 0 {
+            return Ok(());
+        }
+        self.command_ok(TransferToHost2d {
+            header: Header::new(CMD_TRANSFER_TO_HOST_2D),
+            rect,
+            offset: (u64::from(rect.y) * u64::from(self.width) + u64::from(rect.x))
+                * BYTES_PER_PIXEL,
+            resource_id: self.resource,
+            padding: 0,
+        })?;
+        self.command_ok(ResourceFlush {
+            header: Header::new(CMD_RESOURCE_FLUSH),
+            rect,
+            resource_id: self.resource,
+            padding: 0,
+        })
+    }
+}
+
+/// Initialize the first virtio GPU if there is one and show a black screen in the mode suggested
+/// by the host, must be called once after [pci::init] and [dma::init].
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let device = match pci::find(VENDOR_ID, DEVICE_ID) {
+        Some(device) => device,
+        None => return,
+    };
+    let mut gpu = match Gpu::new(device, mapper, frame_allocator) {
+        Some(gpu) => gpu,
+        None => {
+            warn!("failed to initialize virtio-gpu at {}", device.address);
+            return;
+        }
+    };
+
+    let (width, height) = match gpu.preferred_mode() {
+        Ok(mode) => mode.unwrap_or(DEFAULT_MODE),
+        Err(err) => {
+            warn!("virtio-gpu at {}: {}", device.address, err);
+            return;
+        }
+    };
+    if let Err(err) = gpu.set_mode(width.min(MAX_WIDTH), height.min(MAX_HEIGHT)) {
+        warn!("virtio-gpu at {}: {}", device.address, err);
+        return;
+    }
+    info!(
+        "virtio-gpu at {}, {}x{}",
+        device.address, gpu.width, gpu.height
+    );
+    GPU.init_once(|| Mutex::new(gpu));
+}
+
+/// Whether a GPU has been initialized.
+pub fn is_present() -> bool {
+    GPU.is_initialized()
+}
+
+fn gpu() -> Result<&'static Mutex<Gpu>, GpuError> {
+    GPU.try_get().map_err(|_| GpuError::NoDevice)
+}
+
+/// The mode of the display, its width and height in pixels.
+pub fn resolution() -> Result<(u32, u32), GpuError> {
+    let gpu = gpu()?.lock();
+    Ok((gpu.width, gpu.height))
+}
+
+/// Draw on the framebuffer with `f`, the display is updated by [flush].
+pub fn draw<R>(f: impl FnOnce(&mut Framebuffer<'_>) -> R) -> Result<R, GpuError> {
+    let mut gpu = gpu()?.lock();
+    Ok(f(&mut gpu.framebuffer()))
+}
+
+/// Update the part of `rect` within the display with the pixels of the framebuffer.
+pub fn flush(rect: Rect) -> Result<(), GpuError> {
+    gpu()?.lock().flush(rect)
+}
+
+/// Change the mode of the display to `width` x `height` pixels, at most [MAX_WIDTH] x
+/// [MAX_HEIGHT]. The framebuffer is cleared to black.
+pub fn resize(width: u32, height: u32) -> Result<(), GpuError> {
+    gpu()?.lock().set_mode(width, height)
+}
+
+/// Follow the mode suggested by the host if it changed the display configuration since the last
+/// call, e.g. the window of QEMU was resized. Returns the new mode, `None` if it didn't change.
+pub fn poll_display_change() -> Result<Option<(u32, u32)>, GpuError> {
+    let mut gpu = gpu()?.lock();
+    if gpu.transport.read_config(CONFIG_EVENTS_READ) & EVENT_DISPLAY == 0 {
+        return Ok(None);
+    }
+    gpu.transport
+        .write_config(CONFIG_EVENTS_CLEAR, EVENT_DISPLAY);
+
+    let (width, height) = match gpu.preferred_mode()? {
+        Some((width, height)) => (width.min(MAX_WIDTH), height.min(MAX_HEIGHT)),
+        None => return Ok(None),
+    };
+    if (width, height) == (gpu.width, gpu.height) {
+        return Ok(None);
+    }
+    gpu.set_mode(width, height)?;
+    Ok(Some((width, height)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn display_initialized() {
+        let (width, height) = resolution().expect("no virtio-gpu device");
+        assert!(width <= MAX_WIDTH && height <= MAX_HEIGHT);
+
+        draw(|framebuffer| {
+            framebuffer.fill_rect(Rect::new(width - 10, 0, 20, 10), 0xff0000);
+            // clipped to the display
+            assert_eq!(framebuffer.pixels()[(width - 1) as usize], 0xff0000);
+            assert_eq!(framebuffer.pixels()[(width - 11) as usize], 0);
+        })
+        .unwrap();
+        assert_eq!(flush(Rect::new(0, 0, width, height)), Ok(()));
+        assert_eq!(poll_display_change(), Ok(None));
+    }
+
+    #[test_case]
+    fn resized() {
+        let mode = resolution().unwrap();
+        assert_eq!(resize(640, 480), Ok(()));
+        assert_eq!(resolution(), Ok((640, 480)));
+        assert_eq!(
+            draw(|framebuffer| framebuffer.pixels().len()),
+            Ok(640 * 480)
+        );
+
+        assert_eq!(
+            resize(MAX_WIDTH + 1, 480),
+            Err(GpuError::InvalidMode(MAX_WIDTH + 1, 480))
+        );
+        assert_eq!(resize(0, 0), Err(GpuError::InvalidMode(0, 0)));
+        assert_eq!(resolution(), Ok((640, 480)));
+
+        assert_eq!(resize(mode.0, mode.1), Ok(()));
+    }
+}
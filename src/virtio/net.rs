@@ -0,0 +1,363 @@
+//! Driver of the virtio network card, `-device virtio-net-pci` on QEMU.
+//!
+//! Ethernet frames are exchanged on two virtqueues, received frames on [RX_QUEUE] and transmitted
+//! frames on [TX_QUEUE], each preceded by a header for the offloads, none of which is negotiated.
+//! Receive buffers are posted to the device on initialization and reposted once their frame is
+//! copied out. The interrupt of the card only wakes the task reading the [PacketStream], the used
+//! rings are drained in task context. Transmitted frames are reclaimed lazily by the next
+//! [send], their interrupts are suppressed.
+
+use alloc::{vec, vec::Vec};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use futures_util::{task::AtomicWaker, Stream};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use super::{Transport, Virtqueue, ISR_QUEUE, VENDOR_ID};
+use crate::{
+    dma::{self, DmaBuffer},
+    info, interrupts,
+    metrics::Counter,
+    net::{self, MacAddress, NetDevice, NetError, MAX_FRAME_SIZE},
+    pci, warn,
+};
+
+/// The device ID of transitional network cards.
+pub const DEVICE_ID: u16 = 0x1000;
+
+/// The device has a MAC address in its configuration.
+const FEATURE_MAC: u32 = 1 << 5;
+const CONFIG_MAC: u16 = 0;
+
+/// The virtqueue of received frames.
+pub const RX_QUEUE: u16 = 0;
+/// The virtqueue of transmitted frames.
+pub const TX_QUEUE: u16 = 1;
+
+/// The size of the header preceding each frame, `struct virtio_net_hdr` without the field of
+/// mergeable receive buffers.
+const HEADER_SIZE: u32 = 10;
+/// The frame follows the header in its own descriptor at this offset of a buffer.
+const FRAME_OFFSET: u64 = 16;
+/// The size of a buffer holding the header and a frame.
+const BUFFER_SIZE: u64 = 2048;
+/// Number of receive and transmit buffers each, at most half the size of the queues with 2
+/// descriptors per buffer.
+const BUFFERS: u16 = 32;
+
+static NET: OnceCell<Net> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+static RX_PACKETS: Counter = Counter::new("virtio_net.rx_packets");
+static TX_PACKETS: Counter = Counter::new("virtio_net.tx_packets");
+static TX_QUEUE_FULL: Counter = Counter::new("virtio_net.tx_queue_full");
+
+/// The initialized network card.
+struct Net {
+    transport: Transport,
+    mac: MacAddress,
+    queues: Mutex<Queues>,
+}
+
+/// The virtqueues and their buffers, a buffer is indexed by its slot.
+struct Queues {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_buffers: DmaBuffer,
+    tx_buffers: DmaBuffer,
+    /// The slot of the buffer of each chain on the queues, indexed by the head of the chain.
+    rx_slots: Vec<Option<u16>>,
+    tx_slots: Vec<Option<u16>>,
+    tx_free: Vec<u16>,
+}
+
+impl Queues {
+    /// Offer the receive buffer in `slot` to the device.
+    fn post_rx(&mut self, slot: u16) {
+        let (header, frame) = buffer_parts(&self.rx_buffers, slot);
+        // # Safety
+        // The buffer belongs to the slot, which is only reused once popped from the queue.
+        let head = unsafe {
+            self.rx.add(
+                &[],
+                &[
+                    (header, HEADER_SIZE),
+                    (frame, (BUFFER_SIZE - FRAME_OFFSET) as u32),
+                ],
+            )
+        }
+        .expect("more receive buffers than descriptors");
+        self.rx_slots[usize::from(head)] = Some(slot);
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let (head, len) = self.rx.pop_used()?;
+        let slot = self.rx_slots[usize::from(head)]
+            .take()
+            .expect("used chain not posted");
+
+        let len = (len.saturating_sub(HEADER_SIZE) as usize).min(MAX_FRAME_SIZE);
+        let mut packet = vec![0; len];
+        // the device is done with the buffer and wrote `len` bytes of frame to it
+        self.rx_buffers.read_bytes(frame_offset(slot), &mut packet);
+        self.post_rx(slot);
+        self.rx.notify();
+
+        RX_PACKETS.inc();
+        Some(packet)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        while let Some((head, _)) = self.tx.pop_used() {
+            let slot = self.tx_slots[usize::from(head)]
+                .take()
+                .expect("used chain not sent");
+            self.tx_free.push(slot);
+        }
+        let slot = match self.tx_free.pop() {
+            Some(slot) => slot,
+            None => {
+                TX_QUEUE_FULL.inc();
+                return Err(NetError::QueueFull);
+            }
+        };
+
+        let (header, data) = buffer_parts(&self.tx_buffers, slot);
+        // the buffer of a free slot isn't accessed by the device, the header stays zeroed: no
+        // offload is requested
+        self.tx_buffers.write_bytes(frame_offset(slot), frame);
+        // # Safety
+        // The buffer is offered to the device until popped.
+        unsafe {
+            let head = self
+                .tx
+                .add(&[(header, HEADER_SIZE), (data, frame.len() as u32)], &[])
+                .expect("more transmit buffers than descriptors");
+            self.tx_slots[usize::from(head)] = Some(slot);
+        }
+        self.tx.notify();
+
+        TX_PACKETS.inc();
+        Ok(())
+    }
+}
+
+/// The physical addresses of the header and the frame of the buffer in `slot`.
+fn buffer_parts(buffers: &DmaBuffer, slot: u16) -> (PhysAddr, PhysAddr) {
+    let header = u64::from(slot) * BUFFER_SIZE;
+    (buffers.phys_at(header), buffers.phys_at(frame_offset(slot)))
+}
+
+/// The offset of the frame of the buffer in `slot`.
+fn frame_offset(slot: u16) -> u64 {
+    u64::from(slot) * BUFFER_SIZE + FRAME_OFFSET
+}
+
+impl Net {
+    fn new(device: &pci::Device) -> Option<Self> {
+        let transport = Transport::new(device)?;
+        let features = transport.begin_init(FEATURE_MAC)?;
+        if features & FEATURE_MAC == 0 {
+            warn!("virtio-net at {} has no MAC address", device.address);
+            transport.fail();
+            return None;
+        }
+
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = transport.read_config(CONFIG_MAC + i as u16);
+        }
+
+        let queues = (|| {
+            let rx = Virtqueue::new(transport, RX_QUEUE)?;
+            let mut tx = Virtqueue::new(transport, TX_QUEUE)?;
+            tx.suppress_interrupts();
+            let buffers = BUFFERS.min(rx.size() / 2).min(tx.size() / 2);
+            let mut queues = Queues {
+                rx_slots: vec![None; usize::from(rx.size())],
+                tx_slots: vec![None; usize::from(tx.size())],
+                rx,
+                tx,
+                rx_buffers: dma::alloc(u64::from(buffers) * BUFFER_SIZE)?,
+                tx_buffers: dma::alloc(u64::from(buffers) * BUFFER_SIZE)?,
+                tx_free: (0..buffers).collect(),
+            };
+            for slot in 0..buffers {
+                queues.post_rx(slot);
+            }
+            Some(queues)
+        })();
+        let queues = match queues {
+            Some(queues) => queues,
+            None => {
+                warn!(
+                    "failed to set up the virtqueues of virtio-net at {}",
+                    device.address
+                );
+                transport.fail();
+                return None;
+            }
+        };
+
+        transport.finish_init();
+        queues.rx.notify();
+        Some(Net {
+            transport,
+            mac: MacAddress(mac),
+            queues: Mutex::new(queues),
+        })
+    }
+}
+
+/// Initialize the first virtio network card if there is one, must be called once after
+/// [pci::init] and [dma::init].
+pub fn init() {
+    let device = match pci::find(VENDOR_ID, DEVICE_ID) {
+        Some(device) => device,
+        None => return,
+    };
+    let net = match Net::new(device) {
+        Some(net) => net,
+        None => return,
+    };
+
+    info!(
+        "virtio-net at {} with MAC address {}",
+        device.address, net.mac
+    );
+    NET.init_once(|| net);
+    match device.interrupt_line {
+        Some(irq) => interrupts::register_irq_handler(irq, handle_interrupt),
+        None => warn!("virtio-net at {} has no interrupt line", device.address),
+    }
+    if let Ok(net) = NET.try_get() {
+        net::register(net);
+    }
+}
+
+/// Acknowledge the interrupt of the card and wake the reader of the [PacketStream].
+fn handle_interrupt() {
+    if let Ok(net) = NET.try_get() {
+        // the line may be shared, the status tells whether the card raised the interrupt
+        if net.transport.read_isr() & ISR_QUEUE != 0 {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Whether a network card has been initialized.
+pub fn is_present() -> bool {
+    NET.is_initialized()
+}
+
+/// The MAC address of the network card, `None` if there is none.
+pub fn mac_address() -> Option<MacAddress> {
+    NET.try_get().ok().map(|net| net.mac)
+}
+
+/// Transmit the Ethernet frame `frame` without its frame check sequence.
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    NET.try_get().map_err(|_| NetError::NoDevice)?.send(frame)
+}
+
+/// Take the next received Ethernet frame without waiting, `None` if there is none.
+pub fn try_receive() -> Option<Vec<u8>> {
+    NET.try_get().ok()?.try_receive()
+}
+
+impl NetDevice for Net {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge(frame.len()));
+        }
+        self.queues.lock().send(frame)
+    }
+
+    fn try_receive(&self) -> Option<Vec<u8>> {
+        self.queues.lock().receive()
+    }
+
+    fn poll_receive(&self, cx: &mut Context<'_>) -> Poll<Vec<u8>> {
+        if let Some(packet) = self.try_receive() {
+            return Poll::Ready(packet);
+        }
+
+        WAKER.register(&cx.waker());
+
+        // the interrupt may have fired after the first check
+        match self.try_receive() {
+            Some(packet) => {
+                WAKER.take();
+                Poll::Ready(packet)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// A stream of the Ethernet frames received by the network card, woken by its interrupts. Ends at
+/// once if there is no network card.
+pub struct PacketStream {
+    _private: (),
+}
+
+impl PacketStream {
+    /// Create the [PacketStream]. Creating more than one [PacketStream] causes kernel panic.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        assert!(
+            !STREAM_TAKEN.swap(true, Ordering::SeqCst),
+            "PacketStream::new should only be called once"
+        );
+        PacketStream { _private: () }
+    }
+}
+
+impl Stream for PacketStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        match NET.try_get() {
+            Ok(net) => net.poll_receive(cx).map(Some),
+            Err(_) => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address of the card on QEMU unless `mac=` is given to the device.
+    const QEMU_MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+    #[test_case]
+    fn mac_from_config() {
+        assert_eq!(mac_address(), Some(QEMU_MAC));
+    }
+
+    #[test_case]
+    fn oversized_frame_refused() {
+        assert_eq!(
+            send(&[0; MAX_FRAME_SIZE + 1]),
+            Err(NetError::FrameTooLarge(MAX_FRAME_SIZE + 1))
+        );
+    }
+
+    #[test_case]
+    fn gateway_answers_arp() {
+        let net = NET.try_get().expect("no network card");
+        crate::net::tests::gateway_answers_arp(net);
+    }
+}
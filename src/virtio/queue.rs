@@ -0,0 +1,261 @@
+//! Virtqueues, the rings of buffers shared between the driver and a virtio device.
+//!
+//! A legacy virtqueue of `size` entries is one physically contiguous region in 3 parts:
+//! - the descriptor table, `size` descriptors of a buffer each, chained by their `next` field
+//! - the available ring, the heads of the chains the driver offers to the device
+//! - the used ring on the next page boundary, the heads of the chains the device is done with and
+//!   the number of bytes it wrote to them
+//!
+//! Free descriptors are kept in a list chained by their `next` field as well. Buffers only move
+//! from the driver to the device through the available ring and back through the used ring, the
+//! indices of both rings are published after the entries with a fence in between.
+
+use core::mem;
+
+use x86_64::PhysAddr;
+
+use super::Transport;
+use crate::dma::{self, DmaBuffer, Pod};
+
+/// The alignment of the used ring of legacy virtqueues, and the unit of their address.
+pub(super) const USED_RING_ALIGN: u64 = 4096;
+
+/// Set in a descriptor followed by the descriptor in its `next` field.
+const DESC_F_NEXT: u16 = 1 << 0;
+/// Set in a descriptor of a buffer written by the device.
+const DESC_F_WRITE: u16 = 1 << 1;
+/// Set in the flags of the available ring to suppress the interrupts of the used buffers.
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+/// Set by the device in the flags of the used ring when it doesn't need notifications.
+const USED_F_NO_NOTIFY: u16 = 1 << 0;
+
+/// A buffer of the descriptor table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// # Safety
+// The fields are integers laid out without padding.
+unsafe impl Pod for Descriptor {}
+
+/// A legacy virtqueue set up on a device.
+#[derive(Debug)]
+pub struct Virtqueue {
+    transport: Transport,
+    index: u16,
+    size: u16,
+    region: DmaBuffer,
+    /// The offset of the notification register of the queue, see [Transport::notify].
+    notify_offset: u64,
+    avail_offset: u64,
+    used_offset: u64,
+    /// The head of the list of free descriptors.
+    free_head: u16,
+    free_count: u16,
+    /// The index of the next entry of the available ring.
+    avail_index: u16,
+    /// The index of the next entry of the used ring to look at.
+    last_used_index: u16,
+}
+
+impl Virtqueue {
+    /// Set up the virtqueue `index` of the device at `transport`, `None` if the device has no such
+    /// queue or the DMA pool is exhausted. Must be called between [Transport::begin_init] and
+    /// [Transport::finish_init].
+    pub fn new(transport: Transport, index: u16) -> Option<Self> {
+        let size = transport.queue_size(index);
+        if size == 0 {
+            return None;
+        }
+
+        let avail_offset = mem::size_of::<Descriptor>() as u64 * u64::from(size);
+        // flags, index, the ring and the used event
+        let avail_size = 2 * (3 + u64::from(size));
+        let used_offset = align_up(avail_offset + avail_size, USED_RING_ALIGN);
+        // flags, index, the ring of (id, len) and the available event
+        let used_size = 2 * 3 + 8 * u64::from(size);
+        let region = dma::alloc(used_offset + used_size)?;
+
+        let mut queue = Virtqueue {
+            transport,
+            index,
+            size,
+            region,
+            notify_offset: 0,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            last_used_index: 0,
+        };
+        for i in 0..size {
+            queue.write_descriptor(
+                i,
+                Descriptor {
+                    addr: 0,
+                    len: 0,
+                    flags: 0,
+                    next: (i + 1) % size,
+                },
+            );
+        }
+
+        // # Safety
+        // The region is zeroed and laid out for `size` entries, it's owned by the queue until the
+        // device is reset.
+        queue.notify_offset = unsafe {
+            transport.set_queue(index, queue.region.phys_addr(), avail_offset, used_offset)
+        };
+        Some(queue)
+    }
+
+    /// The number of entries of the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The number of free descriptors, a chain needs one per buffer.
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    /// Ask the device not to interrupt when it uses buffers of this queue, it may still do.
+    pub fn suppress_interrupts(&mut self) {
+        // the flags of the available ring are only written by the driver
+        self.region.write(self.avail_offset, AVAIL_F_NO_INTERRUPT);
+    }
+
+    /// Offer a chain of the buffers `readable` by the device followed by the buffers `writable` by
+    /// it, each a physical address and a length. Returns the head of the chain, which is given
+    /// back by [Virtqueue::pop_used], `None` if there aren't enough free descriptors.
+    ///
+    /// # Safety
+    /// The buffers must stay valid until the chain is popped, the device accesses them by DMA.
+    pub unsafe fn add(
+        &mut self,
+        readable: &[(PhysAddr, u32)],
+        writable: &[(PhysAddr, u32)],
+    ) -> Option<u16> {
+        let count = readable.len() + writable.len();
+        assert!(count > 0, "a chain needs at least one buffer");
+        if count > usize::from(self.free_count) {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        let buffers = readable
+            .iter()
+            .map(|&buffer| (buffer, 0))
+            .chain(writable.iter().map(|&buffer| (buffer, DESC_F_WRITE)));
+        for (i, ((addr, len), flags)) in buffers.enumerate() {
+            let next = self.read_descriptor(index).next;
+            let flags = if i + 1 < count {
+                flags | DESC_F_NEXT
+            } else {
+                flags
+            };
+            self.write_descriptor(
+                index,
+                Descriptor {
+                    addr: addr.as_u64(),
+                    len,
+                    flags,
+                    next,
+                },
+            );
+            index = next;
+        }
+        self.free_head = index;
+        self.free_count -= count as u16;
+
+        let slot = u64::from(self.avail_index % self.size);
+        self.region.write(self.avail_offset + 4 + 2 * slot, head);
+        // the entry must be visible before the index publishing it
+        self.region.sync_for_device();
+        self.avail_index = self.avail_index.wrapping_add(1);
+        self.region.write(self.avail_offset + 2, self.avail_index);
+        Some(head)
+    }
+
+    /// Tell the device about the chains added since the last notification, unless it asked not
+    /// to be notified.
+    pub fn notify(&self) {
+        // the index must be visible before the device is notified
+        self.region.sync_for_device();
+        // the flags of the used ring are only written by the device
+        let flags: u16 = self.region.read(self.used_offset);
+        if flags & USED_F_NO_NOTIFY == 0 {
+            self.transport.notify(self.index, self.notify_offset);
+        }
+    }
+
+    /// Take the next chain the device is done with, returns its head and the number of bytes the
+    /// device wrote to it. Its descriptors are freed.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        // the used ring is only written by the device, entries up to its index are complete
+        let used_index: u16 = self.region.read(self.used_offset + 2);
+        if used_index == self.last_used_index {
+            return None;
+        }
+        // the entry must be read after the index publishing it
+        self.region.sync_for_cpu();
+        let slot = u64::from(self.last_used_index % self.size);
+        let entry = self.used_offset + 4 + 8 * slot;
+        let (head, len) = (
+            self.region.read::<u32>(entry) as u16,
+            self.region.read::<u32>(entry + 4),
+        );
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        // the chain is prepended to the free list
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let descriptor = self.read_descriptor(last);
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            last = descriptor.next;
+            count += 1;
+        }
+        let descriptor = self.read_descriptor(last);
+        self.write_descriptor(
+            last,
+            Descriptor {
+                next: self.free_head,
+                ..descriptor
+            },
+        );
+        self.free_head = head;
+        self.free_count += count;
+
+        Some((head, len))
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        assert!(index < self.size);
+        self.region
+            .read(u64::from(index) * mem::size_of::<Descriptor>() as u64)
+    }
+
+    fn write_descriptor(&mut self, index: u16, descriptor: Descriptor) {
+        assert!(index < self.size);
+        // the device only reads the descriptors of the chains on the available ring, which are not
+        // written until popped
+        self.region.write(
+            u64::from(index) * mem::size_of::<Descriptor>() as u64,
+            descriptor,
+        );
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
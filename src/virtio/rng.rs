@@ -0,0 +1,136 @@
+//! Driver of the virtio entropy device, `-device virtio-rng-pci` on QEMU.
+//!
+//! The device has a single virtqueue, each buffer offered on it is filled with random bytes from
+//! the entropy source of the host. Requests are rare and small, they're polled for instead of
+//! waiting for interrupts.
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+use super::{Transport, Virtqueue, VENDOR_ID};
+use crate::{
+    dma::{self, DmaBuffer},
+    info, pci, time, warn,
+};
+
+/// The device ID of transitional entropy devices.
+pub const DEVICE_ID: u16 = 0x1005;
+
+const REQUEST_QUEUE: u16 = 0;
+/// Number of polls of the virtqueue before a request is given up, 10 microseconds apart.
+const POLLS: usize = 10_000;
+
+static RNG: OnceCell<Mutex<Rng>> = OnceCell::uninit();
+
+struct Rng {
+    queue: Virtqueue,
+    buffer: DmaBuffer,
+    /// A request timed out and may still be filled, the buffer can't be reused.
+    stuck: bool,
+}
+
+impl Rng {
+    fn new(device: &pci::Device) -> Option<Self> {
+        let transport = Transport::new(device)?;
+        transport.begin_init(0)?;
+        let rng = (|| {
+            let mut queue = Virtqueue::new(transport, REQUEST_QUEUE)?;
+            queue.suppress_interrupts();
+            Some(Rng {
+                queue,
+                buffer: dma::alloc(1)?,
+                stuck: false,
+            })
+        })();
+        match rng {
+            Some(_) => transport.finish_init(),
+            None => transport.fail(),
+        }
+        rng
+    }
+
+    /// Fill `buf` with at most one buffer of random bytes, returns the number of bytes filled.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        if self.stuck {
+            return 0;
+        }
+        let len = buf.len().min(self.buffer.size() as usize);
+        // # Safety
+        // The buffer is only used by this request, which is waited for below.
+        let head = unsafe {
+            self.queue
+                .add(&[], &[(self.buffer.phys_addr(), len as u32)])
+                .expect("the only request is already in flight")
+        };
+        self.queue.notify();
+
+        for _ in 0..POLLS {
+            if let Some((used, written)) = self.queue.pop_used() {
+                debug_assert_eq!(used, head);
+                let written = (written as usize).min(len);
+                // the device is done with the buffer and wrote `written` bytes to it
+                self.buffer.read_bytes(0, &mut buf[..written]);
+                return written;
+            }
+            time::delay_us(10);
+        }
+
+        // the request stays in flight, the device may still fill the buffer later
+        warn!("virtio-rng didn't answer within {} ms", POLLS / 100);
+        self.stuck = true;
+        0
+    }
+}
+
+/// Initialize the first virtio entropy device if there is one, must be called once after
+/// [pci::init] and [dma::init].
+pub fn init() {
+    let device = match pci::find(VENDOR_ID, DEVICE_ID) {
+        Some(device) => device,
+        None => return,
+    };
+    match Rng::new(device) {
+        Some(rng) => {
+            info!("virtio-rng at {}", device.address);
+            RNG.init_once(|| Mutex::new(rng));
+        }
+        None => warn!("failed to initialize virtio-rng at {}", device.address),
+    }
+}
+
+/// Whether an entropy device has been initialized.
+pub fn is_present() -> bool {
+    RNG.is_initialized()
+}
+
+/// Fill `buf` with random bytes from the host, returns the number of bytes filled, 0 if there is
+/// no entropy device or it stopped answering.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut rng = match RNG.try_get() {
+        Ok(rng) => rng.lock(),
+        Err(_) => return 0,
+    };
+
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = rng.read(&mut buf[filled..]);
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn read_random_bytes() {
+        let mut buf = [0; 64];
+        assert_eq!(read(&mut buf), buf.len());
+        // 64 zero bytes from a working device are practically impossible
+        assert!(buf.iter().any(|&b| b != 0));
+    }
+}
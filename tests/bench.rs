@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+use bootloader::{entry_point, BootInfo};
+
+use rust_kernel::{
+    bench, println,
+    task::{simple_executor::SimpleExecutor, Task},
+    Bench,
+};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_kernel::init(boot_info);
+    test_main();
+    unreachable!("test_main should exit QEMU");
+}
+
+fn alloc_small() {
+    let value = Box::new(42u64);
+    // # Safety
+    // The volatile read keeps the allocation from being optimized out.
+    unsafe { ptr::read_volatile(&*value) };
+}
+
+#[test_case]
+const ALLOC_SMALL: Bench = bench!(alloc_small, 1000);
+
+fn alloc_fallback() {
+    // larger than the largest block size, served by the fallback allocator
+    let buffer: Vec<u8> = Vec::with_capacity(4096);
+    // # Safety
+    // As above.
+    unsafe { ptr::read_volatile(&buffer.as_ptr()) };
+}
+
+#[test_case]
+const ALLOC_FALLBACK: Bench = bench!(alloc_fallback, 1000);
+
+fn vga_scroll() {
+    // the warm-up iterations fill the screen, every timed line scrolls it
+    println!("vga_scroll");
+}
+
+#[test_case]
+const VGA_SCROLL: Bench = bench!(vga_scroll, 200);
+
+/// A future pending once before completing, giving way to the other tasks.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Number of times each of the two tasks of [task_switch] gives way to the other.
+const SWITCHES: usize = 32;
+
+async fn yield_repeatedly() {
+    for _ in 0..SWITCHES {
+        YieldNow(false).await;
+    }
+}
+
+fn task_switch() {
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(yield_repeatedly()));
+    executor.spawn(Task::new(yield_repeatedly()));
+    executor.run();
+}
+
+#[test_case]
+const TASK_SWITCH: Bench = bench!(task_switch, 200);
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::{
+    process, testing,
+    user::{self, loader, Exit, Program, PROGRAM_START},
+};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_kernel::init(boot_info);
+    test_main();
+    unreachable!("test_main should exit QEMU");
+}
+
+/// Where [HELLO] keeps its counter, on the page after its code at the same offset as in the file.
+const COUNTER: u64 = PROGRAM_START + 0x10f0;
+
+/// A static executable linked at [PROGRAM_START], writes "hello\n" to its standard output, adds the
+/// number of bytes written to its counter in `.data`, initially 36, then exits with the counter
+/// ORed with the zeroed word of `.bss` after it, 42.
+#[rustfmt::skip]
+const HELLO: &[u8] = &[
+    // ELF header: ELF64, little-endian, version 1, an x86_64 executable
+    0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    2, 0, 0x3e, 0, 1, 0, 0, 0,
+    // entry point at 0x2000_0040_00b0
+    0xb0, 0x00, 0x40, 0x00, 0x00, 0x20, 0x00, 0x00,
+    // program headers at 64, no section headers, no flags
+    64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    // header size 64, 2 program headers of 56 bytes, no section headers
+    64, 0, 56, 0, 2, 0, 64, 0, 0, 0, 0, 0,
+    // PT_LOAD, readable and executable: the headers and the code, bytes 0..0xf0 at 0x2000_0040_0000
+    1, 0, 0, 0, 5, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0x00, 0x00, 0x40, 0x00, 0x00, 0x20, 0x00, 0x00,
+    0x00, 0x00, 0x40, 0x00, 0x00, 0x20, 0x00, 0x00,
+    0xf0, 0, 0, 0, 0, 0, 0, 0,
+    0xf0, 0, 0, 0, 0, 0, 0, 0,
+    0x00, 0x10, 0, 0, 0, 0, 0, 0,
+    // PT_LOAD, readable and writable: `.data`, bytes 0xf0..0xf8 at 0x2000_0040_10f0, and 8 bytes of
+    // `.bss`
+    1, 0, 0, 0, 6, 0, 0, 0,
+    0xf0, 0, 0, 0, 0, 0, 0, 0,
+    0xf0, 0x10, 0x40, 0x00, 0x00, 0x20, 0x00, 0x00,
+    0xf0, 0x10, 0x40, 0x00, 0x00, 0x20, 0x00, 0x00,
+    8, 0, 0, 0, 0, 0, 0, 0,
+    16, 0, 0, 0, 0, 0, 0, 0,
+    0x00, 0x10, 0, 0, 0, 0, 0, 0,
+    // code
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (write)
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0x48, 0x8d, 0x35, 0x23, 0x00, 0x00, 0x00, // lea rsi, [rip + message]
+    0xba, 0x06, 0x00, 0x00, 0x00, // mov edx, 6
+    0x0f, 0x05, // syscall
+    0x48, 0x01, 0x05, 0x21, 0x10, 0x00, 0x00, // add [rip + counter], rax
+    0x48, 0x8b, 0x3d, 0x1a, 0x10, 0x00, 0x00, // mov rdi, [rip + counter]
+    0x48, 0x0b, 0x3d, 0x1b, 0x10, 0x00, 0x00, // or rdi, [rip + zeroed]
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2 (exit)
+    0x0f, 0x05, // syscall
+    // message
+    b'h', b'e', b'l', b'l', b'o', b'\n',
+    // padding
+    0, 0, 0, 0, 0, 0,
+    // .data: counter
+    36, 0, 0, 0, 0, 0, 0, 0,
+];
+
+#[test_case]
+fn headers_of_embedded_binary() {
+    let executable = loader::parse(HELLO).unwrap();
+    assert_eq!(executable.entry, VirtAddr::new(PROGRAM_START + 0xb0));
+    assert_eq!(executable.segments.len(), 2);
+    assert!(!executable.segments[0].writable);
+    assert!(executable.segments[1].writable);
+    assert_eq!(executable.segments[1].address, VirtAddr::new(COUNTER));
+}
+
+#[test_case]
+fn embedded_binary_runs() {
+    let program = Program::load_elf(HELLO, 0).unwrap();
+    let (mut program, exit) = user::run_program(program);
+    assert_eq!(exit, Exit::Exited(42));
+
+    let mut counter = [0; 8];
+    program
+        .space
+        .read(VirtAddr::new(COUNTER), &mut counter)
+        .unwrap();
+    assert_eq!(u64::from_le_bytes(counter), 42);
+}
+
+#[test_case]
+fn embedded_binary_spawned() {
+    let pid = process::spawn("hello", HELLO, None).unwrap();
+    assert_eq!(
+        testing::run_processes_until(process::wait(None, Some(pid))),
+        Ok((pid, Exit::Exited(42)))
+    );
+}
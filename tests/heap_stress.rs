@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, realloc, Layout};
+use core::{
+    panic::PanicInfo,
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+
+use rust_kernel::{
+    allocator, serial_println,
+    testing::{self, Rng},
+};
+
+/// Number of random operations of a workload.
+const OPERATIONS: usize = 40_000;
+/// Number of live allocations a workload juggles at most.
+const SLOTS: usize = 128;
+/// Number of operations between two heap integrity checks.
+const CHECK_INTERVAL: usize = 1024;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_kernel::init(boot_info);
+    SEED.store(testing::seed(), Ordering::Relaxed);
+    serial_println!("heap_stress seed={}", seed());
+    test_main();
+    unreachable!("test_main should exit QEMU");
+}
+
+/// The seed of all the workloads of the run, printed before the tests.
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+/// A live allocation filled with bytes derived from `tag`.
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    tag: u8,
+}
+
+impl Block {
+    fn bytes(&self) -> &[u8] {
+        // # Safety
+        // `ptr` is a live allocation of `layout`, owned by the block.
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    fn fill(&mut self) {
+        // # Safety
+        // As above, the block is borrowed mutably.
+        let bytes = unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) };
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.tag.wrapping_add(i as u8);
+        }
+    }
+
+    /// Check the first `len` bytes, panics if they're not the ones written by [Block::fill].
+    fn validate(&self, len: usize, op: usize) {
+        for (i, &byte) in self.bytes()[..len].iter().enumerate() {
+            assert_eq!(
+                byte,
+                self.tag.wrapping_add(i as u8),
+                "byte {} of a {:?} block corrupted at operation {}, seed={}",
+                i,
+                self.layout,
+                op,
+                seed()
+            );
+        }
+    }
+}
+
+/// A random layout, mostly small sizes which are served by the fixed-size blocks and some larger
+/// ones served by the fallback allocator.
+fn random_layout(rng: &mut Rng, max_size: u64) -> Layout {
+    let size = if rng.chance(7, 8) {
+        1 + rng.below(256)
+    } else {
+        1 + rng.below(max_size)
+    };
+    let align = 1 << rng.below(7);
+    Layout::from_size_align(size as usize, align).unwrap()
+}
+
+/// Run a workload of random allocations, deallocations and reallocations of up to `max_size`
+/// bytes, all blocks are freed at the end.
+fn workload(rng: &mut Rng, max_size: u64) {
+    const EMPTY: Option<Block> = None;
+    let mut slots = [EMPTY; SLOTS];
+
+    for op in 0..OPERATIONS {
+        let index = rng.below(SLOTS as u64) as usize;
+        match slots[index].take() {
+            None => {
+                let layout = random_layout(rng, max_size);
+                // # Safety
+                // The layout has a non-zero size.
+                let ptr = unsafe { alloc(layout) };
+                assert!(
+                    !ptr.is_null(),
+                    "{:?} failed at operation {}, seed={}",
+                    layout,
+                    op,
+                    seed()
+                );
+                let mut block = Block {
+                    ptr,
+                    layout,
+                    tag: rng.next_u64() as u8,
+                };
+                block.fill();
+                slots[index] = Some(block);
+            }
+            Some(block) if rng.chance(1, 3) => {
+                block.validate(block.layout.size(), op);
+                let old_size = block.layout.size();
+                let new_size = 1 + rng.below(max_size) as usize;
+                // # Safety
+                // The block was allocated with its layout, the new size is non-zero and can't
+                // overflow when rounded up to the alignment.
+                let ptr = unsafe { realloc(block.ptr, block.layout, new_size) };
+                assert!(
+                    !ptr.is_null(),
+                    "realloc to {} bytes failed at operation {}, seed={}",
+                    new_size,
+                    op,
+                    seed()
+                );
+                let layout = Layout::from_size_align(new_size, block.layout.align()).unwrap();
+                let mut block = Block {
+                    ptr,
+                    layout,
+                    ..block
+                };
+                // the bytes up to the smaller of the two sizes are preserved
+                block.validate(old_size.min(new_size), op);
+                block.fill();
+                slots[index] = Some(block);
+            }
+            Some(block) => {
+                block.validate(block.layout.size(), op);
+                // # Safety
+                // The block was allocated with its layout and is not used after.
+                unsafe { dealloc(block.ptr, block.layout) };
+            }
+        }
+
+        if op % CHECK_INTERVAL == 0 {
+            if let Err(err) = allocator::check() {
+                panic!(
+                    "heap corrupted at operation {}: {}, seed={}",
+                    op,
+                    err,
+                    seed()
+                );
+            }
+        }
+    }
+
+    for block in slots.iter_mut().filter_map(Option::take) {
+        block.validate(block.layout.size(), OPERATIONS);
+        // # Safety
+        // As above.
+        unsafe { dealloc(block.ptr, block.layout) };
+    }
+    assert_eq!(allocator::check(), Ok(()));
+}
+
+#[test_case]
+fn small_blocks() {
+    workload(&mut Rng::new(seed()), 256);
+}
+
+#[test_case]
+fn mixed_sizes() {
+    workload(&mut Rng::new(seed().wrapping_add(1)), 4096);
+}
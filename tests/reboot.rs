@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+
+use rust_kernel::{persist, testing::stage};
+
+/// Written before the reboot, checked after.
+const MARKER: u32 = 0x5eed_cafe;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_kernel::init(boot_info);
+    if stage::current() == 0 {
+        persist::clear();
+        persist::set_marker(MARKER);
+        stage::reboot_on_panic(1);
+        crash();
+    }
+    test_main();
+    unreachable!("test_main should exit QEMU");
+}
+
+/// The line of the panic in [crash], must stay right above it.
+const CRASH_LINE: u32 = line!() + 3;
+
+fn crash() -> ! {
+    panic!("crash before the reboot");
+}
+
+#[test_case]
+fn rebooted_once() {
+    assert_eq!(stage::current(), 1);
+}
+
+#[test_case]
+fn marker_survives_reboot() {
+    assert_eq!(persist::marker(), Some(MARKER));
+}
+
+#[test_case]
+fn crash_recorded() {
+    let crash = persist::last_crash().expect("the crash before the reboot wasn't recorded");
+    assert_eq!(crash.count, 1);
+    assert_eq!(crash.line, CRASH_LINE);
+}
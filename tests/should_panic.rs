@@ -1,23 +1,28 @@
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![no_main]
 
 use core::panic::PanicInfo;
-use rust_kernel::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+use rust_kernel::{should_panic, testing::ShouldPanic};
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info);
 }
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
+    test_main();
+    // test_main calls into test_runner which always exits QEMU.
+    unreachable!();
 }
 
 fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
     assert_eq!(0, 1);
 }
+
+#[test_case]
+const SHOULD_FAIL: ShouldPanic = should_panic!(should_fail);